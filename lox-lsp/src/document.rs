@@ -0,0 +1,252 @@
+use lox_vm::ast::Stmt;
+use lox_vm::compiler::Compiler;
+use lox_vm::diagnostic::{self, Severity};
+use lox_vm::interpreter::VirtualMemory;
+use lox_vm::parser;
+use lox_vm::resolver::{self, DeclarationKind, Resolution};
+use lox_vm::scanner::scan_tokens;
+use lox_vm::token::Span;
+use lsp_types::{
+    Diagnostic, DiagnosticSeverity, DocumentSymbol, NumberOrString, Position, Range, SymbolKind,
+};
+
+// An open file along with everything we know about it. It is rebuilt from
+// scratch on every change since Lox files are small.
+pub struct Document {
+    lines: Vec<String>,
+    statements: Vec<Stmt>,
+    resolution: Resolution,
+    diagnostics: Vec<diagnostic::Diagnostic>,
+}
+
+impl Document {
+    pub fn new(source: &str) -> Document {
+        let source = String::from(source);
+        let mut document = Document {
+            lines: source.split('\n').map(String::from).collect(),
+            statements: vec![],
+            resolution: Resolution::default(),
+            diagnostics: vec![],
+        };
+
+        match scan_tokens(&source) {
+            Ok(tokens) => {
+                //Errors come from the real compiler so they match what the VM reports.
+                let mut compiler = Compiler::new(tokens.clone(), VirtualMemory::new());
+                if let Err(errors) = compiler.compile() {
+                    document.diagnostics = errors
+                        .iter()
+                        .map(|e| diagnostic::Diagnostic::from_compiler_error(e, None))
+                        .collect();
+                }
+
+                let (statements, _) = parser::parse(tokens);
+                document.resolution = resolver::resolve(&statements);
+                document.statements = statements;
+            }
+            Err(error) => document
+                .diagnostics
+                .push(diagnostic::Diagnostic::from_scanner_error(&error, None)),
+        }
+
+        document
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics
+            .iter()
+            .map(|d| Diagnostic {
+                range: self.to_range(d.span),
+                severity: Some(match d.severity {
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                }),
+                code: Some(NumberOrString::String(String::from(d.code))),
+                source: Some(String::from("lox")),
+                message: d.message.clone(),
+                ..Diagnostic::default()
+            })
+            .collect()
+    }
+
+    pub fn definition(&self, position: Position) -> Option<Range> {
+        let (line, column) = self.line_and_column(position);
+        self.resolution
+            .declaration_at(line, column)
+            .map(|d| self.to_range(d.span))
+    }
+
+    pub fn hover(&self, position: Position) -> Option<String> {
+        let (line, column) = self.line_and_column(position);
+        let declaration = self.resolution.declaration_at(line, column)?;
+        let arity = declaration.arity.unwrap_or(0);
+
+        let text = match declaration.kind {
+            DeclarationKind::Global => format!("var {} (global)", declaration.name),
+            DeclarationKind::Local => format!("var {} (local)", declaration.name),
+            DeclarationKind::Parameter => format!("parameter {}", declaration.name),
+            DeclarationKind::Function => format!("fun {}/{}", declaration.name, arity),
+            DeclarationKind::Class => format!("class {} (init/{})", declaration.name, arity),
+            DeclarationKind::Method => format!(
+                "method {}.{}/{}",
+                declaration.class.as_deref().unwrap_or("?"),
+                declaration.name,
+                arity
+            ),
+        };
+        Some(text)
+    }
+
+    #[allow(deprecated)]
+    pub fn symbols(&self) -> Vec<DocumentSymbol> {
+        let symbol = |name: &str, kind, span, children| DocumentSymbol {
+            name: String::from(name),
+            detail: None,
+            kind,
+            tags: None,
+            deprecated: None,
+            range: self.to_range(span),
+            selection_range: self.to_range(span),
+            children,
+        };
+
+        self.statements
+            .iter()
+            .flat_map(|stmt| match stmt {
+                Stmt::Var(name, _, _) => {
+                    vec![symbol(&name.name, SymbolKind::VARIABLE, name.span, None)]
+                }
+                Stmt::VarUnpack(_, names, _) => names
+                    .iter()
+                    .map(|(name, _)| symbol(&name.name, SymbolKind::VARIABLE, name.span, None))
+                    .collect(),
+                Stmt::Import(name, _) => {
+                    vec![symbol(&name.name, SymbolKind::MODULE, name.span, None)]
+                }
+                Stmt::Function(function) => vec![symbol(
+                    &function.name.name,
+                    SymbolKind::FUNCTION,
+                    function.name.span,
+                    None,
+                )],
+                Stmt::Class(class) => {
+                    let methods = class
+                        .all_methods()
+                        .map(|m| symbol(&m.name.name, SymbolKind::METHOD, m.name.span, None))
+                        .collect();
+                    vec![symbol(
+                        &class.name.name,
+                        SymbolKind::CLASS,
+                        class.name.span,
+                        Some(methods),
+                    )]
+                }
+                _ => vec![],
+            })
+            .collect()
+    }
+
+    //Our lines and columns are 1-based and count chars, LSP positions are
+    //0-based and count UTF-16 code units.
+    fn line_and_column(&self, position: Position) -> (usize, usize) {
+        let line = self.line(position.line as usize);
+        let character = position.character as usize;
+        //A position inside a surrogate pair is on the char it belongs to
+        let mut units = 0;
+        let mut chars = 0;
+        for c in line.chars() {
+            units += c.len_utf16();
+            if units > character {
+                break;
+            }
+            chars += 1;
+        }
+        (position.line as usize + 1, chars + 1)
+    }
+
+    fn to_range(&self, span: Span) -> Range {
+        let line = span.line.saturating_sub(1);
+        let text = self.line(line);
+        let column = span.column.saturating_sub(1);
+        Range {
+            start: Position::new(line as u32, utf16_length(text, column)),
+            end: Position::new(line as u32, utf16_length(text, column + span.length)),
+        }
+    }
+
+    fn line(&self, line: usize) -> &str {
+        self.lines.get(line).map(String::as_str).unwrap_or("")
+    }
+}
+
+//Of the first chars of line, with any past its end counted as one unit each
+fn utf16_length(line: &str, chars: usize) -> u32 {
+    let units: usize = line.chars().take(chars).map(char::len_utf16).sum();
+    (units + chars.saturating_sub(line.chars().count())) as u32
+}
+
+#[cfg(test)]
+mod document_tests {
+    use super::*;
+
+    const SOURCE: &str = "fun add(a, b) {\n  return a + b;\n}\nclass Point {\n  init(x, y) { this.x = x; }\n  norm() { return 0; }\n}\nvar p = Point(1, 2);\nprint add(p.norm(), 1);\nvar q, r;\n";
+
+    #[test]
+    fn reports_compiler_errors() {
+        let document = Document::new("var a = ;\nprint 1");
+        let diagnostics = document.diagnostics();
+        assert!(!diagnostics.is_empty());
+        assert_eq!(diagnostics[0].range.start, Position::new(0, 8));
+    }
+
+    #[test]
+    fn goes_to_definitions() {
+        let document = Document::new(SOURCE);
+        //`add` in the print statement
+        let range = document.definition(Position::new(8, 7)).unwrap();
+        assert_eq!(range.start, Position::new(0, 4));
+        //`b` in the function body
+        let range = document.definition(Position::new(1, 13)).unwrap();
+        assert_eq!(range.start, Position::new(0, 11));
+        //`norm` through a property access
+        let range = document.definition(Position::new(8, 12)).unwrap();
+        assert_eq!(range.start, Position::new(5, 2));
+    }
+
+    #[test]
+    fn hovers_with_arity() {
+        let document = Document::new(SOURCE);
+        assert_eq!(
+            document.hover(Position::new(7, 9)).as_deref(),
+            Some("class Point (init/2)")
+        );
+        assert_eq!(
+            document.hover(Position::new(8, 12)).as_deref(),
+            Some("method Point.norm/0")
+        );
+    }
+
+    #[test]
+    fn counts_utf16_code_units() {
+        //Both take two UTF-16 code units but are one char to the scanner
+        let document = Document::new("var s = \"😀\"; var 𝒳 = s;\nprint 𝒳;\n");
+        let range = document.definition(Position::new(0, 23)).unwrap();
+        assert_eq!(range, Range::new(Position::new(0, 4), Position::new(0, 5)));
+        let range = document.definition(Position::new(1, 6)).unwrap();
+        assert_eq!(range, Range::new(Position::new(0, 18), Position::new(0, 20)));
+        assert_eq!(
+            document.hover(Position::new(1, 7)).as_deref(),
+            Some("var 𝒳 (global)")
+        );
+        assert_eq!(document.symbols()[1].range.start, Position::new(0, 18));
+    }
+
+    #[test]
+    fn lists_symbols() {
+        let document = Document::new(SOURCE);
+        let symbols = document.symbols();
+        let names: Vec<&str> = symbols.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["add", "Point", "p", "q", "r"]);
+        assert_eq!(symbols[1].children.as_ref().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,169 @@
+mod document;
+
+use document::Document;
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationTrait, PublishDiagnostics,
+};
+use lsp_types::request::{DocumentSymbolRequest, GotoDefinition, HoverRequest};
+use lsp_types::{
+    DocumentSymbolResponse, GotoDefinitionResponse, Hover, HoverContents, HoverProviderCapability,
+    Location, MarkedString, OneOf, PublishDiagnosticsParams, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use std::collections::HashMap;
+use std::error::Error;
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Sync + Send>>;
+
+struct Server {
+    connection: Connection,
+    documents: HashMap<Url, Document>,
+}
+
+fn main() -> Result<()> {
+    //stdout carries the protocol so anything for humans goes to stderr.
+    eprintln!("lox-lsp starting");
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let server = Server {
+        connection,
+        documents: HashMap::new(),
+    };
+    //The server owns the connection and must be gone before the io threads can finish.
+    server.run()?;
+    io_threads.join()?;
+    eprintln!("lox-lsp stopped");
+    Ok(())
+}
+
+impl Server {
+    fn run(mut self) -> Result<()> {
+        while let Ok(message) = self.connection.receiver.recv() {
+            match message {
+                Message::Request(request) => {
+                    if self.connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    self.request(request)?;
+                }
+                Message::Notification(notification) => self.notification(notification)?,
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&mut self, request: Request) -> Result<()> {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            <GotoDefinition as lsp_types::request::Request>::METHOD => {
+                let (_, params) = request.extract::<lsp_types::GotoDefinitionParams>(
+                    <GotoDefinition as lsp_types::request::Request>::METHOD,
+                )?;
+                let position = params.text_document_position_params;
+                let (uri, position) = (position.text_document.uri, position.position);
+                let range = self
+                    .documents
+                    .get(&uri)
+                    .and_then(|d| d.definition(position));
+                serde_json::to_value(
+                    range.map(|range| GotoDefinitionResponse::Scalar(Location { uri, range })),
+                )?
+            }
+            <HoverRequest as lsp_types::request::Request>::METHOD => {
+                let (_, params) = request.extract::<lsp_types::HoverParams>(
+                    <HoverRequest as lsp_types::request::Request>::METHOD,
+                )?;
+                let position = params.text_document_position_params;
+                let text = self
+                    .documents
+                    .get(&position.text_document.uri)
+                    .and_then(|d| d.hover(position.position));
+                serde_json::to_value(text.map(|text| Hover {
+                    contents: HoverContents::Scalar(MarkedString::String(text)),
+                    range: None,
+                }))?
+            }
+            <DocumentSymbolRequest as lsp_types::request::Request>::METHOD => {
+                let (_, params) = request.extract::<lsp_types::DocumentSymbolParams>(
+                    <DocumentSymbolRequest as lsp_types::request::Request>::METHOD,
+                )?;
+                let symbols = self
+                    .documents
+                    .get(&params.text_document.uri)
+                    .map(|d| DocumentSymbolResponse::Nested(d.symbols()));
+                serde_json::to_value(symbols)?
+            }
+            _ => return self.respond_unknown(id, &request.method),
+        };
+
+        self.send(Message::Response(Response::new_ok(id, result)))
+    }
+
+    fn respond_unknown(&self, id: RequestId, method: &str) -> Result<()> {
+        let response = Response::new_err(
+            id,
+            lsp_server::ErrorCode::MethodNotFound as i32,
+            format!("Unsupported request {}", method),
+        );
+        self.send(Message::Response(response))
+    }
+
+    fn notification(&mut self, notification: Notification) -> Result<()> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: lsp_types::DidOpenTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let document = params.text_document;
+                self.update(document.uri, &document.text)
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: lsp_types::DidChangeTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                //We only ask for full syncs, so the last change is the whole file.
+                match params.content_changes.last() {
+                    Some(change) => self.update(params.text_document.uri, &change.text),
+                    None => Ok(()),
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: lsp_types::DidCloseTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                self.documents.remove(&params.text_document.uri);
+                self.publish(params.text_document.uri, vec![])
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn update(&mut self, uri: Url, text: &str) -> Result<()> {
+        let document = Document::new(text);
+        let diagnostics = document.diagnostics();
+        self.documents.insert(uri.clone(), document);
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(&self, uri: Url, diagnostics: Vec<lsp_types::Diagnostic>) -> Result<()> {
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        self.send(Message::Notification(Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            params,
+        )))
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        self.connection.sender.send(message)?;
+        Ok(())
+    }
+}
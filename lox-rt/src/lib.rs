@@ -0,0 +1,431 @@
+// The runtime for natively compiled Lox programs. Generated code keeps its
+// values on the stack in here and calls one of these functions for every
+// instruction, the same stack discipline the VM uses. Memory is reference
+// counted: without closures or classes a program can't build a cycle.
+//
+// Every function is only ever called by code from lox-vm build, which passes
+// the runtime it was given and pointers into its own read only data.
+#![allow(clippy::missing_safety_doc)]
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::{slice, str};
+
+const MAX_CALL_DEPTH: usize = 256;
+
+//What generated functions return
+const OK: i32 = 0;
+const ERROR: i32 = 1;
+
+pub type Code = unsafe extern "C" fn(*mut Runtime) -> i32;
+
+pub struct Function {
+    name: String,
+    arity: usize,
+    code: Code,
+}
+
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Integer(i64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Function(fun) => write!(f, "<fn {}>", fun.name),
+        }
+    }
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+}
+
+#[derive(Default)]
+pub struct Runtime {
+    stack: Vec<Value>,
+    //Stack index of slot zero for each active call, the script's first
+    frames: Vec<usize>,
+    globals: HashMap<String, Value>,
+    error: Option<(usize, String)>,
+}
+
+impl Runtime {
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("Runtime stack underflow")
+    }
+
+    fn peek(&self, look_back: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - look_back]
+    }
+
+    fn fail(&mut self, line: usize, message: String) -> i32 {
+        self.error = Some((line, message));
+        ERROR
+    }
+
+    fn number_operands(&mut self, line: usize) -> Result<(f64, f64), i32> {
+        match (number(&self.pop()), number(&self.pop())) {
+            (Some(b), Some(a)) => Ok((a, b)),
+            _ => Err(self.fail(line, String::from("Expected a number"))),
+        }
+    }
+}
+
+//Integers count as numbers wherever a float is expected
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => Some(*n),
+        Value::Integer(n) => Some(*n as f64),
+        _ => None,
+    }
+}
+
+unsafe fn text<'a>(ptr: *const u8, len: usize) -> &'a str {
+    str::from_utf8_unchecked(slice::from_raw_parts(ptr, len))
+}
+
+// Runs a compiled script and returns the process exit code.
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_run(main: Code) -> i32 {
+    let mut runtime = Runtime::default();
+    runtime.frames.push(0);
+    if main(&mut runtime) == OK {
+        return 0;
+    }
+    let (line, message) = runtime.error.take().unwrap_or_default();
+    eprintln!("Runtime Error: {}: {}", line, message);
+    70
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_nil(rt: *mut Runtime) {
+    (*rt).push(Value::Nil);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_bool(rt: *mut Runtime, b: i32) {
+    (*rt).push(Value::Boolean(b != 0));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_number(rt: *mut Runtime, n: f64) {
+    (*rt).push(Value::Number(n));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_integer(rt: *mut Runtime, n: i64) {
+    (*rt).push(Value::Integer(n));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_string(rt: *mut Runtime, ptr: *const u8, len: usize) {
+    (*rt).push(Value::String(Rc::from(text(ptr, len))));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_function(
+    rt: *mut Runtime,
+    code: Code,
+    name: *const u8,
+    name_len: usize,
+    arity: usize,
+) {
+    (*rt).push(Value::Function(Rc::new(Function {
+        name: String::from(text(name, name_len)),
+        arity,
+        code,
+    })));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_pop(rt: *mut Runtime) {
+    (*rt).pop();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_print(rt: *mut Runtime) {
+    println!("{}", (*rt).pop());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_truthy(rt: *mut Runtime) -> i32 {
+    (*rt).peek(0).is_truthy() as i32
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_not(rt: *mut Runtime) {
+    let value = (*rt).pop();
+    (*rt).push(Value::Boolean(!value.is_truthy()));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_equal(rt: *mut Runtime) {
+    let rt = &mut *rt;
+    let b = rt.pop();
+    let a = rt.pop();
+    let equal = match (a, b) {
+        (Value::Nil, Value::Nil) => true,
+        (Value::Boolean(a), Value::Boolean(b)) => a == b,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        (Value::Integer(a), Value::Integer(b)) => a == b,
+        (Value::Integer(a), Value::Number(b)) | (Value::Number(b), Value::Integer(a)) => {
+            a as f64 == b
+        }
+        (Value::String(a), Value::String(b)) => a == b,
+        _ => false,
+    };
+    rt.push(Value::Boolean(equal));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_negate(rt: *mut Runtime, line: usize) -> i32 {
+    let rt = &mut *rt;
+    match rt.pop() {
+        Value::Number(n) => {
+            rt.push(Value::Number(-n));
+            OK
+        }
+        Value::Integer(n) => {
+            rt.push(match n.checked_neg() {
+                Some(n) => Value::Integer(n),
+                None => Value::Number(-(n as f64)),
+            });
+            OK
+        }
+        _ => rt.fail(line, String::from("Operand must be a number.")),
+    }
+}
+
+pub const ADD: i32 = 0;
+pub const SUBTRACT: i32 = 1;
+pub const MULTIPLY: i32 = 2;
+pub const DIVIDE: i32 = 3;
+pub const GREATER: i32 = 4;
+pub const LESS: i32 = 5;
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_binary(rt: *mut Runtime, op: i32, line: usize) -> i32 {
+    let rt = &mut *rt;
+    if op == ADD {
+        if let (Value::String(a), Value::String(b)) = (rt.peek(1), rt.peek(0)) {
+            let joined = format!("{}{}", a, b);
+            rt.pop();
+            rt.pop();
+            rt.push(Value::String(Rc::from(joined)));
+            return OK;
+        }
+    }
+
+    //Integers stay exact unless they overflow, division always gives a float
+    if let (Value::Integer(a), Value::Integer(b)) = (rt.peek(1), rt.peek(0)) {
+        let (a, b) = (*a, *b);
+        let result = match op {
+            ADD => a.checked_add(b).map(Value::Integer),
+            SUBTRACT => a.checked_sub(b).map(Value::Integer),
+            MULTIPLY => a.checked_mul(b).map(Value::Integer),
+            GREATER => Some(Value::Boolean(a > b)),
+            LESS => Some(Value::Boolean(a < b)),
+            _ => None,
+        };
+        if let Some(result) = result {
+            rt.pop();
+            rt.pop();
+            rt.push(result);
+            return OK;
+        }
+    }
+
+    let (a, b) = match rt.number_operands(line) {
+        Ok(operands) => operands,
+        Err(status) => return status,
+    };
+    rt.push(match op {
+        ADD => Value::Number(a + b),
+        SUBTRACT => Value::Number(a - b),
+        MULTIPLY => Value::Number(a * b),
+        DIVIDE => Value::Number(a / b),
+        GREATER => Value::Boolean(a > b),
+        LESS => Value::Boolean(a < b),
+        _ => panic!("Unknown binary operator {}", op),
+    });
+    OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_get_local(rt: *mut Runtime, slot: usize) {
+    let rt = &mut *rt;
+    let base = *rt.frames.last().unwrap();
+    let value = rt.stack[base + slot].clone();
+    rt.push(value);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_set_local(rt: *mut Runtime, slot: usize) {
+    let rt = &mut *rt;
+    let base = *rt.frames.last().unwrap();
+    rt.stack[base + slot] = rt.peek(0).clone();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_define_global(rt: *mut Runtime, name: *const u8, len: usize) {
+    let value = (*rt).pop();
+    (*rt).globals.insert(String::from(text(name, len)), value);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_get_global(
+    rt: *mut Runtime,
+    name: *const u8,
+    len: usize,
+    line: usize,
+) -> i32 {
+    let rt = &mut *rt;
+    let name = text(name, len);
+    match rt.globals.get(name).cloned() {
+        Some(value) => {
+            rt.push(value);
+            OK
+        }
+        None => rt.fail(line, format!("Undefined variable {}", name)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_set_global(
+    rt: *mut Runtime,
+    name: *const u8,
+    len: usize,
+    line: usize,
+) -> i32 {
+    let rt = &mut *rt;
+    let name = text(name, len);
+    if !rt.globals.contains_key(name) {
+        return rt.fail(line, format!("Undefined variable {}", name));
+    }
+    let value = rt.peek(0).clone();
+    rt.globals.insert(String::from(name), value);
+    OK
+}
+
+// The callee sits below a placeholder slot and the arguments, as in the VM.
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_call(rt: *mut Runtime, arg_count: usize, line: usize) -> i32 {
+    let runtime = &mut *rt;
+    let function = match runtime.peek(arg_count + 1) {
+        Value::Function(function) => function.clone(),
+        _ => {
+            return runtime.fail(
+                line,
+                String::from("Attempt to call a value which is not a function"),
+            )
+        }
+    };
+    if function.arity != arg_count {
+        return runtime.fail(
+            line,
+            format!(
+                "Expected {} arguments but got {}",
+                function.arity, arg_count
+            ),
+        );
+    }
+    if runtime.frames.len() > MAX_CALL_DEPTH {
+        return runtime.fail(line, String::from("Stack overflow"));
+    }
+
+    runtime.frames.push(runtime.stack.len() - (arg_count + 1));
+    (function.code)(rt)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lox_rt_return(rt: *mut Runtime) {
+    let rt = &mut *rt;
+    let result = rt.pop();
+    let base = rt.frames.pop().unwrap();
+    rt.stack.truncate(base - 1); //The callee goes too
+    rt.push(result);
+}
+
+#[cfg(test)]
+mod runtime_tests {
+    use super::*;
+
+    unsafe extern "C" fn double(rt: *mut Runtime) -> i32 {
+        lox_rt_get_local(rt, 1);
+        lox_rt_number(rt, 2.0);
+        let status = lox_rt_binary(rt, MULTIPLY, 1);
+        lox_rt_return(rt);
+        status
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        let mut runtime = Runtime::default();
+        runtime.frames.push(0);
+        let name = "double";
+        unsafe {
+            lox_rt_function(&mut runtime, double, name.as_ptr(), name.len(), 1);
+            lox_rt_nil(&mut runtime);
+            lox_rt_number(&mut runtime, 21.0);
+            assert_eq!(lox_rt_call(&mut runtime, 1, 1), OK);
+        }
+        assert_eq!(runtime.stack.len(), 1);
+        assert_eq!(format!("{}", runtime.stack[0]), "42");
+    }
+
+    #[test]
+    fn keeps_integers_exact() {
+        let mut runtime = Runtime::default();
+        runtime.frames.push(0);
+        unsafe {
+            lox_rt_integer(&mut runtime, 9007199254740993);
+            lox_rt_integer(&mut runtime, 2);
+            assert_eq!(lox_rt_binary(&mut runtime, ADD, 1), OK);
+            lox_rt_integer(&mut runtime, i64::MAX);
+            lox_rt_integer(&mut runtime, 2);
+            assert_eq!(lox_rt_binary(&mut runtime, MULTIPLY, 1), OK);
+            lox_rt_integer(&mut runtime, 1);
+            lox_rt_integer(&mut runtime, 2);
+            assert_eq!(lox_rt_binary(&mut runtime, DIVIDE, 1), OK);
+        }
+        let printed: Vec<String> = runtime.stack.iter().map(|v| v.to_string()).collect();
+        assert_eq!(printed, vec!["9007199254740995", "18446744073709552000", "0.5"]);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut runtime = Runtime::default();
+        runtime.frames.push(0);
+        unsafe {
+            lox_rt_string(&mut runtime, "a".as_ptr(), 1);
+            lox_rt_number(&mut runtime, 1.0);
+            assert_eq!(lox_rt_binary(&mut runtime, ADD, 3), ERROR);
+            assert_eq!(runtime.error, Some((3, String::from("Expected a number"))));
+
+            lox_rt_number(&mut runtime, 5.0);
+            lox_rt_nil(&mut runtime);
+            assert_eq!(lox_rt_call(&mut runtime, 0, 4), ERROR);
+        }
+        let message = String::from("Attempt to call a value which is not a function");
+        assert_eq!(runtime.error, Some((4, message)));
+    }
+}
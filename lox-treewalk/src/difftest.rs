@@ -0,0 +1,209 @@
+// Differential testing: run a program on the VM and on the tree walker and
+// compare what each printed and how each stopped. The tree walker is simple
+// enough to trust, so a divergence usually points at a bug in the VM.
+
+use crate::interpreter::Interpreter;
+use lox_vm::compiler::Compiler;
+use lox_vm::interpreter::{VirtualMemory, VM};
+use lox_vm::{parser, scanner};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::thread;
+
+//Walking the tree recurses deeply, a call in Lox is a dozen calls in Rust
+const TREEWALK_STACK_SIZE: usize = 256 * 1024 * 1024;
+
+#[derive(Clone)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Capture {
+    fn new() -> Capture {
+        Capture(Rc::new(RefCell::new(vec![])))
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ending {
+    Finished,
+    RuntimeError(usize, String),
+    Panic(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub output: String,
+    pub ending: Ending,
+}
+
+pub enum Report {
+    //The program doesn't compile, so there's nothing to compare
+    Rejected(Vec<String>),
+    Agreed(Outcome),
+    Diverged { vm: Outcome, treewalk: Outcome },
+}
+
+// Scans and checks a program the way the VM would, returning error messages.
+pub fn check(source: &str) -> Result<Vec<lox_vm::ast::Stmt>, Vec<String>> {
+    let tokens = scanner::scan_tokens(&String::from(source)).map_err(|e| vec![e.to_string()])?;
+    let mut compiler = Compiler::new(tokens.clone(), VirtualMemory::new());
+    if let Err(errors) = compiler.compile() {
+        return Err(errors.iter().map(|e| e.to_string()).collect());
+    }
+    Ok(parser::parse(tokens).0)
+}
+
+pub fn run_vm(source: &str) -> Result<Outcome, Vec<String>> {
+    let tokens = scanner::scan_tokens(&String::from(source)).map_err(|e| vec![e.to_string()])?;
+    let mut vm = VM::new();
+    let mut compiler = Compiler::new(tokens, vm.take_virtual_memory());
+    let main = compiler
+        .compile()
+        .map_err(|errors| errors.iter().map(|e| e.to_string()).collect::<Vec<_>>())?;
+
+    let capture = Capture::new();
+    vm.set_output(Box::new(capture.clone()));
+    let heap = compiler.heap;
+    let ending = match panic::catch_unwind(AssertUnwindSafe(|| vm.interpret(main, heap))) {
+        Ok(Ok(())) => Ending::Finished,
+        Ok(Err(e)) => Ending::RuntimeError(e.line(), String::from(e.message())),
+        Err(payload) => Ending::Panic(panic_message(payload)),
+    };
+    Ok(Outcome {
+        output: capture.text(),
+        ending,
+    })
+}
+
+pub fn run_treewalk(source: &str) -> Result<Outcome, Vec<String>> {
+    let statements = check(source)?;
+    let worker = thread::Builder::new()
+        .stack_size(TREEWALK_STACK_SIZE)
+        .spawn(move || {
+            let capture = Capture::new();
+            let mut interpreter = Interpreter::new(Box::new(capture.clone()));
+            let ending = match interpreter.interpret(&statements) {
+                Ok(()) => Ending::Finished,
+                Err(e) => Ending::RuntimeError(e.line, e.message),
+            };
+            Outcome {
+                output: capture.text(),
+                ending,
+            }
+        })
+        .expect("Failed to start the interpreter thread");
+    worker
+        .join()
+        .map_err(|payload| vec![format!("Tree walker panicked: {}", panic_message(payload))])
+}
+
+pub fn difftest(source: &str) -> Report {
+    let vm = match run_vm(source) {
+        Ok(outcome) => outcome,
+        Err(errors) => return Report::Rejected(errors),
+    };
+    let treewalk = match run_treewalk(source) {
+        Ok(outcome) => outcome,
+        Err(errors) => return Report::Rejected(errors),
+    };
+    if vm == treewalk {
+        Report::Agreed(vm)
+    } else {
+        Report::Diverged { vm, treewalk }
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        String::from(*message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+#[cfg(test)]
+mod difftest_tests {
+    use super::*;
+    use lox_vm::generator::{self, Config};
+
+    fn treewalk_output(source: &str) -> String {
+        run_treewalk(source).unwrap().output
+    }
+
+    #[test]
+    fn closures_see_variables_from_where_they_were_declared() {
+        let source = "var a = \"global\";
+{
+  fun show() { print a; }
+  show();
+  var a = \"block\";
+  show();
+}";
+        assert_eq!(treewalk_output(source), "global\nglobal\n");
+    }
+
+    #[test]
+    fn classes() {
+        let source = "class A { init(n) { this.n = n; } get() { return this.n; } }
+class B < A { twice() { return this.get() * 2; } }
+var b = B(21);
+var m = b.twice;
+print m();
+print b;
+print B(1) == B(1);";
+        assert_eq!(treewalk_output(source), "42\nB instance\nfalse\n");
+    }
+
+    #[test]
+    fn agrees_with_the_vm() {
+        let source = "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+for (var i = 0; i < 10; i = i + 1) print fib(i);
+print \"a\" + -nil;";
+        match difftest(source) {
+            Report::Agreed(outcome) => {
+                assert_eq!(
+                    outcome.ending,
+                    Ending::RuntimeError(3, String::from("Operand must be a number."))
+                );
+            }
+            _ => panic!("Expected the engines to agree"),
+        }
+    }
+
+    #[test]
+    fn generated_programs_finish() {
+        let config = Config {
+            error_rate: 0,
+            ..Config::default()
+        };
+        for seed in 0..100 {
+            let source = generator::generate(seed, &config);
+            let outcome = run_treewalk(&source).unwrap();
+            assert_eq!(
+                outcome.ending,
+                Ending::Finished,
+                "seed {}\n{}",
+                seed,
+                source
+            );
+        }
+    }
+}
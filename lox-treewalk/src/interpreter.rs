@@ -0,0 +1,1397 @@
+use lox_vm::ast::*;
+use lox_vm::natives::STRING_METHODS;
+use lox_vm::value::{GeneratorState, GENERATOR_METHODS};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+// A jlox style interpreter that walks the syntax tree directly. It's slow but
+// simple enough to trust, which makes it a reference for the VM: error
+// messages follow the VM's wording so the two can be compared line for line.
+
+pub const MAX_CALL_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl RuntimeError {
+    fn new(line: usize, message: &str) -> RuntimeError {
+        RuntimeError {
+            line,
+            message: String::from(message),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+pub struct Function {
+    decl: Rc<FunctionDecl>,
+    closure: Option<Rc<Scope>>,
+    is_initializer: bool,
+}
+
+pub struct Class {
+    name: String,
+    //Global classes gain methods when declared again
+    methods: RefCell<Methods>,
+    //Run in place of reading or writing a property
+    getters: RefCell<Methods>,
+    setters: RefCell<Methods>,
+    //Called on the class itself, with it as 'this'
+    statics: RefCell<Methods>,
+}
+
+type Methods = HashMap<String, Rc<Function>>;
+
+pub struct Instance {
+    class: Rc<Class>,
+    fields: HashMap<String, Value>,
+}
+
+pub struct Generator {
+    decl: Rc<FunctionDecl>,
+    scope: Option<Rc<Scope>>, //With the parameters, for the first next()
+    //The way back to the last yield, innermost first
+    resume: Vec<Resume>,
+    state: GeneratorState,
+}
+
+// Yields only ever happen between statements, so a generator picks up again
+// by going back down the statements it was in, one entry for each that can't
+// just start over, then carrying on from the yield.
+enum Resume {
+    Yield(Option<Rc<Scope>>), //With the scope it was in, which every block within shares
+    Statement(usize),         //Position in a list of statements
+    Scope(Option<Rc<Scope>>), //What a block goes back to when it ends
+    Branch(bool),             //Which way an if went
+    Case(Option<usize>),      //Which switch case ran, None for the default
+}
+
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Integer(i64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
+    List(List),
+    Tuple(List), //Never changed, but shares List's cycle check when printing
+    Generator(Rc<RefCell<Generator>>),
+}
+
+type List = Rc<RefCell<Vec<Value>>>;
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Function(fun) => write!(f, "<fn {}>", fun.decl.name.name),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
+            //It's already borrowed while one of its own items is being written
+            Value::List(list) if list.try_borrow_mut().is_err() => write!(f, "[...]"),
+            Value::List(list) => {
+                let items: Vec<String> = list.borrow().iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Tuple(tuple) if tuple.try_borrow_mut().is_err() => write!(f, "(...)"),
+            Value::Tuple(tuple) => {
+                let items: Vec<String> = tuple.borrow().iter().map(|v| v.to_string()).collect();
+                write!(f, "({})", items.join(", "))
+            }
+            Value::Generator(generator) => {
+                write!(f, "<generator {}>", generator.borrow().decl.name.name)
+            }
+        }
+    }
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Integer(a), Value::Number(b)) | (Value::Number(b), Value::Integer(a)) => {
+                *a as f64 == *b
+            }
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) | (Value::Tuple(a), Value::Tuple(b)) => {
+                Rc::ptr_eq(a, b)
+            }
+            (Value::Generator(a), Value::Generator(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    //Whether it's of the type a match pattern names, where a class only
+    //matches its own instances
+    fn is_type(&self, name: &str) -> bool {
+        match name {
+            "Nil" => matches!(self, Value::Nil),
+            "Bool" => matches!(self, Value::Boolean(_)),
+            "Number" => matches!(self, Value::Number(_) | Value::Integer(_)),
+            "String" => matches!(self, Value::String(_)),
+            "List" => matches!(self, Value::List(_)),
+            "Tuple" => matches!(self, Value::Tuple(_)),
+            "Class" => matches!(self, Value::Class(_)),
+            "Function" => matches!(self, Value::Function(_)),
+            "Generator" => matches!(self, Value::Generator(_)),
+            "Module" => false,
+            _ => match self {
+                Value::Instance(instance) => instance.borrow().class.name == name,
+                _ => false,
+            },
+        }
+    }
+
+    //Anything that lives on the VM's heap
+    fn is_object(&self) -> bool {
+        !matches!(
+            self,
+            Value::Nil | Value::Boolean(_) | Value::Number(_) | Value::Integer(_)
+        )
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Integer(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
+
+    //Floats are truncated, for the bitwise operators
+    fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Number(n) => Some(*n as i64),
+            Value::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+// Local variables form a linked list with one link per declaration. A closure
+// holds on to the link current when it was made, so it never sees variables
+// declared after it, just as if they had been resolved ahead of time.
+struct Scope {
+    name: String,
+    value: RefCell<Value>,
+    parent: Option<Rc<Scope>>,
+}
+
+impl Scope {
+    fn find(scope: &Option<Rc<Scope>>, name: &str) -> Option<Rc<Scope>> {
+        let mut current = scope.clone();
+        while let Some(link) = current {
+            if link.name == name {
+                return Some(link);
+            }
+            current = link.parent.clone();
+        }
+        None
+    }
+}
+
+enum Unwind {
+    Return(Value),
+    Break(Option<String>), //The label of the loop to leave, or the innermost
+    Continue(Option<String>),
+    Throw(usize, Value), //Line of the throw
+    Yield(Value, Vec<Resume>),
+    Error(RuntimeError),
+    //Like the VM's limits, scripts can't catch these
+    Limit(RuntimeError),
+}
+
+impl From<RuntimeError> for Unwind {
+    fn from(e: RuntimeError) -> Unwind {
+        Unwind::Error(e)
+    }
+}
+
+type Exec<T> = Result<T, Unwind>;
+
+pub struct Interpreter {
+    globals: HashMap<String, Value>,
+    scope: Option<Rc<Scope>>,
+    depth: usize, //Blocks and functions we're inside, zero for global code
+    call_depth: usize,
+    //Left of a generator's way back to its last yield, while it's being picked up
+    resume: Vec<Resume>,
+    out: Box<dyn Write>,
+}
+
+impl Interpreter {
+    pub fn new(out: Box<dyn Write>) -> Interpreter {
+        Interpreter {
+            globals: HashMap::new(),
+            scope: None,
+            depth: 0,
+            call_depth: 0,
+            resume: vec![],
+            out,
+        }
+    }
+
+    pub fn interpret(&mut self, statements: &[Stmt]) -> Result<(), RuntimeError> {
+        for statement in statements {
+            match self.execute(statement) {
+                //Break and continue outside of a loop don't compile
+                Ok(())
+                | Err(Unwind::Return(_))
+                | Err(Unwind::Yield(_, _))
+                | Err(Unwind::Break(_))
+                | Err(Unwind::Continue(_)) => {}
+                Err(Unwind::Error(e)) | Err(Unwind::Limit(e)) => return Err(e),
+                Err(Unwind::Throw(line, value)) => {
+                    return Err(RuntimeError::new(line, &value.to_string()))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, value: Value) {
+        if self.depth == 0 {
+            self.globals.insert(String::from(name), value);
+        } else {
+            self.scope = Some(Rc::new(Scope {
+                name: String::from(name),
+                value: RefCell::new(value),
+                parent: self.scope.take(),
+            }));
+        }
+    }
+
+    fn execute(&mut self, statement: &Stmt) -> Exec<()> {
+        match statement {
+            Stmt::Expression(expr) => {
+                self.evaluate(expr)?;
+            }
+            Stmt::Print(expr) => {
+                let value = self.evaluate(expr)?;
+                let value = self.call_to_string(value, expr.span.line)?;
+                writeln!(self.out, "{}", value).expect("Failed to write program output");
+            }
+            Stmt::Var(name, _, initializer) => {
+                let value = match initializer {
+                    Some(expr) => self.evaluate(expr)?,
+                    None => Value::Nil,
+                };
+                self.define(&name.name, value);
+            }
+            Stmt::VarUnpack(unpacking, names, initializer) => {
+                let line = names[0].0.span.line;
+                let values = match initializer {
+                    Some(expr) => unpack(self.evaluate(expr)?, *unpacking, names, line)?,
+                    None => vec![Value::Nil; names.len()],
+                };
+                for ((name, _), value) in names.iter().zip(values) {
+                    self.define(&name.name, value);
+                }
+            }
+            Stmt::Block(statements, _) => {
+                self.block(|interpreter| interpreter.execute_all(statements))?
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                let branch = match self.resume.pop() {
+                    Some(Resume::Branch(branch)) => branch,
+                    _ => self.evaluate(condition)?.is_truthy(),
+                };
+                let result = match (branch, else_branch) {
+                    (true, _) => self.execute(then_branch),
+                    (false, Some(else_branch)) => self.execute(else_branch),
+                    (false, None) => Ok(()),
+                };
+                result.map_err(|e| suspended(e, Resume::Branch(branch)))?;
+            }
+            Stmt::While(..) | Stmt::DoWhile(..) | Stmt::For(..) => {
+                self.execute_loop(statement, None)?
+            }
+            Stmt::Labeled(label, body) => self.execute_loop(body, Some(&label.name))?,
+            Stmt::Switch(value, cases, default) => {
+                let case = match self.resume.pop() {
+                    Some(Resume::Case(case)) => case,
+                    _ => {
+                        let value = self.evaluate(value)?;
+                        let mut matched = None;
+                        for (i, (case, _)) in cases.iter().enumerate() {
+                            if self.evaluate(case)?.equals(&value) {
+                                matched = Some(i);
+                                break;
+                            }
+                        }
+                        matched
+                    }
+                };
+                let body = match case {
+                    Some(i) => Some(&cases[i].1),
+                    None => default.as_ref(),
+                };
+                if let Some(body) = body {
+                    self.block(|interpreter| interpreter.execute_all(body))
+                        .map_err(|e| suspended(e, Resume::Case(case)))?;
+                }
+            }
+            Stmt::Function(decl) => {
+                //Declared first so the function can call itself
+                self.define(&decl.name.name, Value::Nil);
+                let function = self.function(decl, false);
+                self.assign_local_or_global(&decl.name.name, function);
+            }
+            Stmt::Return(_, value) => {
+                let value = match value {
+                    Some(expr) => self.evaluate(expr)?,
+                    None => Value::Nil,
+                };
+                return Err(Unwind::Return(value));
+            }
+            Stmt::Yield(_, value) => {
+                //Picked up again, carrying on after it
+                if let Some(Resume::Yield(_)) = self.resume.pop() {
+                    return Ok(());
+                }
+                let value = match value {
+                    Some(expr) => self.evaluate(expr)?,
+                    None => Value::Nil,
+                };
+                return Err(Unwind::Yield(value, vec![Resume::Yield(self.scope.clone())]));
+            }
+            Stmt::Try(body, catch, finally) => {
+                let mut result = self.block(|interpreter| interpreter.execute_all(body));
+                if let Some((name, handler)) = catch {
+                    //Runtime errors are caught as their message
+                    let error = match &result {
+                        Err(Unwind::Throw(_, value)) => Some(value.clone()),
+                        Err(Unwind::Error(e)) => Some(Value::String(Rc::from(e.message.as_str()))),
+                        _ => None,
+                    };
+                    if let Some(error) = error {
+                        result = self.block(|interpreter| {
+                            interpreter.define(&name.name, error);
+                            interpreter.execute_all(handler)
+                        });
+                    }
+                }
+                if let Some(finally) = finally {
+                    self.block(|interpreter| interpreter.execute_all(finally))?;
+                }
+                result?;
+            }
+            Stmt::Throw(keyword, value) => {
+                let value = self.evaluate(value)?;
+                return Err(Unwind::Throw(keyword.line, value));
+            }
+            Stmt::Import(name, _) => {
+                let message = "Modules are only supported by the bytecode VM.";
+                return Err(RuntimeError::new(name.span.line, message).into());
+            }
+            Stmt::Break(_, label) => return Err(Unwind::Break(label_name(label))),
+            Stmt::Continue(_, label) => return Err(Unwind::Continue(label_name(label))),
+            Stmt::Class(decl) => self.class(decl)?,
+        }
+        Ok(())
+    }
+
+    fn execute_all(&mut self, statements: &[Stmt]) -> Exec<()> {
+        let start = match self.resume.pop() {
+            Some(Resume::Statement(i)) => i,
+            _ => 0,
+        };
+        for (i, statement) in statements.iter().enumerate().skip(start) {
+            self.execute(statement).map_err(|e| suspended(e, Resume::Statement(i)))?;
+        }
+        Ok(())
+    }
+
+    fn execute_loop(&mut self, statement: &Stmt, label: Option<&str>) -> Exec<()> {
+        match statement {
+            //Picked up in the body, so the condition waits for the next time round
+            Stmt::While(condition, body) => {
+                let mut resuming = !self.resume.is_empty();
+                while resuming || self.evaluate(condition)?.is_truthy() {
+                    resuming = false;
+                    if !self.loop_body(body, label)? {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            Stmt::DoWhile(body, condition) => {
+                while self.loop_body(body, label)? && self.evaluate(condition)?.is_truthy() {}
+                Ok(())
+            }
+            Stmt::For(initializer, condition, increment, body) => self.block(|interpreter| {
+                let mut resuming = !interpreter.resume.is_empty();
+                if let Some(initializer) = initializer.as_ref().filter(|_| !resuming) {
+                    interpreter.execute(initializer)?;
+                }
+                loop {
+                    if let Some(condition) = condition {
+                        if !resuming && !interpreter.evaluate(condition)?.is_truthy() {
+                            break;
+                        }
+                    }
+                    resuming = false;
+                    if !interpreter.loop_body(body, label)? {
+                        break;
+                    }
+                    if let Some(increment) = increment {
+                        interpreter.evaluate(increment)?;
+                    }
+                }
+                Ok(())
+            }),
+            _ => self.execute(statement),
+        }
+    }
+
+    // Runs a loop's body once, telling whether the loop goes on. A break or
+    // continue naming another loop passes through to it.
+    fn loop_body(&mut self, body: &Stmt, label: Option<&str>) -> Exec<bool> {
+        match self.execute(body) {
+            Ok(()) => Ok(true),
+            Err(Unwind::Continue(target)) if targets(&target, label) => Ok(true),
+            Err(Unwind::Break(target)) if targets(&target, label) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Runs f one level deeper, dropping whatever it declared afterwards.
+    fn block<F>(&mut self, f: F) -> Exec<()>
+    where
+        F: FnOnce(&mut Interpreter) -> Exec<()>,
+    {
+        let scope = match self.resume.pop() {
+            Some(Resume::Scope(scope)) => scope,
+            _ => self.scope.clone(),
+        };
+        self.depth += 1;
+        let result = f(self).map_err(|e| suspended(e, Resume::Scope(scope.clone())));
+        self.depth -= 1;
+        self.scope = scope;
+        result
+    }
+
+    fn function(&self, decl: &FunctionDecl, is_initializer: bool) -> Value {
+        Value::Function(Rc::new(Function {
+            decl: Rc::new(decl.clone()),
+            closure: self.scope.clone(),
+            is_initializer,
+        }))
+    }
+
+    fn class(&mut self, decl: &ClassDecl) -> Exec<()> {
+        let superclass = self.superclass(decl)?;
+        let reopened = match self.globals.get(&decl.name.name) {
+            Some(Value::Class(class)) if self.depth == 0 => Some(class.clone()),
+            _ => None,
+        };
+        if reopened.is_none() {
+            self.define(&decl.name.name, Value::Nil);
+        }
+        let declared = self.class_value(decl, superclass);
+
+        if let Some(class) = reopened {
+            //Its own methods win over inherited ones, old or new
+            merge(&class.methods, &decl.methods, declared.methods.into_inner());
+            merge(&class.getters, &decl.getters, declared.getters.into_inner());
+            merge(&class.setters, &decl.setters, declared.setters.into_inner());
+            merge(&class.statics, &decl.statics, declared.statics.into_inner());
+            return Ok(());
+        }
+        self.assign_local_or_global(&decl.name.name, Value::Class(Rc::new(declared)));
+        Ok(())
+    }
+
+    //What 'super' refers to. The leftmost superclass with a method wins.
+    fn superclass(&mut self, decl: &ClassDecl) -> Exec<Option<Rc<Class>>> {
+        let mut superclasses = vec![];
+        for superclass in decl.superclasses.iter() {
+            match self.variable(superclass)? {
+                Value::Class(superclass) => superclasses.push(superclass),
+                _ => {
+                    let message = "Superclass must be a class object";
+                    return Err(RuntimeError::new(superclass.span.line, message).into());
+                }
+            }
+        }
+        Ok(match superclasses.len() {
+            0 => None,
+            1 => superclasses.pop(),
+            _ => {
+                let names: Vec<&str> = superclasses.iter().map(|c| c.name.as_str()).collect();
+                let merged = Class {
+                    name: names.join(", "),
+                    methods: RefCell::default(),
+                    getters: RefCell::default(),
+                    setters: RefCell::default(),
+                    statics: RefCell::default(),
+                };
+                for superclass in superclasses.iter().rev() {
+                    merged.methods.borrow_mut().extend(superclass.methods.borrow().clone());
+                    merged.getters.borrow_mut().extend(superclass.getters.borrow().clone());
+                    merged.setters.borrow_mut().extend(superclass.setters.borrow().clone());
+                    merged.statics.borrow_mut().extend(superclass.statics.borrow().clone());
+                }
+                Some(Rc::new(merged))
+            }
+        })
+    }
+
+    //The class decl declares, on top of what it inherits from superclass
+    fn class_value(&mut self, decl: &ClassDecl, superclass: Option<Rc<Class>>) -> Class {
+        let inherited = |methods: fn(&Class) -> &RefCell<Methods>| match &superclass {
+            Some(superclass) => methods(superclass).borrow().clone(),
+            None => Methods::new(),
+        };
+        let class = Class {
+            name: decl.name.name.clone(),
+            methods: RefCell::new(inherited(|c| &c.methods)),
+            getters: RefCell::new(inherited(|c| &c.getters)),
+            setters: RefCell::new(inherited(|c| &c.setters)),
+            statics: RefCell::new(inherited(|c| &c.statics)),
+        };
+        //Static methods can't use 'super'
+        self.declare_methods(&class.statics, &decl.statics, false);
+
+        let scope = self.scope.clone();
+        if let Some(superclass) = superclass {
+            self.scope = Some(Rc::new(Scope {
+                name: String::from("super"),
+                value: RefCell::new(Value::Class(superclass)),
+                parent: self.scope.take(),
+            }));
+        }
+        self.declare_methods(&class.methods, &decl.methods, true);
+        self.declare_methods(&class.getters, &decl.getters, false);
+        self.declare_methods(&class.setters, &decl.setters, false);
+        self.scope = scope;
+        class
+    }
+
+    fn declare_methods(&self, methods: &RefCell<Methods>, decls: &[FunctionDecl], init: bool) {
+        for method in decls.iter() {
+            let is_initializer = init && method.name.name == "init";
+            if let Value::Function(function) = self.function(method, is_initializer) {
+                methods.borrow_mut().insert(method.name.name.clone(), function);
+            }
+        }
+    }
+
+    fn assign_local_or_global(&mut self, name: &str, value: Value) {
+        match Scope::find(&self.scope, name) {
+            Some(link) => *link.value.borrow_mut() = value,
+            None => {
+                self.globals.insert(String::from(name), value);
+            }
+        }
+    }
+
+    fn assign(&mut self, name: &Identifier, value: Value) -> Exec<()> {
+        if let Some(link) = Scope::find(&self.scope, &name.name) {
+            *link.value.borrow_mut() = value;
+        } else if let Some(global) = self.globals.get_mut(&name.name) {
+            *global = value;
+        } else {
+            let message = format!("Undefined variable {}", name.name);
+            return Err(RuntimeError::new(name.span.line, &message).into());
+        }
+        Ok(())
+    }
+
+    fn variable(&self, name: &Identifier) -> Exec<Value> {
+        if let Some(link) = Scope::find(&self.scope, &name.name) {
+            return Ok(link.value.borrow().clone());
+        }
+        match self.globals.get(&name.name) {
+            Some(value) => Ok(value.clone()),
+            None => {
+                let message = format!("Undefined variable {}", name.name);
+                Err(RuntimeError::new(name.span.line, &message).into())
+            }
+        }
+    }
+
+    fn evaluate(&mut self, expr: &Expr) -> Exec<Value> {
+        let line = expr.span.line;
+        let value = match &expr.kind {
+            ExprKind::Literal(literal) => match literal {
+                Literal::Number(n) => Value::Number(*n),
+                Literal::Integer(n) => Value::Integer(*n),
+                Literal::String(s) => Value::String(Rc::from(s.as_str())),
+                Literal::Boolean(b) => Value::Boolean(*b),
+                Literal::Nil => Value::Nil,
+            },
+            ExprKind::Variable(name) => self.variable(name)?,
+            ExprKind::Assign(name, value) => {
+                let value = self.evaluate(value)?;
+                self.assign(name, value.clone())?;
+                value
+            }
+            ExprKind::Compound(target, op, value) => match &target.kind {
+                ExprKind::Variable(name) => {
+                    let current = self.variable(name)?;
+                    let value = self.evaluate(value)?;
+                    let result = binary(line, current, *op, value)?;
+                    self.assign(name, result.clone())?;
+                    result
+                }
+                ExprKind::Get(object, name) => match self.evaluate(object)? {
+                    Value::Instance(instance) => {
+                        let current = self.get_property(&instance, name)?;
+                        let value = self.evaluate(value)?;
+                        let result = binary(line, current, *op, value)?;
+                        self.set_property(&instance, name, result.clone())?;
+                        result
+                    }
+                    _ => return Err(not_an_instance(name).into()),
+                },
+                _ => unreachable!("Only variables and fields are compound assignment targets"),
+            },
+            ExprKind::Unary(op, operand) => {
+                let operand = self.evaluate(operand)?;
+                match (op, operand) {
+                    (UnaryOp::Not, operand) => Value::Boolean(!operand.is_truthy()),
+                    (UnaryOp::Negate, Value::Number(n)) => Value::Number(-n),
+                    (UnaryOp::Negate, Value::Integer(n)) => match n.checked_neg() {
+                        Some(n) => Value::Integer(n),
+                        None => Value::Number(-(n as f64)),
+                    },
+                    (UnaryOp::Negate, _) => {
+                        return Err(RuntimeError::new(line, "Operand must be a number.").into())
+                    }
+                    (UnaryOp::BitNot, operand) => match operand.as_integer() {
+                        Some(n) => Value::Integer(!n),
+                        None => return Err(RuntimeError::new(line, "Expected a number").into()),
+                    },
+                }
+            }
+            ExprKind::Binary(left, op, right) => {
+                let mut left = self.evaluate(left)?;
+                let mut right = self.evaluate(right)?;
+                if *op == BinaryOp::Add && left.is_object() && right.is_object() {
+                    left = self.call_to_string(left, line)?;
+                    right = self.call_to_string(right, line)?;
+                }
+                binary(line, left, *op, right)?
+            }
+            ExprKind::Logical(left, op, right) => {
+                let left = self.evaluate(left)?;
+                match (op, left.is_truthy()) {
+                    (LogicalOp::Or, true) | (LogicalOp::And, false) => left,
+                    _ => self.evaluate(right)?,
+                }
+            }
+            ExprKind::Conditional(condition, then_branch, else_branch) => {
+                if self.evaluate(condition)?.is_truthy() {
+                    self.evaluate(then_branch)?
+                } else {
+                    self.evaluate(else_branch)?
+                }
+            }
+            ExprKind::Interpolation(parts) => {
+                let mut s = String::new();
+                for part in parts {
+                    let value = self.evaluate(part)?;
+                    s.push_str(&self.call_to_string(value, line)?.to_string());
+                }
+                Value::String(Rc::from(s))
+            }
+            ExprKind::Grouping(inner) => self.evaluate(inner)?,
+            ExprKind::Call(callee, args) => {
+                //Lists have methods but nothing a Get could return
+                let callee = match &callee.kind {
+                    ExprKind::Get(object, name) => match self.evaluate(object)? {
+                        Value::List(list) => {
+                            let values = self.arguments(args)?;
+                            return Ok(list_method(&list, name, values, line)?);
+                        }
+                        Value::String(s) => {
+                            let values = self.arguments(args)?;
+                            return Ok(string_method(&s, name, values, line)?);
+                        }
+                        Value::Generator(generator) => {
+                            let values = self.arguments(args)?;
+                            return self.generator_method(&generator, name, values, line);
+                        }
+                        Value::Instance(instance) if invokes_getter(&instance, name) => {
+                            let message =
+                                format!("Getter {} can't be called like a method", name.name);
+                            return Err(RuntimeError::new(line, &message).into());
+                        }
+                        Value::Instance(instance) => self.get_property(&instance, name)?,
+                        Value::Class(class) => get_static(&class, name)?,
+                        _ => return Err(not_an_instance(name).into()),
+                    },
+                    _ => self.evaluate(callee)?,
+                };
+                let values = self.arguments(args)?;
+                self.call(callee, values, line)?
+            }
+            ExprKind::Get(object, name) => match self.evaluate(object)? {
+                Value::Instance(instance) => self.get_property(&instance, name)?,
+                Value::Class(class) => get_static(&class, name)?,
+                Value::String(_) if STRING_METHODS.iter().any(|m| m.name == name.name) => {
+                    let message = format!("String method {} must be called", name.name);
+                    return Err(RuntimeError::new(line, &message).into());
+                }
+                Value::String(_) => {
+                    let message = format!("Undefined property {}", name.name);
+                    return Err(RuntimeError::new(line, &message).into());
+                }
+                Value::Generator(_) => {
+                    let message = if GENERATOR_METHODS.contains(&name.name.as_str()) {
+                        format!("Generator method {} must be called", name.name)
+                    } else {
+                        format!("Undefined property {}", name.name)
+                    };
+                    return Err(RuntimeError::new(line, &message).into());
+                }
+                _ => return Err(not_an_instance(name).into()),
+            },
+            ExprKind::Set(object, name, value) => match self.evaluate(object)? {
+                Value::Instance(instance) => {
+                    let value = self.evaluate(value)?;
+                    self.set_property(&instance, name, value.clone())?;
+                    value
+                }
+                _ => return Err(not_an_instance(name).into()),
+            },
+            ExprKind::List(items) => Value::List(Rc::new(RefCell::new(self.arguments(items)?))),
+            ExprKind::Index(list, index) => {
+                let (list, index) = (self.evaluate(list)?, self.evaluate(index)?);
+                let (list, idx) = list_slot(list, index, line)?;
+                let value = list.borrow()[idx].clone();
+                value
+            }
+            ExprKind::IndexSet(list, index, value) => {
+                let (list, index) = (self.evaluate(list)?, self.evaluate(index)?);
+                let value = self.evaluate(value)?;
+                let is_tuple = matches!(list, Value::Tuple(_));
+                let (list, idx) = list_slot(list, index, line)?;
+                if is_tuple {
+                    return Err(RuntimeError::new(line, "Tuples can't be changed").into());
+                }
+                list.borrow_mut()[idx] = value.clone();
+                value
+            }
+            ExprKind::Tuple(items) => Value::Tuple(Rc::new(RefCell::new(self.arguments(items)?))),
+            ExprKind::This => match Scope::find(&self.scope, "this") {
+                Some(link) => link.value.borrow().clone(),
+                None => Value::Nil,
+            },
+            ExprKind::Class(decl) => {
+                let superclass = self.superclass(decl)?;
+                Value::Class(Rc::new(self.class_value(decl, superclass)))
+            }
+            ExprKind::Match(value, arms) => self.match_value(value, arms, line)?,
+            ExprKind::Super(name) => {
+                let find = |name| Scope::find(&self.scope, name).map(|l| l.value.borrow().clone());
+                let (superclass, this) = (find("super"), find("this"));
+                match (superclass, this) {
+                    (Some(Value::Class(superclass)), Some(Value::Instance(instance))) => {
+                        match superclass.methods.borrow().get(&name.name) {
+                            Some(method) => {
+                                Value::Function(bind(method, Value::Instance(instance)))
+                            }
+                            None => {
+                                let message = format!("Undefined superclass method {}", name.name);
+                                return Err(RuntimeError::new(line, &message).into());
+                            }
+                        }
+                    }
+                    _ => Value::Nil,
+                }
+            }
+        };
+        Ok(value)
+    }
+
+    fn get_property(&mut self, instance: &Rc<RefCell<Instance>>, name: &Identifier) -> Exec<Value> {
+        let getter = {
+            let borrowed = instance.borrow();
+            if let Some(value) = borrowed.fields.get(&name.name) {
+                return Ok(value.clone());
+            }
+            let getter = borrowed.class.getters.borrow().get(&name.name).cloned();
+            getter
+        };
+        let this = Value::Instance(instance.clone());
+        if let Some(getter) = getter {
+            return self.call_function(&bind(&getter, this), vec![], name.span.line);
+        }
+        let method = instance.borrow().class.methods.borrow().get(&name.name).cloned();
+        match method {
+            Some(method) => Ok(Value::Function(bind(&method, this))),
+            None => {
+                let message = format!("Undefined property {}", name.name);
+                Err(RuntimeError::new(name.span.line, &message).into())
+            }
+        }
+    }
+
+    //next() picks the generator up where it last yielded and done() tells
+    //whether it has finished
+    fn generator_method(
+        &mut self,
+        generator: &Rc<RefCell<Generator>>,
+        name: &Identifier,
+        args: Vec<Value>,
+        line: usize,
+    ) -> Exec<Value> {
+        if !GENERATOR_METHODS.contains(&name.name.as_str()) {
+            return Err(RuntimeError::new(line, "Undefined property").into());
+        }
+        if !args.is_empty() {
+            let message = format!("Expected 0 arguments but got {}", args.len());
+            return Err(RuntimeError::new(line, &message).into());
+        }
+        let state = generator.borrow().state;
+        match (name.name.as_str(), state) {
+            ("done", _) => return Ok(Value::Boolean(state == GeneratorState::Done)),
+            (_, GeneratorState::Done) => return Ok(Value::Nil),
+            (_, GeneratorState::Running) => {
+                let message = "Generator is already running";
+                return Err(RuntimeError::new(line, message).into());
+            }
+            (_, GeneratorState::Suspended) => {}
+        }
+        if self.call_depth > MAX_CALL_DEPTH {
+            generator.borrow_mut().state = GeneratorState::Done;
+            return Err(Unwind::Limit(RuntimeError::new(line, "Stack overflow")));
+        }
+
+        let (decl, scope) = {
+            let mut generator = generator.borrow_mut();
+            generator.state = GeneratorState::Running;
+            self.resume = std::mem::take(&mut generator.resume);
+            let scope = match self.resume.first() {
+                Some(Resume::Yield(scope)) => scope.clone(),
+                _ => generator.scope.clone(),
+            };
+            (generator.decl.clone(), scope)
+        };
+        let scope = std::mem::replace(&mut self.scope, scope);
+        let depth = std::mem::replace(&mut self.depth, 1);
+        self.call_depth += 1;
+        let result = self.execute_all(&decl.body);
+        self.call_depth -= 1;
+        self.depth = depth;
+        self.scope = scope;
+
+        let mut generator = generator.borrow_mut();
+        generator.state = GeneratorState::Done;
+        match result {
+            Ok(()) => Ok(Value::Nil),
+            Err(Unwind::Return(value)) => Ok(value),
+            Err(Unwind::Yield(value, resume)) => {
+                generator.resume = resume;
+                generator.state = GeneratorState::Suspended;
+                Ok(value)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    //The result of the first arm whose pattern matches, with what it binds in
+    //a scope of its own. Falling past them all throws.
+    fn match_value(&mut self, value: &Expr, arms: &[(Pattern, Expr)], line: usize) -> Exec<Value> {
+        let value = self.evaluate(value)?;
+        for (pattern, result) in arms.iter() {
+            let binding = match pattern {
+                Pattern::Literal(literal) if !self.evaluate(literal)?.equals(&value) => continue,
+                Pattern::Type(_, type_name) if !value.is_type(&type_name.name) => continue,
+                Pattern::Binding(name) | Pattern::Type(Some(name), _) => Some(name),
+                _ => None,
+            };
+            let scope = self.scope.clone();
+            self.depth += 1;
+            if let Some(name) = binding {
+                self.define(&name.name, value.clone());
+            }
+            let result = self.evaluate(result);
+            self.depth -= 1;
+            self.scope = scope;
+            return result;
+        }
+        let message = format!("No match arm for {}", self.call_to_string(value, line)?);
+        Err(Unwind::Throw(line, Value::String(Rc::from(message))))
+    }
+
+    //An instance whose class defines toString prints and concatenates as what
+    //it returns, which has to be a string
+    fn call_to_string(&mut self, value: Value, line: usize) -> Exec<Value> {
+        let method = match &value {
+            Value::Instance(instance) => {
+                instance.borrow().class.methods.borrow().get("toString").cloned()
+            }
+            _ => None,
+        };
+        let method = match method {
+            Some(method) => method,
+            None => return Ok(value),
+        };
+        match self.call_function(&bind(&method, value), vec![], line)? {
+            Value::String(s) => Ok(Value::String(s)),
+            other => {
+                let message = format!("toString must return a string, not {}", other);
+                Err(RuntimeError::new(line, &message).into())
+            }
+        }
+    }
+
+    fn set_property(
+        &mut self,
+        instance: &Rc<RefCell<Instance>>,
+        name: &Identifier,
+        value: Value,
+    ) -> Exec<()> {
+        let setter = instance.borrow().class.setters.borrow().get(&name.name).cloned();
+        match setter {
+            Some(setter) => {
+                let setter = bind(&setter, Value::Instance(instance.clone()));
+                self.call_function(&setter, vec![value], name.span.line)?;
+            }
+            None => {
+                instance.borrow_mut().fields.insert(name.name.clone(), value);
+            }
+        }
+        Ok(())
+    }
+
+    fn arguments(&mut self, args: &[Expr]) -> Exec<Vec<Value>> {
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.evaluate(arg)?);
+        }
+        Ok(values)
+    }
+
+    fn call(&mut self, callee: Value, args: Vec<Value>, line: usize) -> Exec<Value> {
+        match callee {
+            Value::Function(function) => self.call_function(&function, args, line),
+            Value::Class(class) => {
+                let instance = Rc::new(RefCell::new(Instance {
+                    class: class.clone(),
+                    fields: HashMap::new(),
+                }));
+                let this = Value::Instance(instance.clone());
+                let init = class.methods.borrow().get("init").map(|init| bind(init, this));
+                if let Some(init) = init {
+                    self.call_function(&init, args, line)?;
+                } else if !args.is_empty() {
+                    let message = format!("Expected 0 arguments but got {}", args.len());
+                    return Err(RuntimeError::new(line, &message).into());
+                }
+                Ok(Value::Instance(instance))
+            }
+            Value::String(_) | Value::Instance(_) | Value::List(_) | Value::Generator(_) => {
+                let message = "Attempted to call an object that's not callable";
+                Err(RuntimeError::new(line, message).into())
+            }
+            _ => {
+                let message = "Attempt to call a value which is not a function";
+                Err(RuntimeError::new(line, message).into())
+            }
+        }
+    }
+
+    fn call_function(
+        &mut self,
+        function: &Function,
+        mut args: Vec<Value>,
+        line: usize,
+    ) -> Exec<Value> {
+        let arity = function.decl.params.len();
+        if function.decl.variadic {
+            let required = arity - 1;
+            if args.len() < required {
+                let message =
+                    format!("Expected at least {} arguments but got {}", required, args.len());
+                return Err(RuntimeError::new(line, &message).into());
+            }
+            let rest = args.split_off(required);
+            args.push(Value::List(Rc::new(RefCell::new(rest))));
+        } else if args.len() != arity {
+            let message = format!("Expected {} arguments but got {}", arity, args.len());
+            return Err(RuntimeError::new(line, &message).into());
+        }
+        if self.call_depth > MAX_CALL_DEPTH {
+            return Err(Unwind::Limit(RuntimeError::new(line, "Stack overflow")));
+        }
+
+        let scope = std::mem::replace(&mut self.scope, function.closure.clone());
+        let depth = std::mem::replace(&mut self.depth, 1);
+        self.call_depth += 1;
+        for (param, arg) in function.decl.params.iter().zip(args) {
+            self.define(&param.name, arg);
+        }
+        let mut result = Ok(Value::Nil);
+        if function.decl.is_generator() {
+            //Nothing runs until the first next()
+            result = Ok(Value::Generator(Rc::new(RefCell::new(Generator {
+                decl: function.decl.clone(),
+                scope: self.scope.clone(),
+                resume: vec![],
+                state: GeneratorState::Suspended,
+            }))));
+        } else {
+            for statement in function.decl.body.iter() {
+                match self.execute(statement) {
+                    Ok(()) => {}
+                    Err(Unwind::Return(value)) => {
+                        result = Ok(value);
+                        break;
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+        }
+        self.call_depth -= 1;
+        self.depth = depth;
+        self.scope = scope;
+
+        if function.is_initializer && result.is_ok() {
+            if let Some(this) = Scope::find(&function.closure, "this") {
+                return Ok(this.value.borrow().clone());
+            }
+        }
+        result
+    }
+}
+
+//Notes where a yield passing through came from, for picking it up again
+fn suspended(e: Unwind, entry: Resume) -> Unwind {
+    match e {
+        Unwind::Yield(value, mut resume) => {
+            resume.push(entry);
+            Unwind::Yield(value, resume)
+        }
+        e => e,
+    }
+}
+
+fn label_name(label: &Option<Identifier>) -> Option<String> {
+    label.as_ref().map(|label| label.name.clone())
+}
+
+//An unlabeled break or continue targets the innermost loop
+fn targets(target: &Option<String>, label: Option<&str>) -> bool {
+    match target {
+        Some(target) => Some(target.as_str()) == label,
+        None => true,
+    }
+}
+
+fn binary(line: usize, left: Value, op: BinaryOp, right: Value) -> Result<Value, RuntimeError> {
+    let value = match (op, &left, &right) {
+        (BinaryOp::Equal, _, _) => Value::Boolean(left.equals(&right)),
+        (BinaryOp::NotEqual, _, _) => Value::Boolean(!left.equals(&right)),
+        (BinaryOp::Add, Value::String(a), Value::String(b)) => {
+            Value::String(Rc::from(format!("{}{}", a, b)))
+        }
+        (BinaryOp::Add, _, _) if left.is_object() && right.is_object() => {
+            let message = "Expected two strings for '+' operator";
+            return Err(RuntimeError::new(line, message));
+        }
+        (BinaryOp::BitAnd, _, _)
+        | (BinaryOp::BitOr, _, _)
+        | (BinaryOp::BitXor, _, _)
+        | (BinaryOp::ShiftLeft, _, _)
+        | (BinaryOp::ShiftRight, _, _) => match (left.as_integer(), right.as_integer()) {
+            (Some(a), Some(b)) => Value::Integer(bitwise(op, a, b)),
+            _ => return Err(RuntimeError::new(line, "Expected a number")),
+        },
+        (_, Value::Integer(a), Value::Integer(b)) if integer_op(op, *a, *b).is_some() => {
+            integer_op(op, *a, *b).unwrap()
+        }
+        (_, _, _) if left.as_number().is_some() && right.as_number().is_some() => {
+            let (a, b) = (left.as_number().unwrap(), right.as_number().unwrap());
+            match op {
+                BinaryOp::Add => Value::Number(a + b),
+                BinaryOp::Subtract => Value::Number(a - b),
+                BinaryOp::Multiply => Value::Number(a * b),
+                BinaryOp::Divide => Value::Number(a / b),
+                BinaryOp::Greater => Value::Boolean(a > b),
+                BinaryOp::GreaterEqual => Value::Boolean(a >= b),
+                BinaryOp::Less => Value::Boolean(a < b),
+                BinaryOp::LessEqual => Value::Boolean(a <= b),
+                _ => unreachable!(),
+            }
+        }
+        _ => return Err(RuntimeError::new(line, "Expected a number")),
+    };
+    Ok(value)
+}
+
+//None when the result doesn't fit, or for '/' which is always a float like the VM's
+fn integer_op(op: BinaryOp, a: i64, b: i64) -> Option<Value> {
+    match op {
+        BinaryOp::Add => a.checked_add(b).map(Value::Integer),
+        BinaryOp::Subtract => a.checked_sub(b).map(Value::Integer),
+        BinaryOp::Multiply => a.checked_mul(b).map(Value::Integer),
+        BinaryOp::Greater => Some(Value::Boolean(a > b)),
+        BinaryOp::GreaterEqual => Some(Value::Boolean(a >= b)),
+        BinaryOp::Less => Some(Value::Boolean(a < b)),
+        BinaryOp::LessEqual => Some(Value::Boolean(a <= b)),
+        _ => None,
+    }
+}
+
+//Only the low 6 bits of a shift count are used, like the VM
+fn bitwise(op: BinaryOp, a: i64, b: i64) -> i64 {
+    match op {
+        BinaryOp::BitAnd => a & b,
+        BinaryOp::BitOr => a | b,
+        BinaryOp::BitXor => a ^ b,
+        BinaryOp::ShiftLeft => a.wrapping_shl(b as u32),
+        _ => a.wrapping_shr(b as u32),
+    }
+}
+
+fn unpack(
+    value: Value,
+    unpacking: Unpacking,
+    names: &[(Identifier, Option<Identifier>)],
+    line: usize,
+) -> Result<Vec<Value>, RuntimeError> {
+    let count = names.len();
+    let message = match (unpacking, &value) {
+        (Unpacking::Fields, Value::Instance(instance)) => {
+            let instance = instance.borrow();
+            let mut fields = vec![];
+            for (name, _) in names.iter() {
+                match instance.fields.get(&name.name) {
+                    Some(field) => fields.push(field.clone()),
+                    None => {
+                        let message =
+                            format!("{} has no field {} to destructure", value, name.name);
+                        return Err(RuntimeError::new(line, &message));
+                    }
+                }
+            }
+            return Ok(fields);
+        }
+        (Unpacking::Fields, _) => format!("Can't destructure {} as an instance", value),
+        (Unpacking::List, Value::List(list)) if list.borrow().len() == count => {
+            return Ok(list.borrow().clone())
+        }
+        (Unpacking::List, Value::List(list)) => {
+            format!("Expected a list of {} items but got {}", count, list.borrow().len())
+        }
+        (Unpacking::List, _) => format!("Can't destructure {} as a list", value),
+        //Anything that isn't a tuple is a single value
+        (Unpacking::Tuple, Value::Tuple(tuple)) if tuple.borrow().len() == count => {
+            return Ok(tuple.borrow().clone())
+        }
+        (Unpacking::Tuple, Value::Tuple(tuple)) => {
+            format!("Expected {} values to unpack but got {}", count, tuple.borrow().len())
+        }
+        (Unpacking::Tuple, _) => format!("Expected {} values to unpack but got 1", count),
+    };
+    Err(RuntimeError::new(line, &message))
+}
+
+fn bind(method: &Rc<Function>, this: Value) -> Rc<Function> {
+    let this = Scope {
+        name: String::from("this"),
+        value: RefCell::new(this),
+        parent: method.closure.clone(),
+    };
+    Rc::new(Function {
+        decl: method.decl.clone(),
+        closure: Some(Rc::new(this)),
+        is_initializer: method.is_initializer,
+    })
+}
+
+//Whether calling name on instance would call what a getter returns, which the VM doesn't do
+fn invokes_getter(instance: &Rc<RefCell<Instance>>, name: &Identifier) -> bool {
+    let instance = instance.borrow();
+    !instance.fields.contains_key(&name.name)
+        && !instance.class.methods.borrow().contains_key(&name.name)
+        && instance.class.getters.borrow().contains_key(&name.name)
+}
+
+//Replaces what's declared in a class being reopened, keeping the rest
+fn merge(existing: &RefCell<Methods>, declared: &[FunctionDecl], methods: Methods) {
+    let mut existing = existing.borrow_mut();
+    for method in declared.iter() {
+        existing.remove(&method.name.name);
+    }
+    for (name, method) in methods {
+        existing.entry(name).or_insert(method);
+    }
+}
+
+fn get_static(class: &Rc<Class>, name: &Identifier) -> Result<Value, RuntimeError> {
+    let method = class.statics.borrow().get(&name.name).cloned();
+    match method {
+        Some(method) => Ok(Value::Function(bind(&method, Value::Class(class.clone())))),
+        None => {
+            let message = format!("Undefined property {}", name.name);
+            Err(RuntimeError::new(name.span.line, &message))
+        }
+    }
+}
+
+fn list_slot(
+    list: Value,
+    index: Value,
+    line: usize,
+) -> Result<(List, usize), RuntimeError> {
+    let list = match list {
+        Value::List(list) | Value::Tuple(list) => list,
+        _ => return Err(RuntimeError::new(line, "Only lists and tuples can be indexed")),
+    };
+    let len = list.borrow().len();
+    match index {
+        Value::Integer(n) if n >= 0 && (n as usize) < len => Ok((list, n as usize)),
+        Value::Integer(n) => {
+            let message = format!("Index {} is out of bounds for a list of {} items", n, len);
+            Err(RuntimeError::new(line, &message))
+        }
+        Value::Number(n) if n.fract() == 0.0 && n >= 0.0 && (n as usize) < len => {
+            Ok((list, n as usize))
+        }
+        Value::Number(n) if n.fract() == 0.0 => {
+            let message = format!("Index {} is out of bounds for a list of {} items", n, len);
+            Err(RuntimeError::new(line, &message))
+        }
+        _ => Err(RuntimeError::new(line, "List indices must be whole numbers")),
+    }
+}
+
+//The same methods as the VM's natives::LIST_METHODS
+fn list_method(
+    list: &RefCell<Vec<Value>>,
+    name: &Identifier,
+    args: Vec<Value>,
+    line: usize,
+) -> Result<Value, RuntimeError> {
+    let arity = match name.name.as_str() {
+        "push" => 1,
+        "pop" | "len" => 0,
+        _ => return Err(RuntimeError::new(line, "Undefined property")),
+    };
+    if args.len() != arity {
+        let message = format!("Expected {} arguments but got {}", arity, args.len());
+        return Err(RuntimeError::new(line, &message));
+    }
+    let mut list = list.borrow_mut();
+    match name.name.as_str() {
+        "push" => {
+            list.push(args[0].clone());
+            Ok(Value::Nil)
+        }
+        "pop" => list
+            .pop()
+            .ok_or_else(|| RuntimeError::new(line, "Can't pop from an empty list")),
+        _ => Ok(Value::Integer(list.len() as i64)),
+    }
+}
+
+//The same methods as the VM's natives::STRING_METHODS
+fn string_method(
+    s: &str,
+    name: &Identifier,
+    args: Vec<Value>,
+    line: usize,
+) -> Result<Value, RuntimeError> {
+    let method = STRING_METHODS
+        .iter()
+        .find(|method| method.name == name.name)
+        .ok_or_else(|| RuntimeError::new(line, "Undefined property"))?;
+    if args.len() != method.arity() {
+        let message = format!("Expected {} arguments but got {}", method.arity(), args.len());
+        return Err(RuntimeError::new(line, &message));
+    }
+    let string_arg = |value: &Value| match value {
+        Value::String(s) => Ok(s.clone()),
+        _ => Err(RuntimeError::new(line, &format!("{} expects a string", name.name))),
+    };
+    let string = |s: String| Value::String(Rc::from(s));
+    let len = s.chars().count();
+    match name.name.as_str() {
+        "length" => Ok(Value::Integer(len as i64)),
+        "substring" => {
+            let start = string_position(&args[0], len, line)?;
+            let end = string_position(&args[1], len, line)?;
+            if start > end {
+                let message = format!("Substring start {} is after its end {}", start, end);
+                return Err(RuntimeError::new(line, &message));
+            }
+            Ok(string(s.chars().skip(start).take(end - start).collect()))
+        }
+        "indexOf" => {
+            let needle = string_arg(&args[0])?;
+            Ok(Value::Integer(match s.find(&*needle) {
+                Some(byte) => s[..byte].chars().count() as i64,
+                None => -1,
+            }))
+        }
+        "split" => {
+            let separator = string_arg(&args[0])?;
+            let parts: Vec<Value> = if separator.is_empty() {
+                s.chars().map(|c| string(String::from(c))).collect()
+            } else {
+                s.split(&*separator).map(|part| string(String::from(part))).collect()
+            };
+            Ok(Value::List(Rc::new(RefCell::new(parts))))
+        }
+        "upper" => Ok(string(s.to_uppercase())),
+        "lower" => Ok(string(s.to_lowercase())),
+        _ => Ok(string(String::from(s.trim()))),
+    }
+}
+
+fn string_position(value: &Value, len: usize, line: usize) -> Result<usize, RuntimeError> {
+    let position = match *value {
+        Value::Integer(n) if n >= 0 => n as usize,
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => n as usize,
+        _ => return Err(RuntimeError::new(line, "String positions must be whole numbers")),
+    };
+    if position > len {
+        let message = format!("Position {} is past the end of the string", position);
+        return Err(RuntimeError::new(line, &message));
+    }
+    Ok(position)
+}
+
+fn not_an_instance(name: &Identifier) -> RuntimeError {
+    let message = format!(
+        "Attempted to access field {}, but target was not an instance of an object",
+        name.name
+    );
+    RuntimeError::new(name.span.line, &message)
+}
@@ -0,0 +1,2 @@
+pub mod difftest;
+pub mod interpreter;
@@ -0,0 +1,210 @@
+use lox_treewalk::difftest::{self, Ending, Outcome, Report};
+use lox_treewalk::interpreter::Interpreter;
+use lox_vm::generator;
+
+use clap::{Parser, Subcommand};
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+
+#[derive(Parser)]
+#[command(
+    name = "lox-treewalk",
+    version,
+    about = "A reference tree walking interpreter for Lox"
+)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The script to run
+    script: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run scripts on both the VM and the tree walker and compare the results
+    Difftest {
+        #[arg(required_unless_present = "generate")]
+        scripts: Vec<PathBuf>,
+
+        /// Compare this many generated programs instead
+        #[arg(long, value_name = "N", conflicts_with = "scripts")]
+        generate: Option<u64>,
+
+        /// Seed of the first generated program
+        #[arg(long, value_name = "SEED", default_value_t = 0)]
+        seed: u64,
+    },
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let code = match (cli.command, cli.script) {
+        (Some(Command::Difftest { generate, seed, .. }), _) if generate.is_some() => {
+            run_generated(generate.unwrap(), seed)
+        }
+        (Some(Command::Difftest { scripts, .. }), _) => run_difftest(&scripts),
+        (None, Some(script)) => run_script(&script),
+        (None, None) => {
+            eprintln!("Usage: lox-treewalk <SCRIPT> | lox-treewalk difftest <SCRIPTS>...");
+            64
+        }
+    };
+    process::exit(code);
+}
+
+fn read(script: &Path) -> Result<String, i32> {
+    fs::read_to_string(script).map_err(|e| {
+        eprintln!("{}: {}", script.display(), e);
+        66
+    })
+}
+
+fn run_script(script: &Path) -> i32 {
+    let source = match read(script) {
+        Ok(source) => source,
+        Err(code) => return code,
+    };
+    let statements = match difftest::check(&source) {
+        Ok(statements) => statements,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("Compiler error: {}", e);
+            }
+            return 65;
+        }
+    };
+
+    //Deep recursion in Lox is deep recursion here
+    let worker = thread::Builder::new()
+        .stack_size(256 * 1024 * 1024)
+        .spawn(move || {
+            let mut interpreter = Interpreter::new(Box::new(io::stdout()));
+            interpreter.interpret(&statements)
+        });
+    match worker
+        .expect("Failed to start the interpreter thread")
+        .join()
+    {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            eprintln!("Runtime Error: {}", e);
+            70
+        }
+        Err(_) => 70,
+    }
+}
+
+// Exits with 1 if any script diverged.
+fn run_difftest(scripts: &[PathBuf]) -> i32 {
+    //Panics in the VM are reported as divergences
+    panic::set_hook(Box::new(|_| {}));
+    let mut diverged = 0;
+    for script in scripts {
+        let source = match read(script) {
+            Ok(source) => source,
+            Err(code) => return code,
+        };
+        match difftest::difftest(&source) {
+            Report::Rejected(errors) => {
+                println!(
+                    "skip {}: doesn't compile ({})",
+                    script.display(),
+                    errors.join("; ")
+                );
+            }
+            Report::Agreed(_) => println!("ok   {}", script.display()),
+            Report::Diverged { vm, treewalk } => {
+                diverged += 1;
+                println!("FAIL {}", script.display());
+                describe(&vm, &treewalk);
+            }
+        }
+    }
+
+    println!("{} of {} scripts diverged", diverged, scripts.len());
+    if diverged > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+// Only divergences are printed, with the program so it can be saved.
+fn run_generated(count: u64, first_seed: u64) -> i32 {
+    panic::set_hook(Box::new(|_| {}));
+    let config = generator::Config::default();
+    let mut diverged = 0;
+    for seed in first_seed..first_seed + count {
+        let source = generator::generate(seed, &config);
+        match difftest::difftest(&source) {
+            Report::Rejected(errors) => {
+                diverged += 1;
+                println!(
+                    "FAIL seed {}: doesn't compile ({})",
+                    seed,
+                    errors.join("; ")
+                );
+                println!("{}", source);
+            }
+            Report::Agreed(_) => {}
+            Report::Diverged { vm, treewalk } => {
+                diverged += 1;
+                println!("FAIL seed {}", seed);
+                describe(&vm, &treewalk);
+                println!("{}", source);
+            }
+        }
+    }
+
+    println!("{} of {} programs diverged", diverged, count);
+    if diverged > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+fn describe(vm: &Outcome, treewalk: &Outcome) {
+    let vm_lines: Vec<_> = vm.output.lines().collect();
+    let treewalk_lines: Vec<_> = treewalk.output.lines().collect();
+    let first_difference = vm_lines
+        .iter()
+        .zip(treewalk_lines.iter())
+        .position(|(a, b)| a != b)
+        .or_else(|| {
+            if vm_lines.len() != treewalk_lines.len() {
+                Some(vm_lines.len().min(treewalk_lines.len()))
+            } else {
+                None
+            }
+        });
+    if let Some(idx) = first_difference {
+        println!("  output differs at line {}:", idx + 1);
+        println!(
+            "    vm:        {}",
+            vm_lines.get(idx).unwrap_or(&"<end of output>")
+        );
+        println!(
+            "    tree walk: {}",
+            treewalk_lines.get(idx).unwrap_or(&"<end of output>")
+        );
+    }
+    if vm.ending != treewalk.ending {
+        println!("  vm:        {}", ending(&vm.ending));
+        println!("  tree walk: {}", ending(&treewalk.ending));
+    }
+}
+
+fn ending(ending: &Ending) -> String {
+    match ending {
+        Ending::Finished => String::from("finished"),
+        Ending::RuntimeError(line, message) => format!("runtime error: {}: {}", line, message),
+        Ending::Panic(message) => format!("panicked: {}", message),
+    }
+}
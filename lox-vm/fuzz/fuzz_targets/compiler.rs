@@ -0,0 +1,50 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use lox_vm::compiler::Compiler;
+use lox_vm::interpreter::VirtualMemory;
+use lox_vm::token::{Token, TokenType};
+use std::convert::TryFrom;
+
+// Token streams the scanner would never produce still have to be rejected
+// with a syntax error rather than a panic.
+#[derive(Arbitrary, Debug)]
+struct FuzzToken {
+    kind: u8,
+    line: u8,
+}
+
+fuzz_target!(|input: Vec<FuzzToken>| {
+    let kinds = TokenType::EOF as usize;
+    let mut tokens: Vec<Token> = input
+        .iter()
+        .map(|t| {
+            let token_type = TokenType::try_from(t.kind as usize % kinds).unwrap();
+            let (lexeme, literal) = match token_type {
+                TokenType::Identifier => ("a", Some("a")),
+                TokenType::StringToken => ("\"s\"", Some("s")),
+                TokenType::Interpolation => ("\"s${", Some("s")),
+                TokenType::NumberToken => ("1.5", Some("1.5")),
+                TokenType::IntegerToken => ("1", Some("1")),
+                _ => ("", None),
+            };
+            Token {
+                token_type,
+                lexeme: String::from(lexeme),
+                line: t.line as usize,
+                column: 0,
+                literal: literal.map(String::from),
+            }
+        })
+        .collect();
+    tokens.push(Token {
+        token_type: TokenType::EOF,
+        lexeme: String::from("EOF"),
+        line: 0,
+        column: 0,
+        literal: None,
+    });
+
+    let mut compiler = Compiler::new(tokens, VirtualMemory::new());
+    let _ = compiler.compile();
+});
@@ -0,0 +1,27 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use lox_vm::compiler::Compiler;
+use lox_vm::generator::{self, Config};
+use lox_vm::interpreter::VM;
+use lox_vm::scanner::scan_tokens;
+use std::io;
+
+// Well formed programs get much further into the VM than random bytes do.
+// Generated programs always compile, so a compile error is a bug too.
+const FUEL: usize = 1_000_000;
+
+fuzz_target!(|seed: u64| {
+    let source = generator::generate(seed, &Config::default());
+    let tokens = scan_tokens(&source).expect("Generated program didn't scan");
+
+    let mut vm = VM::new();
+    vm.set_output(Box::new(io::sink()));
+    vm.fuel = Some(FUEL);
+
+    let mut compiler = Compiler::new(tokens, vm.take_virtual_memory());
+    let main = match compiler.compile() {
+        Ok(main) => main,
+        Err(_) => panic!("Generated program didn't compile:\n{}", source),
+    };
+    let _ = vm.interpret(main, compiler.heap);
+});
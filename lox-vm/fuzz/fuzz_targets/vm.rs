@@ -0,0 +1,29 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use lox_vm::compiler::Compiler;
+use lox_vm::interpreter::VM;
+use lox_vm::scanner::scan_tokens;
+use std::io;
+
+// Bounds the work per input so infinite loops surface as errors, not timeouts.
+const FUEL: usize = 100_000;
+
+fuzz_target!(|data: &[u8]| {
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => String::from(source),
+        Err(_) => return,
+    };
+    let tokens = match scan_tokens(&source) {
+        Ok(tokens) => tokens,
+        Err(_) => return,
+    };
+
+    let mut vm = VM::new();
+    vm.set_output(Box::new(io::sink()));
+    vm.fuel = Some(FUEL);
+
+    let mut compiler = Compiler::new(tokens, vm.take_virtual_memory());
+    if let Ok(main) = compiler.compile() {
+        let _ = vm.interpret(main, compiler.heap);
+    }
+});
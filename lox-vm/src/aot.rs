@@ -0,0 +1,600 @@
+use super::chunk::OpCode;
+use super::interpreter::VirtualMemory;
+use super::value::{Function, Object, Value};
+use cranelift_codegen::ir::{types, AbiParam, Block, FuncRef, InstBuilder, Signature, Type};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_module::{default_libcall_names, DataDescription, DataId, FuncId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Experimental ahead of time compiler. Every chunk becomes a native function
+// that calls into lox-rt once per instruction, so there is no interpreter
+// loop left but values still live on the runtime's stack. Jumps turn into
+// native branches. Closures that capture variables and classes aren't
+// supported yet and are reported before anything is generated.
+
+pub const RUNTIME_LIBRARY: &str = "liblox_rt.a";
+
+//Runtime entry points: name, parameters after the runtime pointer, whether it returns a status
+const RUNTIME_FUNCTIONS: &[(&str, &[Type], bool)] = &[
+    ("lox_rt_nil", &[], false),
+    ("lox_rt_bool", &[types::I32], false),
+    ("lox_rt_number", &[types::F64], false),
+    ("lox_rt_integer", &[types::I64], false),
+    ("lox_rt_string", &[types::I64, types::I64], false),
+    (
+        "lox_rt_function",
+        &[types::I64, types::I64, types::I64, types::I64],
+        false,
+    ),
+    ("lox_rt_pop", &[], false),
+    ("lox_rt_print", &[], false),
+    ("lox_rt_truthy", &[], true),
+    ("lox_rt_not", &[], false),
+    ("lox_rt_equal", &[], false),
+    ("lox_rt_negate", &[types::I64], true),
+    ("lox_rt_binary", &[types::I32, types::I64], true),
+    ("lox_rt_get_local", &[types::I64], false),
+    ("lox_rt_set_local", &[types::I64], false),
+    ("lox_rt_define_global", &[types::I64, types::I64], false),
+    (
+        "lox_rt_get_global",
+        &[types::I64, types::I64, types::I64],
+        true,
+    ),
+    (
+        "lox_rt_set_global",
+        &[types::I64, types::I64, types::I64],
+        true,
+    ),
+    ("lox_rt_call", &[types::I64, types::I64], true),
+    ("lox_rt_return", &[], false),
+];
+
+fn unsupported(op: &OpCode) -> Option<&'static str> {
+    match op {
+        OpCode::Closure(_, upvalues) if *upvalues > 0 => Some("closures that capture variables"),
+        OpCode::GetUpValue(_)
+        | OpCode::SetUpValue(_)
+        | OpCode::Upvalue(_)
+        | OpCode::CloseUpvalue
+        | OpCode::GetEnclosing(_)
+        | OpCode::SetEnclosing(_) => Some("closures that capture variables"),
+        OpCode::Class(_)
+        | OpCode::OpenClass(_)
+        | OpCode::GetProperty(_, _)
+        | OpCode::SetProperty(_, _)
+        | OpCode::Method(_, _)
+        | OpCode::StaticMethod(_, _)
+        | OpCode::Getter(_, _)
+        | OpCode::Setter(_, _)
+        | OpCode::Invoke(_, _, _)
+        | OpCode::Inherit(_)
+        | OpCode::GetSuper(_, _)
+        | OpCode::SuperInvoke(_, _, _) => Some("classes"),
+        OpCode::Echo => Some("REPL output"),
+        OpCode::Dup => Some("compound assignment to fields"),
+        OpCode::Stringify => Some("string interpolation"),
+        OpCode::Try(_) | OpCode::EndTry | OpCode::Throw => Some("exceptions"),
+        OpCode::Import(_) => Some("modules"),
+        OpCode::Generator | OpCode::Yield | OpCode::GeneratorReturn => Some("generators"),
+        OpCode::IsType(_) => Some("match expressions"),
+        OpCode::BitAnd
+        | OpCode::BitOr
+        | OpCode::BitXor
+        | OpCode::BitNot
+        | OpCode::ShiftLeft
+        | OpCode::ShiftRight => Some("bitwise operators"),
+        OpCode::BuildList(_) | OpCode::Index | OpCode::IndexSet => Some("lists"),
+        OpCode::BuildTuple(_) | OpCode::Unpack(_) => Some("multiple return values"),
+        OpCode::UnpackList(_) | OpCode::UnpackFields(_, _) => Some("destructuring"),
+        OpCode::AddConstant(_)
+        | OpCode::IncrementLocal(_)
+        | OpCode::CompareLocalConstant(_, _, _)
+        | OpCode::AddLocal(_)
+        | OpCode::CallConstant(_, _) => Some("specialized instructions"),
+        _ => None,
+    }
+}
+
+struct Lowering {
+    module: ObjectModule,
+    runtime: HashMap<&'static str, FuncId>,
+    //Lox functions by heap address, the script under None
+    functions: HashMap<Option<u64>, FuncId>,
+    strings: HashMap<String, DataId>,
+}
+
+// Compiles a program to an object file exporting a C main.
+pub fn compile_object(main: &Function, heap: &VirtualMemory) -> Result<Vec<u8>, String> {
+    let mut all = vec![(None, main)];
+    collect_functions(main, heap, &mut all);
+    for (_, function) in all.iter() {
+        if function.variadic {
+            return Err(format!(
+                "{}: rest parameters aren't supported by the native backend yet",
+                function.chunk.line_numbers.first().unwrap_or(&0)
+            ));
+        }
+        for (idx, op) in function.chunk.code.iter().enumerate() {
+            if let Some(feature) = unsupported(op) {
+                return Err(format!(
+                    "{}: {} aren't supported by the native backend yet",
+                    function.chunk.line_numbers[idx], feature
+                ));
+            }
+        }
+    }
+
+    let mut flags = settings::builder();
+    flags.set("is_pic", "true").map_err(|e| e.to_string())?;
+    let isa = cranelift_native::builder()
+        .map_err(|e| e.to_string())?
+        .finish(settings::Flags::new(flags))
+        .map_err(|e| e.to_string())?;
+    let builder =
+        ObjectBuilder::new(isa, "lox", default_libcall_names()).map_err(|e| e.to_string())?;
+    let mut lowering = Lowering {
+        module: ObjectModule::new(builder),
+        runtime: HashMap::new(),
+        functions: HashMap::new(),
+        strings: HashMap::new(),
+    };
+
+    for (name, params, returns_status) in RUNTIME_FUNCTIONS.iter() {
+        let signature = lowering.signature(params, *returns_status);
+        let id = lowering
+            .module
+            .declare_function(name, Linkage::Import, &signature)
+            .map_err(|e| e.to_string())?;
+        lowering.runtime.insert(name, id);
+    }
+
+    let code_signature = lowering.signature(&[], true);
+    for (idx, (address, _)) in all.iter().enumerate() {
+        let id = lowering
+            .module
+            .declare_function(&format!("lox_fn_{}", idx), Linkage::Local, &code_signature)
+            .map_err(|e| e.to_string())?;
+        lowering.functions.insert(*address, id);
+    }
+    for (address, function) in all.iter() {
+        lowering.define(*address, function, heap)?;
+    }
+    lowering.define_main()?;
+
+    let product = lowering.module.finish();
+    product.emit().map_err(|e| e.to_string())
+}
+
+fn collect_functions<'a>(
+    function: &'a Function,
+    heap: &'a VirtualMemory,
+    all: &mut Vec<(Option<u64>, &'a Function)>,
+) {
+    for constant in function.chunk.constants.iter() {
+        if let Value::Object(ptr) = constant {
+            if let Object::Function(inner) = heap.deref(*ptr) {
+                if !all.iter().any(|(address, _)| *address == Some(*ptr)) {
+                    all.push((Some(*ptr), inner));
+                    collect_functions(inner, heap, all);
+                }
+            }
+        }
+    }
+}
+
+impl Lowering {
+    fn pointer(&self) -> Type {
+        self.module.target_config().pointer_type()
+    }
+
+    fn signature(&self, params: &[Type], returns_status: bool) -> Signature {
+        let mut signature = self.module.make_signature();
+        signature.params.push(AbiParam::new(self.pointer()));
+        for param in params.iter() {
+            signature.params.push(AbiParam::new(*param));
+        }
+        if returns_status {
+            signature.returns.push(AbiParam::new(types::I32));
+        }
+        signature
+    }
+
+    fn string_data(&mut self, s: &str) -> Result<DataId, String> {
+        if let Some(id) = self.strings.get(s) {
+            return Ok(*id);
+        }
+        let id = self
+            .module
+            .declare_data(
+                &format!("lox_str_{}", self.strings.len()),
+                Linkage::Local,
+                false,
+                false,
+            )
+            .map_err(|e| e.to_string())?;
+        let mut description = DataDescription::new();
+        //Never empty, so every string has an address
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        description.define(bytes.into_boxed_slice());
+        self.module
+            .define_data(id, &description)
+            .map_err(|e| e.to_string())?;
+        self.strings.insert(String::from(s), id);
+        Ok(id)
+    }
+
+    fn define(
+        &mut self,
+        address: Option<u64>,
+        function: &Function,
+        heap: &VirtualMemory,
+    ) -> Result<(), String> {
+        let chunk = &function.chunk;
+        let string_constant = |idx: usize| match chunk.constants[idx] {
+            Value::Object(ptr) => heap.deref(ptr).as_string().clone(),
+            _ => panic!("Expected a string constant"),
+        };
+
+        //Everything the body refers to has to exist before the builder borrows the function
+        let mut strings = HashMap::new();
+        let mut callees = HashMap::new();
+        for op in chunk.code.iter() {
+            match op {
+                OpCode::DefineGlobal(idx, _)
+                | OpCode::GetGlobal(idx, _)
+                | OpCode::SetGlobal(idx, _) => {
+                    let s = string_constant(*idx);
+                    strings.insert(*idx, (self.string_data(&s)?, s.len()));
+                }
+                OpCode::Constant(idx) => {
+                    if let Value::Object(ptr) = chunk.constants[*idx] {
+                        let s = heap.deref(ptr).as_string().clone();
+                        strings.insert(*idx, (self.string_data(&s)?, s.len()));
+                    }
+                }
+                OpCode::Closure(idx, _) => {
+                    if let Value::Object(ptr) = chunk.constants[*idx] {
+                        let name = heap.deref(ptr).as_function().name.clone();
+                        let name_data = (self.string_data(&name)?, name.len());
+                        callees.insert(*idx, (self.functions[&Some(ptr)], name_data, ptr));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut context = self.module.make_context();
+        context.func.signature = self.signature(&[], true);
+        let mut builder_context = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+
+        let mut runtime: HashMap<&'static str, FuncRef> = HashMap::new();
+        for (name, id) in self.runtime.iter() {
+            runtime.insert(name, self.module.declare_func_in_func(*id, builder.func));
+        }
+
+        //One block per instruction so any of them can be a jump target, plus
+        //one for falling off the end
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        let blocks: Vec<Block> = (0..=chunk.code.len())
+            .map(|_| builder.create_block())
+            .collect();
+        let error = builder.create_block();
+
+        builder.switch_to_block(entry);
+        let rt = builder.block_params(entry)[0];
+        builder.ins().jump(blocks[0], &[]);
+
+        let pointer = self.pointer();
+        for (idx, op) in chunk.code.iter().enumerate() {
+            builder.switch_to_block(blocks[idx]);
+            let next = blocks[idx + 1];
+            let line = builder
+                .ins()
+                .iconst(types::I64, chunk.line_numbers[idx] as i64);
+            let int = |builder: &mut FunctionBuilder, n: usize| {
+                builder.ins().iconst(types::I64, n as i64)
+            };
+
+            //Calls that can fail return a status, anything but zero unwinds
+            let checked = |builder: &mut FunctionBuilder,
+                           name: &str,
+                           args: &[cranelift_codegen::ir::Value]| {
+                let call = builder.ins().call(runtime[name], args);
+                let status = builder.inst_results(call)[0];
+                builder.ins().brif(status, error, &[], next, &[]);
+            };
+
+            match op {
+                OpCode::Constant(idx) => match chunk.constants[*idx] {
+                    Value::Number(n) => {
+                        let n = builder.ins().f64const(n);
+                        builder.ins().call(runtime["lox_rt_number"], &[rt, n]);
+                    }
+                    Value::Integer(n) => {
+                        let n = builder.ins().iconst(types::I64, n);
+                        builder.ins().call(runtime["lox_rt_integer"], &[rt, n]);
+                    }
+                    Value::Boolean(b) => {
+                        let b = builder.ins().iconst(types::I32, b as i64);
+                        builder.ins().call(runtime["lox_rt_bool"], &[rt, b]);
+                    }
+                    Value::Nil => {
+                        builder.ins().call(runtime["lox_rt_nil"], &[rt]);
+                    }
+                    Value::Object(_) => {
+                        let (data, len) = strings[idx];
+                        let global = self.module.declare_data_in_func(data, builder.func);
+                        let ptr = builder.ins().symbol_value(pointer, global);
+                        let len = int(&mut builder, len);
+                        builder
+                            .ins()
+                            .call(runtime["lox_rt_string"], &[rt, ptr, len]);
+                    }
+                },
+                OpCode::Nil | OpCode::ThisPlaceholder => {
+                    builder.ins().call(runtime["lox_rt_nil"], &[rt]);
+                }
+                OpCode::True | OpCode::False => {
+                    let b = builder
+                        .ins()
+                        .iconst(types::I32, matches!(op, OpCode::True) as i64);
+                    builder.ins().call(runtime["lox_rt_bool"], &[rt, b]);
+                }
+                OpCode::Pop => {
+                    builder.ins().call(runtime["lox_rt_pop"], &[rt]);
+                }
+                OpCode::Print => {
+                    builder.ins().call(runtime["lox_rt_print"], &[rt]);
+                }
+                OpCode::Not => {
+                    builder.ins().call(runtime["lox_rt_not"], &[rt]);
+                }
+                OpCode::Equal => {
+                    builder.ins().call(runtime["lox_rt_equal"], &[rt]);
+                }
+                OpCode::Negate => {
+                    checked(&mut builder, "lox_rt_negate", &[rt, line]);
+                    continue;
+                }
+                OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Greater
+                | OpCode::Less => {
+                    let operator = match op {
+                        OpCode::Add => lox_rt::ADD,
+                        OpCode::Subtract => lox_rt::SUBTRACT,
+                        OpCode::Multiply => lox_rt::MULTIPLY,
+                        OpCode::Divide => lox_rt::DIVIDE,
+                        OpCode::Greater => lox_rt::GREATER,
+                        _ => lox_rt::LESS,
+                    };
+                    let operator = builder.ins().iconst(types::I32, operator as i64);
+                    checked(&mut builder, "lox_rt_binary", &[rt, operator, line]);
+                    continue;
+                }
+                OpCode::GetLocal(slot) | OpCode::SetLocal(slot) => {
+                    let name = if let OpCode::GetLocal(_) = op {
+                        "lox_rt_get_local"
+                    } else {
+                        "lox_rt_set_local"
+                    };
+                    let slot = int(&mut builder, *slot);
+                    builder.ins().call(runtime[name], &[rt, slot]);
+                }
+                OpCode::DefineGlobal(idx, _)
+                | OpCode::GetGlobal(idx, _)
+                | OpCode::SetGlobal(idx, _) => {
+                    let (data, len) = strings[idx];
+                    let global = self.module.declare_data_in_func(data, builder.func);
+                    let ptr = builder.ins().symbol_value(pointer, global);
+                    let len = int(&mut builder, len);
+                    match op {
+                        OpCode::DefineGlobal(..) => {
+                            builder
+                                .ins()
+                                .call(runtime["lox_rt_define_global"], &[rt, ptr, len]);
+                        }
+                        OpCode::GetGlobal(..) => {
+                            checked(&mut builder, "lox_rt_get_global", &[rt, ptr, len, line]);
+                            continue;
+                        }
+                        _ => {
+                            checked(&mut builder, "lox_rt_set_global", &[rt, ptr, len, line]);
+                            continue;
+                        }
+                    }
+                }
+                OpCode::Jump(offset) => {
+                    builder.ins().jump(blocks[idx + 1 + offset], &[]);
+                    continue;
+                }
+                OpCode::Loop(offset) => {
+                    builder.ins().jump(blocks[idx + 1 - offset], &[]);
+                    continue;
+                }
+                OpCode::JumpIfFalse(offset) => {
+                    let call = builder.ins().call(runtime["lox_rt_truthy"], &[rt]);
+                    let truthy = builder.inst_results(call)[0];
+                    builder
+                        .ins()
+                        .brif(truthy, next, &[], blocks[idx + 1 + offset], &[]);
+                    continue;
+                }
+                OpCode::Call(arg_count) => {
+                    let arg_count = int(&mut builder, *arg_count);
+                    checked(&mut builder, "lox_rt_call", &[rt, arg_count, line]);
+                    continue;
+                }
+                OpCode::Closure(idx, _) => {
+                    let (id, (name, name_len), ptr) = callees[idx];
+                    let callee = self.module.declare_func_in_func(id, builder.func);
+                    let code = builder.ins().func_addr(pointer, callee);
+                    let global = self.module.declare_data_in_func(name, builder.func);
+                    let name = builder.ins().symbol_value(pointer, global);
+                    let name_len = int(&mut builder, name_len);
+                    let arity = int(&mut builder, heap.deref(ptr).as_function().arity);
+                    builder.ins().call(
+                        runtime["lox_rt_function"],
+                        &[rt, code, name, name_len, arity],
+                    );
+                }
+                OpCode::Return => {
+                    builder.ins().call(runtime["lox_rt_return"], &[rt]);
+                    let ok = builder.ins().iconst(types::I32, 0);
+                    builder.ins().return_(&[ok]);
+                    continue;
+                }
+                OpCode::EOF => {
+                    let ok = builder.ins().iconst(types::I32, 0);
+                    builder.ins().return_(&[ok]);
+                    continue;
+                }
+                _ => unreachable!("Unsupported opcodes are rejected up front"),
+            }
+            builder.ins().jump(next, &[]);
+        }
+
+        builder.switch_to_block(blocks[chunk.code.len()]);
+        let ok = builder.ins().iconst(types::I32, 0);
+        builder.ins().return_(&[ok]);
+
+        builder.switch_to_block(error);
+        let failed = builder.ins().iconst(types::I32, 1);
+        builder.ins().return_(&[failed]);
+
+        builder.seal_all_blocks();
+        builder.finalize();
+
+        let id = self.functions[&address];
+        self.module
+            .define_function(id, &mut context)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+
+    // int main() { return lox_rt_run(script); }
+    fn define_main(&mut self) -> Result<(), String> {
+        let mut signature = self.module.make_signature();
+        signature.returns.push(AbiParam::new(types::I32));
+        let main = self
+            .module
+            .declare_function("main", Linkage::Export, &signature)
+            .map_err(|e| e.to_string())?;
+
+        let mut run_signature = self.module.make_signature();
+        run_signature.params.push(AbiParam::new(self.pointer()));
+        run_signature.returns.push(AbiParam::new(types::I32));
+        let run = self
+            .module
+            .declare_function("lox_rt_run", Linkage::Import, &run_signature)
+            .map_err(|e| e.to_string())?;
+
+        let mut context = self.module.make_context();
+        context.func.signature = signature;
+        let mut builder_context = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        let run = self.module.declare_func_in_func(run, builder.func);
+        let script = self
+            .module
+            .declare_func_in_func(self.functions[&None], builder.func);
+
+        let entry = builder.create_block();
+        builder.switch_to_block(entry);
+        let script = builder.ins().func_addr(self.pointer(), script);
+        let call = builder.ins().call(run, &[script]);
+        let status = builder.inst_results(call)[0];
+        builder.ins().return_(&[status]);
+        builder.seal_all_blocks();
+        builder.finalize();
+
+        self.module
+            .define_function(main, &mut context)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+}
+
+// The runtime library is looked for in LOX_RUNTIME, then next to this executable.
+fn runtime_library() -> Result<PathBuf, String> {
+    if let Some(path) = env::var_os("LOX_RUNTIME") {
+        return Ok(PathBuf::from(path));
+    }
+    let exe = env::current_exe().map_err(|e| e.to_string())?;
+    let path = exe
+        .parent()
+        .map(|dir| dir.join(RUNTIME_LIBRARY))
+        .filter(|path| path.is_file());
+    path.ok_or_else(|| {
+        format!(
+            "Couldn't find {}, build lox-rt or point LOX_RUNTIME at it",
+            RUNTIME_LIBRARY
+        )
+    })
+}
+
+// Links an object from compile_object with the runtime using the system C compiler.
+pub fn link(object: &[u8], output: &Path) -> Result<(), String> {
+    let runtime = runtime_library()?;
+    let object_path = output.with_extension("o");
+    fs::write(&object_path, object).map_err(|e| format!("{}: {}", object_path.display(), e))?;
+
+    let linker = env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    let status = Command::new(&linker)
+        .arg(&object_path)
+        .arg(&runtime)
+        .arg("-o")
+        .arg(output)
+        .args(["-lpthread", "-ldl", "-lm"])
+        .status();
+    let _ = fs::remove_file(&object_path);
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} failed with {}", linker, status)),
+        Err(e) => Err(format!("{}: {}", linker, e)),
+    }
+}
+
+#[cfg(test)]
+mod aot_tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::scanner::scan_tokens;
+
+    fn compile(source: &str) -> Result<Vec<u8>, String> {
+        let tokens = scan_tokens(&String::from(source)).unwrap();
+        let mut compiler = Compiler::new(tokens, VirtualMemory::new());
+        compiler.specialize = false;
+        let main = compiler.compile().ok().unwrap();
+        compile_object(&main, &compiler.heap)
+    }
+
+    #[test]
+    fn emits_an_object() {
+        let object = compile(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\nfor (var i = 0; i < 5; i = i + 1) print fib(i);\nprint \"done\";",
+        )
+        .unwrap();
+        assert!(!object.is_empty());
+    }
+
+    #[test]
+    fn rejects_classes() {
+        let error = compile("class A {}\nprint A;").err().unwrap();
+        assert_eq!(
+            error,
+            "1: classes aren't supported by the native backend yet"
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use super::token::Span;
+
+// Syntax tree for tooling (language server, linter, alternative backends).
+// The bytecode compiler is single pass and does not use it.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identifier {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    Integer(i64),
+    String(String),
+    Boolean(bool),
+    Nil,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+//What a declaration with several names takes apart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unpacking {
+    Tuple,
+    List,
+    Fields, //The names are field names too
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span, //The token that best identifies the expression
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Literal(Literal),
+    Variable(Identifier),
+    Assign(Identifier, Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+    Logical(Box<Expr>, LogicalOp, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>), //Condition, then, else
+    //String literal segments alternating with the expressions between them,
+    //starting and ending with a segment
+    Interpolation(Vec<Expr>),
+    Grouping(Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    Get(Box<Expr>, Identifier),
+    Set(Box<Expr>, Identifier, Box<Expr>),
+    List(Vec<Expr>),
+    Tuple(Vec<Expr>), //Only as what return a, b; gives
+    Index(Box<Expr>, Box<Expr>),                //List, index
+    IndexSet(Box<Expr>, Box<Expr>, Box<Expr>), //List, index, value
+    //x += y or obj.x += y, with the target a Variable or a Get
+    Compound(Box<Expr>, BinaryOp, Box<Expr>),
+    This,
+    Super(Identifier), //The method named after 'super.'
+    Class(Box<ClassDecl>),
+    Match(Box<Expr>, Vec<(Pattern, Expr)>), //Value, then each arm's pattern and result
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Literal(Expr), //A literal, or a negated number
+    Wildcard,      //_
+    Binding(Identifier),
+    //name: Type or _: Type, with the name bound only if there is one
+    Type(Option<Identifier>, Identifier),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDecl {
+    pub name: Identifier,
+    pub params: Vec<Identifier>,
+    pub variadic: bool, //The last parameter collects any extra arguments into a list
+    //Annotations, by parameter. Only the type checker reads them.
+    pub param_types: Vec<Option<Identifier>>,
+    pub return_type: Option<Identifier>,
+    pub body: Vec<Stmt>,
+    pub end_line: usize, //Line of the closing brace
+}
+
+impl FunctionDecl {
+    //Whether a yield in its own body, rather than a nested function's, makes
+    //calling it give back a generator
+    pub fn is_generator(&self) -> bool {
+        self.body.iter().any(Stmt::yields)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassDecl {
+    pub name: Identifier,
+    pub superclasses: Vec<Identifier>, //Earlier ones win when several define a method
+    pub methods: Vec<FunctionDecl>,
+    pub statics: Vec<FunctionDecl>, //Called on the class itself
+    pub getters: Vec<FunctionDecl>, //Run on reading a property, with no parameters
+    pub setters: Vec<FunctionDecl>, //Run on writing a property, with one
+}
+
+impl ClassDecl {
+    //Everything declared in the body, whatever kind of method it is
+    pub fn all_methods(&self) -> impl Iterator<Item = &FunctionDecl> {
+        self.methods
+            .iter()
+            .chain(self.getters.iter())
+            .chain(self.setters.iter())
+            .chain(self.statics.iter())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Expression(Expr),
+    Print(Expr),
+    Var(Identifier, Option<Identifier>, Option<Expr>), //Name, annotated type, initializer
+    //var a, b = f(); var [a, b] = list; or var {x, y} = instance; with each name's annotated type
+    VarUnpack(Unpacking, Vec<(Identifier, Option<Identifier>)>, Option<Expr>),
+    Block(Vec<Stmt>, Span),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    While(Expr, Box<Stmt>),
+    DoWhile(Box<Stmt>, Expr),
+    For(
+        Option<Box<Stmt>>,
+        Option<Expr>,
+        Option<Expr>,
+        Box<Stmt>,
+    ),
+    Switch(Expr, Vec<(Expr, Vec<Stmt>)>, Option<Vec<Stmt>>), //Value, cases, default
+    //Body, the catch's error name and body, the finally body
+    Try(Vec<Stmt>, Option<(Identifier, Vec<Stmt>)>, Option<Vec<Stmt>>),
+    Throw(Span, Expr),
+    Import(Identifier, String), //The name it's bound to, the path as written
+    Function(FunctionDecl),
+    Return(Span, Option<Expr>),
+    Yield(Span, Option<Expr>), //Makes the function it's in a generator
+    Break(Span, Option<Identifier>), //With the label of the loop to leave
+    Continue(Span, Option<Identifier>),
+    Labeled(Identifier, Box<Stmt>), //outer: while (...), only ever a loop
+    Class(ClassDecl),
+}
+
+impl Stmt {
+    fn yields(&self) -> bool {
+        match self {
+            Stmt::Yield(_, _) => true,
+            Stmt::Block(body, _) => body.iter().any(Stmt::yields),
+            Stmt::If(_, then_branch, else_branch) => {
+                then_branch.yields() || else_branch.iter().any(|stmt| stmt.yields())
+            }
+            Stmt::While(_, body) | Stmt::DoWhile(body, _) | Stmt::For(_, _, _, body) => {
+                body.yields()
+            }
+            Stmt::Labeled(_, body) => body.yields(),
+            Stmt::Switch(_, cases, default) => cases
+                .iter()
+                .flat_map(|(_, body)| body.iter())
+                .chain(default.iter().flatten())
+                .any(Stmt::yields),
+            Stmt::Try(body, catch, finally) => body
+                .iter()
+                .chain(catch.iter().flat_map(|(_, body)| body.iter()))
+                .chain(finally.iter().flatten())
+                .any(Stmt::yields),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// Standalone executables: a copy of this binary with a compiled program
+// appended, followed by a trailer giving the program's length. At startup we
+// look for the trailer on our own executable and, if it's there, run the
+// program instead of parsing arguments.
+
+use lox_vm::compiler::Compiler;
+use lox_vm::interpreter::{InterpreterError, VM};
+use lox_vm::{bytecode, scanner};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const TRAILER_MAGIC: &[u8; 8] = b"LOXBNDL1";
+const TRAILER_LEN: u64 = 16;
+
+pub fn embedded_program() -> Option<Vec<u8>> {
+    let mut exe = File::open(env::current_exe().ok()?).ok()?;
+    let size = exe.seek(SeekFrom::End(0)).ok()?;
+    if size < TRAILER_LEN {
+        return None;
+    }
+
+    let mut trailer = [0; TRAILER_LEN as usize];
+    exe.seek(SeekFrom::End(-(TRAILER_LEN as i64))).ok()?;
+    exe.read_exact(&mut trailer).ok()?;
+    if &trailer[8..] != TRAILER_MAGIC {
+        return None;
+    }
+
+    let mut length = [0; 8];
+    length.copy_from_slice(&trailer[..8]);
+    let length = u64::from_le_bytes(length);
+    if length > size - TRAILER_LEN {
+        return None;
+    }
+
+    let mut program = vec![0; length as usize];
+    exe.seek(SeekFrom::Start(size - TRAILER_LEN - length)).ok()?;
+    exe.read_exact(&mut program).ok()?;
+    Some(program)
+}
+
+pub fn run_embedded(program: &[u8]) -> i32 {
+    let (main, heap) = match bytecode::deserialize(program) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Corrupt bundle: {}", e);
+            return 70;
+        }
+    };
+
+    let mut interpreter = VM::new();
+    match interpreter.interpret(main, heap) {
+        Ok(()) => 0,
+        Err(InterpreterError::Exit(_, status)) => status,
+        Err(e) => {
+            eprintln!("Runtime Error: {}", e);
+            70
+        }
+    }
+}
+
+// Exits with 65 when the script doesn't compile, like a run would.
+pub fn bundle(script: &Path, output: &Path) -> i32 {
+    let source = match fs::read_to_string(script) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", script.display(), e);
+            return 66;
+        }
+    };
+
+    let tokens = match scanner::scan_tokens(&source) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 65;
+        }
+    };
+    let mut compiler = Compiler::new(tokens, VM::new().take_virtual_memory());
+    let main = match compiler.compile() {
+        Ok(main) => main,
+        Err(errors) => {
+            for e in errors.iter() {
+                eprintln!("Compiler error: {}", e.to_string());
+            }
+            return 65;
+        }
+    };
+    let program = bytecode::serialize(&main, &compiler.heap);
+
+    match write_executable(&program, output) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", output.display(), e);
+            73
+        }
+    }
+}
+
+fn write_executable(program: &[u8], output: &Path) -> io::Result<()> {
+    let mut bytes = fs::read(env::current_exe()?)?;
+    bytes.extend_from_slice(program);
+    bytes.extend_from_slice(&(program.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(TRAILER_MAGIC);
+    fs::write(output, bytes)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(output, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
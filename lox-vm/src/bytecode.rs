@@ -0,0 +1,618 @@
+use super::chunk::{Chunk, Comparison, LocalInfo, OpCode, Upvalue};
+use super::interpreter::VirtualMemory;
+use super::value::{FnType, Function, Object, Value};
+use std::collections::HashMap;
+
+const MAGIC: &[u8; 4] = b"LOXB";
+const VERSION: u32 = 5;
+
+// A compiled program on disk. The main function comes first, followed by
+// every string and function its constants reach, in the order they are first
+// referenced. Pointers become indices into that list and are turned back into
+// fresh heap addresses when loading. All integers are little endian u64.
+pub fn serialize(main: &Function, heap: &VirtualMemory) -> Vec<u8> {
+    let mut objects = vec![];
+    let mut indices = HashMap::new();
+    collect(&main.chunk, heap, &mut objects, &mut indices);
+
+    let mut writer = Writer { bytes: vec![] };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.bytes.extend_from_slice(&VERSION.to_le_bytes());
+    writer.function(main, &indices);
+    writer.usize(objects.len());
+    for ptr in objects.iter() {
+        match heap.deref(*ptr) {
+            Object::String(s) => {
+                writer.u8(0);
+                writer.string(s);
+            }
+            Object::Function(fun) => {
+                writer.u8(1);
+                writer.function(fun, &indices);
+            }
+            _ => panic!("Only strings and functions can be constants"),
+        }
+    }
+    writer.bytes
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<(Function, VirtualMemory), String> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(4)? != MAGIC {
+        return Err(String::from("Not a compiled Lox program"));
+    }
+    let version = u32::from_le_bytes([reader.u8()?, reader.u8()?, reader.u8()?, reader.u8()?]);
+    if version != VERSION {
+        return Err(format!("Unsupported bytecode version {}", version));
+    }
+
+    let mut main = reader.function()?;
+    let count = reader.usize()?;
+    let mut objects = vec![];
+    for _ in 0..count {
+        objects.push(match reader.u8()? {
+            0 => Object::String(reader.string()?),
+            1 => Object::Function(reader.function()?),
+            tag => return Err(format!("Unknown object tag {}", tag)),
+        });
+    }
+
+    //Addresses are handed out in order, so index i ends up at addresses[i]
+    let mut heap = VirtualMemory::new();
+    let addresses: Vec<u64> = objects
+        .into_iter()
+        .map(|object| heap.add_to_heap(object))
+        .collect();
+    relocate(&mut main.chunk, &addresses)?;
+    for address in addresses.iter() {
+        if let Object::Function(fun) = heap.deref_mut(*address) {
+            relocate(&mut fun.chunk, &addresses)?;
+        }
+    }
+    let ids = name_ids(&main.chunk, &mut heap);
+    set_name_ids(&mut main.chunk, ids);
+    main.chunk.finish();
+    for address in addresses.iter() {
+        let chunk = match heap.deref(*address) {
+            Object::Function(fun) => fun.chunk.clone(),
+            _ => continue,
+        };
+        let ids = name_ids(&chunk, &mut heap);
+        if let Object::Function(fun) = heap.deref_mut(*address) {
+            set_name_ids(&mut fun.chunk, ids);
+            fun.chunk.finish();
+        }
+    }
+    Ok((main, heap))
+}
+
+fn collect(
+    chunk: &Chunk,
+    heap: &VirtualMemory,
+    objects: &mut Vec<u64>,
+    indices: &mut HashMap<u64, usize>,
+) {
+    for constant in chunk.constants.iter() {
+        if let Value::Object(ptr) = constant {
+            if indices.contains_key(ptr) {
+                continue;
+            }
+            indices.insert(*ptr, objects.len());
+            objects.push(*ptr);
+            if let Object::Function(fun) = heap.deref(*ptr) {
+                collect(&fun.chunk, heap, objects, indices);
+            }
+        }
+    }
+}
+
+fn relocate(chunk: &mut Chunk, addresses: &[u64]) -> Result<(), String> {
+    for constant in chunk.constants.iter_mut() {
+        if let Value::Object(idx) = constant {
+            *idx = *addresses
+                .get(*idx as usize)
+                .ok_or_else(|| format!("Constant refers to missing object {}", idx))?;
+        }
+    }
+    Ok(())
+}
+
+//Interns the name of each instruction that carries a symbol or global slot,
+//by code index
+fn name_ids(chunk: &Chunk, heap: &mut VirtualMemory) -> Vec<(usize, usize)> {
+    let mut ids = vec![];
+    for (idx, op) in chunk.code.iter().enumerate() {
+        let (name, global) = match op {
+            OpCode::GetProperty(name, _)
+            | OpCode::SetProperty(name, _)
+            | OpCode::Method(name, _)
+            | OpCode::StaticMethod(name, _)
+            | OpCode::Getter(name, _)
+            | OpCode::Setter(name, _)
+            | OpCode::GetSuper(name, _)
+            | OpCode::Invoke(name, _, _)
+            | OpCode::SuperInvoke(name, _, _) => (*name, false),
+            OpCode::DefineGlobal(name, _)
+            | OpCode::GetGlobal(name, _)
+            | OpCode::SetGlobal(name, _) => (*name, true),
+            _ => continue,
+        };
+        if let Some(Value::Object(ptr)) = chunk.constants.get(name) {
+            if let Object::String(name) = heap.deref(*ptr) {
+                let name = name.clone();
+                let id = if global {
+                    heap.global_names.slot(&name)
+                } else {
+                    heap.symbols.intern(&name)
+                };
+                ids.push((idx, id));
+            }
+        }
+    }
+    ids
+}
+
+fn set_name_ids(chunk: &mut Chunk, ids: Vec<(usize, usize)>) {
+    for (idx, name_id) in ids {
+        match &mut chunk.code[idx] {
+            OpCode::GetProperty(_, id)
+            | OpCode::SetProperty(_, id)
+            | OpCode::Method(_, id)
+            | OpCode::StaticMethod(_, id)
+            | OpCode::Getter(_, id)
+            | OpCode::Setter(_, id)
+            | OpCode::GetSuper(_, id)
+            | OpCode::Invoke(_, _, id)
+            | OpCode::SuperInvoke(_, _, id)
+            | OpCode::DefineGlobal(_, id)
+            | OpCode::GetGlobal(_, id)
+            | OpCode::SetGlobal(_, id) => *id = name_id,
+            _ => {}
+        }
+    }
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn usize(&mut self, n: usize) {
+        self.bytes.extend_from_slice(&(n as u64).to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.usize(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn function(&mut self, fun: &Function, indices: &HashMap<u64, usize>) {
+        self.u8(match fun.fn_type {
+            FnType::Function => 0,
+            FnType::Initializer => 1,
+            FnType::Script => 2,
+            FnType::Method => 3,
+            FnType::Getter => 4,
+            FnType::Setter => 5,
+        });
+        self.string(&fun.name);
+        self.usize(fun.arity);
+        self.u8(fun.variadic as u8);
+        self.usize(fun.upvalue_count);
+
+        let chunk = &fun.chunk;
+        self.usize(chunk.code.len());
+        for (op, line) in chunk.code.iter().zip(chunk.line_numbers.iter()) {
+            self.op(op);
+            self.usize(*line);
+        }
+
+        self.usize(chunk.constants.len());
+        for constant in chunk.constants.iter() {
+            match constant {
+                Value::Number(n) => {
+                    self.u8(0);
+                    self.bytes.extend_from_slice(&n.to_le_bytes());
+                }
+                Value::Integer(n) => {
+                    self.u8(4);
+                    self.bytes.extend_from_slice(&n.to_le_bytes());
+                }
+                Value::Boolean(b) => {
+                    self.u8(1);
+                    self.u8(*b as u8);
+                }
+                Value::Object(ptr) => {
+                    self.u8(2);
+                    self.usize(indices[ptr]);
+                }
+                Value::Nil => self.u8(3),
+            }
+        }
+
+        self.usize(chunk.locals.len());
+        for local in chunk.locals.iter() {
+            self.string(&local.name);
+            self.usize(local.slot);
+            self.usize(local.start);
+            self.usize(local.end);
+        }
+    }
+
+    fn op(&mut self, op: &OpCode) {
+        let (tag, operands): (u8, &[usize]) = match op {
+            OpCode::Constant(a) => (0, &[*a]),
+            OpCode::DefineGlobal(a, _) => (1, &[*a]),
+            OpCode::Nil => (2, &[]),
+            OpCode::True => (3, &[]),
+            OpCode::False => (4, &[]),
+            OpCode::Negate => (5, &[]),
+            OpCode::Add => (6, &[]),
+            OpCode::Stringify => (48, &[]),
+            OpCode::BuildList(count) => (49, &[*count]),
+            OpCode::Index => (50, &[]),
+            OpCode::IndexSet => (51, &[]),
+            OpCode::StaticMethod(a, _) => (52, &[*a]),
+            OpCode::Getter(a, _) => (53, &[*a]),
+            OpCode::Setter(a, _) => (54, &[*a]),
+            OpCode::Try(a) => (55, &[*a]),
+            OpCode::EndTry => (56, &[]),
+            OpCode::Throw => (57, &[]),
+            OpCode::Import(a) => (58, &[*a]),
+            OpCode::IsType(a) => (72, &[*a]),
+            OpCode::AddLocal(a) => (73, &[*a]),
+            OpCode::CallConstant(a, b) => (74, &[*a, *b]),
+            OpCode::BitAnd => (59, &[]),
+            OpCode::BitOr => (60, &[]),
+            OpCode::BitXor => (61, &[]),
+            OpCode::BitNot => (62, &[]),
+            OpCode::ShiftLeft => (63, &[]),
+            OpCode::ShiftRight => (64, &[]),
+            OpCode::BuildTuple(count) => (65, &[*count]),
+            OpCode::Unpack(count) => (66, &[*count]),
+            OpCode::UnpackList(count) => (67, &[*count]),
+            OpCode::UnpackFields(a, b) => {
+                self.u8(68);
+                self.usize(*a);
+                self.usize(*b);
+                return;
+            }
+            OpCode::Generator => (69, &[]),
+            OpCode::Yield => (70, &[]),
+            OpCode::GeneratorReturn => (71, &[]),
+            OpCode::Subtract => (7, &[]),
+            OpCode::Multiply => (8, &[]),
+            OpCode::Divide => (9, &[]),
+            OpCode::Return => (10, &[]),
+            OpCode::Print => (11, &[]),
+            OpCode::Echo => (12, &[]),
+            OpCode::Pop => (13, &[]),
+            OpCode::Dup => (47, &[]),
+            OpCode::Not => (14, &[]),
+            OpCode::Equal => (15, &[]),
+            OpCode::Greater => (16, &[]),
+            OpCode::Less => (17, &[]),
+            OpCode::GetGlobal(a, _) => (18, &[*a]),
+            OpCode::SetGlobal(a, _) => (19, &[*a]),
+            OpCode::SetLocal(a) => (20, &[*a]),
+            OpCode::GetLocal(a) => (21, &[*a]),
+            OpCode::GetUpValue(a) => (22, &[*a]),
+            OpCode::SetUpValue(a) => (23, &[*a]),
+            OpCode::JumpIfFalse(a) => (24, &[*a]),
+            OpCode::Jump(a) => (25, &[*a]),
+            OpCode::Loop(a) => (26, &[*a]),
+            OpCode::Call(a) => (27, &[*a]),
+            OpCode::Closure(a, b) => {
+                self.u8(28);
+                self.usize(*a);
+                self.usize(*b);
+                return;
+            }
+            OpCode::Class(a) => (29, &[*a]),
+            OpCode::OpenClass(a) => (46, &[*a]),
+            OpCode::Upvalue(upvalue) => {
+                self.u8(30);
+                self.u8(upvalue.is_local as u8);
+                self.usize(upvalue.index);
+                return;
+            }
+            OpCode::SetProperty(a, _) => (31, &[*a]),
+            OpCode::GetProperty(a, _) => (32, &[*a]),
+            OpCode::CloseUpvalue => (33, &[]),
+            //Method ids are per heap, so they're interned again when loading
+            OpCode::Method(a, _) => (34, &[*a]),
+            OpCode::Invoke(a, b, _) => {
+                self.u8(35);
+                self.usize(*a);
+                self.usize(*b);
+                return;
+            }
+            OpCode::ThisPlaceholder => (36, &[]),
+            OpCode::Inherit(a) => (37, &[*a]),
+            OpCode::EOF => (38, &[]),
+            OpCode::AddConstant(a) => (39, &[*a]),
+            OpCode::IncrementLocal(a) => (40, &[*a]),
+            OpCode::CompareLocalConstant(a, b, comparison) => {
+                self.u8(41);
+                self.usize(*a);
+                self.usize(*b);
+                self.u8((*comparison == Comparison::Greater) as u8);
+                return;
+            }
+            OpCode::GetEnclosing(a) => (42, &[*a]),
+            OpCode::SetEnclosing(a) => (43, &[*a]),
+            OpCode::GetSuper(a, _) => (44, &[*a]),
+            OpCode::SuperInvoke(a, b, _) => {
+                self.u8(45);
+                self.usize(*a);
+                self.usize(*b);
+                return;
+            }
+        };
+        self.u8(tag);
+        for operand in operands.iter() {
+            self.usize(*operand);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.position < n {
+            return Err(String::from("Unexpected end of bytecode"));
+        }
+        let bytes = &self.bytes[self.position..self.position + n];
+        self.position += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        Ok(self.u64()? as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.usize()?;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|e| format!("{}", e))
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        let fn_type = match self.u8()? {
+            0 => FnType::Function,
+            1 => FnType::Initializer,
+            2 => FnType::Script,
+            3 => FnType::Method,
+            4 => FnType::Getter,
+            5 => FnType::Setter,
+            tag => return Err(format!("Unknown function type {}", tag)),
+        };
+        let name = self.string()?;
+        let arity = self.usize()?;
+        let mut fun = Function::new(name, arity, fn_type);
+        fun.variadic = self.u8()? != 0;
+        fun.upvalue_count = self.usize()?;
+
+        for _ in 0..self.usize()? {
+            let op = self.op()?;
+            let line = self.usize()?;
+            fun.chunk.append_chunk(op, line);
+        }
+
+        for _ in 0..self.usize()? {
+            let constant = match self.u8()? {
+                0 => Value::Number(f64::from_bits(self.u64()?)),
+                1 => Value::Boolean(self.u8()? != 0),
+                2 => Value::Object(self.u64()?),
+                3 => Value::Nil,
+                4 => Value::Integer(self.u64()? as i64),
+                tag => return Err(format!("Unknown constant tag {}", tag)),
+            };
+            fun.chunk.add_constant(constant);
+        }
+
+        for _ in 0..self.usize()? {
+            fun.chunk.locals.push(LocalInfo {
+                name: self.string()?,
+                slot: self.usize()?,
+                start: self.usize()?,
+                end: self.usize()?,
+            });
+        }
+        Ok(fun)
+    }
+
+    fn op(&mut self) -> Result<OpCode, String> {
+        Ok(match self.u8()? {
+            0 => OpCode::Constant(self.usize()?),
+            1 => OpCode::DefineGlobal(self.usize()?, 0),
+            2 => OpCode::Nil,
+            3 => OpCode::True,
+            4 => OpCode::False,
+            5 => OpCode::Negate,
+            6 => OpCode::Add,
+            7 => OpCode::Subtract,
+            8 => OpCode::Multiply,
+            9 => OpCode::Divide,
+            10 => OpCode::Return,
+            11 => OpCode::Print,
+            12 => OpCode::Echo,
+            13 => OpCode::Pop,
+            14 => OpCode::Not,
+            15 => OpCode::Equal,
+            16 => OpCode::Greater,
+            17 => OpCode::Less,
+            18 => OpCode::GetGlobal(self.usize()?, 0),
+            19 => OpCode::SetGlobal(self.usize()?, 0),
+            20 => OpCode::SetLocal(self.usize()?),
+            21 => OpCode::GetLocal(self.usize()?),
+            22 => OpCode::GetUpValue(self.usize()?),
+            23 => OpCode::SetUpValue(self.usize()?),
+            24 => OpCode::JumpIfFalse(self.usize()?),
+            25 => OpCode::Jump(self.usize()?),
+            26 => OpCode::Loop(self.usize()?),
+            27 => OpCode::Call(self.usize()?),
+            28 => OpCode::Closure(self.usize()?, self.usize()?),
+            29 => OpCode::Class(self.usize()?),
+            30 => OpCode::Upvalue(Upvalue {
+                is_local: self.u8()? != 0,
+                index: self.usize()?,
+            }),
+            31 => OpCode::SetProperty(self.usize()?, 0),
+            32 => OpCode::GetProperty(self.usize()?, 0),
+            33 => OpCode::CloseUpvalue,
+            34 => OpCode::Method(self.usize()?, 0),
+            35 => OpCode::Invoke(self.usize()?, self.usize()?, 0),
+            36 => OpCode::ThisPlaceholder,
+            37 => OpCode::Inherit(self.usize()?),
+            38 => OpCode::EOF,
+            39 => OpCode::AddConstant(self.usize()?),
+            40 => OpCode::IncrementLocal(self.usize()?),
+            41 => OpCode::CompareLocalConstant(
+                self.usize()?,
+                self.usize()?,
+                if self.u8()? != 0 {
+                    Comparison::Greater
+                } else {
+                    Comparison::Less
+                },
+            ),
+            42 => OpCode::GetEnclosing(self.usize()?),
+            43 => OpCode::SetEnclosing(self.usize()?),
+            44 => OpCode::GetSuper(self.usize()?, 0),
+            45 => OpCode::SuperInvoke(self.usize()?, self.usize()?, 0),
+            46 => OpCode::OpenClass(self.usize()?),
+            47 => OpCode::Dup,
+            48 => OpCode::Stringify,
+            49 => OpCode::BuildList(self.usize()?),
+            50 => OpCode::Index,
+            51 => OpCode::IndexSet,
+            52 => OpCode::StaticMethod(self.usize()?, 0),
+            53 => OpCode::Getter(self.usize()?, 0),
+            54 => OpCode::Setter(self.usize()?, 0),
+            55 => OpCode::Try(self.usize()?),
+            56 => OpCode::EndTry,
+            57 => OpCode::Throw,
+            58 => OpCode::Import(self.usize()?),
+            59 => OpCode::BitAnd,
+            60 => OpCode::BitOr,
+            61 => OpCode::BitXor,
+            62 => OpCode::BitNot,
+            63 => OpCode::ShiftLeft,
+            64 => OpCode::ShiftRight,
+            65 => OpCode::BuildTuple(self.usize()?),
+            66 => OpCode::Unpack(self.usize()?),
+            67 => OpCode::UnpackList(self.usize()?),
+            68 => OpCode::UnpackFields(self.usize()?, self.usize()?),
+            69 => OpCode::Generator,
+            70 => OpCode::Yield,
+            71 => OpCode::GeneratorReturn,
+            72 => OpCode::IsType(self.usize()?),
+            73 => OpCode::AddLocal(self.usize()?),
+            74 => OpCode::CallConstant(self.usize()?, self.usize()?),
+            tag => return Err(format!("Unknown opcode {}", tag)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod bytecode_tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::VM;
+    use crate::scanner::scan_tokens;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    #[derive(Clone)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn round_trip_runs() {
+        let source = "class Greeter {\n  init(name) { this.name = name; }\n  greet() { return \"hi \" + this.name; }\n}\nfun twice(f) { fun go() { return f() + f(); } return go; }\nvar g = Greeter(\"lox\");\nprint twice(g.greet)();\nfor (var i = 0; i < 2; i = i + 1) print i * 1.5;\n";
+        let tokens = scan_tokens(&String::from(source)).unwrap();
+        let mut compiler = Compiler::new(tokens, VirtualMemory::new());
+        let main = compiler.compile().ok().unwrap();
+        let bytes = serialize(&main, &compiler.heap);
+
+        let (main, heap) = deserialize(&bytes).unwrap();
+        let output = Output(Rc::new(RefCell::new(vec![])));
+        let mut vm = VM::new();
+        vm.set_output(Box::new(output.clone()));
+        assert!(vm.interpret(main, heap).is_ok());
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            "hi loxhi lox\n0\n1.5\n"
+        );
+    }
+
+    #[test]
+    fn reinterns_name_ids() {
+        let source = "class C { init() {} a() { return 1; } b() { return 2; } }\n\
+                      print C().b() * 10 + C().a();\n";
+        let tokens = scan_tokens(&String::from(source)).unwrap();
+        //Ids in the compiled program won't match those of a fresh heap
+        let mut heap = VirtualMemory::new();
+        heap.symbols.intern("unrelated");
+        let mut compiler = Compiler::new(tokens, heap);
+        let main = compiler.compile().ok().unwrap();
+        let bytes = serialize(&main, &compiler.heap);
+
+        let (main, heap) = deserialize(&bytes).unwrap();
+        let output = Output(Rc::new(RefCell::new(vec![])));
+        let mut vm = VM::new();
+        vm.set_output(Box::new(output.clone()));
+        assert!(vm.interpret(main, heap).is_ok());
+        assert_eq!(String::from_utf8(output.0.borrow().clone()).unwrap(), "21\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn corrupt_slots_stay_bounds_checked() {
+        let tokens = scan_tokens(&String::from("print 1;")).unwrap();
+        let mut compiler = Compiler::new(tokens, VirtualMemory::new());
+        let mut main = compiler.compile().ok().unwrap();
+        //Nothing checks slots on the way in
+        main.chunk.code.insert(0, OpCode::GetLocal(1000));
+        main.chunk.line_numbers.insert(0, 1);
+        let bytes = serialize(&main, &compiler.heap);
+
+        let (main, heap) = deserialize(&bytes).unwrap();
+        let _ = VM::new().interpret(main, heap);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(deserialize(b"nope").is_err());
+        assert!(deserialize(b"LOXB\x01\x00\x00\x00\x02").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use lox_vm::hooks::{CallEvent, GcEvent, Hooks};
+use serde_json::{json, Value as Json};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+// Records calls, returns and collections as Chrome trace events, which
+// chrome://tracing and Perfetto can show as a timeline.
+pub struct ChromeTrace {
+    events: Rc<RefCell<Vec<Json>>>,
+    start: Instant,
+}
+
+impl ChromeTrace {
+    pub fn new() -> ChromeTrace {
+        ChromeTrace {
+            events: Rc::new(RefCell::new(vec![])),
+            start: Instant::now(),
+        }
+    }
+
+    // Hooks that add to this trace, for VM::set_hooks.
+    pub fn hooks(&self) -> Hooks {
+        Hooks {
+            on_call: Some(self.call_hook("B")),
+            on_return: Some(self.call_hook("E")),
+            on_gc: Some({
+                let (events, start) = (self.events.clone(), self.start);
+                Box::new(move |e: &GcEvent| {
+                    //Complete events start when the pause did
+                    let end = micros(start);
+                    events.borrow_mut().push(json!({
+                        "name": "gc",
+                        "cat": "gc",
+                        "ph": "X",
+                        "ts": end - e.pause_us as f64,
+                        "dur": e.pause_us,
+                        "pid": 1,
+                        "tid": 1,
+                        "args": {"live_before": e.live_before, "freed": e.freed},
+                    }));
+                })
+            }),
+            on_instruction: None,
+        }
+    }
+
+    fn call_hook(&self, phase: &'static str) -> Box<dyn FnMut(&CallEvent)> {
+        let (events, start) = (self.events.clone(), self.start);
+        Box::new(move |e: &CallEvent| {
+            events.borrow_mut().push(json!({
+                "name": e.name,
+                "cat": "call",
+                "ph": phase,
+                "ts": micros(start),
+                "pid": 1,
+                "tid": 1,
+                "args": {"line": e.line, "depth": e.depth},
+            }));
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        let trace = json!({
+            "traceEvents": *self.events.borrow(),
+            "displayTimeUnit": "ms",
+        });
+        trace.to_string()
+    }
+}
+
+fn micros(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1_000_000.0
+}
+
+#[cfg(test)]
+mod chrome_trace_tests {
+    use super::*;
+    use lox_vm::compiler::Compiler;
+    use lox_vm::interpreter::VM;
+    use lox_vm::scanner::scan_tokens;
+
+    #[test]
+    fn records_nested_calls() {
+        let trace = ChromeTrace::new();
+        let mut vm = VM::new();
+        vm.set_hooks(trace.hooks());
+        let source = String::from("fun g() {}\nfun f() { g(); }\nf();");
+        let mut compiler = Compiler::new(scan_tokens(&source).unwrap(), vm.take_virtual_memory());
+        let main = compiler.compile().map_err(|_| "compile error").unwrap();
+        vm.interpret(main, compiler.heap).map_err(|e| e.to_string()).unwrap();
+
+        let json: Json = serde_json::from_str(&trace.to_json()).unwrap();
+        let events: Vec<(&str, &str)> = json["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["ph"].as_str().unwrap(), e["name"].as_str().unwrap()))
+            .collect();
+        assert_eq!(events, vec![("B", "f"), ("B", "g"), ("E", "g"), ("E", "f")]);
+    }
+}
@@ -1,26 +1,44 @@
+#[cfg(feature = "compact-bytecode")]
+use super::compact::CompactCode;
+#[cfg(feature = "jit")]
+use super::jit::NativeCode;
+#[cfg(feature = "jit")]
+use std::sync::Arc;
 use super::value::Value;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
 
 #[derive(Debug, Copy, Clone)]
 pub enum OpCode {
     Constant(usize), //Index into the constants array
-    DefineGlobal(usize),
+    DefineGlobal(usize, usize), //Constant index for name, global slot
     Nil,
     True,
     False,
     Negate,
     Add,
+    Stringify, //Turns the top of the stack into a string, for interpolation
     Subtract,
     Multiply,
     Divide,
+    //Bitwise operators, on integers or floats truncated to them
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
     Return,
     Print,
+    Echo, //Print with the inspector, for REPL results
     Pop,
+    Dup, //Copies the top of the stack, for obj.field += x
     Not,
     Equal,
     Greater,
     Less,
-    GetGlobal(usize),
-    SetGlobal(usize),
+    GetGlobal(usize, usize), //Constant index for name, global slot
+    SetGlobal(usize, usize), //Constant index for name, global slot
     SetLocal(usize),
     GetLocal(usize),
     GetUpValue(usize),
@@ -28,31 +46,170 @@ pub enum OpCode {
     JumpIfFalse(usize),
     Jump(usize),
     Loop(usize), //Backwards offset instead of forward
+    Try(usize),  //Forward offset to the catch code, for errors until the matching EndTry
+    EndTry,
+    Throw,
+    Import(usize), //Constant holding the path as written
     Call(usize),
     Closure(usize, usize), // (Constant pointer, number of upvalues)
     Class(usize),
+    OpenClass(usize), //Like Class, but reuses a class the global of that name holds
     Upvalue(Upvalue),
-    SetProperty(usize), //Constant index for name
-    GetProperty(usize),
+    BuildList(usize), //Number of elements on the stack
+    BuildTuple(usize), //Number of elements on the stack
+    Unpack(usize),    //Number of elements the tuple on top must have
+    UnpackList(usize), //Number of items the list on top must have
+    UnpackFields(usize, usize), //Constant index of the first field name, number of names after it
+    Index,
+    IndexSet,
+    SetProperty(usize, usize), //Constant index for name, symbol
+    GetProperty(usize, usize), //Constant index for name, symbol
     CloseUpvalue,
-    Method(usize),        //Constant index for name
-    Invoke(usize, usize), //Constant index for name, argCount
+    Method(usize, usize),        //Constant index for name, symbol
+    Invoke(usize, usize, usize), //Constant index for name, argCount, symbol
+    StaticMethod(usize, usize),  //Constant index for name, symbol
+    Getter(usize, usize),        //Constant index for name, symbol
+    Setter(usize, usize),        //Constant index for name, symbol
     ThisPlaceholder,
-    Inherit,
+    Inherit(usize), //Number of superclasses
+    //Method lookups that start at the superclasses of the enclosing class
+    GetSuper(usize, usize),           //Constant index for name, symbol
+    SuperInvoke(usize, usize, usize), //Constant index for name, argCount, symbol
+    //Fused forms the compiler emits for common loop arithmetic
+    AddConstant(usize),                             //Constant index of the right operand
+    IncrementLocal(usize),                          //Slot, for i = i + 1
+    CompareLocalConstant(usize, usize, Comparison), //Slot, constant index
+    //Fused by the superinstructions pass, each followed by the second
+    //instruction of its pair, which it steps over
+    AddLocal(usize),            //Slot of the right operand, for GetLocal then Add
+    CallConstant(usize, usize), //Constant index of the last argument, argCount
+    //A local of the calling frame, for functions that never escape it
+    GetEnclosing(usize),
+    SetEnclosing(usize),
+    //Suspending and resuming generator functions
+    Generator, //First in a generator, which suspends it before the body runs
+    Yield,
+    GeneratorReturn, //Return from a generator, which finishes it
+    IsType(usize), //Constant index of the type name a match pattern checks for
     EOF,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Comparison {
+    Less,
+    Greater,
+}
+
+impl OpCode {
+    //The variant name without operands, as the disassembler shows it
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpCode::Constant(_) => "Constant",
+            OpCode::DefineGlobal(_, _) => "DefineGlobal",
+            OpCode::Nil => "Nil",
+            OpCode::True => "True",
+            OpCode::False => "False",
+            OpCode::Negate => "Negate",
+            OpCode::Add => "Add",
+            OpCode::Stringify => "Stringify",
+            OpCode::BuildList(_) => "BuildList",
+            OpCode::BuildTuple(_) => "BuildTuple",
+            OpCode::Unpack(_) => "Unpack",
+            OpCode::UnpackList(_) => "UnpackList",
+            OpCode::UnpackFields(_, _) => "UnpackFields",
+            OpCode::Index => "Index",
+            OpCode::IndexSet => "IndexSet",
+            OpCode::Subtract => "Subtract",
+            OpCode::Multiply => "Multiply",
+            OpCode::Divide => "Divide",
+            OpCode::BitAnd => "BitAnd",
+            OpCode::BitOr => "BitOr",
+            OpCode::BitXor => "BitXor",
+            OpCode::BitNot => "BitNot",
+            OpCode::ShiftLeft => "ShiftLeft",
+            OpCode::ShiftRight => "ShiftRight",
+            OpCode::Return => "Return",
+            OpCode::Print => "Print",
+            OpCode::Echo => "Echo",
+            OpCode::Pop => "Pop",
+            OpCode::Dup => "Dup",
+            OpCode::Not => "Not",
+            OpCode::Equal => "Equal",
+            OpCode::Greater => "Greater",
+            OpCode::Less => "Less",
+            OpCode::GetGlobal(_, _) => "GetGlobal",
+            OpCode::SetGlobal(_, _) => "SetGlobal",
+            OpCode::SetLocal(_) => "SetLocal",
+            OpCode::GetLocal(_) => "GetLocal",
+            OpCode::GetUpValue(_) => "GetUpValue",
+            OpCode::SetUpValue(_) => "SetUpValue",
+            OpCode::JumpIfFalse(_) => "JumpIfFalse",
+            OpCode::Jump(_) => "Jump",
+            OpCode::Loop(_) => "Loop",
+            OpCode::Try(_) => "Try",
+            OpCode::EndTry => "EndTry",
+            OpCode::Throw => "Throw",
+            OpCode::Import(_) => "Import",
+            OpCode::Call(_) => "Call",
+            OpCode::Closure(_, _) => "Closure",
+            OpCode::Class(_) => "Class",
+            OpCode::OpenClass(_) => "OpenClass",
+            OpCode::Upvalue(_) => "Upvalue",
+            OpCode::SetProperty(_, _) => "SetProperty",
+            OpCode::GetProperty(_, _) => "GetProperty",
+            OpCode::CloseUpvalue => "CloseUpvalue",
+            OpCode::Method(_, _) => "Method",
+            OpCode::StaticMethod(_, _) => "StaticMethod",
+            OpCode::Getter(_, _) => "Getter",
+            OpCode::Setter(_, _) => "Setter",
+            OpCode::Invoke(_, _, _) => "Invoke",
+            OpCode::ThisPlaceholder => "ThisPlaceholder",
+            OpCode::Inherit(_) => "Inherit",
+            OpCode::GetSuper(_, _) => "GetSuper",
+            OpCode::SuperInvoke(_, _, _) => "SuperInvoke",
+            OpCode::AddConstant(_) => "AddConstant",
+            OpCode::IncrementLocal(_) => "IncrementLocal",
+            OpCode::CompareLocalConstant(_, _, _) => "CompareLocalConstant",
+            OpCode::GetEnclosing(_) => "GetEnclosing",
+            OpCode::SetEnclosing(_) => "SetEnclosing",
+            OpCode::Generator => "Generator",
+            OpCode::Yield => "Yield",
+            OpCode::GeneratorReturn => "GeneratorReturn",
+            OpCode::IsType(_) => "IsType",
+            OpCode::AddLocal(_) => "AddLocal",
+            OpCode::CallConstant(_, _) => "CallConstant",
+            OpCode::EOF => "EOF",
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Upvalue {
     pub is_local: bool,
     pub index: usize, //Index in local slots
 }
 
+//Where a named local lives, so debuggers can show variables by name.
+#[derive(Debug, Clone)]
+pub struct LocalInfo {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize, //First instruction where the local is in scope
+    pub end: usize,   //One past the last, usize::MAX until the scope closes
+}
+
 #[derive(Clone)]
 pub struct Chunk {
     pub code: Vec<OpCode>,
     pub constants: Vec<Value>,
     pub line_numbers: Vec<usize>,
+    pub locals: Vec<LocalInfo>,
+    //What the VM decodes instead of code, rebuilt by finish
+    #[cfg(feature = "compact-bytecode")]
+    pub compact: CompactCode,
+    //Set once the function is hot, dropped by finish since it's for the old code
+    #[cfg(feature = "jit")]
+    pub native: Option<Arc<NativeCode>>,
 }
 
 impl Chunk {
@@ -61,6 +218,23 @@ impl Chunk {
             code: vec![],
             constants: vec![],
             line_numbers: vec![],
+            locals: vec![],
+            #[cfg(feature = "compact-bytecode")]
+            compact: CompactCode::default(),
+            #[cfg(feature = "jit")]
+            native: None,
+        }
+    }
+
+    //Called whenever code is done changing, so what runs instead of it matches
+    pub fn finish(&mut self) {
+        #[cfg(feature = "compact-bytecode")]
+        {
+            self.compact = CompactCode::encode(&self.code);
+        }
+        #[cfg(feature = "jit")]
+        {
+            self.native = None;
         }
     }
 
@@ -77,11 +251,8 @@ impl Chunk {
 
     pub fn patch_jump(&mut self, instruction_idx: usize, offset: usize) {
         match &mut self.code[instruction_idx] {
-            OpCode::JumpIfFalse(j) | OpCode::Jump(j) => *j = offset,
-            _ => panic!(format!(
-                "Cant patch opcode {:?}",
-                self.code[instruction_idx]
-            )),
+            OpCode::JumpIfFalse(j) | OpCode::Jump(j) | OpCode::Try(j) => *j = offset,
+            _ => panic!("Cant patch opcode {:?}", self.code[instruction_idx]),
         };
     }
 
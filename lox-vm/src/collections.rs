@@ -0,0 +1,7 @@
+// Hash maps need a source of randomness that only std has, so without it the
+// VM uses ordered maps instead.
+#[cfg(feature = "std")]
+pub use std::collections::{hash_map::Entry, HashMap as Map, HashSet as Set};
+
+#[cfg(not(feature = "std"))]
+pub use alloc::collections::{btree_map::Entry, BTreeMap as Map, BTreeSet as Set};
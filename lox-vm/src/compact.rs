@@ -0,0 +1,292 @@
+use super::chunk::{Comparison, OpCode, Upvalue};
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+// A denser form of a chunk's code for the VM to run, with the compact-bytecode
+// feature. Each instruction is a tag byte followed by its operands as LEB128
+// varints, so most take two or three bytes instead of a whole OpCode. Jumps,
+// inline caches, line numbers and frames still count instructions rather than
+// bytes, so starts maps an instruction's index to where its bytes begin.
+//
+// Off by default: on fib(32) the start table and decoding cost more than the
+// smaller code saves, about 15% slower than indexing the OpCode Vec.
+#[derive(Debug, Clone, Default)]
+pub struct CompactCode {
+    bytes: Vec<u8>,
+    starts: Vec<u32>,
+}
+
+impl CompactCode {
+    pub fn encode(code: &[OpCode]) -> CompactCode {
+        let mut compact = CompactCode::default();
+        for op in code.iter() {
+            compact.starts.push(compact.bytes.len() as u32);
+            compact.push(*op);
+        }
+        compact
+    }
+
+    fn push(&mut self, op: OpCode) {
+        let (tag, operands): (u8, &[usize]) = match op {
+            OpCode::Constant(a) => (0, &[a]),
+            OpCode::DefineGlobal(a, b) => (1, &[a, b]),
+            OpCode::Nil => (2, &[]),
+            OpCode::True => (3, &[]),
+            OpCode::False => (4, &[]),
+            OpCode::Negate => (5, &[]),
+            OpCode::Add => (6, &[]),
+            OpCode::Stringify => (7, &[]),
+            OpCode::Subtract => (8, &[]),
+            OpCode::Multiply => (9, &[]),
+            OpCode::Divide => (10, &[]),
+            OpCode::BitAnd => (11, &[]),
+            OpCode::BitOr => (12, &[]),
+            OpCode::BitXor => (13, &[]),
+            OpCode::BitNot => (14, &[]),
+            OpCode::ShiftLeft => (15, &[]),
+            OpCode::ShiftRight => (16, &[]),
+            OpCode::Return => (17, &[]),
+            OpCode::Print => (18, &[]),
+            OpCode::Echo => (19, &[]),
+            OpCode::Pop => (20, &[]),
+            OpCode::Dup => (21, &[]),
+            OpCode::Not => (22, &[]),
+            OpCode::Equal => (23, &[]),
+            OpCode::Greater => (24, &[]),
+            OpCode::Less => (25, &[]),
+            OpCode::GetGlobal(a, b) => (26, &[a, b]),
+            OpCode::SetGlobal(a, b) => (27, &[a, b]),
+            OpCode::SetLocal(a) => (28, &[a]),
+            OpCode::GetLocal(a) => (29, &[a]),
+            OpCode::GetUpValue(a) => (30, &[a]),
+            OpCode::SetUpValue(a) => (31, &[a]),
+            OpCode::JumpIfFalse(a) => (32, &[a]),
+            OpCode::Jump(a) => (33, &[a]),
+            OpCode::Loop(a) => (34, &[a]),
+            OpCode::Try(a) => (35, &[a]),
+            OpCode::EndTry => (36, &[]),
+            OpCode::Throw => (37, &[]),
+            OpCode::Import(a) => (38, &[a]),
+            OpCode::Call(a) => (39, &[a]),
+            OpCode::Closure(a, b) => (40, &[a, b]),
+            OpCode::Class(a) => (41, &[a]),
+            OpCode::OpenClass(a) => (42, &[a]),
+            OpCode::Upvalue(upvalue) => (43, &[upvalue.is_local as usize, upvalue.index]),
+            OpCode::BuildList(a) => (44, &[a]),
+            OpCode::BuildTuple(a) => (45, &[a]),
+            OpCode::Unpack(a) => (46, &[a]),
+            OpCode::UnpackList(a) => (47, &[a]),
+            OpCode::UnpackFields(a, b) => (48, &[a, b]),
+            OpCode::Index => (49, &[]),
+            OpCode::IndexSet => (50, &[]),
+            OpCode::SetProperty(a, b) => (51, &[a, b]),
+            OpCode::GetProperty(a, b) => (52, &[a, b]),
+            OpCode::CloseUpvalue => (53, &[]),
+            OpCode::Method(a, b) => (54, &[a, b]),
+            OpCode::Invoke(a, b, c) => (55, &[a, b, c]),
+            OpCode::StaticMethod(a, b) => (56, &[a, b]),
+            OpCode::Getter(a, b) => (57, &[a, b]),
+            OpCode::Setter(a, b) => (58, &[a, b]),
+            OpCode::ThisPlaceholder => (59, &[]),
+            OpCode::Inherit(a) => (60, &[a]),
+            OpCode::GetSuper(a, b) => (61, &[a, b]),
+            OpCode::SuperInvoke(a, b, c) => (62, &[a, b, c]),
+            OpCode::AddConstant(a) => (63, &[a]),
+            OpCode::IncrementLocal(a) => (64, &[a]),
+            OpCode::CompareLocalConstant(a, b, comparison) => {
+                (65, &[a, b, (comparison == Comparison::Greater) as usize])
+            }
+            OpCode::AddLocal(a) => (66, &[a]),
+            OpCode::CallConstant(a, b) => (67, &[a, b]),
+            OpCode::GetEnclosing(a) => (68, &[a]),
+            OpCode::SetEnclosing(a) => (69, &[a]),
+            OpCode::Generator => (70, &[]),
+            OpCode::Yield => (71, &[]),
+            OpCode::GeneratorReturn => (72, &[]),
+            OpCode::IsType(a) => (73, &[a]),
+            OpCode::EOF => (74, &[]),
+        };
+        self.bytes.push(tag);
+        for operand in operands.iter() {
+            let mut rest = *operand;
+            while rest >= 0x80 {
+                self.bytes.push((rest & 0x7f) as u8 | 0x80);
+                rest >>= 7;
+            }
+            self.bytes.push(rest as u8);
+        }
+    }
+
+    #[inline]
+    pub fn decode(&self, ip: usize) -> OpCode {
+        let mut cursor = Cursor {
+            bytes: &self.bytes,
+            at: self.starts[ip] as usize + 1,
+        };
+        match self.bytes[self.starts[ip] as usize] {
+            0 => OpCode::Constant(cursor.operand()),
+            1 => OpCode::DefineGlobal(cursor.operand(), cursor.operand()),
+            2 => OpCode::Nil,
+            3 => OpCode::True,
+            4 => OpCode::False,
+            5 => OpCode::Negate,
+            6 => OpCode::Add,
+            7 => OpCode::Stringify,
+            8 => OpCode::Subtract,
+            9 => OpCode::Multiply,
+            10 => OpCode::Divide,
+            11 => OpCode::BitAnd,
+            12 => OpCode::BitOr,
+            13 => OpCode::BitXor,
+            14 => OpCode::BitNot,
+            15 => OpCode::ShiftLeft,
+            16 => OpCode::ShiftRight,
+            17 => OpCode::Return,
+            18 => OpCode::Print,
+            19 => OpCode::Echo,
+            20 => OpCode::Pop,
+            21 => OpCode::Dup,
+            22 => OpCode::Not,
+            23 => OpCode::Equal,
+            24 => OpCode::Greater,
+            25 => OpCode::Less,
+            26 => OpCode::GetGlobal(cursor.operand(), cursor.operand()),
+            27 => OpCode::SetGlobal(cursor.operand(), cursor.operand()),
+            28 => OpCode::SetLocal(cursor.operand()),
+            29 => OpCode::GetLocal(cursor.operand()),
+            30 => OpCode::GetUpValue(cursor.operand()),
+            31 => OpCode::SetUpValue(cursor.operand()),
+            32 => OpCode::JumpIfFalse(cursor.operand()),
+            33 => OpCode::Jump(cursor.operand()),
+            34 => OpCode::Loop(cursor.operand()),
+            35 => OpCode::Try(cursor.operand()),
+            36 => OpCode::EndTry,
+            37 => OpCode::Throw,
+            38 => OpCode::Import(cursor.operand()),
+            39 => OpCode::Call(cursor.operand()),
+            40 => OpCode::Closure(cursor.operand(), cursor.operand()),
+            41 => OpCode::Class(cursor.operand()),
+            42 => OpCode::OpenClass(cursor.operand()),
+            43 => OpCode::Upvalue(Upvalue {
+                is_local: cursor.operand() != 0,
+                index: cursor.operand(),
+            }),
+            44 => OpCode::BuildList(cursor.operand()),
+            45 => OpCode::BuildTuple(cursor.operand()),
+            46 => OpCode::Unpack(cursor.operand()),
+            47 => OpCode::UnpackList(cursor.operand()),
+            48 => OpCode::UnpackFields(cursor.operand(), cursor.operand()),
+            49 => OpCode::Index,
+            50 => OpCode::IndexSet,
+            51 => OpCode::SetProperty(cursor.operand(), cursor.operand()),
+            52 => OpCode::GetProperty(cursor.operand(), cursor.operand()),
+            53 => OpCode::CloseUpvalue,
+            54 => OpCode::Method(cursor.operand(), cursor.operand()),
+            55 => OpCode::Invoke(cursor.operand(), cursor.operand(), cursor.operand()),
+            56 => OpCode::StaticMethod(cursor.operand(), cursor.operand()),
+            57 => OpCode::Getter(cursor.operand(), cursor.operand()),
+            58 => OpCode::Setter(cursor.operand(), cursor.operand()),
+            59 => OpCode::ThisPlaceholder,
+            60 => OpCode::Inherit(cursor.operand()),
+            61 => OpCode::GetSuper(cursor.operand(), cursor.operand()),
+            62 => OpCode::SuperInvoke(cursor.operand(), cursor.operand(), cursor.operand()),
+            63 => OpCode::AddConstant(cursor.operand()),
+            64 => OpCode::IncrementLocal(cursor.operand()),
+            65 => OpCode::CompareLocalConstant(
+                cursor.operand(),
+                cursor.operand(),
+                if cursor.operand() == 0 { Comparison::Less } else { Comparison::Greater },
+            ),
+            66 => OpCode::AddLocal(cursor.operand()),
+            67 => OpCode::CallConstant(cursor.operand(), cursor.operand()),
+            68 => OpCode::GetEnclosing(cursor.operand()),
+            69 => OpCode::SetEnclosing(cursor.operand()),
+            70 => OpCode::Generator,
+            71 => OpCode::Yield,
+            72 => OpCode::GeneratorReturn,
+            73 => OpCode::IsType(cursor.operand()),
+            74 => OpCode::EOF,
+            tag => panic!("Unknown instruction tag {}", tag),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.starts.is_empty()
+    }
+
+    //What the encoding takes, start table included
+    pub fn size_in_bytes(&self) -> usize {
+        self.bytes.len() + self.starts.len() * core::mem::size_of::<u32>()
+    }
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Cursor<'a> {
+    #[inline]
+    fn operand(&mut self) -> usize {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.bytes[self.at];
+            self.at += 1;
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+}
+
+#[cfg(test)]
+mod compact_tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::VM;
+    use crate::scanner::scan_tokens;
+
+    #[test]
+    fn round_trips_every_operand_width() {
+        let code = vec![
+            OpCode::Constant(3),
+            OpCode::Invoke(200, 1, 70000),
+            OpCode::CompareLocalConstant(1, 2, Comparison::Greater),
+            OpCode::Upvalue(Upvalue {
+                is_local: true,
+                index: 129,
+            }),
+            OpCode::Loop(usize::MAX >> 1),
+            OpCode::EOF,
+        ];
+        let compact = CompactCode::encode(&code);
+        let decoded: Vec<String> = (0..compact.len())
+            .map(|ip| format!("{:?}", compact.decode(ip)))
+            .collect();
+        let expected: Vec<String> = code.iter().map(|op| format!("{:?}", op)).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn is_smaller_than_the_enum() {
+        let source = "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }\n\
+                      var x = fib(15);";
+        let mut vm = VM::new();
+        let tokens = scan_tokens(&String::from(source)).unwrap();
+        let mut compiler = Compiler::new(tokens, vm.take_virtual_memory());
+        let main = compiler.compile().ok().unwrap();
+        let code = &main.chunk.code;
+        let compact = CompactCode::encode(code);
+        assert!(compact.size_in_bytes() * 4 < code.len() * core::mem::size_of::<OpCode>());
+        vm.interpret(main, compiler.heap).map_err(|e| e.to_string()).unwrap();
+        let (_, x) = vm.globals().into_iter().find(|(name, _)| name.as_str() == "x").unwrap();
+        assert_eq!(vm.inspect(x), "610");
+    }
+}
@@ -1,22 +1,65 @@
 use super::chunk::*;
 use super::interpreter::VirtualMemory;
+use super::passes::PassManager;
+use super::scanner;
 use super::token::*;
 use super::value::*;
-
+use core::convert::TryFrom;
+use core::mem;
 use num_enum::TryFromPrimitive;
-use std::convert::TryFrom;
+#[cfg(feature = "tracing")]
+use tracing::{debug, debug_span};
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
 
 pub enum CompilerError {
-    SyntaxError(String, usize),
+    SyntaxError(String, Span),
 }
 
 impl CompilerError {
     pub fn to_string(&self) -> String {
         match self {
-            CompilerError::SyntaxError(message, line) => format!("{} : {}", line, message),
+            CompilerError::SyntaxError(message, span) => format!("{} : {}", span.line, message),
             _ => unimplemented!(),
         }
     }
+
+    pub fn line(&self) -> usize {
+        match self {
+            CompilerError::SyntaxError(_, span) => span.line,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            CompilerError::SyntaxError(_, span) => *span,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            CompilerError::SyntaxError(message, _) => message,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompilerError::SyntaxError(_, _) => "syntax-error",
+        }
+    }
+}
+
+//Serialized by name rather than as a tuple variant so tools don't depend on field order
+#[cfg(feature = "serde")]
+impl serde::Serialize for CompilerError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut error = serializer.serialize_struct("CompilerError", 3)?;
+        error.serialize_field("code", self.code())?;
+        error.serialize_field("message", self.message())?;
+        error.serialize_field("span", &self.span())?;
+        error.end()
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -32,10 +75,16 @@ enum Precedence {
     //Allow Precedence to be used an index
     None = 0,
     Assignment,
+    Conditional,
     Or,
     And,
     Equality,
     Comparison,
+    //Bitwise operators bind tighter than comparisons, so x & 1 == 1 needs no parentheses
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
@@ -66,13 +115,24 @@ pub struct Compiler {
     current: usize,
     rules: Vec<ParseRule>,
     has_error: bool,
+    //Errors the parser recovered from without abandoning the declaration
+    recovered: Vec<CompilerError>,
     code_scopes: Vec<CodeScope>,
     class_scopes: Vec<ClassScope>,
     pub heap: VirtualMemory,
+    //Display the value of top level expression statements (used by the REPL)
+    pub echo_results: bool,
+    //Emit specialized instructions like IncrementLocal, and read captures of
+    //functions that never escape straight from the enclosing frame
+    pub specialize: bool,
+    //Optimizations run over the finished bytecode, none by default
+    pub passes: PassManager,
 }
 
 pub struct ClassScope {
     name: Token,
+    has_superclass: bool,
+    in_static: bool, //Compiling a static method, where 'this' is the class
 }
 
 pub struct CodeScope {
@@ -80,6 +140,41 @@ pub struct CodeScope {
     locals: Vec<Local>,
     upvalues: Vec<Upvalue>,
     depth: usize,
+    //Where the last patched jump lands. Fusing must not swallow a jump target.
+    last_jump_target: Option<usize>,
+    //Only ever called directly by the enclosing function, so its locals can be
+    //read off the caller's frame instead of boxed as upvalues
+    enclosing_access: bool,
+    //Innermost last, for break and continue
+    loops: Vec<LoopScope>,
+    //Handlers of the try blocks being compiled, innermost last, with the
+    //finally block each guards, which jumps out of it run first
+    tries: Vec<Option<Finally>>,
+    //Set by a yield, which makes the function a generator
+    yields: bool,
+}
+
+struct LoopScope {
+    //Names the loop for a break or continue in a nested one
+    label: Option<String>,
+    //Where continue jumps back to, the increment in a for loop. None in a
+    //do-while, whose condition comes after the body.
+    start: Option<usize>,
+    //Forward continues, landed on the condition once it's compiled
+    continues: Vec<usize>,
+    //Locals deeper than this are popped on the way out
+    depth: usize,
+    //Handlers already open, the rest are ended on the way out
+    tries: usize,
+    breaks: Vec<usize>,
+}
+
+#[derive(Clone, Copy)]
+struct Finally {
+    //The token its block starts at, compiled again wherever a jump leaves the try
+    block: usize,
+    //Locals declared before the try, the only ones the block can see
+    locals: usize,
 }
 
 impl Compiler {
@@ -89,6 +184,11 @@ impl Compiler {
             locals: vec![],
             upvalues: vec![],
             depth: 0,
+            last_jump_target: None,
+            enclosing_access: false,
+            loops: vec![],
+            tries: vec![],
+            yields: false,
         };
 
         Compiler {
@@ -98,7 +198,11 @@ impl Compiler {
             code_scopes: vec![scope],
             class_scopes: vec![],
             has_error: false,
+            recovered: vec![],
             heap,
+            echo_results: false,
+            specialize: true,
+            passes: PassManager::new(),
         }
     }
 
@@ -116,6 +220,11 @@ impl Compiler {
                     infix: Some(Self::call),
                     precedence: Precedence::Call,
                 }),
+                TokenType::LeftBracket => rules.push(ParseRule {
+                    prefix: Some(Self::list),
+                    infix: Some(Self::index),
+                    precedence: Precedence::Call,
+                }),
                 TokenType::Minus => rules.push(ParseRule {
                     prefix: Some(Compiler::unary),
                     infix: Some(Compiler::binary),
@@ -136,6 +245,31 @@ impl Compiler {
                     infix: Some(Compiler::binary),
                     precedence: Precedence::Factor,
                 }),
+                TokenType::Ampersand => rules.push(ParseRule {
+                    prefix: None,
+                    infix: Some(Compiler::binary),
+                    precedence: Precedence::BitAnd,
+                }),
+                TokenType::Pipe => rules.push(ParseRule {
+                    prefix: None,
+                    infix: Some(Compiler::binary),
+                    precedence: Precedence::BitOr,
+                }),
+                TokenType::Caret => rules.push(ParseRule {
+                    prefix: None,
+                    infix: Some(Compiler::binary),
+                    precedence: Precedence::BitXor,
+                }),
+                TokenType::Tilde => rules.push(ParseRule {
+                    prefix: Some(Compiler::unary),
+                    infix: None,
+                    precedence: Precedence::None,
+                }),
+                TokenType::LessLess | TokenType::GreaterGreater => rules.push(ParseRule {
+                    prefix: None,
+                    infix: Some(Compiler::binary),
+                    precedence: Precedence::Shift,
+                }),
                 TokenType::False => rules.push(ParseRule {
                     prefix: Some(Compiler::literal),
                     infix: None,
@@ -201,7 +335,12 @@ impl Compiler {
                     infix: None,
                     precedence: Precedence::None,
                 }),
-                TokenType::NumberToken => rules.push(ParseRule {
+                TokenType::Interpolation => rules.push(ParseRule {
+                    prefix: Some(Compiler::interpolation),
+                    infix: None,
+                    precedence: Precedence::None,
+                }),
+                TokenType::NumberToken | TokenType::IntegerToken => rules.push(ParseRule {
                     prefix: Some(Compiler::number),
                     infix: None,
                     precedence: Precedence::None,
@@ -216,6 +355,11 @@ impl Compiler {
                     infix: Some(Compiler::or),
                     precedence: Precedence::Or,
                 }),
+                TokenType::Question => rules.push(ParseRule {
+                    prefix: None,
+                    infix: Some(Compiler::conditional),
+                    precedence: Precedence::Conditional,
+                }),
                 TokenType::Dot => rules.push(ParseRule {
                     prefix: None,
                     infix: Some(Compiler::dot),
@@ -226,6 +370,21 @@ impl Compiler {
                     infix: None,
                     precedence: Precedence::None,
                 }),
+                TokenType::Class => rules.push(ParseRule {
+                    prefix: Some(Compiler::class_expression),
+                    infix: None,
+                    precedence: Precedence::None,
+                }),
+                TokenType::Match => rules.push(ParseRule {
+                    prefix: Some(Compiler::match_expression),
+                    infix: None,
+                    precedence: Precedence::None,
+                }),
+                TokenType::Super => rules.push(ParseRule {
+                    prefix: Some(Compiler::super_),
+                    infix: None,
+                    precedence: Precedence::None,
+                }),
                 _ => rules.push(ParseRule {
                     prefix: None,
                     infix: None,
@@ -257,6 +416,13 @@ impl Compiler {
         }
     }
 
+    fn check_next(&self, token_type: TokenType) -> bool {
+        match self.tokens.get(self.current + 1) {
+            Some(token) => token.token_type == token_type,
+            None => false,
+        }
+    }
+
     fn match_token(&mut self, token_type: TokenType) -> bool {
         if self.check_token(token_type) {
             self.advance();
@@ -283,7 +449,7 @@ impl Compiler {
         if self.is_at_end() {
             Err(CompilerError::SyntaxError(
                 String::from(err_message),
-                self.previous().line,
+                self.previous().span(),
             ))
         } else {
             let token = self.peek().clone();
@@ -294,12 +460,32 @@ impl Compiler {
                 self.advance();
                 Err(CompilerError::SyntaxError(
                     String::from(err_message),
-                    token.line,
+                    token.span(),
                 ))
             }
         }
     }
 
+    //A statement missing its ';' right before a '}' still ends there, so the
+    //block closes normally instead of taking the rest of the file with it
+    fn consume_semicolon(&mut self, err_message: &str) -> Result<Token, CompilerError> {
+        if self.check_token(TokenType::RightBrace) {
+            let token = self.previous().clone();
+            self.report(CompilerError::SyntaxError(
+                String::from("Expected ';' before '}'."),
+                self.peek().span(),
+            ));
+            Ok(token)
+        } else {
+            self.try_consume(TokenType::Semicolon, err_message)
+        }
+    }
+
+    fn report(&mut self, error: CompilerError) {
+        self.has_error = true;
+        self.recovered.push(error);
+    }
+
     fn code_scope(&mut self) -> &mut CodeScope {
         self.code_scopes.last_mut().unwrap()
     }
@@ -317,7 +503,7 @@ impl Compiler {
     fn resolve_local(
         code_scope: &CodeScope,
         var_name: &String,
-        line: usize,
+        span: Span,
     ) -> Result<Option<usize>, CompilerError> {
         if code_scope.locals.len() == 0 {
             return Ok(None);
@@ -331,7 +517,7 @@ impl Compiler {
                 } else {
                     return Err(CompilerError::SyntaxError(
                         String::from("Can't read local variable in its own initializer."),
-                        line,
+                        span,
                     ));
                 }
             }
@@ -340,6 +526,19 @@ impl Compiler {
         Ok(None)
     }
 
+    //A local of the enclosing function, when the current one may read it directly
+    fn resolve_enclosing(
+        &mut self,
+        name: &String,
+        span: Span,
+    ) -> Result<Option<usize>, CompilerError> {
+        let scopes = self.code_scopes.len();
+        if scopes < 2 || !self.code_scope().enclosing_access {
+            return Ok(None);
+        }
+        Self::resolve_local(&self.code_scopes[scopes - 2], name, span)
+    }
+
     fn add_string(&mut self, s: String) -> u64 {
         self.heap.add_to_heap(Object::String(s))
     }
@@ -359,12 +558,12 @@ impl Compiler {
         &mut self,
         code_scope_idx: usize,
         name: &String,
-        line: usize,
+        span: Span,
     ) -> Result<Option<usize>, CompilerError> {
         if code_scope_idx == 0 {
             Ok(None) //Zero in codescopes is global scope which is resolved separately.
         } else if let Some(id) =
-            Self::resolve_local(&self.code_scopes[code_scope_idx - 1], name, line)?
+            Self::resolve_local(&self.code_scopes[code_scope_idx - 1], name, span)?
         {
             self.code_scopes[code_scope_idx - 1].locals[id].captured = true;
             Ok(Some(Self::add_upvalue(
@@ -372,7 +571,7 @@ impl Compiler {
                 id,
                 true,
             )))
-        } else if let Some(id) = self.resolve_upvalue(code_scope_idx - 1, name, line)? {
+        } else if let Some(id) = self.resolve_upvalue(code_scope_idx - 1, name, span)? {
             Ok(Some(Self::add_upvalue(
                 &mut self.code_scopes[code_scope_idx],
                 id,
@@ -387,45 +586,149 @@ impl Compiler {
         &mut self,
         can_assign: bool,
         name: String,
-        line: usize,
+        span: Span,
     ) -> Result<(), CompilerError> {
+        let line = span.line;
         let (set_op, get_op) = if let Some(id) =
-            Self::resolve_local(&self.code_scope(), &name, line)?
+            Self::resolve_local(&self.code_scope(), &name, span)?
         {
             (OpCode::SetLocal(id), OpCode::GetLocal(id))
-        } else if let Some(id) = self.resolve_upvalue(self.code_scopes.len() - 1, &name, line)? {
+        } else if let Some(id) = self.resolve_enclosing(&name, span)? {
+            (OpCode::SetEnclosing(id), OpCode::GetEnclosing(id))
+        } else if let Some(id) = self.resolve_upvalue(self.code_scopes.len() - 1, &name, span)? {
             (OpCode::SetUpValue(id), OpCode::GetUpValue(id))
         } else {
+            let slot = self.heap.global_names.slot(&name);
             let str_ptr = self.add_string(name);
             let str_idx = self.chunk().add_constant(Value::Object(str_ptr));
-            (OpCode::SetGlobal(str_idx), OpCode::GetGlobal(str_idx))
+            (OpCode::SetGlobal(str_idx, slot), OpCode::GetGlobal(str_idx, slot))
         };
 
         if can_assign && self.match_token(TokenType::Equal) {
+            let value_start = self.chunk().next();
+            self.expression()?;
+            self.emit_set(set_op, value_start, line);
+        } else if let Some(op) = self.compound_assignment(can_assign) {
+            let value_start = self.chunk().next();
+            self.chunk().append_chunk(get_op, line);
+            let operand_start = self.chunk().next();
             self.expression()?;
-            self.chunk().append_chunk(set_op, line);
+            self.emit_arithmetic(op, operand_start, line);
+            self.emit_set(set_op, value_start, line);
         } else {
             self.chunk().append_chunk(get_op, line);
         }
         Ok(())
     }
 
+    fn emit_set(&mut self, set_op: OpCode, value_start: usize, line: usize) {
+        match set_op {
+            OpCode::SetLocal(slot) if self.is_increment(value_start, slot) => {
+                self.replace_from(value_start, OpCode::IncrementLocal(slot), line)
+            }
+            _ => self.chunk().append_chunk(set_op, line),
+        };
+    }
+
+    //The arithmetic for a +=, -=, *= or /= if one comes next
+    fn compound_assignment(&mut self, can_assign: bool) -> Option<OpCode> {
+        if !can_assign {
+            return None;
+        }
+        let op = match self.peek().token_type {
+            TokenType::PlusEqual => OpCode::Add,
+            TokenType::MinusEqual => OpCode::Subtract,
+            TokenType::StarEqual => OpCode::Multiply,
+            TokenType::SlashEqual => OpCode::Divide,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    fn emit_arithmetic(&mut self, op: OpCode, operand_start: usize, line: usize) -> usize {
+        match op {
+            OpCode::Add => self.emit_add(operand_start, line),
+            _ => self.chunk().append_chunk(op, line),
+        }
+    }
+
+    //Whether the code since start is just slot + 1
+    fn is_increment(&mut self, start: usize, slot: usize) -> bool {
+        let chunk = self.chunk();
+        match chunk.code[start..] {
+            [OpCode::GetLocal(local), OpCode::AddConstant(idx)] if local == slot => {
+                matches!(chunk.constants[idx], Value::Integer(1))
+            }
+            _ => false,
+        }
+    }
+
+    //The constant's index if the code since start is only that constant
+    fn single_constant(&mut self, start: usize) -> Option<usize> {
+        match self.chunk().code[start..] {
+            [OpCode::Constant(idx)] => Some(idx),
+            _ => None,
+        }
+    }
+
+    //Drops the code from start on and emits op in its place
+    fn replace_from(&mut self, start: usize, op: OpCode, line: usize) -> usize {
+        let chunk = self.chunk();
+        chunk.code.truncate(start);
+        chunk.line_numbers.truncate(start);
+        chunk.append_chunk(op, line)
+    }
+
+    fn emit_add(&mut self, operand_start: usize, line: usize) -> usize {
+        match self.single_constant(operand_start) {
+            Some(idx) if self.specialize => {
+                self.replace_from(operand_start, OpCode::AddConstant(idx), line)
+            }
+            _ => self.chunk().append_chunk(OpCode::Add, line),
+        }
+    }
+
+    //Fuses local < constant, unless a jump lands on the constant, since then
+    //the local isn't the whole left operand
+    fn emit_compare(&mut self, operand_start: usize, comparison: Comparison, line: usize) -> usize {
+        let fusable = self.specialize
+            && operand_start > 0
+            && self.code_scope().last_jump_target != Some(operand_start);
+        if fusable {
+            let left = self.chunk().code[operand_start - 1];
+            let right = self.single_constant(operand_start);
+            if let (OpCode::GetLocal(slot), Some(idx)) = (left, right) {
+                let op = OpCode::CompareLocalConstant(slot, idx, comparison);
+                return self.replace_from(operand_start - 1, op, line);
+            }
+        }
+
+        let op = match comparison {
+            Comparison::Less => OpCode::Less,
+            Comparison::Greater => OpCode::Greater,
+        };
+        self.chunk().append_chunk(op, line)
+    }
+
     fn variable(&mut self, can_assign: bool) -> Result<(), CompilerError> {
         let token = self.previous();
-        let line = token.line;
+        let span = token.span();
         let name = token.lexeme.clone();
 
-        self.name_variable(can_assign, name, line)
+        self.name_variable(can_assign, name, span)
     }
 
     fn number(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
         let token = self.previous();
-        assert_eq!(token.token_type, TokenType::NumberToken);
-
-        let number: f64 = token.literal.as_ref().unwrap().parse().unwrap();
+        let literal = token.literal.as_ref().unwrap();
+        let number = match token.token_type {
+            TokenType::IntegerToken => Value::Integer(literal.parse().unwrap()),
+            _ => Value::Number(literal.parse().unwrap()),
+        };
         let line = token.line;
 
-        self.emit_constant(Value::Number(number), line)
+        self.emit_constant(number, line)
     }
 
     fn literal(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
@@ -440,7 +743,7 @@ impl Compiler {
             _ => {
                 return Err(CompilerError::SyntaxError(
                     String::from("Expected literal"),
-                    line,
+                    Span::line(line),
                 ))
             }
         };
@@ -449,24 +752,26 @@ impl Compiler {
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), CompilerError> {
-        let (token_type, line) = {
+        let (token_type, span) = {
             let token = self.advance();
-            (token.token_type, token.line)
+            (token.token_type, token.span())
         };
         if let Some(prefix_fn) = self.get_rule(token_type).prefix {
             let can_assign = precedence <= Precedence::Assignment;
             prefix_fn(self, can_assign)?; // Calls as a method
+        } else if token_type == TokenType::Fun {
+            self.function_value(span)?;
         } else {
             return Err(CompilerError::SyntaxError(
                 String::from("Expected expression."),
-                line,
+                span,
             ));
         }
 
         while precedence <= self.get_rule(self.peek().token_type).precedence {
-            let (token_type, line) = {
+            let (token_type, span) = {
                 let token = self.advance();
-                (token.token_type, token.line)
+                (token.token_type, token.span())
             };
             if let Some(infix_fn) = self.get_rule(token_type).infix {
                 let can_assign = precedence <= Precedence::Assignment;
@@ -474,7 +779,7 @@ impl Compiler {
             } else {
                 return Err(CompilerError::SyntaxError(
                     String::from("Expected expression."),
-                    line,
+                    span,
                 ));
             }
         }
@@ -482,6 +787,41 @@ impl Compiler {
         Ok(())
     }
 
+    //Lox has no function expressions. Skip over what looks like one, parameters
+    //and body, and carry on as if it were nil.
+    fn function_value(&mut self, span: Span) -> Result<(), CompilerError> {
+        self.report(CompilerError::SyntaxError(
+            String::from("'fun' declares a named function and can't be used as a value."),
+            span,
+        ));
+        self.match_token(TokenType::Identifier);
+        for (open, close) in [
+            (TokenType::LeftParen, TokenType::RightParen),
+            (TokenType::LeftBrace, TokenType::RightBrace),
+        ] {
+            if self.check_token(open) {
+                self.skip_balanced(open, close);
+            }
+        }
+        self.chunk().append_chunk(OpCode::Nil, span.line);
+        Ok(())
+    }
+
+    fn skip_balanced(&mut self, open: TokenType, close: TokenType) {
+        let mut depth = 0;
+        while !self.is_at_end() {
+            let token_type = self.advance().token_type;
+            if token_type == open {
+                depth += 1;
+            } else if token_type == close {
+                depth -= 1;
+                if depth == 0 {
+                    return;
+                }
+            }
+        }
+    }
+
     fn expression(&mut self) -> Result<(), CompilerError> {
         self.parse_precedence(Precedence::Assignment)
     }
@@ -505,27 +845,33 @@ impl Compiler {
 
         //Parse operators of higher precedence first
         let new_precedence = self.get_rule(token_type).precedence.next().unwrap();
+        let operand_start = self.chunk().next();
         self.parse_precedence(new_precedence)?;
 
         //Deal with the token itself
         match token_type {
-            TokenType::Plus => self.chunk().append_chunk(OpCode::Add, line),
+            TokenType::Plus => self.emit_add(operand_start, line),
             TokenType::Minus => self.chunk().append_chunk(OpCode::Subtract, line),
             TokenType::Star => self.chunk().append_chunk(OpCode::Multiply, line),
             TokenType::Slash => self.chunk().append_chunk(OpCode::Divide, line),
+            TokenType::Ampersand => self.chunk().append_chunk(OpCode::BitAnd, line),
+            TokenType::Pipe => self.chunk().append_chunk(OpCode::BitOr, line),
+            TokenType::Caret => self.chunk().append_chunk(OpCode::BitXor, line),
+            TokenType::LessLess => self.chunk().append_chunk(OpCode::ShiftLeft, line),
+            TokenType::GreaterGreater => self.chunk().append_chunk(OpCode::ShiftRight, line),
             TokenType::EqualEqual => self.chunk().append_chunk(OpCode::Equal, line),
             TokenType::BangEqual => {
                 self.chunk().append_chunk(OpCode::Equal, line);
                 self.chunk().append_chunk(OpCode::Not, line)
             }
-            TokenType::Greater => self.chunk().append_chunk(OpCode::Greater, line),
+            TokenType::Greater => self.emit_compare(operand_start, Comparison::Greater, line),
             TokenType::GreaterEqual => {
-                self.chunk().append_chunk(OpCode::Less, line);
+                self.emit_compare(operand_start, Comparison::Less, line);
                 self.chunk().append_chunk(OpCode::Not, line)
             }
-            TokenType::Less => self.chunk().append_chunk(OpCode::Less, line),
+            TokenType::Less => self.emit_compare(operand_start, Comparison::Less, line),
             TokenType::LessEqual => {
-                self.chunk().append_chunk(OpCode::Greater, line);
+                self.emit_compare(operand_start, Comparison::Greater, line);
                 self.chunk().append_chunk(OpCode::Not, line)
             }
             _ => unimplemented!(),
@@ -535,16 +881,47 @@ impl Compiler {
     }
 
     fn string(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        assert_eq!(self.previous().token_type, TokenType::StringToken);
+        self.string_segment();
+        Ok(())
+    }
+
+    //Emits the previous string or interpolation token's text as a constant
+    fn string_segment(&mut self) {
         let (str_value, line) = {
             let token = self.previous();
-            assert_eq!(token.token_type, TokenType::StringToken);
-            let str_value = token.literal.as_ref().unwrap().clone();
-            (str_value, token.line)
+            (token.literal.as_ref().unwrap().clone(), token.line)
         };
         let str_ptr = self.add_string(str_value);
         let const_idx = self.chunk().add_constant(Value::Object(str_ptr));
         self.chunk().append_chunk(OpCode::Constant(const_idx), line);
-        Ok(())
+    }
+
+    //"a ${b} c" comes in as Interpolation("a "), b's tokens, then StringToken(" c"),
+    //and is compiled as "a " + str(b) + " c"
+    fn interpolation(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        self.string_segment();
+        loop {
+            self.expression()?;
+            let line = self.previous().line;
+            self.chunk().append_chunk(OpCode::Stringify, line);
+            self.chunk().append_chunk(OpCode::Add, line);
+
+            let done = !self.match_token(TokenType::Interpolation);
+            if done {
+                self.try_consume(TokenType::StringToken, "Expected '}' after interpolation.")?;
+            }
+            //Empty segments like the one after "${a}" don't need adding
+            if !self.previous().literal.as_ref().unwrap().is_empty() {
+                let operand_start = self.chunk().code.len();
+                self.string_segment();
+                let line = self.previous().line;
+                self.emit_add(operand_start, line);
+            }
+            if done {
+                return Ok(());
+            }
+        }
     }
 
     fn unary(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
@@ -558,6 +935,7 @@ impl Compiler {
         match token_type {
             TokenType::Minus => self.chunk().append_chunk(OpCode::Negate, line),
             TokenType::Bang => self.chunk().append_chunk(OpCode::Not, line),
+            TokenType::Tilde => self.chunk().append_chunk(OpCode::BitNot, line),
             _ => unimplemented!(),
         };
 
@@ -566,9 +944,7 @@ impl Compiler {
 
     fn print_statement(&mut self) -> Result<(), CompilerError> {
         self.expression()?;
-        let line = self
-            .try_consume(TokenType::Semicolon, "Expected ';' after expression")?
-            .line;
+        let line = self.consume_semicolon("Expected ';' after expression")?.line;
 
         self.chunk().append_chunk(OpCode::Print, line);
 
@@ -577,11 +953,13 @@ impl Compiler {
 
     fn expression_statement(&mut self) -> Result<(), CompilerError> {
         self.expression()?;
-        let line = self
-            .try_consume(TokenType::Semicolon, "Expected ';' after expression")?
-            .line;
+        let line = self.consume_semicolon("Expected ';' after expression")?.line;
 
-        self.chunk().append_chunk(OpCode::Pop, line);
+        if self.echo_results && self.code_scopes.len() == 1 && self.code_scope().depth == 0 {
+            self.chunk().append_chunk(OpCode::Echo, line);
+        } else {
+            self.chunk().append_chunk(OpCode::Pop, line);
+        }
 
         Ok(())
     }
@@ -599,6 +977,17 @@ impl Compiler {
             }
         } {
             let local = self.code_scope().locals.pop().unwrap();
+            let slot = self.code_scope().locals.len();
+            let end = self.chunk().next();
+            if let Some(info) = self
+                .chunk()
+                .locals
+                .iter_mut()
+                .rev()
+                .find(|info| info.slot == slot && info.end == usize::MAX)
+            {
+                info.end = end;
+            }
             if local.captured {
                 self.chunk()
                     .append_chunk(OpCode::CloseUpvalue, local.name.line);
@@ -608,6 +997,145 @@ impl Compiler {
         }
     }
 
+    //Pops the locals a jump out of the targeted loop leaves behind, keeping
+    //them declared for the rest of the body. That's the innermost loop unless
+    //a label names another, and its index in loops is returned.
+    fn discard_loop_locals(&mut self, keyword: &str) -> Result<usize, CompilerError> {
+        let span = self.previous().span();
+        let innermost = match self.code_scope().loops.len().checked_sub(1) {
+            Some(innermost) => innermost,
+            None => {
+                return Err(CompilerError::SyntaxError(
+                    format!("Can't use '{}' outside of a loop.", keyword),
+                    span,
+                ))
+            }
+        };
+        let target = if self.match_token(TokenType::Identifier) {
+            let label = self.previous().clone();
+            let target = self
+                .code_scope()
+                .loops
+                .iter()
+                .rposition(|scope| scope.label.as_deref() == Some(label.lexeme.as_str()));
+            if target.is_none() {
+                self.report(CompilerError::SyntaxError(
+                    format!("No enclosing loop is labeled '{}'.", label.lexeme),
+                    label.span(),
+                ));
+            }
+            target.unwrap_or(innermost)
+        } else {
+            innermost
+        };
+        let scope = &self.code_scope().loops[target];
+        let (depth, tries) = (scope.depth, scope.tries);
+        self.consume_semicolon(&format!("Expected ';' after '{}'", keyword))?;
+        //Finally blocks run while the locals they might see are still there
+        self.leave_tries(tries, span.line);
+
+        let discarded: Vec<bool> = self
+            .code_scope()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| local.captured)
+            .collect();
+        for captured in discarded {
+            let op = if captured { OpCode::CloseUpvalue } else { OpCode::Pop };
+            self.chunk().append_chunk(op, span.line);
+        }
+        Ok(target)
+    }
+
+    //Ends the handlers a jump leaves behind, innermost first, running the
+    //finally blocks among them on the way. Each is compiled with only the
+    //handlers outside it, so a jump out of a finally doesn't run it again.
+    fn leave_tries(&mut self, tries: usize, line: usize) {
+        let left = self.code_scope().tries.split_off(tries);
+        for (i, finally) in left.iter().enumerate().rev() {
+            self.chunk().append_chunk(OpCode::EndTry, line);
+            if let Some(finally) = finally {
+                self.code_scope().tries.extend_from_slice(&left[..i]);
+                self.inline_finally(*finally);
+                self.code_scope().tries.truncate(tries);
+            }
+        }
+        self.code_scope().tries.extend(left);
+    }
+
+    //Compiles a finally block again where a jump leaves its try. Locals the
+    //try declared are still on the stack, but hidden so names resolve as they
+    //do where the block is written. Errors are left to its own compile.
+    fn inline_finally(&mut self, finally: Finally) {
+        let (resume, has_error) = (self.current, self.has_error);
+        let reported = self.recovered.len();
+        let hidden: Vec<String> = self.code_scope().locals[finally.locals..]
+            .iter_mut()
+            .map(|local| mem::take(&mut local.name.lexeme))
+            .collect();
+
+        self.current = finally.block;
+        if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            let _ = self.block();
+            self.end_scope();
+        }
+
+        for (local, name) in self.code_scope().locals[finally.locals..].iter_mut().zip(hidden) {
+            local.name.lexeme = name;
+        }
+        self.current = resume;
+        self.has_error = has_error;
+        self.recovered.truncate(reported);
+    }
+
+    fn break_statement(&mut self) -> Result<(), CompilerError> {
+        let line = self.previous().line;
+        let target = self.discard_loop_locals("break")?;
+        let jump = self.chunk().append_chunk(OpCode::Jump(0), line);
+        self.code_scope().loops[target].breaks.push(jump);
+        Ok(())
+    }
+
+    fn continue_statement(&mut self) -> Result<(), CompilerError> {
+        let line = self.previous().line;
+        let target = self.discard_loop_locals("continue")?;
+        match self.code_scope().loops[target].start {
+            Some(start) => {
+                let offset = (self.chunk().top() + 2) - start;
+                self.chunk().append_chunk(OpCode::Loop(offset), line);
+            }
+            None => {
+                let jump = self.chunk().append_chunk(OpCode::Jump(0), line);
+                self.code_scope().loops[target].continues.push(jump);
+            }
+        }
+        Ok(())
+    }
+
+    fn begin_loop(&mut self, start: Option<usize>, label: Option<String>) {
+        let depth = self.code_scope().depth;
+        let tries = self.code_scope().tries.len();
+        self.code_scope().loops.push(LoopScope {
+            label,
+            start,
+            depth,
+            tries,
+            continues: vec![],
+            breaks: vec![],
+        });
+    }
+
+    //Lands every break on what comes next
+    fn end_loop(&mut self) {
+        let scope = self.code_scope().loops.pop().unwrap();
+        for jump in scope.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
     fn block(&mut self) -> Result<(), CompilerError> {
         while !self.check_token(TokenType::RightBrace) && !self.check_token(TokenType::EOF) {
             self.declaration()?;
@@ -618,31 +1146,104 @@ impl Compiler {
     }
 
     fn return_statement(&mut self) -> Result<(), CompilerError> {
-        let line = self.previous().line;
+        let span = self.previous().span();
+        let line = span.line;
 
         let fn_type = self.code_scope().function.fn_type;
         if fn_type == FnType::Script {
             return Err(CompilerError::SyntaxError(
                 String::from("Can't return from top-level code."),
-                line,
+                span,
             ));
         } else if fn_type == FnType::Initializer {
             return Err(CompilerError::SyntaxError(
                 String::from("Can't return from within an initializer"),
-                line,
+                span,
             ));
+        } else if fn_type == FnType::Setter {
+            let message = String::from("Can't return from within a setter");
+            self.report(CompilerError::SyntaxError(message, span));
         }
 
         if self.match_token(TokenType::Semicolon) {
             self.chunk().append_chunk(OpCode::Nil, line);
         } else {
+            //return a, b; gives a tuple
+            let mut count = 1;
             self.expression()?;
-            self.try_consume(TokenType::Semicolon, "Expected ';' after return value")?;
+            while self.match_token(TokenType::Comma) {
+                self.expression()?;
+                count += 1;
+            }
+            if count > 1 {
+                self.chunk().append_chunk(OpCode::BuildTuple(count), line);
+            }
+            self.consume_semicolon("Expected ';' after return value")?;
         }
+        //The value waits in a slot of its own while finally blocks run
+        let slot = self.hidden_local(line);
+        self.leave_tries(0, line);
+        self.code_scope().locals.truncate(slot);
         self.chunk().append_chunk(OpCode::Return, line);
         Ok(())
     }
 
+    //Hands a value to whoever called next(), suspending the function until the
+    //next call picks it up again
+    fn yield_statement(&mut self) -> Result<(), CompilerError> {
+        let span = self.previous().span();
+        let line = span.line;
+
+        match self.code_scope().function.fn_type {
+            FnType::Script => {
+                return Err(CompilerError::SyntaxError(
+                    String::from("Can't yield from top-level code."),
+                    span,
+                ))
+            }
+            FnType::Initializer | FnType::Getter | FnType::Setter => {
+                let message = String::from("Only functions and methods can yield.");
+                self.report(CompilerError::SyntaxError(message, span));
+            }
+            FnType::Function | FnType::Method => {}
+        }
+        //A handler can't outlast the frame it was set up in
+        if !self.code_scope().tries.is_empty() {
+            let message = String::from("Can't yield inside a try block.");
+            self.report(CompilerError::SyntaxError(message, span));
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.chunk().append_chunk(OpCode::Nil, line);
+        } else {
+            self.expression()?;
+            self.consume_semicolon("Expected ';' after yielded value")?;
+        }
+        self.chunk().append_chunk(OpCode::Yield, line);
+        self.code_scope().yields = true;
+        Ok(())
+    }
+
+    //Turns the function just compiled into a generator, which suspends as soon
+    //as it's called and finishes when it returns. Jumps are relative, so
+    //putting an instruction in front doesn't move their targets.
+    fn make_generator(&mut self, line: usize) {
+        let chunk = self.chunk();
+        for op in chunk.code.iter_mut() {
+            if let OpCode::Return = op {
+                *op = OpCode::GeneratorReturn;
+            }
+        }
+        chunk.code.insert(0, OpCode::Generator);
+        chunk.line_numbers.insert(0, line);
+        for local in chunk.locals.iter_mut() {
+            local.start += 1;
+            if local.end != usize::MAX {
+                local.end += 1;
+            }
+        }
+    }
+
     fn statement(&mut self) -> Result<(), CompilerError> {
         if self.match_token(TokenType::Print) {
             self.print_statement()
@@ -655,10 +1256,34 @@ impl Compiler {
             self.if_statement()
         } else if self.match_token(TokenType::Return) {
             self.return_statement()
+        } else if self.match_token(TokenType::Yield) {
+            self.yield_statement()
         } else if self.match_token(TokenType::While) {
-            self.while_statement()
+            self.while_statement(None)
+        } else if self.match_token(TokenType::Do) {
+            self.do_while_statement(None)
         } else if self.match_token(TokenType::For) {
-            self.for_statement()
+            self.for_statement(None)
+        } else if self.check_token(TokenType::Identifier) && self.check_next(TokenType::Colon) {
+            self.labeled_statement()
+        } else if self.match_token(TokenType::Switch) {
+            self.switch_statement()
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement()
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement()
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement()
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement()
+        } else if self.match_token(TokenType::Else) {
+            //Most likely an extra ';' or statement ended the 'if' early.
+            //Compile the branch anyway so its own errors still show up.
+            self.report(CompilerError::SyntaxError(
+                String::from("'else' without a matching 'if'."),
+                self.previous().span(),
+            ));
+            self.statement()
         } else {
             self.expression_statement()
         }
@@ -666,7 +1291,10 @@ impl Compiler {
 
     fn parse_variable(&mut self, error_msg: &str) -> Result<u64, CompilerError> {
         let token = self.try_consume(TokenType::Identifier, error_msg)?;
+        Ok(self.declare_variable(token))
+    }
 
+    fn declare_variable(&mut self, token: Token) -> u64 {
         if self.code_scope().depth > 0 {
             let local = Local {
                 name: token.clone(),
@@ -677,31 +1305,65 @@ impl Compiler {
             self.code_scope().locals.push(local);
             //I think shadowing is fine, so we won't look for duplicate id's
 
-            Ok(0) //Us a dummy address
+            0 //Us a dummy address
         } else {
             let name = token.literal.unwrap().clone();
-            Ok(self.add_string(name))
+            self.add_string(name)
+        }
+    }
+
+    //Types are only for `check --types`, so they're skipped without a trace
+    fn type_annotation(&mut self) -> Result<(), CompilerError> {
+        if self.match_token(TokenType::Colon) {
+            self.try_consume(TokenType::Identifier, "Expected type name after ':'.")?;
         }
+        Ok(())
     }
 
     fn mark_initialized(&mut self) {
-        self.code_scope().locals.last_mut().unwrap().initialized = true;
+        let slot = self.code_scope().locals.len() - 1;
+        self.mark_slot_initialized(slot);
+    }
+
+    fn mark_slot_initialized(&mut self, slot: usize) {
+        let local = &mut self.code_scope().locals[slot];
+        local.initialized = true;
+        let name = local.name.lexeme.clone();
+        let start = self.chunk().next();
+        self.chunk().locals.push(LocalInfo {
+            name,
+            slot,
+            start,
+            end: usize::MAX,
+        });
     }
 
     fn finish_define(&mut self, str_ptr: u64, line: usize) {
         if self.code_scope().depth == 0 {
             //Only define globals at scope depth
+            let name = self.heap.deref(str_ptr).as_string().clone();
+            let slot = self.heap.global_names.slot(&name);
             let str_idx = self.chunk().add_constant(Value::Object(str_ptr));
             self.chunk()
-                .append_chunk(OpCode::DefineGlobal(str_idx), line);
+                .append_chunk(OpCode::DefineGlobal(str_idx, slot), line);
         } else {
             self.mark_initialized();
         }
     }
 
     fn var_declaration(&mut self) -> Result<(), CompilerError> {
+        if self.match_token(TokenType::LeftBracket) {
+            return self.destructuring_declaration(TokenType::RightBracket);
+        } else if self.match_token(TokenType::LeftBrace) {
+            return self.destructuring_declaration(TokenType::RightBrace);
+        }
         let str_ptr = self.parse_variable("Expected variable name.")?;
         let line = self.previous().line;
+        self.type_annotation()?;
+
+        if self.check_token(TokenType::Comma) {
+            return self.unpacking_declaration(str_ptr, line);
+        }
 
         if self.match_token(TokenType::Equal) {
             self.expression()?;
@@ -709,10 +1371,7 @@ impl Compiler {
             self.chunk().append_chunk(OpCode::Nil, line);
         }
 
-        self.try_consume(
-            TokenType::Semicolon,
-            "Expected ';' after variable declaration",
-        )?;
+        self.consume_semicolon("Expected ';' after variable declaration")?;
 
         //If global, define as global, if local, mark initialized
         self.finish_define(str_ptr, line);
@@ -720,29 +1379,154 @@ impl Compiler {
         Ok(())
     }
 
-    fn parse_function(&mut self, fn_type: FnType) -> Result<(), CompilerError> {
-        //Swap in a new scope for the new function
-        let function_name = self.previous().lexeme.clone();
-        self.code_scopes.push(CodeScope {
-            function: Function::new(function_name, 0, fn_type),
-            locals: vec![],
-            upvalues: vec![],
-            depth: 0,
-        });
+    //var a, b = f(); takes apart the tuple f returns, while var a, b; gives two nils
+    fn unpacking_declaration(&mut self, first: u64, line: usize) -> Result<(), CompilerError> {
+        let mut names = vec![first];
+        while self.match_token(TokenType::Comma) {
+            names.push(self.parse_variable("Expected variable name after ','.")?);
+            self.type_annotation()?;
+        }
 
-        self.begin_scope();
-        self.try_consume(TokenType::LeftParen, "Expected '(' after function name.")?;
+        if self.match_token(TokenType::Equal) {
+            self.expression()?;
+            self.chunk().append_chunk(OpCode::Unpack(names.len()), line);
+        } else {
+            for _ in names.iter() {
+                self.chunk().append_chunk(OpCode::Nil, line);
+            }
+        }
+        self.consume_semicolon("Expected ';' after variable declaration")?;
+        self.define_unpacked(names, line);
+        Ok(())
+    }
 
-        let this_name = if FnType::Method == fn_type || FnType::Initializer == fn_type {
-            "this"
+    //var [a, b] = list; or var {x, y} = instance; where x and y are field names
+    fn destructuring_declaration(&mut self, close: TokenType) -> Result<(), CompilerError> {
+        let line = self.previous().line;
+        let mut names = vec![];
+        let mut fields = vec![];
+        loop {
+            let token = self.try_consume(TokenType::Identifier, "Expected variable name.")?;
+            fields.push(token.lexeme.clone());
+            names.push(self.declare_variable(token));
+            self.type_annotation()?;
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        let message = if close == TokenType::RightBracket {
+            "Expected ']' after names."
         } else {
-            ""
+            "Expected '}' after field names."
         };
-        self.code_scope().locals.push(Local {
-            name: Token {
-                token_type: TokenType::This,
-                lexeme: String::from(this_name), //Use lexeme some places and literal others... should standardize
+        self.try_consume(close, message)?;
+        self.try_consume(TokenType::Equal, "Expected '=' after a destructuring pattern.")?;
+        self.expression()?;
+
+        if close == TokenType::RightBracket {
+            self.chunk().append_chunk(OpCode::UnpackList(names.len()), line);
+        } else {
+            //The names go in consecutive constants
+            let mut first = None;
+            for field in fields {
+                let str_ptr = self.add_string(field);
+                let idx = self.chunk().add_constant(Value::Object(str_ptr));
+                first.get_or_insert(idx);
+            }
+            let op = OpCode::UnpackFields(first.unwrap(), names.len());
+            self.chunk().append_chunk(op, line);
+        }
+        self.consume_semicolon("Expected ';' after variable declaration")?;
+        self.define_unpacked(names, line);
+        Ok(())
+    }
+
+    //Defines what an unpacking op left on the stack, first name lowest
+    fn define_unpacked(&mut self, names: Vec<u64>, line: usize) {
+        if self.code_scope().depth == 0 {
+            //The last value is on top
+            for str_ptr in names.into_iter().rev() {
+                self.finish_define(str_ptr, line);
+            }
+        } else {
+            let locals = self.code_scope().locals.len();
+            for slot in locals - names.len()..locals {
+                self.mark_slot_initialized(slot);
+            }
+        }
+    }
+
+    //import "path.lox" [as name]; binds the module to name, or the file's name
+    fn import_declaration(&mut self) -> Result<(), CompilerError> {
+        let line = self.previous().line;
+        let message = "Expected module path after 'import'.";
+        let path = self.try_consume(TokenType::StringToken, message)?;
+        let path_str = path.literal.clone().unwrap();
+
+        let name = if self.check_token(TokenType::Identifier) && self.peek().lexeme == "as" {
+            self.advance();
+            self.try_consume(TokenType::Identifier, "Expected module name after 'as'.")?
+        } else {
+            let name = Module::default_name(&path_str);
+            let mut chars = name.chars();
+            let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && scanner::keyword(name).is_none();
+            if !valid {
+                self.report(CompilerError::SyntaxError(
+                    format!("Module name '{}' isn't an identifier, use 'as' to name it.", name),
+                    path.span(),
+                ));
+            }
+            Token {
+                token_type: TokenType::Identifier,
+                lexeme: String::from(name),
+                literal: Some(String::from(name)),
+                ..path.clone()
+            }
+        };
+        self.consume_semicolon("Expected ';' after import")?;
+
+        let str_ptr = self.declare_variable(name);
+        let path_ptr = self.add_string(path_str);
+        let path_idx = self.chunk().add_constant(Value::Object(path_ptr));
+        self.chunk().append_chunk(OpCode::Import(path_idx), line);
+        self.finish_define(str_ptr, line);
+        Ok(())
+    }
+
+    fn parse_function(
+        &mut self,
+        fn_type: FnType,
+        enclosing_access: bool,
+    ) -> Result<(), CompilerError> {
+        //Swap in a new scope for the new function
+        let function_name = self.previous().lexeme.clone();
+        let function_line = self.previous().line;
+        self.code_scopes.push(CodeScope {
+            function: Function::new(function_name, 0, fn_type),
+            locals: vec![],
+            upvalues: vec![],
+            depth: 0,
+            last_jump_target: None,
+            enclosing_access,
+            loops: vec![],
+            tries: vec![],
+            yields: false,
+        });
+
+        self.begin_scope();
+
+        let this_name = match fn_type {
+            FnType::Method | FnType::Initializer | FnType::Getter | FnType::Setter => "this",
+            FnType::Function | FnType::Script => "",
+        };
+        self.code_scope().locals.push(Local {
+            name: Token {
+                token_type: TokenType::This,
+                lexeme: String::from(this_name), //Use lexeme some places and literal others... should standardize
                 line: 0,
+                column: 0,
                 literal: Some(String::from(this_name)),
             },
             depth: 0,
@@ -750,18 +1534,88 @@ impl Compiler {
             captured: false,
         });
 
+        //Getters are just a name and a body
+        if fn_type == FnType::Setter {
+            self.try_consume(TokenType::Equal, "Expected '=' after setter name.")?;
+        }
+        if fn_type != FnType::Getter {
+            self.parameters()?;
+        }
+        if fn_type == FnType::Setter {
+            let arity = self.code_scope().function.arity;
+            if arity != 1 {
+                let span = self.previous().span();
+                let message = String::from("A setter takes exactly one parameter.");
+                self.report(CompilerError::SyntaxError(message, span));
+            }
+            //A hidden copy of the value, which the setter evaluates to however
+            //the parameter changes
+            let depth = self.code_scope().depth;
+            self.chunk().append_chunk(OpCode::GetLocal(arity), function_line);
+            self.code_scope().locals.push(Local {
+                name: Token {
+                    token_type: TokenType::Equal,
+                    lexeme: String::new(),
+                    line: 0,
+                    column: 0,
+                    literal: Some(String::new()),
+                },
+                depth,
+                initialized: true,
+                captured: false,
+            });
+        }
+        self.type_annotation()?;
+
+        self.try_consume(TokenType::LeftBrace, "Expected '{' before function body.")?;
+        self.block()?;
+
+        let line = self.previous().line; //The closing brace
+        if fn_type == FnType::Initializer {
+            //Return this at the end of an initializer
+            self.chunk().append_chunk(OpCode::GetLocal(0), line);
+        } else if fn_type == FnType::Setter {
+            let slot = self.code_scope().function.arity + 1;
+            self.chunk().append_chunk(OpCode::GetLocal(slot), line);
+        } else {
+            //return nil if we fall off the end of the function
+            self.chunk().append_chunk(OpCode::Nil, line);
+        }
+        self.chunk().append_chunk(OpCode::Return, line);
+        if self.code_scope().yields {
+            self.make_generator(function_line);
+        }
+
+        let function_scope = self.code_scopes.pop().unwrap();
+        self.emit_closure(function_scope, function_line);
+
+        Ok(())
+    }
+
+    //The parenthesized parameter list of the function being compiled
+    fn parameters(&mut self) -> Result<(), CompilerError> {
+        self.try_consume(TokenType::LeftParen, "Expected '(' after function name.")?;
         if !self.check_token(TokenType::RightParen) {
             loop {
                 self.code_scope().function.arity += 1;
+                if self.match_token(TokenType::DotDotDot) {
+                    self.code_scope().function.variadic = true;
+                }
 
                 let str_ptr = self.parse_variable("Expected parameter name")?;
                 let line = self.previous().line;
+                self.type_annotation()?;
 
                 self.finish_define(str_ptr, line);
 
                 if !self.match_token(TokenType::Comma) {
                     break;
                 }
+                if self.code_scope().function.variadic {
+                    let span = self.previous().span();
+                    let message = String::from("A rest parameter must be the last parameter.");
+                    self.report(CompilerError::SyntaxError(message, span));
+                }
             }
         }
 
@@ -769,25 +1623,13 @@ impl Compiler {
             TokenType::RightParen,
             "Expected ')' after function parameters.",
         )?;
+        Ok(())
+    }
 
-        self.try_consume(TokenType::LeftBrace, "Expected '{' before function body.")?;
-        self.block()?;
-
-        let line = self.peek().line;
-        if fn_type == FnType::Initializer {
-            //Return this at the end of an initializer
-            self.chunk().append_chunk(OpCode::GetLocal(0), line);
-        } else {
-            //return nil if we fall off the end of the function
-            self.chunk().append_chunk(OpCode::Nil, line);
-        }
-        self.chunk().append_chunk(OpCode::Return, line);
-
-        let mut function_scope = self.code_scopes.pop().unwrap();
-        let line = self.peek().line;
-
+    fn emit_closure(&mut self, mut function_scope: CodeScope, line: usize) {
         let upvalue_count = function_scope.upvalues.len();
         function_scope.function.upvalue_count = upvalue_count;
+        function_scope.function.chunk.finish();
         let addr = self
             .heap
             .add_to_heap(Object::Function(function_scope.function));
@@ -798,15 +1640,78 @@ impl Compiler {
         for upvalue in function_scope.upvalues {
             self.chunk().append_chunk(OpCode::Upvalue(upvalue), line);
         }
+    }
 
-        Ok(())
+    // Whether the local function just named can outlive its frame or be called
+    // from anywhere else. It doesn't escape if the rest of the block only ever
+    // calls it by name. Any mention from a nested function or class counts as
+    // escaping, as does nesting functions in its own body, since those would
+    // capture through it, and yielding, since next() can be called from anywhere.
+    fn escapes(&self, name: &str) -> bool {
+        let rest = &self.tokens[self.current..];
+        let mut depth = 0;
+        //Brace depth a nested function or class body opened at, the first being its own
+        let mut nested_from: Option<usize> = None;
+        let mut own_body = true;
+        let mut body_next = true;
+        for (i, token) in rest.iter().enumerate() {
+            match token.token_type {
+                TokenType::Fun | TokenType::Class => {
+                    if own_body && nested_from.is_some() {
+                        return true;
+                    }
+                    body_next = nested_from.is_none();
+                }
+                TokenType::Yield if own_body && nested_from.is_some() => return true,
+                //A match is a function too, with its value inside
+                TokenType::Match => {
+                    if own_body && nested_from.is_some() {
+                        return true;
+                    }
+                    if nested_from.is_none() {
+                        nested_from = Some(depth);
+                        body_next = false;
+                    }
+                }
+                TokenType::LeftBrace => {
+                    if body_next {
+                        nested_from = Some(depth);
+                        body_next = false;
+                    }
+                    depth += 1;
+                }
+                TokenType::RightBrace => {
+                    if depth == 0 {
+                        return false; //The end of the enclosing block
+                    }
+                    depth -= 1;
+                    if nested_from == Some(depth) {
+                        nested_from = None;
+                        own_body = false;
+                    }
+                }
+                TokenType::Identifier if token.lexeme == name => {
+                    let next = rest.get(i + 1).map(|next| next.token_type);
+                    let called = next == Some(TokenType::LeftParen);
+                    let property = i > 0 && rest[i - 1].token_type == TokenType::Dot;
+                    if nested_from.is_some() || !called || property {
+                        return true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        false
     }
 
     fn fun_declaration(&mut self) -> Result<(), CompilerError> {
         let str_ptr = self.parse_variable("Expected function name")?;
         let line = self.peek().line;
 
-        self.parse_function(FnType::Function)?;
+        let name = self.previous().lexeme.clone();
+        let enclosing_access =
+            self.specialize && self.code_scope().depth > 0 && !self.escapes(&name);
+        self.parse_function(FnType::Function, enclosing_access)?;
 
         self.finish_define(str_ptr, line);
 
@@ -814,83 +1719,440 @@ impl Compiler {
     }
 
     fn method(&mut self) -> Result<(), CompilerError> {
+        let is_static = self.match_token(TokenType::Static);
         let token = self.try_consume(TokenType::Identifier, "Expected method name.")?;
 
         let method_name = token.lexeme.clone();
-        let fn_type = if method_name == "init" {
+        if is_static && method_name == "init" {
+            let message = String::from("Initializers can't be static.");
+            self.report(CompilerError::SyntaxError(message, token.span()));
+        }
+        //A getter has no parameter list and a setter's name is followed by '='
+        let fn_type = if is_static {
+            FnType::Method
+        } else if self.check_token(TokenType::LeftBrace) || self.check_token(TokenType::Colon) {
+            FnType::Getter
+        } else if self.check_token(TokenType::Equal) {
+            FnType::Setter
+        } else if method_name == "init" {
             FnType::Initializer
         } else {
             FnType::Method
         };
 
+        let symbol = self.heap.symbols.intern(&method_name);
         let addr = self.heap.add_to_heap(Object::String(method_name));
         let constant_idx = self.chunk().add_constant(Value::Object(addr));
-        self.parse_function(fn_type)?;
-
-        self.chunk()
-            .append_chunk(OpCode::Method(constant_idx), token.line);
+        self.class_scopes.last_mut().unwrap().in_static = is_static;
+        let result = self.parse_function(fn_type, false);
+        self.class_scopes.last_mut().unwrap().in_static = false;
+        result?;
+
+        let op = match fn_type {
+            _ if is_static => OpCode::StaticMethod(constant_idx, symbol),
+            FnType::Getter => OpCode::Getter(constant_idx, symbol),
+            FnType::Setter => OpCode::Setter(constant_idx, symbol),
+            _ => OpCode::Method(constant_idx, symbol),
+        };
+        self.chunk().append_chunk(op, token.line);
 
         Ok(())
     }
 
     fn this(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
         if self.class_scopes.len() == 0 {
-            let line = self.previous().line;
+            let span = self.previous().span();
             Err(CompilerError::SyntaxError(
                 String::from("Can't use 'this' outside of a class"),
-                line,
+                span,
             ))
         } else {
             self.variable(false)
         }
     }
 
+    fn super_(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let span = self.previous().span();
+        let message = match self.class_scopes.last() {
+            None => Some("Can't use 'super' outside of a class"),
+            Some(scope) if !scope.has_superclass => {
+                Some("Can't use 'super' in a class with no superclass")
+            }
+            _ => None,
+        };
+        if let Some(message) = message {
+            return Err(CompilerError::SyntaxError(String::from(message), span));
+        }
+        if matches!(self.class_scopes.last(), Some(scope) if scope.in_static) {
+            let message = String::from("Can't use 'super' in a static method");
+            self.report(CompilerError::SyntaxError(message, span));
+        }
+
+        self.try_consume(TokenType::Dot, "Expected '.' after 'super'.")?;
+        let token = self.try_consume(TokenType::Identifier, "Expected superclass method name.")?;
+        let line = token.line;
+        let ptr = self.heap.add_to_heap(Object::String(token.lexeme.clone()));
+        let index = self.chunk().add_constant(Value::Object(ptr));
+        let symbol = self.heap.symbols.intern(&token.lexeme);
+
+        self.name_variable(false, String::from("this"), span)?;
+        if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list()?;
+            self.name_variable(false, String::from("super"), span)?;
+            self.chunk()
+                .append_chunk(OpCode::SuperInvoke(index, arg_count, symbol), line);
+        } else {
+            self.name_variable(false, String::from("super"), span)?;
+            self.chunk().append_chunk(OpCode::GetSuper(index, symbol), line);
+        }
+        Ok(())
+    }
+
     fn class_declaration(&mut self) -> Result<(), CompilerError> {
         let name_addr = self.parse_variable("Expected class name")?;
 
         let token = self.previous().clone();
         let name = token.lexeme.clone();
-        self.class_scopes.push(ClassScope { name: token });
+        let line = token.line;
+
+        //Declaring a global class again adds to it rather than replacing it
+        let reopen = self.code_scope().depth == 0;
+        self.emit_class(name.clone(), reopen, line);
+        self.finish_define(name_addr, line);
+
+        self.class_body(token)
+    }
+
+    // A class in expression position, whose name is optional and only shown
+    // when it's printed. Superclasses need a local for 'super', which can't go
+    // among the temporaries of an expression, so a class that has them is built
+    // by a function of its own called on the spot.
+    fn class_expression(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let keyword = self.previous().clone();
+        let name = if self.match_token(TokenType::Identifier) {
+            self.previous().lexeme.clone()
+        } else {
+            String::from("anonymous")
+        };
+        let line = keyword.line;
+
+        if !self.check_token(TokenType::Less) {
+            self.emit_class(name, false, line);
+            self.class_scopes.push(ClassScope {
+                name: keyword,
+                has_superclass: false,
+                in_static: false,
+            });
+            self.methods()?;
+            self.class_scopes.pop();
+            return Ok(());
+        }
+
+        self.code_scopes.push(CodeScope {
+            function: Function::new(name.clone(), 0, FnType::Function),
+            locals: vec![],
+            upvalues: vec![],
+            depth: 0,
+            last_jump_target: None,
+            enclosing_access: false,
+            loops: vec![],
+            tries: vec![],
+            yields: false,
+        });
+        self.begin_scope();
+        self.code_scope().locals.push(Local {
+            name: Token {
+                token_type: TokenType::This,
+                lexeme: String::new(),
+                line: 0,
+                column: 0,
+                literal: Some(String::new()),
+            },
+            depth: 0,
+            initialized: true,
+            captured: false,
+        });
+
+        //Held in a local named by the keyword, which no variable can shadow
+        self.emit_class(name, false, line);
+        let depth = self.code_scope().depth;
+        self.code_scope().locals.push(Local {
+            name: keyword.clone(),
+            depth,
+            initialized: false,
+            captured: false,
+        });
+        self.mark_initialized();
+        self.class_body(keyword.clone())?;
 
-        let offset = self.chunk().add_constant(Value::Object(name_addr));
         let line = self.previous().line;
+        self.name_variable(false, keyword.lexeme.clone(), keyword.span())?;
+        self.chunk().append_chunk(OpCode::Return, line);
+        let function_scope = self.code_scopes.pop().unwrap();
+        self.emit_closure(function_scope, keyword.line);
 
-        self.chunk().append_chunk(OpCode::Class(offset), line);
-        self.finish_define(name_addr, line);
+        self.chunk().append_chunk(OpCode::ThisPlaceholder, line);
+        self.chunk().append_chunk(OpCode::Call(0), line);
+        Ok(())
+    }
 
-        if self.match_token(TokenType::Less) {
-            let token = self.try_consume(TokenType::Identifier, "Expected superclass name")?;
-            let superclass_name = token.lexeme.clone();
-            let line = token.line;
+    //Arms bind names, which can't go among the temporaries of an expression
+    //either, so a match is built by a function of its own called on the spot.
+    //Each arm returns from it, and falling past them all throws.
+    fn match_expression(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let keyword = self.previous().clone();
+        let line = keyword.line;
+        self.try_consume(TokenType::LeftParen, "Expected '(' after 'match'.")?;
 
-            if superclass_name == name {
-                return Err(CompilerError::SyntaxError(
-                    String::from("A class can't inherit from itself"),
-                    line,
+        self.code_scopes.push(CodeScope {
+            function: Function::new(String::from("match"), 0, FnType::Function),
+            locals: vec![],
+            upvalues: vec![],
+            depth: 0,
+            last_jump_target: None,
+            enclosing_access: false,
+            loops: vec![],
+            tries: vec![],
+            yields: false,
+        });
+        self.begin_scope();
+        self.code_scope().locals.push(Local {
+            name: Token {
+                token_type: TokenType::This,
+                lexeme: String::new(),
+                line: 0,
+                column: 0,
+                literal: Some(String::new()),
+            },
+            depth: 0,
+            initialized: true,
+            captured: false,
+        });
+
+        //Held in a local named by the keyword, which no variable can shadow
+        self.expression()?;
+        self.try_consume(TokenType::RightParen, "Expected ')' after match value.")?;
+        let depth = self.code_scope().depth;
+        self.code_scope().locals.push(Local {
+            name: keyword.clone(),
+            depth,
+            initialized: false,
+            captured: false,
+        });
+        self.mark_initialized();
+        let slot = self.code_scope().locals.len() - 1;
+
+        self.try_consume(TokenType::LeftBrace, "Expected '{' before match arms.")?;
+        let mut has_catch_all = false;
+        while !self.check_token(TokenType::RightBrace) && !self.check_token(TokenType::EOF) {
+            if has_catch_all {
+                //Compiled anyway so their own errors still show up
+                self.report(CompilerError::SyntaxError(
+                    String::from("Arms can't come after one that matches everything."),
+                    self.peek().span(),
                 ));
             }
+            has_catch_all |= self.match_arm(slot)?;
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.try_consume(TokenType::RightBrace, "Expected '}' after match arms.")?;
+
+        if !has_catch_all {
+            let message = self.add_string(String::from("No match arm for "));
+            let const_idx = self.chunk().add_constant(Value::Object(message));
+            self.chunk().append_chunk(OpCode::Constant(const_idx), line);
+            self.chunk().append_chunk(OpCode::GetLocal(slot), line);
+            self.chunk().append_chunk(OpCode::Stringify, line);
+            self.chunk().append_chunk(OpCode::Add, line);
+            self.chunk().append_chunk(OpCode::Throw, line);
+        }
+        let function_scope = self.code_scopes.pop().unwrap();
+        self.emit_closure(function_scope, line);
 
-            self.name_variable(false, superclass_name, line)?;
-            self.chunk().append_chunk(OpCode::Inherit, line);
+        self.chunk().append_chunk(OpCode::ThisPlaceholder, line);
+        self.chunk().append_chunk(OpCode::Call(0), line);
+        Ok(())
+    }
+
+    //One pattern => result arm, testing the value in slot. Returns whether the
+    //pattern matches everything.
+    fn match_arm(&mut self, slot: usize) -> Result<bool, CompilerError> {
+        let line = self.peek().line;
+        self.begin_scope();
+        let literal = matches!(
+            self.peek().token_type,
+            TokenType::Minus
+                | TokenType::NumberToken
+                | TokenType::IntegerToken
+                | TokenType::StringToken
+                | TokenType::True
+                | TokenType::False
+                | TokenType::Nil
+        );
+
+        let mut test = None;
+        if literal {
+            self.chunk().append_chunk(OpCode::GetLocal(slot), line);
+            self.literal_pattern()?;
+            self.chunk().append_chunk(OpCode::Equal, line);
+            test = Some(self.chunk().append_chunk(OpCode::JumpIfFalse(0), line));
+            self.chunk().append_chunk(OpCode::Pop, line);
+        } else {
+            //A name binds the value, except for _, and either can be followed by a type
+            let name = self.try_consume(TokenType::Identifier, "Expected pattern.")?;
+            if self.match_token(TokenType::Colon) {
+                let type_name =
+                    self.try_consume(TokenType::Identifier, "Expected type name after ':'.")?;
+                let type_name = self.add_string(type_name.lexeme);
+                let const_idx = self.chunk().add_constant(Value::Object(type_name));
+                self.chunk().append_chunk(OpCode::GetLocal(slot), line);
+                self.chunk().append_chunk(OpCode::IsType(const_idx), line);
+                test = Some(self.chunk().append_chunk(OpCode::JumpIfFalse(0), line));
+                self.chunk().append_chunk(OpCode::Pop, line);
+            }
+            if name.lexeme != "_" {
+                self.chunk().append_chunk(OpCode::GetLocal(slot), line);
+                self.declare_variable(name);
+                self.mark_initialized();
+            }
         }
 
-        //Push the variable reference to the class onto the stack.
-        self.name_variable(false, name, line)?;
+        self.try_consume(TokenType::Arrow, "Expected '=>' after pattern.")?;
+        self.expression()?;
+        let line = self.previous().line;
+        self.chunk().append_chunk(OpCode::Return, line);
+        self.end_scope();
 
-        self.try_consume(TokenType::LeftBrace, "Expected '{' before class body")?;
-        while !self.check_token(TokenType::RightBrace) && !self.check_token(TokenType::EOF) {
-            self.method()?;
+        if let Some(test) = test {
+            self.patch_jump(test);
+            self.chunk().append_chunk(OpCode::Pop, line);
         }
-        self.try_consume(TokenType::RightBrace, "Expected '}' after class body")?;
+        Ok(test.is_none())
+    }
+
+    //A literal, negated if it's a number, to compare the matched value with
+    fn literal_pattern(&mut self) -> Result<(), CompilerError> {
+        let negated = self.match_token(TokenType::Minus);
+        let token_type = self.advance().token_type;
+        match token_type {
+            TokenType::NumberToken | TokenType::IntegerToken => self.number(false)?,
+            TokenType::StringToken if !negated => self.string(false)?,
+            TokenType::True | TokenType::False | TokenType::Nil if !negated => {
+                self.literal(false)?
+            }
+            _ => {
+                return Err(CompilerError::SyntaxError(
+                    String::from("Expected number after '-' in pattern."),
+                    self.previous().span(),
+                ))
+            }
+        }
+        if negated {
+            let line = self.previous().line;
+            self.chunk().append_chunk(OpCode::Negate, line);
+        }
+        Ok(())
+    }
+
+    fn emit_class(&mut self, name: String, reopen: bool, line: usize) {
+        //Locals don't get a name string from parse_variable
+        let class_name = self.add_string(name);
+        let offset = self.chunk().add_constant(Value::Object(class_name));
+        let op = if reopen {
+            OpCode::OpenClass(offset)
+        } else {
+            OpCode::Class(offset)
+        };
+        self.chunk().append_chunk(op, line);
+    }
+
+    //Everything after the name of a class whose value is in the variable token names
+    fn class_body(&mut self, token: Token) -> Result<(), CompilerError> {
+        let name = token.lexeme.clone();
+        let name_span = token.span();
+        self.class_scopes.push(ClassScope {
+            name: token,
+            has_superclass: false,
+            in_static: false,
+        });
+
+        let has_superclass = self.match_token(TokenType::Less);
+        if has_superclass {
+            //Inherit replaces the superclasses with one class holding their
+            //methods, the leftmost winning, which 'super' refers to
+            self.begin_scope();
+            let mut superclasses: Vec<String> = vec![];
+            loop {
+                let token = self.try_consume(TokenType::Identifier, "Expected superclass name")?;
+                let superclass_name = token.lexeme.clone();
+                if superclass_name == name {
+                    return Err(CompilerError::SyntaxError(
+                        String::from("A class can't inherit from itself"),
+                        token.span(),
+                    ));
+                } else if superclasses.contains(&superclass_name) {
+                    return Err(CompilerError::SyntaxError(
+                        format!("'{}' is already a superclass", superclass_name),
+                        token.span(),
+                    ));
+                }
+
+                self.name_variable(false, superclass_name.clone(), token.span())?;
+                superclasses.push(superclass_name);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+
+            let line = self.previous().line;
+            self.name_variable(false, name.clone(), name_span)?;
+            self.chunk()
+                .append_chunk(OpCode::Inherit(superclasses.len()), line);
+            let depth = self.code_scope().depth;
+            self.code_scope().locals.push(Local {
+                name: Token {
+                    token_type: TokenType::Super,
+                    lexeme: String::from("super"),
+                    line,
+                    column: 0,
+                    literal: Some(String::from("super")),
+                },
+                depth,
+                initialized: false,
+                captured: false,
+            });
+            self.mark_initialized();
+            self.class_scopes.last_mut().unwrap().has_superclass = true;
+        }
+
+        //Push the variable reference to the class onto the stack.
+        self.name_variable(false, name, name_span)?;
+        self.methods()?;
 
         //Pop the named reference to the variable off the stack
+        let line = self.previous().line;
         self.chunk().append_chunk(OpCode::Pop, line);
+        if has_superclass {
+            self.end_scope();
+        }
 
         self.class_scopes.pop();
 
         Ok(())
     }
 
+    //The braced list of methods, added to the class on top of the stack
+    fn methods(&mut self) -> Result<(), CompilerError> {
+        self.try_consume(TokenType::LeftBrace, "Expected '{' before class body")?;
+        while !self.check_token(TokenType::RightBrace) && !self.check_token(TokenType::EOF) {
+            self.method()?;
+        }
+        self.try_consume(TokenType::RightBrace, "Expected '}' after class body")?;
+        Ok(())
+    }
+
     fn declaration(&mut self) -> Result<(), CompilerError> {
         if self.match_token(TokenType::Class) {
             self.class_declaration()
@@ -898,6 +2160,8 @@ impl Compiler {
             self.var_declaration()
         } else if self.match_token(TokenType::Fun) {
             self.fun_declaration()
+        } else if self.match_token(TokenType::Import) {
+            self.import_declaration()
         } else {
             self.statement()
         }
@@ -906,11 +2170,12 @@ impl Compiler {
     fn patch_jump(&mut self, instruction_idx: usize) {
         let offset = self.chunk().top() - instruction_idx;
         self.chunk().patch_jump(instruction_idx, offset);
+        self.code_scope().last_jump_target = Some(self.chunk().next());
     }
 
     fn if_statement(&mut self) -> Result<(), CompilerError> {
         self.try_consume(TokenType::LeftParen, "Expected '(' after 'if'.")?;
-        self.expression()?;
+        self.condition()?;
         let line = self
             .try_consume(TokenType::RightParen, "Expected ')' after condition.")?
             .line;
@@ -925,6 +2190,8 @@ impl Compiler {
         let else_jump = self.chunk().append_chunk(OpCode::Jump(0), line);
 
         self.patch_jump(if_jump);
+        //The predicate is still there when the jump was taken
+        self.chunk().append_chunk(OpCode::Pop, line);
 
         if self.match_token(TokenType::Else) {
             self.statement()?;
@@ -935,7 +2202,60 @@ impl Compiler {
         Ok(())
     }
 
-    fn while_statement(&mut self) -> Result<(), CompilerError> {
+    //Assigning in a condition is almost always a typo for '=='. Compiling it as
+    //a comparison keeps the rest of the statement checked.
+    fn condition(&mut self) -> Result<(), CompilerError> {
+        self.parse_precedence(Precedence::Conditional)?;
+        if self.match_token(TokenType::Equal) {
+            let (span, line) = {
+                let equals = self.previous();
+                (equals.span(), equals.line)
+            };
+            self.report(CompilerError::SyntaxError(
+                String::from("Assignment in a condition. Did you mean '=='?"),
+                span,
+            ));
+            self.parse_precedence(Precedence::Conditional)?;
+            self.chunk().append_chunk(OpCode::Equal, line);
+        }
+        Ok(())
+    }
+
+    //outer: while (...) { ... break outer; }
+    fn labeled_statement(&mut self) -> Result<(), CompilerError> {
+        let label = self.advance().clone();
+        self.advance(); //The ':'
+
+        let taken = self
+            .code_scope()
+            .loops
+            .iter()
+            .any(|scope| scope.label.as_deref() == Some(label.lexeme.as_str()));
+        if taken {
+            self.report(CompilerError::SyntaxError(
+                format!("Label '{}' is already used by an enclosing loop.", label.lexeme),
+                label.span(),
+            ));
+        }
+
+        let label = Some(label.lexeme);
+        if self.match_token(TokenType::While) {
+            self.while_statement(label)
+        } else if self.match_token(TokenType::Do) {
+            self.do_while_statement(label)
+        } else if self.match_token(TokenType::For) {
+            self.for_statement(label)
+        } else {
+            //Compile it unlabeled so its own errors still show up
+            self.report(CompilerError::SyntaxError(
+                String::from("Expected a loop after label."),
+                self.peek().span(),
+            ));
+            self.statement()
+        }
+    }
+
+    fn while_statement(&mut self, label: Option<String>) -> Result<(), CompilerError> {
         let loop_start = self.chunk().next();
 
         self.try_consume(TokenType::LeftParen, "Expected '(' after 'if'.")?;
@@ -947,6 +2267,7 @@ impl Compiler {
         let exit_jump = self.chunk().append_chunk(OpCode::JumpIfFalse(0), line);
         self.chunk().append_chunk(OpCode::Pop, line);
 
+        self.begin_loop(Some(loop_start), label);
         self.statement()?;
 
         //Backwards offset instead of forward
@@ -955,12 +2276,46 @@ impl Compiler {
 
         self.patch_jump(exit_jump);
 
+        self.chunk().append_chunk(OpCode::Pop, line);
+        self.end_loop();
+
+        Ok(())
+    }
+
+    //The body runs once before the condition is first checked
+    fn do_while_statement(&mut self, label: Option<String>) -> Result<(), CompilerError> {
+        let loop_start = self.chunk().next();
+
+        self.begin_loop(None, label);
+        self.statement()?;
+
+        let scope = self.code_scope().loops.last_mut().unwrap();
+        for jump in core::mem::take(&mut scope.continues) {
+            self.patch_jump(jump);
+        }
+
+        self.try_consume(TokenType::While, "Expected 'while' after do-while body.")?;
+        self.try_consume(TokenType::LeftParen, "Expected '(' after 'while'.")?;
+        self.expression()?;
+        let line = self
+            .try_consume(TokenType::RightParen, "Expected ')' after condition.")?
+            .line;
+        self.consume_semicolon("Expected ';' after do-while condition")?;
+
+        let exit_jump = self.chunk().append_chunk(OpCode::JumpIfFalse(0), line);
         self.chunk().append_chunk(OpCode::Pop, line);
 
+        let offset = (self.chunk().top() + 2) - loop_start;
+        self.chunk().append_chunk(OpCode::Loop(offset), line);
+
+        self.patch_jump(exit_jump);
+        self.chunk().append_chunk(OpCode::Pop, line);
+        self.end_loop();
+
         Ok(())
     }
 
-    fn for_statement(&mut self) -> Result<(), CompilerError> {
+    fn for_statement(&mut self, label: Option<String>) -> Result<(), CompilerError> {
         self.begin_scope(); //To capture the variable initializer
 
         self.try_consume(TokenType::LeftParen, "Expected '(' after 'for'.")?;
@@ -1006,6 +2361,7 @@ impl Compiler {
             loop_start
         };
 
+        self.begin_loop(Some(loop_start), label);
         self.statement()?;
 
         let line = self.peek().line;
@@ -1016,13 +2372,241 @@ impl Compiler {
             self.patch_jump(exit_jump);
             self.chunk().append_chunk(OpCode::Pop, line);
         }
+        self.end_loop();
+
+        self.end_scope();
+        Ok(())
+    }
+
+    //Each case compares against the value and runs only its own statements,
+    //with no fall-through into the next one.
+    fn switch_statement(&mut self) -> Result<(), CompilerError> {
+        self.try_consume(TokenType::LeftParen, "Expected '(' after 'switch'.")?;
+        self.begin_scope();
+        self.expression()?;
+        self.try_consume(TokenType::RightParen, "Expected ')' after switch value.")?;
+        let line = self
+            .try_consume(TokenType::LeftBrace, "Expected '{' before switch body.")?
+            .line;
+
+        //Kept in an unnamed local so every case can read it
+        let depth = self.code_scope().depth;
+        self.code_scope().locals.push(Local {
+            name: Token {
+                token_type: TokenType::Identifier,
+                lexeme: String::new(),
+                line,
+                column: 0,
+                literal: Some(String::new()),
+            },
+            depth,
+            initialized: true,
+            captured: false,
+        });
+        let slot = self.code_scope().locals.len() - 1;
+
+        let mut end_jumps = vec![];
+        let mut has_default = false;
+        while self.match_token(TokenType::Case) || self.match_token(TokenType::Default) {
+            if has_default {
+                //Compiled anyway so their own errors still show up
+                self.report(CompilerError::SyntaxError(
+                    String::from("Cases can't come after the default."),
+                    self.previous().span(),
+                ));
+            }
+            if self.previous().token_type == TokenType::Default {
+                self.try_consume(TokenType::Colon, "Expected ':' after 'default'.")?;
+                self.case_body()?;
+                has_default = true;
+                continue;
+            }
+
+            let line = self.previous().line;
+            self.chunk().append_chunk(OpCode::GetLocal(slot), line);
+            self.expression()?;
+            self.try_consume(TokenType::Colon, "Expected ':' after case value.")?;
+            self.chunk().append_chunk(OpCode::Equal, line);
+
+            let next_case = self.chunk().append_chunk(OpCode::JumpIfFalse(0), line);
+            self.chunk().append_chunk(OpCode::Pop, line);
+            self.case_body()?;
+            end_jumps.push(self.chunk().append_chunk(OpCode::Jump(0), line));
+
+            self.patch_jump(next_case);
+            self.chunk().append_chunk(OpCode::Pop, line);
+        }
+        self.try_consume(TokenType::RightBrace, "Expected '}' after switch body.")?;
+
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+        Ok(())
+    }
+
+    fn try_statement(&mut self) -> Result<(), CompilerError> {
+        let line = self.previous().line;
+        let (has_catch, finally) = self.try_clauses();
+        //Errors in the catch block still run the finally, so its handler goes first
+        let finally_handler = finally.map(|block| {
+            let locals = self.code_scope().locals.len();
+            self.begin_try(Some(Finally { block, locals }), line)
+        });
+        let catch_handler = if has_catch {
+            Some(self.begin_try(None, line))
+        } else {
+            None
+        };
+
+        self.try_consume(TokenType::LeftBrace, "Expected '{' after 'try'.")?;
+        self.begin_scope();
+        self.block()?;
+        self.end_scope();
+
+        if let Some(handler) = catch_handler {
+            self.end_try(line);
+            let skip_catch = self.chunk().append_chunk(OpCode::Jump(0), line);
+            self.patch_jump(handler);
+            self.catch_clause()?;
+            self.patch_jump(skip_catch);
+        }
+        match finally_handler {
+            Some(handler) => {
+                self.end_try(line);
+                self.finally_clause(handler, line)?;
+            }
+            None if !has_catch => self.report(CompilerError::SyntaxError(
+                String::from("Expected 'catch' or 'finally' after try block."),
+                self.peek().span(),
+            )),
+            None => {}
+        }
+        Ok(())
+    }
+
+    //Which clauses follow the try block, and where the finally's block starts,
+    //since a finally needs its handler set up before the try block is compiled
+    fn try_clauses(&mut self) -> (bool, Option<usize>) {
+        let start = self.current;
+        if self.check_token(TokenType::LeftBrace) {
+            self.skip_balanced(TokenType::LeftBrace, TokenType::RightBrace);
+        }
+        let has_catch = self.match_token(TokenType::Catch);
+        if has_catch {
+            for (open, close) in [
+                (TokenType::LeftParen, TokenType::RightParen),
+                (TokenType::LeftBrace, TokenType::RightBrace),
+            ] {
+                if self.check_token(open) {
+                    self.skip_balanced(open, close);
+                }
+            }
+        }
+        let finally = if self.check_token(TokenType::Finally) {
+            Some(self.current + 1)
+        } else {
+            None
+        };
+        self.current = start;
+        (has_catch, finally)
+    }
+
+    fn begin_try(&mut self, finally: Option<Finally>, line: usize) -> usize {
+        self.code_scope().tries.push(finally);
+        self.chunk().append_chunk(OpCode::Try(0), line)
+    }
+
+    fn end_try(&mut self, line: usize) {
+        self.code_scope().tries.pop();
+        self.chunk().append_chunk(OpCode::EndTry, line);
+    }
+
+    //Runs with the error on the stack, where the variable's slot is
+    fn catch_clause(&mut self) -> Result<(), CompilerError> {
+        self.try_consume(TokenType::Catch, "Expected 'catch' after try block.")?;
+        self.try_consume(TokenType::LeftParen, "Expected '(' after 'catch'.")?;
+        self.begin_scope();
+        self.parse_variable("Expected a name for the error.")?;
+        self.mark_initialized();
+        self.try_consume(TokenType::RightParen, "Expected ')' after the error's name.")?;
+        self.try_consume(TokenType::LeftBrace, "Expected '{' before catch body.")?;
+        self.block()?;
+        self.end_scope();
+        Ok(())
+    }
+
+    fn finally_clause(&mut self, handler: usize, line: usize) -> Result<(), CompilerError> {
+        //Either way in leaves the error and whether to rethrow it on the stack
+        self.chunk().append_chunk(OpCode::Nil, line);
+        self.chunk().append_chunk(OpCode::False, line);
+        let skip_rethrow = self.chunk().append_chunk(OpCode::Jump(0), line);
+        self.patch_jump(handler);
+        self.chunk().append_chunk(OpCode::True, line);
+        self.patch_jump(skip_rethrow);
+
+        self.try_consume(TokenType::Finally, "Expected 'finally' after try block.")?;
+        self.try_consume(TokenType::LeftBrace, "Expected '{' after 'finally'.")?;
+        self.begin_scope();
+        let error = self.hidden_local(line);
+        let rethrow = self.hidden_local(line);
+        self.begin_scope();
+        self.block()?;
+        self.end_scope();
 
+        self.chunk().append_chunk(OpCode::GetLocal(rethrow), line);
+        let done = self.chunk().append_chunk(OpCode::JumpIfFalse(0), line);
+        self.chunk().append_chunk(OpCode::Pop, line);
+        self.chunk().append_chunk(OpCode::GetLocal(error), line);
+        self.chunk().append_chunk(OpCode::Throw, line);
+        self.patch_jump(done);
+        self.chunk().append_chunk(OpCode::Pop, line);
+        self.end_scope();
+        Ok(())
+    }
+
+    //A local for a value the compiler keeps on the stack, which code can't name
+    fn hidden_local(&mut self, line: usize) -> usize {
+        let depth = self.code_scope().depth;
+        self.code_scope().locals.push(Local {
+            name: Token {
+                token_type: TokenType::Identifier,
+                lexeme: String::new(),
+                line,
+                column: 0,
+                literal: Some(String::new()),
+            },
+            depth,
+            initialized: true,
+            captured: false,
+        });
+        self.code_scope().locals.len() - 1
+    }
+
+    fn throw_statement(&mut self) -> Result<(), CompilerError> {
+        let line = self.previous().line;
+        self.expression()?;
+        self.consume_semicolon("Expected ';' after thrown value")?;
+        self.chunk().append_chunk(OpCode::Throw, line);
+        Ok(())
+    }
+
+    fn case_body(&mut self) -> Result<(), CompilerError> {
+        self.begin_scope();
+        while !self.check_token(TokenType::Case)
+            && !self.check_token(TokenType::Default)
+            && !self.check_token(TokenType::RightBrace)
+            && !self.check_token(TokenType::EOF)
+        {
+            self.declaration()?;
+        }
         self.end_scope();
         Ok(())
     }
 
     fn argument_list(&mut self) -> Result<usize, CompilerError> {
-        self.chunk().append_chunk(OpCode::ThisPlaceholder, 0);
+        let line = self.previous().line;
+        self.chunk().append_chunk(OpCode::ThisPlaceholder, line);
         let mut arg_count = 0;
         if !self.check_token(TokenType::RightParen) {
             loop {
@@ -1043,17 +2627,26 @@ impl Compiler {
         let line = token.line;
         let ptr = self.heap.add_to_heap(Object::String(token.lexeme.clone()));
         let index = self.chunk().add_constant(Value::Object(ptr));
+        let symbol = self.heap.symbols.intern(&token.lexeme);
 
         if can_assign && self.match_token(TokenType::Equal) {
             self.expression()?;
-            self.chunk().append_chunk(OpCode::SetProperty(index), line);
+            self.chunk().append_chunk(OpCode::SetProperty(index, symbol), line);
+        } else if let Some(op) = self.compound_assignment(can_assign) {
+            //The instance is needed again to set the field
+            self.chunk().append_chunk(OpCode::Dup, line);
+            self.chunk().append_chunk(OpCode::GetProperty(index, symbol), line);
+            let operand_start = self.chunk().next();
+            self.expression()?;
+            self.emit_arithmetic(op, operand_start, line);
+            self.chunk().append_chunk(OpCode::SetProperty(index, symbol), line);
         } else if self.match_token(TokenType::LeftParen) {
             //Method invocation
             let arg_count = self.argument_list()?;
             self.chunk()
-                .append_chunk(OpCode::Invoke(index, arg_count), line);
+                .append_chunk(OpCode::Invoke(index, arg_count, symbol), line);
         } else {
-            self.chunk().append_chunk(OpCode::GetProperty(index), line);
+            self.chunk().append_chunk(OpCode::GetProperty(index, symbol), line);
         }
 
         Ok(())
@@ -1067,6 +2660,38 @@ impl Compiler {
         Ok(())
     }
 
+    fn list(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let line = self.previous().line;
+        let mut count = 0;
+        if !self.check_token(TokenType::RightBracket) {
+            loop {
+                self.expression()?;
+                count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.try_consume(TokenType::RightBracket, "Expected ']' after list elements.")?;
+        self.chunk().append_chunk(OpCode::BuildList(count), line);
+        Ok(())
+    }
+
+    fn index(&mut self, can_assign: bool) -> Result<(), CompilerError> {
+        let line = self.previous().line;
+        self.expression()?;
+        self.try_consume(TokenType::RightBracket, "Expected ']' after index.")?;
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression()?;
+            self.chunk().append_chunk(OpCode::IndexSet, line);
+        } else {
+            self.chunk().append_chunk(OpCode::Index, line);
+        }
+        Ok(())
+    }
+
     fn and(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
         let line = self.peek().line;
         let end_jump = self.chunk().append_chunk(OpCode::JumpIfFalse(0), line);
@@ -1093,6 +2718,29 @@ impl Compiler {
         Ok(())
     }
 
+    //a ? b : c, with the condition already on the stack. The else branch
+    //binds to the right, so a ? b : c ? d : e nests in the else.
+    fn conditional(&mut self, _can_assign: bool) -> Result<(), CompilerError> {
+        let line = self.previous().line;
+        let else_jump = self.chunk().append_chunk(OpCode::JumpIfFalse(0), line);
+        self.chunk().append_chunk(OpCode::Pop, line);
+
+        self.expression()?;
+        self.try_consume(
+            TokenType::Colon,
+            "Expected ':' after then branch of conditional expression.",
+        )?;
+        let end_jump = self.chunk().append_chunk(OpCode::Jump(0), line);
+
+        self.patch_jump(else_jump);
+        self.chunk().append_chunk(OpCode::Pop, line);
+
+        self.parse_precedence(Precedence::Conditional)?;
+
+        self.patch_jump(end_jump);
+        Ok(())
+    }
+
     fn synchronize(&mut self) {
         while !self.is_at_end() {
             if self.previous().token_type == TokenType::Semicolon {
@@ -1105,7 +2753,15 @@ impl Compiler {
                     | TokenType::For
                     | TokenType::If
                     | TokenType::While
+                    | TokenType::Do
+                    | TokenType::Switch
+                    | TokenType::Break
+                    | TokenType::Continue
+                    | TokenType::Try
+                    | TokenType::Throw
+                    | TokenType::Import
                     | TokenType::Print
+                    | TokenType::Yield
                     | TokenType::Return => return,
                     _ => {
                         self.advance();
@@ -1115,29 +2771,163 @@ impl Compiler {
         }
     }
 
-    pub fn compile(&mut self) -> Result<Function, ()> {
+    pub fn compile(&mut self) -> Result<Function, Vec<CompilerError>> {
+        let _span = debug_span!("compile", tokens = self.tokens.len()).entered();
+
+        let mut errors = vec![];
         let mut old_idx = self.current;
         while !self.is_at_end() {
             let result = self.declaration();
+            errors.append(&mut self.recovered);
             if let Err(e) = result {
                 self.has_error = true;
-                println!("Compiler error: {}", e.to_string());
+                debug!(error = %e.to_string(), "syntax error");
+                errors.push(e);
+                //The error unwound out of any class, function or loop it was in.
+                //Dropping a half compiled nested function loses nothing, since
+                //compile() fails and no code from here on runs. Scanning on at
+                //the top level just keeps its locals and upvalues from being
+                //looked up by what comes after.
+                self.class_scopes.clear();
+                self.code_scopes.truncate(1);
+                self.code_scope().loops.clear();
+                self.code_scope().tries.clear();
                 self.synchronize();
             };
 
             if self.current == old_idx {
-                println!("Error: Infinite loop");
-                return Err(());
+                let span = self.peek().span();
+                errors.push(CompilerError::SyntaxError(
+                    String::from("Parser made no progress."),
+                    span,
+                ));
+                return Err(errors);
             }
 
             old_idx = self.current;
         }
         if self.has_error {
-            Err(())
+            Err(errors)
         } else {
             assert!(self.code_scopes.len() == 1);
-            let scope = self.code_scopes.pop().unwrap();
+            let mut scope = self.code_scopes.pop().unwrap();
+            scope.function.chunk.finish();
+            self.passes.run(&mut scope.function, &mut self.heap);
+            debug!(instructions = scope.function.chunk.code.len(), "compiled");
             Ok(scope.function)
         }
     }
 }
+
+#[cfg(test)]
+mod compiler_tests {
+    use super::*;
+    use crate::scanner::scan_tokens;
+
+    fn errors(source: &str) -> Vec<String> {
+        let tokens = scan_tokens(&String::from(source)).unwrap();
+        let mut compiler = Compiler::new(tokens, VirtualMemory::new());
+        match compiler.compile() {
+            Ok(_) => vec![],
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn suggests_equality_in_if_conditions() {
+        let found = errors("var a = 1;\nif (a = 2) print a;\nprint b c;");
+        assert_eq!(
+            found,
+            vec![
+                "2 : Assignment in a condition. Did you mean '=='?",
+                "3 : Expected ';' after expression",
+            ]
+        );
+        assert!(errors("var a = 1;\nif ((a = 2)) print a;").is_empty());
+    }
+
+    #[test]
+    fn closes_blocks_missing_a_semicolon() {
+        let found = errors("fun f() {\n  return 1\n}\n{ print f() }\nvar x = 1 +;");
+        assert_eq!(
+            found,
+            vec![
+                "3 : Expected ';' before '}'.",
+                "4 : Expected ';' before '}'.",
+                "5 : Expected expression.",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_function_values() {
+        let found = errors("var f = fun(a) { return a; };\nvar g = 1 +;");
+        assert_eq!(
+            found,
+            vec![
+                "1 : 'fun' declares a named function and can't be used as a value.",
+                "2 : Expected expression.",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_else_without_if() {
+        let found = errors("var a = 1;\nif (a) print 1; print 2;\nelse print 3 +;");
+        assert_eq!(
+            found,
+            vec!["3 : 'else' without a matching 'if'.", "3 : Expected expression."]
+        );
+    }
+
+    #[test]
+    fn rejects_super_without_a_superclass() {
+        let found = errors("class A {\n  m() { return super.m(); }\n}\nprint super.x;");
+        assert_eq!(found[0], "2 : Can't use 'super' in a class with no superclass");
+        assert_eq!(
+            found.last().unwrap(),
+            "4 : Can't use 'super' outside of a class"
+        );
+    }
+
+    #[test]
+    fn rejects_break_and_continue_outside_loops() {
+        let found = errors("while (true) {\n  fun f() { continue; }\n}\nbreak;");
+        assert_eq!(found[0], "2 : Can't use 'continue' outside of a loop.");
+        assert_eq!(found.last().unwrap(), "4 : Can't use 'break' outside of a loop.");
+        assert!(errors("for (;;) { { var a = 1; break; } continue; }").is_empty());
+    }
+
+    #[test]
+    fn resolves_loop_labels() {
+        assert!(errors("a: while (true) { b: for (;;) { continue a; break b; } }").is_empty());
+        let found = errors("a: while (true) {\n  a: while (true) break c;\n}");
+        assert_eq!(found[0], "2 : Label 'a' is already used by an enclosing loop.");
+        assert_eq!(found[1], "2 : No enclosing loop is labeled 'c'.");
+    }
+
+    #[test]
+    fn suspends_generators_before_their_body() {
+        let source = String::from("fun g(a) {\n  if (a) return 1;\n  yield 2;\n}");
+        let mut compiler = Compiler::new(scan_tokens(&source).unwrap(), VirtualMemory::new());
+        let main = compiler.compile().ok().unwrap();
+        let generator = main
+            .chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Value::Object(ptr) => match compiler.heap.deref(*ptr) {
+                    Object::Function(function) => Some(function),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .unwrap();
+        let code = &generator.chunk.code;
+        assert!(matches!(code[0], OpCode::Generator));
+        assert_eq!(generator.chunk.line_numbers[0], 1);
+        assert!(code.iter().any(|op| matches!(op, OpCode::Yield)));
+        assert!(!code.iter().any(|op| matches!(op, OpCode::Return)));
+        assert_eq!(errors("fun f() { yield; }\nclass A { x { yield 1; } }").len(), 1);
+    }
+}
@@ -0,0 +1,298 @@
+use super::interpreter::{DEFAULT_GC_GROWTH, DEFAULT_GC_THRESHOLD, DEFAULT_STACK_SIZE};
+use super::lint::LintConfig;
+#[cfg(feature = "project")]
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE: &str = "lox.toml";
+//What runs when a project directory is given instead of a script
+pub const ENTRY_POINT: &str = "main.lox";
+
+// Per-project settings, read from lox.toml:
+//
+//   [vm]
+//   gc-threshold = 1048576     # estimated bytes allocated before the first collection
+//   gc-growth = 2.0            # later ones wait for the surviving bytes times this
+//   gc-step = 100              # objects marked per instruction while collecting,
+//                              # 0 marks them all at once
+//   max-call-depth = 512
+//   stack-size = 65536         # values on the stack before a call overflows it
+//   capabilities = ["clock"]   # native groups scripts may use, all if unset
+//                              # except "ffi", which has to be listed
+//   hot-threshold = 1000       # calls or loop iterations before a function is
+//                              # re-optimized, 0 never
+//
+//   [compiler]
+//   opt-level = 1
+//
+//   [modules]
+//   paths = ["lib"]            # relative to lox.toml
+//
+//   [lint]                     # see lint::LintConfig
+//
+// Command line flags are applied on top of whatever the file says.
+#[derive(Debug, Clone)]
+pub struct VmOptions {
+    pub gc_threshold: usize,
+    pub gc_growth: f64,
+    pub gc_step: usize,
+    pub max_call_depth: usize,
+    pub stack_size: usize,
+    pub capabilities: Option<Vec<String>>,
+    pub opt_level: u8,
+    pub hot_threshold: u64,
+    pub module_paths: Vec<PathBuf>,
+    //Imports resolve here first: the project directory, or the script's own
+    pub module_root: Option<PathBuf>,
+    pub lint: LintConfig,
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        VmOptions {
+            gc_threshold: DEFAULT_GC_THRESHOLD,
+            gc_growth: DEFAULT_GC_GROWTH,
+            gc_step: 0,
+            max_call_depth: 256,
+            stack_size: DEFAULT_STACK_SIZE,
+            capabilities: None,
+            opt_level: 0,
+            hot_threshold: 1000,
+            module_paths: vec![],
+            module_root: None,
+            lint: LintConfig::default(),
+        }
+    }
+}
+
+#[cfg(feature = "project")]
+fn table<'a>(root: &'a toml::Table, name: &str) -> Result<Option<&'a toml::Table>, String> {
+    match root.get(name) {
+        Some(toml::Value::Table(table)) => Ok(Some(table)),
+        Some(_) => Err(format!("[{}] must be a table", name)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(feature = "project")]
+fn positive_integer(table: &toml::Table, key: &str) -> Result<Option<u64>, String> {
+    match table.get(key) {
+        Some(value) => value
+            .as_integer()
+            .filter(|n| *n > 0)
+            .map(|n| Some(n as u64))
+            .ok_or_else(|| format!("{} must be a positive integer", key)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(feature = "project")]
+fn strings(table: &toml::Table, key: &str) -> Result<Option<Vec<String>>, String> {
+    match table.get(key) {
+        Some(toml::Value::Array(values)) => values
+            .iter()
+            .map(|v| {
+                v.as_str()
+                    .map(String::from)
+                    .ok_or_else(|| format!("{} must be a list of strings", key))
+            })
+            .collect::<Result<Vec<String>, String>>()
+            .map(Some),
+        Some(_) => Err(format!("{} must be a list of strings", key)),
+        None => Ok(None),
+    }
+}
+
+impl VmOptions {
+    #[cfg(feature = "project")]
+    pub fn from_toml(source: &str) -> Result<VmOptions, String> {
+        let root: toml::Table = source.parse().map_err(|e| format!("{}", e))?;
+        let mut options = VmOptions::default();
+
+        if let Some(vm) = table(&root, "vm")? {
+            if let Some(threshold) = positive_integer(vm, "gc-threshold")? {
+                options.gc_threshold = threshold as usize;
+            }
+            if let Some(growth) = vm.get("gc-growth") {
+                options.gc_growth = growth
+                    .as_float()
+                    .or_else(|| growth.as_integer().map(|n| n as f64))
+                    .filter(|n| *n >= 1.0)
+                    .ok_or_else(|| String::from("gc-growth must be a number of at least 1"))?;
+            }
+            if let Some(step) = vm.get("gc-step") {
+                options.gc_step = step
+                    .as_integer()
+                    .filter(|n| *n >= 0)
+                    .ok_or_else(|| String::from("gc-step must be a non-negative integer"))?
+                    as usize;
+            }
+            if let Some(depth) = positive_integer(vm, "max-call-depth")? {
+                options.max_call_depth = depth as usize;
+            }
+            if let Some(size) = positive_integer(vm, "stack-size")? {
+                options.stack_size = size as usize;
+            }
+            options.capabilities = strings(vm, "capabilities")?;
+            if let Some(threshold) = vm.get("hot-threshold") {
+                options.hot_threshold = threshold
+                    .as_integer()
+                    .filter(|n| *n >= 0)
+                    .ok_or_else(|| String::from("hot-threshold must be a non-negative integer"))?
+                    as u64;
+            }
+        }
+
+        if let Some(compiler) = table(&root, "compiler")? {
+            if let Some(level) = compiler.get("opt-level") {
+                options.opt_level = level
+                    .as_integer()
+                    .filter(|n| (0..=2).contains(n))
+                    .ok_or_else(|| String::from("opt-level must be 0, 1 or 2"))?
+                    as u8;
+            }
+        }
+
+        if let Some(modules) = table(&root, "modules")? {
+            if let Some(paths) = strings(modules, "paths")? {
+                options.module_paths = paths.into_iter().map(PathBuf::from).collect();
+            }
+        }
+
+        if let Some(lint) = table(&root, "lint")? {
+            options.lint = LintConfig::from_table(lint)?;
+        }
+
+        Ok(options)
+    }
+
+    #[cfg(feature = "project")]
+    pub fn load(path: &Path) -> Result<VmOptions, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut options =
+            VmOptions::from_toml(&source).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        options.module_paths = options
+            .module_paths
+            .iter()
+            .map(|module_path| dir.join(module_path))
+            .collect();
+        Ok(options)
+    }
+
+    // Where an import is looked up, in order.
+    pub fn module_search_path(&self) -> Vec<PathBuf> {
+        self.module_root
+            .iter()
+            .chain(self.module_paths.iter())
+            .cloned()
+            .collect()
+    }
+
+    // The nearest lox.toml in dir or one of its parents, if there is one.
+    #[cfg(feature = "project")]
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        let dir = dir.canonicalize().ok()?;
+        dir.ancestors()
+            .map(|dir| dir.join(CONFIG_FILE))
+            .find(|candidate| candidate.is_file())
+    }
+
+    // Options for running script, or the working directory when there isn't one.
+    #[cfg(feature = "project")]
+    pub fn discover(script: Option<&Path>) -> Result<VmOptions, String> {
+        let dir = match script.and_then(Path::parent) {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        match VmOptions::find(&dir) {
+            Some(path) => VmOptions::load(&path),
+            None => Ok(VmOptions::default()),
+        }
+    }
+}
+
+// The script to run for path along with its module root. A directory is a
+// project: its main.lox runs and imports resolve against the directory.
+pub fn entry_point(path: &Path) -> Result<(PathBuf, PathBuf), String> {
+    if path.is_dir() {
+        let main = path.join(ENTRY_POINT);
+        if !main.is_file() {
+            return Err(format!(
+                "{}: no {} in project directory",
+                path.display(),
+                ENTRY_POINT
+            ));
+        }
+        return Ok((main, path.to_path_buf()));
+    }
+
+    let root = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    Ok((path.to_path_buf(), root))
+}
+
+#[cfg(all(test, feature = "project"))]
+mod config_tests {
+    use super::*;
+    use crate::lint::{Level, Rule};
+
+    #[test]
+    fn reads_every_section() {
+        let options = VmOptions::from_toml(
+            "[vm]\ngc-threshold = 1000\ngc-growth = 1.5\nmax-call-depth = 64\nstack-size = 4096\ncapabilities = [\"clock\"]\n\n[compiler]\nopt-level = 2\n\n[modules]\npaths = [\"lib\"]\n\n[lint]\nshadowing = \"deny\"\n",
+        )
+        .unwrap();
+        assert_eq!(options.gc_threshold, 1000);
+        assert_eq!(options.gc_growth, 1.5);
+        assert_eq!(options.max_call_depth, 64);
+        assert_eq!(options.stack_size, 4096);
+        assert_eq!(options.capabilities, Some(vec![String::from("clock")]));
+        assert_eq!(options.opt_level, 2);
+        assert_eq!(options.module_paths, vec![PathBuf::from("lib")]);
+        assert_eq!(options.lint.level(Rule::Shadowing), Level::Deny);
+    }
+
+    #[test]
+    fn defaults_and_errors() {
+        let options = VmOptions::from_toml("").unwrap();
+        assert_eq!(options.gc_threshold, DEFAULT_GC_THRESHOLD);
+        assert_eq!(options.gc_growth, DEFAULT_GC_GROWTH);
+        assert_eq!(options.capabilities, None);
+
+        assert!(VmOptions::from_toml("[vm]\ngc-threshold = -1").is_err());
+        assert!(VmOptions::from_toml("[vm]\ngc-growth = 0.5").is_err());
+        assert!(VmOptions::from_toml("[vm]\nhot-threshold = -1").is_err());
+        assert_eq!(VmOptions::from_toml("[vm]\nhot-threshold = 0").unwrap().hot_threshold, 0);
+        assert_eq!(VmOptions::from_toml("[vm]\ngc-step = 50").unwrap().gc_step, 50);
+        assert!(VmOptions::from_toml("[vm]\ngc-step = -1").is_err());
+        assert!(VmOptions::from_toml("[compiler]\nopt-level = 7").is_err());
+        assert!(VmOptions::from_toml("vm = 3").is_err());
+    }
+
+    #[test]
+    fn project_directory() {
+        let project = std::env::temp_dir().join(format!("lox-project-{}", std::process::id()));
+        fs::create_dir_all(&project).unwrap();
+        assert!(entry_point(&project).is_err());
+
+        fs::write(project.join(ENTRY_POINT), "print 1;").unwrap();
+        fs::write(project.join(CONFIG_FILE), "[modules]\npaths = [\"lib\"]").unwrap();
+        let (script, root) = entry_point(&project).unwrap();
+        assert_eq!(script, project.join(ENTRY_POINT));
+        assert_eq!(root, project);
+        assert_eq!(entry_point(&script).unwrap().1, project);
+
+        let mut options = VmOptions::discover(Some(&script)).unwrap();
+        options.module_root = Some(root);
+        let search_path = options.module_search_path();
+        assert_eq!(search_path.len(), 2);
+        assert_eq!(search_path[0], project);
+        assert!(search_path[1].ends_with("lib"));
+
+        fs::remove_dir_all(&project).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+use core::fmt;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+// What the VM was doing when it panicked, from VM::crash_report. Everything is
+// already rendered to text since the heap may be in a bad state afterwards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashReport {
+    pub message: String,
+    //The instruction that was running, when the VM records recent instructions
+    pub instruction: Option<String>,
+    //Innermost first
+    pub frames: Vec<String>,
+    pub stack_depth: usize,
+    //Oldest first, ending with the instruction that was running
+    pub recent: Vec<String>,
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        writeln!(f)?;
+        match &self.instruction {
+            Some(instruction) => writeln!(f, "Instruction: {}", instruction)?,
+            None => writeln!(f, "Instruction: unknown")?,
+        }
+        writeln!(f, "Stack depth: {}", self.stack_depth)?;
+        writeln!(f)?;
+        writeln!(f, "Call frames:")?;
+        for frame in self.frames.iter() {
+            writeln!(f, "  {}", frame)?;
+        }
+        if !self.recent.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Recent instructions:")?;
+            for instruction in self.recent.iter() {
+                writeln!(f, "  {}", instruction)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod crash_tests {
+    use crate::compiler::Compiler;
+    use crate::interpreter::VM;
+    use crate::scanner::scan_tokens;
+    use std::io;
+
+    #[test]
+    fn reports_the_last_instructions() {
+        let source = String::from("for (var i = 0; i < 100; i = i + 1) {}\nprint nil;");
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        vm.record_recent_instructions();
+        let mut compiler = Compiler::new(scan_tokens(&source).unwrap(), vm.take_virtual_memory());
+        let main = compiler.compile().map_err(|_| "compile error").unwrap();
+        vm.interpret(main, compiler.heap).map_err(|e| e.to_string()).unwrap();
+
+        let report = vm.crash_report("boom");
+        //The last fetch ran off the end of the chunk, so there's nothing to show for it
+        assert_eq!(report.recent.len(), 31);
+        assert!(report.instruction.as_ref().unwrap().ends_with("Print"));
+        assert_eq!(report.frames.len(), 1);
+        assert!(report.frames[0].starts_with("main"));
+        assert!(report.to_string().starts_with("boom\n"));
+    }
+}
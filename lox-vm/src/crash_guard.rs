@@ -0,0 +1,49 @@
+use lox_vm::interpreter::VM;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::{env, fs, process};
+
+// Turns panics inside the VM into a crash report, so bug reports can say what
+// the VM was running and not just where in Rust it gave up.
+
+thread_local! {
+    static IN_VM: Cell<bool> = const { Cell::new(false) };
+    static PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Panics outside of guard still print the usual message.
+pub fn install_hook() {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if IN_VM.with(Cell::get) {
+            PANIC.with(|panic| *panic.borrow_mut() = Some(info.to_string()));
+        } else {
+            default(info);
+        }
+    }));
+}
+
+// Runs f, and if the VM panics writes a report to the temp directory and
+// exits with 70.
+pub fn guard<T>(vm: &mut VM, f: impl FnOnce(&mut VM) -> T) -> T {
+    IN_VM.with(|in_vm| in_vm.set(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(vm)));
+    IN_VM.with(|in_vm| in_vm.set(false));
+
+    let message = match result {
+        Ok(value) => return value,
+        Err(_) => PANIC
+            .with(|panic| panic.borrow_mut().take())
+            .unwrap_or_else(|| String::from("panicked")),
+    };
+    let report = vm.crash_report(&message);
+    let path = env::temp_dir().join(format!("lox-vm-crash-{}.txt", process::id()));
+    match fs::write(&path, report.to_string()) {
+        Ok(()) => eprintln!(
+            "lox-vm crashed, which is a bug. Please include {} when reporting it.",
+            path.display()
+        ),
+        Err(_) => eprintln!("lox-vm crashed, which is a bug.\n\n{}", report),
+    }
+    process::exit(70);
+}
@@ -0,0 +1,327 @@
+// A Debug Adapter Protocol server on stdin/stdout, so editors can debug Lox
+// scripts without a plugin of their own. There is only ever one thread and
+// requests are handled in order, so while the script runs we don't read.
+
+use lox_vm::compiler::Compiler;
+use lox_vm::config::VmOptions;
+use lox_vm::debugger::{Debugger, PauseReason, Resume, Status};
+use lox_vm::interpreter::{InterpreterError, VirtualMemory, VM};
+use lox_vm::scanner;
+use lox_vm::value::Function;
+use serde_json::{json, Value as Json};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+const THREAD_ID: i64 = 1;
+//Frames use their index in the backtrace offset by FRAME_REFERENCE
+const GLOBALS_REFERENCE: i64 = 1;
+const FRAME_REFERENCE: i64 = 2;
+
+//Collects what the script prints so it can be sent as output events.
+#[derive(Clone)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Session {
+    seq: i64,
+    program: String,
+    breakpoints: HashSet<usize>,
+    stop_on_entry: bool,
+    //Compiled by launch, run once configuration is done
+    pending: Option<(Function, VirtualMemory)>,
+    vm: VM,
+    output: Output,
+    paused: bool,
+    done: bool,
+}
+
+pub fn run_server() -> i32 {
+    let output = Output(Rc::new(RefCell::new(vec![])));
+    let mut vm = VM::new();
+    vm.set_output(Box::new(output.clone()));
+
+    let mut session = Session {
+        seq: 1,
+        program: String::new(),
+        breakpoints: HashSet::new(),
+        stop_on_entry: false,
+        pending: None,
+        vm,
+        output,
+        paused: false,
+        done: false,
+    };
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    while !session.done {
+        match read_message(&mut input) {
+            Some(message) => session.handle(&message),
+            None => break,
+        }
+    }
+    0
+}
+
+fn read_message(input: &mut impl BufRead) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+impl Session {
+    fn send(&mut self, mut message: Json) {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let _ = write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = out.flush();
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn respond_error(&mut self, request: &Json, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.send(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }));
+    }
+
+    fn handle(&mut self, request: &Json) {
+        let arguments = &request["arguments"];
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => {
+                self.respond(request, json!({ "supportsConfigurationDoneRequest": true }));
+                self.event("initialized", json!({}));
+            }
+            "launch" => match self.launch(arguments) {
+                Ok(()) => self.respond(request, json!({})),
+                Err(message) => self.respond_error(request, &message),
+            },
+            "setBreakpoints" => {
+                let lines: Vec<usize> = arguments["breakpoints"]
+                    .as_array()
+                    .map(|breakpoints| {
+                        breakpoints
+                            .iter()
+                            .filter_map(|b| b["line"].as_u64())
+                            .map(|line| line as usize)
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                self.breakpoints = lines.iter().copied().collect();
+                if let Some(debugger) = self.vm.debugger_mut() {
+                    debugger.breakpoints = self.breakpoints.clone();
+                }
+
+                let breakpoints: Vec<Json> = lines
+                    .iter()
+                    .map(|line| json!({ "verified": true, "line": line }))
+                    .collect();
+                self.respond(request, json!({ "breakpoints": breakpoints }));
+            }
+            "configurationDone" => {
+                self.respond(request, json!({}));
+                if let Some((main, heap)) = self.pending.take() {
+                    let mut debugger = Debugger::new(self.stop_on_entry);
+                    debugger.breakpoints = self.breakpoints.clone();
+                    self.vm.attach_debugger(debugger);
+                    let result = self.vm.start(main, heap);
+                    self.report(result);
+                }
+            }
+            "threads" => self.respond(
+                request,
+                json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            ),
+            "stackTrace" => {
+                let frames: Vec<Json> = self
+                    .vm
+                    .backtrace()
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, frame)| {
+                        json!({
+                            "id": idx,
+                            "name": frame.name,
+                            "line": frame.line,
+                            "column": 1,
+                            "source": { "path": self.program },
+                        })
+                    })
+                    .collect();
+                let total = frames.len();
+                self.respond(
+                    request,
+                    json!({ "stackFrames": frames, "totalFrames": total }),
+                );
+            }
+            "scopes" => {
+                let frame = arguments["frameId"].as_i64().unwrap_or(0);
+                self.respond(
+                    request,
+                    json!({ "scopes": [
+                        { "name": "Locals", "variablesReference": frame + FRAME_REFERENCE, "expensive": false },
+                        { "name": "Globals", "variablesReference": GLOBALS_REFERENCE, "expensive": false },
+                    ]}),
+                );
+            }
+            "variables" => {
+                let reference = arguments["variablesReference"].as_i64().unwrap_or(0);
+                let variables = self.variables(reference);
+                self.respond(request, json!({ "variables": variables }));
+            }
+            "continue" => self.resume(request, Resume::Continue),
+            "next" => self.resume(request, Resume::StepOver),
+            "stepIn" => self.resume(request, Resume::StepIn),
+            "stepOut" => self.resume(request, Resume::StepOut),
+            "disconnect" | "terminate" => {
+                self.respond(request, json!({}));
+                self.done = true;
+            }
+            command => self.respond_error(request, &format!("Unsupported request '{}'", command)),
+        }
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<(), String> {
+        let program = arguments["program"]
+            .as_str()
+            .ok_or_else(|| String::from("Missing 'program' to launch"))?;
+        let source = fs::read_to_string(program).map_err(|e| format!("{}: {}", program, e))?;
+        self.program = String::from(program);
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.vm.configure(&VmOptions::discover(Some(Path::new(program)))?);
+
+        let tokens = scanner::scan_tokens(&source).map_err(|e| format!("{}", e))?;
+        let mut compiler = Compiler::new(tokens, self.vm.take_virtual_memory());
+        match compiler.compile() {
+            Ok(main) => {
+                self.pending = Some((main, compiler.heap));
+                Ok(())
+            }
+            Err(errors) => {
+                let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                Err(messages.join("\n"))
+            }
+        }
+    }
+
+    fn resume(&mut self, request: &Json, resume: Resume) {
+        if !self.paused {
+            return self.respond_error(request, "The program is not paused");
+        }
+        self.respond(request, json!({ "allThreadsContinued": true }));
+        let result = self.vm.resume(resume);
+        self.report(result);
+    }
+
+    fn variables(&self, reference: i64) -> Vec<Json> {
+        let variables: Vec<(String, String)> = if reference == GLOBALS_REFERENCE {
+            self.vm
+                .globals()
+                .iter()
+                .map(|(name, value)| (name.to_string(), self.vm.inspect(*value)))
+                .collect()
+        } else {
+            let frame = (reference - FRAME_REFERENCE) as usize;
+            match self.vm.backtrace().get(frame) {
+                Some(frame) => frame
+                    .locals
+                    .iter()
+                    .map(|(name, value)| (name.clone(), self.vm.inspect(*value)))
+                    .collect(),
+                None => vec![],
+            }
+        };
+
+        variables
+            .into_iter()
+            .map(|(name, value)| json!({ "name": name, "value": value, "variablesReference": 0 }))
+            .collect()
+    }
+
+    fn flush_output(&mut self) {
+        let text = String::from_utf8_lossy(&self.output.0.borrow()).to_string();
+        self.output.0.borrow_mut().clear();
+        if !text.is_empty() {
+            self.event("output", json!({ "category": "stdout", "output": text }));
+        }
+    }
+
+    fn report(&mut self, result: Result<Status, InterpreterError>) {
+        self.flush_output();
+        self.paused = matches!(result, Ok(Status::Paused(_, _)));
+        match result {
+            Ok(Status::Paused(reason, _)) => {
+                let reason = match reason {
+                    PauseReason::Breakpoint => "breakpoint",
+                    PauseReason::Step => "step",
+                };
+                self.event(
+                    "stopped",
+                    json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+                );
+            }
+            Ok(Status::Finished) => {
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+            }
+            Err(e) => {
+                let message = format!("Runtime Error: {}\n", e);
+                self.event("output", json!({ "category": "stderr", "output": message }));
+                self.event("exited", json!({ "exitCode": 70 }));
+                self.event("terminated", json!({}));
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use super::chunk::{Chunk, Comparison, OpCode};
+use super::interpreter::VirtualMemory;
+use super::value::{Function, Object, Value};
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+// Short rendering of a value for disassembly and trace output.
+pub fn format_value(heap: &VirtualMemory, value: Value) -> String {
+    match value {
+        Value::Number(n) => format!("{}", n),
+        Value::Integer(n) => format!("{}", n),
+        Value::Boolean(b) => format!("{}", b),
+        Value::Nil => String::from("nil"),
+        Value::Object(ptr) => match heap.deref(ptr) {
+            Object::String(s) => format!("{:?}", s),
+            object => format!("{}", object),
+        },
+    }
+}
+
+fn constant_operand(chunk: &Chunk, heap: &VirtualMemory, idx: usize) -> String {
+    format!("{:>4} '{}'", idx, format_value(heap, chunk.constants[idx]))
+}
+
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize, heap: &VirtualMemory) -> String {
+    let line = chunk.line_numbers[offset];
+    let line = if offset > 0 && chunk.line_numbers[offset - 1] == line {
+        String::from("   |")
+    } else {
+        format!("{:>4}", line)
+    };
+
+    let op = chunk.code[offset];
+    let operands = match op {
+        OpCode::Constant(idx)
+        | OpCode::DefineGlobal(idx, _)
+        | OpCode::GetGlobal(idx, _)
+        | OpCode::SetGlobal(idx, _)
+        | OpCode::Class(idx)
+        | OpCode::OpenClass(idx)
+        | OpCode::SetProperty(idx, _)
+        | OpCode::GetProperty(idx, _)
+        | OpCode::Method(idx, _)
+        | OpCode::StaticMethod(idx, _)
+        | OpCode::Getter(idx, _)
+        | OpCode::Setter(idx, _)
+        | OpCode::GetSuper(idx, _)
+        | OpCode::Import(idx)
+        | OpCode::IsType(idx)
+        | OpCode::AddConstant(idx) => constant_operand(chunk, heap, idx),
+        OpCode::CompareLocalConstant(slot, idx, comparison) => format!(
+            "{:>4} {} {}",
+            slot,
+            if comparison == Comparison::Less { "<" } else { ">" },
+            constant_operand(chunk, heap, idx).trim_start()
+        ),
+        OpCode::Invoke(idx, arg_count, _)
+        | OpCode::SuperInvoke(idx, arg_count, _)
+        | OpCode::CallConstant(idx, arg_count) => format!(
+            "{} ({} args)",
+            constant_operand(chunk, heap, idx),
+            arg_count
+        ),
+        OpCode::UnpackFields(idx, count) => format!(
+            "{} ({} fields)",
+            constant_operand(chunk, heap, idx),
+            count
+        ),
+        OpCode::Closure(idx, upvalue_count) => format!(
+            "{} ({} upvalues)",
+            constant_operand(chunk, heap, idx),
+            upvalue_count
+        ),
+        OpCode::SetLocal(slot)
+        | OpCode::GetLocal(slot)
+        | OpCode::GetUpValue(slot)
+        | OpCode::SetUpValue(slot)
+        | OpCode::IncrementLocal(slot)
+        | OpCode::AddLocal(slot)
+        | OpCode::GetEnclosing(slot)
+        | OpCode::SetEnclosing(slot)
+        | OpCode::Inherit(slot)
+        | OpCode::BuildList(slot)
+        | OpCode::BuildTuple(slot)
+        | OpCode::Unpack(slot)
+        | OpCode::UnpackList(slot)
+        | OpCode::Call(slot) => format!("{:>4}", slot),
+        OpCode::JumpIfFalse(jump) | OpCode::Jump(jump) | OpCode::Try(jump) => {
+            format!("{:>4} -> {}", jump, offset + 1 + jump)
+        }
+        OpCode::Loop(jump) => format!("{:>4} -> {}", jump, offset + 1 - jump),
+        OpCode::Upvalue(upvalue) => format!(
+            "{} {}",
+            if upvalue.is_local { "local" } else { "upvalue" },
+            upvalue.index
+        ),
+        _ => String::new(),
+    };
+
+    format!("{:04} {} {:<16} {}", offset, line, op.name(), operands)
+        .trim_end()
+        .to_string()
+}
+
+// Disassembles a function followed by every function nested in its constants.
+pub fn disassemble_function(function: &Function, heap: &VirtualMemory) -> String {
+    let mut out = format!("== {} ==\n", function.name);
+    for offset in 0..function.chunk.code.len() {
+        out.push_str(&disassemble_instruction(&function.chunk, offset, heap));
+        out.push('\n');
+    }
+
+    for constant in function.chunk.constants.iter() {
+        if let Value::Object(ptr) = constant {
+            if let Object::Function(nested) = heap.deref(*ptr) {
+                out.push('\n');
+                out.push_str(&disassemble_function(nested, heap));
+            }
+        }
+    }
+    out
+}
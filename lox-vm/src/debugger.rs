@@ -0,0 +1,149 @@
+use super::collections::Set;
+use super::value::Value;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    Continue,
+    StepIn,
+    StepOver,
+    StepOut,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PauseReason {
+    Breakpoint,
+    Step,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Paused(PauseReason, usize), //Line we stopped at
+    Finished,
+}
+
+// A paused call frame, innermost first in VM::backtrace.
+#[derive(Debug, Clone)]
+pub struct FrameInfo {
+    pub name: String,
+    pub line: usize,
+    pub locals: Vec<(String, Value)>,
+}
+
+// Decides when the VM should stop. The VM asks before every instruction while
+// a debugger is attached, and we only stop on the first instruction of a
+// line so a statement is never stepped through one opcode at a time.
+pub struct Debugger {
+    pub breakpoints: Set<usize>,
+    resume: Resume,
+    resume_depth: usize,
+    //Last line executed in each active call frame
+    lines: Vec<usize>,
+}
+
+impl Debugger {
+    pub fn new(stop_on_entry: bool) -> Debugger {
+        Debugger {
+            breakpoints: Set::new(),
+            resume: if stop_on_entry {
+                Resume::StepIn
+            } else {
+                Resume::Continue
+            },
+            resume_depth: 0,
+            lines: vec![],
+        }
+    }
+
+    pub fn resume(&mut self, resume: Resume, depth: usize) {
+        self.resume = resume;
+        self.resume_depth = depth;
+    }
+
+    pub fn should_pause(&mut self, depth: usize, line: usize) -> Option<PauseReason> {
+        self.lines.truncate(depth + 1);
+        if self.lines.len() == depth + 1 {
+            if self.lines[depth] == line {
+                return None;
+            }
+            self.lines[depth] = line;
+        } else {
+            self.lines.resize(depth + 1, 0);
+            self.lines[depth] = line;
+        }
+
+        if self.breakpoints.contains(&line) {
+            return Some(PauseReason::Breakpoint);
+        }
+
+        let step = match self.resume {
+            Resume::Continue => false,
+            Resume::StepIn => true,
+            Resume::StepOver => depth <= self.resume_depth,
+            Resume::StepOut => depth < self.resume_depth,
+        };
+        if step {
+            Some(PauseReason::Step)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod debugger_tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::VM;
+    use crate::scanner::scan_tokens;
+    use std::io;
+
+    const SOURCE: &str = "fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\nvar x = add(1, 2);\nprint x;\n";
+
+    fn start(debugger: Debugger) -> (VM, Status) {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        vm.attach_debugger(debugger);
+        let tokens = scan_tokens(&String::from(SOURCE)).unwrap();
+        let mut compiler = Compiler::new(tokens, vm.take_virtual_memory());
+        let main = compiler.compile().ok().unwrap();
+        let status = vm.start(main, compiler.heap).ok().unwrap();
+        (vm, status)
+    }
+
+    #[test]
+    fn breakpoints_and_locals() {
+        let mut debugger = Debugger::new(false);
+        debugger.breakpoints.insert(3);
+        let (mut vm, status) = start(debugger);
+        assert_eq!(status, Status::Paused(PauseReason::Breakpoint, 3));
+
+        let backtrace = vm.backtrace();
+        assert_eq!(backtrace[0].name, "add");
+        let names: Vec<&str> = backtrace[0].locals.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "sum"]);
+        assert_eq!(backtrace[1].line, 5);
+
+        assert_eq!(vm.resume(Resume::Continue).ok(), Some(Status::Finished));
+    }
+
+    #[test]
+    fn stepping() {
+        let (mut vm, status) = start(Debugger::new(true));
+        assert_eq!(status, Status::Paused(PauseReason::Step, 1));
+        assert_eq!(
+            vm.resume(Resume::StepOver).ok(),
+            Some(Status::Paused(PauseReason::Step, 5))
+        );
+        assert_eq!(
+            vm.resume(Resume::StepIn).ok(),
+            Some(Status::Paused(PauseReason::Step, 2))
+        );
+        assert_eq!(
+            vm.resume(Resume::StepOut).ok(),
+            Some(Status::Paused(PauseReason::Step, 6))
+        );
+        assert_eq!(vm.resume(Resume::StepOver).ok(), Some(Status::Finished));
+    }
+}
@@ -0,0 +1,170 @@
+use super::compiler::CompilerError;
+use super::interpreter::InterpreterError;
+use super::scanner::ScannerError;
+use super::token::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+// A problem found while scanning, compiling or running a script, in a form
+// that tools can consume.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub file: Option<String>,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn from_scanner_error(error: &ScannerError, file: Option<&str>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code: "scan-error",
+            message: error.description.clone(),
+            file: file.map(String::from),
+            span: Span {
+                line: error.line,
+                column: error.column,
+                length: 1,
+            },
+        }
+    }
+
+    pub fn from_compiler_error(error: &CompilerError, file: Option<&str>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code: error.code(),
+            message: String::from(error.message()),
+            file: file.map(String::from),
+            span: error.span(),
+        }
+    }
+
+    pub fn from_runtime_error(error: &InterpreterError, file: Option<&str>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code: error.code(),
+            message: String::from(error.message()),
+            file: file.map(String::from),
+            span: Span::line(error.line()),
+        }
+    }
+
+    // Columns are 1-based, so a column of 0 means we only know the line.
+    pub fn to_json(&self) -> String {
+        let file = match &self.file {
+            Some(file) => json_string(file),
+            None => String::from("null"),
+        };
+        let (column, span) = if self.span.column == 0 {
+            (String::from("null"), String::from("null"))
+        } else {
+            (
+                self.span.column.to_string(),
+                format!(
+                    "{{\"line\":{},\"column\":{},\"length\":{}}}",
+                    self.span.line, self.span.column, self.span.length
+                ),
+            )
+        };
+
+        format!(
+            "{{\"severity\":{},\"code\":{},\"message\":{},\"file\":{},\"line\":{},\"column\":{},\"span\":{}}}",
+            json_string(self.severity.as_str()),
+            json_string(self.code),
+            json_string(&self.message),
+            file,
+            self.span.line,
+            column,
+            span
+        )
+    }
+}
+
+// The same shape as to_json, so either can be used to read the other.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Diagnostic {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let known_column = self.span.column != 0;
+        let mut diagnostic = serializer.serialize_struct("Diagnostic", 7)?;
+        diagnostic.serialize_field("severity", &self.severity)?;
+        diagnostic.serialize_field("code", self.code)?;
+        diagnostic.serialize_field("message", &self.message)?;
+        diagnostic.serialize_field("file", &self.file)?;
+        diagnostic.serialize_field("line", &self.span.line)?;
+        diagnostic.serialize_field("column", &Some(self.span.column).filter(|_| known_column))?;
+        diagnostic.serialize_field("span", &Some(self.span).filter(|_| known_column))?;
+        diagnostic.end()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod diagnostic_tests {
+    use super::*;
+
+    #[test]
+    fn serializes_like_to_json() {
+        let span = Span {
+            line: 2,
+            column: 7,
+            length: 1,
+        };
+        let error = CompilerError::SyntaxError(String::from("Expect ';'"), span);
+        let runtime = InterpreterError::NameError(4, String::from("Undefined variable \"x\""));
+        for diagnostic in &[
+            Diagnostic::from_compiler_error(&error, Some("a.lox")),
+            Diagnostic::from_runtime_error(&runtime, None),
+        ] {
+            assert_eq!(serde_json::to_string(diagnostic).unwrap(), diagnostic.to_json());
+        }
+    }
+
+    #[test]
+    fn serializes_errors_by_name() {
+        let error = InterpreterError::TypeError(3, String::from("Expected a number"));
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"code":"type-error","message":"Expected a number","line":3}"#
+        );
+        let error = CompilerError::SyntaxError(String::from("Expect ')'"), Span::line(1));
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"code":"syntax-error","message":"Expect ')'","span":{"line":1,"column":0,"length":0}}"#
+        );
+    }
+}
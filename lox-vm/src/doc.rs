@@ -0,0 +1,134 @@
+use super::interpreter::VM;
+use super::natives;
+use super::value::{FnType, Function, Object, Value};
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+// What :doc shows for a global: a native's signature and description from its
+// registration, or the parameters of a function or a class's methods.
+pub fn doc(vm: &VM, name: &str) -> Option<String> {
+    let (_, value) = vm.globals().into_iter().find(|(global, _)| *global == name)?;
+    let ptr = match value {
+        Value::Object(ptr) => ptr,
+        _ => return None,
+    };
+
+    match vm.heap_object(ptr)? {
+        Object::NativeFunction(name, arity, _) => Some(match natives::lookup(name) {
+            Some(native) => format!("native {}\n    {}", native.signature(), native.doc),
+            None => format!("native {}\n    Takes {} arguments.", name, arity),
+        }),
+        Object::Closure(closure) => {
+            let fun = function(vm, closure.function_pointer)?;
+            let takes = if fun.variadic {
+                format!("at least {}", fun.arity - 1)
+            } else {
+                fun.arity.to_string()
+            };
+            Some(format!("fun {}\n    Takes {} arguments.", signature(fun), takes))
+        }
+        Object::Class(class) => {
+            let methods = method_signatures(vm, class.methods.values(), "");
+            let statics = method_signatures(vm, class.statics.values(), "static ");
+            let accessors = class.getters.values().chain(class.setters.values());
+            let accessors = method_signatures(vm, accessors, "");
+            Some(format!("class {}{}{}{}", class.name, methods, accessors, statics))
+        }
+        _ => None,
+    }
+}
+
+fn function(vm: &VM, ptr: u64) -> Option<&Function> {
+    match vm.heap_object(ptr)? {
+        Object::Function(fun) => Some(fun),
+        _ => None,
+    }
+}
+
+//Parameters are the locals in slots 1 to arity, after the callee
+//One sorted line for each method
+fn method_signatures<'a>(
+    vm: &VM,
+    closures: impl Iterator<Item = &'a u64>,
+    prefix: &str,
+) -> String {
+    let mut methods: Vec<String> = closures
+        .filter_map(|closure_p| match vm.heap_object(*closure_p)? {
+            Object::Closure(closure) => function(vm, closure.function_pointer),
+            _ => None,
+        })
+        .map(|method| format!("\n    {}{}", prefix, signature(method)))
+        .collect();
+    methods.sort();
+    methods.concat()
+}
+
+fn signature(fun: &Function) -> String {
+    let params: Vec<String> = (1..=fun.arity)
+        .map(|slot| {
+            let name = fun
+                .chunk
+                .locals
+                .iter()
+                .find(|local| local.slot == slot)
+                .map_or("_", |local| local.name.as_str());
+            if fun.variadic && slot == fun.arity {
+                format!("...{}", name)
+            } else {
+                String::from(name)
+            }
+        })
+        .collect();
+    match fun.fn_type {
+        FnType::Getter => fun.name.clone(),
+        FnType::Setter => format!("{}=({})", fun.name, params.join(", ")),
+        _ => format!("{}({})", fun.name, params.join(", ")),
+    }
+}
+
+#[cfg(test)]
+mod doc_tests {
+    use super::*;
+    use crate::test_support::run;
+
+    fn vm_with(source: &str) -> VM {
+        let mut vm = VM::new();
+        vm.define_natives(&natives::TEST);
+        run(&mut vm, source).unwrap();
+        vm
+    }
+
+    #[test]
+    fn documents_natives() {
+        let vm = vm_with("");
+        assert_eq!(
+            doc(&vm, "fail").unwrap(),
+            "native fail(message)\n    Fails the test with message."
+        );
+    }
+
+    #[test]
+    fn documents_functions_and_classes() {
+        let vm = vm_with(
+            "fun add(a, b) { var c = a + b; return c; }\n\
+             class Point { init(x, y) {} norm() {} static origin() {} x { return 0; } x=(v) {} }\n\
+             var n = 1;",
+        );
+        assert_eq!(doc(&vm, "add").unwrap(), "fun add(a, b)\n    Takes 2 arguments.");
+        assert_eq!(
+            doc(&vm, "Point").unwrap(),
+            "class Point\n    init(x, y)\n    norm()\n    x\n    x=(v)\n    static origin()"
+        );
+        assert_eq!(doc(&vm, "n"), None);
+        assert_eq!(doc(&vm, "missing"), None);
+    }
+
+    #[test]
+    fn documents_rest_parameters() {
+        let vm = vm_with("fun log(level, ...parts) {}");
+        assert_eq!(
+            doc(&vm, "log").unwrap(),
+            "fun log(level, ...parts)\n    Takes at least 1 arguments."
+        );
+    }
+}
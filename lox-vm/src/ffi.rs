@@ -0,0 +1,269 @@
+use super::interpreter::VM;
+use super::value::{Object, Userdata, Value};
+use libloading::Library;
+use std::ffi::{c_char, c_int, c_long, c_void, CStr, CString};
+
+// Calls into C libraries. Nothing checks that a signature matches the function
+// it's given, so a wrong one is undefined behavior, which is why scripts only
+// get these natives when lox.toml lists the "ffi" capability.
+//
+// A signature is the return type followed by the parameter types in
+// parentheses, like "d(dd)" for pow:
+//
+//   d  double, a number
+//   i  int, a number rounded towards zero
+//   l  long, the same
+//   s  const char *, a string copied with a nul on the end, or nil for NULL
+//   p  an opaque pointer, userdata or nil for NULL
+//   v  void, only as the return type where it gives nil
+//
+// Up to four parameters are supported.
+
+const LIBRARY: &str = "library";
+const POINTER: &str = "pointer";
+const MAX_ARGS: usize = 4;
+
+//How each argument is passed, which is all the calling convention cares about
+#[derive(Clone, Copy)]
+enum Arg {
+    Float(f64),
+    Int(usize),
+}
+
+pub fn load(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path = string(vm, args[0]).ok_or_else(|| String::from("Expected a library path"))?;
+    let library = unsafe { Library::new(&path) }
+        .map_err(|e| format!("Couldn't load {}: {}", path, e))?;
+    //Functions from it may be called at any point, so it's never unloaded
+    let address = Box::into_raw(Box::new(library)) as usize;
+    Ok(vm.new_userdata(Userdata {
+        kind: LIBRARY,
+        address,
+    }))
+}
+
+pub fn call(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if args.len() < 3 {
+        return Err(format!("Expected at least 3 arguments but got {}", args.len()));
+    }
+    let library = match userdata(vm, args[0]) {
+        Some(Userdata { kind: LIBRARY, address }) => unsafe { &*(address as *const Library) },
+        _ => return Err(String::from("Expected a library from ffiLoad")),
+    };
+    let name = string(vm, args[1]).ok_or_else(|| String::from("Expected a function name"))?;
+    let signature =
+        string(vm, args[2]).ok_or_else(|| String::from("Expected a signature like \"d(d)\""))?;
+    let (ret, params) = parse_signature(&signature)?;
+
+    let values = &args[3..];
+    if params.len() != values.len() {
+        return Err(format!(
+            "{} takes {} arguments but got {}",
+            name,
+            params.len(),
+            values.len()
+        ));
+    }
+
+    //The strings have to outlive the call
+    let mut strings = vec![];
+    let mut call_args = vec![];
+    for (param, value) in params.iter().zip(values.iter()) {
+        call_args.push(argument(vm, *param, *value, &mut strings)?);
+    }
+
+    let function = unsafe { library.get::<*const c_void>(name.as_bytes()) }
+        .map_err(|e| format!("Couldn't find {}: {}", name, e))?;
+    let function = *function;
+    unsafe {
+        Ok(match ret {
+            'd' => Value::Number(invoke::<f64>(function, &call_args)),
+            'i' => Value::Integer(i64::from(invoke::<c_int>(function, &call_args))),
+            //c_long is already i64 here, but only 32 bits on LLP64 targets like Windows
+            #[allow(clippy::useless_conversion)]
+            'l' => Value::Integer(i64::from(invoke::<c_long>(function, &call_args))),
+            's' => match invoke::<*const c_char>(function, &call_args) {
+                ptr if ptr.is_null() => Value::Nil,
+                ptr => {
+                    let s = CStr::from_ptr(ptr).to_string_lossy().into_owned();
+                    vm.new_string(s)
+                }
+            },
+            'p' => match invoke::<*mut c_void>(function, &call_args) {
+                ptr if ptr.is_null() => Value::Nil,
+                ptr => vm.new_userdata(Userdata {
+                    kind: POINTER,
+                    address: ptr as usize,
+                }),
+            },
+            _ => {
+                invoke::<()>(function, &call_args);
+                Value::Nil
+            }
+        })
+    }
+}
+
+fn parse_signature(signature: &str) -> Result<(char, Vec<char>), String> {
+    let invalid = || format!("Invalid signature \"{}\"", signature);
+    let mut chars = signature.chars();
+    let ret = chars.next().filter(|c| "dilspv".contains(*c)).ok_or_else(invalid)?;
+    let params = chars
+        .as_str()
+        .strip_prefix('(')
+        .and_then(|rest| rest.strip_suffix(')'))
+        .ok_or_else(invalid)?;
+    if !params.chars().all(|c| "dilsp".contains(c)) {
+        return Err(invalid());
+    }
+    if params.len() > MAX_ARGS {
+        return Err(format!("At most {} parameters are supported", MAX_ARGS));
+    }
+    Ok((ret, params.chars().collect()))
+}
+
+fn argument(
+    vm: &VM,
+    param: char,
+    value: Value,
+    strings: &mut Vec<CString>,
+) -> Result<Arg, String> {
+    let mismatch = |expected: &str| {
+        format!("Expected {} for '{}' but got {}", expected, param, vm.stringify(value))
+    };
+    match (param, value) {
+        ('d', Value::Number(n)) => Ok(Arg::Float(n)),
+        ('d', Value::Integer(n)) => Ok(Arg::Float(n as f64)),
+        //A float is only passed on if it's a count or size C can take as is
+        ('i', Value::Number(n)) | ('l', Value::Number(n)) if n < 0.0 || n.fract() != 0.0 => {
+            Err(mismatch("a non-negative integer"))
+        }
+        ('i', Value::Number(n)) | ('l', Value::Number(n)) => Ok(Arg::Int(n as usize)),
+        ('i', Value::Integer(n)) | ('l', Value::Integer(n)) => Ok(Arg::Int(n as usize)),
+        ('s', Value::Nil) | ('p', Value::Nil) => Ok(Arg::Int(0)),
+        ('s', _) => {
+            let s = string(vm, value).ok_or_else(|| mismatch("a string"))?;
+            let s = CString::new(s).map_err(|_| String::from("Strings can't contain nul"))?;
+            let ptr = s.as_ptr() as usize;
+            strings.push(s);
+            Ok(Arg::Int(ptr))
+        }
+        ('p', _) => match userdata(vm, value) {
+            Some(Userdata { kind: POINTER, address }) => Ok(Arg::Int(address)),
+            _ => Err(mismatch("a pointer")),
+        },
+        _ => Err(mismatch("a number")),
+    }
+}
+
+fn string(vm: &VM, value: Value) -> Option<String> {
+    match value {
+        Value::Object(ptr) => match vm.heap_object(ptr)? {
+            Object::String(s) => Some(s.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn userdata(vm: &VM, value: Value) -> Option<Userdata> {
+    match value {
+        Value::Object(ptr) => match vm.heap_object(ptr)? {
+            Object::Userdata(userdata) => Some(*userdata),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Calls function with the arguments in registers the way C expects. Integers
+// and pointers travel the same way on the platforms we support, so only
+// whether each argument is a double picks the function type.
+unsafe fn invoke<R>(function: *const c_void, args: &[Arg]) -> R {
+    use Arg::{Float as F, Int as I};
+
+    macro_rules! call {
+        ($($arg:ident: $ty:ty),*) => {{
+            let function: extern "C" fn($($ty),*) -> R = std::mem::transmute(function);
+            function($(*$arg),*)
+        }};
+    }
+
+    match args {
+        [] => call!(),
+        [F(a)] => call!(a: f64),
+        [I(a)] => call!(a: usize),
+        [F(a), F(b)] => call!(a: f64, b: f64),
+        [F(a), I(b)] => call!(a: f64, b: usize),
+        [I(a), F(b)] => call!(a: usize, b: f64),
+        [I(a), I(b)] => call!(a: usize, b: usize),
+        [F(a), F(b), F(c)] => call!(a: f64, b: f64, c: f64),
+        [F(a), F(b), I(c)] => call!(a: f64, b: f64, c: usize),
+        [F(a), I(b), F(c)] => call!(a: f64, b: usize, c: f64),
+        [F(a), I(b), I(c)] => call!(a: f64, b: usize, c: usize),
+        [I(a), F(b), F(c)] => call!(a: usize, b: f64, c: f64),
+        [I(a), F(b), I(c)] => call!(a: usize, b: f64, c: usize),
+        [I(a), I(b), F(c)] => call!(a: usize, b: usize, c: f64),
+        [I(a), I(b), I(c)] => call!(a: usize, b: usize, c: usize),
+        [F(a), F(b), F(c), F(d)] => call!(a: f64, b: f64, c: f64, d: f64),
+        [F(a), F(b), F(c), I(d)] => call!(a: f64, b: f64, c: f64, d: usize),
+        [F(a), F(b), I(c), F(d)] => call!(a: f64, b: f64, c: usize, d: f64),
+        [F(a), F(b), I(c), I(d)] => call!(a: f64, b: f64, c: usize, d: usize),
+        [F(a), I(b), F(c), F(d)] => call!(a: f64, b: usize, c: f64, d: f64),
+        [F(a), I(b), F(c), I(d)] => call!(a: f64, b: usize, c: f64, d: usize),
+        [F(a), I(b), I(c), F(d)] => call!(a: f64, b: usize, c: usize, d: f64),
+        [F(a), I(b), I(c), I(d)] => call!(a: f64, b: usize, c: usize, d: usize),
+        [I(a), F(b), F(c), F(d)] => call!(a: usize, b: f64, c: f64, d: f64),
+        [I(a), F(b), F(c), I(d)] => call!(a: usize, b: f64, c: f64, d: usize),
+        [I(a), F(b), I(c), F(d)] => call!(a: usize, b: f64, c: usize, d: f64),
+        [I(a), F(b), I(c), I(d)] => call!(a: usize, b: f64, c: usize, d: usize),
+        [I(a), I(b), F(c), F(d)] => call!(a: usize, b: usize, c: f64, d: f64),
+        [I(a), I(b), F(c), I(d)] => call!(a: usize, b: usize, c: f64, d: usize),
+        [I(a), I(b), I(c), F(d)] => call!(a: usize, b: usize, c: usize, d: f64),
+        [I(a), I(b), I(c), I(d)] => call!(a: usize, b: usize, c: usize, d: usize),
+        _ => unreachable!("Signatures are limited to {} parameters", MAX_ARGS),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod ffi_tests {
+    use super::*;
+    use crate::natives::{FFI, TEST};
+    use crate::test_support;
+
+    fn run(source: &str) -> Result<(), String> {
+        let mut vm = VM::new();
+        vm.define_natives(&TEST);
+        vm.define_natives(&FFI);
+        test_support::run(&mut vm, source)
+    }
+
+    #[test]
+    fn calls_c_functions() {
+        let source = "var m = ffiLoad(\"libm.so.6\");\n\
+                      assertEq(ffiCall(m, \"cos\", \"d(d)\", 0), 1);\n\
+                      assertEq(ffiCall(m, \"pow\", \"d(dd)\", 2, 10), 1024);\n\
+                      var c = ffiLoad(\"libc.so.6\");\n\
+                      assertEq(ffiCall(c, \"strlen\", \"l(s)\", \"hello\"), 5);\n\
+                      assertEq(ffiCall(c, \"abs\", \"i(i)\", -3), 3);\n\
+                      var buffer = ffiCall(c, \"malloc\", \"p(l)\", 16);\n\
+                      ffiCall(c, \"strcpy\", \"p(ps)\", buffer, \"copied\");\n\
+                      assertEq(ffiCall(c, \"strdup\", \"s(p)\", buffer), \"copied\");\n\
+                      ffiCall(c, \"free\", \"v(p)\", buffer);";
+        assert_eq!(run(source), Ok(()));
+    }
+
+    #[test]
+    fn checks_signatures_and_arguments() {
+        let m = "var m = ffiLoad(\"libm.so.6\");\n";
+        let error = |call: &str| run(&format!("{}{}", m, call)).unwrap_err();
+        assert!(error("ffiCall(m, \"cos\", \"x(d)\", 1);").contains("Invalid signature"));
+        assert!(error("ffiCall(m, \"cos\", \"d(d)\");").contains("takes 1 arguments but got 0"));
+        assert!(error("ffiCall(m, \"cos\", \"d(d)\", \"a\");").contains("Expected a number"));
+        assert!(error("ffiCall(m, \"nope\", \"d(d)\", 1);").contains("Couldn't find nope"));
+        let ldexp = |n: &str| error(&format!("ffiCall(m, \"ldexp\", \"d(di)\", 1, {});", n));
+        assert!(ldexp("2.5").contains("Expected a non-negative integer"));
+        assert!(ldexp("-2.0").contains("Expected a non-negative integer"));
+        assert!(run("ffiLoad(\"no-such-library.so\");").unwrap_err().contains("Couldn't load"));
+    }
+}
@@ -0,0 +1,568 @@
+// Generates random but well formed Lox programs for fuzzing and differential
+// testing. Programs only use names that are in scope and values of the right
+// type, so most of them run to completion rather than stopping at the first
+// undefined variable. Every loop is bounded and a function can only call
+// functions declared before it, so every program terminates.
+
+use super::rng::Rng;
+use std::fmt::Write;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub statements: usize, //Top level statements
+    pub depth: usize,      //How deeply blocks, functions and expressions nest
+    pub error_rate: u32,   //Percent of programs with a type error somewhere
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            statements: 16,
+            depth: 3,
+            error_rate: 5,
+        }
+    }
+}
+
+pub fn generate(seed: u64, config: &Config) -> String {
+    let mut generator = Generator {
+        rng: Rng::new(seed),
+        config: config.clone(),
+        out: String::new(),
+        indent: 0,
+        scopes: vec![Scope::default()],
+        classes: vec![],
+        next_name: 0,
+        in_function: false,
+        in_method: false,
+        type_error_at: None,
+    };
+    if generator.rng.chance(config.error_rate) {
+        let at = generator.rng.below(config.statements.max(1) as u64) as usize;
+        generator.type_error_at = Some(at);
+    }
+
+    for idx in 0..config.statements {
+        if generator.type_error_at == Some(idx) {
+            generator.type_error();
+        } else {
+            generator.statement(config.depth);
+        }
+    }
+    generator.out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Number,
+    String,
+    Boolean,
+}
+
+struct Variable {
+    name: String,
+    ty: Type,
+    assignable: bool, //Loop counters aren't, or the loop might not end
+}
+
+//Anything that can be called with numbers and returns a number
+struct Callable {
+    name: String,
+    arity: usize,
+}
+
+struct Class {
+    name: String,
+    methods: Vec<String>, //No arguments and returns a number
+}
+
+struct Instance {
+    name: String,
+    class: usize,
+}
+
+#[derive(Default)]
+struct Scope {
+    variables: Vec<Variable>,
+    callables: Vec<Callable>,
+    instances: Vec<Instance>,
+}
+
+struct Generator {
+    rng: Rng,
+    config: Config,
+    out: String,
+    indent: usize,
+    scopes: Vec<Scope>,
+    classes: Vec<Class>,
+    next_name: usize,
+    in_function: bool,
+    in_method: bool,
+    type_error_at: Option<usize>,
+}
+
+impl Generator {
+    fn name(&mut self, prefix: &str) -> String {
+        self.next_name += 1;
+        format!("{}{}", prefix, self.next_name)
+    }
+
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    fn variables(&self, ty: Type, assignable: bool) -> Vec<String> {
+        self.scopes
+            .iter()
+            .flat_map(|scope| scope.variables.iter())
+            .filter(|v| v.ty == ty && (v.assignable || !assignable))
+            .map(|v| v.name.clone())
+            .collect()
+    }
+
+    fn callables(&self) -> Vec<(String, usize)> {
+        self.scopes
+            .iter()
+            .flat_map(|scope| scope.callables.iter())
+            .map(|c| (c.name.clone(), c.arity))
+            .collect()
+    }
+
+    fn instances(&self) -> Vec<(String, usize)> {
+        self.scopes
+            .iter()
+            .flat_map(|scope| scope.instances.iter())
+            .map(|i| (i.name.clone(), i.class))
+            .collect()
+    }
+
+    // Runs f inside a new scope, at one more level of indentation.
+    fn nested<F: FnOnce(&mut Generator)>(&mut self, f: F) {
+        self.scopes.push(Scope::default());
+        self.indent += 1;
+        f(self);
+        self.indent -= 1;
+        self.scopes.pop();
+    }
+
+    fn block(&mut self, depth: usize) {
+        let count = 1 + self.rng.below(3);
+        self.nested(|g| {
+            for _ in 0..count {
+                g.statement(depth);
+            }
+        });
+    }
+
+    fn statement(&mut self, depth: usize) {
+        let top_level = self.scopes.len() == 1;
+        let choice = if depth == 0 {
+            self.rng.below(5)
+        } else {
+            self.rng.below(13)
+        };
+        match choice {
+            0 | 1 => {
+                let ty = self.any_type();
+                let expr = self.expr(ty, self.config.depth);
+                self.line(&format!("print {};", expr));
+            }
+            2 => self.var_declaration(),
+            3 => self.assignment(),
+            4 => self.call_statement(),
+            5 => self.if_statement(depth),
+            6 => self.for_statement(depth),
+            7 => self.while_statement(depth),
+            8 => {
+                self.line("{");
+                self.block(depth - 1);
+                self.line("}");
+            }
+            9 => self.function(depth),
+            10 => self.counter(),
+            11 if top_level => self.class(),
+            _ => self.instance(),
+        }
+    }
+
+    fn any_type(&mut self) -> Type {
+        match self.rng.below(3) {
+            0 => Type::String,
+            1 => Type::Boolean,
+            _ => Type::Number,
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let ty = self.any_type();
+        let expr = self.expr(ty, self.config.depth);
+        let name = self.name("v");
+        self.line(&format!("var {} = {};", name, expr));
+        self.scope().variables.push(Variable {
+            name,
+            ty,
+            assignable: true,
+        });
+    }
+
+    fn assignment(&mut self) {
+        let ty = self.any_type();
+        let targets = self.variables(ty, true);
+        match self.rng.pick(&targets).cloned() {
+            Some(target) => {
+                //Strings built from themselves could double in every loop
+                let expr = if ty == Type::String {
+                    self.string_literal()
+                } else {
+                    self.expr(ty, self.config.depth)
+                };
+                self.line(&format!("{} = {};", target, expr));
+            }
+            None => self.var_declaration(),
+        }
+    }
+
+    fn call_statement(&mut self) {
+        let callables = self.callables();
+        match self.rng.pick(&callables).cloned() {
+            Some((name, arity)) => {
+                let call = self.call(&name, arity, self.config.depth);
+                self.line(&format!("{};", call));
+            }
+            None => self.var_declaration(),
+        }
+    }
+
+    fn if_statement(&mut self, depth: usize) {
+        let condition = self.expr(Type::Boolean, self.config.depth);
+        self.line(&format!("if ({}) {{", condition));
+        self.block(depth - 1);
+        if self.in_function && self.rng.chance(20) {
+            let value = self.expr(Type::Number, 1);
+            self.indent += 1;
+            self.line(&format!("return {};", value));
+            self.indent -= 1;
+        }
+        if self.rng.chance(50) {
+            self.line("} else {");
+            self.block(depth - 1);
+        }
+        self.line("}");
+    }
+
+    fn for_statement(&mut self, depth: usize) {
+        let counter = self.name("i");
+        let limit = 1 + self.rng.below(5);
+        self.line(&format!(
+            "for (var {0} = 0; {0} < {1}; {0} = {0} + 1) {{",
+            counter, limit
+        ));
+        self.nested(|g| {
+            g.scope().variables.push(Variable {
+                name: counter,
+                ty: Type::Number,
+                assignable: false,
+            });
+            g.block(depth - 1);
+        });
+        self.line("}");
+    }
+
+    fn while_statement(&mut self, depth: usize) {
+        let counter = self.name("w");
+        let limit = 1 + self.rng.below(5);
+        self.line(&format!("var {} = 0;", counter));
+        self.scope().variables.push(Variable {
+            name: counter.clone(),
+            ty: Type::Number,
+            assignable: false,
+        });
+        self.line(&format!("while ({} < {}) {{", counter, limit));
+        let count = 1 + self.rng.below(3);
+        self.nested(|g| {
+            g.line(&format!("{0} = {0} + 1;", counter));
+            for _ in 0..count {
+                g.statement(depth - 1);
+            }
+        });
+        self.line("}");
+    }
+
+    fn function(&mut self, depth: usize) {
+        let name = self.name("f");
+        let arity = self.rng.below(3) as usize;
+        let params: Vec<_> = (0..arity).map(|_| self.name("p")).collect();
+        self.line(&format!("fun {}({}) {{", name, params.join(", ")));
+
+        let in_function = self.in_function;
+        self.in_function = true;
+        self.nested(|g| {
+            for param in params {
+                g.scope().variables.push(Variable {
+                    name: param,
+                    ty: Type::Number,
+                    assignable: true,
+                });
+            }
+            let count = 1 + g.rng.below(3);
+            for _ in 0..count {
+                g.statement(depth - 1);
+            }
+            let result = g.expr(Type::Number, g.config.depth);
+            g.line(&format!("return {};", result));
+        });
+        self.in_function = in_function;
+
+        self.line("}");
+        //Declared after the body so functions can't recurse
+        self.scope().callables.push(Callable { name, arity });
+    }
+
+    // A closure over a local that it counts up each call.
+    fn counter(&mut self) {
+        let maker = self.name("make");
+        let count = self.name("c");
+        let inner = self.name("next");
+        let step = self.expr(Type::Number, 1);
+        self.line(&format!("fun {}() {{", maker));
+        self.nested(|g| {
+            g.line(&format!("var {} = 0;", count));
+            g.line(&format!("fun {}() {{", inner));
+            g.nested(|g| {
+                g.line(&format!("{0} = {0} + {1};", count, step));
+                g.line(&format!("return {};", count));
+            });
+            g.line("}");
+            g.line(&format!("return {};", inner));
+        });
+        self.line("}");
+
+        let name = self.name("k");
+        self.line(&format!("var {} = {}();", name, maker));
+        self.scope().callables.push(Callable { name, arity: 0 });
+    }
+
+    fn class(&mut self) {
+        let name = self.name("C");
+        let superclass = self.rng.pick(&self.classes).map(|c| c.name.clone());
+        let superclass_idx = self
+            .classes
+            .iter()
+            .position(|c| Some(&c.name) == superclass.as_ref());
+        match &superclass {
+            Some(superclass) => self.line(&format!("class {} < {} {{", name, superclass)),
+            None => self.line(&format!("class {} {{", name)),
+        }
+
+        let mut methods = superclass_idx
+            .map(|idx| self.classes[idx].methods.clone())
+            .unwrap_or_default();
+        let inherited = methods.clone();
+        let in_method = self.in_method;
+        self.in_method = true;
+        self.indent += 1;
+        if superclass.is_none() {
+            self.line("init(n) {");
+            self.nested(|g| g.line("this.f = n;"));
+            self.line("}");
+        }
+        for _ in 0..1 + self.rng.below(3) {
+            //Sometimes override what's inherited
+            let method = match self.rng.pick(&inherited).cloned() {
+                Some(inherited) if self.rng.chance(30) => inherited,
+                _ => self.name("m"),
+            };
+            self.line(&format!("{}() {{", method));
+            self.nested(|g| {
+                let result = g.expr(Type::Number, g.config.depth);
+                g.line(&format!("return {};", result));
+            });
+            self.line("}");
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+        }
+        self.indent -= 1;
+        self.in_method = in_method;
+        self.line("}");
+        self.classes.push(Class { name, methods });
+    }
+
+    fn instance(&mut self) {
+        if self.classes.is_empty() {
+            return self.var_declaration();
+        }
+        let class = self.rng.below(self.classes.len() as u64) as usize;
+        let arg = self.expr(Type::Number, 1);
+        let name = self.name("o");
+        self.line(&format!(
+            "var {} = {}({});",
+            name, self.classes[class].name, arg
+        ));
+        self.scope().instances.push(Instance { name, class });
+
+        let instances = self.instances();
+        let (name, class) = self.rng.pick(&instances).cloned().unwrap();
+        if self.rng.chance(30) {
+            let value = self.expr(Type::Number, 1);
+            self.line(&format!("{}.f = {};", name, value));
+        } else if self.rng.chance(30) {
+            let method = self
+                .rng
+                .pick(&self.classes[class].methods)
+                .cloned()
+                .unwrap();
+            let bound = self.name("b");
+            self.line(&format!("var {} = {}.{};", bound, name, method));
+            self.scope().callables.push(Callable {
+                name: bound,
+                arity: 0,
+            });
+        } else if self.rng.chance(20) {
+            self.line(&format!("print {};", name));
+        }
+    }
+
+    fn call(&mut self, name: &str, arity: usize, depth: usize) -> String {
+        let args: Vec<_> = (0..arity).map(|_| self.expr(Type::Number, depth)).collect();
+        format!("{}({})", name, args.join(", "))
+    }
+
+    fn expr(&mut self, ty: Type, depth: usize) -> String {
+        if depth == 0 || self.rng.chance(30) {
+            return self.leaf(ty);
+        }
+        let depth = depth - 1;
+        match ty {
+            Type::Number => match self.rng.below(8) {
+                0 => format!("-{}", self.expr(Type::Number, depth)),
+                1 => format!("({})", self.expr(Type::Number, depth)),
+                2 => {
+                    let callables = self.callables();
+                    match self.rng.pick(&callables).cloned() {
+                        Some((name, arity)) => self.call(&name, arity, depth),
+                        None => self.leaf(ty),
+                    }
+                }
+                3 => {
+                    let instances = self.instances();
+                    match self.rng.pick(&instances).cloned() {
+                        Some((name, class)) if self.rng.chance(50) => {
+                            let methods = &self.classes[class].methods;
+                            let method = self.rng.pick(methods).cloned().unwrap();
+                            format!("{}.{}()", name, method)
+                        }
+                        Some((name, _)) => format!("{}.f", name),
+                        None => self.leaf(ty),
+                    }
+                }
+                _ => {
+                    let op = *self.rng.pick(&["+", "-", "*", "/"]).unwrap();
+                    let left = self.expr(Type::Number, depth);
+                    let right = self.expr(Type::Number, depth);
+                    format!("({} {} {})", left, op, right)
+                }
+            },
+            Type::String => {
+                let left = self.expr(Type::String, depth);
+                let right = self.expr(Type::String, depth);
+                format!("({} + {})", left, right)
+            }
+            Type::Boolean => match self.rng.below(5) {
+                0 => format!("!{}", self.expr(Type::Boolean, depth)),
+                1 => {
+                    let op = *self.rng.pick(&["and", "or"]).unwrap();
+                    let left = self.expr(Type::Boolean, depth);
+                    let right = self.expr(Type::Boolean, depth);
+                    format!("({} {} {})", left, op, right)
+                }
+                2 => {
+                    let op = *self.rng.pick(&["==", "!="]).unwrap();
+                    let ty = self.any_type();
+                    let left = self.expr(ty, depth);
+                    let right = self.expr(ty, depth);
+                    format!("({} {} {})", left, op, right)
+                }
+                _ => {
+                    let op = *self.rng.pick(&["<", "<=", ">", ">="]).unwrap();
+                    let left = self.expr(Type::Number, depth);
+                    let right = self.expr(Type::Number, depth);
+                    format!("({} {} {})", left, op, right)
+                }
+            },
+        }
+    }
+
+    fn leaf(&mut self, ty: Type) -> String {
+        let variables = self.variables(ty, false);
+        if self.rng.chance(50) {
+            if let Some(name) = self.rng.pick(&variables) {
+                return name.clone();
+            }
+        }
+        match ty {
+            Type::Number if self.in_method && self.rng.chance(50) => String::from("this.f"),
+            Type::Number => {
+                let mut literal = self.rng.below(20).to_string();
+                if self.rng.chance(20) {
+                    write!(literal, ".{}", 1 + self.rng.below(9)).unwrap();
+                }
+                literal
+            }
+            Type::String => self.string_literal(),
+            Type::Boolean => String::from(*self.rng.pick(&["true", "false"]).unwrap()),
+        }
+    }
+
+    fn string_literal(&mut self) -> String {
+        let word = *self.rng.pick(&["a", "lox", "", "hello", "42"]).unwrap();
+        format!("\"{}\"", word)
+    }
+
+    fn type_error(&mut self) {
+        let number = self.expr(Type::Number, 1);
+        let string = self.expr(Type::String, 1);
+        let expr = match self.rng.below(3) {
+            0 => format!("-{}", string),
+            1 => format!("{} + {}", number, string),
+            _ => format!("{} < nil", number),
+        };
+        self.line(&format!("print {};", expr));
+    }
+}
+
+#[cfg(test)]
+mod generator_tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::VirtualMemory;
+    use crate::scanner::scan_tokens;
+
+    #[test]
+    fn deterministic() {
+        let config = Config::default();
+        assert_eq!(generate(7, &config), generate(7, &config));
+        assert_ne!(generate(7, &config), generate(8, &config));
+    }
+
+    #[test]
+    fn programs_compile() {
+        for seed in 0..200 {
+            let source = generate(seed, &Config::default());
+            let tokens = scan_tokens(&source).unwrap();
+            let mut compiler = Compiler::new(tokens, VirtualMemory::new());
+            if let Err(errors) = compiler.compile() {
+                let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+                panic!("seed {}: {:?}\n{}", seed, errors, source);
+            }
+        }
+    }
+}
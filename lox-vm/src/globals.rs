@@ -0,0 +1,129 @@
+use super::collections::Map;
+use super::value::Value;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+// Global variable names interned as slots. The compiler puts a name's slot in
+// the DefineGlobal, GetGlobal and SetGlobal instructions next to the name's
+// constant, so the VM reads and writes globals by index and only looks at the
+// name to report an undefined variable. Slots are shared by every namespace,
+// so a module's table has room for names only other modules use.
+
+pub type GlobalSlot = usize;
+
+pub struct GlobalNames {
+    slots: Map<String, GlobalSlot>,
+    names: Vec<String>,
+}
+
+impl GlobalNames {
+    pub fn new() -> GlobalNames {
+        GlobalNames {
+            slots: Map::new(),
+            names: vec![],
+        }
+    }
+
+    pub fn slot(&mut self, name: &str) -> GlobalSlot {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
+        }
+        let slot = self.names.len();
+        self.slots.insert(String::from(name), slot);
+        self.names.push(String::from(name));
+        slot
+    }
+
+    pub fn get(&self, name: &str) -> Option<GlobalSlot> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn name(&self, slot: GlobalSlot) -> &String {
+        &self.names[slot]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl Default for GlobalNames {
+    fn default() -> GlobalNames {
+        GlobalNames::new()
+    }
+}
+
+//One namespace's globals by slot, None until defined
+#[derive(Debug, Clone, Default)]
+pub struct Globals {
+    values: Vec<Option<Value>>,
+}
+
+impl Globals {
+    pub fn new() -> Globals {
+        Globals::default()
+    }
+
+    #[inline]
+    pub fn get(&self, slot: GlobalSlot) -> Option<Value> {
+        self.values.get(slot).copied().flatten()
+    }
+
+    //Only defined globals can be assigned, so this is None for the rest
+    #[inline]
+    pub fn get_mut(&mut self, slot: GlobalSlot) -> Option<&mut Value> {
+        self.values.get_mut(slot).and_then(|value| value.as_mut())
+    }
+
+    pub fn define(&mut self, slot: GlobalSlot, value: Value) {
+        if slot >= self.values.len() {
+            self.values.resize(slot + 1, None);
+        }
+        self.values[slot] = Some(value);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (GlobalSlot, Value)> + '_ {
+        let slots = self.values.iter().enumerate();
+        slots.filter_map(|(slot, value)| Some((slot, (*value)?)))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = Value> + '_ {
+        self.values.iter().flatten().copied()
+    }
+}
+
+#[cfg(test)]
+mod globals_tests {
+    use crate::interpreter::VM;
+    use crate::test_support::run;
+    #[cfg(not(feature = "std"))]
+    use crate::prelude::*;
+
+    #[test]
+    fn slots_survive_between_runs() {
+        let mut vm = VM::new();
+        run(&mut vm, "var a = 1; var b = 2;").unwrap();
+        run(&mut vm, "var c = a + b; b = 10;").unwrap();
+        let globals: Vec<String> = vm
+            .globals()
+            .into_iter()
+            .map(|(name, value)| format!("{}={}", name, vm.inspect(value)))
+            .collect();
+        assert_eq!(globals, vec!["a=1", "b=10", "c=3"]);
+        assert_eq!(vm.virtual_memory().global_names.len(), 3);
+    }
+
+    #[test]
+    fn undefined_globals_are_reported_by_name() {
+        let mut vm = VM::new();
+        //Resolved to a slot at compile time, but never defined
+        let error = run(&mut vm, "fun f() { return later; }\nf();\nvar later = 1;");
+        assert_eq!(error.unwrap_err(), "1: Undefined variable later");
+        let error = run(&mut vm, "missing = 2;");
+        assert_eq!(error.unwrap_err(), "1: Undefined variable missing");
+    }
+}
@@ -0,0 +1,340 @@
+use super::collections::{Entry, Map};
+use super::interpreter::VirtualMemory;
+use super::symbols::Symbol;
+use super::value::{Object, Value};
+use alloc::collections::VecDeque;
+use core::fmt;
+use core::mem::size_of;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+const LARGEST_INSTANCES: usize = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeSummary {
+    pub type_name: &'static str,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceSummary {
+    pub address: u64,
+    pub class: String,
+    pub fields: usize,
+    pub bytes: usize,
+}
+
+// What is alive on the heap. Sizes are estimates: the object itself plus what
+// it owns directly, without allocator overhead.
+#[derive(Debug, Clone)]
+pub struct HeapReport {
+    pub live_objects: usize,
+    pub total_bytes: usize,
+    pub by_type: Vec<TypeSummary>,
+    pub largest_instances: Vec<InstanceSummary>,
+}
+
+impl fmt::Display for HeapReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} live objects, ~{} bytes",
+            self.live_objects, self.total_bytes
+        )?;
+        for summary in self.by_type.iter() {
+            writeln!(
+                f,
+                "  {:<14} {:>8} {:>10}",
+                summary.type_name, summary.count, summary.bytes
+            )?;
+        }
+        if !self.largest_instances.is_empty() {
+            writeln!(f, "Largest instances:")?;
+            for instance in self.largest_instances.iter() {
+                writeln!(
+                    f,
+                    "  @{} {} ({} fields) ~{} bytes",
+                    instance.address, instance.class, instance.fields, instance.bytes
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn type_name(object: &Object) -> &'static str {
+    match object {
+        Object::String(_) => "String",
+        Object::Function(_) => "Function",
+        Object::NativeFunction(_, _, _) => "NativeFunction",
+        Object::Closure(_) => "Closure",
+        Object::Value(_) => "Upvalue",
+        Object::OpenUpvalue(_, _) => "OpenUpvalue",
+        Object::Class(_) => "Class",
+        Object::Instance(_) => "Instance",
+        Object::BoundMethod(_) => "BoundMethod",
+        Object::Userdata(_) => "Userdata",
+        Object::List(_) => "List",
+        Object::Tuple(_) => "Tuple",
+        Object::Module(_) => "Module",
+        Object::Generator(_) => "Generator",
+    }
+}
+
+pub fn approximate_size(object: &Object) -> usize {
+    let owned = match object {
+        Object::String(s) => s.capacity(),
+        Object::Function(fun) => {
+            fun.name.capacity()
+                + fun.chunk.code.capacity() * size_of::<crate::chunk::OpCode>()
+                + fun.chunk.constants.capacity() * size_of::<Value>()
+                + fun.chunk.line_numbers.capacity() * size_of::<usize>()
+        }
+        Object::NativeFunction(name, _, _) => name.capacity(),
+        Object::Closure(closure) => closure.closed_values.capacity() * size_of::<u64>(),
+        Object::Class(class) => {
+            let methods = [&class.methods, &class.statics, &class.getters, &class.setters];
+            let entries: usize = methods.iter().map(|methods| methods.len()).sum();
+            entries * (size_of::<Symbol>() + size_of::<u64>())
+                + class.subclasses.capacity() * size_of::<u64>()
+        }
+        //Field names live in the shape, which instances share
+        Object::Instance(instance) => instance.fields.capacity() * size_of::<Value>(),
+        Object::List(items) | Object::Tuple(items) => items.capacity() * size_of::<Value>(),
+        Object::Module(module) => module.name.capacity(),
+        Object::Generator(generator) => {
+            generator.slots.capacity() * size_of::<Value>()
+                + generator.captured.capacity() * size_of::<(usize, u64)>()
+        }
+        Object::Value(_)
+        | Object::OpenUpvalue(_, _)
+        | Object::BoundMethod(_)
+        | Object::Userdata(_) => 0,
+    };
+    size_of::<Object>() + owned
+}
+
+// The objects this one keeps alive, labelled with how it holds them.
+pub fn references(heap: &VirtualMemory, object: &Object) -> Vec<(String, u64)> {
+    let symbols = &heap.symbols;
+    let mut references = vec![];
+    match object {
+        Object::Function(fun) => {
+            for (idx, value) in fun.chunk.constants.iter().enumerate() {
+                if let Value::Object(ptr) = value {
+                    references.push((format!("constant {}", idx), *ptr));
+                }
+            }
+        }
+        Object::Closure(closure) => {
+            references.push((String::from("function"), closure.function_pointer));
+            for (idx, ptr) in closure.closed_values.iter().enumerate() {
+                references.push((format!("upvalue {}", idx), *ptr));
+            }
+        }
+        Object::Value(Value::Object(ptr)) => references.push((String::from("value"), *ptr)),
+        Object::Class(class) => {
+            for (name, ptr) in class.methods.iter() {
+                references.push((format!("method {}", symbols.name(*name)), *ptr));
+            }
+            for (name, ptr) in class.statics.iter() {
+                references.push((format!("static method {}", symbols.name(*name)), *ptr));
+            }
+            for (name, ptr) in class.getters.iter() {
+                references.push((format!("getter {}", symbols.name(*name)), *ptr));
+            }
+            for (name, ptr) in class.setters.iter() {
+                references.push((format!("setter {}", symbols.name(*name)), *ptr));
+            }
+            for ptr in class.subclasses.iter() {
+                references.push((String::from("subclass"), *ptr));
+            }
+        }
+        Object::Instance(instance) => {
+            references.push((String::from("class"), instance.class_ptr));
+            for (name, value) in heap.instance_fields(instance) {
+                if let Value::Object(ptr) = value {
+                    references.push((format!("field {}", name), *ptr));
+                }
+            }
+        }
+        Object::BoundMethod(bound_method) => {
+            if let Value::Object(ptr) = bound_method.receiver {
+                references.push((String::from("receiver"), ptr));
+            }
+            references.push((String::from("method"), bound_method.closure_ptr));
+        }
+        Object::Generator(generator) => {
+            references.push((String::from("function"), generator.closure_ptr));
+            for (idx, value) in generator.slots.iter().enumerate() {
+                if let Value::Object(ptr) = value {
+                    references.push((format!("slot {}", idx), *ptr));
+                }
+            }
+            for (slot, ptr) in generator.captured.iter() {
+                references.push((format!("upvalue for slot {}", slot), *ptr));
+            }
+        }
+        Object::List(items) | Object::Tuple(items) => {
+            for (idx, value) in items.iter().enumerate() {
+                if let Value::Object(ptr) = value {
+                    references.push((format!("item {}", idx), *ptr));
+                }
+            }
+        }
+        _ => {}
+    }
+    //Map order isn't stable, keep paths reproducible
+    references.sort();
+    references
+}
+
+pub fn build_report(heap: &VirtualMemory) -> HeapReport {
+    let mut by_type: Map<&'static str, TypeSummary> = Map::new();
+    let mut instances = vec![];
+    let mut total_bytes = 0;
+
+    for (address, object) in heap.objects() {
+        let bytes = approximate_size(object);
+        total_bytes += bytes;
+
+        let name = type_name(object);
+        let summary = by_type.entry(name).or_insert(TypeSummary {
+            type_name: name,
+            count: 0,
+            bytes: 0,
+        });
+        summary.count += 1;
+        summary.bytes += bytes;
+
+        if let Object::Instance(instance) = object {
+            let class = match heap.get(instance.class_ptr) {
+                Some(Object::Class(class)) => class.name.clone(),
+                _ => String::from("?"),
+            };
+            instances.push(InstanceSummary {
+                address,
+                class,
+                fields: instance.fields.len(),
+                bytes,
+            });
+        }
+    }
+
+    let mut by_type: Vec<TypeSummary> = by_type.into_values().collect();
+    by_type.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.type_name.cmp(b.type_name)));
+    instances.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.address.cmp(&b.address)));
+    instances.truncate(LARGEST_INSTANCES);
+
+    HeapReport {
+        live_objects: heap.live_objects(),
+        total_bytes,
+        by_type,
+        largest_instances: instances,
+    }
+}
+
+// Shortest chain of references from a root to the target, one step per line,
+// or None if nothing reachable holds on to it.
+pub fn retention_path(
+    heap: &VirtualMemory,
+    roots: &[(String, Value)],
+    target: u64,
+) -> Option<Vec<String>> {
+    let describe = |label: &str, ptr: u64| match heap.get(ptr) {
+        Some(object) => format!("{} -> @{} {}", label, ptr, type_name(object)),
+        None => format!("{} -> @{}", label, ptr),
+    };
+
+    //Breadth first so we find the shortest path, remembering how we got to each object.
+    let mut came_from: Map<u64, (Option<u64>, String)> = Map::new();
+    let mut queue = VecDeque::new();
+    for (label, value) in roots.iter() {
+        if let Value::Object(ptr) = value {
+            if !came_from.contains_key(ptr) {
+                came_from.insert(*ptr, (None, label.clone()));
+                queue.push_back(*ptr);
+            }
+        }
+    }
+
+    while let Some(ptr) = queue.pop_front() {
+        if ptr == target {
+            let mut path = vec![];
+            let mut current = Some(ptr);
+            while let Some(ptr) = current {
+                let (parent, label) = &came_from[&ptr];
+                path.push(describe(label, ptr));
+                current = *parent;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        if let Some(object) = heap.get(ptr) {
+            for (label, child) in references(heap, object) {
+                if let Entry::Vacant(entry) = came_from.entry(child) {
+                    entry.insert((Some(ptr), label));
+                    queue.push_back(child);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(all(test, feature = "std"))]
+mod heap_report_tests {
+    use crate::interpreter::VM;
+    use crate::test_support;
+    use std::io;
+
+    fn run(source: &str) -> VM {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        assert!(test_support::run(&mut vm, source).is_ok());
+        vm
+    }
+
+    #[test]
+    fn summarizes_instances() {
+        let vm = run("class Point {}\nvar p = Point();\np.x = 1;\np.y = 2;\nvar q = Point();");
+        let report = vm.heap_report();
+        let instances = report
+            .by_type
+            .iter()
+            .find(|s| s.type_name == "Instance")
+            .unwrap();
+        assert_eq!(instances.count, 2);
+        assert_eq!(report.largest_instances[0].class, "Point");
+        assert_eq!(report.largest_instances[0].fields, 2);
+    }
+
+    #[test]
+    fn finds_retention_path() {
+        let vm = run("fun make() {\n  var kept = \"secret\";\n  fun get() { return kept; }\n  return get;\n}\nvar getter = make();");
+        let getter = vm
+            .globals()
+            .into_iter()
+            .find(|(name, _)| name.as_str() == "getter")
+            .map(|(_, value)| value)
+            .unwrap();
+        let closure = match getter {
+            crate::value::Value::Object(ptr) => ptr,
+            _ => panic!("Expected a closure"),
+        };
+        let closure = vm.heap_object(closure).unwrap();
+        let upvalue = crate::heap_report::references(vm.virtual_memory(), closure)
+            .into_iter()
+            .find(|(label, _)| label == "upvalue 0")
+            .unwrap()
+            .1;
+
+        let path = vm.retention_path(upvalue).unwrap();
+        assert_eq!(path.len(), 2);
+        assert!(path[0].starts_with("global getter -> "));
+        assert!(path[1].starts_with("upvalue 0 -> "));
+    }
+}
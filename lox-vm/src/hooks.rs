@@ -0,0 +1,102 @@
+use super::interpreter::PoolStats;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+pub type CallHook = Box<dyn FnMut(&CallEvent)>;
+//Gets the ip of each instruction before it runs. An error stops the VM with a
+//LimitError, which is how a watchdog gives up on a script.
+pub type InstructionHook = Box<dyn FnMut(usize) -> Result<(), String>>;
+pub type GcHook = Box<dyn FnMut(&GcEvent)>;
+
+// Callbacks for embedders that want to watch the VM run, to build profilers,
+// tracers or watchdogs. Unset hooks cost one check of VM::hooks per event.
+#[derive(Default)]
+pub struct Hooks {
+    pub on_call: Option<CallHook>,
+    pub on_return: Option<CallHook>,
+    pub on_instruction: Option<InstructionHook>,
+    pub on_gc: Option<GcHook>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallEvent<'a> {
+    pub name: &'a str,
+    //Frames below this one, so the script's own calls are at depth 1
+    pub depth: usize,
+    //Of the call for on_call and of the return for on_return
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcEvent {
+    pub live_before: usize,
+    pub freed: usize,
+    //0 without std, which has no clock
+    pub pause_us: u64,
+    pub pools: PoolStats,
+}
+
+#[cfg(test)]
+mod hooks_tests {
+    use super::*;
+    use crate::interpreter::VM;
+    use crate::test_support::run;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    #[test]
+    fn calls_and_returns_are_paired() {
+        let events = Rc::new(RefCell::new(vec![]));
+        let (calls, returns) = (events.clone(), events.clone());
+        let mut vm = VM::new();
+        vm.set_hooks(Hooks {
+            on_call: Some(Box::new(move |e| {
+                calls.borrow_mut().push(format!("call {} {} {}", e.name, e.depth, e.line))
+            })),
+            on_return: Some(Box::new(move |e| {
+                returns.borrow_mut().push(format!("return {} {} {}", e.name, e.depth, e.line))
+            })),
+            ..Hooks::default()
+        });
+        run(&mut vm, "fun g() {\n  return 1;\n}\nfun f() {\n  return g();\n}\nf();").unwrap();
+        assert_eq!(
+            *events.borrow(),
+            vec!["call f 1 7", "call g 2 5", "return g 2 2", "return f 1 5"]
+        );
+    }
+
+    #[test]
+    fn instruction_hook_can_stop_the_vm() {
+        let mut count = 0;
+        let mut vm = VM::new();
+        vm.set_hooks(Hooks {
+            on_instruction: Some(Box::new(move |_| {
+                count += 1;
+                if count > 100 {
+                    Err(String::from("Watchdog"))
+                } else {
+                    Ok(())
+                }
+            })),
+            ..Hooks::default()
+        });
+        let error = run(&mut vm, "while (true) {}").unwrap_err();
+        assert_eq!(error, "1: Watchdog");
+    }
+
+    #[test]
+    fn gc_hook_sees_collections() {
+        let freed = Rc::new(RefCell::new(0));
+        let seen = freed.clone();
+        let mut vm = VM::new();
+        let mut heap = vm.take_virtual_memory();
+        heap.set_gc_tuning(4096, 2.0);
+        vm.give_virtual_memory(heap);
+        vm.set_hooks(Hooks {
+            on_gc: Some(Box::new(move |e| *seen.borrow_mut() += e.freed)),
+            ..Hooks::default()
+        });
+        run(&mut vm, "class A {} for (var i = 0; i < 2000; i = i + 1) { A(); }").unwrap();
+        assert!(*freed.borrow() > 0);
+    }
+}
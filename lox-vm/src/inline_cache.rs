@@ -0,0 +1,180 @@
+use super::collections::Map;
+use super::shape::ShapeId;
+
+// What Call, Invoke and property instructions resolved the last time each site
+// ran, so the next run can skip the heap and name lookups when it sees the same
+// callee, receiver class or instance shape again. A site is the closure running
+// the instruction and its ip.
+//
+// Entries hold heap addresses, so everything is dropped whenever a class gains
+// methods or the GC runs and may hand a swept address to a new object.
+#[derive(Debug, Clone, Default)]
+pub struct InlineCaches {
+    entries: Map<(u64, usize), InlineCache>,
+    pub hits: u64,
+    pub misses: u64,
+    //Field entries don't know the class, so once any class has a setter
+    //writes check theirs before using one
+    pub setters: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InlineCache {
+    //A Call to this closure
+    Closure { closure_ptr: u64, arity: usize },
+    //An Invoke on an instance of class, or a super call to class, which found method
+    Method { class_ptr: u64, method_ptr: u64, arity: usize },
+    //An Invoke on class itself, which found the static method
+    StaticMethod { class_ptr: u64, method_ptr: u64, arity: usize },
+    //An Invoke on a list or a string, which found the native at index in the
+    //receiver's method table
+    NativeMethod { on_list: bool, index: usize },
+    //A method read off an instance of class, whose shape has no field hiding it
+    BoundMethod { shape: ShapeId, class_ptr: u64, closure_ptr: u64 },
+    //A field read or write on an instance with shape
+    Field { shape: ShapeId, slot: usize },
+    //A write that added a field, moving the instance from one shape to the next
+    Transition { from: ShapeId, to: ShapeId },
+}
+
+impl InlineCaches {
+    pub fn new() -> InlineCaches {
+        InlineCaches::default()
+    }
+
+    //The VM counts hits, since an entry for another callee is a miss
+    #[inline]
+    pub fn get(&self, closure_ptr: u64, ip: usize) -> Option<InlineCache> {
+        self.entries.get(&(closure_ptr, ip)).copied()
+    }
+
+    pub fn insert(&mut self, closure_ptr: u64, ip: usize, entry: InlineCache) {
+        self.entries.insert((closure_ptr, ip), entry);
+    }
+
+    pub fn invalidate(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod inline_cache_tests {
+    use crate::interpreter::VM;
+    use crate::test_support::{global, run};
+    #[cfg(not(feature = "std"))]
+    use crate::prelude::*;
+
+    #[test]
+    fn reuses_resolved_methods_in_loops() {
+        let mut vm = VM::new();
+        let source = "class Counter { init() { this.n = 0; } bump() { this.n = this.n + 1; } }\n\
+                      var c = Counter();\n\
+                      for (var i = 0; i < 100; i = i + 1) { c.bump(); }\n\
+                      var total = c.n;";
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&vm, "total"), "100");
+        assert!(vm.inline_caches().hits >= 99);
+    }
+
+    #[test]
+    fn forgets_static_methods_of_a_reopened_class() {
+        let mut vm = VM::new();
+        let source = "class A { static f() { return 1; } }\n\
+                      fun call() { return A.f(); }\n\
+                      var before = call() + call();\n\
+                      class A { static f() { return 2; } }\n\
+                      var after = call();";
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&vm, "before"), "2");
+        assert_eq!(global(&vm, "after"), "2");
+    }
+
+    #[test]
+    fn misses_when_the_receiver_class_changes() {
+        let mut vm = VM::new();
+        let source = "class A { init() {} name() { return \"a\"; } }\n\
+                      class B { init() {} name() { return \"b\"; } }\n\
+                      fun call(o) { return o.name(); }\n\
+                      var out = \"\";\n\
+                      for (var i = 0; i < 4; i = i + 1) { out = out + call(A()) + call(B()); }";
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&vm, "out"), "\"abababab\"");
+    }
+
+    #[test]
+    fn reads_fields_across_shapes() {
+        let mut vm = VM::new();
+        let source = "class P { init(first) { if (first) { this.x = 1; this.y = 2; } \
+                      else { this.y = 20; this.x = 10; } } }\n\
+                      fun sum(p) { return p.x + p.y; }\n\
+                      var total = 0;\n\
+                      for (var i = 0; i < 4; i = i + 1) { total = total + sum(P(true)) + sum(P(false)); }";
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&vm, "total"), "132");
+        //Empty, {x}, {x, y}, {y} and {y, x}
+        assert_eq!(vm.shapes().len(), 5);
+    }
+
+    #[test]
+    fn caches_methods_read_as_values() {
+        let mut vm = VM::new();
+        let source = "class A { init() {} m() { return 1; } }\n\
+                      fun read(o) { return o.m; }\n\
+                      var a = A();\n\
+                      var total = 0;\n\
+                      for (var i = 0; i < 50; i = i + 1) { total = total + read(a)(); }\n\
+                      var b = A();\n\
+                      b.m = 5;\n\
+                      var shadowed = read(b);";
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&vm, "total"), "50");
+        assert_eq!(global(&vm, "shadowed"), "5");
+        //Both the call to read and its read of m
+        assert!(vm.inline_caches().hits >= 98);
+    }
+
+    #[test]
+    fn invokes_without_allocating() {
+        let allocations = |n: usize| {
+            let mut vm = VM::new();
+            vm.enable_metrics();
+            let source = format!(
+                "class A {{ init() {{}} m() {{ return 1; }} static s() {{ return 2; }} }}\n\
+                 class B < A {{ init() {{}} m() {{ return super.m() + 1; }} }}\n\
+                 var b = B();\n\
+                 var list = [1, 2];\n\
+                 var total = 0;\n\
+                 for (var i = 0; i < {}; i = i + 1) {{\n\
+                 total = total + b.m() + A.s() + list.len(); }}",
+                n
+            );
+            run(&mut vm, &source).unwrap();
+            assert_eq!(global(&vm, "total"), (n * 6).to_string());
+            //b.m, super.m, A.s and list.len all hit after the first time round
+            assert!(vm.inline_caches().hits as usize >= 4 * (n - 1));
+            vm.metrics().unwrap().allocations.values().sum::<u64>()
+        };
+        assert_eq!(allocations(10), allocations(500));
+    }
+
+    #[test]
+    fn follows_redefined_classes() {
+        let mut vm = VM::new();
+        let source = "class A { init() {} m() { return 1; } }\n\
+                      fun f() { return A().m(); }\n\
+                      var first = f();\n\
+                      class A { init() {} m() { return 2; } }\n\
+                      var second = f();";
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&vm, "first"), "1");
+        assert_eq!(global(&vm, "second"), "2");
+    }
+}
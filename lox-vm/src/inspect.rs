@@ -0,0 +1,140 @@
+use super::collections::Map;
+use super::interpreter::VirtualMemory;
+use super::symbols::Symbol;
+use super::value::{Object, Value};
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+const MAX_DEPTH: usize = 3;
+
+// Renders values for interactive display. Unlike the Display impls, this
+// follows pointers so instances show their class and fields, and functions
+// show their signature.
+pub fn inspect(heap: &VirtualMemory, value: Value) -> String {
+    let mut inspector = Inspector {
+        heap,
+        visiting: vec![],
+    };
+    inspector.value(value, 0)
+}
+
+struct Inspector<'a> {
+    heap: &'a VirtualMemory,
+    //Instances and lists on the path from the root, used to cut cycles.
+    visiting: Vec<u64>,
+}
+
+impl<'a> Inspector<'a> {
+    fn value(&mut self, value: Value, depth: usize) -> String {
+        match value {
+            Value::Number(n) => format!("{}", n),
+            Value::Integer(n) => format!("{}", n),
+            Value::Boolean(b) => format!("{}", b),
+            Value::Nil => String::from("nil"),
+            Value::Object(ptr) => self.object(ptr, depth),
+        }
+    }
+
+    fn function_signature(&self, function_ptr: u64) -> String {
+        let function = self.heap.deref(function_ptr).as_function();
+        format!("<fn {}/{}>", function.name, function.arity)
+    }
+
+    fn object(&mut self, ptr: u64, depth: usize) -> String {
+        match self.heap.deref(ptr) {
+            Object::String(s) => format!("{:?}", s),
+            Object::Function(_) => self.function_signature(ptr),
+            Object::Closure(closure) => self.function_signature(closure.function_pointer),
+            Object::NativeFunction(name, _, _) => format!("<native fn {}>", name),
+            Object::Value(value) => self.value(*value, depth),
+            Object::OpenUpvalue(_, _) => String::from("<upvalue>"),
+            Object::Userdata(userdata) => userdata.to_string(),
+            Object::Module(module) => format!("<module {}>", module.name),
+            Object::Generator(generator) => {
+                let closure = self.heap.deref(generator.closure_ptr).as_closure();
+                let name = &self.heap.deref(closure.function_pointer).as_function().name;
+                format!("<generator {}>", name)
+            }
+            Object::Class(class) => {
+                let symbols = &self.heap.symbols;
+                let labelled = |methods: &'a Map<Symbol, u64>, label: &'static str| {
+                    methods.iter().map(move |(name, ptr)| {
+                        (format!("{}{}", label, symbols.name(*name)), ptr)
+                    })
+                };
+                let mut methods: Vec<String> = labelled(&class.methods, "")
+                    .chain(labelled(&class.getters, "get "))
+                    .chain(labelled(&class.setters, "set "))
+                    .chain(labelled(&class.statics, "static "))
+                    .map(|(name, closure_ptr)| {
+                        let function_ptr = self.heap.deref(*closure_ptr).as_closure().function_pointer;
+                        let arity = self.heap.deref(function_ptr).as_function().arity;
+                        format!("{}/{}", name, arity)
+                    })
+                    .collect();
+                methods.sort();
+                if methods.is_empty() {
+                    format!("<class {}>", class.name)
+                } else {
+                    format!("<class {} {{ {} }}>", class.name, methods.join(", "))
+                }
+            }
+            Object::BoundMethod(bound_method) => {
+                let function_ptr = self
+                    .heap
+                    .deref(bound_method.closure_ptr)
+                    .as_closure()
+                    .function_pointer;
+                let function = self.heap.deref(function_ptr).as_function();
+                format!("<bound method {}/{}>", function.name, function.arity)
+            }
+            Object::List(items) | Object::Tuple(items) => {
+                let (open, close) = match self.heap.deref(ptr) {
+                    Object::Tuple(_) => ("(", ")"),
+                    _ => ("[", "]"),
+                };
+                if self.visiting.contains(&ptr) {
+                    return format!("{}<cycle>{}", open, close);
+                }
+                if items.is_empty() {
+                    return format!("{}{}", open, close);
+                }
+                if depth >= MAX_DEPTH {
+                    return format!("{}...{}", open, close);
+                }
+
+                self.visiting.push(ptr);
+                let items: Vec<String> =
+                    items.iter().map(|item| self.value(*item, depth + 1)).collect();
+                self.visiting.pop();
+
+                format!("{}{}{}", open, items.join(", "), close)
+            }
+            Object::Instance(instance) => {
+                let class_name = &self.heap.deref(instance.class_ptr).as_class().name;
+                if self.visiting.contains(&ptr) {
+                    return format!("{} {{<cycle>}}", class_name);
+                }
+                if instance.fields.is_empty() {
+                    return format!("{} {{}}", class_name);
+                }
+                if depth >= MAX_DEPTH {
+                    return format!("{} {{...}}", class_name);
+                }
+
+                let mut fields: Vec<(&str, &Value)> =
+                    self.heap.instance_fields(instance).collect();
+                fields.sort_by(|a, b| a.0.cmp(b.0));
+
+                self.visiting.push(ptr);
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}", name, self.value(**value, depth + 1)))
+                    .collect();
+                self.visiting.pop();
+
+                format!("{} {{ {} }}", class_name, fields.join(", "))
+            }
+        }
+    }
+}
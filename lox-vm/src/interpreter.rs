@@ -1,15 +1,61 @@
 use super::chunk::*;
+use super::collections::{Map, Set};
+use super::compiler::Compiler;
+use super::crash::CrashReport;
+#[cfg(feature = "std")]
+use super::config::VmOptions;
+use super::debug::{disassemble_instruction, format_value};
+use super::debugger::{Debugger, FrameInfo, Resume, Status};
+use super::globals::{GlobalNames, Globals};
+use super::heap_report::{self, HeapReport};
+use super::hooks::{CallEvent, GcEvent, Hooks};
+use super::inline_cache::{InlineCache, InlineCaches};
+use super::inspect::inspect;
+use super::scanner::scan_tokens;
+use super::tiering::Tiering;
+use super::shape::{Shapes, EMPTY_SHAPE};
+use super::symbols::{Symbol, Symbols, INIT};
+use super::metrics::Metrics;
+use super::profile::Profile;
+#[cfg(feature = "std")]
+use super::modules;
+use super::natives;
+use super::natives::{Group, Native};
+use super::rng::Rng;
 use super::value::{
-    BoundMethod, Class, Closure, FromValue, Function, Instance, Object, ToValue, Value,
+    BoundMethod, Class, Closure, FromValue, Function, Generator, GeneratorState, Instance, NativeFn,
+    Object, Table, ToValue, Userdata, Value, GENERATOR_METHODS, VARIADIC,
 };
-use std::collections::HashMap;
-use std::fmt;
-use std::mem::swap;
+#[cfg(feature = "std")]
+use super::value::Module;
+#[cfg(feature = "std")]
+use super::workers::WorkerGroup;
+use alloc::collections::VecDeque;
+use core::fmt;
+#[cfg(not(feature = "std"))]
+use core::fmt::Write;
+use core::mem::swap;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, Write};
+#[cfg(feature = "std")]
+use std::path::PathBuf;
+#[cfg(feature = "std")]
+use std::sync::Arc;
+#[cfg(feature = "std")]
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "tracing")]
+use tracing::{debug, debug_span, trace};
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
 
 pub enum InterpreterError {
     TypeError(usize, String),
     NameError(usize, String),
     FunctionError(usize, String),
+    LimitError(usize, String),
+    Thrown(usize, String), //A value thrown with 'throw' that nothing caught
+    ImportError(usize, String),
+    Exit(usize, i32), //exit() was called with this status
 }
 
 impl fmt::Display for InterpreterError {
@@ -17,393 +63,1473 @@ impl fmt::Display for InterpreterError {
         match self {
             InterpreterError::TypeError(line, msg)
             | InterpreterError::NameError(line, msg)
-            | InterpreterError::FunctionError(line, msg) => write!(f, "{}: {}", line, msg),
+            | InterpreterError::FunctionError(line, msg)
+            | InterpreterError::LimitError(line, msg)
+            | InterpreterError::Thrown(line, msg)
+            | InterpreterError::ImportError(line, msg) => write!(f, "{}: {}", line, msg),
+            InterpreterError::Exit(line, status) => write!(f, "{}: exit({})", line, status),
         }
     }
 }
 
+impl InterpreterError {
+    pub fn line(&self) -> usize {
+        match self {
+            InterpreterError::TypeError(line, _)
+            | InterpreterError::NameError(line, _)
+            | InterpreterError::FunctionError(line, _)
+            | InterpreterError::LimitError(line, _)
+            | InterpreterError::Thrown(line, _)
+            | InterpreterError::ImportError(line, _)
+            | InterpreterError::Exit(line, _) => *line,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            InterpreterError::TypeError(_, msg)
+            | InterpreterError::NameError(_, msg)
+            | InterpreterError::FunctionError(_, msg)
+            | InterpreterError::LimitError(_, msg)
+            | InterpreterError::Thrown(_, msg)
+            | InterpreterError::ImportError(_, msg) => msg,
+            InterpreterError::Exit(_, _) => "Exited",
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            InterpreterError::TypeError(_, _) => "type-error",
+            InterpreterError::NameError(_, _) => "name-error",
+            InterpreterError::FunctionError(_, _) => "function-error",
+            InterpreterError::LimitError(_, _) => "limit-error",
+            InterpreterError::Thrown(_, _) => "uncaught-exception",
+            InterpreterError::ImportError(_, _) => "import-error",
+            InterpreterError::Exit(_, _) => "exit",
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for InterpreterError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut error = serializer.serialize_struct("InterpreterError", 3)?;
+        error.serialize_field("code", self.code())?;
+        error.serialize_field("message", self.message())?;
+        error.serialize_field("line", &self.line())?;
+        error.end()
+    }
+}
+
+//How many instructions a crash report shows
+const RECENT_INSTRUCTIONS: usize = 32;
+
 pub enum GCMark {
     Started,
     Complete,
 }
 
-#[derive(Clone, Copy)]
+//A collection's progress through the heap, kept between steps when gc_step isn't 0
+struct Marking {
+    marks: Map<u64, GCMark>,
+    worklist: Vec<u64>,
+    live_before: usize,
+}
+
+impl Marking {
+    fn new(live_before: usize) -> Marking {
+        Marking {
+            marks: Map::new(),
+            worklist: vec![],
+            live_before,
+        }
+    }
+
+    //Queues ptr to have its references marked, unless it's already marked
+    fn gray(&mut self, ptr: u64) {
+        if !self.marks.contains_key(&ptr) {
+            self.marks.insert(ptr, GCMark::Started);
+            self.worklist.push(ptr);
+        }
+    }
+
+    //Queues ptr even if its references were marked, since they may have changed
+    fn regray(&mut self, ptr: u64) {
+        self.marks.insert(ptr, GCMark::Started);
+        self.worklist.push(ptr);
+    }
+}
+
+#[derive(Clone, Copy, Default)]
 pub struct CallFrame {
     closure_pointer: u64,
     ip: usize,
     stack_pointer: usize,
+    namespace: usize,
+}
+
+// The running closure's code, constants and lines, so instructions don't go
+// closure -> function -> chunk through the heap each time. The heap moving
+// its objects around doesn't move these buffers, only rewriting the chunk
+// does, so they're fetched again on every call and return and after a tier-up.
+#[derive(Clone, Copy)]
+struct ActiveChunk {
+    closure_pointer: u64,
+    code: *const [OpCode],
+    constants: *const [Value],
+    lines: *const [usize],
+}
+
+impl ActiveChunk {
+    fn new(closure_pointer: u64, chunk: &Chunk) -> ActiveChunk {
+        ActiveChunk {
+            closure_pointer,
+            code: chunk.code.as_slice(),
+            constants: chunk.constants.as_slice(),
+            lines: chunk.line_numbers.as_slice(),
+        }
+    }
+
+    //For the frame's closure, if it's the running one
+    #[inline]
+    fn of(&self, frame: &CallFrame) -> Option<&ActiveChunk> {
+        (self.closure_pointer == frame.closure_pointer).then_some(self)
+    }
+}
+
+impl Default for ActiveChunk {
+    fn default() -> ActiveChunk {
+        ActiveChunk {
+            //Not an address, so no frame's closure matches it
+            closure_pointer: u64::MAX,
+            code: &[],
+            constants: &[],
+            lines: &[],
+        }
+    }
+}
+
+//Where a try block sends errors raised inside it
+struct Handler {
+    //How many frames were suspended below the one running the try
+    frames: usize,
+    //That frame, pointing at the catch code
+    frame: CallFrame,
+    stack_len: usize,
+}
+
+//The globals of an imported module
+struct Namespace {
+    #[cfg(feature = "std")]
+    path: String,
+    globals: Globals,
 }
 
+//Field maps kept for reuse, past this they're dropped
+const MAX_POOLED_FIELDS: usize = 1024;
+
+// How often allocations were served by recycling what the GC freed, counted
+// since the heap was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PoolStats {
+    //Addresses of swept objects, reused before looking for a new one
+    pub slot_hits: u64,
+    pub slot_misses: u64,
+    //Field maps of swept instances, reused for new instances
+    pub fields_hits: u64,
+    pub fields_misses: u64,
+}
+
+impl PoolStats {
+    pub fn slot_hit_rate(&self) -> f64 {
+        hit_rate(self.slot_hits, self.slot_misses)
+    }
+
+    pub fn fields_hit_rate(&self) -> f64 {
+        hit_rate(self.fields_hits, self.fields_misses)
+    }
+}
+
+fn hit_rate(hits: u64, misses: u64) -> f64 {
+    if hits + misses == 0 {
+        0.0
+    } else {
+        hits as f64 / (hits + misses) as f64
+    }
+}
+
+// Objects live in an arena indexed by their address, so a pointer is an
+// index and allocating reuses a free slot or pushes a new one.
 pub struct VirtualMemory {
-    pub heap: HashMap<u64, Object>,
-    pub next_addr: u64,
+    heap: Vec<Option<Object>>,
+    live: usize,
     pub allocations: u64,
-    pub max_allocations: u64,
+    //Estimated bytes allocated, recounted from what survives each collection
+    pub bytes_allocated: usize,
+    //A collection starts once bytes_allocated passes this
+    pub next_gc: usize,
+    //next_gc starts here and never drops below it
+    pub gc_threshold: usize,
+    //After a collection next_gc is set to the surviving bytes times this
+    pub gc_growth: f64,
+    //Swept slots, which instances, bound methods and upvalues churn through in OO code
+    free_addrs: Vec<u64>,
+    free_fields: Vec<Vec<Value>>,
+    pub pool_stats: PoolStats,
+    pub shapes: Shapes,
+    pub symbols: Symbols,
+    pub global_names: GlobalNames,
+    //Addresses of live strings by hash, so equal strings share one. Sweeping a
+    //string drops it from here, so being interned doesn't keep it alive.
+    strings: Map<u64, Vec<u64>>,
+    //While an incremental collection is marking, the objects allocated or
+    //borrowed mutably since its last step, which it has to look at again
+    dirty: Option<Vec<u64>>,
+}
+
+pub const DEFAULT_GC_THRESHOLD: usize = 1024 * 1024;
+pub const DEFAULT_GC_GROWTH: f64 = 2.0;
+//Values on the stack before a call overflows it
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+//Room past the stack size for the locals and temporaries of the frame on top
+const FRAME_HEADROOM: usize = 256;
+
+//FNV-1a, like clox's hashString
+fn hash_string(s: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in s.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
 }
 
 impl VirtualMemory {
     pub fn new() -> VirtualMemory {
-        let max_allocations = if cfg!(test_gc) { 5 } else { 500 };
+        //Stress builds collect after every allocation
+        let (gc_threshold, gc_growth) = if cfg!(test_gc) {
+            (0, 1.0)
+        } else {
+            (DEFAULT_GC_THRESHOLD, DEFAULT_GC_GROWTH)
+        };
         VirtualMemory {
-            heap: HashMap::new(),
-            next_addr: 0,
+            heap: vec![],
+            live: 0,
             allocations: 0,
-            max_allocations,
+            bytes_allocated: 0,
+            next_gc: gc_threshold,
+            gc_threshold,
+            gc_growth,
+            free_addrs: vec![],
+            free_fields: vec![],
+            pool_stats: PoolStats::default(),
+            shapes: Shapes::new(),
+            symbols: Symbols::new(),
+            global_names: GlobalNames::new(),
+            strings: Map::new(),
+            dirty: None,
         }
     }
 
     #[inline]
-    pub fn next_addr_inner(&mut self) -> Option<u64> {
-        while self.next_addr < u64::MAX {
-            if !self.heap.contains_key(&self.next_addr) {
-                let addr = self.next_addr;
-                self.next_addr += 1;
-                return Some(addr);
+    fn touch(&mut self, ptr: u64) {
+        if let Some(dirty) = self.dirty.as_mut() {
+            if dirty.last() != Some(&ptr) {
+                dirty.push(ptr);
             }
-            self.next_addr += 1;
         }
-
-        None
     }
 
-    pub fn next_addr(&mut self) -> u64 {
-        if let Some(addr) = self.next_addr_inner() {
-            addr
-        } else {
-            self.next_addr = 0;
-            if let Some(addr) = self.next_addr_inner() {
+    //Strings equal to one already on the heap come back as its address
+    #[inline]
+    pub fn add_to_heap(&mut self, object: Object) -> u64 {
+        let hash = match &object {
+            Object::String(s) => {
+                let hash = hash_string(s);
+                if let Some(addr) = self.interned(hash, s) {
+                    return addr;
+                }
+                Some(hash)
+            }
+            _ => None,
+        };
+        self.allocations += 1;
+        self.bytes_allocated += heap_report::approximate_size(&object);
+        self.live += 1;
+        let new_address = match self.free_addrs.pop() {
+            Some(addr) => {
+                self.pool_stats.slot_hits += 1;
+                self.heap[addr as usize] = Some(object);
                 addr
-            } else {
-                panic!("Out of memory!");
             }
+            None => {
+                self.pool_stats.slot_misses += 1;
+                self.heap.push(Some(object));
+                (self.heap.len() - 1) as u64
+            }
+        };
+        self.touch(new_address);
+        if let Some(hash) = hash {
+            self.strings.entry(hash).or_default().push(new_address);
         }
+        new_address
     }
 
-    #[inline]
-    pub fn add_to_heap(&mut self, object: Object) -> u64 {
-        self.allocations += 1;
-        let new_address = self.next_addr();
-        self.heap.insert(new_address, object);
-        new_address
+    pub fn set_gc_tuning(&mut self, threshold: usize, growth: f64) {
+        self.gc_threshold = threshold;
+        self.gc_growth = growth;
+        self.next_gc = threshold;
+    }
+
+    //Counts the bytes of what survived a collection and moves the next one
+    //out in proportion, so a big live heap isn't traced over and over
+    fn recount(&mut self) {
+        self.bytes_allocated = self
+            .objects()
+            .map(|(_, object)| heap_report::approximate_size(object))
+            .sum();
+        let grown = (self.bytes_allocated as f64 * self.gc_growth) as usize;
+        self.next_gc = grown.max(self.gc_threshold);
+    }
+
+    fn interned(&self, hash: u64, s: &str) -> Option<u64> {
+        self.strings.get(&hash)?.iter().copied().find(|addr| match self.get(*addr) {
+            Some(Object::String(other)) => other == s,
+            _ => false,
+        })
     }
 
     #[inline]
     pub fn remove_from_heap(&mut self, addr: u64) {
-        self.heap.remove(&addr);
+        let slot = self.heap.get_mut(addr as usize).and_then(Option::take);
+        if let Some(object) = slot {
+            self.live -= 1;
+            match object {
+                Object::Instance(instance) if self.free_fields.len() < MAX_POOLED_FIELDS => {
+                    let mut fields = instance.fields;
+                    fields.clear();
+                    self.free_fields.push(fields);
+                }
+                Object::String(s) => {
+                    let hash = hash_string(&s);
+                    if let Some(addrs) = self.strings.get_mut(&hash) {
+                        addrs.retain(|interned| *interned != addr);
+                        if addrs.is_empty() {
+                            self.strings.remove(&hash);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            self.free_addrs.push(addr);
+        }
+    }
+
+    //Empty fields for a new instance, with capacity left from a swept one if we can
+    pub fn new_fields(&mut self) -> Vec<Value> {
+        match self.free_fields.pop() {
+            Some(fields) => {
+                self.pool_stats.fields_hits += 1;
+                fields
+            }
+            None => {
+                self.pool_stats.fields_misses += 1;
+                vec![]
+            }
+        }
+    }
+
+    #[inline]
+    pub fn field(&self, instance: &Instance, name: Symbol) -> Option<Value> {
+        let slot = self.shapes.slot(instance.shape, name)?;
+        Some(instance.fields[slot])
+    }
+
+    //In slot order, which is the order they were first assigned
+    pub fn instance_fields<'a>(
+        &'a self,
+        instance: &'a Instance,
+    ) -> impl Iterator<Item = (&'a str, &'a Value)> {
+        let names = self.shapes.names(instance.shape).iter();
+        names.map(move |name| self.symbols.name(*name)).zip(instance.fields.iter())
+    }
+
+    //None for addresses that were never handed out or have been swept
+    #[inline]
+    pub fn get(&self, ptr: u64) -> Option<&Object> {
+        self.heap.get(ptr as usize)?.as_ref()
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, ptr: u64) -> Option<&mut Object> {
+        self.touch(ptr);
+        self.heap.get_mut(ptr as usize)?.as_mut()
     }
 
     #[inline]
     pub fn deref(&self, ptr: u64) -> &Object {
-        &self.heap[&ptr]
+        self.get(ptr).expect("Dangling heap pointer")
     }
 
     #[inline]
     pub fn deref_mut(&mut self, ptr: u64) -> &mut Object {
-        self.heap.get_mut(&ptr).unwrap()
+        self.get_mut(ptr).expect("Dangling heap pointer")
+    }
+
+    pub fn live_objects(&self) -> usize {
+        self.live
+    }
+
+    //Live objects in address order
+    pub fn objects(&self) -> impl Iterator<Item = (u64, &Object)> {
+        let slots = self.heap.iter().enumerate();
+        slots.filter_map(|(addr, slot)| Some((addr as u64, slot.as_ref()?)))
     }
 
     #[inline]
     fn closure_deref(&self, closure_p: u64) -> &Closure {
-        self.heap[&closure_p].as_closure()
+        self.deref(closure_p).as_closure()
     }
 
     #[inline]
     fn fun_deref(&self, fun_p: u64) -> &Function {
-        self.heap[&fun_p].as_fun()
+        self.deref(fun_p).as_fun()
     }
 
     #[inline]
     fn class_deref(&self, class_p: u64) -> &Class {
-        self.heap[&class_p].as_class()
+        self.deref(class_p).as_class()
     }
 
     #[inline]
     fn value_deref(&self, value_ptr: u64) -> Value {
-        self.heap[&value_ptr].as_value()
+        self.deref(value_ptr).as_value()
     }
 
+    //Replaces a live object, like an open upvalue when it's closed
     #[inline]
     fn write(&mut self, addr: u64, object: Object) {
-        self.heap.insert(addr, object);
+        *self.deref_mut(addr) = object;
     }
 
     #[inline]
     fn function_deref(&self, fp: u64) -> &Function {
-        self.heap[&fp].as_function()
+        self.deref(fp).as_function()
     }
 
     #[inline]
     fn string_deref(&self, str_ptr: u64) -> &String {
-        self.heap[&str_ptr].as_string()
+        self.deref(str_ptr).as_string()
+    }
+}
+
+//Without std there's nowhere to print until an embedder calls set_output
+#[cfg(not(feature = "std"))]
+struct Discard;
+
+#[cfg(not(feature = "std"))]
+impl Write for Discard {
+    fn write_str(&mut self, _: &str) -> fmt::Result {
+        Ok(())
     }
 }
 
 pub struct VM {
+    //Allocated up front, so pushing never moves it mid-run
     stack: Vec<Value>,
+    stack_size: usize,
     virtual_memory: Option<VirtualMemory>,
-    globals: HashMap<String, Value>,
-    //Never holds the active frame
-    call_frames: Vec<CallFrame>,
+    globals: Globals,
+    //Namespace n is at n - 1, since 0 is the main program's globals
+    namespaces: Vec<Namespace>,
+    //Where the main program came from, imports are relative to it
+    #[cfg(feature = "std")]
+    script_path: Option<String>,
+    //Where imports are looked for when they aren't next to the importer
+    #[cfg(feature = "std")]
+    module_search_path: Vec<PathBuf>,
+    //Modules by canonical path, so each file only runs once
+    module_cache: Map<String, Value>,
+    //(path, frame depth) of the modules still running their top level
+    importing: Vec<(String, usize)>,
+    //Frame depths of the methods run to completion from inside an
+    //instruction, like toString for print. Returning from one ends its run.
+    reentry: Vec<usize>,
+    //Fixed size, with the active frame on top. The loop runs on a copy of it,
+    //so its ip is only written back when it calls out or pauses.
+    frames: Vec<CallFrame>,
+    //In use, so the active frame is at frame_count - 1
+    frame_count: usize,
+    active: ActiveChunk,
+    //Innermost last, across every frame
+    handlers: Vec<Handler>,
+    //What the last 'throw' threw, until it's caught
+    exception: Option<Value>,
     open_upvalues: Vec<(usize, usize, u64)>, //Nope, linear search.
+    //Print each instruction and the stack as it executes
+    pub trace: bool,
+    //Where 'print' writes to, an io::Write with std and a fmt::Write without
+    out: Box<dyn Write>,
+    //Where readLine reads from, stdin if None
+    #[cfg(feature = "std")]
+    input: Option<Box<dyn BufRead>>,
+    //Instructions left before execution is aborted, unlimited if None
+    pub fuel: Option<usize>,
+    max_call_depth: usize,
+    //Objects marked per instruction during a collection, all of them at once if 0
+    pub gc_step: usize,
+    marking: Option<Marking>,
+    debugger: Option<Debugger>,
+    //Only counted once enable_metrics is called
+    metrics: Option<Metrics>,
+    //Only counted once enable_profile is called
+    profile: Option<Profile>,
+    //(closure, ip) of the last instructions run, once record_recent_instructions is called
+    recent: Option<VecDeque<(u64, usize)>>,
+    //Boxed so the hot loop checks a single pointer when there are none
+    hooks: Option<Box<Hooks>>,
+    inline_caches: InlineCaches,
+    //Only once enable_tiering is called
+    tiering: Option<Tiering>,
+    //Native groups scripts may use, all if None
+    capabilities: Option<Vec<String>>,
+    //What clock() counts from
+    #[cfg(feature = "std")]
+    started: Instant,
+    //Behind random() and friends, seeded from the time unless randomSeed is called
+    rng: Rng,
+    //Set by exit() until its call turns it into InterpreterError::Exit
+    exiting: Option<i32>,
+    //The channels and workers scripts made, shared with the workers
+    #[cfg(feature = "std")]
+    worker_group: Option<Arc<WorkerGroup>>,
+    #[cfg(feature = "std")]
+    is_worker: bool,
+    //What setenv changed, None where it removed a variable. The process's own
+    //environment isn't safe to write while other threads may read it.
+    #[cfg(feature = "std")]
+    environment: Map<String, Option<String>>,
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            stack: vec![],
+            stack: Vec::with_capacity(DEFAULT_STACK_SIZE + FRAME_HEADROOM),
+            stack_size: DEFAULT_STACK_SIZE,
             virtual_memory: Some(VirtualMemory::new()),
-            globals: HashMap::new(),
-            call_frames: vec![],
+            globals: Globals::new(),
+            namespaces: vec![],
+            #[cfg(feature = "std")]
+            script_path: None,
+            #[cfg(feature = "std")]
+            module_search_path: vec![],
+            module_cache: Map::new(),
+            importing: vec![],
+            reentry: vec![],
+            frames: vec![CallFrame::default(); 256 + 2], //See set_max_call_depth
+            frame_count: 0,
+            active: ActiveChunk::default(),
+            handlers: vec![],
+            exception: None,
             open_upvalues: vec![],
+            trace: false,
+            #[cfg(feature = "std")]
+            out: Box::new(io::stdout()),
+            #[cfg(not(feature = "std"))]
+            out: Box::new(Discard),
+            #[cfg(feature = "std")]
+            input: None,
+            fuel: None,
+            max_call_depth: 256,
+            gc_step: 0,
+            marking: None,
+            debugger: None,
+            metrics: None,
+            profile: None,
+            recent: None,
+            hooks: None,
+            inline_caches: InlineCaches::new(),
+            tiering: None,
+            capabilities: None,
+            #[cfg(feature = "std")]
+            started: Instant::now(),
+            rng: Rng::new(VM::time_seed()),
+            exiting: None,
+            #[cfg(feature = "std")]
+            worker_group: None,
+            #[cfg(feature = "std")]
+            is_worker: false,
+            #[cfg(feature = "std")]
+            environment: Map::new(),
         }
     }
 
-    pub fn take_virtual_memory(&mut self) -> VirtualMemory {
-        let mut spare = None;
-        swap(&mut spare, &mut self.virtual_memory);
-        spare.unwrap()
+    #[cfg(feature = "std")]
+    fn time_seed() -> u64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_nanos() as u64,
+            Err(_) => 0,
+        }
     }
 
-    pub fn give_virtual_memory(&mut self, virtual_memory: VirtualMemory) {
-        self.virtual_memory = Some(virtual_memory);
+    //Nothing to seed from without std, so every run gets the same numbers
+    #[cfg(not(feature = "std"))]
+    fn time_seed() -> u64 {
+        0
     }
 
-    pub fn interpret(
-        &mut self,
-        main: Function,
-        virtual_memory: VirtualMemory,
-    ) -> Result<(), InterpreterError> {
-        self.virtual_memory = Some(virtual_memory);
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
 
-        let fp = self.add_to_heap(Object::Function(main));
-        let closure_p = self.add_to_heap(Object::Closure(Closure {
-            function_pointer: fp,
-            closed_values: vec![],
-        }));
-        self.call_frames.push(CallFrame {
-            closure_pointer: closure_p,
-            ip: 0,
-            stack_pointer: 0,
-        }); //Will be immediately popped when run is called.
-        self.run()
+    //Made the first time a script uses a channel or worker
+    #[cfg(feature = "std")]
+    pub fn worker_group(&mut self) -> Arc<WorkerGroup> {
+        self.worker_group.get_or_insert_with(Arc::default).clone()
     }
 
-    fn mark_object_started(gc_marks: &mut HashMap<u64, GCMark>, ptr: u64) -> bool {
-        if !gc_marks.contains_key(&ptr) {
-            gc_marks.insert(ptr, GCMark::Started);
-            true
-        } else {
-            false
-        }
+    //Makes this VM a worker of the one the group belongs to
+    #[cfg(feature = "std")]
+    pub fn join_worker_group(&mut self, group: Arc<WorkerGroup>) {
+        self.worker_group = Some(group);
+        self.is_worker = true;
     }
 
-    fn mark_stack(&mut self, gc_marks: &mut HashMap<u64, GCMark>) {
-        for value in self.stack.iter() {
-            if let Value::Object(ptr) = value {
-                Self::mark_object_started(gc_marks, *ptr);
-            }
-        }
+    #[cfg(feature = "std")]
+    pub fn is_worker(&self) -> bool {
+        self.is_worker
     }
 
-    fn mark_globals(&self, gc_marks: &mut HashMap<u64, GCMark>) {
-        for val in self.globals.values() {
-            if let Value::Object(ptr) = val {
-                Self::mark_object_started(gc_marks, *ptr);
-            }
+    //The variable as setenv left it, or as the process has it otherwise
+    #[cfg(feature = "std")]
+    pub fn env_var(&self, name: &str) -> Option<String> {
+        match self.environment.get(name) {
+            Some(value) => value.clone(),
+            None => std::env::var_os(name).map(|value| value.to_string_lossy().into_owned()),
         }
     }
 
-    fn mark_callframes(&mut self, current_frame: &CallFrame, gc_marks: &mut HashMap<u64, GCMark>) {
-        Self::mark_object_started(gc_marks, current_frame.closure_pointer);
-
-        for frame in self.call_frames.iter() {
-            Self::mark_object_started(gc_marks, frame.closure_pointer);
-        }
+    #[cfg(feature = "std")]
+    pub fn set_env_var(&mut self, name: String, value: Option<String>) {
+        self.environment.insert(name, value);
     }
 
-    #[inline]
-    fn add_to_worklist(gc_marks: &mut HashMap<u64, GCMark>, worklist: &mut Vec<u64>, ptr: u64) {
-        if Self::mark_object_started(gc_marks, ptr) {
-            worklist.push(ptr);
-        }
+    #[cfg(feature = "std")]
+    pub fn env_overrides(&self) -> &Map<String, Option<String>> {
+        &self.environment
     }
 
-    fn mark_object(&self, gc_marks: &mut HashMap<u64, GCMark>, worklist: &mut Vec<u64>, ptr: u64) {
-        let object = self.heap().deref(ptr);
-        match object {
-            Object::Closure(closure) => {
-                Self::add_to_worklist(gc_marks, worklist, closure.function_pointer);
-                for closed_ptr in closure.closed_values.iter() {
-                    Self::add_to_worklist(gc_marks, worklist, *closed_ptr);
-                }
-            }
-            Object::Value(val) => {
-                if let Value::Object(obj_ptr) = val {
-                    Self::add_to_worklist(gc_marks, worklist, *obj_ptr);
-                }
-            }
-            Object::Function(fun) => {
-                for value in fun.chunk.constants.iter() {
-                    if let Value::Object(obj_ptr) = value {
-                        Self::add_to_worklist(gc_marks, worklist, *obj_ptr)
-                    }
-                }
-            }
-            Object::Instance(instance) => {
-                Self::add_to_worklist(gc_marks, worklist, instance.class_ptr);
-                for value in instance.fields.values() {
-                    if let Value::Object(obj_ptr) = value {
-                        Self::add_to_worklist(gc_marks, worklist, *obj_ptr);
-                    }
-                }
-            }
-            Object::Class(class) => {
-                for closure_ptr in class.methods.values() {
-                    Self::add_to_worklist(gc_marks, worklist, *closure_ptr);
-                }
-            }
-            Object::BoundMethod(bound_method) => {
-                if let Value::Object(ptr) = bound_method.receiver {
-                    Self::add_to_worklist(gc_marks, worklist, ptr);
-                }
-                Self::add_to_worklist(gc_marks, worklist, bound_method.closure_ptr);
-            }
-            _ => {}
-        }
+    //For natives, which then return any Err to stop the script with status
+    pub fn exit(&mut self, status: i32) {
+        self.exiting = Some(status);
     }
 
-    fn sweep(&mut self, gc_marks: &HashMap<u64, GCMark>) {
-        let mut to_remove: Vec<u64> = vec![];
-        for ptr in self.heap().heap.keys() {
-            if !gc_marks.contains_key(ptr) {
-                to_remove.push(*ptr);
-            }
-        }
-        for ptr in to_remove.iter() {
-            if cfg!(test_gc) {
-                println!("Removing {}", self.heap().deref(*ptr));
-            }
+    #[cfg(feature = "std")]
+    pub fn seconds_running(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
 
-            self.heap_mut().remove_from_heap(*ptr);
-        }
+    pub fn max_call_depth(&self) -> usize {
+        self.max_call_depth
     }
 
-    fn collect_garbage(&mut self, current_frame: &CallFrame) {
-        let mut gc_marks: HashMap<u64, GCMark> = HashMap::new();
+    //Callers below the running function before a call overflows the stack.
+    //Only between runs, since it sizes the frame array.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+        //The script's frame, and the one that overflows
+        self.frames.resize(depth + 2, CallFrame::default());
+    }
 
-        self.mark_stack(&mut gc_marks);
-        self.mark_globals(&mut gc_marks);
-        self.mark_callframes(current_frame, &mut gc_marks);
+    pub fn stack_size(&self) -> usize {
+        self.stack_size
+    }
 
-        let mut worklist: Vec<u64> = gc_marks.iter().map(|(k, _)| *k).collect();
+    //Values the stack holds before a call overflows it. Never shrinks what's
+    //already allocated.
+    pub fn set_stack_size(&mut self, size: usize) {
+        self.stack_size = size;
+        let capacity = size + FRAME_HEADROOM;
+        self.stack.reserve_exact(capacity.saturating_sub(self.stack.len()));
+    }
 
-        while worklist.len() > 0 {
-            let ptr = worklist.pop().unwrap();
-            if let GCMark::Started = gc_marks[&ptr] {
-                self.mark_object(&mut gc_marks, &mut worklist, ptr);
-                gc_marks.insert(ptr, GCMark::Complete);
+    #[cfg(feature = "std")]
+    pub fn configure(&mut self, options: &VmOptions) {
+        self.set_max_call_depth(options.max_call_depth);
+        self.set_stack_size(options.stack_size);
+        self.gc_step = options.gc_step;
+        self.module_search_path = options.module_search_path();
+        if options.hot_threshold > 0 {
+            self.enable_tiering(options.hot_threshold);
+        }
+        if let Some(heap) = self.virtual_memory.as_mut() {
+            heap.set_gc_tuning(options.gc_threshold, options.gc_growth);
+        }
+        self.capabilities = options.capabilities.clone();
+        for group in natives::STANDARD.iter() {
+            if self.allows(group.name) {
+                self.define_natives(group);
             }
         }
+        //Only when asked for by name
+        for group in natives::OPT_IN.iter() {
+            if self.capabilities.iter().flatten().any(|c| c == group.name) {
+                self.define_natives(group);
+            }
+        }
+    }
 
-        self.sweep(&gc_marks);
+    //Whether lox.toml lets scripts use a group of natives
+    pub fn allows(&self, group: &str) -> bool {
+        match &self.capabilities {
+            Some(capabilities) => capabilities.iter().any(|c| c == group),
+            None => true,
+        }
+    }
 
-        self.heap_mut().allocations = 0;
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = Object::NativeFunction(String::from(name), arity, function);
+        let addr = self.heap_mut().add_to_heap(native);
+        self.define_global(name, Value::Object(addr));
     }
 
-    fn should_run_gc(&self) -> bool {
-        if self.heap().allocations > self.heap().max_allocations {
-            true
-        } else {
-            false
-        }
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        let slot = self.heap_mut().global_names.slot(name);
+        self.globals.define(slot, value);
     }
 
-    #[inline]
-    fn heap_mut(&mut self) -> &mut VirtualMemory {
-        self.virtual_memory.as_mut().unwrap()
+    //For natives that return strings
+    pub fn new_string(&mut self, s: String) -> Value {
+        Value::Object(self.add_to_heap(Object::String(s)))
     }
 
-    #[inline]
-    fn heap(&self) -> &VirtualMemory {
-        &self.virtual_memory.as_ref().unwrap()
+    pub fn new_list(&mut self, items: Vec<Value>) -> Value {
+        Value::Object(self.add_to_heap(Object::List(items)))
     }
 
-    #[inline]
-    fn get_closed_value(&self, frame: &CallFrame, index: usize) -> Value {
-        let closure = self.heap().closure_deref(frame.closure_pointer);
-        let value_ptr = closure.closed_values[index];
-        let obj = self.heap().deref(value_ptr);
-        match obj {
-            Object::OpenUpvalue(call_frame_idx, slot_idx) => {
-                let closure_frame = self.call_frames[*call_frame_idx];
-                self.read_stack(&closure_frame, *slot_idx)
-            }
-            Object::Value(value) => *value,
-            _ => panic!("Attempt to get closed value which is not an OpenUpvalue or Value type"),
-        }
+    pub fn new_userdata(&mut self, userdata: Userdata) -> Value {
+        Value::Object(self.add_to_heap(Object::Userdata(userdata)))
     }
 
-    #[inline]
-    fn set_closed_value(&mut self, frame: &CallFrame, index: usize, value: Value) {
-        let closure = self.heap().closure_deref(frame.closure_pointer);
-        let value_ptr = closure.closed_values[index];
-        let obj = self.heap().deref(value_ptr).clone(); //Only expensive in the case where we'll panic anyway...
+    pub fn define_natives(&mut self, group: &Group) {
+        for native in group.natives.iter() {
+            self.define_native(native.name, native.arity(), native.function);
+        }
+    }
 
-        match obj {
-            Object::OpenUpvalue(call_frame_idx, slot_idx) => {
-                let closure_frame = self.call_frames[call_frame_idx];
-                self.write_stack(&closure_frame, slot_idx, value);
-            }
-            Object::Value(_) => {
-                self.heap_mut().write(value_ptr, Object::Value(value));
-            }
-            _ => {
-                panic!("Attempt to write to closed value which is not an OpenUpvalue or Value type")
+    // Runs source into the globals before anything else, so a team can share
+    // helper functions without modules. Errors are formatted for the user.
+    pub fn with_prelude(mut self, source: &str) -> Result<VM, String> {
+        let tokens = scan_tokens(&String::from(source)).map_err(|e| e.to_string())?;
+        let mut compiler = Compiler::new(tokens, self.take_virtual_memory());
+        match compiler.compile() {
+            Ok(main) => self
+                .interpret(main, compiler.heap)
+                .map_err(|e| format!("Runtime Error: {}", e))?,
+            Err(errors) => {
+                let errors: Vec<String> = errors
+                    .iter()
+                    .map(|e| format!("Compiler error: {}", e.to_string()))
+                    .collect();
+                return Err(errors.join("\n"));
             }
         }
+        Ok(self)
     }
 
-    #[inline]
-    fn chunk(&self, closure_p: u64) -> &Chunk {
-        let fp = self.heap().closure_deref(closure_p).function_pointer;
-        &self.heap().function_deref(fp).chunk
+    //Imports in the script are found relative to this
+    #[cfg(feature = "std")]
+    pub fn set_script_path(&mut self, path: &str) {
+        self.script_path = Some(modules::canonical(path).unwrap_or_else(|_| String::from(path)));
     }
 
-    #[inline]
-    fn code(&self, closure_p: u64) -> &Vec<OpCode> {
-        &self.chunk(closure_p).code
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
     }
 
-    #[inline]
-    fn consume(&self, frame: &mut CallFrame) -> OpCode {
-        let code = self.code(frame.closure_pointer);
-        if frame.ip < code.len() {
-            let op = code[frame.ip];
-            frame.ip += 1;
-            op
-        } else {
-            OpCode::EOF
-        }
+    #[cfg(feature = "std")]
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = Some(input);
     }
 
-    fn read_constant(&self, frame: &CallFrame, address: usize) -> Value {
-        self.chunk(frame.closure_pointer).constants[address].clone()
-    }
+    //The next line of input without its line ending, None at the end
+    #[cfg(feature = "std")]
+    pub fn read_line(&mut self) -> io::Result<Option<String>> {
+        //So a prompt printed without a newline shows before we wait
+        self.out.flush()?;
+        let mut line = String::new();
+        let read = match self.input.as_mut() {
+            Some(input) => input.read_line(&mut line)?,
+            None => io::stdin().read_line(&mut line)?,
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    pub fn take_virtual_memory(&mut self) -> VirtualMemory {
+        let mut spare = None;
+        swap(&mut spare, &mut self.virtual_memory);
+        spare.unwrap()
+    }
+
+    pub fn give_virtual_memory(&mut self, virtual_memory: VirtualMemory) {
+        self.virtual_memory = Some(virtual_memory);
+    }
+
+    pub fn inspect(&self, value: Value) -> String {
+        inspect(self.heap(), value)
+    }
+
+    pub fn globals(&self) -> Vec<(&String, Value)> {
+        let names = &self.heap().global_names;
+        let mut globals: Vec<(&String, Value)> =
+            self.globals.iter().map(|(slot, value)| (names.name(slot), value)).collect();
+        globals.sort_by(|a, b| a.0.cmp(b.0));
+        globals
+    }
+
+    pub fn heap_report(&self) -> HeapReport {
+        heap_report::build_report(self.heap())
+    }
+
+    pub fn virtual_memory(&self) -> &VirtualMemory {
+        self.heap()
+    }
+
+    pub fn heap_object(&self, address: u64) -> Option<&Object> {
+        self.heap().get(address)
+    }
+
+    pub fn heap_object_mut(&mut self, address: u64) -> Option<&mut Object> {
+        self.heap_mut().get_mut(address)
+    }
+
+    pub fn shapes(&self) -> &Shapes {
+        &self.heap().shapes
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.heap().symbols
+    }
+
+    // How the object at address is kept alive, starting from a global, a stack
+    // slot or an active call frame.
+    pub fn retention_path(&self, address: u64) -> Option<Vec<String>> {
+        let mut roots: Vec<(String, Value)> = self
+            .globals()
+            .into_iter()
+            .map(|(name, value)| (format!("global {}", name), value))
+            .collect();
+        for (idx, value) in self.stack.iter().enumerate() {
+            roots.push((format!("stack[{}]", idx), *value));
+        }
+        for (idx, frame) in self.frames[..self.frame_count].iter().enumerate() {
+            roots.push((format!("frame {}", idx), Value::Object(frame.closure_pointer)));
+        }
+        heap_report::retention_path(self.heap(), &roots, address)
+    }
+
+    pub fn enable_metrics(&mut self) {
+        self.metrics = Some(Metrics::default());
+    }
+
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn inline_caches(&self) -> &InlineCaches {
+        &self.inline_caches
+    }
+
+    pub fn enable_tiering(&mut self, threshold: u64) {
+        self.tiering = Some(Tiering::new(threshold));
+    }
+
+    pub fn tiering_mut(&mut self) -> Option<&mut Tiering> {
+        self.tiering.as_mut()
+    }
+
+    pub fn set_hooks(&mut self, hooks: Hooks) {
+        self.hooks = Some(Box::new(hooks));
+    }
+
+    pub fn take_hooks(&mut self) -> Option<Hooks> {
+        self.hooks.take().map(|hooks| *hooks)
+    }
+
+    pub fn record_recent_instructions(&mut self) {
+        self.recent = Some(VecDeque::with_capacity(RECENT_INSTRUCTIONS));
+    }
+
+    // What the VM was doing, for after it panics. The running function's ip
+    // is only written back when it calls out, so where it was comes from the
+    // last recorded instruction.
+    pub fn crash_report(&self, message: &str) -> CrashReport {
+        let mut recent = vec![];
+        let mut frames = vec![];
+        if let Some(heap) = self.virtual_memory.as_ref() {
+            if let Some(instructions) = self.recent.as_ref() {
+                recent = instructions
+                    .iter()
+                    .filter_map(|(closure_p, ip)| describe_instruction(heap, *closure_p, *ip))
+                    .collect();
+                if let Some((closure_p, ip)) = instructions.back() {
+                    frames.push(describe_frame(heap, *closure_p, *ip));
+                }
+            }
+            let callers = self.frame_count.saturating_sub(1);
+            for frame in self.frames[..callers].iter().rev() {
+                //Callers have already advanced past their Call instruction
+                let ip = frame.ip.saturating_sub(1);
+                frames.push(describe_frame(heap, frame.closure_pointer, ip));
+            }
+        }
+
+        CrashReport {
+            message: String::from(message),
+            instruction: recent.last().cloned(),
+            frames,
+            stack_depth: self.stack.len(),
+            recent,
+        }
+    }
+
+    pub fn attach_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(debugger);
+    }
+
+    pub fn debugger_mut(&mut self) -> Option<&mut Debugger> {
+        self.debugger.as_mut()
+    }
+
+    pub fn interpret(
+        &mut self,
+        main: Function,
+        virtual_memory: VirtualMemory,
+    ) -> Result<(), InterpreterError> {
+        self.start(main, virtual_memory).map(|_| ())
+    }
+
+    // Runs a function that takes no arguments as the whole program, the way
+    // workers start. Its locals begin after the receiver slot a call would fill.
+    pub fn interpret_function(
+        &mut self,
+        function: Function,
+        virtual_memory: VirtualMemory,
+    ) -> Result<(), InterpreterError> {
+        self.stack.push(Value::Nil);
+        self.interpret(function, virtual_memory)
+    }
+
+    // Like interpret, but returns early if an attached debugger pauses. Use
+    // resume to carry on from there.
+    pub fn start(
+        &mut self,
+        main: Function,
+        virtual_memory: VirtualMemory,
+    ) -> Result<Status, InterpreterError> {
+        self.virtual_memory = Some(virtual_memory);
+        self.inline_caches.invalidate();
+        //A collection left marking by the last run can't know what this heap holds
+        self.marking = None;
+        self.heap_mut().dirty = None;
+
+        let fp = self.add_to_heap(Object::Function(main));
+        let closure_p = self.add_to_heap(Object::Closure(Closure {
+            function_pointer: fp,
+            closed_values: vec![],
+            namespace: 0,
+        }));
+        self.frames[0] = CallFrame {
+            closure_pointer: closure_p,
+            ip: 0,
+            stack_pointer: 0,
+            namespace: 0,
+        };
+        self.frame_count = 1;
+
+        let _span = debug_span!("interpret").entered();
+        self.run()
+    }
+
+    pub fn resume(&mut self, resume: Resume) -> Result<Status, InterpreterError> {
+        //The paused frame is on top, with its ip written back
+        let depth = self.depth();
+        if let Some(debugger) = self.debugger.as_mut() {
+            debugger.resume(resume, depth);
+        }
+        self.run()
+    }
+
+    // Only meaningful while paused, when every frame's ip is up to date.
+    pub fn backtrace(&self) -> Vec<FrameInfo> {
+        self.frames[..self.frame_count]
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(idx, frame)| {
+                let chunk = self.chunk(frame.closure_pointer);
+                let fp = self.heap().closure_deref(frame.closure_pointer).function_pointer;
+                //Callers have already advanced past their Call instruction
+                let ip = if idx + 1 == self.frame_count {
+                    frame.ip
+                } else {
+                    frame.ip.saturating_sub(1)
+                };
+                let locals = chunk
+                    .locals
+                    .iter()
+                    .filter(|info| info.start <= ip && ip < info.end)
+                    .filter_map(|info| {
+                        self.stack
+                            .get(frame.stack_pointer + info.slot)
+                            .map(|value| (info.name.clone(), *value))
+                    })
+                    .collect();
+
+                FrameInfo {
+                    name: self.heap().fun_deref(fp).name.clone(),
+                    line: chunk.line_numbers.get(ip).copied().unwrap_or(0),
+                    locals,
+                }
+            })
+            .collect()
+    }
+
+    fn mark_stack(&self, marking: &mut Marking) {
+        for value in self.stack.iter() {
+            if let Value::Object(ptr) = value {
+                marking.gray(*ptr);
+            }
+        }
+    }
+
+    fn mark_globals(&self, marking: &mut Marking) {
+        let modules = self.namespaces.iter().flat_map(|namespace| namespace.globals.values());
+        let cached = self.module_cache.values().copied();
+        for val in self.globals.values().chain(modules).chain(cached) {
+            if let Value::Object(ptr) = val {
+                marking.gray(ptr);
+            }
+        }
+    }
+
+    fn mark_callframes(&self, marking: &mut Marking) {
+        for frame in self.frames[..self.frame_count].iter() {
+            marking.gray(frame.closure_pointer);
+        }
+    }
+
+    //Returning frames write through these even if no closure holds them anymore,
+    //which must not land on a recycled address
+    fn mark_open_upvalues(&self, marking: &mut Marking) {
+        for (_, _, ptr) in self.open_upvalues.iter() {
+            marking.gray(*ptr);
+        }
+    }
+
+    fn mark_roots(&self, marking: &mut Marking) {
+        self.mark_stack(marking);
+        self.mark_globals(marking);
+        self.mark_callframes(marking);
+        self.mark_open_upvalues(marking);
+    }
+
+    //Marks what up to budget objects on the worklist refer to, returning
+    //whether the worklist ran out
+    fn mark_until(&self, marking: &mut Marking, budget: usize) -> bool {
+        for _ in 0..budget {
+            let ptr = match marking.worklist.pop() {
+                Some(ptr) => ptr,
+                None => return true,
+            };
+            if let GCMark::Started = marking.marks[&ptr] {
+                self.mark_object(marking, ptr);
+                marking.marks.insert(ptr, GCMark::Complete);
+            }
+        }
+        marking.worklist.is_empty()
+    }
+
+    fn mark_object(&self, marking: &mut Marking, ptr: u64) {
+        let object = self.heap().deref(ptr);
+        match object {
+            Object::Closure(closure) => {
+                marking.gray(closure.function_pointer);
+                for closed_ptr in closure.closed_values.iter() {
+                    marking.gray(*closed_ptr);
+                }
+            }
+            Object::Value(val) => {
+                if let Value::Object(obj_ptr) = val {
+                    marking.gray(*obj_ptr);
+                }
+            }
+            Object::Function(fun) => {
+                for value in fun.chunk.constants.iter() {
+                    if let Value::Object(obj_ptr) = value {
+                        marking.gray(*obj_ptr)
+                    }
+                }
+            }
+            Object::Instance(instance) => {
+                marking.gray(instance.class_ptr);
+                for value in instance.fields.iter() {
+                    if let Value::Object(obj_ptr) = value {
+                        marking.gray(*obj_ptr);
+                    }
+                }
+            }
+            Object::Class(class) => {
+                let statics = class.statics.values();
+                let accessors = class.getters.values().chain(class.setters.values());
+                for closure_ptr in class.methods.values().chain(statics).chain(accessors) {
+                    marking.gray(*closure_ptr);
+                }
+                for subclass_ptr in class.subclasses.iter() {
+                    marking.gray(*subclass_ptr);
+                }
+            }
+            Object::BoundMethod(bound_method) => {
+                if let Value::Object(ptr) = bound_method.receiver {
+                    marking.gray(ptr);
+                }
+                marking.gray(bound_method.closure_ptr);
+            }
+            Object::List(items) | Object::Tuple(items) => {
+                for value in items.iter() {
+                    if let Value::Object(obj_ptr) = value {
+                        marking.gray(*obj_ptr);
+                    }
+                }
+            }
+            Object::Generator(generator) => {
+                marking.gray(generator.closure_ptr);
+                for value in generator.slots.iter() {
+                    if let Value::Object(obj_ptr) = value {
+                        marking.gray(*obj_ptr);
+                    }
+                }
+                for (_, upvalue_ptr) in generator.captured.iter() {
+                    marking.gray(*upvalue_ptr);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn sweep(&mut self, gc_marks: &Map<u64, GCMark>) -> usize {
+        let mut to_remove: Vec<u64> = vec![];
+        for (ptr, _) in self.heap().objects() {
+            if !gc_marks.contains_key(&ptr) {
+                to_remove.push(ptr);
+            }
+        }
+        for ptr in to_remove.iter() {
+            trace!(ptr, object = %self.heap().deref(*ptr), "sweep");
+            self.heap_mut().remove_from_heap(*ptr);
+        }
+        debug!(freed = to_remove.len(), "sweep complete");
+        to_remove.len()
+    }
+
+    //Collects everything at once, or starts marking a bit per instruction if gc_step is set
+    fn collect_garbage(&mut self) {
+        let live = self.heap().live_objects();
+        let mut marking = Marking::new(live);
+        if self.gc_step > 0 {
+            debug!(live, "gc marking started");
+            self.mark_roots(&mut marking);
+            self.heap_mut().dirty = Some(vec![]);
+            self.marking = Some(marking);
+            return;
+        }
+        let _span = debug_span!("gc", live).entered();
+        #[cfg(feature = "std")]
+        let started = Instant::now();
+        self.mark_roots(&mut marking);
+        self.mark_until(&mut marking, usize::MAX);
+        let freed = self.finish_collection(marking);
+        #[cfg(feature = "std")]
+        let pause_us = started.elapsed().as_micros() as u64;
+        #[cfg(not(feature = "std"))]
+        let pause_us = 0;
+        self.report_gc(live, freed, pause_us);
+    }
+
+    //The next gc_step objects of an incremental collection, finishing it once
+    //nothing is left to mark
+    fn mark_step(&mut self) {
+        let mut marking = self.marking.take().unwrap();
+        for ptr in self.heap_mut().dirty.iter_mut().flat_map(|dirty| dirty.drain(..)) {
+            marking.regray(ptr);
+        }
+        if !self.mark_until(&mut marking, self.gc_step) {
+            self.marking = Some(marking);
+            return;
+        }
+        let live = marking.live_before;
+        let _span = debug_span!("gc", live).entered();
+        #[cfg(feature = "std")]
+        let started = Instant::now();
+        //The roots changed without telling us, so they're marked again with
+        //the program stopped, along with anything new they lead to
+        self.heap_mut().dirty = None;
+        self.mark_roots(&mut marking);
+        self.mark_until(&mut marking, usize::MAX);
+        let freed = self.finish_collection(marking);
+        //Only this last part counts as the pause, the steps before are spread out
+        #[cfg(feature = "std")]
+        let pause_us = started.elapsed().as_micros() as u64;
+        #[cfg(not(feature = "std"))]
+        let pause_us = 0;
+        self.report_gc(live, freed, pause_us);
+    }
+
+    //Sweeps what marking didn't reach, returning how many objects that freed
+    fn finish_collection(&mut self, marking: Marking) -> usize {
+        let freed = self.sweep(&marking.marks);
+        self.inline_caches.invalidate();
+        if let Some(tiering) = self.tiering.as_mut() {
+            tiering.forget();
+        }
+        if let Some(profile) = self.profile.as_mut() {
+            profile.forget();
+        }
+
+        self.heap_mut().allocations = 0;
+        self.heap_mut().recount();
+        freed
+    }
+
+    fn report_gc(&mut self, live: usize, freed: usize, pause_us: u64) {
+        let pools = self.heap().pool_stats;
+        if let Some(metrics) = self.metrics.as_mut() {
+            metrics.record_gc(freed, pause_us, pools);
+        }
+        if let Some(on_gc) = self.hooks.as_mut().and_then(|hooks| hooks.on_gc.as_mut()) {
+            on_gc(&GcEvent {
+                live_before: live,
+                freed,
+                pause_us,
+                pools,
+            });
+        }
+    }
+
+    fn should_run_gc(&self) -> bool {
+        let heap = self.heap();
+        heap.bytes_allocated > heap.next_gc
+    }
+
+    #[inline]
+    fn heap_mut(&mut self) -> &mut VirtualMemory {
+        self.virtual_memory.as_mut().unwrap()
+    }
+
+    #[inline]
+    fn heap(&self) -> &VirtualMemory {
+        &self.virtual_memory.as_ref().unwrap()
+    }
+
+    #[inline]
+    fn get_closed_value(&self, frame: &CallFrame, index: usize) -> Value {
+        let closure = self.heap().closure_deref(frame.closure_pointer);
+        let value_ptr = closure.closed_values[index];
+        let obj = self.heap().deref(value_ptr);
+        match obj {
+            Object::OpenUpvalue(call_frame_idx, slot_idx) => {
+                let closure_frame = self.frames[*call_frame_idx];
+                self.read_stack(&closure_frame, *slot_idx)
+            }
+            Object::Value(value) => *value,
+            _ => panic!("Attempt to get closed value which is not an OpenUpvalue or Value type"),
+        }
+    }
+
+    #[inline]
+    fn set_closed_value(&mut self, frame: &CallFrame, index: usize, value: Value) {
+        let closure = self.heap().closure_deref(frame.closure_pointer);
+        let value_ptr = closure.closed_values[index];
+        let obj = self.heap().deref(value_ptr).clone(); //Only expensive in the case where we'll panic anyway...
+
+        match obj {
+            Object::OpenUpvalue(call_frame_idx, slot_idx) => {
+                let closure_frame = self.frames[call_frame_idx];
+                self.write_stack(&closure_frame, slot_idx, value);
+            }
+            Object::Value(_) => {
+                self.heap_mut().write(value_ptr, Object::Value(value));
+            }
+            _ => {
+                panic!("Attempt to write to closed value which is not an OpenUpvalue or Value type")
+            }
+        }
+    }
+
+    #[inline]
+    fn chunk(&self, closure_p: u64) -> &Chunk {
+        let fp = self.heap().closure_deref(closure_p).function_pointer;
+        &self.heap().function_deref(fp).chunk
+    }
+
+    //Counts a call or loop iteration, re-optimizing the function once it gets hot
+    fn heat(&mut self, frame: &CallFrame) {
+        let heap = self.virtual_memory.as_mut().unwrap();
+        let fp = heap.closure_deref(frame.closure_pointer).function_pointer;
+        let tiering = self.tiering.as_mut().unwrap();
+        if tiering.heat(fp) {
+            debug!(function = %heap.function_deref(fp).name, "tier up");
+            if tiering.tier_up(fp, heap) {
+                self.inline_caches.invalidate();
+                self.activate(frame.closure_pointer);
+            }
+        }
+    }
+
+    //Lets the frame's native code run from its ip, if its function has any.
+    //Anything watching single instructions keeps it interpreted, except the
+    //recent ones crash reports show, which just leave the native code out.
+    #[cfg(feature = "jit")]
+    fn run_native(&mut self, frame: &mut CallFrame) {
+        let watched = self.trace
+            || self.fuel.is_some()
+            || self.debugger.is_some()
+            || self.hooks.is_some()
+            || self.metrics.is_some()
+            || self.profile.is_some();
+        let code = match &self.chunk(frame.closure_pointer).native {
+            Some(code) if !watched => code.clone(),
+            _ => return,
+        };
+        //namespace_mut would borrow the stack too
+        let globals = match frame.namespace {
+            0 => &mut self.globals,
+            n => &mut self.namespaces[n - 1].globals,
+        };
+        if let Some(ip) = code.run(&mut self.stack, frame.stack_pointer, globals, frame.ip) {
+            frame.ip = ip;
+        }
+    }
+
+    #[inline]
+    #[cfg(not(feature = "jit"))]
+    fn run_native(&mut self, _frame: &mut CallFrame) {}
+
+    #[inline]
+    fn code(&self, frame: &CallFrame) -> &[OpCode] {
+        match self.active.of(frame) {
+            Some(active) => unsafe { &*active.code },
+            None => &self.chunk(frame.closure_pointer).code,
+        }
+    }
+
+    #[inline]
+    #[cfg(not(feature = "compact-bytecode"))]
+    fn consume(&self, frame: &mut CallFrame) -> OpCode {
+        let code = self.code(frame);
+        if frame.ip < code.len() {
+            let op = code[frame.ip];
+            frame.ip += 1;
+            op
+        } else {
+            OpCode::EOF
+        }
+    }
+
+    #[inline]
+    #[cfg(feature = "compact-bytecode")]
+    fn consume(&self, frame: &mut CallFrame) -> OpCode {
+        let compact = &self.chunk(frame.closure_pointer).compact;
+        debug_assert_eq!(compact.len(), self.code(frame).len());
+        if frame.ip < compact.len() {
+            let op = compact.decode(frame.ip);
+            frame.ip += 1;
+            op
+        } else {
+            OpCode::EOF
+        }
+    }
+
+    #[inline]
+    fn read_constant(&self, frame: &CallFrame, address: usize) -> Value {
+        let constants = match self.active.of(frame) {
+            Some(active) => unsafe { &*active.constants },
+            None => &self.chunk(frame.closure_pointer).constants,
+        };
+        constants[address]
+    }
 
     #[inline]
     fn push(&mut self, val: Value) {
+        //execute checks the stack size before each instruction, which leaves
+        //FRAME_HEADROOM for what the instruction pushes
+        debug_assert!(self.stack.len() < self.stack.capacity(), "Value stack overflow");
         self.stack.push(val);
     }
 
+    //For instructions that push a number of values only known when they run
+    fn reserve_stack(&self, count: usize, line: usize) -> Result<(), InterpreterError> {
+        if self.stack.len() + count > self.stack_size {
+            return Err(InterpreterError::FunctionError(line, String::from("Stack overflow")));
+        }
+        Ok(())
+    }
+
     #[inline]
     fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
+        //Checked, since bytecode loaded from a file or bundle isn't verified
+        self.stack.pop().expect("Value stack underflow")
     }
 
     #[inline]
     fn current_line(&self, frame: &CallFrame) -> usize {
-        //Since we've already advanced past it
-        self.chunk(frame.closure_pointer).line_numbers[frame.ip]
+        //Since we've already advanced past it. The script's last instruction
+        //has nothing after it, so it keeps its own line.
+        let lines = match self.active.of(frame) {
+            Some(active) => unsafe { &*active.lines },
+            None => &self.chunk(frame.closure_pointer).line_numbers[..],
+        };
+        lines.get(frame.ip).or_else(|| lines.last()).copied().unwrap_or(0)
+    }
+
+    //The name is only looked at once the slot turns out to be empty
+    #[cold]
+    fn undefined_variable(&self, frame: &CallFrame, name_idx: usize) -> InterpreterError {
+        let name_ptr = u64::as_val_or_panic(self.read_constant(frame, name_idx));
+        InterpreterError::NameError(
+            self.current_line(frame),
+            format!("Undefined variable {}", self.heap().string_deref(name_ptr)),
+        )
+    }
+
+    //The sum when both are numbers of one kind and it doesn't overflow, so
+    //fused adds can skip add and its type checks
+    #[inline]
+    fn add_numbers(a: Value, b: Value) -> Option<Value> {
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => Some(Value::Number(a + b)),
+            (Value::Integer(a), Value::Integer(b)) => a.checked_add(b).map(Value::Integer),
+            _ => None,
+        }
     }
 
     fn lox_bool_coercion(val: Value) -> bool {
@@ -427,6 +1553,205 @@ impl VM {
         Ok(())
     }
 
+    //Integers stay integers while int_op gives a result, anything else is
+    //done with floats, so overflow and mixed operands both promote
+    fn numeric_op<I: ToValue, F: ToValue>(
+        &mut self,
+        frame: &CallFrame,
+        int_op: fn(i64, i64) -> Option<I>,
+        float_op: fn(f64, f64) -> F,
+    ) -> Result<(), InterpreterError> {
+        if let (Value::Integer(a), Value::Integer(b)) = (*self.peek(1), *self.peek(0)) {
+            if let Some(result) = int_op(a, b) {
+                self.pop();
+                self.pop();
+                self.push(I::to_value(result));
+                return Ok(());
+            }
+        }
+        self.binary_op(frame, float_op)
+    }
+
+    fn add(&mut self, frame: &CallFrame) -> Result<(), InterpreterError> {
+        let a = self.peek(0);
+        let b = self.peek(1);
+        match (a, b) {
+            (Value::Object(_), Value::Object(_)) => {
+                self.apply_to_string(frame, 1)?;
+                self.apply_to_string(frame, 0)?;
+                self.string_concat()?;
+            }
+            _ => self.numeric_op(frame, i64::checked_add, |a: f64, b: f64| a + b)?,
+        };
+        Ok(())
+    }
+
+    fn greater(&mut self, frame: &CallFrame) -> Result<(), InterpreterError> {
+        self.numeric_op(frame, |a: i64, b: i64| Some(a > b), |a: f64, b: f64| a > b)
+    }
+
+    fn less(&mut self, frame: &CallFrame) -> Result<(), InterpreterError> {
+        self.numeric_op(frame, |a: i64, b: i64| Some(a < b), |a: f64, b: f64| a < b)
+    }
+
+    //Runs one of natives::LIST_METHODS or STRING_METHODS, which get the
+    //receiver as their first argument
+    fn invoke_native_method(
+        &mut self,
+        method: &Native,
+        num_args: usize,
+        line: usize,
+    ) -> Result<(), InterpreterError> {
+        if method.arity() != num_args {
+            return Err(InterpreterError::FunctionError(
+                line,
+                format!("Expected {} arguments but got {}", method.arity(), num_args),
+            ));
+        }
+
+        //The receiver goes where the ThisPlaceholder was, in front of the args
+        let receiver_slot = self.stack.len() - num_args - 2;
+        self.stack[receiver_slot + 1] = self.stack[receiver_slot];
+        let args = self.stack.split_off(receiver_slot + 1);
+        self.pop();
+        let result = (method.function)(self, &args)
+            .map_err(|e| InterpreterError::FunctionError(line, e))?;
+        self.push(result);
+        Ok(())
+    }
+
+    //A generator's frame has the generator where a call's callee would be
+    fn running_generator(&mut self, frame: &CallFrame) -> &mut Generator {
+        let generator_ptr = u64::as_val_or_panic(self.stack[frame.stack_pointer - 1]);
+        match self.heap_mut().deref_mut(generator_ptr) {
+            Object::Generator(generator) => generator,
+            _ => panic!("Generator frame without a generator"),
+        }
+    }
+
+    //next() picks a generator up where it last yielded and done() tells whether
+    //it has finished. Gives back the frame to carry on in.
+    fn invoke_generator(
+        &mut self,
+        frame: &CallFrame,
+        generator_ptr: u64,
+        method: Symbol,
+        num_args: usize,
+        line: usize,
+    ) -> Result<CallFrame, InterpreterError> {
+        let name = self.heap().symbols.name(method);
+        if !GENERATOR_METHODS.contains(&name) {
+            return Err(InterpreterError::NameError(line, String::from("Undefined property")));
+        }
+        let done = name == "done";
+        if num_args != 0 {
+            return Err(InterpreterError::FunctionError(
+                line,
+                format!("Expected 0 arguments but got {}", num_args),
+            ));
+        }
+        self.pop(); //The ThisPlaceholder
+
+        if let Object::Generator(generator) = self.heap().deref(generator_ptr) {
+            self.reserve_stack(generator.slots.len(), line)?;
+        }
+        let generator = match self.heap_mut().deref_mut(generator_ptr) {
+            Object::Generator(generator) => generator,
+            _ => panic!("Invoked a generator method on something else"),
+        };
+        let state = generator.state;
+        if done || state == GeneratorState::Done {
+            self.pop();
+            self.push(if done {
+                Value::Boolean(state == GeneratorState::Done)
+            } else {
+                Value::Nil
+            });
+            return Ok(*frame);
+        }
+        if state == GeneratorState::Running {
+            return Err(InterpreterError::FunctionError(
+                line,
+                String::from("Generator is already running"),
+            ));
+        }
+
+        //The generator stays under its slots, standing in for the callee
+        generator.state = GeneratorState::Running;
+        let (closure_ptr, ip) = (generator.closure_ptr, generator.ip);
+        let slots = core::mem::take(&mut generator.slots);
+        let captured = core::mem::take(&mut generator.captured);
+        let num_slots = slots.len();
+        self.stack.extend(slots);
+        let (old_frame, mut new_frame) =
+            match self.closure_frame(frame, closure_ptr, num_slots - 1, line) {
+                Ok(frames) => frames,
+                Err(error) => {
+                    //Its slots are gone, so it can't be picked up again
+                    self.stack.truncate(self.stack.len() - num_slots);
+                    if let Object::Generator(generator) = self.heap_mut().deref_mut(generator_ptr) {
+                        generator.state = GeneratorState::Done;
+                    }
+                    return Err(error);
+                }
+            };
+        new_frame.ip = ip;
+
+        //Closures that captured its locals read them off the stack again
+        let call_frame_idx = self.depth() + 1;
+        for (slot, upvalue_ptr) in captured {
+            let value = self.heap().deref(upvalue_ptr).as_value();
+            self.write_stack(&new_frame, slot, value);
+            let upvalue = Object::OpenUpvalue(call_frame_idx, slot);
+            self.heap_mut().write(upvalue_ptr, upvalue);
+            self.open_upvalues.push((call_frame_idx, slot, upvalue_ptr));
+        }
+        self.push_frame(old_frame, new_frame);
+        self.record_call(closure_ptr, line);
+        Ok(new_frame)
+    }
+
+    //The list and position an Index or IndexSet refers to
+    fn list_slot(
+        &self,
+        list: Value,
+        index: Value,
+        line: usize,
+    ) -> Result<(u64, usize), InterpreterError> {
+        let len = match list {
+            Value::Object(ptr) => match self.heap().deref(ptr) {
+                Object::List(items) | Object::Tuple(items) => Some(items.len()),
+                _ => None,
+            },
+            _ => None,
+        };
+        let (list_ptr, len) = match (list, len) {
+            (Value::Object(ptr), Some(len)) => (ptr, len),
+            _ => {
+                return Err(InterpreterError::TypeError(
+                    line,
+                    String::from("Only lists and tuples can be indexed"),
+                ))
+            }
+        };
+        //No f64::fract without std
+        let index = match index {
+            Value::Number(n) if n == n as i64 as f64 => Value::Integer(n as i64),
+            index => index,
+        };
+        match index {
+            Value::Integer(n) if n >= 0 && (n as usize) < len => Ok((list_ptr, n as usize)),
+            Value::Integer(n) => Err(InterpreterError::TypeError(
+                line,
+                format!("Index {} is out of bounds for a list of {} items", n, len),
+            )),
+            _ => Err(InterpreterError::TypeError(
+                line,
+                String::from("List indices must be whole numbers"),
+            )),
+        }
+    }
+
     fn deref_str_value(&self, value: Value) -> Result<&String, InterpreterError> {
         if let Value::Object(ptr) = value {
             if let Object::String(s) = self.heap().deref(ptr) {
@@ -447,81 +1772,356 @@ impl VM {
         let s_b = self.deref_str_value(b)?;
 
         let s_c = format!("{}{}", s_a, s_b);
-        let str_ptr = self.heap_mut().add_to_heap(Object::String(s_c));
-        self.stack.push(Value::Object(str_ptr));
+        let str_ptr = self.add_to_heap(Object::String(s_c));
+        self.push(Value::Object(str_ptr));
         Ok(())
     }
 
-    fn values_equal(&self, a: Value, b: Value) -> bool {
+    pub fn values_equal(&self, a: Value, b: Value) -> bool {
         match (a, b) {
             (Value::Boolean(ba), Value::Boolean(bb)) => ba == bb,
             (Value::Number(na), Value::Number(nb)) => na == nb,
-            (Value::Nil, Value::Nil) => true,
-            (Value::Object(p_a), Value::Object(p_b)) => {
-                let v_a = self.heap().deref(p_a);
-                let v_b = self.heap().deref(p_b);
-                match (v_a, v_b) {
-                    (Object::String(s1), Object::String(s2)) => s1 == s2,
-                    _ => false,
-                }
+            (Value::Integer(na), Value::Integer(nb)) => na == nb,
+            (Value::Integer(na), Value::Number(nb)) | (Value::Number(nb), Value::Integer(na)) => {
+                na as f64 == nb
             }
+            (Value::Nil, Value::Nil) => true,
+            //Strings are interned, so equal ones are the same object
+            (Value::Object(p_a), Value::Object(p_b)) => p_a == p_b,
             _ => false,
         }
     }
 
-    fn print(&self, value: Value) {
+    //Whether a value is of the type a match pattern names. Any name that isn't
+    //a built in type is a class, which only its own instances match.
+    pub fn is_type(&self, value: Value, name: &str) -> bool {
+        let object = match value {
+            Value::Object(ptr) => Some(self.heap().deref(ptr)),
+            _ => None,
+        };
+        match name {
+            "Nil" => matches!(value, Value::Nil),
+            "Bool" => matches!(value, Value::Boolean(_)),
+            "Number" => matches!(value, Value::Number(_) | Value::Integer(_)),
+            "String" => matches!(object, Some(Object::String(_))),
+            "List" => matches!(object, Some(Object::List(_))),
+            "Tuple" => matches!(object, Some(Object::Tuple(_))),
+            "Class" => matches!(object, Some(Object::Class(_))),
+            "Module" => matches!(object, Some(Object::Module(_))),
+            "Generator" => matches!(object, Some(Object::Generator(_))),
+            "Function" => matches!(
+                object,
+                Some(Object::Closure(_) | Object::NativeFunction(_, _, _) | Object::BoundMethod(_))
+            ),
+            _ => match object {
+                Some(Object::Instance(instance)) => {
+                    self.heap().deref(instance.class_ptr).as_class().name == name
+                }
+                _ => false,
+            },
+        }
+    }
+
+    //What type() gives, lower case unlike the names match patterns use
+    pub fn type_name(&self, value: Value) -> &'static str {
         match value {
-            Value::Object(p) => println!("{}", self.heap().deref(p)),
-            _ => println!("{}", value),
+            Value::Nil => "nil",
+            Value::Boolean(_) => "bool",
+            Value::Number(_) | Value::Integer(_) => "number",
+            Value::Object(ptr) => match self.heap().deref(ptr) {
+                Object::String(_) => "string",
+                Object::List(_) => "list",
+                Object::Tuple(_) => "tuple",
+                Object::Class(_) => "class",
+                Object::Instance(_) => "instance",
+                Object::Module(_) => "module",
+                Object::Generator(_) => "generator",
+                Object::Userdata(_) => "userdata",
+                Object::Function(_)
+                | Object::Closure(_)
+                | Object::NativeFunction(_, _, _)
+                | Object::BoundMethod(_) => "function",
+                Object::Value(_) | Object::OpenUpvalue(_, _) => {
+                    panic!("Upvalues are never given to Lox code")
+                }
+            },
+        }
+    }
+
+    // The user facing string form of a value, as produced by 'print'.
+    pub fn stringify(&self, value: Value) -> String {
+        match value {
+            Value::Number(n) => format!("{}", n),
+            Value::Integer(n) => format!("{}", n),
+            Value::Boolean(b) => format!("{}", b),
+            Value::Nil => String::from("nil"),
+            Value::Object(ptr) => match self.heap().deref(ptr) {
+                Object::String(s) => s.clone(),
+                Object::Function(fun) => format!("<fn {}>", fun.name),
+                Object::Closure(closure) => {
+                    format!("<fn {}>", self.heap().fun_deref(closure.function_pointer).name)
+                }
+                Object::NativeFunction(_, _, _) => String::from("<native fn>"),
+                Object::Value(value) => self.stringify(*value),
+                Object::OpenUpvalue(_, _) => String::from("<upvalue>"),
+                Object::Class(class) => class.name.clone(),
+                Object::Instance(instance) => {
+                    format!("{} instance", self.heap().class_deref(instance.class_ptr).name)
+                }
+                Object::BoundMethod(bound_method) => {
+                    let closure = self.heap().closure_deref(bound_method.closure_ptr);
+                    format!("<fn {}>", self.heap().fun_deref(closure.function_pointer).name)
+                }
+                Object::Userdata(userdata) => userdata.to_string(),
+                Object::List(_) | Object::Tuple(_) => self.stringify_list(ptr, &mut vec![]),
+                Object::Module(module) => format!("<module {}>", module.name),
+                Object::Generator(generator) => {
+                    let closure = self.heap().closure_deref(generator.closure_ptr);
+                    let name = &self.heap().fun_deref(closure.function_pointer).name;
+                    format!("<generator {}>", name)
+                }
+            },
         }
     }
 
+    //A list inside itself prints as [...] rather than recursing forever. Tuples
+    //come through here too, since one can hold a list that holds it
+    fn stringify_list(&self, list_ptr: u64, visiting: &mut Vec<u64>) -> String {
+        let (items, open, close) = match self.heap().deref(list_ptr) {
+            Object::List(items) => (items, "[", "]"),
+            Object::Tuple(items) => (items, "(", ")"),
+            _ => panic!("Expected a list"),
+        };
+        if visiting.contains(&list_ptr) {
+            return format!("{}...{}", open, close);
+        }
+
+        visiting.push(list_ptr);
+        let items: Vec<String> = items
+            .iter()
+            .map(|item| match *item {
+                Value::Object(ptr)
+                    if matches!(self.heap().deref(ptr), Object::List(_) | Object::Tuple(_)) =>
+                {
+                    self.stringify_list(ptr, visiting)
+                }
+                _ => self.stringify(*item),
+            })
+            .collect();
+        visiting.pop();
+        format!("{}{}{}", open, items.join(", "), close)
+    }
+
+    fn print(&mut self, value: Value) {
+        let s = self.stringify(value);
+        writeln!(self.out, "{}", s).expect("Failed to write program output");
+    }
+
     fn peek(&self, look_back: usize) -> &Value {
         &self.stack[self.stack.len() - 1 - look_back]
     }
 
-    #[inline]
-    fn add_to_heap(&mut self, object: Object) -> u64 {
-        self.heap_mut().add_to_heap(object)
+    #[inline]
+    fn add_to_heap(&mut self, object: Object) -> u64 {
+        if let Some(metrics) = self.metrics.as_mut() {
+            metrics.record_allocation(heap_report::type_name(&object));
+        }
+        self.heap_mut().add_to_heap(object)
+    }
+
+    //Takes a closure or a native function
+    fn callee_name(&self, callee_p: u64) -> &str {
+        let heap = self.virtual_memory.as_ref().unwrap();
+        match heap.deref(callee_p) {
+            Object::Closure(closure) => &heap.fun_deref(closure.function_pointer).name,
+            Object::NativeFunction(name, _, _) => name,
+            _ => "",
+        }
+    }
+
+    //Once the callee's frame is pushed, if it has one
+    fn record_call(&mut self, callee_p: u64, line: usize) {
+        if self.metrics.is_none() && self.hooks.is_none() {
+            return;
+        }
+        let name = String::from(self.callee_name(callee_p));
+        if let Some(metrics) = self.metrics.as_mut() {
+            metrics.record_call(&name);
+        }
+        let depth = self.depth();
+        if let Some(on_call) = self.hooks.as_mut().and_then(|hooks| hooks.on_call.as_mut()) {
+            on_call(&CallEvent {
+                name: &name,
+                depth,
+                line,
+            });
+        }
+    }
+
+    //Before the callee's frame is popped
+    fn record_return(&mut self, callee_p: u64, line: usize) {
+        if self.hooks.is_none() {
+            return;
+        }
+        let name = String::from(self.callee_name(callee_p));
+        let depth = self.depth();
+        if let Some(on_return) = self.hooks.as_mut().and_then(|hooks| hooks.on_return.as_mut()) {
+            on_return(&CallEvent {
+                name: &name,
+                depth,
+                line,
+            });
+        }
+    }
+
+    //Callers below the active frame, which is also its index
+    #[inline]
+    fn depth(&self) -> usize {
+        self.frame_count - 1
+    }
+
+    //Suspends caller, with its ip past the call, under the callee it made
+    #[inline]
+    fn push_frame(&mut self, caller: CallFrame, callee: CallFrame) {
+        self.frames[self.frame_count - 1] = caller;
+        self.frames[self.frame_count] = callee;
+        self.frame_count += 1;
+        self.activate(callee.closure_pointer);
+    }
+
+    //Drops the active frame and gives back its caller's
+    #[inline]
+    fn pop_frame(&mut self) -> CallFrame {
+        self.frame_count -= 1;
+        let caller = self.frames[self.frame_count - 1];
+        self.activate(caller.closure_pointer);
+        caller
+    }
+
+    #[inline]
+    //Defines a method on a class and on the classes that inherited it, which
+    //still have what the class had before unless they define their own. Each
+    //got the class's methods when it was declared, so this is how one that is
+    //reopened reaches them.
+    fn define_method(&mut self, class_ptr: u64, table: Table, symbol: Symbol, method_ptr: u64) {
+        let heap = self.heap_mut();
+        let class = heap.deref_mut(class_ptr).as_class_mut();
+        let old = class.define(table, symbol, method_ptr);
+        let mut pending = class.subclasses.clone();
+        let mut seen = Set::new();
+        while let Some(subclass_ptr) = pending.pop() {
+            if !seen.insert(subclass_ptr) {
+                continue;
+            }
+            let subclass = heap.deref_mut(subclass_ptr).as_class_mut();
+            let current = subclass.table(table).get(&symbol).copied();
+            if current.is_none() || current == old {
+                subclass.define(table, symbol, method_ptr);
+                pending.extend(subclass.subclasses.iter().copied());
+            }
+        }
+    }
+
+    fn activate(&mut self, closure_p: u64) {
+        self.active = ActiveChunk::new(closure_p, self.chunk(closure_p));
     }
 
+    //Slots come from the compiler, which only hands out ones already pushed,
+    //but loaded bytecode isn't verified so they're still bounds checked
+    #[inline]
     fn read_stack(&self, frame: &CallFrame, offset: usize) -> Value {
         self.stack[frame.stack_pointer + offset]
     }
 
+    #[inline]
     fn write_stack(&mut self, frame: &CallFrame, offset: usize, value: Value) {
         self.stack[frame.stack_pointer + offset] = value;
     }
 
-    fn call_lox_function<'a>(
-        &'a self,
+    fn call_lox_function(
+        &mut self,
         frame: &CallFrame,
-        closure: &Closure,
         closure_p: u64,
         num_args: usize,
     ) -> Result<(CallFrame, CallFrame), InterpreterError> {
         let line = self.current_line(&frame);
-        let fun_def = self.heap().fun_deref(closure.function_pointer);
+        let function_ptr = self.heap().closure_deref(closure_p).function_pointer;
+        let fun_def = self.heap().fun_deref(function_ptr);
+        let (arity, variadic) = (fun_def.arity, fun_def.variadic);
+
+        let num_args = if variadic {
+            self.collect_rest(arity, num_args, line)?
+        } else if arity != num_args {
+            return Err(InterpreterError::FunctionError(
+                line,
+                format!("Expected {} arguments but got {}", arity, num_args),
+            ));
+        } else {
+            num_args
+        };
+
+        self.closure_frame(frame, closure_p, num_args, line)
+    }
 
-        if fun_def.arity != num_args {
+    //Replaces the arguments from the rest parameter's position on with a list
+    //of them, returning how many arguments that leaves
+    fn collect_rest(
+        &mut self,
+        arity: usize,
+        num_args: usize,
+        line: usize,
+    ) -> Result<usize, InterpreterError> {
+        let required = arity - 1;
+        if num_args < required {
             return Err(InterpreterError::FunctionError(
                 line,
-                format!("Expected {} arguments but got {}", num_args, fun_def.arity),
+                format!("Expected at least {} arguments but got {}", required, num_args),
             ));
         }
+        let rest = self.stack.split_off(self.stack.len() - (num_args - required));
+        let list_ptr = self.add_to_heap(Object::List(rest));
+        self.push(Value::Object(list_ptr));
+        Ok(arity)
+    }
+
+    fn has_setters(&self, instance_ptr: u64) -> bool {
+        match self.heap().deref(instance_ptr) {
+            Object::Instance(instance) => {
+                !self.heap().class_deref(instance.class_ptr).setters.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    //Inline caches skip the arity check, which variadic functions can't
+    fn is_variadic(&self, closure_p: u64) -> bool {
+        let function_ptr = self.heap().closure_deref(closure_p).function_pointer;
+        self.heap().fun_deref(function_ptr).variadic
+    }
 
-        if self.call_frames.len() > 256 {
+    //The frames for a call whose arity has already been checked
+    fn closure_frame(
+        &self,
+        frame: &CallFrame,
+        closure_p: u64,
+        num_args: usize,
+        line: usize,
+    ) -> Result<(CallFrame, CallFrame), InterpreterError> {
+        if self.depth() > self.max_call_depth || self.stack.len() > self.stack_size {
             return Err(InterpreterError::FunctionError(
                 line,
                 String::from("Stack overflow"),
             ));
         }
 
+        trace!(function = %self.callee_name(closure_p), args = num_args, depth = self.frame_count, "call");
+
         let stack_pointer = self.stack.len() - (num_args + 1); // +1 for "this"
         let new_frame = CallFrame {
             closure_pointer: closure_p,
             ip: 0,
             stack_pointer,
+            namespace: self.heap().closure_deref(closure_p).namespace,
         };
         Ok((*frame, new_frame))
     }
@@ -553,7 +2153,7 @@ impl VM {
 
     fn capture_upvalue(&mut self, frame: &CallFrame, upvalue: Upvalue) -> u64 {
         if upvalue.is_local {
-            let call_frame_idx = self.call_frames.len(); //Use n+1 since the current frame is not added yet.
+            let call_frame_idx = self.depth();
             if let Some(ptr) = self.search_captured_upvalue(call_frame_idx, upvalue.index) {
                 ptr
             } else {
@@ -569,6 +2169,91 @@ impl VM {
         }
     }
 
+    //Moves the values of the frame's captured locals into their upvalues, giving
+    //back the slot and upvalue of each
+    fn close_frame_upvalues(&mut self, frame: &CallFrame) -> Vec<(usize, u64)> {
+        let call_frame_idx = self.depth();
+        let mut closed = vec![];
+        self.open_upvalues.retain(|(cf, s, ptr)| {
+            if *cf == call_frame_idx {
+                closed.push((*s, *ptr));
+                false
+            } else {
+                true
+            }
+        });
+        for (s, ptr) in closed.iter() {
+            let value = self.read_stack(frame, *s);
+            self.heap_mut().write(*ptr, Object::Value(value));
+        }
+        closed
+    }
+
+    //Drops the frame's values and callee for result and carries on in the frame
+    //below. True when that ends a call_method_now.
+    fn return_to_caller(&mut self, frame: &mut CallFrame, result: Value) -> bool {
+        if self.hooks.is_some() {
+            let chunk = self.chunk(frame.closure_pointer);
+            let line = chunk.line_numbers[frame.ip - 1];
+            self.record_return(frame.closure_pointer, line);
+        }
+
+        //Pop the function values off the stack.
+        while self.stack.len() > frame.stack_pointer {
+            self.pop();
+        }
+        self.pop(); //And the function address
+
+        self.push(result);
+        *frame = self.pop_frame();
+        self.reentry.last() == Some(&self.frame_count)
+    }
+
+    //Calls what's under the arguments, through the inline cache for closures
+    fn call_value(
+        &mut self,
+        frame: &mut CallFrame,
+        num_args: usize,
+    ) -> Result<(), InterpreterError> {
+        let line = self.current_line(frame);
+        let obj_ptr = if let Value::Object(obj_ptr) = self.peek(num_args + 1) {
+            *obj_ptr
+        } else {
+            return Err(InterpreterError::FunctionError(
+                line,
+                String::from("Attempt to call a value which is not a function"),
+            ));
+        };
+
+        let site = (frame.closure_pointer, frame.ip);
+        let cached = match self.inline_caches.get(site.0, site.1) {
+            Some(InlineCache::Closure { closure_ptr, arity }) => {
+                closure_ptr == obj_ptr && arity == num_args
+            }
+            _ => false,
+        };
+        if cached {
+            self.inline_caches.hits += 1;
+            let (old_frame, new_frame) = self.closure_frame(frame, obj_ptr, num_args, line)?;
+            self.push_frame(old_frame, new_frame);
+            self.record_call(obj_ptr, line);
+            *frame = new_frame;
+        } else {
+            self.inline_caches.misses += 1;
+            let is_closure = matches!(self.heap().deref(obj_ptr), Object::Closure(_))
+                && !self.is_variadic(obj_ptr);
+            *frame = self.call_object(frame, num_args, obj_ptr)?;
+            if is_closure {
+                let entry = InlineCache::Closure {
+                    closure_ptr: obj_ptr,
+                    arity: num_args,
+                };
+                self.inline_caches.insert(site.0, site.1, entry);
+            }
+        }
+        Ok(())
+    }
+
     fn call_object(
         &mut self,
         frame: &mut CallFrame,
@@ -579,36 +2264,47 @@ impl VM {
         let obj = self.heap().deref(obj_ptr);
 
         match obj {
-            Object::NativeFunction(_, body) => {
-                let body = *body;
-                let mut native_call_stack: Vec<Value> = vec![];
-                for _ in 0..num_args {
-                    let value = self.pop();
-                    native_call_stack.push(value);
+            Object::NativeFunction(_, arity, body) => {
+                let (arity, body) = (*arity, *body);
+                if arity != num_args && arity != VARIADIC {
+                    return Err(InterpreterError::FunctionError(
+                        line,
+                        format!("Expected {} arguments but got {}", arity, num_args),
+                    ));
                 }
-                let result = body(native_call_stack)?;
+                self.record_call(obj_ptr, line);
+                let args = self.stack.split_off(self.stack.len() - num_args);
+                self.pop(); //The ThisPlaceholder
+                self.pop(); //And the native itself
+                let result = body(self, &args).map_err(|e| match self.exiting.take() {
+                    Some(status) => InterpreterError::Exit(line, status),
+                    None => InterpreterError::FunctionError(line, e),
+                })?;
+                self.record_return(obj_ptr, line);
                 self.push(result);
                 Ok(*frame)
             }
-            Object::Closure(closure) => {
-                //Todo: upvalues
+            Object::Closure(_) => {
                 let (old_frame, new_frame) =
-                    self.call_lox_function(&frame, &closure, obj_ptr, num_args)?;
-                self.call_frames.push(old_frame);
+                    self.call_lox_function(&frame, obj_ptr, num_args)?;
+                self.push_frame(old_frame, new_frame);
+                self.record_call(obj_ptr, line);
                 Ok(new_frame)
             }
             Object::Class(class) => {
+                let init_addr = class.method(INIT);
+                let fields = self.heap_mut().new_fields();
                 let obj_instance = Object::Instance(Instance {
                     class_ptr: obj_ptr,
-                    fields: HashMap::new(),
+                    shape: EMPTY_SHAPE,
+                    fields,
                 });
-                let init_addr = class.methods.get(&String::from("init")).copied();
                 let addr = self.add_to_heap(obj_instance);
                 if let Some(closure_addr) = init_addr {
-                    let closure = self.heap().closure_deref(closure_addr);
                     let (old_frame, new_frame) =
-                        self.call_lox_function(&frame, closure, closure_addr, num_args)?;
-                    self.call_frames.push(old_frame);
+                        self.call_lox_function(&frame, closure_addr, num_args)?;
+                    self.push_frame(old_frame, new_frame);
+                    self.record_call(closure_addr, line);
                     self.write_stack(&new_frame, 0, Value::Object(addr));
                     Ok(new_frame)
                 } else if num_args != 0 {
@@ -618,17 +2314,18 @@ impl VM {
                     ));
                 } else {
                     self.pop(); //Remove the the ThisPlaceholder
+                    self.pop(); //And the class
                     self.push(Value::Object(addr));
                     Ok(*frame)
                 }
             }
             Object::BoundMethod(bound_method) => {
                 let closure_ptr = bound_method.closure_ptr;
-                let closure = self.heap().closure_deref(closure_ptr);
                 let receiver = bound_method.receiver; //Copy here to drop the ref to bound_method
                 let (old_frame, new_frame) =
-                    self.call_lox_function(&frame, closure, closure_ptr, num_args)?;
-                self.call_frames.push(old_frame);
+                    self.call_lox_function(&frame, closure_ptr, num_args)?;
+                self.push_frame(old_frame, new_frame);
+                self.record_call(closure_ptr, line);
                 self.write_stack(&new_frame, 0, receiver);
                 Ok(new_frame)
             }
@@ -641,148 +2338,753 @@ impl VM {
         }
     }
 
-    fn run(&mut self) -> Result<(), InterpreterError> {
-        let mut frame = self.call_frames.pop().unwrap();
+    fn trace_instruction(&mut self, frame: &CallFrame) {
+        let stack: String = self
+            .stack
+            .iter()
+            .map(|value| format!("[ {} ]", format_value(self.heap(), *value)))
+            .collect();
+        writeln!(self.out, "          {}", stack).expect("Failed to write trace");
+
+        let chunk = self.chunk(frame.closure_pointer);
+        if frame.ip < chunk.code.len() {
+            let instruction = disassemble_instruction(chunk, frame.ip, self.heap());
+            writeln!(self.out, "{}", instruction).expect("Failed to write trace");
+        }
+    }
+
+    fn check_pause(&mut self, frame: &CallFrame) -> Option<Status> {
+        let line = self
+            .chunk(frame.closure_pointer)
+            .line_numbers
+            .get(frame.ip)
+            .copied()?;
+        let depth = self.depth();
+        let reason = self.debugger.as_mut()?.should_pause(depth, line)?;
+        Some(Status::Paused(reason, line))
+    }
+
+    fn run(&mut self) -> Result<Status, InterpreterError> {
+        let mut frame = self.frames[self.depth()];
+        self.activate(frame.closure_pointer);
         loop {
-            if self.should_run_gc() {
-                self.collect_garbage(&frame);
+            match self.execute(&mut frame) {
+                Err(error) => match self.catch(error, 0) {
+                    Ok(handler_frame) => frame = handler_frame,
+                    Err(error) => {
+                        self.handlers.clear();
+                        self.importing.clear();
+                        self.frame_count = 0;
+                        return Err(error);
+                    }
+                },
+                Ok(Status::Finished) => {
+                    self.frame_count = 0;
+                    return Ok(Status::Finished);
+                }
+                paused => return paused,
             }
+        }
+    }
 
-            match self.consume(&mut frame) {
-                OpCode::EOF => return Ok(()),
-                OpCode::Return => {
-                    let result = self.pop();
-                    if self.call_frames.len() == 0 {
-                        return Ok(());
+    //Hands error to the innermost handler, if it's one of those opened with at
+    //least depth frames suspended, and returns the frame of its catch code
+    fn catch(
+        &mut self,
+        error: InterpreterError,
+        depth: usize,
+    ) -> Result<CallFrame, InterpreterError> {
+        //Limits are for whoever runs the script, so scripts can't catch them,
+        //and exit() leaves without running finally blocks
+        if matches!(error, InterpreterError::LimitError(_, _) | InterpreterError::Exit(_, _)) {
+            return Err(error);
+        }
+        let handler = match self.handlers.last() {
+            Some(handler) if handler.frames >= depth => self.handlers.pop().unwrap(),
+            _ => return Err(error),
+        };
+        let exception = match (self.exception.take(), &error) {
+            (Some(value), InterpreterError::Thrown(_, _)) => value,
+            _ => self.new_string(String::from(error.message())),
+        };
+        self.unwind(&handler);
+        self.importing.retain(|(_, depth)| *depth <= handler.frames);
+        self.push(exception);
+        self.frames[handler.frames] = handler.frame;
+        self.activate(handler.frame.closure_pointer);
+        Ok(handler.frame)
+    }
+
+    //Runs a method with no arguments to completion and gives back its result,
+    //for instructions like print that need it before they can go on. Errors
+    //the method doesn't catch itself end up with whatever handles frame's.
+    fn call_method_now(
+        &mut self,
+        frame: &CallFrame,
+        receiver: Value,
+        method_ptr: u64,
+    ) -> Result<Value, InterpreterError> {
+        let line = self.current_line(frame);
+        //Like a getter's, a copy of the receiver under the frame stands in for the callee
+        self.push(receiver);
+        self.push(receiver);
+        let (old_frame, mut callee) = self.call_lox_function(frame, method_ptr, 0)?;
+        self.push_frame(old_frame, callee);
+        self.record_call(method_ptr, line);
+
+        let depth = self.depth();
+        self.reentry.push(depth);
+        let result = loop {
+            match self.execute(&mut callee) {
+                Ok(Status::Finished) => break Ok(self.pop()),
+                //Pausing needs the outer loop, so the debugger skips this stop
+                Ok(Status::Paused(_, _)) => callee = self.frames[self.depth()],
+                Err(error) => match self.catch(error, depth) {
+                    Ok(handler_frame) => callee = handler_frame,
+                    Err(error) => break Err(error),
+                },
+            }
+        };
+        self.reentry.pop();
+        result
+    }
+
+    //What print and concatenation show for an instance whose class defines
+    //toString, which has to give back a string
+    fn call_to_string(
+        &mut self,
+        frame: &CallFrame,
+        value: Value,
+    ) -> Result<Option<Value>, InterpreterError> {
+        let method_ptr = match value {
+            Value::Object(ptr) => match self.heap().deref(ptr) {
+                Object::Instance(instance) => {
+                    let class = self.heap().class_deref(instance.class_ptr);
+                    let to_string = self.heap().symbols.get("toString");
+                    to_string.and_then(|symbol| class.methods.get(&symbol).copied())
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        let method_ptr = match method_ptr {
+            Some(method_ptr) => method_ptr,
+            None => return Ok(None),
+        };
+        let result = self.call_method_now(frame, value, method_ptr)?;
+        if self.deref_str_value(result).is_err() {
+            return Err(InterpreterError::TypeError(
+                self.current_line(frame),
+                format!("toString must return a string, not {}", self.stringify(result)),
+            ));
+        }
+        Ok(Some(result))
+    }
+
+    //Swaps the value distance down the stack for its toString result
+    fn apply_to_string(
+        &mut self,
+        frame: &CallFrame,
+        distance: usize,
+    ) -> Result<(), InterpreterError> {
+        let value = *self.peek(distance);
+        if let Some(s) = self.call_to_string(frame, value)? {
+            let idx = self.stack.len() - 1 - distance;
+            self.stack[idx] = s;
+        }
+        Ok(())
+    }
+
+    //Drops every frame and value above where the handler's try started,
+    //closing the upvalues that pointed into them
+    fn unwind(&mut self, handler: &Handler) {
+        let frames = &self.frames;
+        let mut closed = vec![];
+        self.open_upvalues.retain(|(cf, s, ptr)| {
+            let stack_pointer = frames[*cf].stack_pointer;
+            if stack_pointer + s < handler.stack_len {
+                return true;
+            }
+            closed.push((stack_pointer + s, *ptr));
+            false
+        });
+        for (idx, ptr) in closed {
+            let value = self.stack[idx];
+            self.heap_mut().write(ptr, Object::Value(value));
+        }
+
+        //Generators the error left mid-run can't be picked up again. The frame
+        //running the try itself carries on.
+        let unwound = handler.frames + 1..self.frame_count;
+        let stack_pointers: Vec<usize> = unwound.map(|f| self.frames[f].stack_pointer).collect();
+        //Frames that aren't calls, like a module's, start at the bottom with no callee
+        for stack_pointer in stack_pointers.into_iter().filter(|sp| *sp > 0) {
+            if let Value::Object(callee) = self.stack[stack_pointer - 1] {
+                if let Object::Generator(generator) = self.heap_mut().deref_mut(callee) {
+                    generator.state = GeneratorState::Done;
+                }
+            }
+        }
+
+        self.stack.truncate(handler.stack_len);
+        self.frame_count = handler.frames + 1;
+    }
+
+    fn namespace(&self, namespace: usize) -> &Globals {
+        match namespace {
+            0 => &self.globals,
+            n => &self.namespaces[n - 1].globals,
+        }
+    }
+
+    fn namespace_mut(&mut self, namespace: usize) -> &mut Globals {
+        match namespace {
+            0 => &mut self.globals,
+            n => &mut self.namespaces[n - 1].globals,
+        }
+    }
+
+    //For names that weren't resolved at compile time, like a module's exports
+    fn global_named(&self, namespace: usize, name: &str) -> Option<Value> {
+        let slot = self.heap().global_names.get(name)?;
+        self.namespace(namespace).get(slot)
+    }
+
+    // Starts running the module at path in a frame of its own, or pushes it
+    // straight away if it already ran. finish_import picks up at its end.
+    #[cfg(feature = "std")]
+    fn import(&mut self, frame: &mut CallFrame, path: &str) -> Result<(), InterpreterError> {
+        let line = self.current_line(frame);
+        let error = |msg: String| InterpreterError::ImportError(line, msg);
+        let importer = match frame.namespace {
+            0 => self.script_path.as_deref(),
+            n => Some(self.namespaces[n - 1].path.as_str()),
+        };
+        let resolved = modules::resolve(importer, path, &self.module_search_path).map_err(error)?;
+        if let Some(module) = self.module_cache.get(&resolved).copied() {
+            self.push(module);
+            return Ok(());
+        }
+        let running = self.importing.iter().any(|(running, _)| *running == resolved);
+        if running || self.script_path.as_deref() == Some(resolved.as_str()) {
+            return Err(error(format!("Circular import of {}", path)));
+        }
+        if self.depth() > self.max_call_depth {
+            return Err(InterpreterError::FunctionError(line, String::from("Stack overflow")));
+        }
+
+        let main = self.compile_module(&resolved).map_err(error)?;
+        //Modules start out with the natives and nothing else
+        let mut natives = Globals::new();
+        for (slot, value) in self.globals.iter() {
+            if let Value::Object(ptr) = value {
+                if let Object::NativeFunction(..) = self.heap().deref(ptr) {
+                    natives.define(slot, value);
+                }
+            }
+        }
+        self.namespaces.push(Namespace {
+            path: resolved.clone(),
+            globals: natives,
+        });
+        let namespace = self.namespaces.len();
+
+        //The module sits under its frame the way a callee does
+        let module = Object::Module(Module {
+            name: String::from(Module::default_name(path)),
+            namespace,
+        });
+        let module_ptr = self.add_to_heap(module);
+        self.push(Value::Object(module_ptr));
+        let fp = self.add_to_heap(Object::Function(main));
+        let closure_p = self.add_to_heap(Object::Closure(Closure {
+            function_pointer: fp,
+            closed_values: vec![],
+            namespace,
+        }));
+
+        let callee = CallFrame {
+            closure_pointer: closure_p,
+            ip: 0,
+            stack_pointer: self.stack.len(),
+            namespace,
+        };
+        self.push_frame(*frame, callee);
+        self.importing.push((resolved, self.depth()));
+        *frame = callee;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn import(&mut self, frame: &mut CallFrame, _: &str) -> Result<(), InterpreterError> {
+        Err(InterpreterError::ImportError(
+            self.current_line(frame),
+            String::from("Modules need the std feature"),
+        ))
+    }
+
+    #[cfg(feature = "std")]
+    fn compile_module(&mut self, path: &str) -> Result<Function, String> {
+        let source = modules::read(path)?;
+        let tokens = scan_tokens(&source).map_err(|e| format!("{}: {}", path, e))?;
+        let mut compiler = Compiler::new(tokens, self.take_virtual_memory());
+        let result = compiler.compile();
+        self.give_virtual_memory(compiler.heap);
+        result.map_err(|errors| {
+            let errors: Vec<String> = errors
+                .iter()
+                .map(|e| format!("{}:{}: {}", path, e.line(), e.message()))
+                .collect();
+            errors.join("\n")
+        })
+    }
+
+    //A module's top level ran to the end, so the importer gets the module
+    fn finish_import(&mut self, frame: &mut CallFrame) {
+        self.stack.truncate(frame.stack_pointer);
+        let (path, _) = self.importing.pop().unwrap();
+        self.module_cache.insert(path, *self.peek(0));
+        *frame = self.pop_frame();
+    }
+
+    fn execute(&mut self, frame: &mut CallFrame) -> Result<Status, InterpreterError> {
+        loop {
+            if self.debugger.is_some() {
+                if let Some(status) = self.check_pause(frame) {
+                    let depth = self.depth();
+                    self.frames[depth] = *frame;
+                    return Ok(status);
+                }
+            }
+
+            if self.marking.is_some() {
+                self.mark_step();
+            } else if self.should_run_gc() {
+                self.collect_garbage();
+            }
+
+            if self.trace {
+                self.trace_instruction(frame);
+            }
+
+            if let Some(fuel) = self.fuel.as_mut() {
+                if *fuel == 0 {
+                    let chunk = self.chunk(frame.closure_pointer);
+                    let line = chunk.line_numbers.get(frame.ip).copied().unwrap_or(0);
+                    return Err(InterpreterError::LimitError(
+                        line,
+                        String::from("Ran out of fuel"),
+                    ));
+                }
+                *fuel -= 1;
+            }
+
+            if let Some(recent) = self.recent.as_mut() {
+                if recent.len() == RECENT_INSTRUCTIONS {
+                    recent.pop_front();
+                }
+                recent.push_back((frame.closure_pointer, frame.ip));
+            }
+
+            if let Some(hooks) = self.hooks.as_mut() {
+                if let Some(on_instruction) = hooks.on_instruction.as_mut() {
+                    if let Err(message) = on_instruction(frame.ip) {
+                        let chunk = self.chunk(frame.closure_pointer);
+                        let line = chunk.line_numbers.get(frame.ip).copied().unwrap_or(0);
+                        return Err(InterpreterError::LimitError(line, message));
                     }
+                }
+            }
 
-                    let mut to_open_upvalues: Vec<(usize, usize, u64)> = vec![];
-                    let mut to_remove: Vec<(usize, usize, u64)> = vec![];
+            //Every frame starts at 0, so this counts calls
+            if self.tiering.is_some() && frame.ip == 0 {
+                self.heat(frame);
+                self.run_native(frame);
+            }
 
-                    let call_frame_idx = self.call_frames.len();
-                    for (cf, s, ptr) in self.open_upvalues.iter() {
-                        if *cf == call_frame_idx {
-                            to_remove.push((*cf, *s, *ptr));
-                        } else {
-                            to_open_upvalues.push((*cf, *s, *ptr));
+            if let Some(profile) = self.profile.as_mut() {
+                let heap = self.virtual_memory.as_ref().unwrap();
+                profile.count(heap, frame.closure_pointer, frame.ip);
+            }
+
+            if self.stack.len() >= self.stack_size {
+                let line = self.current_line(frame);
+                return Err(InterpreterError::FunctionError(line, String::from("Stack overflow")));
+            }
+
+            let op = self.consume(frame);
+            if let Some(metrics) = self.metrics.as_mut() {
+                metrics.record_opcode(op.name());
+            }
+            match op {
+                OpCode::EOF => {
+                    let depth = self.depth();
+                    if self.importing.last().map(|(_, d)| *d) != Some(depth) {
+                        return Ok(Status::Finished);
+                    }
+                    self.finish_import(frame);
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    if self.depth() == 0 {
+                        return Ok(Status::Finished);
+                    }
+                    trace!(depth = self.depth(), "return");
+                    self.close_frame_upvalues(frame);
+                    if self.return_to_caller(frame, result) {
+                        return Ok(Status::Finished);
+                    }
+                    if self.tiering.is_some() {
+                        self.run_native(frame);
+                    }
+                }
+                OpCode::Generator => {
+                    //Nothing has run yet, so there are no upvalues to close
+                    let generator = Generator {
+                        closure_ptr: frame.closure_pointer,
+                        ip: frame.ip,
+                        slots: self.stack.split_off(frame.stack_pointer),
+                        captured: vec![],
+                        state: GeneratorState::Suspended,
+                    };
+                    let generator_ptr = self.add_to_heap(Object::Generator(generator));
+                    if self.return_to_caller(frame, Value::Object(generator_ptr)) {
+                        return Ok(Status::Finished);
+                    }
+                }
+                OpCode::Yield => {
+                    let value = self.pop();
+                    let captured = self.close_frame_upvalues(frame);
+                    let slots = self.stack.split_off(frame.stack_pointer);
+                    let generator = self.running_generator(frame);
+                    generator.ip = frame.ip;
+                    generator.slots = slots;
+                    generator.captured = captured;
+                    generator.state = GeneratorState::Suspended;
+                    if self.return_to_caller(frame, value) {
+                        return Ok(Status::Finished);
+                    }
+                }
+                OpCode::GeneratorReturn => {
+                    let result = self.pop();
+                    self.close_frame_upvalues(frame);
+                    self.running_generator(frame).state = GeneratorState::Done;
+                    if self.return_to_caller(frame, result) {
+                        return Ok(Status::Finished);
+                    }
+                }
+                OpCode::Print => {
+                    self.apply_to_string(frame, 0)?;
+                    let value = self.pop();
+                    self.print(value);
+                }
+                OpCode::Echo => {
+                    let value = self.pop();
+                    let s = self.inspect(value);
+                    writeln!(self.out, "{}", s).expect("Failed to write program output");
+                }
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::Dup => {
+                    let value = *self.peek(0);
+                    self.push(value);
+                }
+                OpCode::Constant(address) => {
+                    let val = self.read_constant(frame, address);
+                    self.push(val);
+                }
+                OpCode::Negate => match self.pop() {
+                    Value::Number(n) => self.push(Value::Number(-n)),
+                    Value::Integer(n) => match n.checked_neg() {
+                        Some(n) => self.push(Value::Integer(n)),
+                        None => self.push(Value::Number(-(n as f64))),
+                    },
+                    _ => {
+                        return Err(InterpreterError::TypeError(
+                            self.current_line(frame),
+                            String::from("Operand must be a number."),
+                        ))
+                    }
+                },
+                OpCode::Add => {
+                    self.add(frame)?;
+                }
+                OpCode::Stringify => {
+                    self.apply_to_string(frame, 0)?;
+                    //Strings are left alone rather than copied
+                    let value = *self.peek(0);
+                    if self.deref_str_value(value).is_err() {
+                        self.pop();
+                        let s = self.stringify(value);
+                        let str_ptr = self.add_to_heap(Object::String(s));
+                        self.push(Value::Object(str_ptr));
+                    }
+                }
+                OpCode::BuildList(count) => {
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    let list_ptr = self.add_to_heap(Object::List(items));
+                    self.push(Value::Object(list_ptr));
+                }
+                OpCode::BuildTuple(count) => {
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    let tuple_ptr = self.add_to_heap(Object::Tuple(items));
+                    self.push(Value::Object(tuple_ptr));
+                }
+                OpCode::Unpack(count) => {
+                    let value = self.pop();
+                    let items = match value {
+                        Value::Object(ptr) => match self.heap().deref(ptr) {
+                            Object::Tuple(items) => Some(items.clone()),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    //Anything that isn't a tuple is a single value
+                    let got = items.as_ref().map_or(1, |items| items.len());
+                    match items {
+                        Some(items) if got == count => {
+                            self.reserve_stack(count, self.current_line(frame))?;
+                            self.stack.extend(items);
+                        }
+                        _ => {
+                            return Err(InterpreterError::TypeError(
+                                self.current_line(frame),
+                                format!("Expected {} values to unpack but got {}", count, got),
+                            ))
+                        }
+                    }
+                }
+                OpCode::UnpackList(count) => {
+                    let value = self.pop();
+                    let items = match value {
+                        Value::Object(ptr) => match self.heap().deref(ptr) {
+                            Object::List(items) => Some(items.clone()),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    match items {
+                        Some(items) if items.len() == count => {
+                            self.reserve_stack(count, self.current_line(frame))?;
+                            self.stack.extend(items);
+                        }
+                        _ => {
+                            let message = match items {
+                                Some(items) => format!(
+                                    "Expected a list of {} items but got {}",
+                                    count,
+                                    items.len()
+                                ),
+                                None => {
+                                    format!("Can't destructure {} as a list", self.stringify(value))
+                                }
+                            };
+                            let line = self.current_line(frame);
+                            return Err(InterpreterError::TypeError(line, message));
+                        }
+                    }
+                }
+                OpCode::UnpackFields(first, count) => {
+                    let line = self.current_line(frame);
+                    let value = self.pop();
+                    let instance = match value {
+                        Value::Object(ptr) => match self.heap().deref(ptr) {
+                            Object::Instance(instance) => Some(instance),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    let instance = match instance {
+                        Some(instance) => instance,
+                        None => {
+                            let value = self.stringify(value);
+                            let message = format!("Can't destructure {} as an instance", value);
+                            return Err(InterpreterError::TypeError(line, message));
+                        }
+                    };
+                    let mut fields = Vec::with_capacity(count);
+                    for idx in first..first + count {
+                        let name_ptr = u64::as_val_or_panic(self.read_constant(frame, idx));
+                        let name = self.heap().string_deref(name_ptr);
+                        let slot = match self.heap().symbols.get(name) {
+                            Some(symbol) => self.heap().shapes.slot(instance.shape, symbol),
+                            None => None,
+                        };
+                        match slot {
+                            Some(slot) => fields.push(instance.fields[slot]),
+                            None => {
+                                let message = format!(
+                                    "{} has no field {} to destructure",
+                                    self.stringify(value),
+                                    name
+                                );
+                                return Err(InterpreterError::NameError(line, message));
+                            }
+                        }
+                    }
+                    self.reserve_stack(count, line)?;
+                    self.stack.extend(fields);
+                }
+                OpCode::Index => {
+                    let line = self.current_line(frame);
+                    let index = self.pop();
+                    let list = self.pop();
+                    let (list_ptr, idx) = self.list_slot(list, index, line)?;
+                    let value = match self.heap().deref(list_ptr) {
+                        Object::List(items) | Object::Tuple(items) => items[idx],
+                        _ => unreachable!(),
+                    };
+                    self.push(value);
+                }
+                OpCode::IndexSet => {
+                    let line = self.current_line(frame);
+                    let value = self.pop();
+                    let index = self.pop();
+                    let list = self.pop();
+                    let (list_ptr, idx) = self.list_slot(list, index, line)?;
+                    match self.heap_mut().deref_mut(list_ptr) {
+                        Object::List(items) => items[idx] = value,
+                        _ => {
+                            let message = String::from("Tuples can't be changed");
+                            return Err(InterpreterError::TypeError(line, message));
                         }
                     }
-
-                    for (_, s, ptr) in to_remove.iter() {
-                        let value = self.read_stack(&frame, *s);
-                        self.heap_mut().write(*ptr, Object::Value(value));
-                    }
-
-                    self.open_upvalues = to_open_upvalues;
-
-                    //Pop the function values off the stack.
-                    while self.stack.len() > frame.stack_pointer {
+                    self.push(value);
+                }
+                OpCode::AddConstant(idx) => {
+                    let b = self.read_constant(frame, idx);
+                    if let Some(sum) = Self::add_numbers(*self.peek(0), b) {
                         self.pop();
+                        self.push(sum);
+                    } else {
+                        self.push(b);
+                        self.add(frame)?;
                     }
-                    self.pop(); //And the function address
-
-                    self.push(result);
-                    frame = self.call_frames.pop().unwrap();
-                }
-                OpCode::Print => {
-                    let value = self.pop();
-                    self.print(value);
                 }
-                OpCode::Pop => {
-                    self.pop();
+                OpCode::AddLocal(slot) => {
+                    frame.ip += 1;
+                    let b = self.read_stack(frame, slot);
+                    if let Some(sum) = Self::add_numbers(*self.peek(0), b) {
+                        self.pop();
+                        self.push(sum);
+                    } else {
+                        self.push(b);
+                        self.add(frame)?;
+                    }
                 }
-                OpCode::Constant(address) => {
-                    let val = self.read_constant(&frame, address);
-                    self.push(val);
+                OpCode::IncrementLocal(slot) => {
+                    let value = self.read_stack(frame, slot);
+                    let result = match value {
+                        Value::Integer(n) => n.checked_add(1).map(Value::Integer),
+                        Value::Number(n) => Some(Value::Number(n + 1.0)),
+                        _ => None,
+                    };
+                    let result = if let Some(result) = result {
+                        self.push(result);
+                        result
+                    } else {
+                        //Fails or overflows the same way i + 1 would
+                        self.push(value);
+                        self.push(Value::Integer(1));
+                        self.add(frame)?;
+                        *self.peek(0)
+                    };
+                    self.write_stack(frame, slot, result);
                 }
-                OpCode::Negate => match self.pop() {
-                    Value::Number(n) => self.push(Value::Number(-n)),
-                    _ => {
-                        return Err(InterpreterError::TypeError(
-                            self.current_line(&frame),
-                            String::from("Operand must be a number."),
-                        ))
-                    }
-                },
-                OpCode::Add => {
-                    let a = self.peek(0);
-                    let b = self.peek(1);
-                    match (a, b) {
-                        (Value::Object(_), Value::Object(_)) => {
-                            self.string_concat()?;
-                        }
-                        _ => self.binary_op(&frame, |a: f64, b: f64| a + b)?,
+                OpCode::CompareLocalConstant(slot, idx, comparison) => {
+                    let a = self.read_stack(frame, slot);
+                    let b = self.read_constant(frame, idx);
+                    let result = match (a, b, comparison) {
+                        (Value::Integer(a), Value::Integer(b), Comparison::Less) => Some(a < b),
+                        (Value::Integer(a), Value::Integer(b), Comparison::Greater) => Some(a > b),
+                        (Value::Number(a), Value::Number(b), Comparison::Less) => Some(a < b),
+                        (Value::Number(a), Value::Number(b), Comparison::Greater) => Some(a > b),
+                        _ => None,
                     };
+                    if let Some(result) = result {
+                        self.push(Value::Boolean(result));
+                    } else {
+                        self.push(a);
+                        self.push(b);
+                        match comparison {
+                            Comparison::Less => self.less(frame)?,
+                            Comparison::Greater => self.greater(frame)?,
+                        }
+                    }
                 }
                 OpCode::Subtract => {
-                    self.binary_op(&frame, |a: f64, b: f64| a - b)?;
+                    self.numeric_op(frame, i64::checked_sub, |a: f64, b: f64| a - b)?;
                 }
                 OpCode::Multiply => {
-                    self.binary_op(&frame, |a: f64, b: f64| a * b)?;
+                    self.numeric_op(frame, i64::checked_mul, |a: f64, b: f64| a * b)?;
                 }
+                //Always a float, so 1 / 2 is still 0.5
                 OpCode::Divide => {
-                    self.binary_op(&frame, |a: f64, b: f64| a / b)?;
+                    self.binary_op(frame, |a: f64, b: f64| a / b)?;
+                }
+                OpCode::BitAnd => self.binary_op(frame, |a: i64, b: i64| a & b)?,
+                OpCode::BitOr => self.binary_op(frame, |a: i64, b: i64| a | b)?,
+                OpCode::BitXor => self.binary_op(frame, |a: i64, b: i64| a ^ b)?,
+                OpCode::BitNot => {
+                    let line = self.current_line(frame);
+                    let n = i64::as_val(self.pop(), line)?;
+                    self.push(Value::Integer(!n));
+                }
+                //Only the low 6 bits of the count are used, like Java
+                OpCode::ShiftLeft => {
+                    self.binary_op(frame, |a: i64, b: i64| a.wrapping_shl(b as u32))?
+                }
+                OpCode::ShiftRight => {
+                    self.binary_op(frame, |a: i64, b: i64| a.wrapping_shr(b as u32))?
                 }
                 OpCode::Nil => {
-                    self.stack.push(Value::Nil);
+                    self.push(Value::Nil);
                 }
-                OpCode::True => self.stack.push(Value::Boolean(true)),
-                OpCode::False => self.stack.push(Value::Boolean(false)),
+                OpCode::True => self.push(Value::Boolean(true)),
+                OpCode::False => self.push(Value::Boolean(false)),
                 OpCode::Not => {
                     let b = VM::lox_bool_coercion(self.pop());
-                    self.stack.push(Value::Boolean(!b));
+                    self.push(Value::Boolean(!b));
                 }
                 OpCode::Equal => {
                     let b = self.pop();
                     let a = self.pop();
                     let result = self.values_equal(a, b);
-                    self.stack.push(Value::Boolean(result));
+                    self.push(Value::Boolean(result));
                 }
-                OpCode::Greater => {
-                    self.binary_op(&frame, |a: f64, b: f64| a > b)?;
-                }
-                OpCode::Less => {
-                    self.binary_op(&frame, |a: f64, b: f64| a < b)?;
+                OpCode::IsType(const_idx) => {
+                    let name_ptr = u64::as_val_or_panic(self.read_constant(frame, const_idx));
+                    let value = self.pop();
+                    let result = self.is_type(value, self.heap().string_deref(name_ptr));
+                    self.push(Value::Boolean(result));
                 }
-                OpCode::DefineGlobal(string_idx) => {
-                    let name_ptr = u64::as_val_or_panic(self.read_constant(&frame, string_idx));
-                    let name = self.heap().string_deref(name_ptr).clone();
+                OpCode::Greater => self.greater(frame)?,
+                OpCode::Less => self.less(frame)?,
+                OpCode::DefineGlobal(_, slot) => {
                     let value = self.pop();
-                    self.globals.insert(name, value);
-                }
-                OpCode::GetGlobal(string_idx) => {
-                    let name_ptr = u64::as_val_or_panic(self.read_constant(&frame, string_idx));
-                    let name = self.heap().string_deref(name_ptr);
-                    if !self.globals.contains_key(name) {
-                        return Err(InterpreterError::NameError(
-                            self.current_line(&frame),
-                            format!("Undefined variable {}", name),
-                        ));
-                    } else {
-                        let value = self.globals[name];
-                        self.push(value);
+                    self.namespace_mut(frame.namespace).define(slot, value);
+                }
+                OpCode::GetGlobal(string_idx, slot) => {
+                    match self.namespace(frame.namespace).get(slot) {
+                        Some(value) => self.push(value),
+                        None => return Err(self.undefined_variable(frame, string_idx)),
                     }
                 }
-                OpCode::SetGlobal(string_idx) => {
-                    let name_ptr = u64::as_val_or_panic(self.read_constant(&frame, string_idx));
-                    let name = self.heap().string_deref(name_ptr).clone();
-                    if !self.globals.contains_key(&name) {
-                        return Err(InterpreterError::NameError(
-                            self.current_line(&frame),
-                            format!("Undefined variable {}", name),
-                        ));
-                    } else {
-                        let value = *self.peek(0);
-                        self.globals.insert(name, value);
+                OpCode::SetGlobal(string_idx, slot) => {
+                    let value = *self.peek(0);
+                    match self.namespace_mut(frame.namespace).get_mut(slot) {
+                        Some(global) => *global = value,
+                        None => return Err(self.undefined_variable(frame, string_idx)),
                     }
                 }
                 OpCode::GetLocal(slot) => {
-                    let value = self.read_stack(&frame, slot);
+                    let value = self.read_stack(frame, slot);
                     self.push(value);
                 }
                 OpCode::SetLocal(slot) => {
                     let value = self.peek(0).clone();
-                    self.write_stack(&frame, slot, value);
+                    self.write_stack(frame, slot, value);
                 }
                 OpCode::Jump(offset) => {
                     frame.ip += offset;
@@ -792,28 +3094,48 @@ impl VM {
                         frame.ip += offset;
                     }
                 }
+                OpCode::Try(offset) => self.handlers.push(Handler {
+                    frames: self.depth(),
+                    frame: CallFrame {
+                        ip: frame.ip + offset,
+                        ..*frame
+                    },
+                    stack_len: self.stack.len(),
+                }),
+                OpCode::EndTry => {
+                    self.handlers.pop();
+                }
+                OpCode::Import(const_idx) => {
+                    let path_ptr = u64::as_val_or_panic(self.read_constant(frame, const_idx));
+                    let path = self.heap().string_deref(path_ptr).clone();
+                    self.import(frame, &path)?;
+                }
+                OpCode::Throw => {
+                    let value = self.pop();
+                    let line = self.chunk(frame.closure_pointer).line_numbers[frame.ip - 1];
+                    self.exception = Some(value);
+                    return Err(InterpreterError::Thrown(line, self.stringify(value)));
+                }
                 OpCode::Loop(offset) => {
                     frame.ip -= offset;
+                    if self.tiering.is_some() {
+                        self.heat(frame);
+                        self.run_native(frame);
+                    }
                 }
-                OpCode::Call(num_args) => {
-                    let line = self.current_line(&frame);
-                    let obj_ptr = if let Value::Object(obj_ptr) = self.peek(num_args + 1) {
-                        *obj_ptr
-                    } else {
-                        return Err(InterpreterError::FunctionError(
-                            line,
-                            String::from("Attempt to call a value which is not a function"),
-                        ));
-                    };
-
-                    frame = self.call_object(&mut frame, num_args, obj_ptr)?;
+                OpCode::Call(num_args) => self.call_value(frame, num_args)?,
+                OpCode::CallConstant(idx, num_args) => {
+                    frame.ip += 1;
+                    let argument = self.read_constant(frame, idx);
+                    self.push(argument);
+                    self.call_value(frame, num_args)?;
                 }
                 OpCode::Closure(idx, num_upvalues) => {
-                    if let Value::Object(function_pointer) = self.read_constant(&frame, idx) {
+                    if let Value::Object(function_pointer) = self.read_constant(frame, idx) {
                         let mut closed_values: Vec<u64> = vec![];
                         for _i in 0..num_upvalues {
-                            if let OpCode::Upvalue(upvalue) = self.consume(&mut frame) {
-                                closed_values.push(self.capture_upvalue(&frame, upvalue));
+                            if let OpCode::Upvalue(upvalue) = self.consume(frame) {
+                                closed_values.push(self.capture_upvalue(frame, upvalue));
                             } else {
                                 panic!("Expected upvalue op");
                             }
@@ -821,6 +3143,7 @@ impl VM {
                         let closure_addr = self.add_to_heap(Object::Closure(Closure {
                             function_pointer,
                             closed_values,
+                            namespace: frame.namespace,
                         }));
                         self.push(Value::Object(closure_addr));
                     } else {
@@ -828,53 +3151,130 @@ impl VM {
                     }
                 }
                 OpCode::GetUpValue(value_index) => {
-                    let value = self.get_closed_value(&frame, value_index);
+                    let value = self.get_closed_value(frame, value_index);
                     self.push(value);
                 }
                 OpCode::SetUpValue(value_index) => {
                     let value = *self.peek(0);
-                    self.set_closed_value(&frame, value_index, value);
+                    self.set_closed_value(frame, value_index, value);
                 }
                 OpCode::Upvalue(_) => {
                     panic!("Upvalue instruction should be handled by closure instruction")
                 }
+                OpCode::GetEnclosing(slot) => {
+                    //The compiler only emits these in functions called straight from their definer
+                    let caller = self.frames[self.depth() - 1];
+                    let value = self.read_stack(&caller, slot);
+                    self.push(value);
+                }
+                OpCode::SetEnclosing(slot) => {
+                    let caller = self.frames[self.depth() - 1];
+                    let value = *self.peek(0);
+                    self.write_stack(&caller, slot, value);
+                }
                 OpCode::CloseUpvalue => {
                     let value = self.pop();
-                    let call_frame_idx = self.call_frames.len();
+                    let call_frame_idx = self.depth();
                     let slot = self.stack.len() - frame.stack_pointer;
 
                     let ptr = self.remove_open_upvalue(call_frame_idx, slot);
                     self.heap_mut().write(ptr, Object::Value(value));
                 }
                 OpCode::Class(const_idx) => {
-                    let value = self.read_constant(&frame, const_idx);
+                    let value = self.read_constant(frame, const_idx);
                     let ptr = u64::as_val_or_panic(value);
                     let name = self.heap().string_deref(ptr).clone();
-                    let new_class = Object::Class(Class {
-                        name,
-                        methods: HashMap::new(),
-                    });
+                    let new_class = Object::Class(Class::new(name));
                     let addr = self.add_to_heap(new_class);
                     self.push(Value::Object(addr));
                 }
-                OpCode::GetProperty(const_idx) => {
-                    let line = self.current_line(&frame);
-                    let name_ptr = u64::as_val_or_panic(self.read_constant(&frame, const_idx));
-                    let name = self.heap().string_deref(name_ptr).clone(); //Can we eliminate this clone?
+                OpCode::OpenClass(const_idx) => {
+                    let value = self.read_constant(frame, const_idx);
+                    let ptr = u64::as_val_or_panic(value);
+                    let name = self.heap().string_deref(ptr);
+                    let addr = match self.global_named(frame.namespace, name) {
+                        Some(Value::Object(addr))
+                            if matches!(self.heap().deref(addr), Object::Class(_)) =>
+                        {
+                            addr
+                        }
+                        _ => {
+                            let new_class = Object::Class(Class::new(name.clone()));
+                            self.add_to_heap(new_class)
+                        }
+                    };
+                    self.push(Value::Object(addr));
+                }
+                OpCode::GetProperty(_, symbol) => {
+                    let line = self.current_line(frame);
+                    let site = (frame.closure_pointer, frame.ip);
+                    let instance_ptr = u64::as_val_or_panic(*self.peek(0));
+                    let cached = match self.inline_caches.get(site.0, site.1) {
+                        Some(InlineCache::Field { shape, slot }) => {
+                            match self.heap().deref(instance_ptr) {
+                                Object::Instance(instance) if instance.shape == shape => {
+                                    Some(instance.fields[slot])
+                                }
+                                _ => None,
+                            }
+                        }
+                        Some(InlineCache::BoundMethod { shape, class_ptr, closure_ptr }) => {
+                            let hit = matches!(self.heap().deref(instance_ptr),
+                                Object::Instance(instance)
+                                    if instance.shape == shape && instance.class_ptr == class_ptr);
+                            if hit {
+                                let bound_method = Object::BoundMethod(BoundMethod {
+                                    receiver: Value::Object(instance_ptr),
+                                    closure_ptr,
+                                });
+                                Some(Value::Object(self.add_to_heap(bound_method)))
+                            } else {
+                                None
+                            }
+                        }
+                        _ => None,
+                    };
+                    if let Some(value) = cached {
+                        self.inline_caches.hits += 1;
+                        self.pop();
+                        self.push(value);
+                        continue;
+                    }
+                    self.inline_caches.misses += 1;
 
-                    let instance_value = self.pop();
-                    let instance_ptr = u64::as_val_or_panic(instance_value);
+                    self.pop();
+                    //Borrowed from the heap, so nothing below may allocate while it's in use
+                    let name = self.heap().symbols.name(symbol);
                     let object = self.heap().deref(instance_ptr);
                     if let Object::Instance(instance) = object {
-                        let field_val = instance.fields.get(&name).copied();
-                        if let Some(value) = field_val {
+                        let shape = instance.shape;
+                        let class_ptr = instance.class_ptr;
+                        let slot = self.heap().shapes.slot(shape, symbol);
+                        if let Some(slot) = slot {
                             //Read the field
+                            let value = instance.fields[slot];
+                            let entry = InlineCache::Field { shape, slot };
+                            self.inline_caches.insert(site.0, site.1, entry);
                             self.push(value);
                         } else {
+                            let class = self.heap().class_deref(class_ptr);
+                            if let Some(getter_ptr) = class.getters.get(&symbol).copied() {
+                                //A copy of the instance under the frame stands in for the callee
+                                self.push(Value::Object(instance_ptr));
+                                self.push(Value::Object(instance_ptr));
+                                let (old_frame, new_frame) =
+                                    self.call_lox_function(frame, getter_ptr, 0)?;
+                                self.push_frame(old_frame, new_frame);
+                                self.record_call(getter_ptr, line);
+                                *frame = new_frame;
+                                continue;
+                            }
                             //check if there's a method
-                            let class = self.heap().class_deref(instance.class_ptr);
-                            let closure_ptr = class.methods.get(&name).copied();
+                            let closure_ptr = class.methods.get(&symbol).copied();
                             if let Some(closure_ptr) = closure_ptr {
+                                let entry =
+                                    InlineCache::BoundMethod { shape, class_ptr, closure_ptr };
+                                self.inline_caches.insert(site.0, site.1, entry);
                                 let bound_method = Object::BoundMethod(BoundMethod {
                                     receiver: Value::Object(instance_ptr),
                                     closure_ptr,
@@ -888,6 +3288,48 @@ impl VM {
                                 ));
                             }
                         };
+                    } else if let Object::Class(class) = object {
+                        match class.statics.get(&symbol).copied() {
+                            Some(closure_ptr) => {
+                                let bound_method = Object::BoundMethod(BoundMethod {
+                                    receiver: Value::Object(instance_ptr),
+                                    closure_ptr,
+                                });
+                                let addr = self.add_to_heap(bound_method);
+                                self.push(Value::Object(addr));
+                            }
+                            None => {
+                                return Err(InterpreterError::NameError(
+                                    line,
+                                    format!("Undefined property {}", name),
+                                ))
+                            }
+                        }
+                    } else if let Object::String(_) = object {
+                        //String methods are only ever invoked
+                        let message = if natives::STRING_METHODS.iter().any(|m| m.name == name) {
+                            format!("String method {} must be called", name)
+                        } else {
+                            format!("Undefined property {}", name)
+                        };
+                        return Err(InterpreterError::NameError(line, message));
+                    } else if let Object::Generator(_) = object {
+                        let message = if GENERATOR_METHODS.contains(&name) {
+                            format!("Generator method {} must be called", name)
+                        } else {
+                            format!("Undefined property {}", name)
+                        };
+                        return Err(InterpreterError::NameError(line, message));
+                    } else if let Object::Module(module) = object {
+                        match self.global_named(module.namespace, name) {
+                            Some(value) => self.push(value),
+                            None => {
+                                return Err(InterpreterError::NameError(
+                                    line,
+                                    format!("Undefined property {}", name),
+                                ))
+                            }
+                        }
                     } else {
                         return Err(InterpreterError::TypeError(
                             line,
@@ -895,66 +3337,184 @@ impl VM {
                         ));
                     }
                 }
-                OpCode::SetProperty(const_idx) => {
-                    let line = self.current_line(&frame);
-                    let name_ptr = u64::as_val_or_panic(self.read_constant(&frame, const_idx));
-                    let name = self.heap().string_deref(name_ptr).clone(); //Can we eliminate this clone?
-
+                OpCode::SetProperty(_, symbol) => {
+                    let line = self.current_line(frame);
+                    let site = (frame.closure_pointer, frame.ip);
                     let value_set = self.pop();
+                    let instance_ptr = u64::as_val_or_panic(self.pop());
 
-                    let instance_value = self.pop();
-                    let instance_ptr = u64::as_val_or_panic(instance_value);
-                    let object = self.heap_mut().deref_mut(instance_ptr);
-                    if let Object::Instance(instance) = object {
-                        instance.fields.insert(name, value_set);
+                    let mut cache = self.inline_caches.get(site.0, site.1);
+                    if self.inline_caches.setters && self.has_setters(instance_ptr) {
+                        cache = None;
+                    }
+                    if let Object::Instance(instance) = self.heap_mut().deref_mut(instance_ptr) {
+                        let hit = match cache {
+                            Some(InlineCache::Field { shape, slot }) if instance.shape == shape => {
+                                instance.fields[slot] = value_set;
+                                true
+                            }
+                            Some(InlineCache::Transition { from, to }) if instance.shape == from => {
+                                instance.shape = to;
+                                instance.fields.push(value_set);
+                                true
+                            }
+                            _ => false,
+                        };
+                        if hit {
+                            self.inline_caches.hits += 1;
+                            self.push(value_set);
+                            continue;
+                        }
+                    }
+                    self.inline_caches.misses += 1;
+
+                    let (shape, setter) = match self.heap().deref(instance_ptr) {
+                        Object::Instance(instance) => {
+                            let class = self.heap().class_deref(instance.class_ptr);
+                            (instance.shape, class.setters.get(&symbol).copied())
+                        }
+                        _ => {
+                            let name = self.heap().symbols.name(symbol);
+                            return Err(InterpreterError::TypeError(
+                                line,
+                                format!("Attempted to access field {}, but target was not an instance of an object", name),
+                            ));
+                        }
+                    };
+                    if let Some(setter_ptr) = setter {
+                        //Setters evaluate to the value written, so the callee's slot
+                        //only needs filling
+                        self.push(Value::Object(instance_ptr));
+                        self.push(Value::Object(instance_ptr));
                         self.push(value_set);
-                    } else {
-                        return Err(InterpreterError::TypeError(
-                            line,
-                            format!("Attempted to access field {}, but target was not an instance of an object", name),
-                        ));
+                        let (old_frame, new_frame) =
+                            self.call_lox_function(frame, setter_ptr, 1)?;
+                        self.push_frame(old_frame, new_frame);
+                        self.record_call(setter_ptr, line);
+                        *frame = new_frame;
+                        continue;
                     }
-                }
-                OpCode::Method(const_idx) => {
-                    let string_ptr = u64::as_val_or_panic(self.read_constant(&frame, const_idx));
-                    let method_name = self.heap().string_deref(string_ptr).clone();
 
+                    let heap = self.heap_mut();
+                    let entry = match heap.shapes.slot(shape, symbol) {
+                        Some(slot) => InlineCache::Field { shape, slot },
+                        None => {
+                            let to = heap.shapes.transition(shape, symbol);
+                            InlineCache::Transition { from: shape, to }
+                        }
+                    };
+                    if let Object::Instance(instance) = heap.deref_mut(instance_ptr) {
+                        match entry {
+                            InlineCache::Transition { to, .. } => {
+                                instance.shape = to;
+                                instance.fields.push(value_set);
+                            }
+                            InlineCache::Field { slot, .. } => instance.fields[slot] = value_set,
+                            _ => unreachable!(),
+                        }
+                    }
+                    self.inline_caches.insert(site.0, site.1, entry);
+                    self.push(value_set);
+                }
+                OpCode::Method(_, symbol)
+                | OpCode::StaticMethod(_, symbol)
+                | OpCode::Getter(_, symbol)
+                | OpCode::Setter(_, symbol) => {
+                    let table = match op {
+                        OpCode::Method(_, _) => Table::Methods,
+                        OpCode::StaticMethod(_, _) => Table::Statics,
+                        OpCode::Getter(_, _) => Table::Getters,
+                        _ => Table::Setters,
+                    };
+                    if table == Table::Setters {
+                        self.inline_caches.setters = true;
+                    }
                     let method_ptr = u64::as_val_or_panic(self.pop());
-
                     let class_ptr = u64::as_val_or_panic(*self.peek(0));
-                    let class_obj = self.heap_mut().deref_mut(class_ptr);
-                    if let Object::Class(class) = class_obj {
-                        class.methods.insert(method_name, method_ptr);
-                    } else {
-                        panic!("Expected class object");
-                    }
+                    self.define_method(class_ptr, table, symbol, method_ptr);
+                    self.inline_caches.invalidate();
                 }
                 OpCode::ThisPlaceholder => {
                     self.push(Value::Nil);
                 }
-                OpCode::Invoke(const_idx, num_args) => {
-                    let line = self.current_line(&frame);
-                    let string_ptr = u64::as_val_or_panic(self.read_constant(&frame, const_idx));
-                    let method_name = self.heap().string_deref(string_ptr).clone();
-
+                OpCode::Invoke(_, num_args, symbol) => {
+                    let line = self.current_line(frame);
+                    let site = (frame.closure_pointer, frame.ip);
                     let receiver_ptr = u64::as_val_or_panic(*self.peek(num_args + 1));
+                    let entry = self.inline_caches.get(site.0, site.1);
+                    let cached = match entry {
+                        Some(InlineCache::Method {
+                            class_ptr,
+                            method_ptr,
+                            arity,
+                        }) if arity == num_args => match self.heap().deref(receiver_ptr) {
+                            Object::Instance(instance) if instance.class_ptr == class_ptr => {
+                                Some(method_ptr)
+                            }
+                            _ => None,
+                        },
+                        Some(InlineCache::StaticMethod {
+                            class_ptr,
+                            method_ptr,
+                            arity,
+                        }) if arity == num_args && receiver_ptr == class_ptr => Some(method_ptr),
+                        _ => None,
+                    };
+                    let native = match entry {
+                        Some(InlineCache::NativeMethod { on_list, index }) => {
+                            match self.heap().deref(receiver_ptr) {
+                                Object::List(_) if on_list => Some(&natives::LIST_METHODS[index]),
+                                Object::String(_) if !on_list => {
+                                    Some(&natives::STRING_METHODS[index])
+                                }
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    if let Some(native) = native {
+                        self.inline_caches.hits += 1;
+                        self.invoke_native_method(native, num_args, line)?;
+                        continue;
+                    }
+                    if let Some(method_ptr) = cached {
+                        self.inline_caches.hits += 1;
+                        let (old_frame, new_frame) =
+                            self.closure_frame(frame, method_ptr, num_args, line)?;
+                        self.push_frame(old_frame, new_frame);
+                        self.record_call(method_ptr, line);
+                        *frame = new_frame;
+                        self.write_stack(frame, 0, Value::Object(receiver_ptr));
+                        continue;
+                    }
+                    self.inline_caches.misses += 1;
+
                     let receiver = self.heap().deref(receiver_ptr);
 
                     if let Object::Instance(instance) = receiver {
-                        let class = self.heap().class_deref(instance.class_ptr);
-                        let method_ptr = class.methods.get(&method_name).copied();
+                        let class_ptr = instance.class_ptr;
+                        let class = self.heap().class_deref(class_ptr);
+                        let method_ptr = class.method(symbol);
                         if let Some(method_ptr) = method_ptr {
-                            let closure = self.heap().closure_deref(method_ptr);
                             let (old_frame, new_frame) =
-                                self.call_lox_function(&frame, &closure, method_ptr, num_args)?;
-                            self.call_frames.push(old_frame);
-                            frame = new_frame;
-                            self.write_stack(&frame, 0, Value::Object(receiver_ptr));
+                                self.call_lox_function(frame, method_ptr, num_args)?;
+                            if !self.is_variadic(method_ptr) {
+                                let entry = InlineCache::Method {
+                                    class_ptr,
+                                    method_ptr,
+                                    arity: num_args,
+                                };
+                                self.inline_caches.insert(site.0, site.1, entry);
+                            }
+                            self.push_frame(old_frame, new_frame);
+                            self.record_call(method_ptr, line);
+                            *frame = new_frame;
+                            self.write_stack(frame, 0, Value::Object(receiver_ptr));
                         } else {
-                            let field = instance.fields.get(&method_name).copied();
+                            let field = self.heap().field(instance, symbol);
                             if let Some(field) = field {
                                 if let Value::Object(obj_ptr) = field {
-                                    frame = self.call_object(&mut frame, num_args, obj_ptr)?;
+                                    *frame = self.call_object(frame, num_args, obj_ptr)?;
                                 } else {
                                     return Err(InterpreterError::FunctionError(
                                         line,
@@ -963,6 +3523,12 @@ impl VM {
                                         ),
                                     ));
                                 }
+                            } else if class.getters.contains_key(&symbol) {
+                                let method_name = self.heap().symbols.name(symbol);
+                                return Err(InterpreterError::FunctionError(
+                                    line,
+                                    format!("Getter {} can't be called like a method", method_name),
+                                ));
                             } else {
                                 return Err(InterpreterError::NameError(
                                     line,
@@ -970,6 +3536,64 @@ impl VM {
                                 ));
                             }
                         }
+                    } else if let Object::List(_) | Object::String(_) = receiver {
+                        let on_list = matches!(receiver, Object::List(_));
+                        let methods = if on_list {
+                            natives::LIST_METHODS
+                        } else {
+                            natives::STRING_METHODS
+                        };
+                        let method_name = self.heap().symbols.name(symbol);
+                        let undefined = || {
+                            InterpreterError::NameError(line, String::from("Undefined property"))
+                        };
+                        let index = methods
+                            .iter()
+                            .position(|method| method.name == method_name)
+                            .ok_or_else(undefined)?;
+                        let entry = InlineCache::NativeMethod { on_list, index };
+                        self.inline_caches.insert(site.0, site.1, entry);
+                        self.invoke_native_method(&methods[index], num_args, line)?;
+                    } else if let Object::Generator(_) = receiver {
+                        *frame =
+                            self.invoke_generator(frame, receiver_ptr, symbol, num_args, line)?;
+                    } else if let Object::Class(class) = receiver {
+                        let method_ptr = class.statics.get(&symbol).copied().ok_or_else(|| {
+                            InterpreterError::NameError(line, String::from("Undefined property"))
+                        })?;
+                        let (old_frame, new_frame) =
+                            self.call_lox_function(frame, method_ptr, num_args)?;
+                        if !self.is_variadic(method_ptr) {
+                            let entry = InlineCache::StaticMethod {
+                                class_ptr: receiver_ptr,
+                                method_ptr,
+                                arity: num_args,
+                            };
+                            self.inline_caches.insert(site.0, site.1, entry);
+                        }
+                        self.push_frame(old_frame, new_frame);
+                        self.record_call(method_ptr, line);
+                        *frame = new_frame;
+                        self.write_stack(frame, 0, Value::Object(receiver_ptr));
+                    } else if let Object::Module(module) = receiver {
+                        let name = self.heap().symbols.name(symbol);
+                        match self.global_named(module.namespace, name) {
+                            Some(Value::Object(obj_ptr)) => {
+                                *frame = self.call_object(frame, num_args, obj_ptr)?;
+                            }
+                            Some(_) => {
+                                return Err(InterpreterError::FunctionError(
+                                    line,
+                                    String::from("Attempt to call a value which is not a function"),
+                                ))
+                            }
+                            None => {
+                                return Err(InterpreterError::NameError(
+                                    line,
+                                    format!("Undefined property {}", name),
+                                ))
+                            }
+                        }
                     } else {
                         return Err(InterpreterError::FunctionError(
                             line,
@@ -977,31 +3601,410 @@ impl VM {
                         ));
                     }
                 }
-                OpCode::Inherit => {
-                    //Need to make copies since we need a mutable reference to subclass
-                    let superclass_addr = u64::as_val_or_panic(*self.peek(1));
-                    let line = self.current_line(&frame);
-                    let mut superclass_methods =
-                        if let Object::Class(superclass) = self.heap().deref(superclass_addr) {
-                            let mut superclass_methods: Vec<(String, u64)> = vec![];
-                            for (key, value) in superclass.methods.iter() {
-                                superclass_methods.push((key.clone(), *value));
+                OpCode::Inherit(count) => {
+                    //Superclasses stay on the stack until the merged class is
+                    //allocated, in case that collects garbage
+                    let line = self.current_line(frame);
+                    let mut superclass_addrs = vec![];
+                    for distance in (1..=count).rev() {
+                        match *self.peek(distance) {
+                            Value::Object(addr)
+                                if matches!(self.heap().deref(addr), Object::Class(_)) =>
+                            {
+                                superclass_addrs.push(addr)
                             }
-                            superclass_methods
-                        } else {
-                            return Err(InterpreterError::TypeError(
-                                line,
-                                String::from("Superclass must be a class object"),
-                            ));
-                        };
+                            _ => {
+                                return Err(InterpreterError::TypeError(
+                                    line,
+                                    String::from("Superclass must be a class object"),
+                                ))
+                            }
+                        }
+                    }
+
+                    //With several superclasses the leftmost one that has a method
+                    //wins, since inherit keeps what a class already has
+                    let super_addr = if let [superclass_addr] = superclass_addrs[..] {
+                        superclass_addr
+                    } else {
+                        let names: Vec<&str> = superclass_addrs
+                            .iter()
+                            .map(|addr| self.heap().class_deref(*addr).name.as_str())
+                            .collect();
+                        let mut merged = Class::new(names.join(", "));
+                        for addr in superclass_addrs.iter() {
+                            let superclass = self.heap().class_deref(*addr);
+                            merged.inherit(superclass.clone());
+                        }
+                        self.add_to_heap(Object::Class(merged))
+                    };
 
+                    //Need to make copies since we need a mutable reference to subclass
+                    let superclass = self.heap().class_deref(super_addr).clone();
                     let subclass_addr = u64::as_val_or_panic(*self.peek(0));
                     let subclass = self.heap_mut().deref_mut(subclass_addr).as_class_mut();
-                    for (key, value) in superclass_methods.drain(..) {
-                        subclass.methods.insert(key, value);
+                    subclass.inherit(superclass);
+                    for addr in superclass_addrs {
+                        let superclass = self.heap_mut().deref_mut(addr).as_class_mut();
+                        superclass.subclasses.push(subclass_addr);
+                    }
+                    self.inline_caches.invalidate();
+
+                    for _ in 0..=count {
+                        self.pop();
+                    }
+                    self.push(Value::Object(super_addr));
+                }
+                OpCode::GetSuper(_, symbol) => {
+                    let line = self.current_line(frame);
+                    let super_addr = u64::as_val_or_panic(self.pop());
+                    let receiver = *self.peek(0);
+                    let superclass = self.heap().class_deref(super_addr);
+                    match superclass.methods.get(&symbol).copied() {
+                        Some(closure_ptr) => {
+                            let bound_method = Object::BoundMethod(BoundMethod {
+                                receiver,
+                                closure_ptr,
+                            });
+                            let addr = self.add_to_heap(bound_method);
+                            self.pop();
+                            self.push(Value::Object(addr));
+                        }
+                        None => {
+                            let name = self.heap().symbols.name(symbol);
+                            return Err(InterpreterError::NameError(
+                                line,
+                                format!("Undefined superclass method {}", name),
+                            ));
+                        }
+                    }
+                }
+                OpCode::SuperInvoke(_, num_args, symbol) => {
+                    let line = self.current_line(frame);
+                    let site = (frame.closure_pointer, frame.ip);
+                    let super_addr = u64::as_val_or_panic(self.pop());
+                    let receiver = *self.peek(num_args + 1);
+                    if let Some(InlineCache::Method {
+                        class_ptr,
+                        method_ptr,
+                        arity,
+                    }) = self.inline_caches.get(site.0, site.1)
+                    {
+                        if class_ptr == super_addr && arity == num_args {
+                            self.inline_caches.hits += 1;
+                            let (old_frame, new_frame) =
+                                self.closure_frame(frame, method_ptr, num_args, line)?;
+                            self.push_frame(old_frame, new_frame);
+                            self.record_call(method_ptr, line);
+                            *frame = new_frame;
+                            self.write_stack(frame, 0, receiver);
+                            continue;
+                        }
+                    }
+                    self.inline_caches.misses += 1;
+
+                    let superclass = self.heap().class_deref(super_addr);
+                    match superclass.method(symbol) {
+                        Some(method_ptr) => {
+                            let (old_frame, new_frame) =
+                                self.call_lox_function(frame, method_ptr, num_args)?;
+                            if !self.is_variadic(method_ptr) {
+                                let entry = InlineCache::Method {
+                                    class_ptr: super_addr,
+                                    method_ptr,
+                                    arity: num_args,
+                                };
+                                self.inline_caches.insert(site.0, site.1, entry);
+                            }
+                            self.push_frame(old_frame, new_frame);
+                            self.record_call(method_ptr, line);
+                            *frame = new_frame;
+                            self.write_stack(frame, 0, receiver);
+                        }
+                        None => {
+                            let name = self.heap().symbols.name(symbol);
+                            return Err(InterpreterError::NameError(
+                                line,
+                                format!("Undefined superclass method {}", name),
+                            ));
+                        }
                     }
                 }
             }
         }
     }
 }
+
+//Lookups for crash reports that don't panic, since the heap may be why we crashed
+fn crash_function(heap: &VirtualMemory, closure_p: u64) -> Option<&Function> {
+    match heap.get(closure_p)? {
+        Object::Closure(closure) => match heap.get(closure.function_pointer)? {
+            Object::Function(fun) => Some(fun),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn describe_frame(heap: &VirtualMemory, closure_p: u64, ip: usize) -> String {
+    match crash_function(heap, closure_p) {
+        Some(fun) => {
+            let line = fun.chunk.line_numbers.get(ip).copied().unwrap_or(0);
+            format!("{} (line {})", fun.name, line)
+        }
+        None => format!("<closure {}>", closure_p),
+    }
+}
+
+fn describe_instruction(heap: &VirtualMemory, closure_p: u64, ip: usize) -> Option<String> {
+    let fun = crash_function(heap, closure_p)?;
+    if ip < fun.chunk.code.len() {
+        Some(format!("{}: {}", fun.name, disassemble_instruction(&fun.chunk, ip, heap)))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod interpreter_tests {
+    use super::*;
+    use crate::test_support::{global, run};
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    #[test]
+    fn prelude_defines_globals() {
+        let mut vm = VM::new()
+            .with_prelude("fun twice(x) { return x * 2; }")
+            .unwrap();
+        run(&mut vm, "var y = twice(4);").unwrap();
+        assert_eq!(global(&vm, "y"), "8");
+    }
+
+    #[test]
+    fn prelude_errors_are_reported() {
+        let error = VM::new().with_prelude("var x = -nil;").err().unwrap();
+        assert_eq!(error, "Runtime Error: 1: Operand must be a number.");
+        assert!(VM::new().with_prelude("var = 1;").is_err());
+    }
+
+    #[test]
+    fn overflows_at_the_stack_size_without_growing() {
+        let mut vm = VM::new();
+        vm.set_max_call_depth(10_000);
+        vm.set_stack_size(1000);
+        let capacity = vm.stack.capacity();
+        let source = "fun deep(n) { var a = n; var b = n; return deep(n + 1) + a + b; }\ndeep(0);";
+        assert_eq!(run(&mut vm, source).unwrap_err(), "1: Stack overflow");
+        assert_eq!(vm.stack.capacity(), capacity);
+    }
+
+    #[test]
+    fn frames_start_over_after_an_error() {
+        let depths = Rc::new(RefCell::new(vec![]));
+        let seen = depths.clone();
+        let mut vm = VM::new();
+        vm.set_hooks(Hooks {
+            on_call: Some(Box::new(move |event| seen.borrow_mut().push(event.depth))),
+            ..Hooks::default()
+        });
+        for source in ["fun f() { return nil + 1; }\nf();", "fun g() {}\ng();"] {
+            let _ = run(&mut vm, source);
+        }
+        //The frames f left behind are gone by the time g is called
+        assert_eq!(*depths.borrow(), vec![1, 1]);
+    }
+
+    #[test]
+    fn follows_the_running_chunk_through_calls_unwinds_and_tier_ups() {
+        let mut vm = VM::new();
+        vm.enable_tiering(2);
+        let source = "fun sum(n) { var t = 0; for (var i = 0; i < n; i = i + 1) t = t + i; return t; }
+fun fail(n) { if (n == 0) throw \"deep\"; return fail(n - 1); }
+fun outer(n) { try { return fail(n); } catch (e) { return sum(n) + 1; } }
+var total = 0;
+for (var i = 0; i < 20; i = i + 1) total = total + outer(i) + sum(i);";
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&vm, "total"), "2300");
+        assert!(vm.tiering_mut().unwrap().tier_ups >= 3);
+    }
+
+    #[test]
+    fn equal_strings_share_an_address() {
+        let mut heap = VirtualMemory::new();
+        let a = heap.add_to_heap(Object::String(String::from("name")));
+        let b = heap.add_to_heap(Object::String(String::from("name")));
+        let other = heap.add_to_heap(Object::String(String::from("other")));
+        assert_eq!(a, b);
+        assert_ne!(a, other);
+        assert_eq!(heap.allocations, 2);
+        //Swept strings are forgotten, so the next one is a new object
+        heap.remove_from_heap(a);
+        let c = heap.add_to_heap(Object::String(String::from("name")));
+        assert!(matches!(heap.deref(c), Object::String(s) if s == "name"));
+        assert_eq!(heap.allocations, 3);
+    }
+
+    #[test]
+    fn gc_threshold_grows_with_what_survives() {
+        let mut heap = VirtualMemory::new();
+        heap.set_gc_tuning(16, 2.0);
+        let kept = heap.add_to_heap(Object::List(Vec::with_capacity(100)));
+        let dropped = heap.add_to_heap(Object::String("x".repeat(1000)));
+        assert!(heap.bytes_allocated > 1000);
+        heap.remove_from_heap(dropped);
+        heap.recount();
+        let live = heap_report::approximate_size(heap.deref(kept));
+        assert_eq!(heap.bytes_allocated, live);
+        assert_eq!(heap.next_gc, live * 2);
+        //A small live heap still waits for the configured threshold
+        heap.set_gc_tuning(1 << 20, 2.0);
+        heap.recount();
+        assert_eq!(heap.next_gc, 1 << 20);
+    }
+
+    #[test]
+    fn swept_slots_are_reused() {
+        let mut heap = VirtualMemory::new();
+        let a = heap.add_to_heap(Object::List(vec![]));
+        let b = heap.add_to_heap(Object::List(vec![]));
+        heap.remove_from_heap(a);
+        assert!(heap.get(a).is_none());
+        assert_eq!(heap.live_objects(), 1);
+        let c = heap.add_to_heap(Object::Tuple(vec![]));
+        assert_eq!(c, a);
+        assert_eq!(heap.pool_stats.slot_hits, 1);
+        let addresses: Vec<u64> = heap.objects().map(|(addr, _)| addr).collect();
+        assert_eq!(addresses, vec![a, b]);
+    }
+
+    #[test]
+    fn incremental_collection_keeps_what_is_reachable() {
+        let collections = Rc::new(RefCell::new(0));
+        let seen = collections.clone();
+        let mut vm = VM::new();
+        vm.gc_step = 2;
+        vm.set_hooks(Hooks {
+            on_gc: Some(Box::new(move |_| *seen.borrow_mut() += 1)),
+            ..Hooks::default()
+        });
+        //New strings land in objects marking has already been through, which
+        //the ballast keeps it busy with for a while
+        let source = "class Node { init(value, next) { this.value = value; this.next = next; } }
+fun counter() { var n = 0; fun inc() { n = n + 1; return n; } return inc; }
+var ballast = []; for (var i = 0; i < 500; i = i + 1) ballast.push([i]);
+var list = nil; var items = []; var old = Node(nil, nil); var count = counter();
+for (var i = 0; i < 300; i = i + 1) {
+  list = Node(i, list);
+  items.push(\"item ${i}\");
+  old.value = [\"v${i}\"];
+  count();
+}
+var ok = items.len() == 300 and old.value[0] == \"v299\" and count() == 301;
+for (var i = 0; i < 300; i = i + 1) ok = ok and items[i] == \"item ${i}\";
+var length = 0;
+for (var node = list; node != nil; node = node.next) length = length + 1;
+ok = ok and length == 300;";
+        let tokens = scan_tokens(&String::from(source)).unwrap();
+        let mut compiler = Compiler::new(tokens, vm.take_virtual_memory());
+        let main = compiler.compile().map_err(|_| "compile error").unwrap();
+        let mut heap = compiler.heap;
+        heap.set_gc_tuning(4096, 1.5);
+        vm.interpret(main, heap).map_err(|e| e.to_string()).unwrap();
+        let ok = vm.globals().into_iter().find(|(name, _)| *name == "ok").unwrap().1;
+        assert_eq!(vm.inspect(ok), "true");
+        assert!(*collections.borrow() > 5);
+    }
+
+    #[test]
+    fn built_strings_equal_literals() {
+        let mut vm = VM::new();
+        let source = "var a = \"ab\"; var b = \"a\"; var same = \"${b}b\" == a;";
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&vm, "same"), "true");
+    }
+
+    fn run_specialized(source: &str, specialize: bool) -> (VM, Result<(), String>) {
+        let mut vm = VM::new();
+        vm.enable_metrics();
+        let tokens = scan_tokens(&String::from(source)).unwrap();
+        let mut compiler = Compiler::new(tokens, vm.take_virtual_memory());
+        compiler.specialize = specialize;
+        let main = compiler.compile().map_err(|_| "compile error").unwrap();
+        let result = vm.interpret(main, compiler.heap).map_err(|e| e.to_string());
+        (vm, result)
+    }
+
+    fn global_values(vm: &VM) -> Vec<String> {
+        vm.globals().into_iter().map(|(_, value)| vm.inspect(value)).collect()
+    }
+
+    #[test]
+    fn specialized_instructions_match_plain_ones() {
+        let source = "fun sum() { var total = 0; for (var i = 0; i < 10; i = i + 1) { \
+                      if (i >= 3) total = total + i; } return total; }\n\
+                      fun edge(a) { var i = 5; return (a or i) < 3; }\n\
+                      var total = sum(); var first = edge(false); var second = edge(1);";
+        let (specialized, result) = run_specialized(source, true);
+        result.unwrap();
+        let (plain, result) = run_specialized(source, false);
+        result.unwrap();
+        let expected = vec!["<fn edge/1>", "false", "true", "<fn sum/0>", "42"];
+        assert_eq!(global_values(&specialized), expected);
+        assert_eq!(global_values(&specialized), global_values(&plain));
+    }
+
+    #[test]
+    fn fuses_loop_arithmetic() {
+        let source = String::from("{ for (var i = 0; i < 3; i = i + 1) {} }");
+        let tokens = scan_tokens(&source).unwrap();
+        let main = Compiler::new(tokens, VirtualMemory::new()).compile().ok().unwrap();
+        let names: Vec<&str> = main.chunk.code.iter().map(|op| op.name()).collect();
+        assert!(names.contains(&"CompareLocalConstant"));
+        assert!(names.contains(&"IncrementLocal"));
+        assert!(!names.contains(&"Add") && !names.contains(&"Less"));
+    }
+
+    #[test]
+    fn specialized_instructions_fail_like_plain_ones() {
+        let source = "{ var s = \"a\"; s = s + 1; }";
+        let (_, specialized) = run_specialized(source, true);
+        let (_, plain) = run_specialized(source, false);
+        assert!(specialized.is_err());
+        assert_eq!(specialized, plain);
+    }
+
+    fn upvalue_boxes(vm: &VM) -> u64 {
+        let allocations = &vm.metrics().unwrap().allocations;
+        allocations.get("OpenUpvalue").copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn reads_captures_of_non_escaping_functions_in_place() {
+        let source = "fun outer() { var count = 0;\n\
+                      fun bump(by) { count = count + by; return count; }\n\
+                      for (var i = 0; i < 3; i = i + 1) { bump(2); }\n\
+                      return bump(1); }\n\
+                      var result = outer();";
+        let (specialized, result) = run_specialized(source, true);
+        result.unwrap();
+        let (plain, result) = run_specialized(source, false);
+        result.unwrap();
+        assert_eq!(global_values(&specialized), global_values(&plain));
+        assert_eq!(global_values(&specialized)[1], "7");
+        assert_eq!(upvalue_boxes(&specialized), 0);
+        assert_eq!(upvalue_boxes(&plain), 1);
+    }
+
+    #[test]
+    fn boxes_captures_of_escaping_functions() {
+        let source = "fun make() { var n = 0;\n\
+                      fun inc() { n = n + 1; return n; }\n\
+                      inc(); return inc; }\n\
+                      var f = make(); var result = f();";
+        let (vm, result) = run_specialized(source, true);
+        result.unwrap();
+        assert_eq!(global_values(&vm)[2], "2");
+        assert_eq!(upvalue_boxes(&vm), 1);
+    }
+}
@@ -0,0 +1,928 @@
+use super::chunk::{Comparison, OpCode};
+use super::globals::Globals;
+use super::interpreter::VirtualMemory;
+use super::value::{FnType, Function, Object, Value};
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, FuncRef, InstBuilder, JumpTableData, MemFlags, Signature,
+    Value as Register,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use std::sync::Arc;
+
+// Compiles hot functions to native code with Cranelift. Values stay where the
+// interpreter keeps them, on the VM's stack, so either one can pick up from
+// the other before any instruction. The native code does arithmetic,
+// comparisons, locals and jumps on numbers and booleans itself and reads and
+// writes globals through the VM. Anything else, like a call or adding two
+// strings, ends it with the offset of that instruction so the interpreter
+// runs it. The interpreter goes back in once a call returns or at the next
+// loop. Functions with instructions it can't step over, like capturing
+// variables, exceptions or generators, aren't compiled at all.
+
+// Value's tags under repr(C, u8), with the payload 8 bytes in
+const NUMBER: i64 = 0;
+const INTEGER: i64 = 1;
+const BOOLEAN: i64 = 2;
+const OBJECT: i64 = 3;
+const NIL: i64 = 4;
+const VALUE_SIZE: usize = 16;
+const PAYLOAD: i32 = 8;
+
+//(the frame's first stack slot, its globals, where to start) -> where the interpreter carries on
+type Entry = unsafe extern "C" fn(*mut Value, *mut Globals, usize) -> usize;
+
+pub struct NativeCode {
+    entry: Entry,
+    //The frame's stack depth before each instruction, None where the code can't be entered
+    depths: Vec<Option<usize>>,
+    max_depth: usize,
+}
+
+impl NativeCode {
+    // Runs the frame whose slots start at base from ip for as long as the native
+    // code can, returning the offset of the instruction the interpreter has to
+    // run next. None if the stack isn't as deep as the code expects there.
+    pub fn run(
+        &self,
+        stack: &mut Vec<Value>,
+        base: usize,
+        globals: &mut Globals,
+        ip: usize,
+    ) -> Option<usize> {
+        let depth = stack.len() - base;
+        if self.depths.get(ip).copied().flatten() != Some(depth) {
+            return None;
+        }
+        //The interpreter sized the stack up front, so leave it to the
+        //interpreter when there isn't room rather than move it
+        if stack.capacity() - stack.len() < self.max_depth - depth {
+            return None;
+        }
+        //Every slot below the exit's depth has been written by then
+        unsafe {
+            let ip = (self.entry)(stack.as_mut_ptr().add(base), globals, ip);
+            stack.set_len(base + self.depths[ip].unwrap());
+            Some(ip)
+        }
+    }
+}
+
+unsafe extern "C" fn lox_jit_get_global(
+    globals: *const Globals,
+    slot: usize,
+    out: *mut Value,
+) -> bool {
+    match (*globals).get(slot) {
+        Some(value) => {
+            out.write(value);
+            true
+        }
+        None => false,
+    }
+}
+
+unsafe extern "C" fn lox_jit_set_global(
+    globals: *mut Globals,
+    slot: usize,
+    value: *const Value,
+) -> bool {
+    match (*globals).get_mut(slot) {
+        Some(global) => {
+            *global = *value;
+            true
+        }
+        None => false,
+    }
+}
+
+pub struct Jit {
+    module: JITModule,
+    get_global: FuncId,
+    set_global: FuncId,
+    //Functions given native code so far
+    pub compiled: u64,
+}
+
+impl Jit {
+    pub fn new() -> Result<Jit, String> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|e| e.to_string())?;
+        flags.set("is_pic", "false").map_err(|e| e.to_string())?;
+        let isa = cranelift_native::builder()
+            .map_err(|e| e.to_string())?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| e.to_string())?;
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("lox_jit_get_global", lox_jit_get_global as *const u8);
+        builder.symbol("lox_jit_set_global", lox_jit_set_global as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let pointer = module.target_config().pointer_type();
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(types::I64));
+        signature.params.push(AbiParam::new(pointer));
+        signature.returns.push(AbiParam::new(types::I8));
+        let mut declare = |name: &str| {
+            module
+                .declare_function(name, Linkage::Import, &signature)
+                .map_err(|e| e.to_string())
+        };
+        let get_global = declare("lox_jit_get_global")?;
+        let set_global = declare("lox_jit_set_global")?;
+        Ok(Jit {
+            module,
+            get_global,
+            set_global,
+            compiled: 0,
+        })
+    }
+
+    // Gives the function at function_ptr native code, unless it has some
+    // already. Errors say why it can't have any.
+    pub fn compile(&mut self, function_ptr: u64, heap: &mut VirtualMemory) -> Result<(), String> {
+        let function = heap.deref(function_ptr).as_function();
+        if function.chunk.native.is_some() {
+            return Ok(());
+        }
+        let (depths, max_depth) = stack_depths(function)?;
+
+        let pointer = self.module.target_config().pointer_type();
+        let mut context = self.module.make_context();
+        let signature = &mut context.func.signature;
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(types::I64));
+        signature.returns.push(AbiParam::new(types::I64));
+        let signature: Signature = signature.clone();
+
+        let mut builder_context = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        let get_global = self
+            .module
+            .declare_func_in_func(self.get_global, builder.func);
+        let set_global = self
+            .module
+            .declare_func_in_func(self.set_global, builder.func);
+        Lowering::new(&mut builder, function, &depths, get_global, set_global).lower();
+        builder.seal_all_blocks();
+        builder.finalize();
+
+        let id = self
+            .module
+            .declare_anonymous_function(&signature)
+            .map_err(|e| e.to_string())?;
+        self.module
+            .define_function(id, &mut context)
+            .map_err(|e| format!("{:?}", e))?;
+        self.module
+            .finalize_definitions()
+            .map_err(|e| e.to_string())?;
+        let entry = self.module.get_finalized_function(id);
+        self.compiled += 1;
+
+        let code = NativeCode {
+            entry: unsafe { std::mem::transmute::<*const u8, Entry>(entry) },
+            depths,
+            max_depth,
+        };
+        if let Object::Function(function) = heap.deref_mut(function_ptr) {
+            function.chunk.native = Some(Arc::new(code));
+        }
+        Ok(())
+    }
+}
+
+// Values an instruction pops and pushes, None for those the native code can't
+// step over.
+fn stack_effect(op: &OpCode) -> Option<(usize, usize)> {
+    let effect = match op {
+        OpCode::Constant(_)
+        | OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::ThisPlaceholder
+        | OpCode::GetLocal(_)
+        | OpCode::GetGlobal(_, _)
+        | OpCode::IncrementLocal(_)
+        | OpCode::CompareLocalConstant(_, _, _) => (0, 1),
+        OpCode::Pop | OpCode::Print | OpCode::DefineGlobal(_, _) => (1, 0),
+        OpCode::SetLocal(_)
+        | OpCode::SetGlobal(_, _)
+        | OpCode::Jump(_)
+        | OpCode::JumpIfFalse(_)
+        | OpCode::Loop(_)
+        | OpCode::Return
+        | OpCode::EOF => (0, 0),
+        OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Less
+        | OpCode::Greater
+        | OpCode::Equal
+        | OpCode::SetProperty(_, _) => (2, 1),
+        OpCode::Not
+        | OpCode::Negate
+        | OpCode::AddConstant(_)
+        | OpCode::AddLocal(_)
+        | OpCode::GetProperty(_, _) => (1, 1),
+        //The callee and the receiver slot under the arguments
+        OpCode::Call(args) | OpCode::Invoke(_, args, _) => (args + 2, 1),
+        OpCode::CallConstant(_, args) => (args + 1, 1),
+        _ => return None,
+    };
+    Some(effect)
+}
+
+// The stack depth before every instruction reachable from the start, and the
+// deepest it gets. Functions start with the receiver and their arguments.
+fn stack_depths(function: &Function) -> Result<(Vec<Option<usize>>, usize), String> {
+    let code = &function.chunk.code;
+    let start = match function.fn_type {
+        FnType::Script => 0,
+        _ => function.arity + 1,
+    };
+    //Running off the end is an EOF too
+    let mut depths = vec![None; code.len() + 1];
+    let mut max_depth = start;
+    let mut work = vec![(0, start)];
+    while let Some((ip, depth)) = work.pop() {
+        match depths[ip] {
+            Some(known) if known == depth => continue,
+            Some(_) => return Err(format!("uneven stack at {}", ip)),
+            None => depths[ip] = Some(depth),
+        }
+        let op = match code.get(ip) {
+            Some(op) => op,
+            None => continue,
+        };
+        let (pops, pushes) =
+            stack_effect(op).ok_or_else(|| format!("{} isn't compiled", op.name()))?;
+        let slot = match op {
+            OpCode::GetLocal(slot)
+            | OpCode::SetLocal(slot)
+            | OpCode::AddLocal(slot)
+            | OpCode::IncrementLocal(slot)
+            | OpCode::CompareLocalConstant(slot, _, _) => Some(*slot),
+            _ => None,
+        };
+        if pops > depth || slot.is_some_and(|slot| slot >= depth) {
+            return Err(format!("uneven stack at {}", ip));
+        }
+        let after = depth - pops + pushes;
+        max_depth = max_depth.max(after);
+        match op {
+            OpCode::Jump(offset) => work.push((ip + 1 + offset, after)),
+            OpCode::Loop(offset) => work.push((ip + 1 - offset, after)),
+            OpCode::JumpIfFalse(offset) => {
+                work.push((ip + 1, after));
+                work.push((ip + 1 + offset, after));
+            }
+            OpCode::Return | OpCode::EOF => {}
+            //Over the instruction left behind when they were fused
+            OpCode::AddLocal(_) | OpCode::CallConstant(_, _) => work.push((ip + 2, after)),
+            _ => work.push((ip + 1, after)),
+        }
+    }
+    Ok((depths, max_depth))
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Stack(usize),
+    Constant(Value),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Arithmetic {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Less,
+    Greater,
+}
+
+struct Lowering<'a, 'b> {
+    builder: &'a mut FunctionBuilder<'b>,
+    function: &'a Function,
+    depths: &'a [Option<usize>],
+    get_global: FuncRef,
+    set_global: FuncRef,
+    //One per reachable instruction, plus one for running off the end
+    blocks: Vec<Option<Block>>,
+    //Returning each instruction's offset to the interpreter, filled in last
+    exits: Vec<Option<Block>>,
+    stack: Register,
+    globals: Register,
+}
+
+impl<'a, 'b> Lowering<'a, 'b> {
+    fn new(
+        builder: &'a mut FunctionBuilder<'b>,
+        function: &'a Function,
+        depths: &'a [Option<usize>],
+        get_global: FuncRef,
+        set_global: FuncRef,
+    ) -> Self {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let params = builder.block_params(entry).to_vec();
+        let blocks = depths
+            .iter()
+            .map(|depth| depth.map(|_| builder.create_block()))
+            .collect();
+        Lowering {
+            builder,
+            function,
+            depths,
+            get_global,
+            set_global,
+            blocks,
+            exits: vec![None; depths.len()],
+            stack: params[0],
+            globals: params[1],
+        }
+    }
+
+    fn lower(mut self) {
+        //Starting anywhere the interpreter might hand over
+        let entry = self.builder.current_block().unwrap();
+        let ip = self.builder.block_params(entry)[2];
+        let unknown = self.builder.create_block();
+        let default = self.builder.func.dfg.block_call(unknown, &[]);
+        let mut table = vec![];
+        for block in self.blocks.iter() {
+            table.push(match block {
+                Some(block) => self.builder.func.dfg.block_call(*block, &[]),
+                None => default,
+            });
+        }
+        let table = JumpTableData::new(default, &table);
+        let table = self.builder.create_jump_table(table);
+        let index = self.builder.ins().ireduce(types::I32, ip);
+        self.builder.ins().br_table(index, table);
+        self.builder.switch_to_block(unknown);
+        self.builder.ins().return_(&[ip]);
+
+        let code = &self.function.chunk.code;
+        for ip in 0..self.depths.len() {
+            let (block, depth) = match (self.blocks[ip], self.depths[ip]) {
+                (Some(block), Some(depth)) => (block, depth),
+                _ => continue,
+            };
+            self.builder.switch_to_block(block);
+            match code.get(ip) {
+                Some(op) => self.instruction(ip, *op, depth),
+                None => self.leave(ip),
+            }
+        }
+        for (ip, exit) in self.exits.iter().enumerate() {
+            if let Some(block) = exit {
+                self.builder.switch_to_block(*block);
+                let ip = self.builder.ins().iconst(types::I64, ip as i64);
+                self.builder.ins().return_(&[ip]);
+            }
+        }
+    }
+
+    fn instruction(&mut self, ip: usize, op: OpCode, depth: usize) {
+        let chunk = &self.function.chunk;
+        match op {
+            OpCode::Constant(idx) => self.store_constant(depth, chunk.constants[idx]),
+            OpCode::Nil | OpCode::ThisPlaceholder => self.store_constant(depth, Value::Nil),
+            OpCode::True => self.store_constant(depth, Value::Boolean(true)),
+            OpCode::False => self.store_constant(depth, Value::Boolean(false)),
+            OpCode::Pop => {}
+            OpCode::GetLocal(slot) => self.copy(slot, depth),
+            OpCode::SetLocal(slot) => self.copy(depth - 1, slot),
+            OpCode::GetGlobal(_, slot) => {
+                let ok = self.call_global(self.get_global, slot, depth);
+                self.guard(ok, ip);
+            }
+            OpCode::SetGlobal(_, slot) => {
+                let ok = self.call_global(self.set_global, slot, depth - 1);
+                self.guard(ok, ip);
+            }
+            OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide => {
+                let arithmetic = match op {
+                    OpCode::Add => Arithmetic::Add,
+                    OpCode::Subtract => Arithmetic::Subtract,
+                    OpCode::Multiply => Arithmetic::Multiply,
+                    _ => Arithmetic::Divide,
+                };
+                let (a, b) = (Operand::Stack(depth - 2), Operand::Stack(depth - 1));
+                self.arithmetic(ip, arithmetic, a, b, &[depth - 2]);
+            }
+            OpCode::Less | OpCode::Greater => {
+                let arithmetic = match op {
+                    OpCode::Less => Arithmetic::Less,
+                    _ => Arithmetic::Greater,
+                };
+                let (a, b) = (Operand::Stack(depth - 2), Operand::Stack(depth - 1));
+                self.arithmetic(ip, arithmetic, a, b, &[depth - 2]);
+            }
+            OpCode::AddConstant(idx) => {
+                let b = Operand::Constant(chunk.constants[idx]);
+                self.arithmetic(
+                    ip,
+                    Arithmetic::Add,
+                    Operand::Stack(depth - 1),
+                    b,
+                    &[depth - 1],
+                );
+            }
+            OpCode::AddLocal(slot) => {
+                let (a, b) = (Operand::Stack(depth - 1), Operand::Stack(slot));
+                self.arithmetic(ip, Arithmetic::Add, a, b, &[depth - 1]);
+                self.jump(ip + 2);
+                return;
+            }
+            OpCode::IncrementLocal(slot) => {
+                let one = Operand::Constant(Value::Integer(1));
+                self.arithmetic(
+                    ip,
+                    Arithmetic::Add,
+                    Operand::Stack(slot),
+                    one,
+                    &[depth, slot],
+                );
+            }
+            OpCode::CompareLocalConstant(slot, idx, comparison) => {
+                let arithmetic = match comparison {
+                    Comparison::Less => Arithmetic::Less,
+                    Comparison::Greater => Arithmetic::Greater,
+                };
+                let b = Operand::Constant(chunk.constants[idx]);
+                self.arithmetic(ip, arithmetic, Operand::Stack(slot), b, &[depth]);
+            }
+            OpCode::Negate => self.negate(ip, depth - 1),
+            OpCode::Not => {
+                let falsy = self.falsy(depth - 1);
+                let falsy = self.builder.ins().uextend(types::I64, falsy);
+                self.store(depth - 1, BOOLEAN, falsy);
+            }
+            OpCode::Equal => self.equal(ip, depth - 2),
+            OpCode::Jump(offset) => {
+                self.jump(ip + 1 + offset);
+                return;
+            }
+            OpCode::Loop(offset) => {
+                self.jump(ip + 1 - offset);
+                return;
+            }
+            OpCode::JumpIfFalse(offset) => {
+                let falsy = self.falsy(depth - 1);
+                let target = self.blocks[ip + 1 + offset].unwrap();
+                let next = self.blocks[ip + 1].unwrap();
+                self.builder.ins().brif(falsy, target, &[], next, &[]);
+                return;
+            }
+            //Calls, returns and object operations are the interpreter's
+            _ => {
+                self.leave(ip);
+                return;
+            }
+        }
+        self.jump(ip + 1);
+    }
+
+    fn jump(&mut self, ip: usize) {
+        let block = self.blocks[ip].unwrap();
+        self.builder.ins().jump(block, &[]);
+    }
+
+    fn leave(&mut self, ip: usize) {
+        let exit = self.exit(ip);
+        self.builder.ins().jump(exit, &[]);
+    }
+
+    fn exit(&mut self, ip: usize) -> Block {
+        if let Some(block) = self.exits[ip] {
+            return block;
+        }
+        let block = self.builder.create_block();
+        self.exits[ip] = Some(block);
+        block
+    }
+
+    //Carries on in a new block if condition holds, leaving for the interpreter at ip if not
+    fn guard(&mut self, condition: Register, ip: usize) {
+        let exit = self.exit(ip);
+        let ok = self.builder.create_block();
+        self.builder.ins().brif(condition, ok, &[], exit, &[]);
+        self.builder.switch_to_block(ok);
+    }
+
+    fn offset(slot: usize) -> i32 {
+        (slot * VALUE_SIZE) as i32
+    }
+
+    fn tag(&mut self, operand: Operand) -> Register {
+        match operand {
+            Operand::Stack(slot) => {
+                let flags = MemFlags::trusted();
+                self.builder
+                    .ins()
+                    .uload8(types::I32, flags, self.stack, Self::offset(slot))
+            }
+            Operand::Constant(value) => self.builder.ins().iconst(types::I32, tag_of(value)),
+        }
+    }
+
+    fn is(&mut self, tag: Register, expected: i64) -> Register {
+        self.builder.ins().icmp_imm(IntCC::Equal, tag, expected)
+    }
+
+    fn payload(&mut self, operand: Operand, ty: types::Type) -> Register {
+        match operand {
+            Operand::Stack(slot) => {
+                let offset = Self::offset(slot) + PAYLOAD;
+                self.builder
+                    .ins()
+                    .load(ty, MemFlags::trusted(), self.stack, offset)
+            }
+            Operand::Constant(value) if ty == types::F64 => {
+                let bits = payload_of(value) as u64;
+                self.builder.ins().f64const(f64::from_bits(bits))
+            }
+            Operand::Constant(value) => self.builder.ins().iconst(ty, payload_of(value)),
+        }
+    }
+
+    fn store(&mut self, slot: usize, tag: i64, payload: Register) {
+        let flags = MemFlags::trusted();
+        let tag = self.builder.ins().iconst(types::I64, tag);
+        let offset = Self::offset(slot);
+        self.builder.ins().store(flags, tag, self.stack, offset);
+        self.builder
+            .ins()
+            .store(flags, payload, self.stack, offset + PAYLOAD);
+    }
+
+    fn store_constant(&mut self, slot: usize, value: Value) {
+        let payload = match value {
+            Value::Number(n) => self.builder.ins().f64const(n),
+            _ => self.builder.ins().iconst(types::I64, payload_of(value)),
+        };
+        self.store(slot, tag_of(value), payload);
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        let flags = MemFlags::trusted();
+        for half in [0, PAYLOAD].iter() {
+            let word =
+                self.builder
+                    .ins()
+                    .load(types::I64, flags, self.stack, Self::offset(from) + half);
+            self.builder
+                .ins()
+                .store(flags, word, self.stack, Self::offset(to) + half);
+        }
+    }
+
+    fn call_global(&mut self, callee: FuncRef, slot: usize, value_slot: usize) -> Register {
+        let slot = self.builder.ins().iconst(types::I64, slot as i64);
+        let value = self
+            .builder
+            .ins()
+            .iadd_imm(self.stack, Self::offset(value_slot) as i64);
+        let call = self
+            .builder
+            .ins()
+            .call(callee, &[self.globals, slot, value]);
+        self.builder.inst_results(call)[0]
+    }
+
+    //Nil and false, from the tag and a boolean's byte
+    fn falsy(&mut self, slot: usize) -> Register {
+        let tag = self.tag(Operand::Stack(slot));
+        let nil = self.is(tag, NIL);
+        let boolean = self.is(tag, BOOLEAN);
+        let offset = Self::offset(slot) + PAYLOAD;
+        let byte = self
+            .builder
+            .ins()
+            .uload8(types::I32, MemFlags::trusted(), self.stack, offset);
+        let zero = self.builder.ins().icmp_imm(IntCC::Equal, byte, 0);
+        let false_ = self.builder.ins().band(boolean, zero);
+        self.builder.ins().bor(nil, false_)
+    }
+
+    // What the interpreter gives for two numbers, stored to every slot in
+    // results. Integers stay integers unless they overflow, which like any
+    // operand that isn't a number leaves it to the interpreter, and mixed
+    // operands are done with floats.
+    fn arithmetic(&mut self, ip: usize, op: Arithmetic, a: Operand, b: Operand, results: &[usize]) {
+        let (tag_a, tag_b) = (self.tag(a), self.tag(b));
+        let done = self.builder.create_block();
+        if op != Arithmetic::Divide {
+            let integers = self.builder.create_block();
+            let not_integers = self.builder.create_block();
+            let both = self.both(tag_a, tag_b, INTEGER);
+            self.builder
+                .ins()
+                .brif(both, integers, &[], not_integers, &[]);
+
+            self.builder.switch_to_block(integers);
+            let (x, y) = (self.payload(a, types::I64), self.payload(b, types::I64));
+            let ins = self.builder.ins();
+            let (result, overflow) = match op {
+                Arithmetic::Add => ins.sadd_overflow(x, y),
+                Arithmetic::Subtract => ins.ssub_overflow(x, y),
+                Arithmetic::Multiply => ins.smul_overflow(x, y),
+                _ => {
+                    let cc = match op {
+                        Arithmetic::Less => IntCC::SignedLessThan,
+                        _ => IntCC::SignedGreaterThan,
+                    };
+                    let result = ins.icmp(cc, x, y);
+                    let result = self.builder.ins().uextend(types::I64, result);
+                    (result, self.builder.ins().iconst(types::I8, 0))
+                }
+            };
+            let fits = self.builder.ins().icmp_imm(IntCC::Equal, overflow, 0);
+            self.guard(fits, ip);
+            let tag = match op {
+                Arithmetic::Less | Arithmetic::Greater => BOOLEAN,
+                _ => INTEGER,
+            };
+            for slot in results.iter() {
+                self.store(*slot, tag, result);
+            }
+            self.builder.ins().jump(done, &[]);
+            self.builder.switch_to_block(not_integers);
+        }
+
+        let numeric_a = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::UnsignedLessThanOrEqual, tag_a, INTEGER);
+        let numeric_b = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::UnsignedLessThanOrEqual, tag_b, INTEGER);
+        let numeric = self.builder.ins().band(numeric_a, numeric_b);
+        self.guard(numeric, ip);
+        let (x, y) = (self.float_of(a, tag_a), self.float_of(b, tag_b));
+        self.float(op, x, y, results);
+        self.builder.ins().jump(done, &[]);
+        self.builder.switch_to_block(done);
+    }
+
+    fn both(&mut self, a: Register, b: Register, tag: i64) -> Register {
+        let a = self.is(a, tag);
+        let b = self.is(b, tag);
+        self.builder.ins().band(a, b)
+    }
+
+    //A number or an integer as a float
+    fn float_of(&mut self, operand: Operand, tag: Register) -> Register {
+        match operand {
+            Operand::Constant(Value::Integer(n)) => self.builder.ins().f64const(n as f64),
+            Operand::Constant(_) => self.payload(operand, types::F64),
+            Operand::Stack(_) => {
+                let float = self.payload(operand, types::F64);
+                let integer = self.payload(operand, types::I64);
+                let converted = self.builder.ins().fcvt_from_sint(types::F64, integer);
+                let is_integer = self.is(tag, INTEGER);
+                self.builder.ins().select(is_integer, converted, float)
+            }
+        }
+    }
+
+    fn float(&mut self, op: Arithmetic, x: Register, y: Register, results: &[usize]) {
+        let ins = self.builder.ins();
+        let (tag, result) = match op {
+            Arithmetic::Add => (NUMBER, ins.fadd(x, y)),
+            Arithmetic::Subtract => (NUMBER, ins.fsub(x, y)),
+            Arithmetic::Multiply => (NUMBER, ins.fmul(x, y)),
+            Arithmetic::Divide => (NUMBER, ins.fdiv(x, y)),
+            Arithmetic::Less | Arithmetic::Greater => {
+                let cc = match op {
+                    Arithmetic::Less => FloatCC::LessThan,
+                    _ => FloatCC::GreaterThan,
+                };
+                let result = ins.fcmp(cc, x, y);
+                (BOOLEAN, self.builder.ins().uextend(types::I64, result))
+            }
+        };
+        for slot in results.iter() {
+            self.store(*slot, tag, result);
+        }
+    }
+
+    fn negate(&mut self, ip: usize, slot: usize) {
+        let operand = Operand::Stack(slot);
+        let tag = self.tag(operand);
+        let number = self.builder.create_block();
+        let not_number = self.builder.create_block();
+        let integer = self.builder.create_block();
+        let done = self.builder.create_block();
+        let is_number = self.is(tag, NUMBER);
+        self.builder
+            .ins()
+            .brif(is_number, number, &[], not_number, &[]);
+
+        self.builder.switch_to_block(number);
+        let n = self.payload(operand, types::F64);
+        let n = self.builder.ins().fneg(n);
+        self.store(slot, NUMBER, n);
+        self.builder.ins().jump(done, &[]);
+
+        self.builder.switch_to_block(not_number);
+        let is_integer = self.is(tag, INTEGER);
+        let exit = self.exit(ip);
+        self.builder.ins().brif(is_integer, integer, &[], exit, &[]);
+
+        self.builder.switch_to_block(integer);
+        let n = self.payload(operand, types::I64);
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        let (n, overflow) = self.builder.ins().ssub_overflow(zero, n);
+        let fits = self.builder.ins().icmp_imm(IntCC::Equal, overflow, 0);
+        self.guard(fits, ip);
+        self.store(slot, INTEGER, n);
+        self.builder.ins().jump(done, &[]);
+        self.builder.switch_to_block(done);
+    }
+
+    //Values with the same tag, the interpreter compares the rest
+    fn equal(&mut self, ip: usize, slot: usize) {
+        let (a, b) = (Operand::Stack(slot), Operand::Stack(slot + 1));
+        let (tag_a, tag_b) = (self.tag(a), self.tag(b));
+        let same = self.builder.ins().icmp(IntCC::Equal, tag_a, tag_b);
+        self.guard(same, ip);
+
+        let (x, y) = (self.payload(a, types::F64), self.payload(b, types::F64));
+        let numbers = self.builder.ins().fcmp(FloatCC::Equal, x, y);
+        let (x, y) = (self.payload(a, types::I64), self.payload(b, types::I64));
+        let words = self.builder.ins().icmp(IntCC::Equal, x, y);
+        let offset = Self::offset(slot) + PAYLOAD;
+        let flags = MemFlags::trusted();
+        let x = self
+            .builder
+            .ins()
+            .uload8(types::I32, flags, self.stack, offset);
+        let offset = offset + VALUE_SIZE as i32;
+        let y = self
+            .builder
+            .ins()
+            .uload8(types::I32, flags, self.stack, offset);
+        let bytes = self.builder.ins().icmp(IntCC::Equal, x, y);
+
+        let is_number = self.is(tag_a, NUMBER);
+        let is_boolean = self.is(tag_a, BOOLEAN);
+        let is_nil = self.is(tag_a, NIL);
+        let one = self.builder.ins().iconst(types::I8, 1);
+        let result = self.builder.ins().select(is_boolean, bytes, words);
+        let result = self.builder.ins().select(is_number, numbers, result);
+        let result = self.builder.ins().select(is_nil, one, result);
+        let result = self.builder.ins().uextend(types::I64, result);
+        self.store(slot, BOOLEAN, result);
+    }
+}
+
+fn tag_of(value: Value) -> i64 {
+    match value {
+        Value::Number(_) => NUMBER,
+        Value::Integer(_) => INTEGER,
+        Value::Boolean(_) => BOOLEAN,
+        Value::Object(_) => OBJECT,
+        Value::Nil => NIL,
+    }
+}
+
+fn payload_of(value: Value) -> i64 {
+    match value {
+        Value::Number(n) => n.to_bits() as i64,
+        Value::Integer(n) => n,
+        Value::Boolean(b) => b as i64,
+        Value::Object(ptr) => ptr as i64,
+        Value::Nil => 0,
+    }
+}
+
+#[cfg(test)]
+mod jit_tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::VM;
+    use crate::scanner::scan_tokens;
+    use crate::test_support;
+
+    fn run(vm: &mut VM, source: &str) -> Vec<String> {
+        test_support::run(vm, source).unwrap();
+        vm.globals()
+            .into_iter()
+            .map(|(name, value)| format!("{}={}", name, vm.inspect(value)))
+            .collect()
+    }
+
+    //What the script gives interpreted, and how many functions got native code
+    fn compare(source: &str) -> u64 {
+        let mut plain = VM::new();
+        let expected = run(&mut plain, source);
+        let mut vm = VM::new();
+        vm.enable_tiering(5);
+        assert_eq!(run(&mut vm, source), expected);
+        vm.tiering_mut().unwrap().jit.as_ref().unwrap().compiled
+    }
+
+    fn function(source: &str, name: &str) -> Function {
+        let tokens = scan_tokens(&String::from(source)).unwrap();
+        let mut compiler = Compiler::new(tokens, VirtualMemory::new());
+        let main = compiler.compile().ok().unwrap();
+        let ptr = main
+            .chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Value::Object(ptr) => match compiler.heap.deref(*ptr) {
+                    Object::Function(function) if function.name == name => Some(*ptr),
+                    _ => None,
+                },
+                _ => None,
+            });
+        compiler.heap.deref(ptr.unwrap()).as_function().clone()
+    }
+
+    #[test]
+    fn values_are_laid_out_as_the_code_expects() {
+        assert_eq!(std::mem::size_of::<Value>(), VALUE_SIZE);
+        let values = [
+            Value::Number(1.5),
+            Value::Integer(-3),
+            Value::Boolean(true),
+            Value::Object(42),
+            Value::Nil,
+        ];
+        for value in values.iter() {
+            let ptr = value as *const Value as *const u8;
+            unsafe {
+                assert_eq!(*ptr as i64, tag_of(*value));
+                let payload = ptr.add(PAYLOAD as usize);
+                match value {
+                    Value::Boolean(b) => assert_eq!(*payload, *b as u8),
+                    Value::Nil => {}
+                    _ => assert_eq!((payload as *const i64).read(), payload_of(*value)),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn runs_hot_loops_natively() {
+        let compiled = compare(
+            "fun sum(n) { var total = 0; for (var i = 0; i < n; i = i + 1) { \
+             total = total + i * 0.5; if (total > 100) total = total - 100; } return total; }\n\
+             var x = sum(1000);\n\
+             var y = sum(10) == sum(10);",
+        );
+        assert_eq!(compiled, 1);
+    }
+
+    #[test]
+    fn hands_other_values_to_the_interpreter() {
+        //Strings and integers that overflow leave the native code at the
+        //instruction they reach, mixed operands are done with floats
+        let compiled = compare(
+            "fun add(a, b) { return a + b; }\n\
+             fun neg(a) { return -a; }\n\
+             var s = \"\";\n\
+             for (var i = 0; i < 20; i = i + 1) s = add(s, \"a\");\n\
+             var big = add(9223372036854775807, 1);\n\
+             var mixed = add(1, 0.5);\n\
+             for (var i = 0; i < 5; i = i + 1) neg(i);\n\
+             var min = neg(-9223372036854775807 - 1);\n\
+             fun later() { return defined + 1; }\n\
+             var defined = 1;\n\
+             var x = later() + later() + later() + later() + later() + later();",
+        );
+        assert_eq!(compiled, 3);
+    }
+
+    #[test]
+    fn leaves_functions_it_cant_step_over_alone() {
+        let f = function("fun f(a) { return a + 1; }", "f");
+        let (depths, max_depth) = stack_depths(&f).unwrap();
+        assert_eq!(depths[0], Some(2));
+        assert_eq!(max_depth, 3);
+
+        let outer = function(
+            "fun outer() { var a = 1; fun inner() { return a; } return inner; }",
+            "outer",
+        );
+        assert_eq!(stack_depths(&outer).unwrap_err(), "Closure isn't compiled");
+        let thrower = function(
+            "fun thrower() { try { return 1; } catch (e) { return 2; } }",
+            "thrower",
+        );
+        assert_eq!(stack_depths(&thrower).unwrap_err(), "Try isn't compiled");
+    }
+}
@@ -0,0 +1,1071 @@
+use super::ast::*;
+use super::natives::STRING_METHODS;
+
+// Lowers a parsed program to JavaScript. The output keeps the shape of the
+// source so it stays readable, with a short prelude for the places where
+// Lox and JavaScript disagree: truthiness, printing, callable classes and
+// bound methods and lists. Runtime type and arity errors are not checked, so a program
+// the VM rejects may still run here. Integers become plain numbers, exact only
+// up to 2^53.
+
+const PRELUDE: &str = r#"// Generated by lox-vm emit-js
+const $truthy = (v) => v != null && v !== false;
+const $and = (a, b) => ($truthy(a) ? b() : a);
+const $or = (a, b) => ($truthy(a) ? a : b());
+const $str = (v) => {
+  if (v == null) return "nil";
+  if (typeof v === "number") {
+    if (Object.is(v, -0)) return "-0";
+    if (v === Infinity) return "inf";
+    if (v === -Infinity) return "-inf";
+    return String(v);
+  }
+  if (typeof v === "function") {
+    if (v.prototype instanceof $Instance) return v.name;
+    return `<fn ${v.name.replace(/^(bound )+/, "")}>`;
+  }
+  if (v instanceof $Instance) {
+    //Only a toString from the Lox class is bound onto the instance itself
+    const own = Object.prototype.hasOwnProperty.call(v, "toString");
+    if (!own) return `${v.constructor.name} instance`;
+    const s = v.toString();
+    if (typeof s !== "string") throw new Error(`toString must return a string, not ${$str(s)}`);
+    return s;
+  }
+  if (v instanceof $Generator) return `<generator ${v.name}>`;
+  if (v instanceof $List || v instanceof $Tuple) {
+    const [open, close] = v instanceof $List ? ["[", "]"] : ["(", ")"];
+    if ($printing.has(v)) return `${open}...${close}`;
+    $printing.add(v);
+    const items = Array.from(v, $str).join(", ");
+    $printing.delete(v);
+    return `${open}${items}${close}`;
+  }
+  return String(v);
+};
+// Lists being printed, so one inside itself isn't printed forever.
+const $printing = new Set();
+// Lox lists with the VM's methods.
+class $List extends Array {
+  push(v) {
+    super.push(v);
+    return null;
+  }
+  len() {
+    return this.length;
+  }
+}
+// Lox's string methods, counting characters rather than UTF-16 units.
+const $strings = {
+  length: (s) => Array.from(s).length,
+  substring: (s, start, end) => {
+    const chars = Array.from(s);
+    for (const p of [start, end]) {
+      if (!Number.isInteger(p) || p < 0) throw new Error("String positions must be whole numbers");
+      if (p > chars.length) throw new Error(`Position ${p} is past the end of the string`);
+    }
+    if (start > end) throw new Error(`Substring start ${start} is after its end ${end}`);
+    return chars.slice(start, end).join("");
+  },
+  indexOf: (s, needle) => {
+    const unit = s.indexOf(needle);
+    return unit < 0 ? -1 : Array.from(s.slice(0, unit)).length;
+  },
+  split: (s, separator) => $List.from(separator === "" ? Array.from(s) : s.split(separator)),
+  upper: (s) => s.toUpperCase(),
+  lower: (s) => s.toLowerCase(),
+  trim: (s) => s.trim(),
+};
+// Calls that could be to a string method, which strings can't hold themselves.
+const $method = (v, name, ...args) =>
+  typeof v === "string" ? $strings[name](v, ...args) : v[name](...args);
+// What a match arm's type pattern checks, where a class only matches its own instances.
+const $is = (v, type) => {
+  const isClass = typeof v === "function" && v.prototype instanceof $Instance;
+  switch (type) {
+    case "Nil": return v == null;
+    case "Bool": return typeof v === "boolean";
+    case "Number": return typeof v === "number";
+    case "String": return typeof v === "string";
+    case "List": return v instanceof $List;
+    case "Tuple": return v instanceof $Tuple;
+    case "Class": return isClass;
+    case "Function": return typeof v === "function" && !isClass;
+    case "Generator": return v instanceof $Generator;
+    case "Module": return false;
+    default: return v instanceof $Instance && v.constructor.name === type;
+  }
+};
+// The VM's type native, with the same lower case names.
+globalThis.type = (v) => {
+  const types = ["Nil", "Bool", "Number", "String", "Tuple", "List", "Class", "Function"];
+  const found = types.find((type) => $is(v, type));
+  if (found) return found.toLowerCase();
+  return v instanceof $Generator ? "generator" : v instanceof $Instance ? "instance" : "userdata";
+};
+globalThis.assert = (condition, message) => {
+  if (condition == null || condition === false) throw `Assertion failed: ${$str(message)}`;
+  return null;
+};
+// Conversions, parsing only what Rust's parse would.
+globalThis.number = (v) => {
+  if (typeof v === "number") return v;
+  if (typeof v !== "string") throw "number expects a string";
+  const text = /^[+-]?(\d+\.?\d*|\.\d+)(e[+-]?\d+)?$/i;
+  return text.test(v.trim()) && Number.isFinite(Number(v.trim())) ? Number(v.trim()) : null;
+};
+globalThis.string = (v) => $str(v);
+globalThis.format = (template, ...args) => {
+  let next = 0;
+  const placeholder = (text) => {
+    const [, position, precision] = /^(\d*)(?::(?:\.(\d+))?)?$/.exec(text) ?? [];
+    if (position === undefined) throw `Invalid placeholder '{${text}}'`;
+    const index = position === "" ? next++ : Number(position);
+    if (index >= args.length) throw `No argument ${index} for format`;
+    if (precision === undefined) return $str(args[index]);
+    if (typeof args[index] !== "number") throw `{${text}} needs a number`;
+    return args[index].toFixed(Number(precision));
+  };
+  return template.replace(/\{\{|\}\}|\{([^}]*)\}|[{}]/g, (found, text) => {
+    if (found === "{{" || found === "}}") return found[0];
+    if (found === "{") throw "Unclosed '{' in format template";
+    if (found === "}") throw "Unmatched '}' in format template";
+    return placeholder(text);
+  });
+};
+const $nomatch = (v) => {
+  throw `No match arm for ${$str(v)}`;
+};
+// Generators with the VM's next() and done(), where next() gives nil once finished.
+class $Generator {
+  constructor(name, steps) {
+    this.name = name;
+    this.steps = steps;
+    this.finished = false;
+  }
+  next() {
+    if (this.finished) return null;
+    let step;
+    try {
+      step = this.steps.next();
+    } catch (e) {
+      this.finished = true;
+      throw e;
+    }
+    if (step.done) this.finished = true;
+    return step.value === undefined ? null : step.value;
+  }
+  done() {
+    return this.finished;
+  }
+}
+// What return a, b; gives, which can't be changed.
+class $Tuple extends Array {}
+const $tuple = (...items) => Object.freeze($Tuple.from(items));
+const $print = (v) => console.log($str(v));
+// Seconds since the program started, like the VM's clock native.
+globalThis.clock = () => performance.now() / 1000;
+for (const name of ["sqrt", "abs", "floor", "ceil", "sin", "cos", "pow", "min", "max"]) {
+  globalThis[name] = Math[name];
+}
+// The VM's xorshift64* generator, so a seed gives the same numbers as there.
+let $rng = BigInt(Date.now()) ^ 0x9e3779b97f4a7c15n;
+const $random = () => {
+  $rng ^= $rng >> 12n;
+  $rng ^= BigInt.asUintN(64, $rng << 25n);
+  $rng ^= $rng >> 27n;
+  return BigInt.asUintN(64, $rng * 0x2545f4914f6cdd1dn);
+};
+globalThis.random = () => Number($random() >> 11n) / 2 ** 53;
+globalThis.randomInt = (lo, hi) => lo + Number($random() % BigInt(hi - lo + 1));
+globalThis.randomSeed = (seed) => {
+  $rng = BigInt.asUintN(64, BigInt(seed)) ^ 0x9e3779b97f4a7c15n;
+  return null;
+};
+globalThis.exit = (status) => process.exit(status);
+globalThis.getenv = (name) => process.env[name] ?? null;
+globalThis.setenv = (name, value) => {
+  if (name === "" || name.includes("=") || name.includes("\0")) {
+    throw `Invalid environment variable name '${name}'`;
+  }
+  if (value === null) delete process.env[name];
+  else process.env[name] = value;
+  return null;
+};
+// Blocks like the VM's sleep rather than yielding to the event loop.
+globalThis.sleep = (ms) => {
+  if (!(ms >= 0 && Number.isFinite(ms))) throw `Can't sleep for ${ms} milliseconds`;
+  Atomics.wait(new Int32Array(new SharedArrayBuffer(4)), 0, 0, ms);
+  return null;
+};
+// Standard input a byte at a time, so nothing after the line is read ahead.
+globalThis.readLine = () => {
+  const fs = require("fs");
+  const byte = Buffer.alloc(1);
+  const bytes = [];
+  while (fs.readSync(0, byte, 0, 1, null) === 1) {
+    if (byte[0] === 10) return Buffer.from(bytes).toString().replace(/\r$/, "");
+    bytes.push(byte[0]);
+  }
+  return bytes.length ? Buffer.from(bytes).toString() : null;
+};
+globalThis.readNumber = () => {
+  const line = readLine();
+  return line === null ? null : number(line);
+};
+// Files, failing with the VM's messages so catch gets a string.
+const $file = (doing, path, action) => {
+  try {
+    return action(require("fs")) ?? null;
+  } catch (e) {
+    throw `Couldn't ${doing} '${path}': ${e.message}`;
+  }
+};
+globalThis.readFile = (path) => $file("read", path, (fs) => fs.readFileSync(path, "utf8"));
+globalThis.writeFile = (path, contents) =>
+  $file("write", path, (fs) => fs.writeFileSync(path, contents));
+globalThis.appendFile = (path, contents) =>
+  $file("append to", path, (fs) => fs.appendFileSync(path, contents));
+globalThis.exists = (path) => require("fs").existsSync(path);
+// Bitwise operators work on 64 bit integers like the VM's, not JavaScript's 32 bit ones.
+const $int = (v) => BigInt.asIntN(64, BigInt(Math.trunc(v)));
+const $bits = (n) => Number(BigInt.asIntN(64, n));
+// Errors JavaScript raises are caught as their message, like the VM's runtime errors.
+const $caught = (e) => (e instanceof Error ? e.message : e);
+// Modules are only compiled one file at a time, so imports can't be followed.
+const $import = (path) => {
+  throw new Error(`Can't import ${path} from JavaScript output`);
+};
+// Methods stay bound to their instance when taken as values, like Lox.
+class $Instance {
+  constructor(...args) {
+    let proto = Object.getPrototypeOf(this);
+    for (; proto !== $Instance.prototype; proto = Object.getPrototypeOf(proto)) {
+      for (const name of Object.getOwnPropertyNames(proto)) {
+        //Getters and setters have no value and stay on the prototype
+        const method = Object.getOwnPropertyDescriptor(proto, name).value;
+        const own = Object.prototype.hasOwnProperty.call(this, name);
+        if (name !== "constructor" && typeof method === "function" && !own) {
+          this[name] = method.bind(this);
+        }
+      }
+    }
+    if (this.init) this.init(...args);
+  }
+}
+// Later superclasses only fill in methods the earlier ones lack, like Lox.
+const $mixin = (first, ...rest) => {
+  const mixed = class extends first {};
+  for (const cls of rest) {
+    let proto = cls.prototype;
+    for (; proto !== $Instance.prototype; proto = Object.getPrototypeOf(proto)) {
+      for (const name of Object.getOwnPropertyNames(proto)) {
+        if (name !== "constructor" && !(name in mixed.prototype)) {
+          const descriptor = Object.getOwnPropertyDescriptor(proto, name);
+          Object.defineProperty(mixed.prototype, name, descriptor);
+        }
+      }
+    }
+    for (let c = cls; c !== $Instance; c = Object.getPrototypeOf(c)) {
+      for (const name of Object.getOwnPropertyNames(c)) {
+        if (!(name in mixed)) mixed[name] = c[name];
+      }
+    }
+  }
+  return mixed;
+};
+// Lox classes are called like functions, and their static methods stay bound.
+const $class = (cls) =>
+  new Proxy(cls, {
+    apply: (target, _, args) => new target(...args),
+    get: (target, name, receiver) => {
+      const value = Reflect.get(target, name, receiver);
+      return typeof value === "function" && name !== "constructor" ? value.bind(receiver) : value;
+    },
+  });
+"#;
+
+//Valid Lox identifiers that can't be used as is in JavaScript, or that the
+//prelude relies on.
+const RESERVED: &[&str] = &[
+    "arguments",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "console",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "enum",
+    "eval",
+    "export",
+    "extends",
+    "finally",
+    "globalThis",
+    "implements",
+    "import",
+    "in",
+    "Infinity",
+    "instanceof",
+    "interface",
+    "let",
+    "NaN",
+    "new",
+    "Object",
+    "package",
+    "private",
+    "protected",
+    "Proxy",
+    "public",
+    "static",
+    "String",
+    "switch",
+    "throw",
+    "try",
+    "typeof",
+    "undefined",
+    "void",
+    "with",
+    "yield",
+];
+
+pub fn emit(statements: &[Stmt]) -> String {
+    let mut emitter = Emitter {
+        out: String::from(PRELUDE),
+        indent: 0,
+        depth: 0,
+        in_method: false,
+        in_initializer: false,
+    };
+    emitter.out.push('\n');
+    for stmt in statements.iter() {
+        emitter.statement(stmt);
+    }
+    emitter.out
+}
+
+struct Emitter {
+    out: String,
+    indent: usize,
+    depth: usize, //Block nesting, 0 for globals
+    in_method: bool,
+    in_initializer: bool,
+}
+
+fn name(identifier: &Identifier) -> String {
+    if RESERVED.contains(&identifier.name.as_str()) {
+        format!("{}$", identifier.name)
+    } else {
+        identifier.name.clone()
+    }
+}
+
+fn is_string_method(name: &Identifier) -> bool {
+    STRING_METHODS.iter().any(|method| method.name == name.name)
+}
+
+//break or continue, naming the loop if Lox did
+fn jump(keyword: &str, label: &Option<Identifier>) -> String {
+    match label {
+        Some(label) => format!("{} {};", keyword, name(label)),
+        None => format!("{};", keyword),
+    }
+}
+
+fn string_literal(s: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            //Line terminators in JavaScript but not JSON
+            '\u{2028}' | '\u{2029}' => literal.push_str(&format!("\\u{:04x}", c as u32)),
+            c if c < ' ' => literal.push_str(&format!("\\u{:04x}", c as u32)),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn is_boolean(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Literal(Literal::Boolean(_)) => true,
+        ExprKind::Unary(UnaryOp::Not, _) => true,
+        ExprKind::Binary(_, op, _) => matches!(
+            op,
+            BinaryOp::Equal
+                | BinaryOp::NotEqual
+                | BinaryOp::Greater
+                | BinaryOp::GreaterEqual
+                | BinaryOp::Less
+                | BinaryOp::LessEqual
+        ),
+        ExprKind::Logical(left, _, right) => is_boolean(left) && is_boolean(right),
+        ExprKind::Conditional(_, then_branch, else_branch) => {
+            is_boolean(then_branch) && is_boolean(else_branch)
+        }
+        ExprKind::Grouping(inner) => is_boolean(inner),
+        _ => false,
+    }
+}
+
+fn is_arithmetic(op: BinaryOp) -> bool {
+    matches!(
+        op,
+        BinaryOp::Add | BinaryOp::Subtract | BinaryOp::Multiply | BinaryOp::Divide
+    )
+}
+
+fn is_nil(expr: &Expr) -> bool {
+    expr.kind == ExprKind::Literal(Literal::Nil)
+}
+
+impl Emitter {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn declaration_keyword(&self) -> &'static str {
+        //Globals may be redeclared in Lox
+        if self.depth == 0 {
+            "var"
+        } else {
+            "let"
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) => {
+                let expr = self.expression(expr);
+                self.line(&format!("{};", expr));
+            }
+            Stmt::Print(expr) => {
+                let expr = self.expression(expr);
+                self.line(&format!("$print({});", expr));
+            }
+            Stmt::Var(..) | Stmt::VarUnpack(..) => {
+                let declaration = self.var_declaration(stmt);
+                self.line(&format!("{};", declaration));
+            }
+            Stmt::Block(statements, _) => {
+                self.line("{");
+                self.body(statements);
+                self.line("}");
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                let condition = self.condition(condition);
+                self.line(&format!("if ({}) {{", condition));
+                self.body(std::slice::from_ref(then_branch.as_ref()));
+                if let Some(else_branch) = else_branch {
+                    self.line("} else {");
+                    self.body(std::slice::from_ref(else_branch.as_ref()));
+                }
+                self.line("}");
+            }
+            Stmt::While(condition, body) => {
+                let condition = self.condition(condition);
+                self.line(&format!("while ({}) {{", condition));
+                self.body(std::slice::from_ref(body.as_ref()));
+                self.line("}");
+            }
+            Stmt::DoWhile(body, condition) => {
+                self.line("do {");
+                self.body(std::slice::from_ref(body.as_ref()));
+                let condition = self.condition(condition);
+                self.line(&format!("}} while ({});", condition));
+            }
+            Stmt::For(initializer, condition, increment, body) => {
+                //The loop variable belongs to the loop, not the enclosing scope
+                self.depth += 1;
+                let initializer = match initializer.as_deref() {
+                    Some(var @ (Stmt::Var(..) | Stmt::VarUnpack(..))) => {
+                        self.var_declaration(var)
+                    }
+                    Some(Stmt::Expression(expr)) => self.expression(expr),
+                    _ => String::new(),
+                };
+                let condition = match condition {
+                    Some(condition) => self.condition(condition),
+                    None => String::new(),
+                };
+                let increment = match increment {
+                    Some(increment) => self.expression(increment),
+                    None => String::new(),
+                };
+                self.line(&format!(
+                    "for ({}; {}; {}) {{",
+                    initializer, condition, increment
+                ));
+                self.body(std::slice::from_ref(body.as_ref()));
+                self.line("}");
+                self.depth -= 1;
+            }
+            Stmt::Switch(value, cases, default) => {
+                //A chain of ifs, since a JavaScript switch would catch Lox's
+                //break meant for an enclosing loop
+                let value = self.expression(value);
+                self.line("{");
+                self.indent += 1;
+                self.line(&format!("const $switch = {};", value));
+                for (idx, (case, body)) in cases.iter().enumerate() {
+                    let operator = if is_nil(case) { "==" } else { "===" };
+                    let case = self.expression(case);
+                    let keyword = if idx == 0 { "if" } else { "} else if" };
+                    self.line(&format!("{} ($switch {} {}) {{", keyword, operator, case));
+                    self.body(body);
+                }
+                if let Some(default) = default {
+                    if cases.is_empty() {
+                        self.line("{");
+                    } else {
+                        self.line("} else {");
+                    }
+                    self.body(default);
+                }
+                if !cases.is_empty() || default.is_some() {
+                    self.line("}");
+                }
+                self.indent -= 1;
+                self.line("}");
+            }
+            Stmt::Function(function) => self.function(function),
+            Stmt::Return(_, value) => {
+                let value = match value {
+                    Some(value) => self.expression(value),
+                    None if self.in_initializer => String::from("this"),
+                    None => String::from("null"),
+                };
+                self.line(&format!("return {};", value));
+            }
+            Stmt::Yield(_, value) => {
+                let value = match value {
+                    Some(value) => self.expression(value),
+                    None => String::from("null"),
+                };
+                self.line(&format!("yield {};", value));
+            }
+            Stmt::Try(body, catch, finally) => {
+                self.line("try {");
+                self.body(body);
+                if let Some((error, body)) = catch {
+                    let error = name(error);
+                    self.line(&format!("}} catch ({}) {{", error));
+                    self.indent += 1;
+                    self.line(&format!("{0} = $caught({0});", error));
+                    self.indent -= 1;
+                    self.body(body);
+                }
+                if let Some(finally) = finally {
+                    self.line("} finally {");
+                    self.body(finally);
+                }
+                self.line("}");
+            }
+            Stmt::Throw(_, value) => {
+                let value = self.expression(value);
+                self.line(&format!("throw {};", value));
+            }
+            Stmt::Import(identifier, path) => {
+                let keyword = self.declaration_keyword();
+                let path = string_literal(path);
+                self.line(&format!("{} {} = $import({});", keyword, name(identifier), path));
+            }
+            Stmt::Break(_, label) => self.line(&jump("break", label)),
+            Stmt::Continue(_, label) => self.line(&jump("continue", label)),
+            Stmt::Labeled(label, body) => {
+                self.line(&format!("{}:", name(label)));
+                self.statement(body);
+            }
+            Stmt::Class(class) => self.class(class),
+        }
+    }
+
+    fn body(&mut self, statements: &[Stmt]) {
+        self.indent += 1;
+        self.depth += 1;
+        for stmt in statements.iter() {
+            //Blocks that are a statement's whole body don't need a second set of braces
+            match stmt {
+                Stmt::Block(inner, _) if statements.len() == 1 => self.body_inline(inner),
+                _ => self.statement(stmt),
+            }
+        }
+        self.depth -= 1;
+        self.indent -= 1;
+    }
+
+    fn body_inline(&mut self, statements: &[Stmt]) {
+        for stmt in statements.iter() {
+            self.statement(stmt);
+        }
+    }
+
+    fn var_declaration(&mut self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Var(identifier, _, initializer) => {
+                let value = match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => String::from("null"),
+                };
+                format!(
+                    "{} {} = {}",
+                    self.declaration_keyword(),
+                    name(identifier),
+                    value
+                )
+            }
+            Stmt::VarUnpack(unpacking, names, initializer) => {
+                let keyword = self.declaration_keyword();
+                let names: Vec<String> = match unpacking {
+                    //Reserved words are fine as field names, just not as variables
+                    Unpacking::Fields => names
+                        .iter()
+                        .map(|(n, _)| format!("{}: {}", n.name, name(n)))
+                        .collect(),
+                    _ => names.iter().map(|(n, _)| name(n)).collect(),
+                };
+                match (initializer, unpacking) {
+                    (Some(initializer), Unpacking::Fields) => {
+                        let value = self.expression(initializer);
+                        format!("{} {{ {} }} = {}", keyword, names.join(", "), value)
+                    }
+                    (Some(initializer), _) => {
+                        let value = self.expression(initializer);
+                        format!("{} [{}] = {}", keyword, names.join(", "), value)
+                    }
+                    (None, _) => format!("{} {} = null", keyword, names.join(" = null, ")),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn parameters(function: &FunctionDecl) -> String {
+        let mut params: Vec<String> = function.params.iter().map(name).collect();
+        if let (true, Some(rest)) = (function.variadic, params.last_mut()) {
+            *rest = format!("...{}", rest);
+        }
+        params.join(", ")
+    }
+
+    //Rest parameters arrive as plain arrays, which don't have the list methods
+    fn function_body(&mut self, function: &FunctionDecl) {
+        if let (true, Some(rest)) = (function.variadic, function.params.last()) {
+            self.indent += 1;
+            self.line(&format!("{0} = $List.from({0});", name(rest)));
+            self.indent -= 1;
+        }
+        if !function.is_generator() {
+            self.body(&function.body);
+            return;
+        }
+        //The body waits for the first next(), keeping the method's 'this'
+        self.indent += 1;
+        self.line(&format!(
+            "return new $Generator({}, function* () {{",
+            string_literal(&function.name.name)
+        ));
+        self.body(&function.body);
+        self.line("}.call(this));");
+        self.indent -= 1;
+    }
+
+    fn function(&mut self, function: &FunctionDecl) {
+        let was_initializer = self.in_initializer;
+        self.in_initializer = false;
+        //Arrow functions keep the enclosing method's 'this'
+        if self.in_method {
+            self.line(&format!(
+                "const {} = ({}) => {{",
+                name(&function.name),
+                Emitter::parameters(function)
+            ));
+            self.function_body(function);
+            self.line("};");
+        } else {
+            self.line(&format!(
+                "function {}({}) {{",
+                name(&function.name),
+                Emitter::parameters(function)
+            ));
+            self.function_body(function);
+            self.line("}");
+        }
+        self.in_initializer = was_initializer;
+    }
+
+    fn class(&mut self, class: &ClassDecl) {
+        let value = self.class_value(class, true);
+        self.line(&format!(
+            "{} {} = {};",
+            self.declaration_keyword(),
+            name(&class.name),
+            value
+        ));
+    }
+
+    //The class as an expression, its methods on lines of their own
+    fn class_value(&mut self, class: &ClassDecl, named: bool) -> String {
+        let superclasses: Vec<String> = class.superclasses.iter().map(name).collect();
+        let superclass = match superclasses.len() {
+            0 => String::from("$Instance"),
+            1 => superclasses[0].clone(),
+            _ => format!("$mixin({})", superclasses.join(", ")),
+        };
+
+        let out = core::mem::take(&mut self.out);
+        self.indent += 1;
+        for method in class.methods.iter() {
+            let initializer = method.name.name == "init";
+            self.line(&format!(
+                "{}({}) {{",
+                method.name.name,
+                Emitter::parameters(method)
+            ));
+            self.in_method = true;
+            self.in_initializer = initializer;
+            self.function_body(method);
+            if initializer {
+                self.indent += 1;
+                self.line("return this;");
+                self.indent -= 1;
+            }
+            self.in_method = false;
+            self.in_initializer = false;
+            self.line("}");
+        }
+        let accessors = [
+            ("get ", &class.getters),
+            ("set ", &class.setters),
+            ("static ", &class.statics),
+        ];
+        for (keyword, methods) in accessors {
+            for method in methods.iter() {
+                self.line(&format!(
+                    "{}{}({}) {{",
+                    keyword,
+                    method.name.name,
+                    Emitter::parameters(method)
+                ));
+                self.in_method = true;
+                self.function_body(method);
+                self.in_method = false;
+                self.line("}");
+            }
+        }
+        self.indent -= 1;
+        let methods = core::mem::replace(&mut self.out, out);
+
+        let class_name = if named {
+            format!("{} ", name(&class.name))
+        } else {
+            String::new()
+        };
+        format!(
+            "$class(class {}extends {} {{\n{}{}}})",
+            class_name,
+            superclass,
+            methods,
+            "  ".repeat(self.indent)
+        )
+    }
+
+    fn condition(&mut self, expr: &Expr) -> String {
+        //Only truthiness matters here, so logical operators can stay plain
+        if let ExprKind::Logical(left, op, right) = &expr.kind {
+            let operator = match op {
+                LogicalOp::And => "&&",
+                LogicalOp::Or => "||",
+            };
+            return format!(
+                "{} {} {}",
+                self.condition(left),
+                operator,
+                self.condition(right)
+            );
+        }
+
+        let condition = self.expression(expr);
+        if is_boolean(expr) {
+            condition
+        } else {
+            format!("$truthy({})", condition)
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) -> String {
+        match &expr.kind {
+            ExprKind::Literal(literal) => match literal {
+                Literal::Number(n) => format!("{}", n),
+                Literal::Integer(n) => format!("{}", n),
+                Literal::String(s) => string_literal(s),
+                Literal::Boolean(b) => format!("{}", b),
+                Literal::Nil => String::from("null"),
+            },
+            ExprKind::Variable(identifier) => name(identifier),
+            ExprKind::Assign(identifier, value) => {
+                format!("{} = {}", name(identifier), self.expression(value))
+            }
+            ExprKind::Unary(UnaryOp::Negate, right) => {
+                let right = self.expression(right);
+                if right.starts_with('-') {
+                    format!("-({})", right)
+                } else {
+                    format!("-{}", right)
+                }
+            }
+            ExprKind::Unary(UnaryOp::Not, right) => {
+                if is_boolean(right) {
+                    format!("!{}", self.expression(right))
+                } else {
+                    format!("!$truthy({})", self.expression(right))
+                }
+            }
+            ExprKind::Unary(UnaryOp::BitNot, right) => {
+                format!("$bits(~$int({}))", self.expression(right))
+            }
+            ExprKind::Binary(left, op, right) if !is_boolean(expr) && !is_arithmetic(*op) => {
+                let (left, right) = (self.expression(left), self.expression(right));
+                let operator = match op {
+                    BinaryOp::BitAnd => "&",
+                    BinaryOp::BitOr => "|",
+                    BinaryOp::BitXor => "^",
+                    BinaryOp::ShiftLeft => "<<",
+                    _ => ">>",
+                };
+                let right = match op {
+                    //Only the low 6 bits of the count are used, like the VM
+                    BinaryOp::ShiftLeft | BinaryOp::ShiftRight => {
+                        format!("($int({}) & 63n)", right)
+                    }
+                    _ => format!("$int({})", right),
+                };
+                format!("$bits($int({}) {} {})", left, operator, right)
+            }
+            ExprKind::Binary(left, op, right) => {
+                let operator = match op {
+                    BinaryOp::Add => "+",
+                    BinaryOp::Subtract => "-",
+                    BinaryOp::Multiply => "*",
+                    BinaryOp::Divide => "/",
+                    //Comparing against nil also has to match undefined
+                    BinaryOp::Equal if is_nil(left) || is_nil(right) => "==",
+                    BinaryOp::NotEqual if is_nil(left) || is_nil(right) => "!=",
+                    BinaryOp::Equal => "===",
+                    BinaryOp::NotEqual => "!==",
+                    BinaryOp::Greater => ">",
+                    BinaryOp::GreaterEqual => ">=",
+                    BinaryOp::Less => "<",
+                    BinaryOp::LessEqual => "<=",
+                    _ => unreachable!("Bitwise operators are handled above"),
+                };
+                format!(
+                    "{} {} {}",
+                    self.expression(left),
+                    operator,
+                    self.expression(right)
+                )
+            }
+            ExprKind::Logical(left, op, right) => {
+                let both_boolean = is_boolean(left) && is_boolean(right);
+                let left = self.expression(left);
+                let right = self.expression(right);
+                match (op, both_boolean) {
+                    (LogicalOp::And, true) => format!("{} && {}", left, right),
+                    (LogicalOp::Or, true) => format!("{} || {}", left, right),
+                    (LogicalOp::And, false) => format!("$and({}, () => {})", left, right),
+                    (LogicalOp::Or, false) => format!("$or({}, () => {})", left, right),
+                }
+            }
+            ExprKind::Conditional(condition, then_branch, else_branch) => format!(
+                "{} ? {} : {}",
+                self.condition(condition),
+                self.expression(then_branch),
+                self.expression(else_branch)
+            ),
+            //Odd parts are the embedded expressions
+            ExprKind::Interpolation(parts) => {
+                let parts: Vec<String> = parts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, part)| match i % 2 {
+                        0 => self.expression(part),
+                        _ => format!("$str({})", self.expression(part)),
+                    })
+                    .collect();
+                format!("({})", parts.join(" + "))
+            }
+            ExprKind::Grouping(inner) => format!("({})", self.expression(inner)),
+            ExprKind::Call(callee, arguments) => {
+                let arguments: Vec<String> =
+                    arguments.iter().map(|arg| self.expression(arg)).collect();
+                let callee = match &callee.kind {
+                    ExprKind::Super(method) => format!("super.{}", method.name),
+                    //Might be a string, which can't hold methods in JavaScript
+                    ExprKind::Get(object, method) if is_string_method(method) => {
+                        let mut receiver = vec![
+                            self.expression(object),
+                            string_literal(&method.name),
+                        ];
+                        receiver.extend(arguments);
+                        return format!("$method({})", receiver.join(", "));
+                    }
+                    _ => self.expression(callee),
+                };
+                format!("{}({})", callee, arguments.join(", "))
+            }
+            ExprKind::Get(object, property) => {
+                format!("{}.{}", self.expression(object), property.name)
+            }
+            ExprKind::Compound(target, op, value) => {
+                let operator = match op {
+                    BinaryOp::Add => "+=",
+                    BinaryOp::Subtract => "-=",
+                    BinaryOp::Multiply => "*=",
+                    _ => "/=",
+                };
+                format!(
+                    "{} {} {}",
+                    self.expression(target),
+                    operator,
+                    self.expression(value)
+                )
+            }
+            ExprKind::Set(object, property, value) => format!(
+                "{}.{} = {}",
+                self.expression(object),
+                property.name,
+                self.expression(value)
+            ),
+            ExprKind::List(items) => {
+                let items: Vec<String> = items.iter().map(|item| self.expression(item)).collect();
+                format!("$List.of({})", items.join(", "))
+            }
+            ExprKind::Tuple(items) => {
+                let items: Vec<String> = items.iter().map(|item| self.expression(item)).collect();
+                format!("$tuple({})", items.join(", "))
+            }
+            ExprKind::Index(list, index) => {
+                format!("{}[{}]", self.expression(list), self.expression(index))
+            }
+            ExprKind::IndexSet(list, index, value) => format!(
+                "{}[{}] = {}",
+                self.expression(list),
+                self.expression(index),
+                self.expression(value)
+            ),
+            ExprKind::This => String::from("this"),
+            //Unlike this.method, nothing binds it ahead of time
+            ExprKind::Super(method) => format!("super.{}.bind(this)", method.name),
+            //Left unnamed, so a name only meant for printing can't shadow anything
+            ExprKind::Class(class) => self.class_value(class, false),
+            //A chain of conditionals in an arrow function, which keeps 'this'
+            ExprKind::Match(value, arms) => {
+                let mut chain = String::from("$nomatch($v)");
+                for (pattern, result) in arms.iter().rev() {
+                    let result = self.expression(result);
+                    let bound =
+                        |binding: &Identifier| format!("(({}) => {})($v)", name(binding), result);
+                    chain = match pattern {
+                        //Comparing against nil also has to match undefined
+                        Pattern::Literal(literal) if is_nil(literal) => {
+                            format!("$v == null ? {} : {}", result, chain)
+                        }
+                        Pattern::Literal(literal) => {
+                            format!("$v === {} ? {} : {}", self.expression(literal), result, chain)
+                        }
+                        Pattern::Wildcard => result,
+                        Pattern::Binding(binding) => bound(binding),
+                        Pattern::Type(binding, type_name) => {
+                            let test = format!("$is($v, {})", string_literal(&type_name.name));
+                            let result = binding.as_ref().map_or(result.clone(), bound);
+                            format!("{} ? {} : {}", test, result, chain)
+                        }
+                    };
+                }
+                format!("(($v) => {})({})", chain, self.expression(value))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod js_tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::scanner::scan_tokens;
+
+    fn emit_source(source: &str) -> String {
+        let (statements, errors) = parse(scan_tokens(&String::from(source)).unwrap());
+        assert!(errors.is_empty());
+        let js = emit(&statements);
+        js[PRELUDE.len()..].trim().to_string()
+    }
+
+    #[test]
+    fn functions_and_scopes() {
+        let js = emit_source(
+            "var count = 0;\nfun counter(step) {\n  var total = 0;\n  fun add() { total = total + step; return total; }\n  return add;\n}\nprint counter(2)();",
+        );
+        assert_eq!(
+            js,
+            "var count = 0;\nfunction counter(step) {\n  let total = 0;\n  function add() {\n    total = total + step;\n    return total;\n  }\n  return add;\n}\n$print(counter(2)());"
+        );
+    }
+
+    #[test]
+    fn classes_and_truthiness() {
+        let js = emit_source(
+            "class A {\n  init(new) { this.new = new; if (new) return; }\n}\nclass B < A {}\nwhile (a and b == nil) a = !a;\nprint a or b;",
+        );
+        assert_eq!(
+            js,
+            "var A = $class(class A extends $Instance {\n  init(new$) {\n    this.new = new$;\n    if ($truthy(new$)) {\n      return this;\n    }\n    return this;\n  }\n});\nvar B = $class(class B extends A {\n});\nwhile ($truthy(a) && b == null) {\n  a = !$truthy(a);\n}\n$print($or(a, () => b));"
+        );
+    }
+
+    #[test]
+    fn mixins_and_super() {
+        let js = emit_source("class C < A, B {\n  m() { var f = super.n; return super.m(f); }\n}");
+        assert_eq!(
+            js,
+            "var C = $class(class C extends $mixin(A, B) {\n  m() {\n    \
+             let f = super.n.bind(this);\n    return super.m(f);\n  }\n});"
+        );
+    }
+
+    #[test]
+    fn match_expressions() {
+        let js = emit_source("print match (x) { 1 => \"one\", n: Number => n, _ => nil };");
+        assert_eq!(
+            js,
+            "$print((($v) => $v === 1 ? \"one\" : $is($v, \"Number\") ? ((n) => n)($v) : \
+             null)(x));"
+        );
+    }
+
+    #[test]
+    fn class_expressions() {
+        let js = emit_source("fun f() {
+  return class Named < A { m() { return 1; } };
+}");
+        assert_eq!(
+            js,
+            "function f() {\n  return $class(class extends A {\n    m() {\n      return 1;\n    \
+             }\n  });\n}"
+        );
+    }
+}
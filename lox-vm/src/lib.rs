@@ -0,0 +1,77 @@
+// The compiler and VM only need alloc. Everything that touches files, the
+// terminal or the process needs the std feature.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+// Without the tracing feature logging compiles to nothing
+#[cfg(not(feature = "tracing"))]
+#[macro_use]
+mod no_tracing;
+
+#[cfg(not(feature = "std"))]
+mod prelude;
+
+#[cfg(feature = "aot")]
+pub mod aot;
+#[cfg(feature = "std")]
+pub mod ast;
+#[cfg(feature = "std")]
+pub mod bytecode;
+pub mod chunk;
+pub mod collections;
+#[cfg(feature = "compact-bytecode")]
+pub mod compact;
+pub mod compiler;
+pub mod crash;
+#[cfg(feature = "std")]
+pub mod config;
+pub mod debug;
+pub mod debugger;
+pub mod doc;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod diagnostic;
+#[cfg(feature = "std")]
+pub mod generator;
+pub mod globals;
+pub mod heap_report;
+pub mod hooks;
+pub mod inline_cache;
+pub mod inspect;
+pub mod interpreter;
+#[cfg(feature = "jit")]
+pub mod jit;
+#[cfg(feature = "std")]
+pub mod js;
+#[cfg(feature = "std")]
+pub mod lint;
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod modules;
+pub mod natives;
+#[cfg(feature = "std")]
+pub mod parser;
+pub mod passes;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod profile;
+#[cfg(feature = "std")]
+pub mod resolver;
+pub mod rng;
+pub mod scanner;
+pub mod shape;
+pub mod symbols;
+#[cfg(test)]
+mod test_support;
+#[cfg(feature = "std")]
+pub mod testing;
+pub mod tiering;
+pub mod token;
+#[cfg(feature = "std")]
+pub mod types;
+pub mod value;
+pub mod vtable;
+#[cfg(feature = "std")]
+pub mod workers;
@@ -0,0 +1,428 @@
+use super::ast::*;
+use super::diagnostic::{Diagnostic, Severity};
+use super::resolver::{self, DeclarationKind};
+use super::token::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl Level {
+    #[cfg(feature = "project")]
+    fn parse(s: &str) -> Option<Level> {
+        match s {
+            "allow" => Some(Level::Allow),
+            "warn" => Some(Level::Warn),
+            "deny" => Some(Level::Deny),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rule {
+    UnusedVariable,
+    Shadowing,
+    EmptyBlock,
+    NilComparison,
+    LongFunction,
+}
+
+pub const RULES: [Rule; 5] = [
+    Rule::UnusedVariable,
+    Rule::Shadowing,
+    Rule::EmptyBlock,
+    Rule::NilComparison,
+    Rule::LongFunction,
+];
+
+impl Rule {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::UnusedVariable => "unused-variable",
+            Rule::Shadowing => "shadowing",
+            Rule::EmptyBlock => "empty-block",
+            Rule::NilComparison => "nil-comparison",
+            Rule::LongFunction => "long-function",
+        }
+    }
+
+    fn default_level(&self) -> Level {
+        match self {
+            Rule::NilComparison => Level::Allow,
+            _ => Level::Warn,
+        }
+    }
+}
+
+// Rule levels, read from the [lint] table of lox.toml (see config):
+//
+//   [lint]
+//   shadowing = "deny"
+//   nil-comparison = "warn"
+//   max-function-lines = 30
+#[derive(Debug, Clone)]
+pub struct LintConfig {
+    levels: Vec<(Rule, Level)>,
+    pub max_function_lines: usize,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        LintConfig {
+            levels: RULES.iter().map(|r| (*r, r.default_level())).collect(),
+            max_function_lines: 50,
+        }
+    }
+}
+
+impl LintConfig {
+    pub fn level(&self, rule: Rule) -> Level {
+        self.levels
+            .iter()
+            .find(|(r, _)| *r == rule)
+            .map(|(_, level)| *level)
+            .unwrap_or_else(|| rule.default_level())
+    }
+
+    pub fn set_level(&mut self, rule: Rule, level: Level) {
+        self.levels.retain(|(r, _)| *r != rule);
+        self.levels.push((rule, level));
+    }
+
+    #[cfg(feature = "project")]
+    pub fn parse(source: &str) -> Result<LintConfig, String> {
+        let table: toml::Table = source.parse().map_err(|e| format!("{}", e))?;
+        match table.get("lint") {
+            Some(toml::Value::Table(lint)) => LintConfig::from_table(lint),
+            Some(_) => Err(String::from("[lint] must be a table")),
+            None => Ok(LintConfig::default()),
+        }
+    }
+
+    #[cfg(feature = "project")]
+    pub fn from_table(lint: &toml::Table) -> Result<LintConfig, String> {
+        let mut config = LintConfig::default();
+        for (key, value) in lint.iter() {
+            if key == "max-function-lines" {
+                config.max_function_lines = value
+                    .as_integer()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| String::from("max-function-lines must be a positive integer"))?
+                    as usize;
+                continue;
+            }
+
+            let rule = RULES
+                .iter()
+                .find(|r| r.name() == key)
+                .ok_or_else(|| format!("Unknown lint rule '{}'", key))?;
+            let level = value
+                .as_str()
+                .and_then(Level::parse)
+                .ok_or_else(|| format!("Lint level for '{}' must be allow, warn or deny", key))?;
+            config.set_level(*rule, level);
+        }
+
+        Ok(config)
+    }
+}
+
+struct Linter<'a> {
+    config: &'a LintConfig,
+    file: Option<&'a str>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// Runs the enabled rules over a parsed program. Warnings come back as
+// Severity::Warning and denied rules as Severity::Error.
+pub fn lint(statements: &[Stmt], config: &LintConfig, file: Option<&str>) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        config,
+        file,
+        diagnostics: vec![],
+    };
+
+    let resolution = resolver::resolve(statements);
+    for declaration in resolution.declarations.iter() {
+        let unused_kind = match declaration.kind {
+            DeclarationKind::Local => Some("variable"),
+            DeclarationKind::Function if !declaration.global => Some("function"),
+            _ => None,
+        };
+        if let Some(kind) = unused_kind {
+            if !declaration.used && !declaration.name.starts_with('_') {
+                linter.report(
+                    Rule::UnusedVariable,
+                    declaration.span,
+                    format!("Unused {} '{}'.", kind, declaration.name),
+                );
+            }
+        }
+
+        if let Some(outer) = declaration.shadows {
+            let outer = &resolution.declarations[outer];
+            linter.report(
+                Rule::Shadowing,
+                declaration.span,
+                format!(
+                    "'{}' shadows the declaration on line {}.",
+                    declaration.name, outer.span.line
+                ),
+            );
+        }
+    }
+
+    for stmt in statements.iter() {
+        linter.statement(stmt);
+    }
+
+    linter
+        .diagnostics
+        .sort_by_key(|d| (d.span.line, d.span.column));
+    linter.diagnostics
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, rule: Rule, span: Span, message: String) {
+        let severity = match self.config.level(rule) {
+            Level::Allow => return,
+            Level::Warn => Severity::Warning,
+            Level::Deny => Severity::Error,
+        };
+        self.diagnostics.push(Diagnostic {
+            severity,
+            code: rule.name(),
+            message,
+            file: self.file.map(String::from),
+            span,
+        });
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expression(expr),
+            Stmt::Var(_, _, initializer) | Stmt::VarUnpack(_, _, initializer) => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+            }
+            Stmt::Block(statements, span) => {
+                if statements.is_empty() {
+                    self.report(Rule::EmptyBlock, *span, String::from("Empty block."));
+                }
+                for stmt in statements.iter() {
+                    self.statement(stmt);
+                }
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Stmt::While(condition, body) => {
+                self.expression(condition);
+                self.statement(body);
+            }
+            Stmt::DoWhile(body, condition) => {
+                self.statement(body);
+                self.expression(condition);
+            }
+            Stmt::For(initializer, condition, increment, body) => {
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                if let Some(condition) = condition {
+                    self.expression(condition);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment);
+                }
+                self.statement(body);
+            }
+            Stmt::Switch(value, cases, default) => {
+                self.expression(value);
+                for (case, body) in cases.iter() {
+                    self.expression(case);
+                    for stmt in body.iter() {
+                        self.statement(stmt);
+                    }
+                }
+                for stmt in default.iter().flatten() {
+                    self.statement(stmt);
+                }
+            }
+            Stmt::Function(function) => self.function(function),
+            Stmt::Return(_, value) | Stmt::Yield(_, value) => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Try(body, catch, finally) => {
+                let catch = catch.iter().flat_map(|(_, body)| body.iter());
+                for stmt in body.iter().chain(catch).chain(finally.iter().flatten()) {
+                    self.statement(stmt);
+                }
+            }
+            Stmt::Throw(_, value) => self.expression(value),
+            Stmt::Labeled(_, body) => self.statement(body),
+            Stmt::Import(_, _) | Stmt::Break(..) | Stmt::Continue(..) => {}
+            Stmt::Class(class) => {
+                for method in class.all_methods() {
+                    self.function(method);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, function: &FunctionDecl) {
+        let lines = function.end_line - function.name.span.line + 1;
+        if lines > self.config.max_function_lines {
+            self.report(
+                Rule::LongFunction,
+                function.name.span,
+                format!(
+                    "'{}' is {} lines long (max {}).",
+                    function.name.name, lines, self.config.max_function_lines
+                ),
+            );
+        }
+        for stmt in function.body.iter() {
+            self.statement(stmt);
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Literal(_) | ExprKind::Variable(_) | ExprKind::This | ExprKind::Super(_) => {}
+            ExprKind::Assign(_, value) => self.expression(value),
+            ExprKind::Compound(target, _, value) => {
+                self.expression(target);
+                self.expression(value);
+            }
+            ExprKind::Unary(_, right) => self.expression(right),
+            ExprKind::Binary(left, op, right) => {
+                let is_nil = |e: &Expr| e.kind == ExprKind::Literal(Literal::Nil);
+                if (*op == BinaryOp::Equal || *op == BinaryOp::NotEqual)
+                    && (is_nil(left) || is_nil(right))
+                {
+                    self.report(
+                        Rule::NilComparison,
+                        expr.span,
+                        String::from("Comparison against nil."),
+                    );
+                }
+                self.expression(left);
+                self.expression(right);
+            }
+            ExprKind::Logical(left, _, right) => {
+                self.expression(left);
+                self.expression(right);
+            }
+            ExprKind::Conditional(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                self.expression(then_branch);
+                self.expression(else_branch);
+            }
+            ExprKind::Interpolation(parts) => {
+                for part in parts.iter() {
+                    self.expression(part);
+                }
+            }
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Call(callee, args) => {
+                self.expression(callee);
+                for arg in args.iter() {
+                    self.expression(arg);
+                }
+            }
+            ExprKind::Get(object, _) => self.expression(object),
+            ExprKind::Set(object, _, value) => {
+                self.expression(object);
+                self.expression(value);
+            }
+            ExprKind::List(items) | ExprKind::Tuple(items) => {
+                for item in items.iter() {
+                    self.expression(item);
+                }
+            }
+            ExprKind::Index(list, index) => {
+                self.expression(list);
+                self.expression(index);
+            }
+            ExprKind::IndexSet(list, index, value) => {
+                self.expression(list);
+                self.expression(index);
+                self.expression(value);
+            }
+            ExprKind::Class(class) => {
+                for method in class.all_methods() {
+                    self.function(method);
+                }
+            }
+            ExprKind::Match(value, arms) => {
+                self.expression(value);
+                for (_, result) in arms.iter() {
+                    self.expression(result);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod lint_tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::scanner::scan_tokens;
+
+    fn lint_source(source: &str, config: &LintConfig) -> Vec<(&'static str, usize)> {
+        let (statements, errors) = parse(scan_tokens(&String::from(source)).unwrap());
+        assert!(errors.is_empty());
+        lint(&statements, config, None)
+            .iter()
+            .map(|d| (d.code, d.span.line))
+            .collect()
+    }
+
+    #[test]
+    fn default_rules() {
+        let source = "var a = 1;\nfun f(b) {\n  var a = b;\n  var unused;\n  if (a == nil) {}\n  return a;\n}";
+        assert_eq!(
+            lint_source(source, &LintConfig::default()),
+            vec![
+                ("shadowing", 3),
+                ("unused-variable", 4),
+                ("empty-block", 5)
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "project")]
+    fn configured_levels() {
+        let config = LintConfig::parse(
+            "[lint]\nshadowing = \"allow\"\nnil-comparison = \"deny\"\nmax-function-lines = 2\n",
+        )
+        .unwrap();
+        assert_eq!(config.level(Rule::NilComparison), Level::Deny);
+
+        let source = "fun f(a) {\n  print a == nil;\n}";
+        assert_eq!(
+            lint_source(source, &config),
+            vec![("long-function", 1), ("nil-comparison", 2)]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "project")]
+    fn rejects_bad_config() {
+        assert!(LintConfig::parse("[lint]\nno-such-rule = \"warn\"").is_err());
+        assert!(LintConfig::parse("[lint]\nshadowing = \"loud\"").is_err());
+    }
+}
@@ -1,87 +1,723 @@
-mod chunk;
-mod compiler;
-mod interpreter;
-mod scanner;
-mod token;
-mod value;
-
-use rustyline::error::ReadlineError;
-use rustyline::Editor;
-use std::env;
+mod bundle;
+mod chrome_trace;
+mod crash_guard;
+mod dap;
+mod repl;
+
+use lox_vm::config::{self, VmOptions};
+use lox_vm::diagnostic::{Diagnostic, Severity};
+use lox_vm::interpreter::{InterpreterError, VirtualMemory};
+use lox_vm::passes::PassManager;
+use lox_vm::{compiler, debug, interpreter, js, lint, parser, plugin, scanner, testing, types};
+
+use chrome_trace::ChromeTrace;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
 use std::error;
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
+use tracing_subscriber::EnvFilter;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => run_prompt(),
-        2 => run_file(&args[1]).unwrap(),
-        _ => println!("Usage: [script]"),
+#[derive(Parser)]
+#[command(name = "lox-vm", version, about = "A bytecode virtual machine for Lox")]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+
+    /// Log more, -v for debug and -vv for trace (RUST_LOG takes precedence)
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// How to report errors
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Human, global = true)]
+    error_format: ErrorFormat,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a script or project directory, or start the REPL without one
+    Run(RunArgs),
+    /// Check a script or project against the lint rules in lox.toml
+    Lint { script: String },
+    /// Check a script or project for errors without running it
+    Check {
+        script: String,
+        /// Also check type annotations against the values that reach them
+        #[arg(long)]
+        types: bool,
+    },
+    /// Run the test functions in *_test.lox files under each path
+    Test {
+        #[arg(default_value = ".")]
+        paths: Vec<PathBuf>,
+    },
+    /// Compile a script or project into a standalone executable
+    Bundle {
+        script: String,
+        /// Where to write the executable
+        #[arg(short, long, value_name = "PATH")]
+        output: PathBuf,
+    },
+    /// Compile a script to a native executable (experimental)
+    #[cfg(feature = "aot")]
+    Build {
+        script: String,
+        /// Where to write the executable
+        #[arg(short, long, value_name = "PATH")]
+        output: PathBuf,
+    },
+    /// Translate a script to JavaScript
+    EmitJs {
+        script: String,
+        /// Write the JavaScript here instead of to stdout
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Serve the Debug Adapter Protocol on stdin and stdout
+    Dap,
+    /// Print a shell completion script
+    Completions { shell: Shell },
+}
+
+#[derive(clap::Args, Clone)]
+struct RunArgs {
+    /// Print each instruction and the stack as it executes
+    #[arg(long)]
+    trace: bool,
+
+    /// Print the compiled bytecode before running
+    #[arg(long)]
+    dump_bytecode: bool,
+
+    /// Print the bytecode before and after each optimization pass that changes it,
+    /// including passes run on functions that get hot
+    #[arg(long)]
+    dump_opt: bool,
+
+    /// Read settings from this file instead of the nearest lox.toml
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Estimated bytes allocated before the first garbage collection
+    #[arg(long, value_name = "BYTES")]
+    gc_threshold: Option<usize>,
+
+    /// How far the threshold grows past what survives a collection
+    #[arg(long, value_name = "FACTOR", value_parser = parse_growth)]
+    gc_growth: Option<f64>,
+
+    /// Objects marked per instruction while collecting, 0 for all at once
+    #[arg(long, value_name = "N")]
+    gc_step: Option<usize>,
+
+    /// Deepest call stack allowed before a stack overflow
+    #[arg(long, value_name = "N")]
+    max_call_depth: Option<usize>,
+
+    /// Values on the stack before a call overflows it
+    #[arg(long, value_name = "N")]
+    stack_size: Option<usize>,
+
+    /// Optimization level for the compiler
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(0..=2))]
+    opt_level: Option<u8>,
+
+    /// Calls or loop iterations before a function is re-optimized, 0 to never
+    #[arg(long, value_name = "N")]
+    hot_threshold: Option<u64>,
+
+    /// Write calls, returns and collections here in the Chrome trace event format
+    #[arg(long, value_name = "PATH")]
+    trace_file: Option<PathBuf>,
+
+    /// Run this file first, so its globals are defined for the script or REPL
+    #[arg(long, value_name = "PATH")]
+    prelude: Option<PathBuf>,
+
+    /// Load natives from this plugin, a shared library built against lox-vm (repeatable)
+    #[arg(long, value_name = "PATH")]
+    plugin: Vec<PathBuf>,
+
+    /// Write opcode, call, allocation and GC counts here as JSON when the script exits
+    #[arg(long, value_name = "PATH")]
+    metrics: Option<PathBuf>,
+
+    /// Count every instruction run and print the hottest functions and loops to stderr at exit
+    #[arg(long)]
+    profile: bool,
+
+    /// A script, or a project directory containing main.lox
+    script: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+pub struct Options {
+    vm: VmOptions,
+    trace: bool,
+    dump_bytecode: bool,
+    dump_opt: bool,
+    metrics: Option<PathBuf>,
+    profile: bool,
+    trace_file: Option<PathBuf>,
+    prelude: Option<PathBuf>,
+    plugins: Vec<PathBuf>,
+    error_format: ErrorFormat,
+    script: Option<String>,
+}
+
+impl Options {
+    fn new(cli: &Cli, run: &RunArgs) -> Options {
+        let entry = run.script.as_deref().map(|script| {
+            config::entry_point(Path::new(script)).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(66);
+            })
+        });
+        let script = entry.as_ref().map(|(script, _)| script.as_path());
+        let vm = match &run.config {
+            Some(path) => VmOptions::load(path),
+            None => VmOptions::discover(script),
+        };
+        let mut vm = vm.unwrap_or_else(|e| {
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(64);
+        });
+        vm.module_root = entry.as_ref().map(|(_, root)| root.clone());
+        if let Some(threshold) = run.gc_threshold {
+            vm.gc_threshold = threshold;
+        }
+        if let Some(growth) = run.gc_growth {
+            vm.gc_growth = growth;
+        }
+        if let Some(step) = run.gc_step {
+            vm.gc_step = step;
+        }
+        if let Some(depth) = run.max_call_depth {
+            vm.max_call_depth = depth;
+        }
+        if let Some(size) = run.stack_size {
+            vm.stack_size = size;
+        }
+        if let Some(level) = run.opt_level {
+            vm.opt_level = level;
+        }
+        if let Some(threshold) = run.hot_threshold {
+            vm.hot_threshold = threshold;
+        }
+
+        Options {
+            vm,
+            trace: run.trace,
+            dump_bytecode: run.dump_bytecode,
+            dump_opt: run.dump_opt,
+            metrics: run.metrics.clone(),
+            profile: run.profile,
+            trace_file: run.trace_file.clone(),
+            prelude: run.prelude.clone(),
+            plugins: run.plugin.clone(),
+            error_format: cli.error_format,
+            script: script.map(|script| script.display().to_string()),
+        }
+    }
+}
+
+fn parse_growth(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(growth) if growth >= 1.0 => Ok(growth),
+        _ => Err(String::from("expected a number of at least 1")),
     }
-    process::exit(64);
 }
 
-fn run_file(filename: &str) -> Result<(), Box<dyn error::Error + 'static>> {
+fn main() {
+    if let Some(program) = bundle::embedded_program() {
+        process::exit(bundle::run_embedded(&program));
+    }
+
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            //Keep the sysexits usage code for bad arguments
+            process::exit(if e.use_stderr() { 64 } else { 0 });
+        }
+    };
+
+    init_logging(cli.verbose as usize);
+    crash_guard::install_hook();
+
+    let options = match &cli.command {
+        None => Options::new(&cli, &cli.run),
+        Some(Command::Run(run)) => Options::new(&cli, run),
+        Some(Command::Lint { script }) => {
+            let run = RunArgs {
+                script: Some(script.clone()),
+                ..cli.run.clone()
+            };
+            let options = Options::new(&cli, &run);
+            process::exit(run_lint(options.script.as_deref().unwrap(), &options));
+        }
+        Some(Command::Check { script, types }) => {
+            let run = RunArgs {
+                script: Some(script.clone()),
+                ..cli.run.clone()
+            };
+            let options = Options::new(&cli, &run);
+            process::exit(run_check(options.script.as_deref().unwrap(), *types, &options));
+        }
+        Some(Command::Test { paths }) => {
+            let options = Options::new(&cli, &cli.run);
+            process::exit(run_tests(paths, &options));
+        }
+        Some(Command::Bundle { script, output }) => {
+            let run = RunArgs {
+                script: Some(script.clone()),
+                ..cli.run.clone()
+            };
+            let options = Options::new(&cli, &run);
+            let script = options.script.as_deref().unwrap();
+            process::exit(bundle::bundle(Path::new(script), output));
+        }
+        #[cfg(feature = "aot")]
+        Some(Command::Build { script, output }) => {
+            process::exit(run_build(script, output));
+        }
+        Some(Command::EmitJs { script, output }) => {
+            process::exit(run_emit_js(script, output.as_deref()));
+        }
+        Some(Command::Dap) => process::exit(dap::run_server()),
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(*shell, &mut Cli::command(), "lox-vm", &mut io::stdout());
+            return;
+        }
+    };
+
     let mut interpreter = interpreter::VM::new();
+    interpreter.configure(&options.vm);
+    if let Some(tiering) = interpreter.tiering_mut() {
+        tiering.passes.dump = options.dump_opt;
+    }
+    interpreter.trace = options.trace;
+    for path in options.plugins.iter() {
+        load_plugin(&mut interpreter, path);
+    }
+    interpreter.record_recent_instructions();
+    if let Some(path) = &options.prelude {
+        interpreter = load_prelude(interpreter, path);
+    }
+    if options.metrics.is_some() {
+        interpreter.enable_metrics();
+    }
+    if options.profile {
+        interpreter.enable_profile();
+    }
+
+    let status = match &options.script {
+        None => {
+            repl::run_prompt(interpreter, &options);
+            0
+        }
+        Some(script) => run_file(script, interpreter, &options).unwrap(),
+    };
+    process::exit(status);
+}
+
+// Exits with 66 if the prelude can't be read and 65 if it doesn't run.
+fn load_prelude(interpreter: interpreter::VM, path: &Path) -> interpreter::VM {
+    let source = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path.display(), e);
+        process::exit(66);
+    });
+    interpreter.with_prelude(&source).unwrap_or_else(|e| {
+        eprintln!("In prelude {}:\n{}", path.display(), e);
+        process::exit(65);
+    })
+}
+
+// Exits with 66 if the plugin can't be loaded. Groups capabilities don't
+// allow are left out with a warning.
+fn load_plugin(interpreter: &mut interpreter::VM, path: &Path) {
+    match plugin::load(interpreter, path) {
+        Ok(skipped) => {
+            for group in skipped {
+                eprintln!(
+                    "warning: {} natives from {} aren't in capabilities",
+                    group,
+                    path.display()
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(66);
+        }
+    }
+}
+
+// Log events go to stderr so they never mix with program output. RUST_LOG
+// takes precedence over the -v flags when set.
+fn init_logging(verbosity: usize) {
+    let default_level = match verbosity {
+        0 => "warn",
+        1 => "debug",
+        _ => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+// The status to exit with, which the script can choose with exit().
+fn run_file(
+    filename: &str,
+    mut interpreter: interpreter::VM,
+    options: &Options,
+) -> Result<i32, Box<dyn error::Error + 'static>> {
     let file_contents = fs::read_to_string(filename)?;
-    run(&file_contents, &mut interpreter);
-    Ok(())
+    interpreter.set_script_path(filename);
+    let trace = options.trace_file.as_ref().map(|_| ChromeTrace::new());
+    if let Some(trace) = &trace {
+        interpreter.set_hooks(trace.hooks());
+    }
+    let outcome = run(&file_contents, &mut interpreter, options);
+    if let (Some(path), Some(trace)) = (&options.trace_file, &trace) {
+        fs::write(path, trace.to_json())?;
+    }
+    if let (Some(path), Some(metrics)) = (&options.metrics, interpreter.metrics()) {
+        fs::write(path, serde_json::to_string_pretty(metrics)?)?;
+    }
+    if let Some(profile) = interpreter.profile() {
+        eprint!("{}", profile.report());
+    }
+    Ok(outcome.status())
 }
 
-fn run_prompt() {
-    let mut interpreter = interpreter::VM::new();
-    let mut rl = Editor::<()>::new();
-    let mut compilable_unit = String::new();
-    loop {
-        let readline = rl.readline(">> ");
-        match readline {
-            Ok(line) => {
-                let line = line.trim_end();
-                if line.ends_with("\\") {
-                    let strip_backslash = &line[..(line.len() - 1)];
-                    compilable_unit.push_str(strip_backslash);
-                } else if line == "exit()" {
-                    std::process::exit(0);
-                } else {
-                    compilable_unit.push_str(&line);
-                    run(&compilable_unit, &mut interpreter);
-                    compilable_unit.clear();
+// How running some source ended.
+pub enum Outcome {
+    Finished,
+    Failed(i32), //65 when it didn't compile, 70 for runtime errors
+    Exited(i32), //The status given to exit()
+}
+
+impl Outcome {
+    fn status(&self) -> i32 {
+        match self {
+            Outcome::Finished => 0,
+            Outcome::Failed(status) | Outcome::Exited(status) => *status,
+        }
+    }
+}
+
+fn run(source: &String, interpreter: &mut interpreter::VM, options: &Options) -> Outcome {
+    run_with_echo(source, interpreter, options, false)
+}
+
+fn run_with_echo(
+    source: &String,
+    interpreter: &mut interpreter::VM,
+    options: &Options,
+    echo_results: bool,
+) -> Outcome {
+    let file = options.script.as_deref();
+    match scanner::scan_tokens(source) {
+        Ok(tokens) => {
+            let mut compiler = compiler::Compiler::new(tokens, interpreter.take_virtual_memory());
+            compiler.echo_results = echo_results;
+            compiler.passes = PassManager::for_level(options.vm.opt_level);
+            compiler.passes.dump = options.dump_opt;
+            match compiler.compile() {
+                Ok(main) => {
+                    for dump in compiler.passes.take_dumps() {
+                        print!("{}", dump);
+                    }
+                    let heap = compiler.heap;
+                    if options.dump_bytecode {
+                        print!("{}", debug::disassemble_function(&main, &heap));
+                    }
+                    let result = crash_guard::guard(interpreter, |vm| vm.interpret(main, heap));
+                    //Functions that got hot while running
+                    if let Some(tiering) = interpreter.tiering_mut() {
+                        for dump in tiering.passes.take_dumps() {
+                            print!("{}", dump);
+                        }
+                    }
+                    match result {
+                        Ok(()) => Outcome::Finished,
+                        Err(InterpreterError::Exit(_, status)) => Outcome::Exited(status),
+                        Err(e) => {
+                            if options.error_format == ErrorFormat::Json {
+                                report_json(Diagnostic::from_runtime_error(&e, file));
+                            } else {
+                                println!("An error ocurred while interpreting.");
+                                println!("Runtime Error: {}", e)
+                            }
+                            Outcome::Failed(70)
+                        }
+                    }
                 }
-                rl.add_history_entry(line);
+                Err(errors) => {
+                    for e in errors.iter() {
+                        if options.error_format == ErrorFormat::Json {
+                            report_json(Diagnostic::from_compiler_error(e, file));
+                        } else {
+                            println!("Compiler error: {}", e.to_string());
+                        }
+                    }
+                    let heap = compiler.heap;
+                    interpreter.give_virtual_memory(heap);
+                    Outcome::Failed(65)
+                }
+            }
+        }
+        Err(error) => {
+            if options.error_format == ErrorFormat::Json {
+                report_json(Diagnostic::from_scanner_error(&error, file));
+            } else {
+                println!("An error ocurred while scanning.");
+                println!("{}", error);
+            }
+            Outcome::Failed(65)
+        }
+    }
+}
+
+// Exits with 1 when a denied rule fires or the file doesn't parse.
+fn run_lint(script: &str, options: &Options) -> i32 {
+    let source = match fs::read_to_string(script) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", script, e);
+            return 1;
+        }
+    };
+    let config = &options.vm.lint;
+
+    let diagnostics = match scanner::scan_tokens(&source) {
+        Ok(tokens) => {
+            let (statements, errors) = parser::parse(tokens);
+            if errors.is_empty() {
+                lint::lint(&statements, config, Some(script))
+            } else {
+                errors
+                    .iter()
+                    .map(|e| Diagnostic::from_compiler_error(e, Some(script)))
+                    .collect()
+            }
+        }
+        Err(e) => vec![Diagnostic::from_scanner_error(&e, Some(script))],
+    };
+
+    report_diagnostics(script, &diagnostics, options)
+}
+
+// Exits with 1 if the file doesn't parse or, with types, has a type error.
+fn run_check(script: &str, types: bool, options: &Options) -> i32 {
+    let source = match fs::read_to_string(script) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", script, e);
+            return 1;
+        }
+    };
+
+    let diagnostics = match scanner::scan_tokens(&source) {
+        Ok(tokens) => {
+            let (statements, errors) = parser::parse(tokens);
+            if !errors.is_empty() {
+                errors
+                    .iter()
+                    .map(|e| Diagnostic::from_compiler_error(e, Some(script)))
+                    .collect()
+            } else if types {
+                types::check(&statements, Some(script))
+            } else {
+                vec![]
             }
-            Err(ReadlineError::Interrupted) => {
-                println!("Interrupted. Type exit() to quit.");
+        }
+        Err(e) => vec![Diagnostic::from_scanner_error(&e, Some(script))],
+    };
+
+    report_diagnostics(script, &diagnostics, options)
+}
+
+fn report_diagnostics(script: &str, diagnostics: &[Diagnostic], options: &Options) -> i32 {
+    for diagnostic in diagnostics.iter() {
+        if options.error_format == ErrorFormat::Json {
+            report_json(diagnostic.clone());
+        } else {
+            println!(
+                "{}:{}:{}: {}[{}]: {}",
+                script,
+                diagnostic.span.line,
+                diagnostic.span.column,
+                diagnostic.severity.as_str(),
+                diagnostic.code,
+                diagnostic.message
+            );
+        }
+    }
+
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        1
+    } else {
+        0
+    }
+}
+
+// Prints a line per test and then the failures. Exits with 1 if any test or
+// test file failed.
+fn run_tests(paths: &[PathBuf], options: &Options) -> i32 {
+    let files = match testing::discover(paths) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 66;
+        }
+    };
+
+    let mut passed = 0;
+    let mut failures: Vec<(String, String)> = vec![];
+    for file in files.iter() {
+        match testing::run_file(file, &options.vm) {
+            Ok(results) => {
+                for result in results {
+                    let name = format!("{}::{}", file.display(), result.name);
+                    match result.outcome {
+                        Ok(()) => {
+                            println!("test {} ... ok", name);
+                            passed += 1;
+                        }
+                        Err(e) => {
+                            println!("test {} ... FAILED", name);
+                            failures.push((name, format!("{}Runtime Error: {}", result.output, e)));
+                        }
+                    }
+                }
             }
-            Err(ReadlineError::Eof) => {
-                println!("Eof Encountered");
+            Err(e) => {
+                let name = file.display().to_string();
+                println!("test {} ... FAILED", name);
+                failures.push((name, e));
             }
-            Err(err) => {
-                println!("An error occurred {}", err);
+        }
+    }
+
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for (name, message) in failures.iter() {
+            println!("\n---- {} ----\n{}", name, message);
+        }
+    }
+    let status = if failures.is_empty() { "ok" } else { "FAILED" };
+    println!(
+        "\ntest result: {}. {} passed; {} failed",
+        status,
+        passed,
+        failures.len()
+    );
+    if failures.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(feature = "aot")]
+fn run_build(script: &str, output: &Path) -> i32 {
+    let source = match fs::read_to_string(script) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", script, e);
+            return 66;
+        }
+    };
+    let tokens = match scanner::scan_tokens(&source) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 65;
+        }
+    };
+    let mut compiler = compiler::Compiler::new(tokens, VirtualMemory::new());
+    //The native backend only lowers the plain instructions
+    compiler.specialize = false;
+    let main = match compiler.compile() {
+        Ok(main) => main,
+        Err(errors) => {
+            for e in errors.iter() {
+                eprintln!("Compiler error: {}", e.to_string());
             }
+            return 65;
+        }
+    };
+
+    let result = lox_vm::aot::compile_object(&main, &compiler.heap)
+        .and_then(|object| lox_vm::aot::link(&object, output));
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            70
         }
     }
 }
 
-fn run(source: &String, interpreter: &mut interpreter::VM) {
-    match scanner::scan_tokens(source) {
+// Exits with 65 when the script doesn't parse.
+fn run_emit_js(script: &str, output: Option<&Path>) -> i32 {
+    let source = match fs::read_to_string(script) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", script, e);
+            return 66;
+        }
+    };
+
+    let statements = match scanner::scan_tokens(&source) {
         Ok(tokens) => {
-            let mut compiler = compiler::Compiler::new(tokens, interpreter.take_virtual_memory());
-            if let Ok(main) = compiler.compile() {
-                let heap = compiler.heap;
-                println!("{:?}", main.chunk.code);
-                if let Err(e) = interpreter.interpret(main, heap) {
-                    println!("An error ocurred while interpreting.");
-                    println!("Runtime Error: {}", e)
+            //Reject what the VM would, not just what the parser catches
+            let mut compiler = compiler::Compiler::new(tokens.clone(), VirtualMemory::new());
+            if let Err(errors) = compiler.compile() {
+                for e in errors.iter() {
+                    eprintln!("Compiler error: {}", e.to_string());
                 }
-            } else {
-                let heap = compiler.heap;
-                interpreter.give_virtual_memory(heap);
+                return 65;
             }
+            parser::parse(tokens).0
         }
-        Err(error) => {
-            println!("An error ocurred while scanning.");
-            println!("{}", error);
+        Err(e) => {
+            eprintln!("{}", e);
+            return 65;
+        }
+    };
+
+    let js = js::emit(&statements);
+    match output {
+        Some(path) => match fs::write(path, js) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                73
+            }
+        },
+        None => {
+            print!("{}", js);
+            0
         }
     }
 }
+
+fn report_json(diagnostic: Diagnostic) {
+    eprintln!("{}", diagnostic.to_json());
+}
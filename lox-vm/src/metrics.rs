@@ -0,0 +1,102 @@
+use super::interpreter::PoolStats;
+use alloc::collections::BTreeMap;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+// Counters gathered while the VM runs, so CI can track performance across
+// commits. Ordered maps keep the exported output stable between runs.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Metrics {
+    pub opcodes: BTreeMap<&'static str, u64>,
+    pub calls: BTreeMap<String, u64>,
+    pub allocations: BTreeMap<&'static str, u64>,
+    pub gc: GcStats,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct GcStats {
+    pub collections: u64,
+    pub freed: u64,
+    //Pauses are only timed with std, which has a clock
+    pub total_pause_us: u64,
+    pub max_pause_us: u64,
+    //Free list hit rates as of the last collection
+    pub pools: PoolStats,
+}
+
+impl Metrics {
+    pub fn record_opcode(&mut self, name: &'static str) {
+        *self.opcodes.entry(name).or_insert(0) += 1;
+    }
+
+    pub fn record_call(&mut self, function: &str) {
+        match self.calls.get_mut(function) {
+            Some(count) => *count += 1,
+            None => {
+                self.calls.insert(String::from(function), 1);
+            }
+        }
+    }
+
+    pub fn record_allocation(&mut self, type_name: &'static str) {
+        *self.allocations.entry(type_name).or_insert(0) += 1;
+    }
+
+    pub fn record_gc(&mut self, freed: usize, pause_us: u64, pools: PoolStats) {
+        self.gc.collections += 1;
+        self.gc.freed += freed as u64;
+        self.gc.total_pause_us += pause_us;
+        self.gc.max_pause_us = self.gc.max_pause_us.max(pause_us);
+        self.gc.pools = pools;
+    }
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::{VirtualMemory, VM};
+    use crate::scanner::scan_tokens;
+
+    fn run(source: &str) -> Metrics {
+        let tokens = scan_tokens(&String::from(source)).unwrap();
+        //Small enough that these loops collect a few times
+        let mut heap = VirtualMemory::new();
+        heap.set_gc_tuning(4096, 2.0);
+        let mut compiler = Compiler::new(tokens, heap);
+        let main = compiler.compile().map_err(|_| "compile error").unwrap();
+        let mut vm = VM::new();
+        vm.enable_metrics();
+        vm.interpret(main, compiler.heap).map_err(|e| e.to_string()).unwrap();
+        vm.metrics().unwrap().clone()
+    }
+
+    #[test]
+    fn counts_calls_and_opcodes() {
+        let metrics = run("fun f(n) { return n + 1; } var x = 0; x = f(x); x = f(x);");
+        assert_eq!(metrics.calls.get("f"), Some(&2));
+        assert_eq!(metrics.opcodes.get("Call"), Some(&2));
+        assert_eq!(metrics.opcodes.get("AddConstant"), Some(&2));
+    }
+
+    #[test]
+    fn counts_allocations_and_collections() {
+        let source = "class A {} for (var i = 0; i < 2000; i = i + 1) { var a = A(); }";
+        let metrics = run(source);
+        assert_eq!(metrics.allocations.get("Instance"), Some(&2000));
+        assert!(metrics.gc.collections > 0);
+        assert!(metrics.gc.freed > 0);
+    }
+
+    #[test]
+    fn recycles_swept_instances() {
+        let source = "class A {} for (var i = 0; i < 2000; i = i + 1) { A().x = i; }";
+        let pools = run(source).gc.pools;
+        assert!(pools.slot_hits > 0);
+        assert!(pools.fields_hits > 0);
+        assert!(pools.slot_hit_rate() > 0.5);
+        assert!(pools.fields_hit_rate() > 0.5);
+    }
+}
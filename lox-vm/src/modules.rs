@@ -0,0 +1,71 @@
+use std::fs;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+// Finding the files import names. A path is relative to the file doing the
+// importing, or to the working directory when there isn't one, like in the
+// REPL. Failing that it's looked for in each directory of the search path: the
+// project root and then the paths lox.toml lists. Modules are cached by their
+// canonical path so the same file reached two ways only runs once.
+
+pub fn canonical(path: &str) -> Result<String, String> {
+    fs::canonicalize(path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .map_err(|e| format!("Couldn't find module {}: {}", path, e))
+}
+
+pub fn resolve(
+    importer: Option<&str>,
+    path: &str,
+    search_path: &[PathBuf],
+) -> Result<String, String> {
+    let dir = importer.and_then(|file| Path::new(file).parent());
+    let nearest = match dir {
+        Some(dir) => dir.join(path),
+        None => Path::new(path).to_path_buf(),
+    };
+    iter::once(nearest)
+        .chain(search_path.iter().map(|dir| dir.join(path)))
+        .find_map(|candidate| canonical(&candidate.to_string_lossy()).ok())
+        .ok_or_else(|| format!("Couldn't find module {}", path))
+}
+
+pub fn read(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Couldn't read module {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod modules_tests {
+    use super::*;
+
+    #[test]
+    fn resolves_relative_to_the_importer() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let importer = format!("{}/tests/lox/modules/import.lox", dir);
+        let resolved = resolve(Some(&importer), "lib/greet.lox", &[]).unwrap();
+        assert!(resolved.ends_with("tests/lox/modules/lib/greet.lox"));
+        let parent = resolve(Some(&importer), "../modules/lib/greet.lox", &[]).unwrap();
+        assert_eq!(parent, resolved);
+    }
+
+    #[test]
+    fn reports_missing_modules() {
+        let error = resolve(None, "no/such/module.lox", &[]).unwrap_err();
+        assert_eq!(error, "Couldn't find module no/such/module.lox");
+    }
+
+    #[test]
+    fn falls_back_to_the_search_path() {
+        let project = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/projects/search_path");
+        let importer = project.join("app/report.lox");
+        let importer = importer.to_str();
+        let search_path = [project.clone(), project.join("vendor")];
+
+        let strings = resolve(importer, "strings.lox", &search_path).unwrap();
+        assert!(strings.ends_with("vendor/strings.lox"));
+        //The project root comes before the configured paths
+        let settings = resolve(importer, "settings.lox", &search_path).unwrap();
+        assert!(settings.ends_with("search_path/settings.lox"));
+        assert!(resolve(importer, "strings.lox", &[]).is_err());
+    }
+}
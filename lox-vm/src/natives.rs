@@ -0,0 +1,1154 @@
+use super::interpreter::VM;
+use super::rng::Rng;
+use super::value::{NativeFn, Object, Value, VARIADIC};
+#[cfg(feature = "ffi")]
+use super::ffi;
+#[cfg(feature = "plugins")]
+use super::plugin;
+#[cfg(feature = "std")]
+use super::workers;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+pub struct Native {
+    pub name: &'static str,
+    pub params: &'static [&'static str],
+    //Shown by :doc in the REPL
+    pub doc: &'static str,
+    pub function: NativeFn,
+}
+
+impl Native {
+    //A last parameter like "args..." takes the rest of the arguments
+    pub fn arity(&self) -> usize {
+        match self.params.last() {
+            Some(param) if param.ends_with("...") => VARIADIC,
+            _ => self.params.len(),
+        }
+    }
+
+    pub fn signature(&self) -> String {
+        format!("{}({})", self.name, self.params.join(", "))
+    }
+}
+
+// Natives come in named groups so lox.toml can limit what a script may use
+// with `capabilities`.
+pub struct Group {
+    pub name: &'static str,
+    pub natives: &'static [Native],
+}
+
+#[cfg(feature = "std")]
+pub const CLOCK: Group = Group {
+    name: "clock",
+    natives: &[Native {
+        name: "clock",
+        params: &[],
+        doc: "Seconds since the program started, with a fractional part.",
+        function: clock,
+    }],
+};
+
+//Whole numbers stay integers where the result is one too
+#[cfg(feature = "std")]
+pub const MATH: Group = Group {
+    name: "math",
+    natives: &[
+        Native {
+            name: "sqrt",
+            params: &["x"],
+            doc: "The square root of x.",
+            function: math_sqrt,
+        },
+        Native {
+            name: "abs",
+            params: &["x"],
+            doc: "x without its sign.",
+            function: math_abs,
+        },
+        Native {
+            name: "floor",
+            params: &["x"],
+            doc: "The largest whole number no greater than x.",
+            function: math_floor,
+        },
+        Native {
+            name: "ceil",
+            params: &["x"],
+            doc: "The smallest whole number no less than x.",
+            function: math_ceil,
+        },
+        Native {
+            name: "sin",
+            params: &["x"],
+            doc: "The sine of x radians.",
+            function: math_sin,
+        },
+        Native {
+            name: "cos",
+            params: &["x"],
+            doc: "The cosine of x radians.",
+            function: math_cos,
+        },
+        Native {
+            name: "pow",
+            params: &["base", "exponent"],
+            doc: "base raised to the power of exponent.",
+            function: math_pow,
+        },
+        Native {
+            name: "min",
+            params: &["a", "b"],
+            doc: "The smaller of a and b.",
+            function: math_min,
+        },
+        Native {
+            name: "max",
+            params: &["a", "b"],
+            doc: "The larger of a and b.",
+            function: math_max,
+        },
+    ],
+};
+
+//Numbers from the VM's generator, the same for the same seed on every platform
+pub const RANDOM: Group = Group {
+    name: "random",
+    natives: &[
+        Native {
+            name: "random",
+            params: &[],
+            doc: "A number from 0 up to but not including 1.",
+            function: random,
+        },
+        Native {
+            name: "randomInt",
+            params: &["lo", "hi"],
+            doc: "A whole number from lo up to and including hi.",
+            function: random_int,
+        },
+        Native {
+            name: "randomSeed",
+            params: &["seed"],
+            doc: "Restarts the generator from seed, repeating the numbers it gave before.",
+            function: random_seed,
+        },
+    ],
+};
+
+//The process the script runs in
+#[cfg(feature = "std")]
+pub const PROCESS: Group = Group {
+    name: "process",
+    natives: &[
+        Native {
+            name: "exit",
+            params: &["status"],
+            doc: "Stops the script, which exits with status. Nothing can catch it.",
+            function: exit,
+        },
+        Native {
+            name: "sleep",
+            params: &["ms"],
+            doc: "Pauses for ms milliseconds, which may have a fractional part.",
+            function: sleep,
+        },
+    ],
+};
+
+//Separate from process so capabilities can keep secrets in the environment away from scripts
+#[cfg(feature = "std")]
+pub const ENV: Group = Group {
+    name: "env",
+    natives: &[
+        Native {
+            name: "getenv",
+            params: &["name"],
+            doc: "The environment variable name, or nil if it isn't set.",
+            function: getenv,
+        },
+        Native {
+            name: "setenv",
+            params: &["name", "value"],
+            doc: "Sets the environment variable name to value, or removes it if value is nil. \
+                  Only getenv in this script and the workers it spawns afterwards see it.",
+            function: setenv,
+        },
+    ],
+};
+
+//Reading standard input, or what VM::set_input gave instead
+//For checks in any script, unlike TEST which only `lox-vm test` defines
+pub const ASSERT: Group = Group {
+    name: "assert",
+    natives: &[Native {
+        name: "assert",
+        params: &["condition", "message"],
+        doc: "A runtime error with message unless condition is truthy.",
+        function: assert,
+    }],
+};
+
+#[cfg(feature = "std")]
+pub const INPUT: Group = Group {
+    name: "input",
+    natives: &[
+        Native {
+            name: "readLine",
+            params: &[],
+            doc: "The next line of input without its line ending, or nil at the end.",
+            function: read_line,
+        },
+        Native {
+            name: "readNumber",
+            params: &[],
+            doc: "The next line of input as a number, or nil at the end or if it isn't one.",
+            function: read_number,
+        },
+    ],
+};
+
+//Paths are relative to the working directory, not the script
+#[cfg(feature = "std")]
+pub const FILES: Group = Group {
+    name: "files",
+    natives: &[
+        Native {
+            name: "readFile",
+            params: &["path"],
+            doc: "The whole file at path as a string.",
+            function: read_file,
+        },
+        Native {
+            name: "writeFile",
+            params: &["path", "contents"],
+            doc: "Replaces the file at path with contents, creating it if needed.",
+            function: write_file,
+        },
+        Native {
+            name: "appendFile",
+            params: &["path", "contents"],
+            doc: "Adds contents to the end of the file at path, creating it if needed.",
+            function: append_file,
+        },
+        Native {
+            name: "exists",
+            params: &["path"],
+            doc: "Whether there's a file or directory at path.",
+            function: exists,
+        },
+    ],
+};
+
+//Looking at values whatever they are
+pub const VALUES: Group = Group {
+    name: "values",
+    natives: &[
+        Native {
+            name: "type",
+            params: &["value"],
+            doc: "The kind of value as a string, like \"number\", \"string\" or \"instance\".",
+            function: type_of,
+        },
+        Native {
+            name: "number",
+            params: &["text"],
+            doc: "The number written in text, or nil if it isn't one.",
+            function: to_number,
+        },
+        Native {
+            name: "string",
+            params: &["value"],
+            doc: "value as print would show it.",
+            function: to_string,
+        },
+        Native {
+            name: "format",
+            params: &["template", "args..."],
+            doc: "template with each {} replaced by the next argument, {1} by a given one \
+                  and {:.2} by a number with 2 decimal places. {{ and }} stand for braces.",
+            function: format,
+        },
+    ],
+};
+
+//See workers for what can be sent and what a worker starts with
+#[cfg(feature = "std")]
+pub const THREADS: Group = Group {
+    name: "threads",
+    natives: &[
+        Native {
+            name: "spawn",
+            params: &["function"],
+            doc: "Runs function in a new thread with its own heap, returning the worker.",
+            function: workers::spawn,
+        },
+        Native {
+            name: "join",
+            params: &["worker"],
+            doc: "Waits for worker to finish, returning false if it failed.",
+            function: workers::join,
+        },
+        Native {
+            name: "channel",
+            params: &[],
+            doc: "A new channel that any worker may send to or receive from.",
+            function: workers::channel,
+        },
+        Native {
+            name: "send",
+            params: &["channel", "value"],
+            doc: "Copies nil, a boolean, number, string or list of those onto channel.",
+            function: workers::send,
+        },
+        Native {
+            name: "recv",
+            params: &["channel"],
+            doc: "Takes the oldest value sent to channel, waiting for one if it's empty. \
+                  Fails instead if no worker is left to send one.",
+            function: workers::recv,
+        },
+    ],
+};
+
+//Unsafe, see ffi
+#[cfg(feature = "ffi")]
+pub const FFI: Group = Group {
+    name: "ffi",
+    natives: &[
+        Native {
+            name: "ffiLoad",
+            params: &["path"],
+            doc: "Loads a C shared library, returning it as userdata.",
+            function: ffi::load,
+        },
+        Native {
+            name: "ffiCall",
+            params: &["library", "name", "signature", "args..."],
+            doc: "Calls the C function name in library with a signature like \"d(dd)\".",
+            function: ffi::call,
+        },
+    ],
+};
+
+//Plugins run native code, so this is opt in like ffi
+#[cfg(feature = "plugins")]
+pub const PLUGINS: Group = Group {
+    name: "plugins",
+    natives: &[Native {
+        name: "loadPlugin",
+        params: &["path"],
+        doc: "Loads a native plugin, defining the groups of natives it registers.",
+        function: plugin::load_plugin,
+    }],
+};
+
+//Only defined for `lox-vm test`
+pub const TEST: Group = Group {
+    name: "test",
+    natives: &[
+        Native {
+            name: "assertEq",
+            params: &["actual", "expected"],
+            doc: "Fails the test unless actual == expected.",
+            function: assert_eq,
+        },
+        Native {
+            name: "assertTrue",
+            params: &["value"],
+            doc: "Fails the test unless value is truthy.",
+            function: assert_true,
+        },
+        Native {
+            name: "fail",
+            params: &["message"],
+            doc: "Fails the test with message.",
+            function: fail,
+        },
+    ],
+};
+
+// Methods every list has. They're called with the list as the first argument,
+// which params leaves out.
+pub const LIST_METHODS: &[Native] = &[
+    Native {
+        name: "push",
+        params: &["value"],
+        doc: "Adds value to the end of the list.",
+        function: list_push,
+    },
+    Native {
+        name: "pop",
+        params: &[],
+        doc: "Removes the last item and returns it.",
+        function: list_pop,
+    },
+    Native {
+        name: "len",
+        params: &[],
+        doc: "The number of items in the list.",
+        function: list_len,
+    },
+];
+
+// Methods every string has, called with the string as the first argument
+// like LIST_METHODS. Positions count characters, not bytes.
+pub const STRING_METHODS: &[Native] = &[
+    Native {
+        name: "length",
+        params: &[],
+        doc: "The number of characters in the string.",
+        function: string_length,
+    },
+    Native {
+        name: "substring",
+        params: &["start", "end"],
+        doc: "The characters from start up to but not including end.",
+        function: string_substring,
+    },
+    Native {
+        name: "indexOf",
+        params: &["needle"],
+        doc: "Where needle first appears in the string, or -1.",
+        function: string_index_of,
+    },
+    Native {
+        name: "split",
+        params: &["separator"],
+        doc: "A list of the parts between each separator, or of every character if it's empty.",
+        function: string_split,
+    },
+    Native {
+        name: "upper",
+        params: &[],
+        doc: "The string in upper case.",
+        function: string_upper,
+    },
+    Native {
+        name: "lower",
+        params: &[],
+        doc: "The string in lower case.",
+        function: string_lower,
+    },
+    Native {
+        name: "trim",
+        params: &[],
+        doc: "The string without leading and trailing whitespace.",
+        function: string_trim,
+    },
+];
+
+//What VM::configure defines when capabilities allow it
+#[cfg(feature = "std")]
+pub const STANDARD: &[Group] =
+    &[CLOCK, MATH, RANDOM, VALUES, ASSERT, PROCESS, ENV, INPUT, FILES, THREADS];
+
+//Groups scripts only get when capabilities names them
+#[cfg(feature = "std")]
+pub const OPT_IN: &[Group] = &[
+    #[cfg(feature = "ffi")]
+    FFI,
+    #[cfg(feature = "plugins")]
+    PLUGINS,
+];
+
+// The native registered under name in any of our groups, for its metadata.
+pub fn lookup(name: &str) -> Option<&'static Native> {
+    #[cfg(feature = "std")]
+    let groups: Vec<&Group> = [
+        &CLOCK, &MATH, &RANDOM, &VALUES, &ASSERT, &PROCESS, &ENV, &INPUT, &FILES, &THREADS,
+        &TEST,
+    ]
+    .iter()
+    .copied()
+    .chain(OPT_IN.iter())
+    .collect();
+    #[cfg(not(feature = "std"))]
+    let groups = [&RANDOM, &VALUES, &ASSERT, &TEST];
+    groups
+        .iter()
+        .flat_map(|group| group.natives.iter())
+        .find(|native| native.name == name)
+}
+
+#[cfg(feature = "std")]
+fn clock(vm: &mut VM, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(vm.seconds_running()))
+}
+
+#[cfg(feature = "std")]
+fn number_arg(value: Value, native: &str) -> Result<f64, String> {
+    match value {
+        Value::Integer(n) => Ok(n as f64),
+        Value::Number(n) => Ok(n),
+        _ => Err(format!("{} expects a number", native)),
+    }
+}
+
+#[cfg(feature = "std")]
+fn math_sqrt(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number_arg(args[0], "sqrt")?.sqrt()))
+}
+
+#[cfg(feature = "std")]
+fn math_abs(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Integer(n) => match n.checked_abs() {
+            Some(n) => Ok(Value::Integer(n)),
+            None => Ok(Value::Number(-(n as f64))),
+        },
+        x => Ok(Value::Number(number_arg(x, "abs")?.abs())),
+    }
+}
+
+#[cfg(feature = "std")]
+fn math_floor(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Integer(n) => Ok(Value::Integer(n)),
+        x => Ok(Value::Number(number_arg(x, "floor")?.floor())),
+    }
+}
+
+#[cfg(feature = "std")]
+fn math_ceil(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Integer(n) => Ok(Value::Integer(n)),
+        x => Ok(Value::Number(number_arg(x, "ceil")?.ceil())),
+    }
+}
+
+#[cfg(feature = "std")]
+fn math_sin(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number_arg(args[0], "sin")?.sin()))
+}
+
+#[cfg(feature = "std")]
+fn math_cos(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number_arg(args[0], "cos")?.cos()))
+}
+
+#[cfg(feature = "std")]
+fn math_pow(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let (base, exponent) = (number_arg(args[0], "pow")?, number_arg(args[1], "pow")?);
+    Ok(Value::Number(base.powf(exponent)))
+}
+
+#[cfg(feature = "std")]
+fn math_min(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match (args[0], args[1]) {
+        (Value::Integer(a), Value::Integer(b)) => Ok(Value::Integer(a.min(b))),
+        (a, b) => Ok(Value::Number(number_arg(a, "min")?.min(number_arg(b, "min")?))),
+    }
+}
+
+#[cfg(feature = "std")]
+fn math_max(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match (args[0], args[1]) {
+        (Value::Integer(a), Value::Integer(b)) => Ok(Value::Integer(a.max(b))),
+        (a, b) => Ok(Value::Number(number_arg(a, "max")?.max(number_arg(b, "max")?))),
+    }
+}
+
+fn whole_number_arg(value: Value, native: &str) -> Result<i64, String> {
+    match value {
+        Value::Integer(n) => Ok(n),
+        Value::Number(n) if n == n as i64 as f64 => Ok(n as i64),
+        _ => Err(format!("{} expects whole numbers", native)),
+    }
+}
+
+fn random(vm: &mut VM, _: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(vm.rng().float()))
+}
+
+fn random_int(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let lo = whole_number_arg(args[0], "randomInt")?;
+    let hi = whole_number_arg(args[1], "randomInt")?;
+    if lo > hi {
+        return Err(format!("randomInt range {} to {} is empty", lo, hi));
+    }
+    //Wide enough for lo and hi as far apart as they can be
+    let span = (hi as i128 - lo as i128 + 1) as u128;
+    let offset = vm.rng().next_u64() as u128 % span;
+    Ok(Value::Integer((lo as i128 + offset as i128) as i64))
+}
+
+fn random_seed(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let seed = whole_number_arg(args[0], "randomSeed")?;
+    *vm.rng() = Rng::new(seed as u64);
+    Ok(Value::Nil)
+}
+
+fn type_of(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let name = vm.type_name(args[0]);
+    Ok(vm.new_string(String::from(name)))
+}
+
+//Integers where the text is one, like number literals
+fn parse_number(text: &str) -> Value {
+    let text = text.trim();
+    if let Ok(n) = text.parse::<i64>() {
+        return Value::Integer(n);
+    }
+    match text.parse::<f64>() {
+        Ok(n) if n.is_finite() => Value::Number(n),
+        _ => Value::Nil,
+    }
+}
+
+//Numbers are already numbers
+fn to_number(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        n @ (Value::Integer(_) | Value::Number(_)) => Ok(n),
+        text => Ok(parse_number(&string_arg(vm, text, "number")?)),
+    }
+}
+
+fn to_string(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = vm.stringify(args[0]);
+    Ok(vm.new_string(s))
+}
+
+fn assert(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Nil | Value::Boolean(false) => {
+            Err(format!("Assertion failed: {}", vm.stringify(args[1])))
+        }
+        _ => Ok(Value::Nil),
+    }
+}
+
+#[cfg(feature = "std")]
+fn exit(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    use std::convert::TryFrom;
+    let status = whole_number_arg(args[0], "exit")?;
+    match i32::try_from(status) {
+        Ok(status) => {
+            vm.exit(status);
+            Err(String::from("exit"))
+        }
+        Err(_) => Err(format!("Exit status {} is out of range", status)),
+    }
+}
+
+#[cfg(feature = "std")]
+fn sleep(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let ms = number_arg(args[0], "sleep")?;
+    if !(ms >= 0.0 && ms.is_finite()) {
+        return Err(format!("Can't sleep for {} milliseconds", ms));
+    }
+    std::thread::sleep(std::time::Duration::from_secs_f64(ms / 1000.0));
+    Ok(Value::Nil)
+}
+
+#[cfg(feature = "std")]
+fn getenv(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let name = string_arg(vm, args[0], "getenv")?;
+    match vm.env_var(&name) {
+        Some(value) => Ok(vm.new_string(value)),
+        None => Ok(Value::Nil),
+    }
+}
+
+// Only changes what this VM's getenv sees. Writing the process environment
+// would race with workers and C code reading it from other threads. Names and
+// values the OS couldn't take are still errors.
+#[cfg(feature = "std")]
+fn setenv(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let name = string_arg(vm, args[0], "setenv")?;
+    if name.is_empty() || name.contains('=') || name.contains('\0') {
+        return Err(format!("Invalid environment variable name '{}'", name));
+    }
+    let value = match args[1] {
+        Value::Nil => None,
+        value => {
+            let value = string_arg(vm, value, "setenv")?;
+            if value.contains('\0') {
+                return Err(format!("Invalid value for environment variable '{}'", name));
+            }
+            Some(value)
+        }
+    };
+    vm.set_env_var(name, value);
+    Ok(Value::Nil)
+}
+
+fn format(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if args.is_empty() {
+        return Err(String::from("Expected at least 1 arguments but got 0"));
+    }
+    let template = string_arg(vm, args[0], "format")?;
+    let mut formatted = String::new();
+    let mut next = 0;
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                formatted.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                formatted.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(String::from("Unclosed '{' in format template")),
+                    }
+                }
+                formatted.push_str(&format_placeholder(vm, &placeholder, &args[1..], &mut next)?);
+            }
+            '}' => return Err(String::from("Unmatched '}' in format template")),
+            c => formatted.push(c),
+        }
+    }
+    Ok(vm.new_string(formatted))
+}
+
+//What goes in for one {placeholder}, where next is the argument an empty one takes
+fn format_placeholder(
+    vm: &VM,
+    placeholder: &str,
+    args: &[Value],
+    next: &mut usize,
+) -> Result<String, String> {
+    let invalid = || format!("Invalid placeholder '{{{}}}'", placeholder);
+    let (position, spec) = match placeholder.find(':') {
+        Some(colon) => (&placeholder[..colon], Some(&placeholder[colon + 1..])),
+        None => (placeholder, None),
+    };
+    let position = if position.is_empty() {
+        *next += 1;
+        *next - 1
+    } else {
+        position.parse::<usize>().map_err(|_| invalid())?
+    };
+    let precision = match spec {
+        None | Some("") => None,
+        Some(spec) => match spec.strip_prefix('.').map(str::parse::<usize>) {
+            Some(Ok(precision)) => Some(precision),
+            _ => return Err(invalid()),
+        },
+    };
+    let value = match args.get(position) {
+        Some(value) => *value,
+        None => return Err(format!("No argument {} for format", position)),
+    };
+    match (value, precision) {
+        (_, None) => Ok(vm.stringify(value)),
+        (Value::Integer(n), Some(precision)) => Ok(format!("{:.*}", precision, n as f64)),
+        (Value::Number(n), Some(precision)) => Ok(format!("{:.*}", precision, n)),
+        _ => Err(format!("{{{}}} needs a number", placeholder)),
+    }
+}
+
+#[cfg(feature = "std")]
+fn read_line(vm: &mut VM, _: &[Value]) -> Result<Value, String> {
+    match vm.read_line() {
+        Ok(Some(line)) => Ok(vm.new_string(line)),
+        Ok(None) => Ok(Value::Nil),
+        Err(e) => Err(format!("readLine failed: {}", e)),
+    }
+}
+
+#[cfg(feature = "std")]
+fn read_number(vm: &mut VM, _: &[Value]) -> Result<Value, String> {
+    match vm.read_line() {
+        Ok(Some(line)) => Ok(parse_number(&line)),
+        Ok(None) => Ok(Value::Nil),
+        Err(e) => Err(format!("readNumber failed: {}", e)),
+    }
+}
+
+#[cfg(feature = "std")]
+fn read_file(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path = string_arg(vm, args[0], "readFile")?;
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Ok(vm.new_string(contents)),
+        Err(e) => Err(format!("Couldn't read '{}': {}", path, e)),
+    }
+}
+
+#[cfg(feature = "std")]
+fn write_file(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path = string_arg(vm, args[0], "writeFile")?;
+    let contents = string_arg(vm, args[1], "writeFile")?;
+    match std::fs::write(&path, contents) {
+        Ok(()) => Ok(Value::Nil),
+        Err(e) => Err(format!("Couldn't write '{}': {}", path, e)),
+    }
+}
+
+#[cfg(feature = "std")]
+fn append_file(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    use std::io::Write;
+    let path = string_arg(vm, args[0], "appendFile")?;
+    let contents = string_arg(vm, args[1], "appendFile")?;
+    let appended = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()));
+    match appended {
+        Ok(()) => Ok(Value::Nil),
+        Err(e) => Err(format!("Couldn't append to '{}': {}", path, e)),
+    }
+}
+
+#[cfg(feature = "std")]
+fn exists(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path = string_arg(vm, args[0], "exists")?;
+    Ok(Value::Boolean(std::path::Path::new(&path).exists()))
+}
+
+fn list_items(vm: &mut VM, list: Value) -> &mut Vec<Value> {
+    match list {
+        Value::Object(ptr) => match vm.heap_object_mut(ptr) {
+            Some(Object::List(items)) => items,
+            _ => panic!("List methods are only invoked on lists"),
+        },
+        _ => panic!("List methods are only invoked on lists"),
+    }
+}
+
+fn list_push(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    list_items(vm, args[0]).push(args[1]);
+    Ok(Value::Nil)
+}
+
+fn list_pop(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    list_items(vm, args[0])
+        .pop()
+        .ok_or_else(|| String::from("Can't pop from an empty list"))
+}
+
+fn list_len(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Integer(list_items(vm, args[0]).len() as i64))
+}
+
+fn string_arg(vm: &VM, value: Value, method: &str) -> Result<String, String> {
+    match value {
+        Value::Object(ptr) => match vm.heap_object(ptr) {
+            Some(Object::String(s)) => Ok(s.clone()),
+            _ => Err(format!("{} expects a string", method)),
+        },
+        _ => Err(format!("{} expects a string", method)),
+    }
+}
+
+//A position in a string of len characters, where len itself is the end
+fn string_position(value: Value, len: usize) -> Result<usize, String> {
+    let position = match value {
+        Value::Integer(n) if n >= 0 => n as usize,
+        Value::Number(n) if n >= 0.0 && n == n as usize as f64 => n as usize,
+        _ => return Err(String::from("String positions must be whole numbers")),
+    };
+    if position > len {
+        return Err(format!("Position {} is past the end of the string", position));
+    }
+    Ok(position)
+}
+
+fn string_length(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string_arg(vm, args[0], "length")?;
+    Ok(Value::Integer(s.chars().count() as i64))
+}
+
+fn string_substring(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string_arg(vm, args[0], "substring")?;
+    let len = s.chars().count();
+    let start = string_position(args[1], len)?;
+    let end = string_position(args[2], len)?;
+    if start > end {
+        return Err(format!("Substring start {} is after its end {}", start, end));
+    }
+    let substring = s.chars().skip(start).take(end - start).collect();
+    Ok(vm.new_string(substring))
+}
+
+fn string_index_of(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string_arg(vm, args[0], "indexOf")?;
+    let needle = string_arg(vm, args[1], "indexOf")?;
+    let index = match s.find(&needle) {
+        Some(byte) => s[..byte].chars().count() as i64,
+        None => -1,
+    };
+    Ok(Value::Integer(index))
+}
+
+fn string_split(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string_arg(vm, args[0], "split")?;
+    let separator = string_arg(vm, args[1], "split")?;
+    let parts: Vec<String> = if separator.is_empty() {
+        s.chars().map(String::from).collect()
+    } else {
+        s.split(separator.as_str()).map(String::from).collect()
+    };
+    let items = parts.into_iter().map(|part| vm.new_string(part)).collect();
+    Ok(vm.new_list(items))
+}
+
+fn string_upper(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string_arg(vm, args[0], "upper")?;
+    Ok(vm.new_string(s.to_uppercase()))
+}
+
+fn string_lower(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string_arg(vm, args[0], "lower")?;
+    Ok(vm.new_string(s.to_lowercase()))
+}
+
+fn string_trim(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string_arg(vm, args[0], "trim")?;
+    Ok(vm.new_string(String::from(s.trim())))
+}
+
+fn assert_eq(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let (actual, expected) = (args[0], args[1]);
+    if vm.values_equal(actual, expected) {
+        Ok(Value::Nil)
+    } else {
+        Err(format!(
+            "Assertion failed: expected {} but got {}",
+            vm.inspect(expected),
+            vm.inspect(actual)
+        ))
+    }
+}
+
+fn assert_true(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match args[0] {
+        Value::Nil | Value::Boolean(false) => Err(format!(
+            "Assertion failed: expected a true value but got {}",
+            vm.inspect(args[0])
+        )),
+        _ => Ok(Value::Nil),
+    }
+}
+
+fn fail(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Err(vm.stringify(args[0]))
+}
+
+#[cfg(test)]
+mod natives_tests {
+    use super::*;
+    use crate::interpreter::InterpreterError;
+    use crate::test_support::{self, interpret};
+
+    //Runs source with the groups given and the assertions
+    fn run_with(groups: &[&Group], source: &str) -> Result<(), String> {
+        let mut vm = VM::new();
+        vm.define_natives(&TEST);
+        for group in groups {
+            vm.define_natives(group);
+        }
+        test_support::run(&mut vm, source)
+    }
+
+    fn run(source: &str) -> Result<(), String> {
+        run_with(&[], source)
+    }
+
+    #[test]
+    fn assertions_pass() {
+        assert_eq!(run("assertEq(\"a\" + \"b\", \"ab\"); assertTrue(1 < 2);"), Ok(()));
+    }
+
+    #[test]
+    fn assertions_fail_on_the_calling_line() {
+        let error = run("var x = 1;\nassertEq(x, 2);").unwrap_err();
+        assert_eq!(error, "2: Assertion failed: expected 2 but got 1");
+        assert_eq!(run("fail(\"nope\");").unwrap_err(), "1: nope");
+    }
+
+    #[test]
+    fn looks_up_metadata() {
+        let native = lookup("assertEq").unwrap();
+        assert_eq!(native.signature(), "assertEq(actual, expected)");
+        assert_eq!(native.arity(), 2);
+        assert!(lookup("print").is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn clock_counts_from_the_start() {
+        let source = "var t = clock();\nassertTrue(t >= 0 and t < 60 and clock() >= t);";
+        assert_eq!(run_with(&[&CLOCK], source), Ok(()));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn math() {
+        let source = "assertEq(sqrt(16), 4); assertEq(abs(-3), 3); assertEq(abs(-2.5), 2.5);
+assertEq(floor(2.7), 2); assertEq(ceil(2.2), 3); assertEq(floor(-1), -1);
+assertEq(sin(0), 0); assertEq(cos(0), 1); assertEq(pow(2, 10), 1024);
+assertEq(min(3, -1), -1); assertEq(max(1.5, 2), 2);
+sqrt(\"four\");";
+        let error = run_with(&[&MATH], source).unwrap_err();
+        assert_eq!(error, "5: sqrt expects a number");
+    }
+
+    #[test]
+    fn seeded_random_numbers_repeat() {
+        let source = "randomSeed(7);
+var a = random(); var n = randomInt(-2, 2);
+randomSeed(7);
+assertEq(random(), a); assertEq(randomInt(-2, 2), n);
+assertTrue(a >= 0 and a < 1 and n >= -2 and n <= 2);
+assertEq(randomInt(5, 5), 5);
+randomInt(2, 1);";
+        let error = run_with(&[&RANDOM], source).unwrap_err();
+        assert_eq!(error, "7: randomInt range 2 to 1 is empty");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn reads_lines_until_the_end() {
+        let mut vm = VM::new();
+        vm.define_natives(&INPUT);
+        vm.define_natives(&TEST);
+        vm.set_input(Box::new(std::io::Cursor::new("first\r\n42\n 2.5 \nnope\nlast")));
+        let source = "assertEq(readLine(), \"first\"); assertEq(readNumber(), 42);
+assertEq(readNumber(), 2.5); assertEq(readNumber(), nil);
+assertEq(readLine(), \"last\"); assertEq(readLine(), nil); assertEq(readNumber(), nil);";
+        assert!(interpret(&mut vm, source).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn reads_and_writes_files() {
+        let dir = std::env::temp_dir().join(format!("lox-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.txt");
+        let _ = std::fs::remove_file(&path);
+        let source = format!(
+            "var path = \"{}\";
+assertEq(exists(path), false);
+writeFile(path, \"one \"); appendFile(path, \"two\");
+assertEq(readFile(path), \"one two\"); assertTrue(exists(path));
+try {{ readFile(\"{}\"); }} catch (e) {{ assertTrue(e.indexOf(\"Couldn't read\") == 0); }}
+writeFile(path, 1);",
+            path.display(),
+            dir.join("missing.txt").display()
+        );
+        let error = run_with(&[&FILES], &source).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(error, "6: writeFile expects a string");
+    }
+
+    #[test]
+    fn type_names() {
+        let source = "class A { m() {} } fun f() {}
+assertEq(type(1), \"number\"); assertEq(type(1.5), \"number\"); assertEq(type(\"\"), \"string\");
+assertEq(type(true), \"bool\"); assertEq(type(nil), \"nil\"); assertEq(type([1]), \"list\");
+assertEq(type(f), \"function\"); assertEq(type(A().m), \"function\");
+assertEq(type(type), \"function\");
+assertEq(type(A), \"class\"); assertEq(type(A()), \"instance\");";
+        assert_eq!(run_with(&[&VALUES], source), Ok(()));
+    }
+
+    #[test]
+    fn conversions() {
+        let source = "assertEq(number(\"42\"), 42); assertEq(number(\" -2.5 \"), -2.5);
+assertEq(number(\"4x\"), nil); assertEq(number(\"\"), nil); assertEq(number(7), 7);
+assertEq(string(1.5), \"1.5\"); assertEq(string(nil), \"nil\");
+assertEq(string([1, 2]), \"[1, 2]\");
+assertEq(number(string(12)) + 1, 13);
+number(true);";
+        let error = run_with(&[&VALUES], source).unwrap_err();
+        assert_eq!(error, "6: number expects a string");
+    }
+
+    #[test]
+    fn assert_reports_its_message() {
+        let source = "assert(1 < 2, \"fine\"); assert(0, \"zero is truthy\");
+var caught;
+try { assert(nil, \"caught\"); } catch (e) { caught = e; }
+assert(caught == \"Assertion failed: caught\", \"unexpected ${caught}\");
+fun check(x) {
+  assert(x > 2, \"${x} is too small\");
+}
+check(1);";
+        let error = run_with(&[&ASSERT], source).unwrap_err();
+        assert_eq!(error, "6: Assertion failed: 1 is too small");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn exit_can_not_be_caught() {
+        let mut vm = VM::new();
+        vm.define_natives(&PROCESS);
+        let source = "try {
+  exit(3);
+} catch (e) {
+  print \"caught\";
+}";
+        let error = interpret(&mut vm, source).unwrap_err();
+        assert!(matches!(error, InterpreterError::Exit(2, 3)));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn sleep_waits() {
+        let source = "var start = clock();
+sleep(20); sleep(0.5);
+assertTrue(clock() - start >= 0.02);
+sleep(-1);";
+        let error = run_with(&[&CLOCK, &PROCESS], source).unwrap_err();
+        assert_eq!(error, "4: Can't sleep for -1 milliseconds");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn environment_variables() {
+        let mut vm = VM::new();
+        vm.define_natives(&ENV);
+        vm.define_natives(&TEST);
+        let source = "var name = \"LOX_NATIVES_TEST\";
+assertEq(getenv(name), nil);
+setenv(name, \"set\"); assertEq(getenv(name), \"set\");
+setenv(name, nil); assertEq(getenv(name), nil);
+setenv(name, \"kept\"); setenv(\"PATH\", nil); assertEq(getenv(\"PATH\"), nil);
+setenv(\"A=B\", \"x\");";
+        let error = interpret(&mut vm, source).unwrap_err();
+        assert_eq!(error.to_string(), "6: Invalid environment variable name 'A=B'");
+        //Only the VM sees the changes
+        assert_eq!(vm.env_var("LOX_NATIVES_TEST").as_deref(), Some("kept"));
+        assert!(std::env::var_os("LOX_NATIVES_TEST").is_none());
+        assert!(std::env::var_os("PATH").is_some());
+    }
+
+    #[test]
+    fn format_templates() {
+        let source = "assertEq(format(\"{} and {}\", 1, \"two\"), \"1 and two\");
+assertEq(format(\"{1}{0}{1}\", \"a\", \"b\"), \"bab\");
+assertEq(format(\"{:.2}|{0:.0}|{:.1}\", 3.14159, 2), \"3.14|3|2.0\");
+assertEq(format(\"{{{}}}\", nil), \"{nil}\"); assertEq(format(\"plain\"), \"plain\");
+format(\"{:.2}\", \"text\");";
+        let error = run_with(&[&VALUES], source).unwrap_err();
+        assert_eq!(error, "5: {:.2} needs a number");
+        let mut vm = VM::new();
+        for (template, message) in [
+            ("{", "Unclosed '{' in format template"),
+            ("}", "Unmatched '}' in format template"),
+            ("{x}", "Invalid placeholder '{x}'"),
+            ("{:2}", "Invalid placeholder '{:2}'"),
+            ("{3}", "No argument 3 for format"),
+        ] {
+            let template = vm.new_string(String::from(template));
+            assert_eq!(format(&mut vm, &[template]).unwrap_err(), message);
+        }
+    }
+
+    #[test]
+    fn checks_arity() {
+        let error = run("assertTrue();").unwrap_err();
+        assert_eq!(error, "1: Expected 1 arguments but got 0");
+    }
+}
@@ -0,0 +1,1200 @@
+use super::ast::*;
+use super::compiler::CompilerError;
+use super::token::*;
+use super::value::Module;
+
+// Recursive descent parser producing an AST. It accepts the same language as
+// the bytecode compiler and keeps going after errors so tools can work with
+// partially broken files.
+pub struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+    errors: Vec<CompilerError>,
+}
+
+pub fn parse(tokens: Vec<Token>) -> (Vec<Stmt>, Vec<CompilerError>) {
+    let mut parser = Parser {
+        tokens,
+        current: 0,
+        errors: vec![],
+    };
+    let statements = parser.program();
+    (statements, parser.errors)
+}
+
+impl Parser {
+    fn program(&mut self) -> Vec<Stmt> {
+        let mut statements = vec![];
+        while !self.is_at_end() {
+            let old_idx = self.current;
+            match self.declaration() {
+                Ok(stmt) => statements.push(stmt),
+                Err(e) => {
+                    self.errors.push(e);
+                    self.synchronize();
+                }
+            }
+            if self.current == old_idx {
+                self.advance();
+            }
+        }
+        statements
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current - 1]
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.current >= self.tokens.len() || self.peek().token_type == TokenType::EOF
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current < self.tokens.len() && self.peek().token_type == token_type
+    }
+
+    fn check_next(&self, token_type: TokenType) -> bool {
+        match self.tokens.get(self.current + 1) {
+            Some(token) => token.token_type == token_type,
+            None => false,
+        }
+    }
+
+    fn advance(&mut self) -> &Token {
+        if !self.is_at_end() {
+            self.current += 1;
+        }
+        self.previous()
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if self.check(token_type) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, message: &str) -> CompilerError {
+        CompilerError::SyntaxError(String::from(message), self.peek().span())
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) -> Result<Token, CompilerError> {
+        if self.check(token_type) {
+            Ok(self.advance().clone())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    //Ends a statement that is missing its ';' at a '}', so the block still closes
+    fn semicolon(&mut self, message: &str) -> Result<(), CompilerError> {
+        if self.check(TokenType::RightBrace) {
+            self.errors.push(self.error("Expected ';' before '}'."));
+            Ok(())
+        } else {
+            self.consume(TokenType::Semicolon, message).map(|_| ())
+        }
+    }
+
+    fn identifier(&mut self, message: &str) -> Result<Identifier, CompilerError> {
+        let token = self.consume(TokenType::Identifier, message)?;
+        Ok(Identifier {
+            name: token.lexeme.clone(),
+            span: token.span(),
+        })
+    }
+
+    fn synchronize(&mut self) {
+        while !self.is_at_end() {
+            if self.current > 0 && self.previous().token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.peek().token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Do
+                | TokenType::Switch
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Try
+                | TokenType::Throw
+                | TokenType::Import
+                | TokenType::Print
+                | TokenType::Yield
+                | TokenType::Return => return,
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn declaration(&mut self) -> Result<Stmt, CompilerError> {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration()
+        } else if self.match_token(TokenType::Fun) {
+            Ok(Stmt::Function(self.function("Expected function name")?))
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration()
+        } else if self.match_token(TokenType::Import) {
+            self.import_declaration()
+        } else {
+            self.statement()
+        }
+    }
+
+    fn import_declaration(&mut self) -> Result<Stmt, CompilerError> {
+        let path = self.consume(TokenType::StringToken, "Expected module path after 'import'.")?;
+        let path_str = path.literal.clone().unwrap_or_default();
+        let name = if self.check(TokenType::Identifier) && self.peek().lexeme == "as" {
+            self.advance();
+            self.identifier("Expected module name after 'as'.")?
+        } else {
+            Identifier {
+                name: String::from(Module::default_name(&path_str)),
+                span: path.span(),
+            }
+        };
+        self.semicolon("Expected ';' after import")?;
+        Ok(Stmt::Import(name, path_str))
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt, CompilerError> {
+        let name = self.identifier("Expected class name")?;
+        Ok(Stmt::Class(self.class_body(name)?))
+    }
+
+    fn class_body(&mut self, name: Identifier) -> Result<ClassDecl, CompilerError> {
+        let mut superclasses = vec![];
+        if self.match_token(TokenType::Less) {
+            loop {
+                superclasses.push(self.identifier("Expected superclass name")?);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::LeftBrace, "Expected '{' before class body")?;
+        let mut methods = vec![];
+        let (mut statics, mut getters, mut setters) = (vec![], vec![], vec![]);
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            if self.match_token(TokenType::Static) {
+                statics.push(self.function("Expected method name.")?);
+                continue;
+            }
+            let name = self.identifier("Expected method name.")?;
+            if self.check(TokenType::LeftBrace) || self.check(TokenType::Colon) {
+                getters.push(self.function_body(name, vec![], vec![], false)?);
+            } else if self.match_token(TokenType::Equal) {
+                let setter = self.parameters_and_body(name)?;
+                if setter.params.len() != 1 {
+                    let message = String::from("A setter takes exactly one parameter.");
+                    let span = self.previous().span();
+                    self.errors.push(CompilerError::SyntaxError(message, span));
+                }
+                setters.push(setter);
+            } else {
+                methods.push(self.parameters_and_body(name)?);
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expected '}' after class body")?;
+
+        Ok(ClassDecl {
+            name,
+            superclasses,
+            methods,
+            statics,
+            getters,
+            setters,
+        })
+    }
+
+    fn function(&mut self, message: &str) -> Result<FunctionDecl, CompilerError> {
+        let name = self.identifier(message)?;
+        self.parameters_and_body(name)
+    }
+
+    fn parameters_and_body(&mut self, name: Identifier) -> Result<FunctionDecl, CompilerError> {
+        self.consume(TokenType::LeftParen, "Expected '(' after function name.")?;
+        let mut params = vec![];
+        let mut param_types = vec![];
+        let mut variadic = false;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                variadic |= self.match_token(TokenType::DotDotDot);
+                params.push(self.identifier("Expected parameter name")?);
+                param_types.push(self.type_annotation()?);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+                if variadic {
+                    let message = String::from("A rest parameter must be the last parameter.");
+                    let span = self.previous().span();
+                    self.errors.push(CompilerError::SyntaxError(message, span));
+                }
+            }
+        }
+        self.consume(
+            TokenType::RightParen,
+            "Expected ')' after function parameters.",
+        )?;
+        self.function_body(name, params, param_types, variadic)
+    }
+
+    //The return type and body after a function's parameters
+    fn function_body(
+        &mut self,
+        name: Identifier,
+        params: Vec<Identifier>,
+        param_types: Vec<Option<Identifier>>,
+        variadic: bool,
+    ) -> Result<FunctionDecl, CompilerError> {
+        let return_type = self.type_annotation()?;
+        self.consume(TokenType::LeftBrace, "Expected '{' before function body.")?;
+        let body = self.block()?;
+        let end_line = self.previous().line;
+
+        Ok(FunctionDecl {
+            name,
+            params,
+            variadic,
+            param_types,
+            return_type,
+            body,
+            end_line,
+        })
+    }
+
+    //The type after a ':', if there is one
+    fn type_annotation(&mut self) -> Result<Option<Identifier>, CompilerError> {
+        if self.match_token(TokenType::Colon) {
+            Ok(Some(self.identifier("Expected type name after ':'.")?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn var_declaration(&mut self) -> Result<Stmt, CompilerError> {
+        if self.match_token(TokenType::LeftBracket) {
+            return self.destructuring_declaration(Unpacking::List);
+        } else if self.match_token(TokenType::LeftBrace) {
+            return self.destructuring_declaration(Unpacking::Fields);
+        }
+        let name = self.identifier("Expected variable name.")?;
+        let annotation = self.type_annotation()?;
+        let mut names = vec![];
+        while self.match_token(TokenType::Comma) {
+            let name = self.identifier("Expected variable name after ','.")?;
+            names.push((name, self.type_annotation()?));
+        }
+        let initializer = if self.match_token(TokenType::Equal) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.semicolon("Expected ';' after variable declaration")?;
+        if names.is_empty() {
+            Ok(Stmt::Var(name, annotation, initializer))
+        } else {
+            names.insert(0, (name, annotation));
+            Ok(Stmt::VarUnpack(Unpacking::Tuple, names, initializer))
+        }
+    }
+
+    fn destructuring_declaration(&mut self, unpacking: Unpacking) -> Result<Stmt, CompilerError> {
+        let mut names = vec![];
+        loop {
+            let name = self.identifier("Expected variable name.")?;
+            names.push((name, self.type_annotation()?));
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        if unpacking == Unpacking::List {
+            self.consume(TokenType::RightBracket, "Expected ']' after names.")?;
+        } else {
+            self.consume(TokenType::RightBrace, "Expected '}' after field names.")?;
+        }
+        self.consume(TokenType::Equal, "Expected '=' after a destructuring pattern.")?;
+        let initializer = self.expression()?;
+        self.semicolon("Expected ';' after variable declaration")?;
+        Ok(Stmt::VarUnpack(unpacking, names, Some(initializer)))
+    }
+
+    fn statement(&mut self) -> Result<Stmt, CompilerError> {
+        if self.match_token(TokenType::Print) {
+            let value = self.expression()?;
+            self.semicolon("Expected ';' after expression")?;
+            Ok(Stmt::Print(value))
+        } else if self.match_token(TokenType::LeftBrace) {
+            let span = self.previous().span();
+            Ok(Stmt::Block(self.block()?, span))
+        } else if self.match_token(TokenType::If) {
+            self.if_statement()
+        } else if self.match_token(TokenType::Return) {
+            let keyword = self.previous().span();
+            let value = if self.check(TokenType::Semicolon) {
+                None
+            } else {
+                let value = self.expression()?;
+                if self.check(TokenType::Comma) {
+                    let span = value.span;
+                    let mut values = vec![value];
+                    while self.match_token(TokenType::Comma) {
+                        values.push(self.expression()?);
+                    }
+                    Some(Expr {
+                        kind: ExprKind::Tuple(values),
+                        span,
+                    })
+                } else {
+                    Some(value)
+                }
+            };
+            self.semicolon("Expected ';' after return value")?;
+            Ok(Stmt::Return(keyword, value))
+        } else if self.match_token(TokenType::Yield) {
+            let keyword = self.previous().span();
+            let value = if self.check(TokenType::Semicolon) {
+                None
+            } else {
+                Some(self.expression()?)
+            };
+            self.semicolon("Expected ';' after yielded value")?;
+            Ok(Stmt::Yield(keyword, value))
+        } else if self.match_token(TokenType::While) {
+            self.consume(TokenType::LeftParen, "Expected '(' after 'while'.")?;
+            let condition = self.expression()?;
+            self.consume(TokenType::RightParen, "Expected ')' after condition.")?;
+            let body = self.statement()?;
+            Ok(Stmt::While(condition, Box::new(body)))
+        } else if self.match_token(TokenType::Do) {
+            let body = self.statement()?;
+            self.consume(TokenType::While, "Expected 'while' after do-while body.")?;
+            self.consume(TokenType::LeftParen, "Expected '(' after 'while'.")?;
+            let condition = self.expression()?;
+            self.consume(TokenType::RightParen, "Expected ')' after condition.")?;
+            self.semicolon("Expected ';' after do-while condition")?;
+            Ok(Stmt::DoWhile(Box::new(body), condition))
+        } else if self.match_token(TokenType::For) {
+            self.for_statement()
+        } else if self.match_token(TokenType::Switch) {
+            self.switch_statement()
+        } else if self.match_token(TokenType::Break) {
+            let keyword = self.previous().span();
+            let label = self.loop_label()?;
+            self.semicolon("Expected ';' after 'break'")?;
+            Ok(Stmt::Break(keyword, label))
+        } else if self.match_token(TokenType::Continue) {
+            let keyword = self.previous().span();
+            let label = self.loop_label()?;
+            self.semicolon("Expected ';' after 'continue'")?;
+            Ok(Stmt::Continue(keyword, label))
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement()
+        } else if self.match_token(TokenType::Throw) {
+            let keyword = self.previous().span();
+            let value = self.expression()?;
+            self.semicolon("Expected ';' after thrown value")?;
+            Ok(Stmt::Throw(keyword, value))
+        } else if self.match_token(TokenType::Else) {
+            let span = self.previous().span();
+            let message = String::from("'else' without a matching 'if'.");
+            self.errors.push(CompilerError::SyntaxError(message, span));
+            self.statement()
+        } else if self.check(TokenType::Identifier) && self.check_next(TokenType::Colon) {
+            let label = self.identifier("Expected a label.")?;
+            self.advance(); //The ':'
+            if !self.check(TokenType::While)
+                && !self.check(TokenType::Do)
+                && !self.check(TokenType::For)
+            {
+                let span = self.peek().span();
+                let message = String::from("Expected a loop after label.");
+                self.errors.push(CompilerError::SyntaxError(message, span));
+                return self.statement();
+            }
+            Ok(Stmt::Labeled(label, Box::new(self.statement()?)))
+        } else {
+            let expr = self.expression()?;
+            self.semicolon("Expected ';' after expression")?;
+            Ok(Stmt::Expression(expr))
+        }
+    }
+
+    //The loop a break or continue names, if any
+    fn loop_label(&mut self) -> Result<Option<Identifier>, CompilerError> {
+        if self.check(TokenType::Identifier) {
+            Ok(Some(self.identifier("Expected a label.")?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, CompilerError> {
+        let mut statements = vec![];
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            statements.push(self.declaration()?);
+        }
+        self.consume(TokenType::RightBrace, "Expected '}' after block.")?;
+        Ok(statements)
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, CompilerError> {
+        self.consume(TokenType::LeftParen, "Expected '(' after 'if'.")?;
+        let condition = self.condition()?;
+        self.consume(TokenType::RightParen, "Expected ')' after condition.")?;
+        let then_branch = self.statement()?;
+        let else_branch = if self.match_token(TokenType::Else) {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+        Ok(Stmt::If(condition, Box::new(then_branch), else_branch))
+    }
+
+    //Reads 'a = b' as 'a == b', which is almost always what was meant
+    fn condition(&mut self) -> Result<Expr, CompilerError> {
+        let left = self.conditional()?;
+        if !self.match_token(TokenType::Equal) {
+            return Ok(left);
+        }
+        let span = self.previous().span();
+        let message = String::from("Assignment in a condition. Did you mean '=='?");
+        self.errors.push(CompilerError::SyntaxError(message, span));
+        let right = self.conditional()?;
+        Ok(Expr {
+            kind: ExprKind::Binary(Box::new(left), BinaryOp::Equal, Box::new(right)),
+            span,
+        })
+    }
+
+    fn try_statement(&mut self) -> Result<Stmt, CompilerError> {
+        self.consume(TokenType::LeftBrace, "Expected '{' after 'try'.")?;
+        let body = self.block()?;
+        let catch = if self.match_token(TokenType::Catch) {
+            self.consume(TokenType::LeftParen, "Expected '(' after 'catch'.")?;
+            let name = self.identifier("Expected a name for the error.")?;
+            self.consume(TokenType::RightParen, "Expected ')' after the error's name.")?;
+            self.consume(TokenType::LeftBrace, "Expected '{' before catch body.")?;
+            Some((name, self.block()?))
+        } else {
+            None
+        };
+        let finally = if self.match_token(TokenType::Finally) {
+            self.consume(TokenType::LeftBrace, "Expected '{' after 'finally'.")?;
+            Some(self.block()?)
+        } else {
+            None
+        };
+        if catch.is_none() && finally.is_none() {
+            let message = String::from("Expected 'catch' or 'finally' after try block.");
+            self.errors.push(CompilerError::SyntaxError(message, self.peek().span()));
+        }
+        Ok(Stmt::Try(body, catch, finally))
+    }
+
+    fn switch_statement(&mut self) -> Result<Stmt, CompilerError> {
+        self.consume(TokenType::LeftParen, "Expected '(' after 'switch'.")?;
+        let value = self.expression()?;
+        self.consume(TokenType::RightParen, "Expected ')' after switch value.")?;
+        self.consume(TokenType::LeftBrace, "Expected '{' before switch body.")?;
+
+        let mut cases = vec![];
+        while self.match_token(TokenType::Case) {
+            let case = self.expression()?;
+            self.consume(TokenType::Colon, "Expected ':' after case value.")?;
+            cases.push((case, self.case_body()?));
+        }
+        let default = if self.match_token(TokenType::Default) {
+            self.consume(TokenType::Colon, "Expected ':' after 'default'.")?;
+            Some(self.case_body()?)
+        } else {
+            None
+        };
+        self.consume(TokenType::RightBrace, "Expected '}' after switch body.")?;
+        Ok(Stmt::Switch(value, cases, default))
+    }
+
+    //Statements up to the next case, the default or the end of the switch
+    fn case_body(&mut self) -> Result<Vec<Stmt>, CompilerError> {
+        let mut statements = vec![];
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.is_at_end()
+        {
+            statements.push(self.declaration()?);
+        }
+        Ok(statements)
+    }
+
+    fn for_statement(&mut self) -> Result<Stmt, CompilerError> {
+        self.consume(TokenType::LeftParen, "Expected '(' after 'for'.")?;
+        let initializer = if self.match_token(TokenType::Semicolon) {
+            None
+        } else if self.match_token(TokenType::Var) {
+            Some(Box::new(self.var_declaration()?))
+        } else {
+            let expr = self.expression()?;
+            self.consume(TokenType::Semicolon, "Expected ';' after expression")?;
+            Some(Box::new(Stmt::Expression(expr)))
+        };
+
+        let condition = if self.check(TokenType::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::Semicolon, "Expected ';'.")?;
+
+        let increment = if self.check(TokenType::RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::RightParen, "Expected ')' after 'for' clauses.")?;
+
+        let body = self.statement()?;
+        Ok(Stmt::For(initializer, condition, increment, Box::new(body)))
+    }
+
+    fn expression(&mut self) -> Result<Expr, CompilerError> {
+        self.assignment()
+    }
+
+    fn assignment(&mut self) -> Result<Expr, CompilerError> {
+        let expr = self.conditional()?;
+
+        if self.match_token(TokenType::Equal) {
+            let equals = self.previous().span();
+            let value = Box::new(self.assignment()?);
+            return match expr.kind {
+                ExprKind::Variable(name) => Ok(Expr {
+                    span: name.span,
+                    kind: ExprKind::Assign(name, value),
+                }),
+                ExprKind::Get(object, name) => Ok(Expr {
+                    span: name.span,
+                    kind: ExprKind::Set(object, name, value),
+                }),
+                ExprKind::Index(list, index) => Ok(Expr {
+                    span: expr.span,
+                    kind: ExprKind::IndexSet(list, index, value),
+                }),
+                _ => Err(CompilerError::SyntaxError(
+                    String::from("Invalid assignment target."),
+                    equals,
+                )),
+            };
+        }
+
+        let op = match self.peek().token_type {
+            TokenType::PlusEqual => BinaryOp::Add,
+            TokenType::MinusEqual => BinaryOp::Subtract,
+            TokenType::StarEqual => BinaryOp::Multiply,
+            TokenType::SlashEqual => BinaryOp::Divide,
+            _ => return Ok(expr),
+        };
+        let operator = self.advance().span();
+        let value = Box::new(self.assignment()?);
+        match expr.kind {
+            ExprKind::Variable(_) | ExprKind::Get(..) => Ok(Expr {
+                span: expr.span,
+                kind: ExprKind::Compound(Box::new(expr), op, value),
+            }),
+            _ => Err(CompilerError::SyntaxError(
+                String::from("Invalid assignment target."),
+                operator,
+            )),
+        }
+    }
+
+    fn conditional(&mut self) -> Result<Expr, CompilerError> {
+        let condition = self.or()?;
+        if !self.match_token(TokenType::Question) {
+            return Ok(condition);
+        }
+        let span = self.previous().span();
+        let then_branch = self.expression()?;
+        self.consume(
+            TokenType::Colon,
+            "Expected ':' after then branch of conditional expression.",
+        )?;
+        let else_branch = self.conditional()?;
+        Ok(Expr {
+            kind: ExprKind::Conditional(
+                Box::new(condition),
+                Box::new(then_branch),
+                Box::new(else_branch),
+            ),
+            span,
+        })
+    }
+
+    fn logical(
+        &mut self,
+        token_type: TokenType,
+        op: LogicalOp,
+        operand: fn(&mut Parser) -> Result<Expr, CompilerError>,
+    ) -> Result<Expr, CompilerError> {
+        let mut expr = operand(self)?;
+        while self.match_token(token_type) {
+            let span = self.previous().span();
+            let right = operand(self)?;
+            expr = Expr {
+                kind: ExprKind::Logical(Box::new(expr), op, Box::new(right)),
+                span,
+            };
+        }
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> Result<Expr, CompilerError> {
+        self.logical(TokenType::Or, LogicalOp::Or, Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, CompilerError> {
+        self.logical(TokenType::And, LogicalOp::And, Parser::equality)
+    }
+
+    fn binary(
+        &mut self,
+        operators: &[(TokenType, BinaryOp)],
+        operand: fn(&mut Parser) -> Result<Expr, CompilerError>,
+    ) -> Result<Expr, CompilerError> {
+        let mut expr = operand(self)?;
+        'outer: loop {
+            for (token_type, op) in operators {
+                if self.match_token(*token_type) {
+                    let span = self.previous().span();
+                    let right = operand(self)?;
+                    expr = Expr {
+                        kind: ExprKind::Binary(Box::new(expr), *op, Box::new(right)),
+                        span,
+                    };
+                    continue 'outer;
+                }
+            }
+            return Ok(expr);
+        }
+    }
+
+    fn equality(&mut self) -> Result<Expr, CompilerError> {
+        self.binary(
+            &[
+                (TokenType::EqualEqual, BinaryOp::Equal),
+                (TokenType::BangEqual, BinaryOp::NotEqual),
+            ],
+            Parser::comparison,
+        )
+    }
+
+    fn comparison(&mut self) -> Result<Expr, CompilerError> {
+        self.binary(
+            &[
+                (TokenType::Greater, BinaryOp::Greater),
+                (TokenType::GreaterEqual, BinaryOp::GreaterEqual),
+                (TokenType::Less, BinaryOp::Less),
+                (TokenType::LessEqual, BinaryOp::LessEqual),
+            ],
+            Parser::bit_or,
+        )
+    }
+
+    fn bit_or(&mut self) -> Result<Expr, CompilerError> {
+        self.binary(&[(TokenType::Pipe, BinaryOp::BitOr)], Parser::bit_xor)
+    }
+
+    fn bit_xor(&mut self) -> Result<Expr, CompilerError> {
+        self.binary(&[(TokenType::Caret, BinaryOp::BitXor)], Parser::bit_and)
+    }
+
+    fn bit_and(&mut self) -> Result<Expr, CompilerError> {
+        self.binary(&[(TokenType::Ampersand, BinaryOp::BitAnd)], Parser::shift)
+    }
+
+    fn shift(&mut self) -> Result<Expr, CompilerError> {
+        self.binary(
+            &[
+                (TokenType::LessLess, BinaryOp::ShiftLeft),
+                (TokenType::GreaterGreater, BinaryOp::ShiftRight),
+            ],
+            Parser::term,
+        )
+    }
+
+    fn term(&mut self) -> Result<Expr, CompilerError> {
+        self.binary(
+            &[
+                (TokenType::Plus, BinaryOp::Add),
+                (TokenType::Minus, BinaryOp::Subtract),
+            ],
+            Parser::factor,
+        )
+    }
+
+    fn factor(&mut self) -> Result<Expr, CompilerError> {
+        self.binary(
+            &[
+                (TokenType::Star, BinaryOp::Multiply),
+                (TokenType::Slash, BinaryOp::Divide),
+            ],
+            Parser::unary,
+        )
+    }
+
+    fn unary(&mut self) -> Result<Expr, CompilerError> {
+        let op = if self.match_token(TokenType::Minus) {
+            UnaryOp::Negate
+        } else if self.match_token(TokenType::Bang) {
+            UnaryOp::Not
+        } else if self.match_token(TokenType::Tilde) {
+            UnaryOp::BitNot
+        } else {
+            return self.call();
+        };
+        let span = self.previous().span();
+        let right = self.unary()?;
+        Ok(Expr {
+            kind: ExprKind::Unary(op, Box::new(right)),
+            span,
+        })
+    }
+
+    fn call(&mut self) -> Result<Expr, CompilerError> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.match_token(TokenType::LeftParen) {
+                let span = self.previous().span();
+                let mut args = vec![];
+                if !self.check(TokenType::RightParen) {
+                    loop {
+                        args.push(self.expression()?);
+                        if !self.match_token(TokenType::Comma) {
+                            break;
+                        }
+                    }
+                }
+                self.consume(TokenType::RightParen, "Expected ')' after arguments.")?;
+                expr = Expr {
+                    kind: ExprKind::Call(Box::new(expr), args),
+                    span,
+                };
+            } else if self.match_token(TokenType::Dot) {
+                let name = self.identifier("Expect property name after '.'.")?;
+                expr = Expr {
+                    span: name.span,
+                    kind: ExprKind::Get(Box::new(expr), name),
+                };
+            } else if self.match_token(TokenType::LeftBracket) {
+                let span = self.previous().span();
+                let index = self.expression()?;
+                self.consume(TokenType::RightBracket, "Expected ']' after index.")?;
+                expr = Expr {
+                    kind: ExprKind::Index(Box::new(expr), Box::new(index)),
+                    span,
+                };
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, CompilerError> {
+        let token = self.peek().clone();
+        let kind = match token.token_type {
+            TokenType::False => ExprKind::Literal(Literal::Boolean(false)),
+            TokenType::True => ExprKind::Literal(Literal::Boolean(true)),
+            TokenType::Nil => ExprKind::Literal(Literal::Nil),
+            TokenType::NumberToken => {
+                let number = token.literal.as_ref().unwrap().parse().unwrap();
+                ExprKind::Literal(Literal::Number(number))
+            }
+            TokenType::IntegerToken => {
+                let number = token.literal.as_ref().unwrap().parse().unwrap();
+                ExprKind::Literal(Literal::Integer(number))
+            }
+            TokenType::StringToken => {
+                ExprKind::Literal(Literal::String(token.literal.clone().unwrap()))
+            }
+            TokenType::Interpolation => return self.interpolation(),
+            TokenType::LeftBracket => {
+                self.advance();
+                let mut items = vec![];
+                if !self.check(TokenType::RightBracket) {
+                    loop {
+                        items.push(self.expression()?);
+                        if !self.match_token(TokenType::Comma) {
+                            break;
+                        }
+                    }
+                }
+                self.consume(TokenType::RightBracket, "Expected ']' after list elements.")?;
+                return Ok(Expr {
+                    kind: ExprKind::List(items),
+                    span: token.span(),
+                });
+            }
+            TokenType::This => ExprKind::This,
+            TokenType::Identifier => ExprKind::Variable(Identifier {
+                name: token.lexeme.clone(),
+                span: token.span(),
+            }),
+            TokenType::LeftParen => {
+                self.advance();
+                let expr = self.expression()?;
+                self.consume(TokenType::RightParen, "Expected ')' after expression")?;
+                return Ok(Expr {
+                    kind: ExprKind::Grouping(Box::new(expr)),
+                    span: token.span(),
+                });
+            }
+            TokenType::Fun => return Ok(self.function_value(token.span())),
+            TokenType::Class => {
+                self.advance();
+                //The name of a class expression is only for printing
+                let name = if self.check(TokenType::Identifier) {
+                    self.identifier("Expected class name")?
+                } else {
+                    Identifier {
+                        name: String::from("anonymous"),
+                        span: token.span(),
+                    }
+                };
+                let class = self.class_body(name)?;
+                return Ok(Expr {
+                    kind: ExprKind::Class(Box::new(class)),
+                    span: token.span(),
+                });
+            }
+            TokenType::Match => return self.match_expression(),
+            TokenType::Super => {
+                self.advance();
+                self.consume(TokenType::Dot, "Expected '.' after 'super'.")?;
+                let method = self.identifier("Expected superclass method name.")?;
+                return Ok(Expr {
+                    kind: ExprKind::Super(method),
+                    span: token.span(),
+                });
+            }
+            _ => return Err(self.error("Expected expression.")),
+        };
+        self.advance();
+        Ok(Expr {
+            kind,
+            span: token.span(),
+        })
+    }
+
+    fn match_expression(&mut self) -> Result<Expr, CompilerError> {
+        let keyword = self.advance().clone();
+        self.consume(TokenType::LeftParen, "Expected '(' after 'match'.")?;
+        let value = self.expression()?;
+        self.consume(TokenType::RightParen, "Expected ')' after match value.")?;
+        self.consume(TokenType::LeftBrace, "Expected '{' before match arms.")?;
+
+        let mut arms = vec![];
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            let pattern = self.pattern()?;
+            self.consume(TokenType::Arrow, "Expected '=>' after pattern.")?;
+            arms.push((pattern, self.expression()?));
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expected '}' after match arms.")?;
+        Ok(Expr {
+            kind: ExprKind::Match(Box::new(value), arms),
+            span: keyword.span(),
+        })
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, CompilerError> {
+        match self.peek().token_type {
+            TokenType::NumberToken
+            | TokenType::IntegerToken
+            | TokenType::StringToken
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Nil => return Ok(Pattern::Literal(self.primary()?)),
+            TokenType::Minus => {
+                let minus = self.advance().span();
+                if !self.check(TokenType::NumberToken) && !self.check(TokenType::IntegerToken) {
+                    return Err(self.error("Expected number after '-' in pattern."));
+                }
+                return Ok(Pattern::Literal(Expr {
+                    kind: ExprKind::Unary(UnaryOp::Negate, Box::new(self.primary()?)),
+                    span: minus,
+                }));
+            }
+            _ => {}
+        }
+
+        let name = self.identifier("Expected pattern.")?;
+        let name = if name.name == "_" { None } else { Some(name) };
+        if self.match_token(TokenType::Colon) {
+            let type_name = self.identifier("Expected type name after ':'.")?;
+            return Ok(Pattern::Type(name, type_name));
+        }
+        Ok(match name {
+            Some(name) => Pattern::Binding(name),
+            None => Pattern::Wildcard,
+        })
+    }
+
+    fn interpolation(&mut self) -> Result<Expr, CompilerError> {
+        let segment = |token: &Token| Expr {
+            kind: ExprKind::Literal(Literal::String(token.literal.clone().unwrap())),
+            span: token.span(),
+        };
+        let start = self.advance().clone();
+        let mut parts = vec![segment(&start)];
+        loop {
+            parts.push(self.expression()?);
+            let next = if self.check(TokenType::Interpolation) {
+                self.advance().clone()
+            } else {
+                self.consume(TokenType::StringToken, "Expected '}' after interpolation.")?
+            };
+            parts.push(segment(&next));
+            if next.token_type == TokenType::StringToken {
+                return Ok(Expr {
+                    kind: ExprKind::Interpolation(parts),
+                    span: start.span(),
+                });
+            }
+        }
+    }
+
+    //There are no function expressions. Skip the parameters and body of what
+    //looks like one and stand in nil.
+    fn function_value(&mut self, span: Span) -> Expr {
+        let message = String::from("'fun' declares a named function and can't be used as a value.");
+        self.errors.push(CompilerError::SyntaxError(message, span));
+        self.advance();
+        self.match_token(TokenType::Identifier);
+        for (open, close) in [
+            (TokenType::LeftParen, TokenType::RightParen),
+            (TokenType::LeftBrace, TokenType::RightBrace),
+        ] {
+            if self.check(open) {
+                self.skip_balanced(open, close);
+            }
+        }
+        Expr {
+            kind: ExprKind::Literal(Literal::Nil),
+            span,
+        }
+    }
+
+    fn skip_balanced(&mut self, open: TokenType, close: TokenType) {
+        let mut depth = 0;
+        while !self.is_at_end() {
+            let token_type = self.advance().token_type;
+            if token_type == open {
+                depth += 1;
+            } else if token_type == close {
+                depth -= 1;
+                if depth == 0 {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod parser_tests {
+    use super::*;
+    use crate::scanner::scan_tokens;
+
+    fn parse_source(source: &str) -> (Vec<Stmt>, Vec<CompilerError>) {
+        parse(scan_tokens(&String::from(source)).unwrap())
+    }
+
+    #[test]
+    fn precedence() {
+        let (statements, errors) = parse_source("print 1 + 2 * 3;");
+        assert!(errors.is_empty());
+        if let Stmt::Print(Expr {
+            kind: ExprKind::Binary(_, BinaryOp::Add, right),
+            ..
+        }) = &statements[0]
+        {
+            assert!(matches!(right.kind, ExprKind::Binary(_, BinaryOp::Multiply, _)));
+        } else {
+            panic!("Expected an addition");
+        }
+
+        //Bitwise operators bind tighter than comparisons but looser than arithmetic
+        let (statements, errors) = parse_source("print x & 1 << 2 + 3 == 4;");
+        assert!(errors.is_empty());
+        if let Stmt::Print(Expr {
+            kind: ExprKind::Binary(left, BinaryOp::Equal, _),
+            ..
+        }) = &statements[0]
+        {
+            match &left.kind {
+                ExprKind::Binary(_, BinaryOp::BitAnd, right) => {
+                    assert!(matches!(right.kind, ExprKind::Binary(_, BinaryOp::ShiftLeft, _)))
+                }
+                _ => panic!("Expected a bitwise and"),
+            }
+        } else {
+            panic!("Expected a comparison");
+        }
+    }
+
+    #[test]
+    fn multiple_values() {
+        let (statements, errors) =
+            parse_source("fun f() { return 1, 2; }\nvar a, b: Number = f();");
+        assert!(errors.is_empty());
+        match &statements[0] {
+            Stmt::Function(function) => assert!(matches!(
+                &function.body[0],
+                Stmt::Return(_, Some(Expr { kind: ExprKind::Tuple(values), .. }))
+                    if values.len() == 2
+            )),
+            _ => panic!("Expected a function"),
+        }
+        match &statements[1] {
+            Stmt::VarUnpack(Unpacking::Tuple, names, Some(_)) => {
+                assert_eq!(names[1].0.name, "b");
+                assert_eq!(names[1].1.as_ref().unwrap().name, "Number");
+            }
+            _ => panic!("Expected an unpacking declaration"),
+        }
+    }
+
+    #[test]
+    fn destructuring() {
+        let (statements, errors) = parse_source("var [a, b] = l;\nvar {x} = p;\nvar [c];");
+        assert_eq!(errors.len(), 1);
+        match (&statements[0], &statements[1]) {
+            (
+                Stmt::VarUnpack(Unpacking::List, items, _),
+                Stmt::VarUnpack(Unpacking::Fields, fields, _),
+            ) => {
+                assert_eq!(items.len(), 2);
+                assert_eq!(fields[0].0.name, "x");
+            }
+            _ => panic!("Expected a list and a field pattern"),
+        }
+    }
+
+    #[test]
+    fn declarations() {
+        let (statements, errors) =
+            parse_source("class A < B { init(x) { this.x = x; } }\nfun f(a, b) { return a; }\nvar v = f(1, 2);");
+        assert!(errors.is_empty());
+        assert_eq!(statements.len(), 3);
+        match &statements[0] {
+            Stmt::Class(class) => {
+                assert_eq!(class.name.name, "A");
+                assert_eq!(class.superclasses[0].name, "B");
+                assert_eq!(class.methods[0].params.len(), 1);
+            }
+            _ => panic!("Expected a class"),
+        }
+        match &statements[1] {
+            Stmt::Function(function) => {
+                assert_eq!(function.name.span.line, 2);
+                assert_eq!(function.name.span.column, 5);
+            }
+            _ => panic!("Expected a function"),
+        }
+    }
+
+    #[test]
+    fn imports() {
+        let (statements, errors) =
+            parse_source("import \"lib/shapes.lox\";\nimport \"util.lox\" as u;");
+        assert!(errors.is_empty());
+        match &statements[..] {
+            [Stmt::Import(shapes, path), Stmt::Import(u, _)] => {
+                assert_eq!(shapes.name, "shapes");
+                assert_eq!(path, "lib/shapes.lox");
+                assert_eq!(u.name, "u");
+                assert_eq!(u.span.line, 2);
+            }
+            _ => panic!("Expected two imports"),
+        }
+    }
+
+    #[test]
+    fn type_annotations() {
+        let (statements, errors) =
+            parse_source("fun f(a: Number, b): String { return \"\"; }\nvar v: Point = nil;");
+        assert!(errors.is_empty());
+        match &statements[0] {
+            Stmt::Function(function) => {
+                let types: Vec<_> = function.param_types.iter().map(|t| t.is_some()).collect();
+                assert_eq!(types, vec![true, false]);
+                assert_eq!(function.return_type.as_ref().unwrap().name, "String");
+            }
+            _ => panic!("Expected a function"),
+        }
+        assert!(matches!(&statements[1], Stmt::Var(_, Some(ty), Some(_)) if ty.name == "Point"));
+    }
+
+    #[test]
+    fn match_expressions() {
+        let (statements, errors) =
+            parse_source("print match (x) { -1 => a, n: Number => n, p => p, _ => nil };");
+        assert!(errors.is_empty());
+        match &statements[0] {
+            Stmt::Print(Expr {
+                kind: ExprKind::Match(_, arms),
+                ..
+            }) => {
+                let patterns: Vec<_> = arms.iter().map(|(pattern, _)| pattern).collect();
+                assert!(matches!(patterns[0], Pattern::Literal(_)));
+                assert!(matches!(patterns[1], Pattern::Type(Some(n), ty) if ty.name == "Number"
+                    && n.name == "n"));
+                assert!(matches!(patterns[2], Pattern::Binding(p) if p.name == "p"));
+                assert!(matches!(patterns[3], Pattern::Wildcard));
+            }
+            _ => panic!("Expected a match"),
+        }
+    }
+
+    #[test]
+    fn recovers_after_errors() {
+        let (statements, errors) = parse_source("var a = ;\nvar b = 1;\n1 = 2;\nprint b;");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].line(), 1);
+        assert_eq!(errors[1].line(), 3);
+        assert_eq!(statements.len(), 2);
+    }
+
+    #[test]
+    fn targeted_errors() {
+        let source = "if (a = 1) print a; print 0;\nelse print 2;\n\
+                      var f = fun(x) { return x; };\n{ print f }";
+        let (statements, errors) = parse_source(source);
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "1 : Assignment in a condition. Did you mean '=='?",
+                "2 : 'else' without a matching 'if'.",
+                "3 : 'fun' declares a named function and can't be used as a value.",
+                "4 : Expected ';' before '}'.",
+            ]
+        );
+        assert_eq!(statements.len(), 5);
+    }
+}
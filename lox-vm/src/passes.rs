@@ -0,0 +1,467 @@
+use super::chunk::{Chunk, OpCode};
+use super::collections::Set;
+use super::debug::disassemble_instruction;
+use super::interpreter::VirtualMemory;
+use super::value::{Function, Object, Value};
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+// Optimizations that rewrite compiled bytecode, kept out of the compiler so
+// each can be written and tested on its own. The manager runs every registered
+// pass over the script and each function nested in its constants.
+
+pub trait ChunkPass {
+    //Shown in dumps, in kebab case
+    fn name(&self) -> &'static str;
+    //Rewrites chunk in place, returning whether anything changed
+    fn run(&mut self, chunk: &mut Chunk) -> bool;
+    //Whether every instruction stays at its offset, so frames, handlers and
+    //generators already part way through the chunk can carry on after it is
+    //rewritten
+    fn keeps_offsets(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn ChunkPass>>,
+    //Keep the disassembly around each pass that changed something
+    pub dump: bool,
+    dumps: Vec<String>,
+}
+
+impl PassManager {
+    pub fn new() -> PassManager {
+        PassManager::default()
+    }
+
+    // The standard passes for an optimization level, none at 0.
+    pub fn for_level(level: u8) -> PassManager {
+        let mut manager = PassManager::new();
+        if level >= 1 {
+            manager.register(Box::new(JumpThreading));
+            //After threading, which can leave the jumps it skipped unreachable
+            manager.register(Box::new(DeadCodeElimination));
+        }
+        if level >= 2 {
+            manager.register(Box::new(Superinstructions));
+        }
+        manager
+    }
+
+    // The passes the VM can run over a function that got hot while it runs.
+    pub fn tiered() -> PassManager {
+        let mut manager = PassManager::for_level(2);
+        manager.passes.retain(|pass| pass.keeps_offsets());
+        manager
+    }
+
+    pub fn register(&mut self, pass: Box<dyn ChunkPass>) {
+        self.passes.push(pass);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    pub fn run(&mut self, main: &mut Function, heap: &mut VirtualMemory) {
+        if self.passes.is_empty() {
+            return;
+        }
+
+        self.run_chunk(&main.name, &mut main.chunk, heap);
+        for ptr in nested_functions(&main.chunk, heap) {
+            self.run_function(ptr, heap);
+        }
+    }
+
+    // Runs every pass over the function at ptr, returning whether any changed it.
+    pub fn run_function(&mut self, ptr: u64, heap: &mut VirtualMemory) -> bool {
+        let (name, mut chunk) = match heap.deref_mut(ptr) {
+            Object::Function(function) => (
+                function.name.clone(),
+                core::mem::replace(&mut function.chunk, Chunk::new()),
+            ),
+            _ => return false,
+        };
+        let changed = self.run_chunk(&name, &mut chunk, heap);
+        if let Object::Function(function) = heap.deref_mut(ptr) {
+            function.chunk = chunk;
+        }
+        changed
+    }
+
+    fn run_chunk(&mut self, name: &str, chunk: &mut Chunk, heap: &VirtualMemory) -> bool {
+        let mut changed = false;
+        for pass in self.passes.iter_mut() {
+            let before = self.dump.then(|| disassemble_chunk(chunk, heap));
+            if pass.run(chunk) {
+                chunk.finish();
+                changed = true;
+                if let Some(before) = before {
+                    let after = disassemble_chunk(chunk, heap);
+                    self.dumps.push(format_dump(pass.name(), name, &before, &after));
+                }
+            }
+        }
+        changed
+    }
+
+    pub fn take_dumps(&mut self) -> Vec<String> {
+        core::mem::take(&mut self.dumps)
+    }
+}
+
+fn function(heap: &VirtualMemory, ptr: u64) -> &Function {
+    heap.deref(ptr).as_function()
+}
+
+//Every function reachable through constants, each once
+fn nested_functions(chunk: &Chunk, heap: &VirtualMemory) -> Vec<u64> {
+    let mut found = vec![];
+    let mut seen = Set::new();
+    let mut pending: Vec<u64> = function_constants(chunk, heap);
+    while let Some(ptr) = pending.pop() {
+        if seen.insert(ptr) {
+            found.push(ptr);
+            pending.extend(function_constants(&function(heap, ptr).chunk, heap));
+        }
+    }
+    found
+}
+
+fn function_constants(chunk: &Chunk, heap: &VirtualMemory) -> Vec<u64> {
+    chunk
+        .constants
+        .iter()
+        .filter_map(|constant| match constant {
+            Value::Object(ptr) if matches!(heap.deref(*ptr), Object::Function(_)) => Some(*ptr),
+            _ => None,
+        })
+        .collect()
+}
+
+fn disassemble_chunk(chunk: &Chunk, heap: &VirtualMemory) -> String {
+    let mut out = String::new();
+    for offset in 0..chunk.code.len() {
+        out.push_str(&disassemble_instruction(chunk, offset, heap));
+        out.push('\n');
+    }
+    out
+}
+
+fn format_dump(pass: &str, function: &str, before: &str, after: &str) -> String {
+    format!(
+        "== {} on {} ==\n-- before\n{}-- after\n{}",
+        pass, function, before, after
+    )
+}
+
+// Points jumps that land on an unconditional Jump straight at where it goes,
+// which nested ifs produce at the end of every inner branch.
+pub struct JumpThreading;
+
+impl ChunkPass for JumpThreading {
+    fn name(&self) -> &'static str {
+        "jump-threading"
+    }
+
+    fn run(&mut self, chunk: &mut Chunk) -> bool {
+        let mut changed = false;
+        for idx in 0..chunk.code.len() {
+            if let OpCode::Jump(offset) | OpCode::JumpIfFalse(offset) = chunk.code[idx] {
+                let target = final_target(&chunk.code, idx + 1 + offset);
+                if target != idx + 1 + offset {
+                    chunk.patch_jump(idx, target - idx - 1);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    //Only operands change
+    fn keeps_offsets(&self) -> bool {
+        true
+    }
+}
+
+fn final_target(code: &[OpCode], mut target: usize) -> usize {
+    //Bounded in case the jumps form a cycle
+    for _ in 0..code.len() {
+        match code.get(target) {
+            Some(OpCode::Jump(offset)) => target += 1 + offset,
+            _ => break,
+        }
+    }
+    target
+}
+
+// Drops instructions no path reaches, like the rest of a block after a return
+// or throw, and renumbers the jumps and local ranges around them.
+pub struct DeadCodeElimination;
+
+impl ChunkPass for DeadCodeElimination {
+    fn name(&self) -> &'static str {
+        "dead-code"
+    }
+
+    fn run(&mut self, chunk: &mut Chunk) -> bool {
+        let reachable = reachable(&chunk.code);
+        if reachable.iter().all(|live| *live) {
+            return false;
+        }
+        remove_instructions(chunk, &reachable);
+        true
+    }
+}
+
+// Fuses pairs of instructions that tight loops run a lot into one, to save a
+// dispatch: a local added to what's on the stack, and a call whose last
+// argument is a constant. Calls to properties already compile to Invoke. The
+// fused instruction replaces the first of the pair and steps over the second,
+// which stays where it was so nothing else moves and hot functions can be
+// fused while they run.
+pub struct Superinstructions;
+
+impl ChunkPass for Superinstructions {
+    fn name(&self) -> &'static str {
+        "superinstructions"
+    }
+
+    fn run(&mut self, chunk: &mut Chunk) -> bool {
+        let targets = jump_targets(&chunk.code);
+        let mut changed = false;
+        let mut idx = 0;
+        while idx + 1 < chunk.code.len() {
+            //Jumping to the second of a pair would skip the first
+            let fused = match (chunk.code[idx], chunk.code[idx + 1]) {
+                _ if targets.contains(&(idx + 1)) => None,
+                (OpCode::GetLocal(slot), OpCode::Add) => Some(OpCode::AddLocal(slot)),
+                (OpCode::Constant(constant), OpCode::Call(arg_count)) => {
+                    Some(OpCode::CallConstant(constant, arg_count))
+                }
+                _ => None,
+            };
+            match fused {
+                Some(op) => {
+                    //Errors come from the second, so it keeps that line
+                    chunk.code[idx] = op;
+                    chunk.line_numbers[idx] = chunk.line_numbers[idx + 1];
+                    changed = true;
+                    idx += 2;
+                }
+                None => idx += 1,
+            }
+        }
+        changed
+    }
+
+    fn keeps_offsets(&self) -> bool {
+        true
+    }
+}
+
+//Where any jump, loop or handler lands
+fn jump_targets(code: &[OpCode]) -> Set<usize> {
+    let mut targets = Set::new();
+    for (idx, op) in code.iter().enumerate() {
+        match op {
+            OpCode::Jump(offset) | OpCode::JumpIfFalse(offset) | OpCode::Try(offset) => {
+                targets.insert(idx + 1 + offset);
+            }
+            OpCode::Loop(offset) => {
+                targets.insert(idx + 1 - offset);
+            }
+            _ => {}
+        }
+    }
+    targets
+}
+
+// Drops the instructions keep is false for and renumbers the jumps and local
+// ranges around them. Nothing may jump to a dropped instruction.
+fn remove_instructions(chunk: &mut Chunk, keep: &[bool]) {
+    //Where each instruction moves to, or for a dropped one the next kept one.
+    //The extra entry is for jumps to the end of the chunk.
+    let mut moved = Vec::with_capacity(keep.len() + 1);
+    let mut kept = 0;
+    for live in keep.iter() {
+        moved.push(kept);
+        if *live {
+            kept += 1;
+        }
+    }
+    moved.push(kept);
+
+    let mut code = Vec::with_capacity(kept);
+    let mut line_numbers = Vec::with_capacity(kept);
+    for (idx, op) in chunk.code.iter().enumerate() {
+        if !keep[idx] {
+            continue;
+        }
+        let at = moved[idx];
+        let op = match *op {
+            OpCode::Jump(offset) => OpCode::Jump(moved[idx + 1 + offset] - at - 1),
+            OpCode::JumpIfFalse(offset) => OpCode::JumpIfFalse(moved[idx + 1 + offset] - at - 1),
+            OpCode::Try(offset) => OpCode::Try(moved[idx + 1 + offset] - at - 1),
+            OpCode::Loop(offset) => OpCode::Loop(at + 1 - moved[idx + 1 - offset]),
+            op => op,
+        };
+        code.push(op);
+        line_numbers.push(chunk.line_numbers[idx]);
+    }
+    let last = moved.len() - 1;
+    for local in chunk.locals.iter_mut() {
+        local.start = moved[local.start.min(last)];
+        if local.end != usize::MAX {
+            local.end = moved[local.end.min(last)];
+        }
+    }
+    chunk.code = code;
+    chunk.line_numbers = line_numbers;
+}
+
+//Which instructions some path from the start of the chunk runs. The one a
+//fused instruction steps over counts, so that it stays behind it.
+fn reachable(code: &[OpCode]) -> Vec<bool> {
+    let mut reachable = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(idx) = pending.pop() {
+        if idx >= code.len() || reachable[idx] {
+            continue;
+        }
+        reachable[idx] = true;
+        match code[idx] {
+            OpCode::Jump(offset) => pending.push(idx + 1 + offset),
+            OpCode::Loop(offset) => pending.push(idx + 1 - offset),
+            OpCode::JumpIfFalse(offset) | OpCode::Try(offset) => {
+                pending.push(idx + 1);
+                pending.push(idx + 1 + offset);
+            }
+            OpCode::Return | OpCode::GeneratorReturn | OpCode::Throw | OpCode::EOF => {}
+            _ => pending.push(idx + 1),
+        }
+    }
+    reachable
+}
+
+#[cfg(test)]
+mod passes_tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::interpreter::VM;
+    use crate::scanner::scan_tokens;
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    const NESTED_IFS: &str = "fun pick(a, b) { var r = 0; if (a) { if (b) r = 1; } else r = 2; \
+                              return r; }\n\
+                              var x = pick(true, true) + pick(true, false) + pick(false, true);";
+
+    fn compile(source: &str, passes: PassManager) -> (Function, VirtualMemory) {
+        let tokens = scan_tokens(&String::from(source)).unwrap();
+        let mut compiler = Compiler::new(tokens, VM::new().take_virtual_memory());
+        compiler.passes = passes;
+        let main = compiler.compile().ok().unwrap();
+        (main, compiler.heap)
+    }
+
+    fn result(main: Function, heap: VirtualMemory) -> String {
+        let mut vm = VM::new();
+        vm.interpret(main, heap).map_err(|e| e.to_string()).unwrap();
+        let (_, x) = vm.globals().into_iter().find(|(name, _)| name.as_str() == "x").unwrap();
+        vm.inspect(x)
+    }
+
+    #[test]
+    fn threads_jumps_in_nested_functions() {
+        let mut passes = PassManager::for_level(1);
+        passes.dump = true;
+        let tokens = scan_tokens(&String::from(NESTED_IFS)).unwrap();
+        let mut compiler = Compiler::new(tokens, VM::new().take_virtual_memory());
+        compiler.passes = passes;
+        let main = compiler.compile().ok().unwrap();
+
+        let dumps = compiler.passes.take_dumps();
+        assert_eq!(dumps.len(), 2);
+        assert!(dumps[0].starts_with("== jump-threading on pick ==\n-- before\n"));
+        //The implicit return after pick's own
+        assert!(dumps[1].starts_with("== dead-code on pick ==\n"));
+        assert_eq!(result(main, compiler.heap), "3");
+    }
+
+    #[test]
+    fn optimized_code_behaves_the_same() {
+        let (plain, plain_heap) = compile(NESTED_IFS, PassManager::new());
+        let (optimized, optimized_heap) = compile(NESTED_IFS, PassManager::for_level(2));
+        assert_eq!(result(plain, plain_heap), result(optimized, optimized_heap));
+    }
+
+    #[test]
+    fn removes_code_after_returns() {
+        let source = "fun f(n) { var total = 0;\n\
+                      for (var i = 0; i < n; i = i + 1) {\n\
+                      if (i == 3) { return total; print \"never\"; }\n\
+                      try { throw i; print \"skipped\"; } catch (e) { total = total + e; }\n\
+                      }\n\
+                      return -1; print \"after\"; }\n\
+                      var x = f(10);";
+        let (plain, plain_heap) = compile(source, PassManager::new());
+        let (optimized, optimized_heap) = compile(source, PassManager::for_level(1));
+        let f = |main: &Function, heap: &VirtualMemory| {
+            let ptr = nested_functions(&main.chunk, heap)[0];
+            function(heap, ptr).chunk.clone()
+        };
+        let before = f(&plain, &plain_heap);
+        let after = f(&optimized, &optimized_heap);
+        assert!(before.code.iter().any(|op| matches!(op, OpCode::Print)));
+        assert!(!after.code.iter().any(|op| matches!(op, OpCode::Print)));
+        assert_eq!(after.code.len(), after.line_numbers.len());
+        assert!(after.code.len() < before.code.len());
+        assert_eq!(result(optimized, optimized_heap), "3");
+        assert_eq!(result(plain, plain_heap), "3");
+    }
+
+    #[test]
+    fn fuses_hot_pairs() {
+        let source = "fun sum(n) { var total = 0;\n\
+                      for (var i = 0; i < n; i = i + 1) total = total + i;\n\
+                      return total; }\n\
+                      var x = sum(10) + sum(4);";
+        let (main, heap) = compile(source, PassManager::for_level(2));
+        let calls = main.chunk.code.iter();
+        assert_eq!(calls.filter(|op| matches!(op, OpCode::CallConstant(_, 1))).count(), 2);
+        let sum = &function(&heap, nested_functions(&main.chunk, &heap)[0]).chunk;
+        let pairs = sum.code.windows(2);
+        assert!(pairs.clone().any(|pair| matches!(pair, [OpCode::AddLocal(_), OpCode::Add])));
+        //Only the Adds left behind the fused ones
+        assert!(pairs.filter(|pair| matches!(pair[1], OpCode::Add)).all(|pair| {
+            matches!(pair[0], OpCode::AddLocal(_))
+        }));
+        assert_eq!(result(main, heap), "51");
+    }
+
+    struct CountChunks(Rc<Cell<usize>>);
+
+    impl ChunkPass for CountChunks {
+        fn name(&self) -> &'static str {
+            "count-chunks"
+        }
+
+        fn run(&mut self, _chunk: &mut Chunk) -> bool {
+            self.0.set(self.0.get() + 1);
+            false
+        }
+    }
+
+    #[test]
+    fn runs_registered_passes_over_every_function() {
+        let count = Rc::new(Cell::new(0));
+        let mut passes = PassManager::new();
+        passes.register(Box::new(CountChunks(count.clone())));
+        compile("fun a() { fun b() {} } class C { m() {} }", passes);
+        assert_eq!(count.get(), 4);
+    }
+}
@@ -0,0 +1,144 @@
+use super::interpreter::VM;
+use super::natives::Group;
+use super::value::{Object, Value};
+use libloading::Library;
+use std::path::Path;
+
+// Native function packs loaded from shared libraries, so databases, graphics
+// and the like don't need a fork of the interpreter. A plugin is a cdylib
+// built against the same lox-vm, declaring its natives as Groups the way
+// natives does and exporting:
+//
+//   #[no_mangle]
+//   pub static LOX_PLUGIN_ABI: u32 = lox_vm::plugin::ABI_VERSION;
+//
+//   #[no_mangle]
+//   pub extern "C" fn lox_plugin_register(registry: &mut Registry) {
+//       registry.add(&SQLITE);
+//   }
+//
+// Groups go through capabilities like the built in ones, so a script only
+// gets those lox.toml allows.
+
+//Bumped whenever Registry, Group, the native function type or the layout of
+//the values natives are handed change
+pub const ABI_VERSION: u32 = 2;
+const ABI_SYMBOL: &[u8] = b"LOX_PLUGIN_ABI";
+const REGISTER_SYMBOL: &[u8] = b"lox_plugin_register";
+
+#[allow(improper_ctypes_definitions)]
+pub type RegisterFn = extern "C" fn(&mut Registry);
+
+#[derive(Default)]
+pub struct Registry {
+    groups: Vec<&'static Group>,
+}
+
+impl Registry {
+    pub fn add(&mut self, group: &'static Group) {
+        self.groups.push(group);
+    }
+}
+
+// Loads the plugin at path and defines its groups, returning the names of
+// any that capabilities left out.
+pub fn load(vm: &mut VM, path: &Path) -> Result<Vec<&'static str>, String> {
+    let library = unsafe { Library::new(path) }
+        .map_err(|e| format!("Couldn't load plugin {}: {}", path.display(), e))?;
+    let abi = unsafe { library.get::<*const u32>(ABI_SYMBOL) }
+        .map_err(|_| format!("{} is not a Lox plugin", path.display()))?;
+    let abi = unsafe { **abi };
+    if abi != ABI_VERSION {
+        return Err(format!(
+            "{} was built for plugin ABI {} but this is {}",
+            path.display(),
+            abi,
+            ABI_VERSION
+        ));
+    }
+    let register = *unsafe { library.get::<RegisterFn>(REGISTER_SYMBOL) }
+        .map_err(|_| format!("{} has no lox_plugin_register", path.display()))?;
+
+    //Its natives live in the library, so it stays loaded
+    std::mem::forget(library);
+    Ok(install(vm, register))
+}
+
+pub fn install(vm: &mut VM, register: RegisterFn) -> Vec<&'static str> {
+    let mut registry = Registry::default();
+    register(&mut registry);
+
+    let mut skipped = vec![];
+    for group in registry.groups {
+        if vm.allows(group.name) {
+            vm.define_natives(group);
+        } else {
+            skipped.push(group.name);
+        }
+    }
+    skipped
+}
+
+pub fn load_plugin(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path = match args[0] {
+        Value::Object(ptr) => match vm.heap_object(ptr) {
+            Some(Object::String(s)) => s.clone(),
+            _ => return Err(String::from("Expected a plugin path")),
+        },
+        _ => return Err(String::from("Expected a plugin path")),
+    };
+    load(vm, Path::new(&path))?;
+    Ok(Value::Nil)
+}
+
+#[cfg(test)]
+mod plugin_tests {
+    use super::*;
+    use crate::config::VmOptions;
+    use crate::natives::Native;
+    use crate::test_support::run;
+
+    fn double(_: &mut VM, args: &[Value]) -> Result<Value, String> {
+        match args[0] {
+            Value::Number(n) => Ok(Value::Number(n * 2.0)),
+            Value::Integer(n) => Ok(Value::Integer(n * 2)),
+            _ => Err(String::from("Expected a number")),
+        }
+    }
+
+    const MATH: Group = Group {
+        name: "math",
+        natives: &[Native {
+            name: "double",
+            params: &["n"],
+            doc: "Twice n.",
+            function: double,
+        }],
+    };
+
+    extern "C" fn register(registry: &mut Registry) {
+        registry.add(&MATH);
+    }
+
+    #[test]
+    fn installs_allowed_groups() {
+        let mut vm = VM::new();
+        assert!(install(&mut vm, register).is_empty());
+        assert_eq!(run(&mut vm, "var x = double(21);"), Ok(()));
+
+        let mut vm = VM::new();
+        vm.configure(&VmOptions {
+            capabilities: Some(vec![String::from("clock")]),
+            ..VmOptions::default()
+        });
+        assert_eq!(install(&mut vm, register), vec!["math"]);
+        assert!(run(&mut vm, "double(1);").unwrap_err().contains("Undefined variable"));
+    }
+
+    #[test]
+    fn rejects_libraries_that_arent_plugins() {
+        let mut vm = VM::new();
+        let error = load(&mut vm, Path::new("libm.so.6")).unwrap_err();
+        assert!(error.contains("not a Lox plugin") || error.contains("libm.so.6"));
+    }
+}
@@ -0,0 +1,6 @@
+// What std's prelude would have given the core modules.
+pub use alloc::boxed::Box;
+pub use alloc::format;
+pub use alloc::string::{String, ToString};
+pub use alloc::vec;
+pub use alloc::vec::Vec;
@@ -0,0 +1,242 @@
+use super::chunk::OpCode;
+use super::collections::Map;
+use super::interpreter::VirtualMemory;
+use core::cmp::Reverse;
+use core::fmt;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+const HOTTEST: usize = 10;
+
+// How often each instruction ran, once enable_profile is called, for finding
+// where a script spends its time and which loops are worth optimizing. A loop
+// is its Loop instruction, so its count is how often the back edge was taken.
+//
+// Counts belong to a function's name and line rather than its address, since
+// the GC may hand a swept function's address to a new one. Closures are
+// mapped to them the first time they run after each collection.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    functions: Vec<FunctionCounts>,
+    closures: Map<u64, usize>,
+    //The closure counted last and its index, which is usually the next one too
+    last: Option<(u64, usize)>,
+}
+
+#[derive(Debug, Clone)]
+struct FunctionCounts {
+    name: String,
+    line: usize,
+    //By ip, which tiering leaves where it was
+    instructions: Vec<u64>,
+    //The ip of each Loop and the line it jumps back to
+    loops: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionSummary {
+    pub name: String,
+    pub line: usize,
+    pub instructions: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopSummary {
+    pub function: String,
+    pub line: usize,
+    pub iterations: u64,
+}
+
+// The hottest functions by instructions run and the hottest loops by back
+// edges taken, hottest first.
+#[derive(Debug, Clone)]
+pub struct ProfileReport {
+    pub instructions: u64,
+    pub functions: Vec<FunctionSummary>,
+    pub loops: Vec<LoopSummary>,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    #[inline]
+    pub fn count(&mut self, heap: &VirtualMemory, closure_p: u64, ip: usize) {
+        let idx = match self.last {
+            Some((last, idx)) if last == closure_p => idx,
+            _ => self.function(heap, closure_p),
+        };
+        if let Some(count) = self.functions[idx].instructions.get_mut(ip) {
+            *count += 1;
+        }
+    }
+
+    fn function(&mut self, heap: &VirtualMemory, closure_p: u64) -> usize {
+        let idx = match self.closures.get(&closure_p) {
+            Some(idx) => *idx,
+            None => {
+                let idx = self.resolve(heap, closure_p);
+                self.closures.insert(closure_p, idx);
+                idx
+            }
+        };
+        self.last = Some((closure_p, idx));
+        idx
+    }
+
+    fn resolve(&mut self, heap: &VirtualMemory, closure_p: u64) -> usize {
+        let fp = heap.deref(closure_p).as_closure().function_pointer;
+        let function = heap.deref(fp).as_function();
+        let chunk = &function.chunk;
+        let line = chunk.line_numbers.first().copied().unwrap_or(0);
+        let known = self.functions.iter().position(|f| f.name == function.name && f.line == line);
+        if let Some(idx) = known {
+            return idx;
+        }
+        let loops = chunk
+            .code
+            .iter()
+            .enumerate()
+            .filter_map(|(ip, op)| match op {
+                OpCode::Loop(offset) => {
+                    let target = (ip + 1).saturating_sub(*offset);
+                    Some((ip, chunk.line_numbers.get(target).copied().unwrap_or(0)))
+                }
+                _ => None,
+            })
+            .collect();
+        self.functions.push(FunctionCounts {
+            name: function.name.clone(),
+            line,
+            instructions: vec![0; chunk.code.len()],
+            loops,
+        });
+        self.functions.len() - 1
+    }
+
+    //The GC may reuse the addresses of closures it swept
+    pub fn forget(&mut self) {
+        self.closures.clear();
+        self.last = None;
+    }
+
+    pub fn report(&self) -> ProfileReport {
+        let mut functions: Vec<FunctionSummary> = self
+            .functions
+            .iter()
+            .map(|f| FunctionSummary {
+                name: f.name.clone(),
+                line: f.line,
+                instructions: f.instructions.iter().sum(),
+            })
+            .collect();
+        functions.sort_by_key(|f| Reverse(f.instructions));
+        let mut loops: Vec<LoopSummary> = self
+            .functions
+            .iter()
+            .flat_map(|f| {
+                f.loops.iter().map(move |(ip, line)| LoopSummary {
+                    function: f.name.clone(),
+                    line: *line,
+                    iterations: f.instructions[*ip],
+                })
+            })
+            .filter(|l| l.iterations > 0)
+            .collect();
+        //A for loop with an increment takes two back edges to the same line
+        loops.sort_by_key(|l| Reverse(l.iterations));
+        let mut seen = vec![];
+        loops.retain(|l| {
+            let key = (l.function.clone(), l.line);
+            let first = !seen.contains(&key);
+            seen.push(key);
+            first
+        });
+        ProfileReport {
+            instructions: functions.iter().map(|f| f.instructions).sum(),
+            functions,
+            loops,
+        }
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} instructions run", self.instructions)?;
+        writeln!(f, "Hottest functions:")?;
+        for function in self.functions.iter().take(HOTTEST) {
+            let share = function.instructions as f64 * 100.0 / self.instructions.max(1) as f64;
+            writeln!(
+                f,
+                "  {:<24} {:>12} {:>5.1}%",
+                format!("{} (line {})", function.name, function.line),
+                function.instructions,
+                share
+            )?;
+        }
+        if !self.loops.is_empty() {
+            writeln!(f, "Hottest loops:")?;
+            for hot in self.loops.iter().take(HOTTEST) {
+                writeln!(
+                    f,
+                    "  {:<24} {:>12} iterations",
+                    format!("{} (line {})", hot.function, hot.line),
+                    hot.iterations
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod profile_tests {
+    use crate::interpreter::VM;
+    use crate::test_support::run;
+    #[cfg(not(feature = "std"))]
+    use crate::prelude::*;
+
+    fn profile(source: &str) -> super::ProfileReport {
+        let mut vm = VM::new();
+        vm.enable_profile();
+        run(&mut vm, source).unwrap();
+        vm.profile().unwrap().report()
+    }
+
+    #[test]
+    fn finds_the_hottest_function_and_loop() {
+        let report = profile(
+            "fun cold() { return 1; }\n\
+             fun hot(n) {\n\
+               var t = 0;\n\
+               for (var i = 0; i < n; i = i + 1) {\n\
+                 t = t + i;\n\
+               }\n\
+               return t;\n\
+             }\n\
+             cold();\n\
+             for (var j = 0; j < 3; j = j + 1) hot(100);",
+        );
+        assert_eq!(report.functions[0].name, "hot");
+        assert_eq!(report.functions[0].line, 3);
+        assert_eq!(report.loops[0].function, "hot");
+        assert_eq!(report.loops[0].line, 4);
+        assert_eq!(report.loops[0].iterations, 300);
+        assert_eq!(report.loops[1].iterations, 3);
+        let total: u64 = report.functions.iter().map(|f| f.instructions).sum();
+        assert_eq!(total, report.instructions);
+    }
+
+    #[test]
+    fn counts_closures_of_one_function_together() {
+        let report = profile(
+            "fun make() { fun add(x) { return x + 1; } return add; }\n\
+             var total = 0;\n\
+             for (var i = 0; i < 10; i = i + 1) total = make()(total);",
+        );
+        let add = report.functions.iter().find(|f| f.name == "add").unwrap();
+        //Each call is GetLocal, AddConstant and Return
+        assert_eq!(add.instructions, 30);
+    }
+}
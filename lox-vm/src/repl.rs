@@ -0,0 +1,239 @@
+use super::interpreter;
+use super::{run_with_echo, Options, Outcome};
+use lox_vm::doc;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::fs;
+use std::io;
+
+const PROMPT: &str = ">> ";
+const CONTINUATION_PROMPT: &str = ".. ";
+
+pub fn run_prompt(mut interpreter: interpreter::VM, options: &Options) {
+    let mut rl = Editor::<()>::new();
+    let mut compilable_unit = String::new();
+    //Everything entered this session, for :save
+    let mut history: Vec<String> = vec![];
+    loop {
+        let prompt = if compilable_unit.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        let readline = rl.readline(prompt);
+        match readline {
+            Ok(line) => {
+                let line = line.trim_end();
+                rl.add_history_entry(line);
+
+                if compilable_unit.is_empty() && line == "exit()" {
+                    std::process::exit(0);
+                }
+                if compilable_unit.is_empty() && line.starts_with(':') {
+                    run_command(line, &mut interpreter, options, &history);
+                    continue;
+                }
+
+                //A trailing backslash still forces a continuation line.
+                let (line, forced) = match line.strip_suffix('\\') {
+                    Some(stripped) => (stripped, true),
+                    None => (line, false),
+                };
+                compilable_unit.push_str(line);
+                compilable_unit.push('\n');
+
+                if !forced && is_complete(&compilable_unit) {
+                    let outcome = run_with_echo(&compilable_unit, &mut interpreter, options, true);
+                    if let Outcome::Exited(status) = outcome {
+                        std::process::exit(status);
+                    }
+                    history.push(compilable_unit.clone());
+                    compilable_unit.clear();
+                }
+            }
+            Err(ReadlineError::Interrupted) => {
+                if compilable_unit.is_empty() {
+                    println!("Interrupted. Type exit() to quit.");
+                } else {
+                    //Abandon the unit being entered rather than the session.
+                    compilable_unit.clear();
+                }
+            }
+            Err(ReadlineError::Eof) => {
+                println!("Eof Encountered");
+            }
+            Err(err) => {
+                println!("An error occurred {}", err);
+            }
+        }
+    }
+}
+
+fn run_command(
+    line: &str,
+    interpreter: &mut interpreter::VM,
+    options: &Options,
+    history: &[String],
+) {
+    match line {
+        ":globals" => {
+            for (name, value) in interpreter.globals() {
+                println!("{} = {}", name, interpreter.inspect(value));
+            }
+        }
+        ":heap" => print!("{}", interpreter.heap_report()),
+        _ => {
+            if let Some(address) = line.strip_prefix(":heap ") {
+                heap_path(address.trim(), interpreter);
+            } else if let Some(path) = line.strip_prefix(":load ") {
+                load(path.trim(), interpreter, options);
+            } else if let Some(name) = line.strip_prefix(":doc ") {
+                match doc::doc(interpreter, name.trim()) {
+                    Some(doc) => println!("{}", doc),
+                    None => println!("No function, class or native named {}", name.trim()),
+                }
+            } else if let Some(path) = line.strip_prefix(":save ") {
+                match save(path.trim(), history) {
+                    Ok(()) => println!("Saved {} entries to {}", history.len(), path.trim()),
+                    Err(e) => println!("{}: {}", path.trim(), e),
+                }
+            } else {
+                println!("Unknown command {}", line);
+            }
+        }
+    }
+}
+
+// Runs a file in this session, so its globals stay defined afterwards.
+fn load(path: &str, interpreter: &mut interpreter::VM, options: &Options) {
+    match fs::read_to_string(path) {
+        Ok(source) => {
+            if let Outcome::Exited(status) = run_with_echo(&source, interpreter, options, false) {
+                std::process::exit(status);
+            }
+        }
+        Err(e) => println!("{}: {}", path, e),
+    }
+}
+
+fn save(path: &str, history: &[String]) -> io::Result<()> {
+    fs::write(path, history.concat())
+}
+
+fn heap_path(address: &str, interpreter: &interpreter::VM) {
+    let address = match address.trim_start_matches('@').parse::<u64>() {
+        Ok(address) => address,
+        Err(_) => {
+            println!("Usage: :heap [@address]");
+            return;
+        }
+    };
+
+    if interpreter.heap_object(address).is_none() {
+        println!("Nothing lives at @{}", address);
+        return;
+    }
+    match interpreter.retention_path(address) {
+        Some(path) => {
+            for step in path {
+                println!("{}", step);
+            }
+        }
+        None => println!("@{} is unreachable and will be freed by the next collection", address),
+    }
+}
+
+// Decides whether the accumulated input can be handed to the compiler, or if we
+// should keep prompting. Input is incomplete while it has unclosed '(', '[' or
+// '{', an unterminated string or an unterminated block comment. Too many closing
+// delimiters counts as complete so the compiler gets to report the error.
+fn is_complete(source: &str) -> bool {
+    let mut depth: i64 = 0;
+    let mut in_string = false;
+    //Block comments nest, as they do in the scanner
+    let mut comments = 0;
+    let mut chars = source.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        if comments > 0 {
+            match c {
+                '/' if chars.next_if_eq(&'*').is_some() => comments += 1,
+                '*' if chars.next_if_eq(&'/').is_some() => comments -= 1,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '/' if chars.next_if_eq(&'*').is_some() => comments += 1,
+            '/' if chars.peek() == Some(&'/') => {
+                //Skip the rest of the comment
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    !in_string && comments == 0 && depth <= 0
+}
+
+#[cfg(test)]
+mod repl_tests {
+    use super::*;
+
+    #[test]
+    fn balanced_input_is_complete() {
+        assert!(is_complete("print 1 + 2;\n"));
+        assert!(is_complete("fun f(a) { return a; }\n"));
+    }
+
+    #[test]
+    fn open_delimiters_are_incomplete() {
+        assert!(!is_complete("fun f(a) {\n"));
+        assert!(!is_complete("class A {\n init() {\n }\n"));
+        assert!(!is_complete("print f(1,\n"));
+        assert!(!is_complete("var xs = [1,\n"));
+        assert!(is_complete("var xs = [1,\n2];\n"));
+    }
+
+    #[test]
+    fn save_writes_entries_in_order() {
+        let path = std::env::temp_dir().join(format!("lox-repl-save-{}.lox", std::process::id()));
+        let history = vec![
+            String::from("var a = 1;\n"),
+            String::from("fun f() {\n  return a;\n}\n"),
+        ];
+        save(path.to_str().unwrap(), &history).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "var a = 1;\nfun f() {\n  return a;\n}\n");
+    }
+
+    #[test]
+    fn strings_and_comments_are_ignored() {
+        assert!(!is_complete("print \"unterminated {\n"));
+        assert!(is_complete("print \"{ ( \";\n"));
+        assert!(is_complete("print 1; // {\n"));
+    }
+
+    #[test]
+    fn block_comments_wait_for_their_end() {
+        assert!(!is_complete("/* a comment\n"));
+        assert!(!is_complete("/* outer /* inner */ still open\n"));
+        assert!(is_complete("/* outer /* inner */ closed */ print 1;\n"));
+        assert!(is_complete("print 1; /* { ( [ */\n"));
+    }
+}
@@ -0,0 +1,482 @@
+use super::ast::*;
+use super::token::Span;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeclarationKind {
+    Global,
+    Local,
+    Parameter,
+    Function,
+    Class,
+    Method,
+}
+
+#[derive(Debug, Clone)]
+pub struct Declaration {
+    pub name: String,
+    pub kind: DeclarationKind,
+    pub span: Span,
+    pub arity: Option<usize>,
+    pub class: Option<String>, //Enclosing class for methods
+    pub global: bool,
+    pub used: bool,
+    pub assigned: bool, //After its declaration
+    pub shadows: Option<usize>, //An outer declaration with the same name
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Reference {
+    pub span: Span,
+    pub declaration: usize,
+}
+
+// Binds every name in a syntax tree to the declaration it refers to. Globals
+// are late bound in Lox so they are visible everywhere, while locals follow
+// the usual block scoping. Property accesses resolve to any method with the
+// same name since we don't know the receiver's class.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    pub declarations: Vec<Declaration>,
+    pub references: Vec<Reference>,
+    pub unresolved: Vec<Identifier>,
+}
+
+impl Resolution {
+    // The declaration named at the given position, whether the position is on
+    // the declaration itself or on a reference to it.
+    pub fn declaration_at(&self, line: usize, column: usize) -> Option<&Declaration> {
+        let contains = |span: &Span| {
+            span.line == line && column >= span.column && column < span.column + span.length
+        };
+
+        if let Some(reference) = self.references.iter().find(|r| contains(&r.span)) {
+            return Some(&self.declarations[reference.declaration]);
+        }
+        self.declarations.iter().find(|d| contains(&d.span))
+    }
+}
+
+struct Resolver {
+    resolution: Resolution,
+    globals: HashMap<String, usize>,
+    methods: HashMap<String, usize>,
+    scopes: Vec<HashMap<String, usize>>,
+}
+
+pub fn resolve(statements: &[Stmt]) -> Resolution {
+    let mut resolver = Resolver {
+        resolution: Resolution::default(),
+        globals: HashMap::new(),
+        methods: HashMap::new(),
+        scopes: vec![],
+    };
+
+    //Declare the globals and methods first so forward references resolve.
+    for stmt in statements.iter() {
+        resolver.declare_global(stmt);
+    }
+    for stmt in statements.iter() {
+        resolver.statement(stmt, true);
+    }
+
+    resolver.resolution
+}
+
+impl Resolver {
+    fn add_declaration(
+        &mut self,
+        name: &Identifier,
+        kind: DeclarationKind,
+        arity: Option<usize>,
+        class: Option<String>,
+    ) -> usize {
+        self.resolution.declarations.push(Declaration {
+            name: name.name.clone(),
+            kind,
+            span: name.span,
+            arity,
+            class,
+            global: self.scopes.is_empty() && kind != DeclarationKind::Method,
+            used: false,
+            assigned: false,
+            shadows: None,
+        });
+        self.resolution.declarations.len() - 1
+    }
+
+    fn declare_global(&mut self, stmt: &Stmt) {
+        if let Stmt::VarUnpack(_, names, _) = stmt {
+            for (name, _) in names.iter() {
+                self.add_global(name, DeclarationKind::Global, None);
+            }
+            return;
+        }
+        let (name, kind, arity) = match stmt {
+            Stmt::Var(name, _, _) | Stmt::Import(name, _) => (name, DeclarationKind::Global, None),
+            Stmt::Function(function) => (
+                &function.name,
+                DeclarationKind::Function,
+                Some(function.params.len()),
+            ),
+            Stmt::Class(class) => {
+                let init = class.methods.iter().find(|m| m.name.name == "init");
+                (
+                    &class.name,
+                    DeclarationKind::Class,
+                    Some(init.map(|m| m.params.len()).unwrap_or(0)),
+                )
+            }
+            _ => return,
+        };
+        self.add_global(name, kind, arity);
+    }
+
+    fn add_global(&mut self, name: &Identifier, kind: DeclarationKind, arity: Option<usize>) {
+        if !self.globals.contains_key(&name.name) {
+            let idx = self.add_declaration(name, kind, arity, None);
+            self.globals.insert(name.name.clone(), idx);
+        }
+    }
+
+    fn declare_local(&mut self, name: &Identifier, kind: DeclarationKind, arity: Option<usize>) {
+        let shadows = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name.name))
+            .or_else(|| self.globals.get(&name.name))
+            .copied();
+
+        let idx = self.add_declaration(name, kind, arity, None);
+        self.resolution.declarations[idx].shadows = shadows;
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.name.clone(), idx);
+    }
+
+    fn reference(&mut self, name: &Identifier, read: bool) {
+        let found = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name.name))
+            .or_else(|| self.globals.get(&name.name))
+            .copied();
+
+        match found {
+            Some(idx) => {
+                if read {
+                    self.resolution.declarations[idx].used = true;
+                } else {
+                    self.resolution.declarations[idx].assigned = true;
+                }
+                self.resolution.references.push(Reference {
+                    span: name.span,
+                    declaration: idx,
+                });
+            }
+            None => self.resolution.unresolved.push(name.clone()),
+        }
+    }
+
+    fn property(&mut self, name: &Identifier) {
+        if let Some(idx) = self.methods.get(&name.name).copied() {
+            self.resolution.declarations[idx].used = true;
+            self.resolution.references.push(Reference {
+                span: name.span,
+                declaration: idx,
+            });
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn block(&mut self, statements: &[Stmt]) {
+        self.begin_scope();
+        for stmt in statements.iter() {
+            self.statement(stmt, false);
+        }
+        self.end_scope();
+    }
+
+    fn statement(&mut self, stmt: &Stmt, top_level: bool) {
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expression(expr),
+            Stmt::Var(name, _, initializer) => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                if !top_level {
+                    self.declare_local(name, DeclarationKind::Local, None);
+                }
+            }
+            Stmt::VarUnpack(_, names, initializer) => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                if !top_level {
+                    for (name, _) in names.iter() {
+                        self.declare_local(name, DeclarationKind::Local, None);
+                    }
+                }
+            }
+            Stmt::Block(statements, _) => self.block(statements),
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                self.statement(then_branch, false);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch, false);
+                }
+            }
+            Stmt::While(condition, body) => {
+                self.expression(condition);
+                self.statement(body, false);
+            }
+            Stmt::DoWhile(body, condition) => {
+                self.statement(body, false);
+                self.expression(condition);
+            }
+            Stmt::For(initializer, condition, increment, body) => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer, false);
+                }
+                if let Some(condition) = condition {
+                    self.expression(condition);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment);
+                }
+                self.statement(body, false);
+                self.end_scope();
+            }
+            Stmt::Switch(value, cases, default) => {
+                self.expression(value);
+                for (case, body) in cases.iter() {
+                    self.expression(case);
+                    self.begin_scope();
+                    for stmt in body.iter() {
+                        self.statement(stmt, false);
+                    }
+                    self.end_scope();
+                }
+                if let Some(default) = default {
+                    self.begin_scope();
+                    for stmt in default.iter() {
+                        self.statement(stmt, false);
+                    }
+                    self.end_scope();
+                }
+            }
+            Stmt::Function(function) => {
+                if !top_level {
+                    self.declare_local(
+                        &function.name,
+                        DeclarationKind::Function,
+                        Some(function.params.len()),
+                    );
+                }
+                self.function(function);
+            }
+            Stmt::Return(_, value) | Stmt::Yield(_, value) => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Try(body, catch, finally) => {
+                self.block(body);
+                if let Some((name, body)) = catch {
+                    self.begin_scope();
+                    self.declare_local(name, DeclarationKind::Local, None);
+                    self.block(body);
+                    self.end_scope();
+                }
+                if let Some(finally) = finally {
+                    self.block(finally);
+                }
+            }
+            Stmt::Throw(_, value) => self.expression(value),
+            Stmt::Import(name, _) => {
+                if !top_level {
+                    self.declare_local(name, DeclarationKind::Local, None);
+                }
+            }
+            Stmt::Labeled(_, body) => self.statement(body, false),
+            Stmt::Break(..) | Stmt::Continue(..) => {}
+            Stmt::Class(class) => {
+                if !top_level {
+                    let init = class.methods.iter().find(|m| m.name.name == "init");
+                    let arity = init.map(|m| m.params.len()).unwrap_or(0);
+                    self.declare_local(&class.name, DeclarationKind::Class, Some(arity));
+                }
+                self.class(class);
+            }
+        }
+    }
+
+    fn class(&mut self, class: &ClassDecl) {
+        for superclass in class.superclasses.iter() {
+            self.reference(superclass, true);
+        }
+        for method in class.all_methods() {
+            let idx = self.add_declaration(
+                &method.name,
+                DeclarationKind::Method,
+                Some(method.params.len()),
+                Some(class.name.name.clone()),
+            );
+            self.methods.entry(method.name.name.clone()).or_insert(idx);
+        }
+        for method in class.all_methods() {
+            self.function(method);
+        }
+    }
+
+    fn function(&mut self, function: &FunctionDecl) {
+        self.begin_scope();
+        for param in function.params.iter() {
+            self.declare_local(param, DeclarationKind::Parameter, None);
+        }
+        for stmt in function.body.iter() {
+            self.statement(stmt, false);
+        }
+        self.end_scope();
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Literal(_) | ExprKind::This | ExprKind::Super(_) => {}
+            ExprKind::Variable(name) => self.reference(name, true),
+            ExprKind::Assign(name, value) => {
+                self.expression(value);
+                self.reference(name, false);
+            }
+            ExprKind::Compound(target, _, value) => {
+                self.expression(target);
+                self.expression(value);
+                if let ExprKind::Variable(name) = &target.kind {
+                    self.reference(name, false);
+                }
+            }
+            ExprKind::Unary(_, right) => self.expression(right),
+            ExprKind::Binary(left, _, right) | ExprKind::Logical(left, _, right) => {
+                self.expression(left);
+                self.expression(right);
+            }
+            ExprKind::Conditional(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                self.expression(then_branch);
+                self.expression(else_branch);
+            }
+            ExprKind::Interpolation(parts) => {
+                for part in parts.iter() {
+                    self.expression(part);
+                }
+            }
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Call(callee, args) => {
+                self.expression(callee);
+                for arg in args.iter() {
+                    self.expression(arg);
+                }
+            }
+            ExprKind::Get(object, name) => {
+                self.expression(object);
+                self.property(name);
+            }
+            ExprKind::Set(object, _, value) => {
+                self.expression(object);
+                self.expression(value);
+            }
+            ExprKind::List(items) | ExprKind::Tuple(items) => {
+                for item in items.iter() {
+                    self.expression(item);
+                }
+            }
+            ExprKind::Index(list, index) => {
+                self.expression(list);
+                self.expression(index);
+            }
+            ExprKind::IndexSet(list, index, value) => {
+                self.expression(list);
+                self.expression(index);
+                self.expression(value);
+            }
+            ExprKind::Class(class) => self.class(class),
+            ExprKind::Match(value, arms) => {
+                self.expression(value);
+                for (pattern, result) in arms.iter() {
+                    self.begin_scope();
+                    match pattern {
+                        Pattern::Literal(literal) => self.expression(literal),
+                        Pattern::Binding(name) | Pattern::Type(Some(name), _) => {
+                            self.declare_local(name, DeclarationKind::Local, None);
+                        }
+                        Pattern::Wildcard | Pattern::Type(None, _) => {}
+                    }
+                    self.expression(result);
+                    self.end_scope();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod resolver_tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::scanner::scan_tokens;
+
+    fn resolve_source(source: &str) -> Resolution {
+        let (statements, errors) = parse(scan_tokens(&String::from(source)).unwrap());
+        assert!(errors.is_empty());
+        resolve(&statements)
+    }
+
+    #[test]
+    fn locals_shadow_globals() {
+        let resolution = resolve_source("var a = 1;\n{\n  var a = 2;\n  print a;\n}\nprint a;");
+        let inner = resolution.declaration_at(4, 9).unwrap();
+        assert_eq!(inner.kind, DeclarationKind::Local);
+        assert_eq!(inner.span.line, 3);
+        let outer = resolution.declaration_at(6, 7).unwrap();
+        assert_eq!(outer.kind, DeclarationKind::Global);
+        assert_eq!(outer.span.line, 1);
+    }
+
+    #[test]
+    fn forward_references_to_globals() {
+        let resolution = resolve_source("fun f() { return g(); }\nfun g() { return 1; }");
+        let g = resolution.declaration_at(1, 18).unwrap();
+        assert_eq!(g.name, "g");
+        assert_eq!(g.arity, Some(0));
+        assert!(resolution.unresolved.is_empty());
+    }
+
+    #[test]
+    fn methods_and_unused() {
+        let resolution =
+            resolve_source("class A { m(x, y) { var z; } }\nvar a = A();\na.m(1, 2);\nprint b;");
+        let m = resolution.declaration_at(3, 3).unwrap();
+        assert_eq!(m.kind, DeclarationKind::Method);
+        assert_eq!(m.class.as_deref(), Some("A"));
+        assert_eq!(m.arity, Some(2));
+
+        let z = resolution
+            .declarations
+            .iter()
+            .find(|d| d.name == "z")
+            .unwrap();
+        assert!(!z.used);
+        assert_eq!(resolution.unresolved[0].name, "b");
+    }
+}
@@ -1,12 +1,14 @@
 use super::token::Token;
 use super::token::TokenType;
-use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::fmt;
+use core::fmt;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ScannerError {
     pub line: usize,
+    pub column: usize,
     pub description: String,
 }
 
@@ -16,29 +18,42 @@ impl fmt::Display for ScannerError {
     }
 }
 
-lazy_static! {
-    static ref LITERAL_TO_TOKEN: HashMap<&'static str, TokenType> = vec![
-        ("and", TokenType::And),
-        ("class", TokenType::Class),
-        ("else", TokenType::Else),
-        ("false", TokenType::False),
-        ("true", TokenType::True),
-        ("fun", TokenType::Fun),
-        ("for", TokenType::For),
-        ("if", TokenType::If),
-        ("nil", TokenType::Nil),
-        ("or", TokenType::Or),
-        ("print", TokenType::Print),
-        ("return", TokenType::Return),
-        ("super", TokenType::Super),
-        ("this", TokenType::This),
-        ("var", TokenType::Var),
-        ("while", TokenType::While),
-        ("EOF", TokenType::EOF),
-    ]
-    .iter()
-    .copied()
-    .collect();
+pub fn keyword(literal: &str) -> Option<TokenType> {
+    let token_type = match literal {
+        "and" => TokenType::And,
+        "break" => TokenType::Break,
+        "case" => TokenType::Case,
+        "catch" => TokenType::Catch,
+        "class" => TokenType::Class,
+        "continue" => TokenType::Continue,
+        "default" => TokenType::Default,
+        "do" => TokenType::Do,
+        "else" => TokenType::Else,
+        "false" => TokenType::False,
+        "finally" => TokenType::Finally,
+        "true" => TokenType::True,
+        "fun" => TokenType::Fun,
+        "for" => TokenType::For,
+        "if" => TokenType::If,
+        "import" => TokenType::Import,
+        "match" => TokenType::Match,
+        "nil" => TokenType::Nil,
+        "or" => TokenType::Or,
+        "print" => TokenType::Print,
+        "return" => TokenType::Return,
+        "static" => TokenType::Static,
+        "super" => TokenType::Super,
+        "switch" => TokenType::Switch,
+        "this" => TokenType::This,
+        "throw" => TokenType::Throw,
+        "try" => TokenType::Try,
+        "var" => TokenType::Var,
+        "while" => TokenType::While,
+        "yield" => TokenType::Yield,
+        "EOF" => TokenType::EOF,
+        _ => return None,
+    };
+    Some(token_type)
 }
 
 struct LexicalScanner {
@@ -46,6 +61,11 @@ struct LexicalScanner {
     start: usize,
     current: usize,
     line: usize,
+    line_start: usize,   //Index of the first character on the current line
+    start_column: usize, //Column of the token being scanned
+
+    //Unclosed braces inside each ${ we're in the middle of, innermost last
+    interpolations: Vec<usize>,
 
     //Data Variables
     source_chars: Vec<char>,
@@ -58,6 +78,9 @@ impl LexicalScanner {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_column: 1,
+            interpolations: vec![],
             source_chars: source.chars().collect(),
             tokens: vec![],
         }
@@ -68,6 +91,7 @@ impl LexicalScanner {
             token_type,
             lexeme: self.make_lexeme(),
             line: self.line,
+            column: self.start_column,
             literal,
         };
         self.tokens.push(t);
@@ -127,6 +151,12 @@ impl LexicalScanner {
         }
     }
 
+    //Call after consuming a '\n'
+    pub fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     pub fn consume_whitespace(&mut self) {
         self.start = self.current;
     }
@@ -135,7 +165,7 @@ impl LexicalScanner {
         while self.has_next() {
             let c = self.next();
             if c == '\n' {
-                self.line += 1;
+                self.new_line();
                 break;
             }
         }
@@ -143,23 +173,61 @@ impl LexicalScanner {
         self.start = self.current;
     }
 
+    //Skips a /* */ comment, starting after the opening /*. Comments inside it
+    //nest, so commenting out code that has one of its own still works.
+    pub fn consume_block_comment(&mut self) -> Result<(), ScannerError> {
+        let start_line = self.line;
+        let mut depth = 1;
+        while self.has_next() {
+            match self.next() {
+                '\n' => self.new_line(),
+                '/' if self.match_ahead('*') => depth += 1,
+                '*' if self.match_ahead('/') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.start = self.current;
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Err(ScannerError {
+            line: start_line,
+            column: self.start_column,
+            description: String::from("Unterminated block comment!"),
+        })
+    }
+
+    //Scans up to the closing quote or the next ${, starting after the opening
+    //quote or the } that ended the last interpolated expression
     pub fn consume_string(&mut self) -> Result<(), ScannerError> {
+        let start_line = self.line;
         while self.has_next() {
             match self.next() {
                 '\n' => {
-                    self.line += 1;
+                    self.new_line();
                 }
                 '\"' => {
                     let literal = self.make_literal(self.start + 1, self.current - 1);
                     self.consume_token(TokenType::StringToken, Some(literal));
                     return Ok(());
                 }
+                '$' if self.peek() == '{' => {
+                    self.next();
+                    let literal = self.make_literal(self.start + 1, self.current - 2);
+                    self.consume_token(TokenType::Interpolation, Some(literal));
+                    self.interpolations.push(0);
+                    return Ok(());
+                }
                 _ => {}
             }
         }
 
         Err(ScannerError {
-            line: self.line,
+            line: start_line,
+            column: self.start_column,
             description: String::from("Unterminated string!"),
         })
     }
@@ -185,15 +253,23 @@ impl LexicalScanner {
         }
 
         let literal = self.make_literal(self.start, self.current);
-        self.consume_token(TokenType::NumberToken, Some(literal));
+        //Integers too big for an i64 are left as floats
+        let token_type = if literal.parse::<i64>().is_ok() {
+            TokenType::IntegerToken
+        } else {
+            TokenType::NumberToken
+        };
+        self.consume_token(token_type, Some(literal));
 
         Ok(())
     }
 
+    //Letters from any script can start an identifier, and it can go on with
+    //any letters or digits
     pub fn consume_identifier_or_keyword(&mut self) -> Result<(), ScannerError> {
         while self.has_next() {
             match self.peek() {
-                '0'..='9' | 'a'..='z' | 'A'..='Z' => {
+                c if c.is_alphanumeric() || c == '_' => {
                     self.next();
                 }
                 _ => {
@@ -218,32 +294,86 @@ impl LexicalScanner {
     }
 
     pub fn literal_to_token_type(literal: &String) -> TokenType {
-        //Todo, figure out a way to use a hashtable.
-        if let Some(token) = LITERAL_TO_TOKEN.get(&literal[..]) {
-            *token
-        } else {
-            TokenType::Identifier
-        }
+        keyword(literal).unwrap_or(TokenType::Identifier)
     }
 }
 
 pub fn scan_tokens(source: &String) -> Result<Vec<Token>, ScannerError> {
     let mut scanner = LexicalScanner::new(source);
+    //A #! line lets scripts be run directly on Unix
+    if source.starts_with("#!") {
+        scanner.consume_comment();
+    }
 
     while scanner.has_next() {
+        scanner.start_column = scanner.current - scanner.line_start + 1;
         let c = scanner.next();
 
         let token_or_error = match c {
             '(' => Ok(scanner.consume_token(TokenType::LeftParen, None)),
             ')' => Ok(scanner.consume_token(TokenType::RightParen, None)),
-            '{' => Ok(scanner.consume_token(TokenType::LeftBrace, None)),
-            '}' => Ok(scanner.consume_token(TokenType::RightBrace, None)),
+            '{' => {
+                if let Some(braces) = scanner.interpolations.last_mut() {
+                    *braces += 1;
+                }
+                Ok(scanner.consume_token(TokenType::LeftBrace, None))
+            }
+            '}' => match scanner.interpolations.last_mut() {
+                //Closes the ${, so the string picks up again
+                Some(0) => {
+                    scanner.interpolations.pop();
+                    scanner.consume_string()
+                }
+                Some(braces) => {
+                    *braces -= 1;
+                    Ok(scanner.consume_token(TokenType::RightBrace, None))
+                }
+                None => Ok(scanner.consume_token(TokenType::RightBrace, None)),
+            },
+            '[' => Ok(scanner.consume_token(TokenType::LeftBracket, None)),
+            ']' => Ok(scanner.consume_token(TokenType::RightBracket, None)),
             ',' => Ok(scanner.consume_token(TokenType::Comma, None)),
-            '.' => Ok(scanner.consume_token(TokenType::Dot, None)),
-            '-' => Ok(scanner.consume_token(TokenType::Minus, None)),
-            '+' => Ok(scanner.consume_token(TokenType::Plus, None)),
+            '.' => {
+                let token_type = if scanner.peek() == '.' && scanner.peek_next() == '.' {
+                    scanner.next();
+                    scanner.next();
+                    TokenType::DotDotDot
+                } else {
+                    TokenType::Dot
+                };
+                Ok(scanner.consume_token(token_type, None))
+            }
+            '-' => {
+                let token_type = if scanner.match_ahead('=') {
+                    TokenType::MinusEqual
+                } else {
+                    TokenType::Minus
+                };
+                Ok(scanner.consume_token(token_type, None))
+            }
+            '+' => {
+                let token_type = if scanner.match_ahead('=') {
+                    TokenType::PlusEqual
+                } else {
+                    TokenType::Plus
+                };
+                Ok(scanner.consume_token(token_type, None))
+            }
             ';' => Ok(scanner.consume_token(TokenType::Semicolon, None)),
-            '*' => Ok(scanner.consume_token(TokenType::Star, None)),
+            ':' => Ok(scanner.consume_token(TokenType::Colon, None)),
+            '?' => Ok(scanner.consume_token(TokenType::Question, None)),
+            '&' => Ok(scanner.consume_token(TokenType::Ampersand, None)),
+            '|' => Ok(scanner.consume_token(TokenType::Pipe, None)),
+            '^' => Ok(scanner.consume_token(TokenType::Caret, None)),
+            '~' => Ok(scanner.consume_token(TokenType::Tilde, None)),
+            '*' => {
+                let token_type = if scanner.match_ahead('=') {
+                    TokenType::StarEqual
+                } else {
+                    TokenType::Star
+                };
+                Ok(scanner.consume_token(token_type, None))
+            }
             '!' => {
                 let token_type = if scanner.match_ahead('=') {
                     TokenType::BangEqual
@@ -255,6 +385,8 @@ pub fn scan_tokens(source: &String) -> Result<Vec<Token>, ScannerError> {
             '=' => {
                 let token_type = if scanner.match_ahead('=') {
                     TokenType::EqualEqual
+                } else if scanner.match_ahead('>') {
+                    TokenType::Arrow
                 } else {
                     TokenType::Equal
                 };
@@ -263,6 +395,8 @@ pub fn scan_tokens(source: &String) -> Result<Vec<Token>, ScannerError> {
             '<' => {
                 let token_type = if scanner.match_ahead('=') {
                     TokenType::LessEqual
+                } else if scanner.match_ahead('<') {
+                    TokenType::LessLess
                 } else {
                     TokenType::Less
                 };
@@ -271,6 +405,8 @@ pub fn scan_tokens(source: &String) -> Result<Vec<Token>, ScannerError> {
             '>' => {
                 let token_type = if scanner.match_ahead('=') {
                     TokenType::GreaterEqual
+                } else if scanner.match_ahead('>') {
+                    TokenType::GreaterGreater
                 } else {
                     TokenType::Greater
                 };
@@ -280,6 +416,10 @@ pub fn scan_tokens(source: &String) -> Result<Vec<Token>, ScannerError> {
                 if scanner.match_ahead('/') {
                     scanner.consume_comment();
                     Ok(())
+                } else if scanner.match_ahead('*') {
+                    scanner.consume_block_comment()
+                } else if scanner.match_ahead('=') {
+                    Ok(scanner.consume_token(TokenType::SlashEqual, None))
                 } else {
                     Ok(scanner.consume_token(TokenType::Slash, None))
                 }
@@ -298,14 +438,15 @@ pub fn scan_tokens(source: &String) -> Result<Vec<Token>, ScannerError> {
             }
             '\n' => {
                 scanner.consume_whitespace();
-                scanner.line += 1;
+                scanner.new_line();
                 Ok(())
             }
             '\"' => scanner.consume_string(),
             '0'..='9' => scanner.consume_number(),
-            'a'..='z' | 'A'..='Z' => scanner.consume_identifier_or_keyword(),
+            c if c.is_alphabetic() || c == '_' => scanner.consume_identifier_or_keyword(),
             _ => Err(ScannerError {
                 line: scanner.line,
+                column: scanner.start_column,
                 description: String::from(format!("Unrecognized Token {}", c)),
             }),
         };
@@ -313,11 +454,20 @@ pub fn scan_tokens(source: &String) -> Result<Vec<Token>, ScannerError> {
         token_or_error?;
     }
 
+    if !scanner.interpolations.is_empty() {
+        return Err(ScannerError {
+            line: scanner.line,
+            column: scanner.current - scanner.line_start + 1,
+            description: String::from("Unterminated string interpolation!"),
+        });
+    }
+
     // Add an EOF to the end of the sequence
     scanner.tokens.push(Token {
         token_type: TokenType::EOF,
         lexeme: String::from("EOF"),
-        line: 0,
+        line: scanner.line,
+        column: scanner.current - scanner.line_start + 1,
         literal: None,
     });
     Ok(scanner.tokens)
@@ -355,6 +505,21 @@ mod scanner_tests {
         assert_eq!(tokens[7].token_type, TokenType::Equal);
     }
 
+    #[test]
+    fn bitwise_operators() {
+        let test_input = String::from("& | ^ ~ << >> <<=");
+        let tokens = scan_tokens(&test_input).unwrap();
+
+        assert_eq!(tokens[0].token_type, TokenType::Ampersand);
+        assert_eq!(tokens[1].token_type, TokenType::Pipe);
+        assert_eq!(tokens[2].token_type, TokenType::Caret);
+        assert_eq!(tokens[3].token_type, TokenType::Tilde);
+        assert_eq!(tokens[4].token_type, TokenType::LessLess);
+        assert_eq!(tokens[5].token_type, TokenType::GreaterGreater);
+        assert_eq!(tokens[6].token_type, TokenType::LessLess);
+        assert_eq!(tokens[7].token_type, TokenType::Equal);
+    }
+
     #[test]
     fn slash_comments() {
         let test_input = String::from("// This is to be filtered out\n / ");
@@ -364,6 +529,31 @@ mod scanner_tests {
         assert_eq!(tokens[0].token_type, TokenType::Slash);
     }
 
+    #[test]
+    fn block_comments() {
+        let test_input = String::from("/* outer /* inner */ still\n comment */ / /**/ /");
+        let tokens = scan_tokens(&test_input).unwrap();
+
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.token_type).collect();
+        assert_eq!(types, vec![TokenType::Slash, TokenType::Slash, TokenType::EOF]);
+        assert_eq!(tokens[0].line, 2);
+
+        let unterminated = String::from("\n/* a /* b */\n");
+        let error = scan_tokens(&unterminated).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.description, "Unterminated block comment!");
+    }
+
+    #[test]
+    fn shebang_line() {
+        let test_input = String::from("#!/usr/bin/env lox\nprint");
+        let tokens = scan_tokens(&test_input).unwrap();
+
+        assert_eq!(tokens[0].token_type, TokenType::Print);
+        assert_eq!(tokens[0].line, 2);
+        assert!(scan_tokens(&String::from("print\n#!")).is_err());
+    }
+
     #[test]
     fn regular_string() {
         let test_input = String::from(" \"this is a test string\"");
@@ -386,6 +576,25 @@ mod scanner_tests {
         assert_eq!(*s, String::from(" This is a \n 3multiline \n string"));
     }
 
+    #[test]
+    fn interpolated_string() {
+        let test_input = String::from("\"a ${f({}, \"${c}\")} d\"");
+        let tokens = scan_tokens(&test_input).unwrap();
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.token_type).collect();
+
+        assert_eq!(types[0], TokenType::Interpolation);
+        assert_eq!(types[4], TokenType::RightBrace);
+        assert_eq!(types[6], TokenType::Interpolation);
+        assert_eq!(types[8], TokenType::StringToken);
+        assert_eq!(types[10], TokenType::StringToken);
+        assert_eq!(*tokens[0].literal.as_ref().unwrap(), String::from("a "));
+        assert_eq!(*tokens[10].literal.as_ref().unwrap(), String::from(" d"));
+
+        let unterminated = String::from("\"a ${b");
+        let error = scan_tokens(&unterminated).unwrap_err();
+        assert_eq!(error.description, "Unterminated string interpolation!");
+    }
+
     #[test]
     fn number_token() {
         let test_input = String::from("343 893.4 \n");
@@ -394,7 +603,7 @@ mod scanner_tests {
         println!("{:?}", tokens);
         assert_eq!(tokens.len(), 2);
 
-        assert_eq!(tokens[0].token_type, TokenType::NumberToken);
+        assert_eq!(tokens[0].token_type, TokenType::IntegerToken);
         let n1: f64 = tokens[0].literal.as_ref().unwrap().parse().unwrap();
         assert_eq!(n1, 343.0);
 
@@ -410,7 +619,7 @@ mod scanner_tests {
 
         assert_eq!(tokens[0].token_type, TokenType::Identifier);
         assert_eq!(tokens[1].token_type, TokenType::Identifier);
-        assert_eq!(tokens[2].token_type, TokenType::NumberToken);
+        assert_eq!(tokens[2].token_type, TokenType::IntegerToken);
         assert_eq!(tokens[3].token_type, TokenType::Identifier);
 
         assert_eq!(*tokens[0].literal.as_ref().unwrap(), String::from("valid"));
@@ -419,6 +628,20 @@ mod scanner_tests {
         assert_eq!(*tokens[3].literal.as_ref().unwrap(), String::from("one"));
     }
 
+    #[test]
+    fn unicode_identifiers() {
+        let test_input = String::from("café = \"日本 🎉\" + Ωmega2;");
+        let tokens = scan_tokens(&test_input).unwrap();
+
+        assert_eq!(tokens[0].token_type, TokenType::Identifier);
+        assert_eq!(*tokens[0].literal.as_ref().unwrap(), String::from("café"));
+        assert_eq!(tokens[1].column, 6);
+        assert_eq!(*tokens[2].literal.as_ref().unwrap(), String::from("日本 🎉"));
+        assert_eq!(*tokens[4].literal.as_ref().unwrap(), String::from("Ωmega2"));
+        assert_eq!(tokens[4].span().length, 6);
+        assert!(scan_tokens(&String::from("🎉")).is_err());
+    }
+
     #[test]
     fn keyword_token() {
         let test_input = String::from(" valid class! classical true this;");
@@ -0,0 +1,118 @@
+use super::collections::Map;
+use super::symbols::Symbol;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+// Hidden classes for instance fields. Instances that gained the same fields in
+// the same order share a shape, which maps each field's symbol to a slot in
+// the instance's field Vec, so names are stored once per layout instead of once
+// per instance. Shapes are never freed; a program only has so many field layouts.
+
+pub type ShapeId = usize;
+
+//What every instance starts with
+pub const EMPTY_SHAPE: ShapeId = 0;
+
+struct Shape {
+    //Field symbols by slot
+    names: Vec<Symbol>,
+    slots: Map<Symbol, usize>,
+    //The shape an instance moves to when it gains a field
+    transitions: Map<Symbol, ShapeId>,
+}
+
+pub struct Shapes {
+    shapes: Vec<Shape>,
+}
+
+impl Shapes {
+    pub fn new() -> Shapes {
+        Shapes {
+            shapes: vec![Shape {
+                names: vec![],
+                slots: Map::new(),
+                transitions: Map::new(),
+            }],
+        }
+    }
+
+    #[inline]
+    pub fn slot(&self, shape: ShapeId, name: Symbol) -> Option<usize> {
+        self.shapes[shape].slots.get(&name).copied()
+    }
+
+    pub fn names(&self, shape: ShapeId) -> &[Symbol] {
+        &self.shapes[shape].names
+    }
+
+    // The shape with name added after the fields of shape, created the first
+    // time any instance takes that step.
+    pub fn transition(&mut self, shape: ShapeId, name: Symbol) -> ShapeId {
+        if let Some(next) = self.shapes[shape].transitions.get(&name) {
+            return *next;
+        }
+
+        let mut names = self.shapes[shape].names.clone();
+        let mut slots = self.shapes[shape].slots.clone();
+        slots.insert(name, names.len());
+        names.push(name);
+
+        let next = self.shapes.len();
+        self.shapes.push(Shape {
+            names,
+            slots,
+            transitions: Map::new(),
+        });
+        self.shapes[shape].transitions.insert(name, next);
+        next
+    }
+
+    pub fn len(&self) -> usize {
+        self.shapes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+}
+
+impl Default for Shapes {
+    fn default() -> Shapes {
+        Shapes::new()
+    }
+}
+
+#[cfg(test)]
+mod shape_tests {
+    use super::*;
+
+    const X: Symbol = 1;
+    const Y: Symbol = 2;
+
+    #[test]
+    fn shares_shapes_between_same_layouts() {
+        let mut shapes = Shapes::new();
+        let x = shapes.transition(EMPTY_SHAPE, X);
+        let xy = shapes.transition(x, Y);
+        assert_eq!(shapes.transition(EMPTY_SHAPE, X), x);
+        assert_eq!(shapes.transition(x, Y), xy);
+        assert_eq!(shapes.len(), 3);
+
+        assert_eq!(shapes.slot(xy, X), Some(0));
+        assert_eq!(shapes.slot(xy, Y), Some(1));
+        assert_eq!(shapes.slot(x, Y), None);
+        assert_eq!(shapes.names(xy), &[X, Y]);
+    }
+
+    #[test]
+    fn orders_slots_by_first_assignment() {
+        let mut shapes = Shapes::new();
+        let y = shapes.transition(EMPTY_SHAPE, Y);
+        let yx = shapes.transition(y, X);
+        let x = shapes.transition(EMPTY_SHAPE, X);
+        let xy = shapes.transition(x, Y);
+        assert_ne!(yx, xy);
+        assert_eq!(shapes.slot(yx, X), Some(1));
+        assert_eq!(shapes.slot(xy, X), Some(0));
+    }
+}
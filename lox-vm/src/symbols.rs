@@ -0,0 +1,98 @@
+use super::collections::Map;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+// Property and method names interned as small integers. The compiler puts a
+// name's symbol in the instructions that read, write or define a property next
+// to the name's constant, and shapes and class method maps are keyed by symbol,
+// so the VM never hashes or copies a name to find a field or method. The name
+// is only looked at again to report an error. Symbols are handed out in the
+// order names are first seen and live as long as the heap.
+
+pub type Symbol = usize;
+
+//Reserved so construction doesn't need a lookup
+pub const INIT: Symbol = 0;
+
+pub struct Symbols {
+    ids: Map<String, Symbol>,
+    names: Vec<String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        let mut symbols = Symbols {
+            ids: Map::new(),
+            names: vec![],
+        };
+        symbols.intern("init");
+        symbols
+    }
+
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(symbol) = self.ids.get(name) {
+            return *symbol;
+        }
+        let symbol = self.names.len();
+        self.ids.insert(String::from(name), symbol);
+        self.names.push(String::from(name));
+        symbol
+    }
+
+    //None for names no program has used, which nothing can have as a property
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, symbol: Symbol) -> &str {
+        &self.names[symbol]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl Default for Symbols {
+    fn default() -> Symbols {
+        Symbols::new()
+    }
+}
+
+#[cfg(test)]
+mod symbols_tests {
+    use super::*;
+    use crate::interpreter::VM;
+    use crate::test_support::{global, run};
+
+    #[test]
+    fn interns_names_once() {
+        let mut symbols = Symbols::new();
+        assert_eq!(symbols.get("init"), Some(INIT));
+        let area = symbols.intern("area");
+        assert_eq!(symbols.intern("area"), area);
+        assert_ne!(symbols.intern("perimeter"), area);
+        assert_eq!(symbols.name(area), "area");
+        assert_eq!(symbols.get("missing"), None);
+        assert_eq!(symbols.len(), 3);
+    }
+
+    #[test]
+    fn fields_and_methods_share_symbols() {
+        let source = "class P { init(x) { this.x = x; } x2() { return this.x * 2; } }\n\
+                      var p = P(4);\n\
+                      p.y = p.x2();\n\
+                      var total = p.x + p.y;";
+        let mut vm = VM::new();
+        run(&mut vm, source).unwrap();
+
+        assert_eq!(global(&vm, "total"), "12");
+        //init, x, x2 and y, however often each was used
+        assert_eq!(vm.symbols().len(), 4);
+        assert_eq!(vm.symbols().get("y"), Some(3));
+    }
+}
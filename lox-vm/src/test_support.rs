@@ -0,0 +1,24 @@
+// What the unit tests share for compiling and running Lox source on a VM.
+use super::compiler::Compiler;
+use super::interpreter::{InterpreterError, VM};
+use super::scanner::scan_tokens;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
+
+//Compiles source against vm's heap and runs it, panicking if it doesn't compile
+pub(crate) fn interpret(vm: &mut VM, source: &str) -> Result<(), InterpreterError> {
+    let tokens = scan_tokens(&String::from(source)).unwrap();
+    let mut compiler = Compiler::new(tokens, vm.take_virtual_memory());
+    let main = compiler.compile().map_err(|_| "compile error").unwrap();
+    vm.interpret(main, compiler.heap)
+}
+
+pub(crate) fn run(vm: &mut VM, source: &str) -> Result<(), String> {
+    interpret(vm, source).map_err(|e| e.to_string())
+}
+
+//The global called name, as print would show it
+pub(crate) fn global(vm: &VM, name: &str) -> String {
+    let (_, value) = vm.globals().into_iter().find(|(n, _)| n.as_str() == name).unwrap();
+    vm.inspect(value)
+}
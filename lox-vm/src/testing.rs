@@ -0,0 +1,182 @@
+use super::compiler::Compiler;
+use super::config::VmOptions;
+use super::interpreter::VM;
+use super::natives;
+use super::scanner::scan_tokens;
+use super::value::{Object, Value};
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Runs the tests in *_test.lox files. A test is a global function whose name
+// starts with "test". Each one gets a fresh VM that runs the file's top level
+// and then calls it, so tests can't leak globals into each other.
+
+pub const TEST_SUFFIX: &str = "_test.lox";
+const TEST_PREFIX: &str = "test";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    //The runtime error when the test failed
+    pub outcome: Result<(), String>,
+    //What the test printed
+    pub output: String,
+}
+
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Test files under each path in a stable order. Files named directly are
+// taken whatever they're called.
+pub fn discover(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut files = vec![];
+    for path in paths {
+        if path.is_dir() {
+            collect(path, &mut files)?;
+        } else if path.is_file() {
+            files.push(path.clone());
+        } else {
+            return Err(format!("{}: no such file or directory", path.display()));
+        }
+    }
+    Ok(files)
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            collect(&path, files)?;
+        } else if path.to_string_lossy().ends_with(TEST_SUFFIX) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Errors if the file can't be read, doesn't compile or fails at the top level,
+// since then none of its tests can run.
+pub fn run_file(path: &Path, options: &VmOptions) -> Result<Vec<TestResult>, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let (vm, _, result) = execute(path, &source, options);
+    result.map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    Ok(test_names(&vm)
+        .into_iter()
+        .map(|name| {
+            let source = format!("{}\n{}();\n", source, name);
+            let (_, output, outcome) = execute(path, &source, options);
+            TestResult {
+                name,
+                outcome,
+                output,
+            }
+        })
+        .collect())
+}
+
+fn test_names(vm: &VM) -> Vec<String> {
+    vm.globals()
+        .into_iter()
+        .filter(|(name, value)| match value {
+            Value::Object(ptr) => {
+                name.starts_with(TEST_PREFIX)
+                    && matches!(vm.heap_object(*ptr), Some(Object::Closure(_)))
+            }
+            _ => false,
+        })
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+//Runs source in a fresh VM, returning the VM along with what it printed
+fn execute(path: &Path, source: &str, options: &VmOptions) -> (VM, String, Result<(), String>) {
+    let capture = Rc::new(RefCell::new(vec![]));
+    let mut vm = VM::new();
+    vm.configure(options);
+    vm.set_script_path(&path.to_string_lossy());
+    vm.define_natives(&natives::TEST);
+    vm.set_output(Box::new(Capture(capture.clone())));
+
+    let result = match scan_tokens(&String::from(source)) {
+        Ok(tokens) => {
+            let mut compiler = Compiler::new(tokens, vm.take_virtual_memory());
+            match compiler.compile() {
+                Ok(main) => vm.interpret(main, compiler.heap).map_err(|e| e.to_string()),
+                Err(errors) => {
+                    vm.give_virtual_memory(compiler.heap);
+                    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                    Err(format!("Compiler error: {}", errors.join("\n")))
+                }
+            }
+        }
+        Err(e) => Err(format!("Scanner error: {}", e)),
+    };
+
+    let output = String::from_utf8_lossy(&capture.borrow()).into_owned();
+    (vm, output, result)
+}
+
+#[cfg(test)]
+mod testing_tests {
+    use super::*;
+
+    fn write_test(name: &str, source: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lox-testing-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    #[test]
+    fn runs_each_test_in_a_fresh_vm() {
+        let source = "var count = 0;\n\
+                      fun testFirst() { count = count + 1; assertEq(count, 1); }\n\
+                      fun testSecond() { count = count + 1; assertEq(count, 1); }\n\
+                      fun testFails() { print \"before\"; assertTrue(false); }\n\
+                      fun helper() { fail(\"not a test\"); }\n";
+        let path = write_test("fresh_test.lox", source);
+        let results = run_file(&path, &VmOptions::default()).unwrap();
+
+        let names: Vec<&str> = results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["testFails", "testFirst", "testSecond"]);
+        assert_eq!(
+            results[0].outcome,
+            Err(String::from("4: Assertion failed: expected a true value but got false"))
+        );
+        assert_eq!(results[0].output, "before\n");
+        assert_eq!(results[1].outcome, Ok(()));
+        assert_eq!(results[2].outcome, Ok(()));
+    }
+
+    #[test]
+    fn reports_files_that_dont_compile() {
+        let path = write_test("broken_test.lox", "fun testBroken( {}");
+        assert!(run_file(&path, &VmOptions::default()).is_err());
+    }
+
+    #[test]
+    fn discovers_test_files() {
+        let path = write_test("found_test.lox", "");
+        write_test("helper.lox", "");
+        let files = discover(&[path.parent().unwrap().to_path_buf()]).unwrap();
+        assert!(files.contains(&path));
+        assert!(files.iter().all(|f| f.to_string_lossy().ends_with(TEST_SUFFIX)));
+    }
+}
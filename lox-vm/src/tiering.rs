@@ -0,0 +1,147 @@
+use super::collections::Map;
+use super::interpreter::VirtualMemory;
+#[cfg(feature = "jit")]
+use super::jit::Jit;
+use super::passes::PassManager;
+#[cfg(all(feature = "jit", feature = "tracing"))]
+use tracing::debug;
+
+// Counts how often each function is entered or loops back, and once one gets
+// hot runs the optimizer over it again. Scripts can start at a low opt level
+// and only pay for optimizing what they actually spend their time in.
+//
+// Frames inside the function keep running once its chunk is swapped back in,
+// so only passes that leave every instruction at its offset are used: jump
+// threading and superinstructions. With the jit feature hot functions get
+// native code as well.
+pub struct Tiering {
+    //Calls plus loop iterations before a function is optimized
+    pub threshold: u64,
+    counts: Map<u64, u64>, //By function pointer
+    pub passes: PassManager,
+    pub tier_ups: u64,
+    //None if the host has no code generator, or to leave everything interpreted
+    #[cfg(feature = "jit")]
+    pub jit: Option<Jit>,
+}
+
+impl Tiering {
+    pub fn new(threshold: u64) -> Tiering {
+        Tiering {
+            threshold,
+            counts: Map::new(),
+            passes: PassManager::tiered(),
+            tier_ups: 0,
+            #[cfg(feature = "jit")]
+            jit: Jit::new().ok(),
+        }
+    }
+
+    //Whether this made the function hot, which only happens once per function
+    #[inline]
+    pub fn heat(&mut self, function_ptr: u64) -> bool {
+        let count = self.counts.entry(function_ptr).or_insert(0);
+        if *count >= self.threshold {
+            return false;
+        }
+        *count += 1;
+        *count == self.threshold
+    }
+
+    // Re-optimizes a hot function in place, returning whether its code changed.
+    pub fn tier_up(&mut self, function_ptr: u64, heap: &mut VirtualMemory) -> bool {
+        self.tier_ups += 1;
+        let changed = self.passes.run_function(function_ptr, heap);
+        #[cfg(feature = "jit")]
+        {
+            let compiled = self.jit.as_mut().map(|jit| jit.compile(function_ptr, heap));
+            if let Some(Err(_reason)) = compiled {
+                debug!(reason = %_reason, "left interpreted");
+            }
+        }
+        changed
+    }
+
+    //The GC may reuse a swept function's address
+    pub fn forget(&mut self) {
+        self.counts.clear();
+    }
+}
+
+#[cfg(test)]
+mod tiering_tests {
+    use super::*;
+    use crate::chunk::OpCode;
+    use crate::interpreter::VM;
+    use crate::test_support::{global, run};
+    use crate::value::Value;
+    #[cfg(not(feature = "std"))]
+    use crate::prelude::*;
+
+    const HOT_LOOP: &str = "fun pick(a, b) { var r = 0; if (a) { if (b) r = 1; } else r = 2; \
+                            return r; }\n\
+                            var x = 0;\n\
+                            for (var i = 0; i < 50; i = i + 1) { x = x + pick(i < 25, true); }";
+
+    #[test]
+    fn heats_up_once() {
+        let mut tiering = Tiering::new(3);
+        assert!(!tiering.heat(1));
+        assert!(!tiering.heat(1));
+        assert!(tiering.heat(1));
+        assert!(!tiering.heat(1));
+        assert!(!tiering.heat(2));
+    }
+
+    #[test]
+    fn optimizes_hot_functions_while_they_run() {
+        let mut plain = VM::new();
+        run(&mut plain, HOT_LOOP).unwrap();
+
+        let mut vm = VM::new();
+        vm.enable_tiering(10);
+        vm.tiering_mut().unwrap().passes.dump = true;
+        run(&mut vm, HOT_LOOP).unwrap();
+        assert_eq!(global(&vm, "x"), global(&plain, "x"));
+
+        //The script loops enough to get hot too
+        let tiering = vm.tiering_mut().unwrap();
+        assert_eq!(tiering.tier_ups, 2);
+        let dumps = tiering.passes.take_dumps();
+        assert_eq!(dumps.len(), 1);
+        assert!(dumps[0].starts_with("== jump-threading on pick =="));
+    }
+
+    #[test]
+    fn fuses_instructions_in_hot_functions() {
+        let source = "fun sum(n) { var t = 0;\n\
+                      for (var i = 0; i < n; i = i + 1) t = t + i;\n\
+                      return t; }\n\
+                      var x = 0;\n\
+                      for (var i = 0; i < 30; i = i + 1) x = x + sum(i);";
+        let code = |vm: &VM| {
+            let globals = vm.globals();
+            let (_, sum) = globals.iter().find(|(name, _)| name.as_str() == "sum").unwrap();
+            let heap = vm.virtual_memory();
+            let fp = match *sum {
+                Value::Object(ptr) => heap.deref(ptr).as_closure().function_pointer,
+                _ => panic!("sum isn't a function"),
+            };
+            heap.deref(fp).as_function().chunk.code.clone()
+        };
+        let fused = |code: &[OpCode]| code.iter().any(|op| matches!(op, OpCode::AddLocal(_)));
+
+        let mut plain = VM::new();
+        run(&mut plain, source).unwrap();
+        assert!(!fused(&code(&plain)));
+
+        let mut vm = VM::new();
+        vm.enable_tiering(10);
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&vm, "x"), global(&plain, "x"));
+        let tiered = code(&vm);
+        assert!(fused(&tiered));
+        //Every instruction is where it was
+        assert_eq!(tiered.len(), code(&plain).len());
+    }
+}
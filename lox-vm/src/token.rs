@@ -1,4 +1,6 @@
 use num_enum::TryFromPrimitive;
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
 
 #[derive(Debug, PartialEq, Clone, Copy, TryFromPrimitive)]
 #[repr(usize)]
@@ -7,43 +9,75 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
+    DotDotDot, //Marks a rest parameter
     Minus,
+    MinusEqual,
     Plus,
+    PlusEqual,
     Semicolon,
+    Colon, //In type annotations, switch cases and conditionals
+    Question,
     Slash,
+    SlashEqual,
     Star,
+    StarEqual,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
 
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
+    Arrow, //=> between a match pattern and its result
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
 
     Identifier,
     StringToken,
+    Interpolation, //The part of a string before a ${, with the expression's tokens after it
     NumberToken,
+    IntegerToken,
 
     And,
+    Break,
+    Case,
+    Catch,
     Class,
+    Continue,
+    Default,
+    Do,
     Else,
     False,
+    Finally,
     Fun,
     For,
     If,
+    Import,
+    Match,
     Nil,
     Or,
     Print,
     Return,
+    Static,
     Super,
+    Switch,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
+    Yield,
     //Make sure EOF is always the final enum.
     EOF,
 }
@@ -53,5 +87,36 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub line: usize,
+    pub column: usize, //1-based, 0 for synthesized tokens
     pub literal: Option<String>,
 }
+
+//Source location of a token, used for error reporting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+impl Span {
+    //A span for when only the line is known
+    pub fn line(line: usize) -> Span {
+        Span {
+            line,
+            column: 0,
+            length: 0,
+        }
+    }
+}
+
+impl Token {
+    pub fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            length: self.lexeme.chars().count(),
+        }
+    }
+}
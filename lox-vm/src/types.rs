@@ -0,0 +1,811 @@
+use super::ast::*;
+use super::diagnostic::{Diagnostic, Severity};
+use super::resolver::{self, Resolution};
+use super::token::Span;
+use std::collections::HashMap;
+use std::fmt;
+
+// Checks optional type annotations, as in
+//
+//   fun area(w: Number, h: Number): Number { return w * h; }
+//   var name: String = "box";
+//
+// The compiler skips annotations, so they cost nothing at runtime and annotated
+// code runs on the same VM. Types come from annotations, literals, operators and
+// the signatures of annotated functions. An unannotated variable takes the type
+// of its initializer if it's never assigned again and is unchecked otherwise,
+// so only code that would fail at runtime is reported.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Any, //Unannotated or not worked out
+    Nil,
+    Bool,
+    Number,
+    String,
+    Function,
+    Class(String), //The class itself, rather than one of its instances
+    Instance(String),
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Any => write!(f, "Any"),
+            Type::Nil => write!(f, "Nil"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Number => write!(f, "Number"),
+            Type::String => write!(f, "String"),
+            Type::Function => write!(f, "Function"),
+            Type::Class(name) => write!(f, "class {}", name),
+            Type::Instance(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Signature {
+    name: String,
+    params: Vec<(String, Type)>,
+    returns: Type,
+}
+
+#[derive(Debug, Clone)]
+struct Binding {
+    ty: Type,
+    annotated: bool,
+    //For functions, and the initializer of classes
+    signature: Option<Signature>,
+}
+
+struct Checker<'a> {
+    file: Option<&'a str>,
+    resolution: Resolution,
+    //Superclasses of every named class, which also makes them type names
+    classes: HashMap<String, Vec<String>>,
+    methods: HashMap<(String, String), Signature>,
+    scopes: Vec<HashMap<String, Binding>>,
+    returns: Vec<Option<Type>>,
+    this: Vec<Type>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// Reports mismatches between annotations and the values that reach them, and
+// annotations naming types that don't exist.
+pub fn check(statements: &[Stmt], file: Option<&str>) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        file,
+        resolution: resolver::resolve(statements),
+        classes: HashMap::new(),
+        methods: HashMap::new(),
+        scopes: vec![HashMap::new()],
+        returns: vec![],
+        this: vec![],
+        diagnostics: vec![],
+    };
+    checker.collect_classes(statements);
+    for stmt in statements.iter() {
+        checker.statement(stmt);
+    }
+
+    checker
+        .diagnostics
+        .sort_by_key(|d| (d.span.line, d.span.column));
+    checker.diagnostics
+}
+
+impl<'a> Checker<'a> {
+    fn report(&mut self, code: &'static str, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            code,
+            message,
+            file: self.file.map(String::from),
+            span,
+        });
+    }
+
+    //Classes can be named in annotations before they're declared
+    fn collect_classes(&mut self, statements: &[Stmt]) {
+        for stmt in statements.iter() {
+            match stmt {
+                Stmt::Class(class) => {
+                    let superclasses = class.superclasses.iter().map(|s| s.name.clone());
+                    self.classes
+                        .insert(class.name.name.clone(), superclasses.collect());
+                    for method in class.all_methods() {
+                        self.collect_classes(&method.body);
+                    }
+                }
+                Stmt::Function(function) => self.collect_classes(&function.body),
+                Stmt::Block(body, _) => self.collect_classes(body),
+                Stmt::If(_, then, otherwise) => {
+                    self.collect_classes(core::slice::from_ref(then.as_ref()));
+                    if let Some(otherwise) = otherwise {
+                        self.collect_classes(core::slice::from_ref(otherwise.as_ref()));
+                    }
+                }
+                Stmt::While(_, body)
+                | Stmt::DoWhile(body, _)
+                | Stmt::For(_, _, _, body)
+                | Stmt::Labeled(_, body) => {
+                    self.collect_classes(core::slice::from_ref(body.as_ref()))
+                }
+                Stmt::Switch(_, cases, default) => {
+                    for (_, body) in cases.iter() {
+                        self.collect_classes(body);
+                    }
+                    if let Some(default) = default {
+                        self.collect_classes(default);
+                    }
+                }
+                Stmt::Try(body, catch, finally) => {
+                    self.collect_classes(body);
+                    if let Some((_, body)) = catch {
+                        self.collect_classes(body);
+                    }
+                    if let Some(finally) = finally {
+                        self.collect_classes(finally);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn annotation(&mut self, name: &Option<Identifier>) -> Option<Type> {
+        let name = name.as_ref()?;
+        Some(match name.name.as_str() {
+            "Any" => Type::Any,
+            "Nil" => Type::Nil,
+            "Bool" => Type::Bool,
+            "Number" => Type::Number,
+            "String" => Type::String,
+            "Function" => Type::Function,
+            class if self.classes.contains_key(class) => Type::Instance(String::from(class)),
+            unknown => {
+                self.report(
+                    "unknown-type",
+                    name.span,
+                    format!("Unknown type '{}'.", unknown),
+                );
+                Type::Any
+            }
+        })
+    }
+
+    fn inherits(&self, class: &str, ancestor: &str) -> bool {
+        let mut pending = vec![class];
+        let mut seen = vec![];
+        while let Some(class) = pending.pop() {
+            if class == ancestor {
+                return true;
+            }
+            if !seen.contains(&class) {
+                seen.push(class);
+                if let Some(superclasses) = self.classes.get(class) {
+                    pending.extend(superclasses.iter().map(|s| s.as_str()));
+                }
+            }
+        }
+        false
+    }
+
+    //nil stands in for a missing instance, like a null reference
+    fn assignable(&self, expected: &Type, actual: &Type) -> bool {
+        match (expected, actual) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Instance(_), Type::Nil) => true,
+            (Type::Instance(expected), Type::Instance(actual)) => self.inherits(actual, expected),
+            _ => expected == actual,
+        }
+    }
+
+    fn expect(&mut self, expected: &Type, actual: &Type, span: Span, what: &str) {
+        if !self.assignable(expected, actual) {
+            let message = format!("Expected {} but found {} {}.", expected, actual, what);
+            self.report("type-mismatch", span, message);
+        }
+    }
+
+    fn declare(&mut self, name: &str, binding: Binding) {
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(String::from(name), binding);
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn reassigned(&self, name: &Identifier) -> bool {
+        self.resolution
+            .declarations
+            .iter()
+            .filter(|d| d.span == name.span)
+            .all(|d| d.assigned)
+    }
+
+    fn signature(&mut self, function: &FunctionDecl) -> Signature {
+        let mut params = vec![];
+        for (param, annotation) in function.params.iter().zip(function.param_types.iter()) {
+            let ty = self.annotation(annotation).unwrap_or(Type::Any);
+            params.push((param.name.clone(), ty));
+        }
+        //A rest parameter is always a list, whatever it was annotated with
+        if function.variadic {
+            if let Some((_, ty)) = params.last_mut() {
+                *ty = Type::Any;
+            }
+        }
+        //Calling a generator gives back the generator, whatever its return type
+        let returns = if function.is_generator() {
+            Type::Any
+        } else {
+            self.annotation(&function.return_type).unwrap_or(Type::Any)
+        };
+        Signature {
+            name: function.name.name.clone(),
+            params,
+            returns,
+        }
+    }
+
+    //A new scope, starting with the binding if there is one
+    fn block(&mut self, statements: &[Stmt], binding: Option<(&Identifier, Binding)>) {
+        self.scopes.push(HashMap::new());
+        if let Some((name, binding)) = binding {
+            self.declare(&name.name, binding);
+        }
+        for stmt in statements.iter() {
+            self.statement(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Print(expr) => {
+                self.expression(expr);
+            }
+            Stmt::Var(name, annotation, initializer) => {
+                let value = initializer.as_ref().map(|e| (self.expression(e), e.span));
+                let binding = match self.annotation(annotation) {
+                    Some(ty) => {
+                        if let Some((value, span)) = value {
+                            let what = format!("for '{}'", name.name);
+                            self.expect(&ty, &value, span, &what);
+                        }
+                        Binding {
+                            ty,
+                            annotated: true,
+                            signature: None,
+                        }
+                    }
+                    None => Binding {
+                        ty: match value {
+                            Some((value, _)) if !self.reassigned(name) => value,
+                            _ => Type::Any,
+                        },
+                        annotated: false,
+                        signature: None,
+                    },
+                };
+                self.declare(&name.name, binding);
+            }
+            //What's in a tuple isn't tracked, so only annotations say anything
+            Stmt::VarUnpack(_, names, initializer) => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                for (name, annotation) in names.iter() {
+                    let annotated = self.annotation(annotation);
+                    let binding = Binding {
+                        annotated: annotated.is_some(),
+                        ty: annotated.unwrap_or(Type::Any),
+                        signature: None,
+                    };
+                    self.declare(&name.name, binding);
+                }
+            }
+            Stmt::Block(statements, _) => self.block(statements, None),
+            Stmt::If(condition, then, otherwise) => {
+                self.expression(condition);
+                self.statement(then);
+                if let Some(otherwise) = otherwise {
+                    self.statement(otherwise);
+                }
+            }
+            Stmt::While(condition, body) => {
+                self.expression(condition);
+                self.statement(body);
+            }
+            Stmt::DoWhile(body, condition) => {
+                self.statement(body);
+                self.expression(condition);
+            }
+            Stmt::For(initializer, condition, increment, body) => {
+                self.scopes.push(HashMap::new());
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                if let Some(condition) = condition {
+                    self.expression(condition);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment);
+                }
+                self.statement(body);
+                self.scopes.pop();
+            }
+            Stmt::Switch(value, cases, default) => {
+                self.expression(value);
+                for (case, body) in cases.iter() {
+                    self.expression(case);
+                    self.scopes.push(HashMap::new());
+                    for stmt in body.iter() {
+                        self.statement(stmt);
+                    }
+                    self.scopes.pop();
+                }
+                if let Some(default) = default {
+                    self.scopes.push(HashMap::new());
+                    for stmt in default.iter() {
+                        self.statement(stmt);
+                    }
+                    self.scopes.pop();
+                }
+            }
+            Stmt::Function(function) => {
+                let signature = self.signature(function);
+                let binding = self.callable(&function.name, Type::Function, Some(&signature));
+                self.declare(&function.name.name, binding);
+                self.function(function, signature);
+            }
+            Stmt::Return(span, value) => {
+                let value = match value {
+                    Some(value) => (self.expression(value), value.span),
+                    None => (Type::Nil, *span),
+                };
+                if let Some(Some(expected)) = self.returns.last().cloned() {
+                    self.expect(&expected, &value.0, value.1, "returned");
+                }
+            }
+            Stmt::Yield(_, value) => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Try(body, catch, finally) => {
+                self.block(body, None);
+                if let Some((name, body)) = catch {
+                    //Runtime errors are caught as strings, but anything can be thrown
+                    let error = Binding {
+                        ty: Type::Any,
+                        annotated: false,
+                        signature: None,
+                    };
+                    self.block(body, Some((name, error)));
+                }
+                if let Some(finally) = finally {
+                    self.block(finally, None);
+                }
+            }
+            Stmt::Throw(_, value) => {
+                self.expression(value);
+            }
+            //Modules aren't checked, so nothing is known about what they export
+            Stmt::Import(name, _) => {
+                let binding = Binding {
+                    ty: Type::Any,
+                    annotated: false,
+                    signature: None,
+                };
+                self.declare(&name.name, binding);
+            }
+            Stmt::Labeled(_, body) => self.statement(body),
+            Stmt::Break(..) | Stmt::Continue(..) => {}
+            Stmt::Class(class) => {
+                self.method_signatures(class);
+                let key = (class.name.name.clone(), String::from("init"));
+                let init = self.methods.get(&key).cloned();
+                let ty = Type::Class(class.name.name.clone());
+                let binding = self.callable(&class.name, ty, init.as_ref());
+                self.declare(&class.name.name, binding);
+                self.method_bodies(class);
+            }
+        }
+    }
+
+    //Functions and classes lose their signature if the name is reused
+    fn callable(&self, name: &Identifier, ty: Type, signature: Option<&Signature>) -> Binding {
+        if self.reassigned(name) {
+            return Binding {
+                ty: Type::Any,
+                annotated: false,
+                signature: None,
+            };
+        }
+        Binding {
+            ty,
+            annotated: false,
+            signature: signature.cloned(),
+        }
+    }
+
+    fn function(&mut self, function: &FunctionDecl, signature: Signature) {
+        self.scopes.push(HashMap::new());
+        for (name, ty) in signature.params.iter() {
+            let binding = Binding {
+                ty: ty.clone(),
+                annotated: true,
+                signature: None,
+            };
+            self.declare(name, binding);
+        }
+        //Unannotated functions may return anything
+        let returns = function.return_type.as_ref().map(|_| signature.returns);
+        self.returns.push(returns);
+        for stmt in function.body.iter() {
+            self.statement(stmt);
+        }
+        self.returns.pop();
+        self.scopes.pop();
+    }
+
+    fn method_signatures(&mut self, class: &ClassDecl) {
+        for method in class.methods.iter() {
+            let mut signature = self.signature(method);
+            if method.name.name == "init" {
+                //Reported as calls to the class
+                signature.name = class.name.name.clone();
+            }
+            let key = (class.name.name.clone(), method.name.name.clone());
+            self.methods.insert(key, signature);
+        }
+    }
+
+    fn method_bodies(&mut self, class: &ClassDecl) {
+        self.this.push(Type::Instance(class.name.name.clone()));
+        for method in class.methods.iter() {
+            let key = (class.name.name.clone(), method.name.name.clone());
+            let signature = self.methods[&key].clone();
+            self.function(method, signature);
+        }
+        for accessor in class.getters.iter().chain(class.setters.iter()) {
+            let signature = self.signature(accessor);
+            self.function(accessor, signature);
+        }
+        self.this.pop();
+
+        //'this' is the class itself in a static method
+        self.this.push(Type::Class(class.name.name.clone()));
+        for method in class.statics.iter() {
+            let signature = self.signature(method);
+            self.function(method, signature);
+        }
+        self.this.pop();
+    }
+
+    //A method of class or the first of its superclasses to have one
+    fn method(&self, class: &str, name: &str) -> Option<Signature> {
+        let key = (String::from(class), String::from(name));
+        if let Some(signature) = self.methods.get(&key) {
+            return Some(signature.clone());
+        }
+        let superclasses = self.classes.get(class)?;
+        superclasses.iter().find_map(|s| self.method(s, name))
+    }
+
+    fn call(&mut self, signature: &Signature, arguments: &[Expr]) {
+        for (argument, (param, expected)) in arguments.iter().zip(signature.params.iter()) {
+            let actual = self.expression(argument);
+            let what = format!("for '{}' of '{}'", param, signature.name);
+            self.expect(expected, &actual, argument.span, &what);
+        }
+        for argument in arguments.iter().skip(signature.params.len()) {
+            self.expression(argument);
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) -> Type {
+        match &expr.kind {
+            ExprKind::Literal(literal) => match literal {
+                Literal::Number(_) | Literal::Integer(_) => Type::Number,
+                Literal::String(_) => Type::String,
+                Literal::Boolean(_) => Type::Bool,
+                Literal::Nil => Type::Nil,
+            },
+            ExprKind::Variable(name) => self.lookup(&name.name).map_or(Type::Any, |b| b.ty.clone()),
+            ExprKind::Assign(name, value) => {
+                let actual = self.expression(value);
+                if let Some(binding) = self.lookup(&name.name).filter(|b| b.annotated) {
+                    let expected = binding.ty.clone();
+                    let what = format!("for '{}'", name.name);
+                    self.expect(&expected, &actual, value.span, &what);
+                }
+                actual
+            }
+            ExprKind::Unary(op @ (UnaryOp::Negate | UnaryOp::BitNot), right) => {
+                let operand = self.expression(right);
+                let what = if *op == UnaryOp::Negate { "for '-'" } else { "for '~'" };
+                self.expect(&Type::Number, &operand, right.span, what);
+                Type::Number
+            }
+            ExprKind::Unary(UnaryOp::Not, right) => {
+                self.expression(right);
+                Type::Bool
+            }
+            ExprKind::Binary(left, op, right) => {
+                let (left_type, right_type) = (self.expression(left), self.expression(right));
+                self.binary(*op, (left, left_type), (right, right_type))
+            }
+            ExprKind::Logical(left, _, right) => {
+                let (left, right) = (self.expression(left), self.expression(right));
+                if left == right {
+                    left
+                } else {
+                    Type::Any
+                }
+            }
+            ExprKind::Conditional(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                let (then_type, else_type) =
+                    (self.expression(then_branch), self.expression(else_branch));
+                if then_type == else_type {
+                    then_type
+                } else {
+                    Type::Any
+                }
+            }
+            ExprKind::Interpolation(parts) => {
+                for part in parts.iter() {
+                    self.expression(part);
+                }
+                Type::String
+            }
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Call(callee, arguments) => self.call_expression(callee, arguments),
+            ExprKind::Get(object, _) => {
+                self.expression(object);
+                Type::Any
+            }
+            ExprKind::Set(object, _, value) => {
+                self.expression(object);
+                self.expression(value)
+            }
+            ExprKind::List(items) | ExprKind::Tuple(items) => {
+                for item in items.iter() {
+                    self.expression(item);
+                }
+                Type::Any
+            }
+            ExprKind::Index(list, index) => {
+                self.expression(list);
+                self.expression(index);
+                Type::Any
+            }
+            ExprKind::IndexSet(list, index, value) => {
+                self.expression(list);
+                self.expression(index);
+                self.expression(value)
+            }
+            ExprKind::Compound(target, op, value) => {
+                let (target_type, value_type) = (self.expression(target), self.expression(value));
+                let result = self.binary(*op, (target, target_type), (value, value_type));
+                if let ExprKind::Variable(name) = &target.kind {
+                    if let Some(binding) = self.lookup(&name.name).filter(|b| b.annotated) {
+                        let expected = binding.ty.clone();
+                        let what = format!("for '{}'", name.name);
+                        self.expect(&expected, &result, value.span, &what);
+                    }
+                }
+                result
+            }
+            ExprKind::This => self.this.last().cloned().unwrap_or(Type::Any),
+            ExprKind::Super(_) => Type::Any,
+            ExprKind::Class(class) => {
+                self.method_signatures(class);
+                self.method_bodies(class);
+                Type::Any
+            }
+            ExprKind::Match(value, arms) => {
+                let value_type = self.expression(value);
+                let mut result_types = vec![];
+                for (pattern, result) in arms.iter() {
+                    self.scopes.push(HashMap::new());
+                    self.pattern(pattern, &value_type);
+                    result_types.push(self.expression(result));
+                    self.scopes.pop();
+                }
+                match result_types.split_first() {
+                    Some((first, rest)) if rest.iter().all(|ty| ty == first) => first.clone(),
+                    _ => Type::Any,
+                }
+            }
+        }
+    }
+
+    //Declares what a match arm's pattern binds
+    fn pattern(&mut self, pattern: &Pattern, value_type: &Type) {
+        match pattern {
+            Pattern::Literal(literal) => {
+                self.expression(literal);
+            }
+            Pattern::Binding(name) => {
+                let ty = if self.reassigned(name) {
+                    Type::Any
+                } else {
+                    value_type.clone()
+                };
+                let binding = Binding {
+                    ty,
+                    annotated: false,
+                    signature: None,
+                };
+                self.declare(&name.name, binding);
+            }
+            Pattern::Type(name, type_name) => {
+                //Patterns can check for types that annotations have no name for
+                let ty = match type_name.name.as_str() {
+                    "List" | "Tuple" | "Class" | "Module" | "Generator" => Type::Any,
+                    _ => self.annotation(&Some(type_name.clone())).unwrap_or(Type::Any),
+                };
+                if let Some(name) = name {
+                    let binding = Binding {
+                        ty,
+                        annotated: true,
+                        signature: None,
+                    };
+                    self.declare(&name.name, binding);
+                }
+            }
+            Pattern::Wildcard => {}
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp, left: (&Expr, Type), right: (&Expr, Type)) -> Type {
+        let symbol = match op {
+            BinaryOp::Equal | BinaryOp::NotEqual => return Type::Bool,
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitOr => "|",
+            BinaryOp::BitXor => "^",
+            BinaryOp::ShiftLeft => "<<",
+            BinaryOp::ShiftRight => ">>",
+        };
+
+        if op == BinaryOp::Add {
+            return match (&left.1, &right.1) {
+                (Type::Number, Type::Number) => Type::Number,
+                (Type::String, Type::String) => Type::String,
+                (Type::Any, Type::Any) => Type::Any,
+                (Type::Any, other @ (Type::Number | Type::String))
+                | (other @ (Type::Number | Type::String), Type::Any) => other.clone(),
+                //Instances concatenate as what their toString returns
+                (Type::String, Type::Instance(class)) | (Type::Instance(class), Type::String)
+                    if self.method(class, "toString").is_some() =>
+                {
+                    Type::String
+                }
+                (left_type, right_type) => {
+                    let message = format!(
+                        "Expected two Numbers or two Strings but found {} and {} for '+'.",
+                        left_type, right_type
+                    );
+                    self.report("type-mismatch", left.0.span, message);
+                    Type::Any
+                }
+            };
+        }
+
+        let what = format!("for '{}'", symbol);
+        self.expect(&Type::Number, &left.1, left.0.span, &what);
+        self.expect(&Type::Number, &right.1, right.0.span, &what);
+        match op {
+            BinaryOp::Greater | BinaryOp::GreaterEqual | BinaryOp::Less | BinaryOp::LessEqual => {
+                Type::Bool
+            }
+            _ => Type::Number,
+        }
+    }
+
+    fn call_expression(&mut self, callee: &Expr, arguments: &[Expr]) -> Type {
+        let (callee_type, signature) = match &callee.kind {
+            ExprKind::Variable(name) => match self.lookup(&name.name) {
+                Some(binding) => (binding.ty.clone(), binding.signature.clone()),
+                None => (Type::Any, None),
+            },
+            ExprKind::Get(object, method) => match self.expression(object) {
+                Type::Instance(class) => (Type::Any, self.method(&class, &method.name)),
+                _ => (Type::Any, None),
+            },
+            _ => (self.expression(callee), None),
+        };
+
+        match &signature {
+            Some(signature) => self.call(signature, arguments),
+            None => {
+                for argument in arguments.iter() {
+                    self.expression(argument);
+                }
+            }
+        }
+
+        match (callee_type, signature) {
+            (Type::Class(class), _) => Type::Instance(class),
+            (_, Some(signature)) => signature.returns,
+            _ => Type::Any,
+        }
+    }
+}
+
+#[cfg(test)]
+mod types_tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::scanner::scan_tokens;
+
+    fn check_source(source: &str) -> Vec<(&'static str, usize, String)> {
+        let (statements, errors) = parse(scan_tokens(&String::from(source)).unwrap());
+        assert!(errors.is_empty());
+        check(&statements, None)
+            .into_iter()
+            .map(|d| (d.code, d.span.line, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn checks_annotations() {
+        let source = "fun add(a: Number, b: Number): Number { return a + b; }\n\
+                      var s: String = add(1, 2);\n\
+                      add(\"1\", 2);\n\
+                      fun name(): String { return nil; }\n\
+                      var n: Numbr = 1;";
+        let found = check_source(source);
+        assert_eq!(found.len(), 4);
+        assert_eq!(found[0].1, 2);
+        assert_eq!(found[0].2, "Expected String but found Number for 's'.");
+        assert_eq!(found[1].2, "Expected Number but found String for 'a' of 'add'.");
+        assert_eq!(found[2].2, "Expected String but found Nil returned.");
+        assert_eq!(found[3], ("unknown-type", 5, String::from("Unknown type 'Numbr'.")));
+    }
+
+    #[test]
+    fn accepts_subclasses_and_nil_for_instances() {
+        let source = "class A { init() {} }\nclass B < A { init(x: Number) {} }\n\
+                      var a: A = B(1);\nvar b: B = nil;\nvar c: B = A();";
+        let found = check_source(source);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].2, "Expected B but found A for 'c'.");
+    }
+
+    #[test]
+    fn infers_only_variables_that_keep_their_value() {
+        let source = "var fixed = \"a\";\nvar moving = \"a\";\nmoving = 1;\n\
+                      var x: Number = fixed;\nvar y: Number = moving;\nvar z = -fixed;";
+        let found = check_source(source);
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].1, found[1].1), (4, 6));
+    }
+
+    #[test]
+    fn concatenates_instances_with_to_string() {
+        let source = "class A { toString() { return \"a\"; } }
+class B < A {}
+class C {}
+                      var s: String = \"b: \" + B();
+var t = \"c: \" + C();";
+        let found = check_source(source);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1, 5);
+    }
+}
@@ -1,12 +1,20 @@
 use super::chunk::Chunk;
-use super::interpreter::InterpreterError;
-use std::collections::HashMap;
-use std::fmt;
-use std::fmt::{Display, Formatter};
+use super::collections::Map;
+use super::interpreter::{InterpreterError, VM};
+use super::shape::ShapeId;
+use super::symbols::Symbol;
+use super::vtable::VTABLE_SIZE;
+use core::fmt;
+use core::fmt::{Display, Formatter};
+#[cfg(not(feature = "std"))]
+use super::prelude::*;
 
+//The jit reads and writes values on the stack itself
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "jit", repr(C, u8))]
 pub enum Value {
     Number(f64),
+    Integer(i64), //Literals without a '.', and what arithmetic on them gives
     Boolean(bool),
     Object(u64),
     Nil,
@@ -16,6 +24,7 @@ impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Value::Number(n) => write!(f, "{} : Number", n),
+            Value::Integer(n) => write!(f, "{} : Integer", n),
             Value::Boolean(b) => write!(f, "{} : Boolean", b),
             Value::Nil => write!(f, "nil : Nil"),
             Value::Object(p) => write!(f, "{} : ObjectPtr", p),
@@ -23,18 +32,30 @@ impl Display for Value {
     }
 }
 
+//Natives get their arguments in order and report errors as a message, which
+//the VM turns into a runtime error on the calling line.
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+//The arity of natives that take any number of arguments and check them themselves
+pub const VARIADIC: usize = usize::MAX;
+
 //Consider changing to a struct
 #[derive(Clone)]
 pub enum Object {
     String(String),
     Function(Function),
-    NativeFunction(String, fn(Vec<Value>) -> Result<Value, InterpreterError>),
+    NativeFunction(String, usize, NativeFn), //name, arity
     Closure(Closure),          //Reference to a function object
     Value(Value),              //Box type
     OpenUpvalue(usize, usize), //call_frame, slot
     Class(Class),
     Instance(Instance),
     BoundMethod(BoundMethod),
+    Userdata(Userdata),
+    List(Vec<Value>),
+    Tuple(Vec<Value>), //Multiple return values, which can't be changed once made
+    Module(Module),
+    Generator(Generator),
 }
 
 impl Object {
@@ -100,7 +121,7 @@ impl Display for Object {
         match self {
             Object::String(s) => write!(f, "{}", s),
             Object::Function(func) => write!(f, "{}", func.to_string()),
-            Object::NativeFunction(name, _) => write!(f, "<Native {}>", name),
+            Object::NativeFunction(name, _, _) => write!(f, "<Native {}>", name),
             Object::Closure(closure) => write!(f, "<Closure {}>", closure.function_pointer),
             Object::Value(val) => write!(f, "{}", val),
             Object::OpenUpvalue(call_frame, slot) => {
@@ -116,6 +137,13 @@ impl Display for Object {
             Object::BoundMethod(bound_method) => {
                 write!(f, "<BoundMethod {}>", bound_method.receiver)
             }
+            Object::Userdata(userdata) => write!(f, "{}", userdata),
+            Object::List(items) => write!(f, "<List |{} items|>", items.len()),
+            Object::Tuple(items) => write!(f, "<Tuple |{} items|>", items.len()),
+            Object::Module(module) => write!(f, "<module {}>", module.name),
+            Object::Generator(generator) => {
+                write!(f, "<Generator {:?} at {}>", generator.state, generator.ip)
+            }
         }
     }
 }
@@ -126,12 +154,15 @@ pub enum FnType {
     Initializer,
     Script,
     Method,
+    Getter, //Runs on a property read, taking no parameters
+    Setter, //Runs on a property write, evaluating to the value written
 }
 
 #[derive(Clone)]
 pub struct Function {
     pub fn_type: FnType,
     pub arity: usize,
+    pub variadic: bool, //The last parameter collects any extra arguments into a list
     pub chunk: Chunk,
     pub name: String,
     pub upvalue_count: usize,
@@ -141,6 +172,7 @@ pub struct Function {
 pub struct Closure {
     pub function_pointer: u64,
     pub closed_values: Vec<u64>,
+    pub namespace: usize, //Whose globals it sees, 0 for the main program's
 }
 
 impl Function {
@@ -149,6 +181,7 @@ impl Function {
             fn_type,
             name,
             arity,
+            variadic: false,
             chunk: Chunk::new(),
             upvalue_count: 0,
         }
@@ -162,13 +195,118 @@ impl Function {
 #[derive(Clone)]
 pub struct Class {
     pub name: String,
-    pub methods: HashMap<String, u64>,
+    //All keyed by the name's symbol
+    pub methods: Map<Symbol, u64>,
+    //The same methods by symbol, for symbols below VTABLE_SIZE
+    pub vtable: Vec<Option<u64>>,
+    //Methods called on the class itself, with it as 'this'
+    pub statics: Map<Symbol, u64>,
+    //Accessors run in place of reading or writing a property
+    pub getters: Map<Symbol, u64>,
+    pub setters: Map<Symbol, u64>,
+    //Classes that inherited from this one, which get what it defines once reopened
+    pub subclasses: Vec<u64>,
+}
+
+//Which of a class's tables a method goes in
+#[derive(Clone, Copy, PartialEq)]
+pub enum Table {
+    Methods,
+    Statics,
+    Getters,
+    Setters,
+}
+
+impl Class {
+    pub fn new(name: String) -> Class {
+        Class {
+            name,
+            methods: Map::new(),
+            vtable: vec![],
+            statics: Map::new(),
+            getters: Map::new(),
+            setters: Map::new(),
+            subclasses: vec![],
+        }
+    }
+
+    pub fn table(&self, table: Table) -> &Map<Symbol, u64> {
+        match table {
+            Table::Methods => &self.methods,
+            Table::Statics => &self.statics,
+            Table::Getters => &self.getters,
+            Table::Setters => &self.setters,
+        }
+    }
+
+    //Returns what it replaced, if anything
+    pub fn define(&mut self, table: Table, name: Symbol, method_ptr: u64) -> Option<u64> {
+        let old = self.table(table).get(&name).copied();
+        match table {
+            Table::Methods => self.add_method(name, method_ptr),
+            Table::Statics => {
+                self.statics.insert(name, method_ptr);
+            }
+            Table::Getters => {
+                self.getters.insert(name, method_ptr);
+            }
+            Table::Setters => {
+                self.setters.insert(name, method_ptr);
+            }
+        }
+        old
+    }
+
+    pub fn add_method(&mut self, name: Symbol, method_ptr: u64) {
+        if name < VTABLE_SIZE {
+            if self.vtable.len() <= name {
+                self.vtable.resize(name + 1, None);
+            }
+            self.vtable[name] = Some(method_ptr);
+        }
+        self.methods.insert(name, method_ptr);
+    }
+
+    #[inline]
+    pub fn method(&self, name: Symbol) -> Option<u64> {
+        if name < VTABLE_SIZE {
+            self.vtable.get(name).copied().flatten()
+        } else {
+            self.methods.get(&name).copied()
+        }
+    }
+
+    //Takes the superclass methods this class doesn't define itself, which is
+    //all of them unless it's a class being reopened
+    pub fn inherit(&mut self, superclass: Class) {
+        if self.vtable.len() < superclass.vtable.len() {
+            self.vtable.resize(superclass.vtable.len(), None);
+        }
+        for (id, method_ptr) in superclass.vtable.into_iter().enumerate() {
+            if self.vtable[id].is_none() {
+                self.vtable[id] = method_ptr;
+            }
+        }
+        let maps = [
+            (&mut self.methods, superclass.methods),
+            (&mut self.statics, superclass.statics),
+            (&mut self.getters, superclass.getters),
+            (&mut self.setters, superclass.setters),
+        ];
+        for (own, inherited) in maps {
+            for (name, method_ptr) in inherited {
+                own.entry(name).or_insert(method_ptr);
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Instance {
     pub class_ptr: u64,
-    pub fields: HashMap<String, Value>,
+    //Which field is in which slot of fields
+    pub shape: ShapeId,
+    pub fields: Vec<Value>,
 }
 
 #[derive(Clone)]
@@ -177,6 +315,56 @@ pub struct BoundMethod {
     pub closure_ptr: u64,
 }
 
+//A call to a generator function, suspended between next() calls
+#[derive(Clone)]
+pub struct Generator {
+    pub closure_ptr: u64,
+    pub ip: usize, //Where next() picks up
+    pub slots: Vec<Value>, //The frame's stack while suspended, from slot 0 up
+    //Slots that closures captured, with the upvalue holding each while suspended
+    pub captured: Vec<(usize, u64)>,
+    pub state: GeneratorState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GeneratorState {
+    Suspended,
+    Running,
+    Done, //Returned or failed, next() only gives nil from here on
+}
+
+//Methods every generator has
+pub const GENERATOR_METHODS: &[&str] = &["next", "done"];
+
+//A file brought in with import. Its properties are the globals it declared.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub namespace: usize,
+}
+
+impl Module {
+    //What an import binds without 'as', the file name without ".lox"
+    pub fn default_name(path: &str) -> &str {
+        let file = path.rsplit('/').next().unwrap_or(path);
+        file.strip_suffix(".lox").unwrap_or(file)
+    }
+}
+
+//Something outside the VM that natives hand to scripts, like a C pointer.
+//Scripts can only pass it around, it's up to the natives what it means.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Userdata {
+    pub kind: &'static str,
+    pub address: usize,
+}
+
+impl Display for Userdata {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} {:#x}>", self.kind, self.address)
+    }
+}
+
 pub trait FromValue
 where
     Self: Sized,
@@ -193,6 +381,7 @@ impl FromValue for f64 {
     fn as_val(val: Value, line: usize) -> Result<f64, InterpreterError> {
         match val {
             Value::Number(n) => Ok(n),
+            Value::Integer(n) => Ok(n as f64),
             _ => Err(InterpreterError::TypeError(
                 line,
                 String::from("Expected a number"),
@@ -202,6 +391,28 @@ impl FromValue for f64 {
     fn as_val_or_panic(val: Value) -> f64 {
         match val {
             Value::Number(n) => n,
+            Value::Integer(n) => n as f64,
+            _ => panic!("Expected a number"),
+        }
+    }
+}
+
+//Floats are truncated, for the bitwise operators
+impl FromValue for i64 {
+    fn as_val(val: Value, line: usize) -> Result<i64, InterpreterError> {
+        match val {
+            Value::Integer(n) => Ok(n),
+            Value::Number(n) => Ok(n as i64),
+            _ => Err(InterpreterError::TypeError(
+                line,
+                String::from("Expected a number"),
+            )),
+        }
+    }
+    fn as_val_or_panic(val: Value) -> i64 {
+        match val {
+            Value::Integer(n) => n,
+            Value::Number(n) => n as i64,
             _ => panic!("Expected a number"),
         }
     }
@@ -256,6 +467,12 @@ impl ToValue for f64 {
     }
 }
 
+impl ToValue for i64 {
+    fn to_value(raw: i64) -> Value {
+        Value::Integer(raw)
+    }
+}
+
 impl ToValue for bool {
     fn to_value(raw: bool) -> Value {
         Value::Boolean(raw)
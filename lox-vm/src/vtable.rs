@@ -0,0 +1,33 @@
+// Method dispatch by symbol. The compiler puts a method name's symbol in the
+// Method and Invoke instructions, and each class keeps a vtable indexed by
+// symbol, so dispatch is an array index instead of a map lookup. Symbols are
+// handed out in the order names are first seen, so the vtable only covers the
+// first VTABLE_SIZE names; later ones are found through the class's method map.
+
+//Keeps each class's vtable to a few KB however many names a program has
+pub const VTABLE_SIZE: usize = 256;
+
+#[cfg(test)]
+mod vtable_tests {
+    use super::*;
+    use crate::interpreter::VM;
+    use crate::test_support::{global, run};
+
+    #[test]
+    fn dispatches_past_the_vtable() {
+        //Enough distinct names that the last few only live in the method maps
+        let mut source = String::from("class A { init() {}");
+        for i in 0..VTABLE_SIZE + 4 {
+            source.push_str(&format!(" m{}() {{ return {}; }}", i, i));
+        }
+        source.push_str(" }\nvar b = A();\n");
+        let last = VTABLE_SIZE + 3;
+        source.push_str(&format!("var x = b.m1() + b.m{}();", last));
+
+        let mut vm = VM::new();
+        run(&mut vm, &source).unwrap();
+
+        assert_eq!(global(&vm, "x"), format!("{}", 1 + last));
+        assert!(vm.symbols().len() > VTABLE_SIZE);
+    }
+}
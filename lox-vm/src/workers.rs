@@ -0,0 +1,408 @@
+use super::bytecode;
+use super::interpreter::VM;
+use super::value::{Closure, FnType, Function, NativeFn, Object, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Workers give scripts real parallelism. Each one runs a function in its own
+// OS thread with its own VM and heap, since nothing in a heap is safe to share,
+// and talks to the others through channels. Everything crossing between them
+// is deep copied, so only nil, booleans, numbers, strings and lists of those
+// can be sent.
+//
+// A worker starts with a copy of the globals it can carry over: values that
+// could be sent, natives, and functions that don't capture anything. Channels
+// and workers are numbers in Lox, so they can be handed to workers too. They
+// index the group of the VM that made them, which every worker it spawns
+// shares, so other VMs in the process have channels and workers of their own.
+
+//A value with nothing left in the heap it came from
+#[derive(Debug, Clone, PartialEq)]
+enum Message {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Integer(i64),
+    String(String),
+    List(Vec<Message>),
+}
+
+#[derive(Default)]
+struct Channel {
+    queue: Mutex<VecDeque<Message>>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+pub struct WorkerGroup {
+    channels: Mutex<Vec<Arc<Channel>>>,
+    //None once joined. A worker's result is whether it ran without an error
+    workers: Mutex<Vec<Option<JoinHandle<bool>>>>,
+    //Workers that haven't finished yet, and the errors of those that failed
+    running: AtomicUsize,
+    failures: Mutex<Vec<String>>,
+}
+
+impl WorkerGroup {
+    fn channel(&self, value: Value) -> Result<Arc<Channel>, String> {
+        let channels = self.channels.lock().unwrap();
+        let idx = index(value, channels.len(), "channel")?;
+        Ok(channels[idx].clone())
+    }
+
+    // Lets receivers see that a worker finished. Taking each queue's lock means
+    // a receiver is either waiting, or yet to check what's running.
+    fn wake_receivers(&self) {
+        let channels = self.channels.lock().unwrap().clone();
+        for channel in channels {
+            let _queue = channel.queue.lock().unwrap();
+            channel.ready.notify_all();
+        }
+    }
+}
+
+enum Global {
+    Message(Message),
+    Native(usize, NativeFn), //arity
+    Function(usize),         //Constant of the snapshot's carrier function
+}
+
+// What a new worker needs from the VM that spawned it. Every function goes
+// into one serialized program so they share the worker's heap, with the one
+// to run as its first constant.
+struct Snapshot {
+    program: Vec<u8>,
+    globals: Vec<(String, Global)>,
+    max_call_depth: usize,
+    stack_size: usize,
+    //What setenv changed so far
+    environment: Vec<(String, Option<String>)>,
+}
+
+fn message(vm: &VM, value: Value) -> Result<Message, String> {
+    copy(vm, value, &mut vec![])
+}
+
+//Lists being copied are kept in copying, as one holding itself can't be sent
+fn copy(vm: &VM, value: Value, copying: &mut Vec<u64>) -> Result<Message, String> {
+    match value {
+        Value::Nil => Ok(Message::Nil),
+        Value::Boolean(b) => Ok(Message::Boolean(b)),
+        Value::Number(n) => Ok(Message::Number(n)),
+        Value::Integer(n) => Ok(Message::Integer(n)),
+        Value::Object(ptr) => match vm.heap_object(ptr) {
+            Some(Object::String(s)) => Ok(Message::String(s.clone())),
+            Some(Object::List(items)) if !copying.contains(&ptr) => {
+                copying.push(ptr);
+                let items: Result<Vec<Message>, String> =
+                    items.iter().map(|item| copy(vm, *item, copying)).collect();
+                copying.pop();
+                Ok(Message::List(items?))
+            }
+            Some(Object::List(_)) => Err(String::from("Can't send a list that contains itself")),
+            _ => Err(format!("Can't send {} to another worker", vm.stringify(value))),
+        },
+    }
+}
+
+fn to_value(vm: &mut VM, message: Message) -> Value {
+    match message {
+        Message::Nil => Value::Nil,
+        Message::Boolean(b) => Value::Boolean(b),
+        Message::Number(n) => Value::Number(n),
+        Message::Integer(n) => Value::Integer(n),
+        Message::String(s) => vm.new_string(s),
+        Message::List(items) => {
+            let items = items.into_iter().map(|item| to_value(vm, item)).collect();
+            vm.new_list(items)
+        }
+    }
+}
+
+//Ids are indices, so anything else is rejected
+fn index(value: Value, len: usize, kind: &str) -> Result<usize, String> {
+    match value {
+        Value::Integer(n) if n >= 0 && (n as usize) < len => Ok(n as usize),
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && (n as usize) < len => Ok(n as usize),
+        _ => Err(format!("Expected a {}", kind)),
+    }
+}
+
+//The function behind a closure with no upvalues
+fn capture_free(vm: &VM, value: Value) -> Option<u64> {
+    match value {
+        Value::Object(ptr) => match vm.heap_object(ptr) {
+            Some(Object::Closure(closure)) if closure.closed_values.is_empty() => {
+                Some(closure.function_pointer)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn snapshot(vm: &VM, entry: Value) -> Result<Snapshot, String> {
+    let entry = capture_free(vm, entry)
+        .ok_or_else(|| String::from("Can only spawn functions that capture no variables"))?;
+    let arity = match vm.heap_object(entry) {
+        Some(Object::Function(function)) => function.arity,
+        _ => 0,
+    };
+    if arity != 0 {
+        return Err(format!("Spawned functions take no arguments but got one with {}", arity));
+    }
+
+    let mut carrier = Function::new(String::from("worker"), 0, FnType::Script);
+    carrier.chunk.add_constant(Value::Object(entry));
+    let mut globals = vec![];
+    for (name, value) in vm.globals() {
+        let global = if let Ok(message) = message(vm, value) {
+            Global::Message(message)
+        } else if let Some(function) = capture_free(vm, value) {
+            Global::Function(carrier.chunk.add_constant(Value::Object(function)))
+        } else if let Some(Object::NativeFunction(_, arity, function)) =
+            value_object(vm, value)
+        {
+            Global::Native(*arity, *function)
+        } else {
+            continue;
+        };
+        globals.push((name.clone(), global));
+    }
+
+    Ok(Snapshot {
+        program: bytecode::serialize(&carrier, vm.virtual_memory()),
+        globals,
+        max_call_depth: vm.max_call_depth(),
+        stack_size: vm.stack_size(),
+        environment: vm.env_overrides().clone().into_iter().collect(),
+    })
+}
+
+fn value_object(vm: &VM, value: Value) -> Option<&Object> {
+    match value {
+        Value::Object(ptr) => vm.heap_object(ptr),
+        _ => None,
+    }
+}
+
+fn run_worker(snapshot: Snapshot, group: Arc<WorkerGroup>) -> Result<(), String> {
+    let (carrier, mut heap) = bytecode::deserialize(&snapshot.program)?;
+    let function_at = |idx: usize| match carrier.chunk.constants[idx] {
+        Value::Object(ptr) => ptr,
+        _ => panic!("Worker constants are all functions"),
+    };
+    let entry = heap.deref(function_at(0)).as_function().clone();
+
+    let mut closures = vec![];
+    for (name, global) in snapshot.globals.iter() {
+        if let Global::Function(idx) = global {
+            let closure = heap.add_to_heap(Object::Closure(Closure {
+                function_pointer: function_at(*idx),
+                closed_values: vec![],
+                namespace: 0,
+            }));
+            closures.push((name, closure));
+        }
+    }
+
+    let mut vm = VM::new();
+    vm.join_worker_group(group);
+    vm.set_max_call_depth(snapshot.max_call_depth);
+    vm.set_stack_size(snapshot.stack_size);
+    for (name, value) in snapshot.environment {
+        vm.set_env_var(name, value);
+    }
+    vm.give_virtual_memory(heap);
+    for (name, closure) in closures {
+        vm.define_global(name, Value::Object(closure));
+    }
+    for (name, global) in snapshot.globals {
+        match global {
+            Global::Message(message) => {
+                let value = to_value(&mut vm, message);
+                vm.define_global(&name, value);
+            }
+            Global::Native(arity, function) => vm.define_native(&name, arity, function),
+            Global::Function(_) => {}
+        }
+    }
+
+    let heap = vm.take_virtual_memory();
+    vm.interpret_function(entry, heap).map_err(|e| e.to_string())
+}
+
+pub fn spawn(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let snapshot = snapshot(vm, args[0])?;
+    let group = vm.worker_group();
+    group.running.fetch_add(1, Ordering::SeqCst);
+    let worker_group = group.clone();
+    let handle = thread::spawn(move || {
+        let result = run_worker(snapshot, worker_group.clone());
+        if let Err(e) = &result {
+            worker_group.failures.lock().unwrap().push(e.clone());
+        }
+        worker_group.running.fetch_sub(1, Ordering::SeqCst);
+        worker_group.wake_receivers();
+        result.is_ok()
+    });
+
+    let mut workers = group.workers.lock().unwrap();
+    workers.push(Some(handle));
+    Ok(Value::Integer((workers.len() - 1) as i64))
+}
+
+pub fn join(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let group = vm.worker_group();
+    let handle = {
+        let mut workers = group.workers.lock().unwrap();
+        let idx = index(args[0], workers.len(), "worker")?;
+        workers[idx]
+            .take()
+            .ok_or_else(|| String::from("Worker was already joined"))?
+    };
+    let succeeded = handle.join().unwrap_or(false);
+    Ok(Value::Boolean(succeeded))
+}
+
+pub fn channel(vm: &mut VM, _: &[Value]) -> Result<Value, String> {
+    let group = vm.worker_group();
+    let mut channels = group.channels.lock().unwrap();
+    channels.push(Arc::new(Channel::default()));
+    Ok(Value::Integer((channels.len() - 1) as i64))
+}
+
+pub fn send(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let channel = vm.worker_group().channel(args[0])?;
+    let message = message(vm, args[1])?;
+    channel.queue.lock().unwrap().push_back(message);
+    channel.ready.notify_one();
+    Ok(Value::Nil)
+}
+
+// Blocks until something is sent. The main thread gives up once no worker is
+// left to send anything, rather than waiting forever; workers keep waiting, as
+// the main thread might still send.
+pub fn recv(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let group = vm.worker_group();
+    let channel = group.channel(args[0])?;
+    let mut queue = channel.queue.lock().unwrap();
+    let message = loop {
+        match queue.pop_front() {
+            Some(message) => break message,
+            None if !vm.is_worker() && group.running.load(Ordering::SeqCst) == 0 => {
+                return Err(match group.failures.lock().unwrap().last() {
+                    Some(error) => format!("Nothing to receive, a worker failed: {}", error),
+                    None => String::from("Nothing to receive and no workers are running"),
+                });
+            }
+            None => queue = channel.ready.wait(queue).unwrap(),
+        }
+    };
+    drop(queue);
+    Ok(to_value(vm, message))
+}
+
+#[cfg(test)]
+mod workers_tests {
+    use super::*;
+    use crate::natives::{ENV, TEST, THREADS, VALUES};
+    use crate::test_support;
+
+    fn run(source: &str) -> Result<(), String> {
+        let mut vm = VM::new();
+        vm.define_natives(&ENV);
+        vm.define_natives(&TEST);
+        vm.define_natives(&THREADS);
+        vm.define_natives(&VALUES);
+        test_support::run(&mut vm, source)
+    }
+
+    #[test]
+    fn workers_send_results_back() {
+        let source = "var results = channel();\n\
+                      fun square(n) { return n * n; }\n\
+                      fun work() { var sum = 0; for (var i = 1; i <= 10; i = i + 1) \
+                      sum = sum + square(i); send(results, \"sum \" + \"done\"); \
+                      send(results, sum); }\n\
+                      var worker = spawn(work);\n\
+                      assertEq(recv(results), \"sum done\");\n\
+                      assertEq(recv(results), 385);\n\
+                      assertTrue(join(worker));";
+        assert_eq!(run(source), Ok(()));
+    }
+
+    #[test]
+    fn only_copies_what_can_cross() {
+        let source = "class A { init() {} }\n\
+                      var c = channel();\n\
+                      send(c, A());";
+        assert!(run(source).unwrap_err().contains("Can't send A instance to another worker"));
+
+        let source = "fun outer() { var x = 1; fun inner() { print x; } return inner; }\n\
+                      spawn(outer());";
+        assert!(run(source).unwrap_err().contains("capture no variables"));
+    }
+
+    #[test]
+    fn lists_are_copied_both_ways() {
+        let source = "var to = channel();\n\
+                      var from = channel();\n\
+                      fun work() { var got = recv(to); got[1].push(4); send(from, got); }\n\
+                      var worker = spawn(work);\n\
+                      var sent = [1, [2, \"three\"], nil];\n\
+                      send(to, sent);\n\
+                      var back = recv(from);\n\
+                      assertEq(string(back), \"[1, [2, three, 4], nil]\");\n\
+                      assertEq(string(sent), \"[1, [2, three], nil]\");\n\
+                      assertTrue(join(worker));";
+        assert_eq!(run(source), Ok(()));
+
+        let source = "var l = [1];\nl.push(l);\nsend(channel(), l);";
+        assert!(run(source).unwrap_err().contains("contains itself"));
+    }
+
+    #[test]
+    fn receiving_from_a_failed_worker_is_an_error() {
+        let source = "var c = channel();\n\
+                      fun work() { send(c, -nil); }\n\
+                      var worker = spawn(work);\n\
+                      recv(c);";
+        let error = run(source).unwrap_err();
+        assert!(error.contains("a worker failed"), "{}", error);
+    }
+
+    #[test]
+    fn workers_see_what_setenv_changed() {
+        let source = "setenv(\"LOX_WORKER_TEST\", \"set\");\n\
+                      var c = channel();\n\
+                      fun work() { send(c, getenv(\"LOX_WORKER_TEST\")); }\n\
+                      assertTrue(join(spawn(work)));\n\
+                      assertEq(recv(c), \"set\");";
+        assert_eq!(run(source), Ok(()));
+    }
+
+    #[test]
+    fn each_vm_has_its_own_channels_and_workers() {
+        let source = "var c = channel();\n\
+                      fun work() { send(c, -nil); }\n\
+                      join(spawn(work));";
+        assert_eq!(run(source), Ok(()));
+        //Nothing of the failed worker or its channel is left over
+        let error = run("recv(channel());").unwrap_err();
+        assert!(error.contains("no workers are running"), "{}", error);
+        assert!(run("recv(1);").unwrap_err().contains("Expected a channel"));
+        assert!(run("join(0);").unwrap_err().contains("Expected a worker"));
+    }
+
+    #[test]
+    fn globals_that_cant_cross_are_left_out() {
+        let source = "class A { init() {} }\n\
+                      var c = channel();\n\
+                      fun work() { send(c, A); }\n\
+                      assertTrue(!join(spawn(work)));";
+        assert_eq!(run(source), Ok(()));
+    }
+}
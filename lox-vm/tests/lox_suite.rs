@@ -0,0 +1,206 @@
+// Runs .lox files written in the craftinginterpreters test corpus format and
+// checks the VM's behavior against the expectations in their comments:
+//
+//   print 1;                 // expect: 1
+//   print nil.field;         // expect runtime error: <message>
+//   return;                  // Error at 'return': <message>
+//   // [line 3] Error at 'x': <message>
+//
+// The cases under tests/lox always run. Each directory under tests/projects is
+// a project run from its main.lox with its lox.toml applied. Point
+// LOX_TEST_SUITE at a checkout of craftinginterpreters/test and run with
+// --ignored to check the full corpus.
+#![cfg(feature = "std")]
+
+use lox_vm::compiler::Compiler;
+#[cfg(feature = "project")]
+use lox_vm::config::{entry_point, VmOptions};
+use lox_vm::interpreter::VM;
+use lox_vm::scanner::scan_tokens;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+#[derive(Clone)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default, Debug, PartialEq)]
+struct Outcome {
+    output: Vec<String>,
+    //(line, message)
+    compile_errors: Vec<(usize, String)>,
+    runtime_error: Option<(usize, String)>,
+}
+
+fn strip_error_location(message: &str) -> &str {
+    //"Error at 'x': msg", "Error at end: msg" and "Error: msg" all reduce to msg
+    if let Some(rest) = message.strip_prefix("Error") {
+        if let Some(idx) = rest.find(": ") {
+            return &rest[idx + 2..];
+        }
+    }
+    message
+}
+
+fn parse_expectations(source: &str) -> Outcome {
+    let mut expected = Outcome::default();
+
+    for (idx, line) in source.lines().enumerate() {
+        let line_number = idx + 1;
+        let comment = match line.find("//") {
+            Some(start) => line[start + 2..].trim(),
+            None => continue,
+        };
+
+        if let Some(output) = comment.strip_prefix("expect:") {
+            let output = output.strip_prefix(' ').unwrap_or(output);
+            expected.output.push(String::from(output));
+        } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+            expected.runtime_error = Some((line_number, String::from(message)));
+        } else if let Some(rest) = comment
+            .strip_prefix("[line ")
+            .or_else(|| comment.strip_prefix("[c line "))
+        {
+            if let Some(end) = rest.find(']') {
+                let line_number = rest[..end].parse().unwrap();
+                let message = strip_error_location(rest[end + 1..].trim());
+                expected
+                    .compile_errors
+                    .push((line_number, String::from(message)));
+            }
+        } else if comment.starts_with("Error") {
+            let message = strip_error_location(comment);
+            expected
+                .compile_errors
+                .push((line_number, String::from(message)));
+        }
+    }
+
+    expected
+}
+
+fn run_lox(path: &Path, source: &str, mut vm: VM) -> Outcome {
+    let mut outcome = Outcome::default();
+    let output = Capture(Rc::new(RefCell::new(vec![])));
+
+    vm.set_output(Box::new(output.clone()));
+    vm.set_script_path(&path.to_string_lossy());
+
+    match scan_tokens(&String::from(source)) {
+        Ok(tokens) => {
+            let mut compiler = Compiler::new(tokens, vm.take_virtual_memory());
+            match compiler.compile() {
+                Ok(main) => {
+                    if let Err(e) = vm.interpret(main, compiler.heap) {
+                        outcome.runtime_error = Some((e.line(), String::from(e.message())));
+                    }
+                }
+                Err(errors) => {
+                    for error in errors {
+                        outcome
+                            .compile_errors
+                            .push((error.line(), String::from(error.message())));
+                    }
+                }
+            }
+        }
+        Err(e) => outcome.compile_errors.push((e.line, e.description)),
+    }
+
+    let output = String::from_utf8(output.0.borrow().clone()).unwrap();
+    outcome.output = output.lines().map(String::from).collect();
+    outcome
+}
+
+fn collect_lox_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_lox_files(&path, files);
+        } else if path.extension().and_then(|ext| ext.to_str()) == Some("lox") {
+            files.push(path);
+        }
+    }
+}
+
+fn run_suite(root: &Path) {
+    let mut files = vec![];
+    collect_lox_files(root, &mut files);
+    files.sort();
+    assert!(!files.is_empty(), "No .lox files found in {}", root.display());
+
+    let mut failures = vec![];
+    for path in files.iter() {
+        let source = fs::read_to_string(path).unwrap();
+        let expected = parse_expectations(&source);
+        let actual = run_lox(path, &source, VM::new());
+        if expected != actual {
+            failures.push(format!(
+                "{}\n  expected: {:?}\n  actual:   {:?}",
+                path.display(),
+                expected,
+                actual
+            ));
+        }
+    }
+
+    if !failures.is_empty() {
+        panic!(
+            "{} of {} lox tests failed:\n{}",
+            failures.len(),
+            files.len(),
+            failures.join("\n")
+        );
+    }
+}
+
+#[test]
+fn lox_tests() {
+    run_suite(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox"));
+}
+
+#[test]
+#[cfg(feature = "project")]
+fn lox_projects() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/projects");
+    let mut projects: Vec<PathBuf> = fs::read_dir(&root)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    projects.sort();
+
+    for project in projects.iter() {
+        let (script, module_root) = entry_point(project).unwrap();
+        let mut options = VmOptions::discover(Some(&script)).unwrap();
+        options.module_root = Some(module_root);
+        let mut vm = VM::new();
+        vm.configure(&options);
+
+        let source = fs::read_to_string(&script).unwrap();
+        let expected = parse_expectations(&source);
+        let actual = run_lox(&script, &source, vm);
+        assert_eq!(expected, actual, "{}", project.display());
+    }
+}
+
+#[test]
+#[ignore]
+fn official_test_suite() {
+    let root = env::var("LOX_TEST_SUITE")
+        .expect("Set LOX_TEST_SUITE to the craftinginterpreters test directory");
+    run_suite(Path::new(&root));
+}
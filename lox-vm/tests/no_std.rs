@@ -0,0 +1,33 @@
+// Without the std feature 'print' writes to a core::fmt::Write supplied by the
+// embedder. Run with `cargo test --no-default-features`.
+#![cfg(not(feature = "std"))]
+
+use lox_vm::compiler::Compiler;
+use lox_vm::interpreter::VM;
+use lox_vm::scanner::scan_tokens;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Clone)]
+struct Capture(Rc<RefCell<String>>);
+
+impl fmt::Write for Capture {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.borrow_mut().push_str(s);
+        Ok(())
+    }
+}
+
+#[test]
+fn prints_through_fmt_write() {
+    let source = String::from("class A { init(n) { this.n = n; } }\nprint A(41).n + 1;");
+    let mut vm = VM::new();
+    let output = Capture(Rc::new(RefCell::new(String::new())));
+    vm.set_output(Box::new(output.clone()));
+
+    let mut compiler = Compiler::new(scan_tokens(&source).unwrap(), vm.take_virtual_memory());
+    let main = compiler.compile().ok().unwrap();
+    vm.interpret(main, compiler.heap).ok().unwrap();
+    assert_eq!(*output.0.borrow(), "42\n");
+}